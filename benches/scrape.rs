@@ -0,0 +1,64 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use nvidia_smi_exporter::{default_collectors, exposition, fields, gpu};
+use std::hint::black_box;
+
+/// `nvidia-smi --query-gpu=… --format=csv,noheader,nounits` of whole nodes.
+const OUTPUTS: &[(&str, &[u8])] = &[
+    ("8xh100", include_bytes!("data/query-gpu-8xh100.csv")),
+    ("16xv100", include_bytes!("data/query-gpu-16xv100.csv")),
+];
+
+fn parse_csv(c: &mut Criterion) {
+    let fields = fields(&default_collectors());
+    let mut group = c.benchmark_group("parse_csv");
+    for (name, output) in OUTPUTS {
+        group.throughput(Throughput::Bytes(output.len() as u64));
+        group.bench_function(*name, |b| {
+            b.iter(|| gpu::parse_csv(black_box(output), &fields).unwrap())
+        });
+    }
+    group.finish();
+}
+
+/// The samples of `collector::render` without relabeling or extra labels, from label sets rendered
+/// beforehand and into a buffer sized by the previous iteration, as on repeated scrapes.
+fn render(c: &mut Criterion) {
+    let fields = fields(&default_collectors());
+    let mut group = c.benchmark_group("render");
+    for (name, output) in OUTPUTS {
+        let gpus = gpu::parse_csv(output, &fields).unwrap();
+        let label_sets = gpus
+            .iter()
+            .map(|gpu| {
+                let mut label_set = String::new();
+                let labels = [
+                    ("gpu".to_string(), gpu.index.clone()),
+                    ("name".to_string(), gpu.name.clone()),
+                ];
+                exposition::label_set(&mut label_set, &labels);
+                label_set
+            })
+            .collect::<Vec<_>>();
+        let mut capacity = 0;
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                let mut buffer = String::with_capacity(capacity);
+                for (_, metric) in &fields {
+                    for (gpu, label_set) in black_box(&gpus).iter().zip(&label_sets) {
+                        if let Some(value) = gpu.value(metric) {
+                            buffer.push_str(metric);
+                            buffer.push_str(label_set);
+                            exposition::end_sample(&mut buffer, value);
+                        }
+                    }
+                }
+                capacity = buffer.len();
+                buffer
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse_csv, render);
+criterion_main!(benches);
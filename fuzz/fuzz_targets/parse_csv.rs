@@ -0,0 +1,69 @@
+//! Arbitrary `nvidia-smi --query-gpu` output must either be rejected or render as valid text
+//! format, whatever the GPU names and readings.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nvidia_smi_exporter::{default_collectors, fields, gpu, render_prometheus};
+
+fuzz_target!(|stdout: &[u8]| {
+    let collectors = default_collectors();
+    let gpus = match gpu::parse_csv(stdout, &fields(&collectors)) {
+        Ok(gpus) => gpus,
+        Err(_) => return,
+    };
+    let text = render_prometheus(&gpus, &collectors);
+    for line in text.lines() {
+        if let Err(e) = check_sample(line) {
+            panic!("{}: {:?}", e, line);
+        }
+    }
+});
+
+/// `metric{name="value", …} value`, with only `\\`, `\"` and `\n` escaped in label values.
+fn check_sample(line: &str) -> Result<(), &'static str> {
+    let name_end = line.find(['{', ' ']).ok_or("No value")?;
+    if !valid_name(&line[..name_end], true) {
+        return Err("Invalid metric name");
+    }
+    let mut rest = &line[name_end..];
+    if let Some(labels) = rest.strip_prefix('{') {
+        rest = labels;
+        loop {
+            let (name, value) = rest.split_once("=\"").ok_or("Label without value")?;
+            if !valid_name(name, false) {
+                return Err("Invalid label name");
+            }
+            let mut chars = value.char_indices();
+            let end = loop {
+                match chars.next().ok_or("Unterminated label value")? {
+                    (_, '\\') => match chars.next() {
+                        Some((_, '\\' | '"' | 'n')) => {}
+                        _ => return Err("Invalid escape"),
+                    },
+                    (i, '"') => break i,
+                    (_, '\n') => return Err("Newline in label value"),
+                    _ => {}
+                }
+            };
+            rest = &value[end + 1..];
+            if let Some(next) = rest.strip_prefix(", ") {
+                rest = next;
+            } else {
+                rest = rest.strip_prefix('}').ok_or("Unterminated label set")?;
+                break;
+            }
+        }
+    }
+    let value = rest.strip_prefix(' ').ok_or("No value")?;
+    match value {
+        "NaN" | "+Inf" | "-Inf" => Ok(()),
+        _ if value.parse::<f64>().is_ok_and(f64::is_finite) => Ok(()),
+        _ => Err("Invalid value"),
+    }
+}
+
+fn valid_name(name: &str, colons: bool) -> bool {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || c == '_' || (colons && c == ':');
+    name.chars().next().is_some_and(|c| !c.is_ascii_digit()) && name.chars().all(allowed)
+}
@@ -0,0 +1,220 @@
+use anyhow::{anyhow, bail, Result};
+use async_std::future::timeout;
+use serde_json::json;
+use tide::{Body, Request, Response, StatusCode};
+use tracing::{error, warn};
+
+use crate::api;
+use crate::auth::AuthMiddleware;
+use crate::cli::Cli;
+use crate::collector;
+use crate::gpu::Gpu;
+use crate::State;
+
+/// The power limits in W, as `/api/v1/admin/power-limit` shows them.
+const POWER_FIELDS: &[(&str, &str)] = &[
+    ("power.limit", "nvidia_power_limit"),
+    ("enforced.power.limit", "nvidia_enforced_power_limit"),
+    ("power.default_limit", "nvidia_power_default_limit"),
+    ("power.min_limit", "nvidia_power_min_limit"),
+    ("power.max_limit", "nvidia_power_max_limit"),
+];
+
+/// The admin API changes the GPUs, so it is only served to authenticated clients.
+pub fn check_auth(cli: &Cli, auth: &AuthMiddleware) -> Result<()> {
+    if cli.enable_admin_api && auth.is_empty() {
+        bail!(
+            "--web.enable-admin-api needs --web.basic-auth-file, --web.bearer-token-file or \
+             basic_auth_users in --web.config.file"
+        );
+    }
+    Ok(())
+}
+
+/// `GET /api/v1/admin/power-limit`: the power limits of the GPUs, by `?gpu=` index or UUID if
+/// given.
+pub async fn handle_get_power_limit(req: Request<State>) -> tide::Result {
+    let settings = req.state().settings.read().unwrap().clone();
+    let ids = match ids(&req) {
+        Ok(ids) => ids,
+        Err(e) => return Ok(crate::bad_request(e)),
+    };
+    let gpus = match collector::collect_gpus(&settings, POWER_FIELDS).await {
+        Ok(gpus) => gpus,
+        Err(e) => return error_response(StatusCode::ServiceUnavailable, e),
+    };
+    match select(gpus, &ids) {
+        Ok(gpus) => response(&gpus),
+        Err(e) => Ok(crate::bad_request(e)),
+    }
+}
+
+/// `POST /api/v1/admin/power-limit?gpu=0,1&watts=250`: sets the power limit of the GPUs with
+/// `nvidia-smi -i <gpu> -pl <watts>`, or back to their default with `watts=default`, and returns
+/// the new limits.
+pub async fn handle_set_power_limit(req: Request<State>) -> tide::Result {
+    let settings = req.state().settings.read().unwrap().clone();
+    if settings.mock.is_some() || settings.ssh.is_some() || !settings.nvidia_smi.local_devices() {
+        return Ok(crate::bad_request(
+            "Power limits can only be set on the local GPUs".to_string(),
+        ));
+    }
+    let ids = match ids(&req) {
+        Ok(ids) if ids.is_empty() => {
+            return Ok(crate::bad_request("?gpu= is required".to_string()))
+        }
+        Ok(ids) => ids,
+        Err(e) => return Ok(crate::bad_request(e)),
+    };
+    let watts = req
+        .url()
+        .query_pairs()
+        .find(|(key, _)| key == "watts")
+        .map(|(_, value)| value.into_owned());
+    let watts = match watts.as_deref() {
+        Some("default") => None,
+        Some(watts) => match watts.parse::<f64>() {
+            Ok(watts) if watts.is_finite() => Some(watts),
+            _ => return Ok(crate::bad_request(format!("Invalid watts {:?}", watts))),
+        },
+        None => return Ok(crate::bad_request("?watts= is required".to_string())),
+    };
+
+    // 先查出允许的范围，超出时 nvidia-smi 的报错不够清楚
+    let gpus = match collector::collect_gpus(&settings, POWER_FIELDS).await {
+        Ok(gpus) => gpus,
+        Err(e) => return error_response(StatusCode::ServiceUnavailable, e),
+    };
+    let gpus = match select(gpus, &ids) {
+        Ok(gpus) => gpus,
+        Err(e) => return Ok(crate::bad_request(e)),
+    };
+    let mut changes = Vec::new();
+    for gpu in &gpus {
+        let target = match watts {
+            Some(watts) => watts,
+            None => match gpu.value("nvidia_power_default_limit") {
+                Some(watts) => watts,
+                None => {
+                    return Ok(crate::bad_request(format!(
+                        "GPU {} has no default power limit",
+                        gpu.index
+                    )))
+                }
+            },
+        };
+        let min = gpu.value("nvidia_power_min_limit");
+        let max = gpu.value("nvidia_power_max_limit");
+        match (min, max) {
+            (Some(min), Some(max)) if target < min || target > max => {
+                return Ok(crate::bad_request(format!(
+                    "GPU {} takes power limits from {} W to {} W",
+                    gpu.index, min, max
+                )))
+            }
+            (Some(_), Some(_)) => changes.push((gpu, target)),
+            _ => {
+                return Ok(crate::bad_request(format!(
+                    "GPU {} does not support power limits",
+                    gpu.index
+                )))
+            }
+        }
+    }
+
+    for (gpu, target) in changes {
+        let args = vec![
+            "-i".to_string(),
+            gpu.uuid.clone(),
+            "-pl".to_string(),
+            target.to_string(),
+        ];
+        let result = match timeout(
+            settings.collect_timeout,
+            settings.nvidia_smi.output(&args, None),
+        )
+        .await
+        {
+            Ok(Ok(output)) if output.status.success() => Ok(()),
+            Ok(Ok(output)) => Err(anyhow!(
+                "nvidia-smi exited with {}: {}",
+                output.status,
+                (String::from_utf8_lossy(&output.stdout) + String::from_utf8_lossy(&output.stderr))
+                    .trim()
+            )),
+            Ok(Err(e)) => Err(anyhow::Error::new(e).context("Failed to execute command")),
+            Err(_) => Err(anyhow!(
+                "nvidia-smi timed out after {:?}",
+                settings.collect_timeout
+            )),
+        };
+        match result {
+            // 默认日志级别下也要留下记录
+            Ok(()) => warn!(
+                "Power limit of GPU {} ({}) set to {} W for {}",
+                gpu.index,
+                gpu.uuid,
+                target,
+                req.peer_addr().unwrap_or("a Unix socket")
+            ),
+            Err(e) => {
+                let e = e.context(format!(
+                    "Failed to set the power limit of GPU {}",
+                    gpu.index
+                ));
+                return error_response(StatusCode::InternalServerError, e);
+            }
+        }
+    }
+
+    match collector::collect_gpus(&settings, POWER_FIELDS).await {
+        Ok(gpus) => response(&select(gpus, &ids).unwrap_or_default()),
+        Err(e) => error_response(StatusCode::ServiceUnavailable, e),
+    }
+}
+
+/// `?gpu=0,GPU-…`, validated like on the telemetry path.
+fn ids(req: &Request<State>) -> Result<Vec<String>, String> {
+    let mut ids = Vec::new();
+    for (key, value) in req.url().query_pairs() {
+        if key != "gpu" {
+            continue;
+        }
+        for id in value.split(',').map(str::trim) {
+            if id.parse::<u32>().is_err() && !id.starts_with("GPU-") {
+                return Err(format!("Invalid gpu {:?}", id));
+            }
+            ids.push(id.to_string());
+        }
+    }
+    Ok(ids)
+}
+
+/// The GPUs `ids` names, all without; fails when one of them is missing.
+fn select(gpus: Vec<Gpu>, ids: &[String]) -> Result<Vec<Gpu>, String> {
+    let named = |gpu: &Gpu, id: &String| gpu.index == *id || gpu.uuid == *id;
+    if let Some(id) = ids.iter().find(|id| !gpus.iter().any(|gpu| named(gpu, id))) {
+        return Err(format!("No GPU {}", id));
+    }
+    Ok(gpus
+        .into_iter()
+        .filter(|gpu| ids.is_empty() || ids.iter().any(|id| named(gpu, id)))
+        .collect())
+}
+
+fn response(gpus: &[Gpu]) -> tide::Result {
+    let gpus = gpus
+        .iter()
+        .map(|gpu| api::to_json(gpu, POWER_FIELDS))
+        .collect::<Vec<_>>();
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&json!({ "gpus": gpus }))?)
+        .build())
+}
+
+fn error_response(status: StatusCode, e: anyhow::Error) -> tide::Result {
+    error!("{:#}", e);
+    Ok(Response::builder(status)
+        .body(Body::from_json(&json!({ "error": format!("{:#}", e) }))?)
+        .build())
+}
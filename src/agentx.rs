@@ -0,0 +1,653 @@
+use anyhow::{bail, Context, Result};
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::TcpStream;
+use async_std::os::unix::net::UnixStream;
+use std::convert::TryInto;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::cli::Cli;
+use crate::push::{Batch, Target};
+
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+const HEADER_LEN: usize = 20;
+/// Longest PDU accepted from the master agent.
+const MAX_PAYLOAD: usize = 1 << 16;
+
+// RFC 2741 5.1
+const OPEN: u8 = 1;
+const CLOSE: u8 = 2;
+const REGISTER: u8 = 3;
+const GET: u8 = 5;
+const GET_NEXT: u8 = 6;
+const GET_BULK: u8 = 7;
+const TEST_SET: u8 = 8;
+const COMMIT_SET: u8 = 9;
+const UNDO_SET: u8 = 10;
+const CLEANUP_SET: u8 = 11;
+const RESPONSE: u8 = 18;
+const NON_DEFAULT_CONTEXT: u8 = 0x08;
+const NETWORK_BYTE_ORDER: u8 = 0x10;
+const NOT_WRITABLE: u16 = 17;
+
+/// Column of `gpuEntry` for each metric, and the factor its value is multiplied by to make it a
+/// whole number. Columns 1 and 2 are the `gpu` and `name` labels. New metrics get new columns.
+const COLUMNS: &[(u32, &str, f64)] = &[
+    (3, "nvidia_fan_speed", 1.0),
+    (4, "nvidia_temperature_gpu", 1.0),
+    (5, "nvidia_clocks_gr", 1.0),
+    (6, "nvidia_clocks_sm", 1.0),
+    (7, "nvidia_clocks_mem", 1.0),
+    // 毫瓦，SNMP 没有浮点类型
+    (8, "nvidia_power_draw", 1000.0),
+    (9, "nvidia_utilization_gpu", 1.0),
+    (10, "nvidia_utilization_memory", 1.0),
+    (11, "nvidia_memory_total", 1.0),
+    (12, "nvidia_memory_free", 1.0),
+    (13, "nvidia_memory_used", 1.0),
+];
+
+#[derive(Clone, Debug)]
+enum Value {
+    Integer(i32),
+    OctetString(String),
+    Gauge32(u32),
+}
+
+/// OIDs and values, sorted by OID.
+type Objects = Vec<(Vec<u32>, Value)>;
+
+/// An AgentX subagent (`--agentx.master`) answering for `gpuTable` under `--agentx.oid` from
+/// the latest collection.
+pub struct AgentX {
+    master: String,
+    oid: Vec<u32>,
+    objects: Arc<RwLock<Objects>>,
+}
+
+impl AgentX {
+    pub fn new(master: &str, cli: &Cli) -> Self {
+        AgentX {
+            master: master.to_string(),
+            oid: cli.agentx_oid.0.clone(),
+            objects: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+}
+
+/// A dotted OID such as `1.3.6.1.4.1.8072.9999.9999`.
+#[derive(Clone, Debug)]
+pub struct Oid(pub Vec<u32>);
+
+impl FromStr for Oid {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let oid = s
+            .trim_start_matches('.')
+            .split('.')
+            .map(|n| n.parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("invalid OID {:?}", s))?;
+        if oid.len() < 2 || oid.len() > 100 {
+            bail!("invalid OID {:?}", s);
+        }
+        Ok(Oid(oid))
+    }
+}
+
+impl fmt::Display for Oid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts = self.0.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        write!(f, "{}", parts.join("."))
+    }
+}
+
+#[tide::utils::async_trait]
+impl Target for AgentX {
+    fn name(&self) -> &'static str {
+        "agentx"
+    }
+
+    fn start(&self) {
+        let master = self.master.clone();
+        let oid = self.oid.clone();
+        let objects = self.objects.clone();
+        async_std::task::spawn(async move {
+            loop {
+                if let Err(e) = session(&master, &oid, &objects).await {
+                    warn!("AgentX session with {} ended, {:#}", master, e);
+                }
+                async_std::task::sleep(RECONNECT_INTERVAL).await;
+            }
+        });
+    }
+
+    /// Replaces the objects served with this collection.
+    async fn push(&self, batch: &Batch) -> Result<()> {
+        // gpuTable = <oid>.1, gpuEntry = <oid>.1.1, 行索引为 GPU 编号加一
+        let entry = |column: u32, row: u32| {
+            let mut oid = self.oid.clone();
+            oid.extend_from_slice(&[1, 1, column, row]);
+            oid
+        };
+        let mut objects = Vec::new();
+        for sample in &batch.samples {
+            let label = |name: &str| {
+                sample
+                    .labels
+                    .iter()
+                    .find(|(n, _)| n == name)
+                    .map(|(_, value)| value.as_str())
+            };
+            let gpu = match label("gpu").and_then(|gpu| gpu.parse::<u32>().ok()) {
+                Some(gpu) => gpu,
+                None => continue,
+            };
+            let row = gpu + 1;
+            if !objects.iter().any(|(oid, _)| *oid == entry(1, row)) {
+                objects.push((entry(1, row), Value::Integer(gpu as i32)));
+                let name = label("name").unwrap_or_default().to_string();
+                objects.push((entry(2, row), Value::OctetString(name)));
+            }
+            if let Some((column, _, scale)) = COLUMNS.iter().find(|(_, m, _)| *m == sample.name) {
+                if sample.value.is_finite() {
+                    let value = (sample.value * scale).round().clamp(0.0, u32::MAX as f64);
+                    objects.push((entry(*column, row), Value::Gauge32(value as u32)));
+                }
+            }
+        }
+        objects.sort_by(|a, b| a.0.cmp(&b.0));
+        objects.dedup_by(|a, b| a.0 == b.0);
+        *self.objects.write().unwrap() = objects;
+        Ok(())
+    }
+}
+
+/// Connects to the master agent, registers `oid` and answers requests until the connection ends.
+async fn session(master: &str, oid: &[u32], objects: &RwLock<Objects>) -> Result<()> {
+    match master.strip_prefix("unix:") {
+        Some(path) => {
+            let stream = UnixStream::connect(path)
+                .await
+                .with_context(|| format!("Failed to connect to {}", path))?;
+            serve(stream, oid, objects).await
+        }
+        None => {
+            let stream = TcpStream::connect(master)
+                .await
+                .with_context(|| format!("Failed to connect to {}", master))?;
+            serve(stream, oid, objects).await
+        }
+    }
+}
+
+async fn serve<S>(mut stream: S, oid: &[u32], objects: &RwLock<Objects>) -> Result<()>
+where
+    S: ReadExt + WriteExt + Unpin,
+{
+    // Open: timeout, reserved, id, descr
+    let mut payload = vec![0, 0, 0, 0];
+    put_oid(&mut payload, oid, false);
+    put_octets(&mut payload, env!("CARGO_PKG_NAME").as_bytes());
+    let header = Header {
+        pdu_type: OPEN,
+        flags: NETWORK_BYTE_ORDER,
+        session_id: 0,
+        transaction_id: 0,
+        packet_id: 1,
+    };
+    write_pdu(&mut stream, &header, &payload).await?;
+    let (response, payload) = read_pdu(&mut stream).await?;
+    check_response(&response, &payload, "Open")?;
+    let session_id = response.session_id;
+
+    // Register: timeout, priority, range_subid, reserved, subtree
+    let mut payload = vec![0, 127, 0, 0];
+    put_oid(&mut payload, oid, false);
+    let header = Header {
+        pdu_type: REGISTER,
+        session_id,
+        packet_id: 2,
+        ..header
+    };
+    write_pdu(&mut stream, &header, &payload).await?;
+    let (response, payload) = read_pdu(&mut stream).await?;
+    check_response(&response, &payload, "Register")?;
+    info!(
+        "Registered {} with the AgentX master agent",
+        Oid(oid.to_vec())
+    );
+
+    loop {
+        let (request, payload) = read_pdu(&mut stream).await?;
+        let mut reader = Reader::new(&payload, request.flags);
+        if request.flags & NON_DEFAULT_CONTEXT != 0 && request.pdu_type != CLOSE {
+            reader.octets()?;
+        }
+        let (error, varbinds) = match request.pdu_type {
+            GET | GET_NEXT => {
+                let objects = objects.read().unwrap();
+                let mut varbinds = Vec::new();
+                while !reader.is_empty() {
+                    let (start, include) = reader.oid()?;
+                    let (end, _) = reader.oid()?;
+                    varbinds.push(if request.pdu_type == GET {
+                        get(&objects, start)
+                    } else {
+                        next(&objects, start, include, &end)
+                    });
+                }
+                (0, varbinds)
+            }
+            GET_BULK => {
+                let non_repeaters = reader.u16()? as usize;
+                let max_repetitions = reader.u16()? as usize;
+                let mut ranges = Vec::new();
+                while !reader.is_empty() {
+                    let (start, include) = reader.oid()?;
+                    let (end, _) = reader.oid()?;
+                    ranges.push((start, include, end));
+                }
+                (
+                    0,
+                    bulk(
+                        &objects.read().unwrap(),
+                        ranges,
+                        non_repeaters,
+                        max_repetitions,
+                    ),
+                )
+            }
+            // 只读，不接受 SET
+            TEST_SET => (NOT_WRITABLE, Vec::new()),
+            COMMIT_SET | UNDO_SET => (0, Vec::new()),
+            CLEANUP_SET => continue,
+            CLOSE => bail!("closed by the master agent"),
+            other => {
+                debug!("Ignoring AgentX PDU type {}", other);
+                continue;
+            }
+        };
+
+        // Response: sysUpTime, error, index, varbinds
+        let mut payload = vec![0, 0, 0, 0];
+        payload.extend_from_slice(&error.to_be_bytes());
+        payload.extend_from_slice(&(if error == 0 { 0u16 } else { 1 }).to_be_bytes());
+        for varbind in varbinds {
+            put_varbind(&mut payload, &varbind);
+        }
+        let header = Header {
+            pdu_type: RESPONSE,
+            flags: NETWORK_BYTE_ORDER,
+            ..request
+        };
+        write_pdu(&mut stream, &header, &payload).await?;
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Header {
+    pdu_type: u8,
+    flags: u8,
+    session_id: u32,
+    transaction_id: u32,
+    packet_id: u32,
+}
+
+enum VarBind {
+    Value(Vec<u32>, Value),
+    /// `noSuchObject`, `noSuchInstance` or `endOfMibView`.
+    Exception(Vec<u32>, u16),
+}
+
+const NO_SUCH_INSTANCE: u16 = 129;
+const END_OF_MIB_VIEW: u16 = 130;
+
+fn get(objects: &Objects, oid: Vec<u32>) -> VarBind {
+    match objects.binary_search_by(|(o, _)| o.as_slice().cmp(&oid)) {
+        Ok(i) => VarBind::Value(oid, objects[i].1.clone()),
+        Err(_) => VarBind::Exception(oid, NO_SUCH_INSTANCE),
+    }
+}
+
+/// The first object after `start` (or at it, with `include`) and before `end`, if not empty.
+fn next(objects: &Objects, start: Vec<u32>, include: bool, end: &[u32]) -> VarBind {
+    let found = objects
+        .iter()
+        .find(|(oid, _)| if include { *oid >= start } else { *oid > start })
+        .filter(|(oid, _)| end.is_empty() || oid.as_slice() < end);
+    match found {
+        Some((oid, value)) => VarBind::Value(oid.clone(), value.clone()),
+        None => VarBind::Exception(start, END_OF_MIB_VIEW),
+    }
+}
+
+fn bulk(
+    objects: &Objects,
+    ranges: Vec<(Vec<u32>, bool, Vec<u32>)>,
+    non_repeaters: usize,
+    max_repetitions: usize,
+) -> Vec<VarBind> {
+    let mut varbinds = Vec::new();
+    let mut repeaters = Vec::new();
+    for (i, (start, include, end)) in ranges.into_iter().enumerate() {
+        if i < non_repeaters {
+            varbinds.push(next(objects, start, include, &end));
+        } else {
+            repeaters.push((start, include, end));
+        }
+    }
+    for _ in 0..max_repetitions {
+        if repeaters.is_empty() {
+            break;
+        }
+        let mut done = true;
+        for (start, include, end) in repeaters.iter_mut() {
+            let varbind = next(objects, start.clone(), *include, end);
+            if let VarBind::Value(oid, _) = &varbind {
+                *start = oid.clone();
+                *include = false;
+                done = false;
+            }
+            varbinds.push(varbind);
+        }
+        if done {
+            break;
+        }
+    }
+    varbinds
+}
+
+fn check_response(response: &Header, payload: &[u8], request: &str) -> Result<()> {
+    if response.pdu_type != RESPONSE {
+        bail!(
+            "expected a response to {}, got PDU type {}",
+            request,
+            response.pdu_type
+        );
+    }
+    let mut reader = Reader::new(payload, response.flags);
+    reader.u32()?;
+    let error = reader.u16()?;
+    if error != 0 {
+        bail!("{} failed with error {}", request, error);
+    }
+    Ok(())
+}
+
+async fn read_pdu<S: ReadExt + Unpin>(stream: &mut S) -> Result<(Header, Vec<u8>)> {
+    let mut buf = [0u8; HEADER_LEN];
+    stream.read_exact(&mut buf).await?;
+    let flags = buf[2];
+    let word = |i: usize| {
+        let bytes = buf[i..i + 4].try_into().unwrap();
+        if flags & NETWORK_BYTE_ORDER != 0 {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    };
+    let header = Header {
+        pdu_type: buf[1],
+        flags,
+        session_id: word(4),
+        transaction_id: word(8),
+        packet_id: word(12),
+    };
+    let len = word(16) as usize;
+    if buf[0] != 1 || len > MAX_PAYLOAD {
+        bail!("invalid AgentX header");
+    }
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await?;
+    Ok((header, payload))
+}
+
+async fn write_pdu<S: WriteExt + Unpin>(
+    stream: &mut S,
+    header: &Header,
+    payload: &[u8],
+) -> Result<()> {
+    let mut buf = vec![1, header.pdu_type, header.flags, 0];
+    for word in [
+        header.session_id,
+        header.transaction_id,
+        header.packet_id,
+        payload.len() as u32,
+    ] {
+        buf.extend_from_slice(&word.to_be_bytes());
+    }
+    buf.extend_from_slice(payload);
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+fn put_oid(buf: &mut Vec<u8>, oid: &[u32], include: bool) {
+    buf.extend_from_slice(&[oid.len() as u8, 0, include as u8, 0]);
+    for n in oid {
+        buf.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+fn put_octets(buf: &mut Vec<u8>, octets: &[u8]) {
+    buf.extend_from_slice(&(octets.len() as u32).to_be_bytes());
+    buf.extend_from_slice(octets);
+    buf.resize(buf.len() + (4 - octets.len() % 4) % 4, 0);
+}
+
+fn put_varbind(buf: &mut Vec<u8>, varbind: &VarBind) {
+    let (oid, value_type) = match varbind {
+        VarBind::Value(oid, Value::Integer(_)) => (oid, 2u16),
+        VarBind::Value(oid, Value::OctetString(_)) => (oid, 4),
+        VarBind::Value(oid, Value::Gauge32(_)) => (oid, 66),
+        VarBind::Exception(oid, exception) => (oid, *exception),
+    };
+    buf.extend_from_slice(&value_type.to_be_bytes());
+    buf.extend_from_slice(&[0, 0]);
+    put_oid(buf, oid, false);
+    match varbind {
+        VarBind::Value(_, Value::Integer(n)) => buf.extend_from_slice(&n.to_be_bytes()),
+        VarBind::Value(_, Value::OctetString(s)) => put_octets(buf, s.as_bytes()),
+        VarBind::Value(_, Value::Gauge32(n)) => buf.extend_from_slice(&n.to_be_bytes()),
+        VarBind::Exception(..) => {}
+    }
+}
+
+/// Reads a payload in the byte order of its header.
+struct Reader<'a> {
+    buf: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8], flags: u8) -> Self {
+        Reader {
+            buf,
+            big_endian: flags & NETWORK_BYTE_ORDER != 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < n {
+            bail!("truncated AgentX PDU");
+        }
+        let (taken, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?.try_into().unwrap();
+        Ok(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?.try_into().unwrap();
+        Ok(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    /// An OID and its `include` field; a non-zero prefix stands for `1.3.6.1.<prefix>`.
+    fn oid(&mut self) -> Result<(Vec<u32>, bool)> {
+        let head = self.take(4)?;
+        let (n_subid, prefix, include) = (head[0] as usize, head[1], head[2] != 0);
+        let mut oid = Vec::with_capacity(n_subid + 5);
+        if prefix != 0 {
+            oid.extend_from_slice(&[1, 3, 6, 1, prefix as u32]);
+        }
+        for _ in 0..n_subid {
+            oid.push(self.u32()?);
+        }
+        Ok((oid, include))
+    }
+
+    fn octets(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        let octets = self.take(len)?;
+        self.take((4 - len % 4) % 4)?;
+        Ok(octets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::push::Sample;
+    use async_std::net::TcpListener;
+    use std::time::SystemTime;
+
+    /// Bytes written as hex, with spaces between the fields.
+    fn hex(s: &str) -> Vec<u8> {
+        let digits = s.split_whitespace().collect::<String>();
+        (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    async fn expect(stream: &mut TcpStream, expected: &str) {
+        let expected = hex(expected);
+        let mut pdu = vec![0u8; expected.len()];
+        stream.read_exact(&mut pdu).await.unwrap();
+        assert_eq!(pdu, expected);
+    }
+
+    #[test]
+    fn parses_oids() {
+        let oid: Oid = ".1.3.6.1.4.1.8072.9999.9999".parse().unwrap();
+        assert_eq!(oid.0, [1, 3, 6, 1, 4, 1, 8072, 9999, 9999]);
+        assert_eq!(oid.to_string(), "1.3.6.1.4.1.8072.9999.9999");
+        for invalid in ["", "1", "1..3", "1.3.x", "1.3.-6", "1.3.4294967296"] {
+            assert!(invalid.parse::<Oid>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[async_std::test]
+    async fn speaks_agentx() {
+        let agentx = AgentX {
+            master: String::new(),
+            oid: vec![1, 3, 6, 1, 4, 1, 99],
+            objects: Arc::new(RwLock::new(Vec::new())),
+        };
+        let batch = Batch {
+            exposition: String::new(),
+            samples: vec![Sample {
+                name: "nvidia_temperature_gpu".to_string(),
+                labels: vec![
+                    ("gpu".to_string(), "0".to_string()),
+                    ("name".to_string(), "A100".to_string()),
+                ],
+                value: 45.0,
+            }],
+            timestamp: SystemTime::now(),
+        };
+        agentx.push(&batch).await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let objects = agentx.objects.clone();
+        let subagent = async_std::task::spawn(async move {
+            let stream = TcpStream::connect(address).await.unwrap();
+            serve(stream, &[1, 3, 6, 1, 4, 1, 99], &objects).await
+        });
+        let (mut master, _) = listener.accept().await.unwrap();
+        let oid = "00000001 00000003 00000006 00000001 00000004 00000001 00000063";
+
+        // Open，描述为包名，补齐到 4 字节
+        expect(
+            &mut master,
+            &format!(
+                "01 01 10 00 00000000 00000000 00000001 0000003c
+                 00 000000 07000000 {}
+                 00000013 {} 00",
+                oid, "6e766964 69612d73 6d692d65 78706f72 746572"
+            ),
+        )
+        .await;
+        let response = "01 12 10 00 0000002a 00000000 00000001 00000008 00000000 0000 0000";
+        master.write_all(&hex(response)).await.unwrap();
+        // Register，优先级 127
+        expect(
+            &mut master,
+            &format!(
+                "01 03 10 00 0000002a 00000000 00000002 00000024 00 7f 00 00 07000000 {}",
+                oid
+            ),
+        )
+        .await;
+        let response = "01 12 10 00 0000002a 00000000 00000002 00000008 00000000 0000 0000";
+        master.write_all(&hex(response)).await.unwrap();
+
+        // 小端的 GetNext，OID 用 1.3.6.1.4 前缀
+        let get_next = "01 06 00 00 2a000000 05000000 07000000 10000000
+                        02 04 00 00 01000000 63000000 00 00 00 00";
+        master.write_all(&hex(get_next)).await.unwrap();
+        expect(
+            &mut master,
+            &format!(
+                "01 12 10 00 0000002a 00000005 00000007 00000040
+                 00000000 0000 0000
+                 0002 0000 0b000000 {} 00000001 00000001 00000001 00000001 00000000",
+                oid
+            ),
+        )
+        .await;
+
+        let get = "01 05 10 00 0000002a 00000006 00000008 00000040
+                   06 04 00 00 00000001 00000063 00000001 00000001 00000002 00000001 00000000
+                   06 04 00 00 00000001 00000063 00000001 00000001 00000003 00000001 00000000";
+        master.write_all(&hex(get)).await.unwrap();
+        expect(
+            &mut master,
+            &format!(
+                "01 12 10 00 0000002a 00000006 00000008 00000078
+                 00000000 0000 0000
+                 0004 0000 0b000000 {} 00000001 00000001 00000002 00000001 00000004 41313030
+                 0081 0000 0b000000 {} 00000001 00000001 00000003 00000001",
+                oid, oid
+            ),
+        )
+        .await;
+
+        let close = "01 02 10 00 0000002a 00000000 00000009 00000004 01 000000";
+        master.write_all(&hex(close)).await.unwrap();
+        let ended = subagent.await.unwrap_err();
+        assert_eq!(ended.to_string(), "closed by the master agent");
+    }
+}
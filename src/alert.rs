@@ -0,0 +1,516 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use surf::Url;
+use tracing::{error, info};
+
+use crate::cli::Cli;
+use crate::push::{Batch, Target};
+
+#[derive(Clone, Copy, Debug, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// The payload of Alertmanager's `webhook_config`, on every change.
+    Webhook,
+    /// Alertmanager's `/api/v2/alerts`, with the firing alerts on every evaluation.
+    Alertmanager,
+    /// A Slack incoming webhook message, on every change.
+    Slack,
+}
+
+/// One rule of `--alert.rules-file`: a threshold on a metric, or a minimum number of GPUs.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub alert: String,
+    #[serde(default)]
+    pub metric: Option<String>,
+    #[serde(default)]
+    pub op: Op,
+    #[serde(default)]
+    pub threshold: Option<f64>,
+    /// Fires when fewer GPUs than this are reported, including when nvidia-smi fails.
+    #[serde(default)]
+    pub gpus: Option<usize>,
+    /// Seconds the condition must hold before the alert fires.
+    #[serde(default, rename = "for")]
+    pub for_seconds: u64,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// With `{value}` and `{<label>}` placeholders.
+    #[serde(default)]
+    pub summary: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub enum Op {
+    #[default]
+    #[serde(rename = ">")]
+    Gt,
+    #[serde(rename = ">=")]
+    Ge,
+    #[serde(rename = "<")]
+    Lt,
+    #[serde(rename = "<=")]
+    Le,
+    #[serde(rename = "==")]
+    Eq,
+    #[serde(rename = "!=")]
+    Ne,
+}
+
+impl Op {
+    fn holds(self, value: f64, threshold: f64) -> bool {
+        match self {
+            Op::Gt => value > threshold,
+            Op::Ge => value >= threshold,
+            Op::Lt => value < threshold,
+            Op::Le => value <= threshold,
+            Op::Eq => value == threshold,
+            Op::Ne => value != threshold,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Eq => "==",
+            Op::Ne => "!=",
+        }
+    }
+}
+
+pub fn from_file(path: &str) -> Result<Vec<RuleConfig>> {
+    let content =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+    let rules: Vec<RuleConfig> =
+        serde_yaml::from_str(&content).with_context(|| format!("Invalid alert rules {}", path))?;
+    for rule in &rules {
+        match (&rule.metric, rule.threshold, rule.gpus) {
+            (Some(_), Some(_), None) | (None, None, Some(_)) => {}
+            _ => bail!(
+                "Alert {} needs either metric and threshold, or gpus",
+                rule.alert
+            ),
+        }
+    }
+    Ok(rules)
+}
+
+/// An alert by its labels, pending until its rule's `for` has passed.
+#[derive(Clone, Debug)]
+struct Active {
+    since: SystemTime,
+    firing: bool,
+    value: f64,
+    summary: String,
+}
+
+struct Rules {
+    rules: Vec<RuleConfig>,
+    modified: Option<SystemTime>,
+}
+
+/// Evaluates `--alert.rules-file` on every push and notifies `--alert.webhook-url`. The rules
+/// file is re-read when it changes.
+pub struct Alerter {
+    url: Url,
+    format: Format,
+    rules_file: String,
+    rules: Mutex<Rules>,
+    /// `job` and `instance`.
+    labels: Vec<(String, String)>,
+    active: Mutex<BTreeMap<Vec<(String, String)>, Active>>,
+}
+
+impl Alerter {
+    pub fn new(url: &str, cli: &Cli, labels: Vec<(String, String)>) -> Result<Self> {
+        let url =
+            Url::parse(url).with_context(|| format!("Invalid --alert.webhook-url {}", url))?;
+        let rules_file = cli.alert_rules_file.clone().unwrap_or_default();
+        let rules = Rules {
+            modified: modified(&rules_file),
+            rules: from_file(&rules_file)?,
+        };
+        Ok(Alerter {
+            url,
+            format: cli.alert_webhook_format,
+            rules_file,
+            rules: Mutex::new(rules),
+            labels,
+            active: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    fn rules(&self) -> Vec<RuleConfig> {
+        let mut rules = self.rules.lock().unwrap();
+        let modified = modified(&self.rules_file);
+        if modified != rules.modified {
+            // 读取失败时继续用旧的规则
+            match from_file(&self.rules_file) {
+                Ok(new) => {
+                    info!("Reloaded alert rules {}", self.rules_file);
+                    rules.rules = new;
+                    rules.modified = modified;
+                }
+                Err(e) => error!("Failed to reload alert rules, {:#}", e),
+            }
+        }
+        rules.rules.clone()
+    }
+
+    /// The alerts whose condition holds now, by their labels, with their value and summary.
+    fn evaluate(&self, batch: &Batch) -> BTreeMap<Vec<(String, String)>, (u64, f64, String)> {
+        let mut alerts = BTreeMap::new();
+        for rule in self.rules() {
+            let mut matches = Vec::new();
+            match (&rule.metric, rule.threshold, rule.gpus) {
+                (Some(metric), Some(threshold), _) => {
+                    for sample in &batch.samples {
+                        if &sample.name == metric && rule.op.holds(sample.value, threshold) {
+                            matches.push((sample.labels.clone(), sample.value));
+                        }
+                    }
+                }
+                (_, _, Some(expected)) => {
+                    let gpus = batch
+                        .samples
+                        .iter()
+                        .filter_map(|s| s.labels.iter().find(|(name, _)| name == "gpu"))
+                        .map(|(_, gpu)| gpu)
+                        .collect::<BTreeSet<_>>();
+                    if gpus.len() < expected {
+                        matches.push((Vec::new(), gpus.len() as f64));
+                    }
+                }
+                _ => {}
+            }
+            for (sample_labels, value) in matches {
+                let mut labels = BTreeMap::new();
+                labels.insert("alertname".to_string(), rule.alert.clone());
+                labels.extend(self.labels.iter().cloned());
+                labels.extend(sample_labels);
+                labels.extend(rule.labels.clone());
+                let summary = match &rule.summary {
+                    Some(summary) => labels.iter().fold(
+                        summary.replace("{value}", &value.to_string()),
+                        |summary, (name, label)| summary.replace(&format!("{{{}}}", name), label),
+                    ),
+                    None => match (&rule.metric, rule.threshold, rule.gpus) {
+                        (Some(metric), Some(threshold), _) => format!(
+                            "{} is {} ({} {})",
+                            metric,
+                            value,
+                            rule.op.symbol(),
+                            threshold
+                        ),
+                        (_, _, gpus) => {
+                            format!("{} of {} GPUs reported", value, gpus.unwrap_or_default())
+                        }
+                    },
+                };
+                alerts.insert(
+                    labels.into_iter().collect(),
+                    (rule.for_seconds, value, summary),
+                );
+            }
+        }
+        alerts
+    }
+
+    async fn send(&self, body: Value) -> Result<()> {
+        let mut response = surf::post(self.url.clone())
+            .body(body)
+            .await
+            .map_err(|e| e.into_inner())?;
+        if !response.status().is_success() {
+            let text = response.body_string().await.unwrap_or_default();
+            bail!("{} {}", response.status(), text.trim());
+        }
+        Ok(())
+    }
+}
+
+#[tide::utils::async_trait]
+impl Target for Alerter {
+    fn name(&self) -> &'static str {
+        "alerts"
+    }
+
+    async fn push(&self, batch: &Batch) -> Result<()> {
+        let now = batch.timestamp;
+        let current = self.evaluate(batch);
+        let mut active = self.active.lock().unwrap().clone();
+        // 新触发的和恢复的告警，只在变化时通知 webhook 和 Slack
+        let mut fired = Vec::new();
+        let mut resolved = Vec::new();
+        active.retain(|labels, alert| {
+            let keep = current.contains_key(labels);
+            if !keep && alert.firing {
+                resolved.push((labels.clone(), alert.clone()));
+            }
+            keep
+        });
+        for (labels, (for_seconds, value, summary)) in current {
+            let alert = active.entry(labels.clone()).or_insert(Active {
+                since: now,
+                firing: false,
+                value,
+                summary: String::new(),
+            });
+            alert.value = value;
+            alert.summary = summary;
+            let held = now.duration_since(alert.since).unwrap_or_default();
+            if !alert.firing && held >= Duration::from_secs(for_seconds) {
+                alert.firing = true;
+                fired.push((labels, alert.clone()));
+            }
+        }
+
+        let firing = active
+            .iter()
+            .filter(|(_, alert)| alert.firing)
+            .map(|(labels, alert)| (labels.clone(), alert.clone()))
+            .collect::<Vec<_>>();
+        let body = match self.format {
+            Format::Alertmanager if !firing.is_empty() || !resolved.is_empty() => {
+                Some(Value::Array(
+                    firing
+                        .iter()
+                        .map(|(labels, alert)| alert_json(labels, alert, None))
+                        .chain(
+                            resolved
+                                .iter()
+                                .map(|(labels, alert)| alert_json(labels, alert, Some(now))),
+                        )
+                        .collect(),
+                ))
+            }
+            Format::Webhook if !fired.is_empty() || !resolved.is_empty() => {
+                let alerts =
+                    fired
+                        .iter()
+                        .map(|(labels, alert)| (alert_json(labels, alert, None), "firing"))
+                        .chain(resolved.iter().map(|(labels, alert)| {
+                            (alert_json(labels, alert, Some(now)), "resolved")
+                        }))
+                        .map(|(mut alert, status)| {
+                            alert["status"] = status.into();
+                            alert
+                        })
+                        .collect::<Vec<_>>();
+                Some(json!({
+                    "version": "4",
+                    "status": if fired.is_empty() { "resolved" } else { "firing" },
+                    "receiver": "nvidia-smi-exporter",
+                    "groupLabels": {},
+                    "commonLabels": {},
+                    "commonAnnotations": {},
+                    "externalURL": "",
+                    "alerts": alerts,
+                }))
+            }
+            Format::Slack if !fired.is_empty() || !resolved.is_empty() => {
+                let line = |status: &str, labels: &[(String, String)], alert: &Active| {
+                    let label = |name: &str| {
+                        labels
+                            .iter()
+                            .find(|(n, _)| n == name)
+                            .map_or("", |(_, value)| value.as_str())
+                    };
+                    let gpu = match label("gpu") {
+                        "" => String::new(),
+                        gpu => format!(" GPU {}", gpu),
+                    };
+                    format!(
+                        "[{}] *{}* on {}{}: {}",
+                        status,
+                        label("alertname"),
+                        label("instance"),
+                        gpu,
+                        alert.summary
+                    )
+                };
+                let text = fired
+                    .iter()
+                    .map(|(labels, alert)| line("FIRING", labels, alert))
+                    .chain(
+                        resolved
+                            .iter()
+                            .map(|(labels, alert)| line("RESOLVED", labels, alert)),
+                    )
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                Some(json!({ "text": text }))
+            }
+            _ => None,
+        };
+        if let Some(body) = body {
+            self.send(body).await?;
+        }
+        // 发送成功后才记录，失败的通知下次再发
+        *self.active.lock().unwrap() = active;
+        Ok(())
+    }
+}
+
+/// An alert as Alertmanager takes and sends it, resolved when `ends_at` is given.
+fn alert_json(labels: &[(String, String)], alert: &Active, ends_at: Option<SystemTime>) -> Value {
+    let labels = labels
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone().into()))
+        .collect::<Map<_, _>>();
+    let mut alert = json!({
+        "labels": labels,
+        "annotations": { "summary": alert.summary },
+        "startsAt": rfc3339(alert.since),
+    });
+    if let Some(ends_at) = ends_at {
+        alert["endsAt"] = rfc3339(ends_at).into();
+    }
+    alert
+}
+
+fn rfc3339(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::push::Sample;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn rules(yaml: &str) -> Result<Vec<RuleConfig>> {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "nvidia-smi-exporter-alert-{}-{}.yml",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, yaml).unwrap();
+        let rules = from_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        rules
+    }
+
+    fn alerter(rules: Vec<RuleConfig>) -> Alerter {
+        Alerter {
+            url: Url::parse("http://127.0.0.1:9093/api/v2/alerts").unwrap(),
+            format: Format::Alertmanager,
+            rules_file: String::new(),
+            rules: Mutex::new(Rules {
+                rules,
+                modified: None,
+            }),
+            labels: vec![("instance".to_string(), "node1:9835".to_string())],
+            active: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn sample(name: &str, gpu: &str, value: f64) -> Sample {
+        Sample {
+            name: name.to_string(),
+            labels: vec![("gpu".to_string(), gpu.to_string())],
+            value,
+        }
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rules = rules(
+            "- alert: GpuHot\n  metric: nvidia_temperature_gpu\n  op: '>='\n  threshold: 85\n  for: 60\n  labels: {severity: warning}\n  summary: GPU {gpu} is {value}C\n- alert: GpuMissing\n  gpus: 8\n",
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].op, Op::Ge);
+        assert_eq!(rules[0].threshold, Some(85.0));
+        assert_eq!(rules[0].for_seconds, 60);
+        assert_eq!(rules[0].labels["severity"], "warning");
+        assert_eq!(rules[1].op, Op::Gt);
+        assert_eq!(rules[1].gpus, Some(8));
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        for yaml in [
+            "- alert: NoCondition\n",
+            "- alert: NoThreshold\n  metric: nvidia_temperature_gpu\n",
+            "- alert: Both\n  metric: nvidia_temperature_gpu\n  threshold: 85\n  gpus: 8\n",
+            "- alert: BadOp\n  metric: nvidia_temperature_gpu\n  op: '=>'\n  threshold: 85\n",
+            "- alert: Unknown\n  gpus: 8\n  severity: warning\n",
+            "alert: NotAList\n",
+        ] {
+            assert!(rules(yaml).is_err(), "{}", yaml);
+        }
+    }
+
+    #[test]
+    fn holds() {
+        assert!(Op::Gt.holds(86.0, 85.0) && !Op::Gt.holds(85.0, 85.0));
+        assert!(Op::Ge.holds(85.0, 85.0) && !Op::Ge.holds(84.0, 85.0));
+        assert!(Op::Lt.holds(84.0, 85.0) && !Op::Lt.holds(85.0, 85.0));
+        assert!(Op::Le.holds(85.0, 85.0) && !Op::Le.holds(86.0, 85.0));
+        assert!(Op::Eq.holds(85.0, 85.0) && !Op::Eq.holds(86.0, 85.0));
+        assert!(Op::Ne.holds(86.0, 85.0) && !Op::Ne.holds(85.0, 85.0));
+    }
+
+    #[test]
+    fn evaluates_rules() {
+        let alerter = alerter(
+            rules(
+                "- alert: GpuHot\n  metric: nvidia_temperature_gpu\n  threshold: 85\n  summary: GPU {gpu} is {value}C\n- alert: GpuMissing\n  gpus: 4\n  labels: {severity: critical}\n",
+            )
+            .unwrap(),
+        );
+        let batch = Batch {
+            exposition: String::new(),
+            samples: vec![
+                sample("nvidia_temperature_gpu", "0", 90.0),
+                sample("nvidia_temperature_gpu", "1", 85.0),
+                sample("nvidia_power_draw", "1", 300.0),
+            ],
+            timestamp: SystemTime::now(),
+        };
+        let alerts = alerter.evaluate(&batch).into_iter().collect::<Vec<_>>();
+        let labels = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            alerts,
+            vec![
+                (
+                    labels(&[
+                        ("alertname", "GpuHot"),
+                        ("gpu", "0"),
+                        ("instance", "node1:9835")
+                    ]),
+                    (0, 90.0, "GPU 0 is 90C".to_string())
+                ),
+                (
+                    labels(&[
+                        ("alertname", "GpuMissing"),
+                        ("instance", "node1:9835"),
+                        ("severity", "critical")
+                    ]),
+                    (0, 2.0, "2 of 4 GPUs reported".to_string())
+                ),
+            ]
+        );
+    }
+}
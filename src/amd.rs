@@ -0,0 +1,495 @@
+use anyhow::Result;
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge, IntGauge};
+use serde_json::Value;
+use std::io::ErrorKind;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+use crate::cli::Cli;
+use crate::collector;
+use crate::gpu::Gpu;
+
+/// How long the names and UUIDs from `amd-smi list` and `amd-smi static` are reused.
+const STATIC_TTL: Duration = Duration::from_secs(600);
+
+/// The JSON paths of each metric in `amd-smi metric --json`, the first one present wins.
+/// amd-smi reports memory in MB, which are MiB like nvidia-smi's.
+const AMD_SMI_METRICS: &[(&str, &[&[&str]])] = &[
+    ("nvidia_fan_speed", &[&["fan", "usage"]]),
+    (
+        "nvidia_temperature_gpu",
+        // MI300 等没有 edge 传感器
+        &[&["temperature", "edge"], &["temperature", "hotspot"]],
+    ),
+    ("nvidia_clocks_gr", &[&["clock", "gfx_0", "clk"]]),
+    ("nvidia_clocks_mem", &[&["clock", "mem_0", "clk"]]),
+    (
+        "nvidia_power_draw",
+        &[
+            &["power", "socket_power"],
+            &["power", "average_socket_power"],
+        ],
+    ),
+    ("nvidia_utilization_gpu", &[&["usage", "gfx_activity"]]),
+    ("nvidia_utilization_memory", &[&["usage", "umc_activity"]]),
+    ("nvidia_memory_total", &[&["mem_usage", "total_vram"]]),
+    ("nvidia_memory_free", &[&["mem_usage", "free_vram"]]),
+    ("nvidia_memory_used", &[&["mem_usage", "used_vram"]]),
+];
+
+/// The keys of each metric in `rocm-smi --json`, the first one present wins, and the factor
+/// to MiB for memory in bytes.
+const ROCM_SMI_METRICS: &[(&str, &[&str], f64)] = &[
+    ("nvidia_fan_speed", &["Fan speed (%)"], 1.0),
+    (
+        "nvidia_temperature_gpu",
+        &[
+            "Temperature (Sensor edge) (C)",
+            "Temperature (Sensor junction) (C)",
+        ],
+        1.0,
+    ),
+    ("nvidia_clocks_gr", &["sclk clock speed:"], 1.0),
+    ("nvidia_clocks_mem", &["mclk clock speed:"], 1.0),
+    (
+        "nvidia_power_draw",
+        &[
+            "Average Graphics Package Power (W)",
+            "Current Socket Graphics Package Power (W)",
+        ],
+        1.0,
+    ),
+    ("nvidia_utilization_gpu", &["GPU use (%)"], 1.0),
+    ("nvidia_utilization_memory", &["GPU memory use (%)"], 1.0),
+    (
+        "nvidia_memory_total",
+        &["VRAM Total Memory (B)"],
+        1.0 / 1048576.0,
+    ),
+    (
+        "nvidia_memory_used",
+        &["VRAM Total Used Memory (B)"],
+        1.0 / 1048576.0,
+    ),
+];
+
+lazy_static! {
+    /// The tool found on the first collection without `--collect.amd.command`.
+    static ref TOOL: Mutex<Option<Tool>> = Mutex::new(None);
+    /// The GPUs of `amd-smi list`, without readings.
+    static ref STATIC: Mutex<Option<(Instant, Vec<Gpu>)>> = Mutex::new(None);
+    static ref UP: IntGauge = register_int_gauge!(
+        "nvidia_smi_exporter_amd_smi_up",
+        "Whether amd-smi or rocm-smi answered the last collection of AMD GPUs."
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Tool {
+    AmdSmi,
+    RocmSmi,
+}
+
+/// `--collect.amd`: AMD GPUs read with amd-smi, or rocm-smi on ROCm before 6.
+#[derive(Clone, Debug)]
+pub struct Amd {
+    /// `--collect.amd.command`, or None to use amd-smi and fall back to rocm-smi.
+    command: Option<String>,
+}
+
+impl Amd {
+    pub fn from_cli(cli: &Cli) -> Option<Self> {
+        cli.collect_amd.then(|| Amd {
+            command: cli.collect_amd_command.clone(),
+        })
+    }
+
+    /// The AMD GPUs, as `Gpu`s whose values are named like the NVIDIA metrics they match.
+    async fn gpus(&self, collect_timeout: Duration) -> Result<Vec<Gpu>> {
+        if let Some(command) = &self.command {
+            let tool = match command.rsplit('/').next().unwrap_or_default() {
+                name if name.starts_with("rocm-smi") => Tool::RocmSmi,
+                _ => Tool::AmdSmi,
+            };
+            return read(tool, command, collect_timeout).await;
+        }
+        let tool = *TOOL.lock().unwrap();
+        match tool {
+            Some(Tool::AmdSmi) => read(Tool::AmdSmi, "amd-smi", collect_timeout).await,
+            Some(Tool::RocmSmi) => read(Tool::RocmSmi, "rocm-smi", collect_timeout).await,
+            None => {
+                let result = match read(Tool::AmdSmi, "amd-smi", collect_timeout).await {
+                    Err(e) if not_found(&e) => {
+                        debug!("amd-smi is not installed, trying rocm-smi");
+                        read(Tool::RocmSmi, "rocm-smi", collect_timeout)
+                            .await
+                            .map(|gpus| (Tool::RocmSmi, gpus))
+                    }
+                    result => result.map(|gpus| (Tool::AmdSmi, gpus)),
+                };
+                let (tool, gpus) = result?;
+                *TOOL.lock().unwrap() = Some(tool);
+                Ok(gpus)
+            }
+        }
+    }
+}
+
+/// The AMD GPUs, within `collect_timeout`, or none if `--collect.amd` is not given or neither
+/// tool answers; exported as `nvidia_smi_exporter_amd_smi_up`.
+pub async fn collect(amd: Option<&Amd>, collect_timeout: Duration) -> Vec<Gpu> {
+    let amd = match amd {
+        Some(amd) => amd,
+        None => return Vec::new(),
+    };
+    let result = amd.gpus(collect_timeout).await;
+    UP.set(result.is_ok() as i64);
+    result.unwrap_or_else(|e| {
+        warn!("Failed to collect AMD GPUs, {:#}", e);
+        Vec::new()
+    })
+}
+
+/// Whether any AMD GPU can be read, for `/readyz`.
+pub async fn check_ready(amd: &Amd, collect_timeout: Duration) -> Result<(), String> {
+    match amd.gpus(collect_timeout).await {
+        Ok(gpus) if !gpus.is_empty() => Ok(()),
+        Ok(_) => Err("No AMD GPU found".to_string()),
+        Err(e) => Err(format!("{:#}", e)),
+    }
+}
+
+fn not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == ErrorKind::NotFound)
+}
+
+async fn read(tool: Tool, program: &str, collect_timeout: Duration) -> Result<Vec<Gpu>> {
+    match tool {
+        Tool::AmdSmi => read_amd_smi(program, collect_timeout).await,
+        Tool::RocmSmi => read_rocm_smi(program, collect_timeout).await,
+    }
+}
+
+/// One entry per GPU; newer amd-smi wraps them in `gpu_data`.
+fn entries(value: &Value) -> Vec<&Value> {
+    match value.as_array() {
+        Some(entries) => entries.iter().collect(),
+        None => value["gpu_data"].as_array().into_iter().flatten().collect(),
+    }
+}
+
+fn index(entry: &Value) -> String {
+    match &entry["gpu"] {
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => s.clone(),
+        _ => String::new(),
+    }
+}
+
+/// The index, UUID and name of each GPU, from `amd-smi list` and `amd-smi static --asic`.
+async fn amd_smi_static(program: &str, collect_timeout: Duration) -> Result<Vec<Gpu>> {
+    if let Some((at, gpus)) = &*STATIC.lock().unwrap() {
+        if at.elapsed() < STATIC_TTL {
+            return Ok(gpus.clone());
+        }
+    }
+    let list = collector::json(program, &["list", "--json"], collect_timeout).await?;
+    let asic = collector::json(program, &["static", "--asic", "--json"], collect_timeout).await?;
+    let gpus = static_gpus(&list, &asic);
+    *STATIC.lock().unwrap() = Some((Instant::now(), gpus.clone()));
+    Ok(gpus)
+}
+
+/// The GPUs of `amd-smi list --json`, named after `amd-smi static --asic --json`.
+fn static_gpus(list: &Value, asic: &Value) -> Vec<Gpu> {
+    entries(list)
+        .into_iter()
+        .map(|entry| {
+            let index = index(entry);
+            let name = entries(asic)
+                .into_iter()
+                .find(|asic| self::index(asic) == index)
+                .and_then(|asic| asic["asic"]["market_name"].as_str())
+                .unwrap_or_default()
+                .to_string();
+            let uuid = match entry["uuid"].as_str() {
+                Some(uuid) => uuid.to_string(),
+                None => format!("amd:{}", index),
+            };
+            Gpu {
+                index,
+                uuid,
+                name,
+                driver_version: String::new(),
+                values: Vec::new(),
+                host: None,
+            }
+        })
+        .collect()
+}
+
+async fn read_amd_smi(program: &str, collect_timeout: Duration) -> Result<Vec<Gpu>> {
+    let gpus = amd_smi_static(program, collect_timeout).await?;
+    let args = [
+        "metric",
+        "--usage",
+        "--power",
+        "--clock",
+        "--temperature",
+        "--fan",
+        "--mem-usage",
+        "--json",
+    ];
+    let metrics = collector::json(program, &args, collect_timeout).await?;
+    Ok(amd_smi_gpus(&gpus, &metrics))
+}
+
+/// The readings of `amd-smi metric --json` of `gpus`.
+fn amd_smi_gpus(gpus: &[Gpu], metrics: &Value) -> Vec<Gpu> {
+    entries(metrics)
+        .into_iter()
+        .map(|entry| {
+            let index = index(entry);
+            let gpu = gpus.iter().find(|gpu| gpu.index == index).cloned();
+            let values = AMD_SMI_METRICS
+                .iter()
+                .filter_map(|(metric, paths)| {
+                    let value = paths.iter().find_map(|path| {
+                        collector::json_number(path.iter().fold(entry, |value, key| &value[*key]))
+                    })?;
+                    Some((metric.to_string(), value))
+                })
+                .collect();
+            Gpu {
+                values,
+                ..gpu.unwrap_or_else(|| Gpu {
+                    uuid: format!("amd:{}", index),
+                    index,
+                    name: String::new(),
+                    driver_version: String::new(),
+                    values: Vec::new(),
+                    host: None,
+                })
+            }
+        })
+        .collect()
+}
+
+async fn read_rocm_smi(program: &str, collect_timeout: Duration) -> Result<Vec<Gpu>> {
+    let args = [
+        "--showtemp",
+        "--showuse",
+        "--showmemuse",
+        "--showpower",
+        "--showclocks",
+        "--showfan",
+        "--showmeminfo",
+        "vram",
+        "--showproductname",
+        "--showuniqueid",
+        "--json",
+    ];
+    let cards = collector::json(program, &args, collect_timeout).await?;
+    Ok(rocm_smi_gpus(&cards))
+}
+
+/// The GPUs of `rocm-smi --json`, one `cardN` key each.
+fn rocm_smi_gpus(cards: &Value) -> Vec<Gpu> {
+    let mut gpus = Vec::new();
+    for (card, entry) in cards.as_object().into_iter().flatten() {
+        let index = match card.strip_prefix("card") {
+            Some(index) => index.to_string(),
+            None => continue,
+        };
+        let mut values = ROCM_SMI_METRICS
+            .iter()
+            .filter_map(|(metric, keys, factor)| {
+                let value = keys
+                    .iter()
+                    .find_map(|key| collector::json_number(&entry[*key]))?;
+                Some((metric.to_string(), value * factor))
+            })
+            .collect::<Vec<(String, f64)>>();
+        let memory = |metric: &str| values.iter().find(|(m, _)| m == metric).map(|(_, v)| *v);
+        if let (Some(total), Some(used)) =
+            (memory("nvidia_memory_total"), memory("nvidia_memory_used"))
+        {
+            values.push(("nvidia_memory_free".to_string(), total - used));
+        }
+        let name = ["Card Series", "Card series", "Card model"]
+            .iter()
+            .find_map(|key| entry[*key].as_str())
+            .unwrap_or_default();
+        let uuid = match entry["Unique ID"].as_str() {
+            Some(id) if id != "N/A" => id.to_string(),
+            _ => format!("amd:{}", index),
+        };
+        gpus.push(Gpu {
+            index,
+            uuid,
+            name: name.to_string(),
+            driver_version: String::new(),
+            values,
+            host: None,
+        });
+    }
+    gpus.sort_by_key(|gpu| gpu.index.parse::<u32>().unwrap_or(u32::MAX));
+    gpus
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `amd-smi list --json` and `amd-smi static --asic --json` of an MI300X node, ROCm 6.1.
+    const LIST: &str = r#"[
+        {"gpu": 0, "bdf": "0000:0c:00.0", "uuid": "e2ff74a1-0000-1000-8041-93f3b4c2e5a8", "kfd_id": 42924, "node_id": 2, "partition_id": 0},
+        {"gpu": 1, "bdf": "0000:22:00.0", "uuid": "1dff74a1-0000-1000-80b3-5d8a9e0c7f21", "kfd_id": 17753, "node_id": 3, "partition_id": 0}
+    ]"#;
+    const ASIC: &str = r#"[
+        {"gpu": 0, "asic": {"market_name": "AMD Instinct MI300X", "vendor_id": "0x1002", "vendor_name": "Advanced Micro Devices Inc. [AMD/ATI]", "device_id": "0x74a1", "rev_id": "0x00", "asic_serial": "0xE2A1B3C4D5E6F708", "oam_id": 5}},
+        {"gpu": 1, "asic": {"market_name": "AMD Instinct MI300X", "vendor_id": "0x1002", "vendor_name": "Advanced Micro Devices Inc. [AMD/ATI]", "device_id": "0x74a1", "rev_id": "0x00", "asic_serial": "0x1DA2B3C4D5E6F708", "oam_id": 4}}
+    ]"#;
+
+    /// `amd-smi metric --json` of the same node: no edge sensor or fan, ROCm 6.3 wraps the GPUs in
+    /// `gpu_data`.
+    const METRIC: &str = r#"{"gpu_data": [
+        {
+            "gpu": 0,
+            "usage": {"gfx_activity": {"value": 37, "unit": "%"}, "umc_activity": {"value": 12, "unit": "%"}, "mm_activity": "N/A"},
+            "power": {"socket_power": {"value": 418, "unit": "W"}, "gfx_voltage": "N/A", "soc_voltage": "N/A", "mem_voltage": "N/A", "throttle_status": "N/A", "power_management": "ENABLED"},
+            "clock": {"gfx_0": {"clk": {"value": 2100, "unit": "MHz"}, "min_clk": {"value": 500, "unit": "MHz"}, "max_clk": {"value": 2100, "unit": "MHz"}, "clk_locked": "N/A", "deep_sleep": "DISABLED"}, "mem_0": {"clk": {"value": 1300, "unit": "MHz"}, "min_clk": {"value": 900, "unit": "MHz"}, "max_clk": {"value": 1300, "unit": "MHz"}, "clk_locked": "N/A", "deep_sleep": "DISABLED"}},
+            "temperature": {"edge": "N/A", "hotspot": {"value": 64, "unit": "C"}, "mem": {"value": 51, "unit": "C"}},
+            "fan": {"speed": "N/A", "max": "N/A", "rpm": "N/A", "usage": "N/A"},
+            "mem_usage": {"total_vram": {"value": 196592, "unit": "MB"}, "used_vram": {"value": 147210, "unit": "MB"}, "free_vram": {"value": 49382, "unit": "MB"}, "total_visible_vram": {"value": 196592, "unit": "MB"}, "used_visible_vram": {"value": 147210, "unit": "MB"}, "free_visible_vram": {"value": 49382, "unit": "MB"}, "total_gtt": {"value": 128716, "unit": "MB"}, "used_gtt": {"value": 20, "unit": "MB"}, "free_gtt": {"value": 128696, "unit": "MB"}}
+        },
+        {
+            "gpu": 1,
+            "usage": "N/A",
+            "power": {"socket_power": "N/A"},
+            "clock": {"gfx_0": {"clk": {"value": 132, "unit": "MHz"}}},
+            "temperature": {"edge": "N/A", "hotspot": {"value": 38, "unit": "C"}}
+        },
+        {
+            "gpu": 2,
+            "usage": {"gfx_activity": {"value": 0, "unit": "%"}}
+        }
+    ]}"#;
+
+    #[test]
+    fn parses_amd_smi() {
+        let list = serde_json::from_str(LIST).unwrap();
+        let gpus = static_gpus(&list, &serde_json::from_str(ASIC).unwrap());
+        assert_eq!(gpus.len(), 2);
+        assert_eq!(gpus[1].index, "1");
+        assert_eq!(gpus[1].uuid, "1dff74a1-0000-1000-80b3-5d8a9e0c7f21");
+        assert_eq!(gpus[1].name, "AMD Instinct MI300X");
+        // 没有 static 信息时名字为空
+        let gpus_without_names = static_gpus(&list, &Value::Null);
+        assert_eq!(gpus_without_names[0].name, "");
+
+        let gpus = amd_smi_gpus(&gpus, &serde_json::from_str(METRIC).unwrap());
+        assert_eq!(gpus.len(), 3);
+        let gpu = &gpus[0];
+        assert_eq!(gpu.uuid, "e2ff74a1-0000-1000-8041-93f3b4c2e5a8");
+        assert_eq!(gpu.value("nvidia_utilization_gpu"), Some(37.0));
+        assert_eq!(gpu.value("nvidia_utilization_memory"), Some(12.0));
+        assert_eq!(gpu.value("nvidia_power_draw"), Some(418.0));
+        assert_eq!(gpu.value("nvidia_clocks_gr"), Some(2100.0));
+        assert_eq!(gpu.value("nvidia_clocks_mem"), Some(1300.0));
+        // edge 为 N/A 时用 hotspot
+        assert_eq!(gpu.value("nvidia_temperature_gpu"), Some(64.0));
+        assert_eq!(gpu.value("nvidia_fan_speed"), None);
+        assert_eq!(gpu.value("nvidia_memory_total"), Some(196592.0));
+        assert_eq!(gpu.value("nvidia_memory_used"), Some(147210.0));
+        assert_eq!(gpu.value("nvidia_memory_free"), Some(49382.0));
+
+        let gpu = &gpus[1];
+        assert_eq!(gpu.values.len(), 2, "{:?}", gpu.values);
+        assert_eq!(gpu.value("nvidia_utilization_gpu"), None);
+        assert_eq!(gpu.value("nvidia_power_draw"), None);
+        assert_eq!(gpu.value("nvidia_clocks_gr"), Some(132.0));
+
+        // 不在 list 里的 GPU 也导出，以索引为 UUID
+        assert_eq!(gpus[2].uuid, "amd:2");
+        assert_eq!(gpus[2].name, "");
+    }
+
+    /// `rocm-smi --showtemp … --json` of a Radeon PRO W6800 and an MI210 on ROCm 5.7; the
+    /// W6800 has no unique ID, the MI210 no edge sensor or fan.
+    const ROCM_SMI: &str = r#"{
+        "card1": {
+            "Temperature (Sensor edge) (C)": "N/A",
+            "Temperature (Sensor junction) (C)": "45.0",
+            "Temperature (Sensor memory) (C)": "48.0",
+            "fclk clock speed:": "(1600Mhz)",
+            "mclk clock speed:": "(1600Mhz)",
+            "sclk clock speed:": "(800Mhz)",
+            "socclk clock speed:": "(1090Mhz)",
+            "Fan speed (%)": "N/A",
+            "Average Graphics Package Power (W)": "43.0",
+            "GPU use (%)": "2",
+            "GPU memory use (%)": "0",
+            "VRAM Total Memory (B)": "68702699520",
+            "VRAM Total Used Memory (B)": "10960896",
+            "Card series": "0x740f",
+            "Card model": "0x0c34",
+            "Card vendor": "Advanced Micro Devices, Inc. [AMD/ATI]",
+            "Card SKU": "D67301",
+            "Unique ID": "0x4fb1a2c3d4e5f607"
+        },
+        "card0": {
+            "Temperature (Sensor edge) (C)": "38.0",
+            "Temperature (Sensor junction) (C)": "40.0",
+            "Temperature (Sensor memory) (C)": "46.0",
+            "mclk clock speed:": "(96Mhz)",
+            "sclk clock speed:": "(0Mhz)",
+            "Fan speed (%)": "21",
+            "Current Socket Graphics Package Power (W)": "14.0",
+            "GPU use (%)": "0",
+            "GPU memory use (%)": "0",
+            "VRAM Total Memory (B)": "34342961152",
+            "Card Series": "Navi 21 GL-XL [Radeon PRO W6800]",
+            "Card model": "0x0e1e",
+            "Unique ID": "N/A"
+        },
+        "system": {"Driver version": "6.2.4"}
+    }"#;
+
+    #[test]
+    fn parses_rocm_smi() {
+        let gpus = rocm_smi_gpus(&serde_json::from_str(ROCM_SMI).unwrap());
+        assert_eq!(gpus.len(), 2);
+
+        let gpu = &gpus[0];
+        assert_eq!(gpu.index, "0");
+        assert_eq!(gpu.uuid, "amd:0");
+        assert_eq!(gpu.name, "Navi 21 GL-XL [Radeon PRO W6800]");
+        assert_eq!(gpu.value("nvidia_temperature_gpu"), Some(38.0));
+        assert_eq!(gpu.value("nvidia_clocks_gr"), Some(0.0));
+        assert_eq!(gpu.value("nvidia_clocks_mem"), Some(96.0));
+        assert_eq!(gpu.value("nvidia_fan_speed"), Some(21.0));
+        assert_eq!(gpu.value("nvidia_power_draw"), Some(14.0));
+        assert_eq!(gpu.value("nvidia_memory_total"), Some(32752.0));
+        // 没有已用显存就不算空闲显存
+        assert_eq!(gpu.value("nvidia_memory_used"), None);
+        assert_eq!(gpu.value("nvidia_memory_free"), None);
+
+        let gpu = &gpus[1];
+        assert_eq!(gpu.index, "1");
+        assert_eq!(gpu.uuid, "0x4fb1a2c3d4e5f607");
+        assert_eq!(gpu.name, "0x740f");
+        assert_eq!(gpu.value("nvidia_temperature_gpu"), Some(45.0));
+        assert_eq!(gpu.value("nvidia_fan_speed"), None);
+        assert_eq!(gpu.value("nvidia_clocks_gr"), Some(800.0));
+        assert_eq!(gpu.value("nvidia_power_draw"), Some(43.0));
+        assert_eq!(gpu.value("nvidia_utilization_gpu"), Some(2.0));
+        assert_eq!(gpu.value("nvidia_memory_total"), Some(65520.0));
+        assert_eq!(gpu.value("nvidia_memory_used"), Some(10.453125));
+        assert_eq!(gpu.value("nvidia_memory_free"), Some(65509.546875));
+    }
+}
@@ -0,0 +1,84 @@
+use serde_json::{json, Map, Value};
+use tide::{Body, Request, Response, StatusCode};
+use tracing::error;
+
+use crate::collector;
+use crate::gpu::Gpu;
+use crate::State;
+
+/// `GET /api/v1/gpus`: collects like the telemetry path, with the same `?collect[]=`, `?gpu=` and
+/// `?uuid=` parameters, and returns every GPU's identity and values as JSON.
+pub async fn handle_gpus(req: Request<State>) -> tide::Result {
+    let settings = match crate::scrape_settings(&req) {
+        Ok(settings) => settings,
+        Err(e) => return Ok(crate::bad_request(e)),
+    };
+    let fields = collector::fields(&settings);
+    let (status, body) = match collector::collect_gpus(&settings, &fields).await {
+        Ok(gpus) => {
+            let gpus = gpus
+                .iter()
+                .map(|gpu| to_json(gpu, &fields))
+                .collect::<Vec<_>>();
+            (StatusCode::Ok, json!({ "gpus": gpus }))
+        }
+        Err(e) => {
+            error!("Failed to process nvidia-smi, {:#}", e);
+            (
+                StatusCode::ServiceUnavailable,
+                json!({ "error": format!("{:#}", e) }),
+            )
+        }
+    };
+    Ok(Response::builder(status)
+        .body(Body::from_json(&body)?)
+        .build())
+}
+
+/// Values are nested by nvidia-smi field name, so `clocks.gr` is `{"clocks": {"gr": 1410}}`, and
+/// unavailable ones are null.
+pub fn to_json(gpu: &Gpu, fields: &[(&str, &str)]) -> Value {
+    let mut object = Map::new();
+    let index = gpu
+        .index
+        .parse::<u64>()
+        .map_or_else(|_| Value::from(gpu.index.as_str()), Value::from);
+    object.insert("index".to_string(), index);
+    if let Some(host) = &gpu.host {
+        object.insert("host".to_string(), Value::from(host.as_str()));
+    }
+    object.insert("uuid".to_string(), Value::from(gpu.uuid.as_str()));
+    object.insert("name".to_string(), Value::from(gpu.name.as_str()));
+    object.insert(
+        "driver_version".to_string(),
+        Value::from(gpu.driver_version.as_str()),
+    );
+    for (field, metric) in fields {
+        insert(
+            &mut object,
+            field,
+            gpu.value(metric).map_or(Value::Null, Value::from),
+        );
+    }
+    Value::Object(object)
+}
+
+fn insert(object: &mut Map<String, Value>, field: &str, value: Value) {
+    let (key, rest) = match field.split_once('.') {
+        Some(split) => split,
+        None => {
+            object.insert(field.to_string(), value);
+            return;
+        }
+    };
+    match object
+        .entry(key)
+        .or_insert_with(|| Value::Object(Map::new()))
+    {
+        Value::Object(child) => insert(child, rest, value),
+        // 前缀本身也是字段（如同时查询 a.b 和 a.b.c）时用完整字段名
+        _ => {
+            object.insert(field.to_string(), value);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tide::http::auth::BasicAuth;
+use tide::http::headers::{AUTHORIZATION, WWW_AUTHENTICATE};
+use tide::{Middleware, Next, Request, Response, StatusCode};
+use tracing::debug;
+
+use crate::tenant::Tenant;
+
+/// Rejects requests that present neither valid Basic credentials nor a configured bearer token.
+/// Without any users or tokens every request is let through. Requests of a tenant's user or
+/// token get the `Arc<Tenant>` as an extension.
+#[derive(Default)]
+pub struct AuthMiddleware {
+    users: HashMap<String, String>,
+    tokens: Vec<String>,
+    tenants: Vec<Arc<Tenant>>,
+}
+
+/// Who presented valid credentials.
+enum Principal {
+    User(String),
+    Token(String),
+}
+
+impl AuthMiddleware {
+    /// Reads `user:hash` lines as written by `htpasswd -B`.
+    pub fn with_users_file(self, path: &str) -> Result<Self> {
+        let mut users = Vec::new();
+        for (no, line) in read_lines(path)? {
+            match line.split_once(':') {
+                Some((user, hash)) => users.push((user.to_string(), hash.to_string())),
+                None => bail!("{}:{}: expected user:bcrypt-hash", path, no),
+            }
+        }
+        if users.is_empty() {
+            bail!("No users found in {}", path);
+        }
+        self.with_users(users)
+            .with_context(|| format!("Invalid users file {}", path))
+    }
+
+    pub fn with_users(mut self, users: impl IntoIterator<Item = (String, String)>) -> Result<Self> {
+        for (user, hash) in users {
+            if !hash.starts_with("$2") {
+                bail!("Password of {} is not a bcrypt hash", user);
+            }
+            self.users.insert(user, hash);
+        }
+        Ok(self)
+    }
+
+    /// Reads one accepted bearer token per line.
+    pub fn with_tokens_file(mut self, path: &str) -> Result<Self> {
+        self.tokens
+            .extend(read_lines(path)?.into_iter().map(|(_, token)| token));
+        if self.tokens.is_empty() {
+            bail!("No tokens found in {}", path);
+        }
+        Ok(self)
+    }
+
+    /// Accepts the tenants' tokens too; their users must be among those already added.
+    pub fn with_tenants(mut self, tenants: Vec<Arc<Tenant>>) -> Result<Self> {
+        for (i, tenant) in tenants.iter().enumerate() {
+            if let Some(user) = tenant.users.iter().find(|u| !self.users.contains_key(*u)) {
+                bail!("Tenant {} has unknown user {}", tenant.name, user);
+            }
+            // 同一凭据属于两个租户时无法判断该看哪些 GPU
+            for other in &tenants[..i] {
+                if tenant.users.iter().any(|u| other.users.contains(u))
+                    || tenant.tokens.iter().any(|t| other.tokens.contains(t))
+                {
+                    bail!(
+                        "Tenants {} and {} share credentials",
+                        other.name,
+                        tenant.name
+                    );
+                }
+            }
+            self.tokens.extend(tenant.tokens.iter().cloned());
+        }
+        self.tenants = tenants;
+        Ok(self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.tokens.is_empty()
+    }
+
+    fn verify<State>(&self, req: &Request<State>) -> Option<Principal> {
+        let bearer = req
+            .header(AUTHORIZATION)
+            .and_then(|v| v.as_str().strip_prefix("Bearer "));
+        if let Some(presented) = bearer {
+            let presented = presented.trim();
+            return self
+                .tokens
+                .iter()
+                .any(|token| constant_time_eq(token.as_bytes(), presented.as_bytes()))
+                .then(|| Principal::Token(presented.to_string()));
+        }
+        match BasicAuth::from_headers(req) {
+            Ok(Some(auth))
+                if self
+                    .users
+                    .get(auth.username())
+                    .is_some_and(|hash| bcrypt::verify(auth.password(), hash).unwrap_or(false)) =>
+            {
+                Some(Principal::User(auth.username().to_string()))
+            }
+            _ => None,
+        }
+    }
+
+    fn tenant(&self, principal: &Principal) -> Option<&Arc<Tenant>> {
+        self.tenants.iter().find(|tenant| match principal {
+            Principal::User(user) => tenant.users.contains(user),
+            Principal::Token(presented) => tenant
+                .tokens
+                .iter()
+                .any(|token| constant_time_eq(token.as_bytes(), presented.as_bytes())),
+        })
+    }
+
+    fn challenge(&self) -> &'static str {
+        if self.users.is_empty() {
+            "Bearer"
+        } else {
+            "Basic realm=\"nvidia-smi-exporter\""
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AuthMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if self.is_empty() {
+            return Ok(next.run(req).await);
+        }
+        if let Some(principal) = self.verify(&req) {
+            if let Some(tenant) = self.tenant(&principal) {
+                req.set_ext(tenant.clone());
+            }
+            return Ok(next.run(req).await);
+        }
+        debug!("Rejected request without valid credentials");
+        Ok(Response::builder(StatusCode::Unauthorized)
+            .header(WWW_AUTHENTICATE, self.challenge())
+            .build())
+    }
+}
+
+/// Non-empty, non-comment lines with their 1-based line numbers.
+pub fn read_lines(path: &str) -> Result<Vec<(usize, String)>> {
+    let content =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+    Ok(content
+        .lines()
+        .enumerate()
+        .map(|(no, line)| (no + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(no, line)| (no, line.to_string()))
+        .collect())
+}
+
+// 避免按字节提前返回泄露 token 前缀
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,214 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use surf::Url;
+
+use crate::cli::Cli;
+use crate::push::{Batch, Target};
+
+const IMDS_ENDPOINT: &str = "http://169.254.169.254";
+const RESOURCE: &str = "https://monitoring.azure.com/";
+/// Access tokens are renewed this long before they expire.
+const REFRESH_BEFORE: Duration = Duration::from_secs(300);
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+    /// Seconds, as a string.
+    expires_in: String,
+}
+
+/// Azure Monitor custom metrics (`--push.azure-monitor`), sent the GPU samples of the VM.
+/// Authenticates with the VM's managed identity and finds the VM's region and resource id in
+/// the instance metadata service.
+pub struct AzureMonitor {
+    client: surf::Client,
+    imds: String,
+    client_id: Option<String>,
+    namespace: String,
+    /// `--push.azure-monitor.region`/`.resource-id`, or the VM's from the metadata service.
+    target: Mutex<(Option<String>, Option<String>)>,
+    endpoint: Option<String>,
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl AzureMonitor {
+    pub fn new(cli: &Cli) -> Self {
+        AzureMonitor {
+            client: surf::Client::new(),
+            imds: std::env::var("IMDS_ENDPOINT")
+                .unwrap_or(IMDS_ENDPOINT.to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            client_id: cli.azure_monitor_client_id.clone(),
+            namespace: cli.azure_monitor_namespace.clone(),
+            target: Mutex::new((
+                cli.azure_monitor_region.clone(),
+                cli.azure_monitor_resource_id.clone(),
+            )),
+            endpoint: cli.azure_monitor_endpoint.clone(),
+            token: Mutex::new(None),
+        }
+    }
+
+    async fn imds(&self, path_and_query: &str) -> Result<String> {
+        let mut response = self
+            .client
+            .get(format!("{}{}", self.imds, path_and_query))
+            .header("Metadata", "true")
+            .await
+            .map_err(|e| e.into_inner())
+            .context("Failed to reach the Azure instance metadata service")?;
+        let body = response.body_string().await.map_err(|e| e.into_inner())?;
+        if !response.status().is_success() {
+            bail!(
+                "Instance metadata service returned {} {}",
+                response.status(),
+                body.trim()
+            );
+        }
+        Ok(body)
+    }
+
+    async fn token(&self) -> Result<String> {
+        if let Some((token, expires)) = &*self.token.lock().unwrap() {
+            if Instant::now() + REFRESH_BEFORE < *expires {
+                return Ok(token.clone());
+            }
+        }
+        let mut query = format!("api-version=2018-02-01&resource={}", RESOURCE);
+        if let Some(client_id) = &self.client_id {
+            query += &format!("&client_id={}", client_id);
+        }
+        let body = self
+            .imds(&format!("/metadata/identity/oauth2/token?{}", query))
+            .await
+            .context("Failed to get a managed identity token")?;
+        let token: Token = serde_json::from_str(&body).context("Invalid access token")?;
+        let expires_in = token.expires_in.parse().unwrap_or(0);
+        let expires = Instant::now() + Duration::from_secs(expires_in);
+        *self.token.lock().unwrap() = Some((token.access_token.clone(), expires));
+        Ok(token.access_token)
+    }
+
+    /// The URL metrics are posted to: the regional endpoint and the resource id.
+    async fn url(&self) -> Result<Url> {
+        let (region, resource_id) = self.target.lock().unwrap().clone();
+        let (region, resource_id) = match (region, resource_id) {
+            (Some(region), Some(resource_id)) => (region, resource_id),
+            (region, resource_id) => {
+                let body = self
+                    .imds("/metadata/instance/compute?api-version=2021-02-01")
+                    .await?;
+                let compute: Value =
+                    serde_json::from_str(&body).context("Invalid instance metadata")?;
+                let field = |name: &str| {
+                    compute[name]
+                        .as_str()
+                        .map(str::to_string)
+                        .ok_or_else(|| anyhow!("No {} in the instance metadata", name))
+                };
+                let region = match region {
+                    Some(region) => region,
+                    None => field("location")?,
+                };
+                let resource_id = match resource_id {
+                    Some(resource_id) => resource_id,
+                    None => field("resourceId")?,
+                };
+                *self.target.lock().unwrap() = (Some(region.clone()), Some(resource_id.clone()));
+                (region, resource_id)
+            }
+        };
+        let endpoint = self
+            .endpoint
+            .clone()
+            .unwrap_or_else(|| format!("https://{}.monitoring.azure.com", region));
+        let url = format!(
+            "{}/{}/metrics",
+            endpoint.trim_end_matches('/'),
+            resource_id.trim_start_matches('/')
+        );
+        Url::parse(&url).with_context(|| format!("Invalid Azure Monitor URL {}", url))
+    }
+}
+
+#[tide::utils::async_trait]
+impl Target for AzureMonitor {
+    fn name(&self) -> &'static str {
+        "azure_monitor"
+    }
+
+    async fn push(&self, batch: &Batch) -> Result<()> {
+        let time =
+            DateTime::<Utc>::from(batch.timestamp).to_rfc3339_opts(SecondsFormat::Secs, true);
+        // 每个请求只能有一个指标
+        let mut metrics: BTreeMap<&str, Vec<_>> = BTreeMap::new();
+        for sample in &batch.samples {
+            if sample.value.is_finite() && sample.labels.iter().any(|(name, _)| name == "gpu") {
+                metrics.entry(&sample.name).or_default().push(sample);
+            }
+        }
+        if metrics.is_empty() {
+            return Ok(());
+        }
+        let url = self.url().await?;
+        for (metric, samples) in metrics {
+            let dim_names = samples
+                .iter()
+                .flat_map(|s| s.labels.iter().map(|(name, _)| name.as_str()))
+                .collect::<BTreeSet<_>>();
+            let series = samples
+                .iter()
+                .map(|sample| {
+                    let dim_values = dim_names
+                        .iter()
+                        .map(|name| {
+                            sample
+                                .labels
+                                .iter()
+                                .find(|(n, _)| n == name)
+                                .map_or("", |(_, value)| value.as_str())
+                        })
+                        .collect::<Vec<_>>();
+                    json!({
+                        "dimValues": dim_values,
+                        "min": sample.value,
+                        "max": sample.value,
+                        "sum": sample.value,
+                        "count": 1,
+                    })
+                })
+                .collect::<Vec<_>>();
+            let body = json!({
+                "time": time,
+                "data": {
+                    "baseData": {
+                        "metric": metric,
+                        "namespace": self.namespace,
+                        "dimNames": dim_names,
+                        "series": series,
+                    },
+                },
+            });
+            let request = surf::post(url.clone())
+                .header("Authorization", format!("Bearer {}", self.token().await?))
+                .body(body);
+            let mut response = self
+                .client
+                .send(request)
+                .await
+                .map_err(|e| e.into_inner())?;
+            // 成功时也读完响应体，下一个请求才能复用连接
+            let text = response.body_string().await.unwrap_or_default();
+            if !response.status().is_success() {
+                bail!("{} for {}: {}", response.status(), metric, text.trim());
+            }
+        }
+        Ok(())
+    }
+}
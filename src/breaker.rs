@@ -0,0 +1,192 @@
+use anyhow::{bail, Result};
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge, IntGauge};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::gpu::Gpu;
+
+lazy_static! {
+    static ref STATE: Mutex<State> = Mutex::new(State::default());
+    static ref OPEN: IntGauge = register_int_gauge!(
+        "nvidia_smi_exporter_circuit_breaker_open",
+        "Whether nvidia-smi is not run after --collect.breaker-failures failed collections in a row."
+    )
+    .unwrap();
+}
+
+#[derive(Default)]
+struct State {
+    /// Failed collections in a row.
+    failures: u32,
+    /// Until when nvidia-smi is not run.
+    open_until: Option<Instant>,
+    /// The GPUs of the last successful collection of all devices, by its fields.
+    cached: HashMap<Vec<String>, Vec<Gpu>>,
+}
+
+/// `--collect.breaker-failures` and `--collect.breaker-cooldown`: stops running nvidia-smi for a
+/// while after failed collections in a row, so that a wedged driver does not pile up hung
+/// processes scrape after scrape.
+#[derive(Clone, Debug)]
+pub struct Breaker {
+    pub failures: u32,
+    pub cooldown: Duration,
+}
+
+impl Breaker {
+    /// Whether nvidia-smi may be run. Once the cool-down is over, one collection is let through
+    /// to try again, and the others wait for its outcome for another cool-down.
+    pub fn allow(&self) -> bool {
+        let mut state = STATE.lock().unwrap();
+        match state.open_until {
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                state.open_until = Some(Instant::now() + self.cooldown);
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Counts the outcome of a collection of `fields` of `devices`, opening the breaker after
+    /// `failures` in a row. Any success closes it, but only failures of all devices count and
+    /// only their GPUs are kept: a request for a GPU that does not exist fails without the driver
+    /// being stuck.
+    pub fn record(&self, fields: &[(&str, &str)], devices: &[String], result: &Result<Vec<Gpu>>) {
+        let mut state = STATE.lock().unwrap();
+        match result {
+            Ok(gpus) => {
+                if state.open_until.take().is_some() {
+                    info!("nvidia-smi answered again, closing the circuit breaker");
+                }
+                state.failures = 0;
+                if devices.is_empty() {
+                    state.cached.insert(key(fields), gpus.clone());
+                }
+                OPEN.set(0);
+            }
+            Err(_) if !devices.is_empty() => {}
+            Err(_) => {
+                state.failures = state.failures.saturating_add(1);
+                if state.failures < self.failures {
+                    return;
+                }
+                if state.open_until.is_none() {
+                    warn!(
+                        "{} collections failed in a row, not running nvidia-smi for {:?}",
+                        state.failures, self.cooldown
+                    );
+                }
+                state.open_until = Some(Instant::now() + self.cooldown);
+                OPEN.set(1);
+            }
+        }
+    }
+
+    /// The GPUs of the last successful collection of the same `fields` of all devices, served
+    /// while the breaker is open; collections of other fields, or of some devices, fail.
+    pub fn cached(&self, fields: &[(&str, &str)], devices: &[String]) -> Result<Vec<Gpu>> {
+        let state = STATE.lock().unwrap();
+        match state
+            .cached
+            .get(&key(fields))
+            .filter(|_| devices.is_empty())
+        {
+            Some(gpus) => Ok(gpus.clone()),
+            None => bail!(
+                "{} collections failed in a row, not running nvidia-smi for {:?}",
+                state.failures,
+                self.cooldown
+            ),
+        }
+    }
+}
+
+fn key(fields: &[(&str, &str)]) -> Vec<String> {
+    fields.iter().map(|(field, _)| field.to_string()).collect()
+}
+
+/// Whether the breaker is open, so that the optional collections and readiness checks do not run
+/// nvidia-smi either.
+pub fn open() -> bool {
+    let state = STATE.lock().unwrap();
+    state.open_until.is_some_and(|until| Instant::now() < until)
+}
+
+pub fn init_metrics() {
+    lazy_static::initialize(&OPEN);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    const FIELDS: &[(&str, &str)] = &[("temperature.gpu", "nvidia_temperature_gpu")];
+    const OTHER_FIELDS: &[(&str, &str)] = &[("power.draw", "nvidia_power_draw")];
+
+    fn gpus(temperature: f64) -> Result<Vec<Gpu>> {
+        Ok(vec![Gpu {
+            index: "0".to_string(),
+            uuid: "GPU-0".to_string(),
+            name: "Tesla T4".to_string(),
+            driver_version: "535.104.05".to_string(),
+            values: vec![("nvidia_temperature_gpu".to_string(), temperature)],
+            host: None,
+        }])
+    }
+
+    fn temperature(result: Result<Vec<Gpu>>) -> f64 {
+        result.unwrap()[0].value("nvidia_temperature_gpu").unwrap()
+    }
+
+    #[test]
+    fn opens_serves_the_last_readings_and_closes() {
+        let breaker = Breaker {
+            failures: 2,
+            cooldown: Duration::from_millis(100),
+        };
+        let failed = || Err(anyhow!("nvidia-smi timed out after 5s"));
+        let gpu = ["999".to_string()];
+
+        assert!(breaker.allow());
+        breaker.record(FIELDS, &[], &gpus(40.0));
+        // 指定了不存在的 GPU 的请求失败多少次都不算
+        for _ in 0..3 {
+            breaker.record(FIELDS, &gpu, &failed());
+        }
+        assert!(!open());
+        breaker.record(FIELDS, &[], &failed());
+        assert!(!open());
+        breaker.record(FIELDS, &[], &failed());
+        assert!(open());
+        assert_eq!(OPEN.get(), 1);
+
+        // 打开期间只返回相同字段、全部 GPU 的上次读数
+        assert!(!breaker.allow());
+        assert_eq!(temperature(breaker.cached(FIELDS, &[])), 40.0);
+        assert!(breaker.cached(OTHER_FIELDS, &[]).is_err());
+        assert!(breaker.cached(FIELDS, &gpu).is_err());
+
+        // 冷却之后放一次进来，失败则再冷却一次
+        std::thread::sleep(breaker.cooldown);
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+        breaker.record(FIELDS, &[], &failed());
+        assert!(open());
+
+        std::thread::sleep(breaker.cooldown);
+        assert!(breaker.allow());
+        breaker.record(FIELDS, &[], &gpus(45.0));
+        assert!(!open());
+        assert_eq!(OPEN.get(), 0);
+        assert!(breaker.allow());
+        assert_eq!(temperature(breaker.cached(FIELDS, &[])), 45.0);
+        // 恢复后重新计数
+        breaker.record(FIELDS, &[], &failed());
+        assert!(!open());
+    }
+}
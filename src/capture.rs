@@ -0,0 +1,123 @@
+use anyhow::{bail, Context, Result};
+use async_std::process::Output;
+use lazy_static::lazy_static;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::warn;
+
+use crate::nvidia_smi::{NvidiaSmi, OutputFuture};
+use crate::ssh::{Ssh, Target};
+
+const HEADER: &str = "# nvidia-smi-exporter capture";
+
+lazy_static! {
+    /// Hashes of the outputs saved by this process, so a GPU that keeps failing is saved once.
+    static ref SAVED: Mutex<HashSet<u64>> = Mutex::new(HashSet::new());
+}
+
+/// `--capture-dir`: saves the `--query-gpu` output nvidia-smi gave on `host` (empty if local)
+/// that could not be parsed, as `query-gpu-<hash>.capture` with the command on the second line.
+/// The same output is saved once, also across restarts.
+pub fn save(dir: &str, host: &str, args: &[String], stdout: &[u8]) {
+    let mut hasher = DefaultHasher::new();
+    stdout.hash(&mut hasher);
+    let hash = hasher.finish();
+    if !SAVED.lock().unwrap().insert(hash) {
+        return;
+    }
+    let path = Path::new(dir).join(format!("query-gpu-{:016x}.capture", hash));
+    let from = match host {
+        "" => String::new(),
+        host => format!(" from {}", host),
+    };
+    let mut content = format!(
+        "{} {}{}\nnvidia-smi {}\n",
+        HEADER,
+        env!("CARGO_PKG_VERSION"),
+        from,
+        args.join(" ")
+    )
+    .into_bytes();
+    content.extend_from_slice(stdout);
+    match std::fs::write(&path, content) {
+        Ok(()) => warn!(
+            "Saved the nvidia-smi output that failed to parse to {}",
+            path.display()
+        ),
+        Err(e) => warn!("Failed to save a capture to {}, {}", path.display(), e),
+    }
+}
+
+/// `replay`: answers the collection from a capture instead of running nvidia-smi.
+#[derive(Debug)]
+pub struct Replay {
+    /// The `--query-gpu=…` argument of the captured run.
+    query: String,
+    stdout: Vec<u8>,
+}
+
+impl Replay {
+    pub fn load(path: &str) -> Result<Self> {
+        let content =
+            std::fs::read(path).with_context(|| format!("Failed to read the capture {}", path))?;
+        let mut lines = content.splitn(3, |&b| b == b'\n');
+        let (header, command) = (lines.next(), lines.next());
+        if !header.is_some_and(|header| header.starts_with(HEADER.as_bytes())) {
+            bail!("{} is not a capture of --capture-dir", path);
+        }
+        let command = String::from_utf8_lossy(command.unwrap_or_default());
+        let query = command
+            .split_whitespace()
+            .find(|arg| arg.starts_with("--query-gpu="))
+            .with_context(|| format!("No --query-gpu in {}", path))?
+            .to_string();
+        Ok(Replay {
+            query,
+            stdout: lines.next().unwrap_or_default().to_vec(),
+        })
+    }
+}
+
+impl NvidiaSmi for Replay {
+    fn output<'a>(
+        &'a self,
+        args: &'a [String],
+        _remote: Option<(&'a Ssh, &'a Target)>,
+    ) -> OutputFuture<'a> {
+        // 设备列表（--id=）可能不同，只比较查询的字段
+        let output = match args.iter().find(|arg| arg.starts_with("--query-gpu=")) {
+            Some(query) if *query == self.query => Output {
+                status: ExitStatusExt::from_raw(0),
+                stdout: self.stdout.clone(),
+                stderr: Vec::new(),
+            },
+            Some(query) => failed(format!(
+                "The capture queried {} but this run {}; replay with the collectors and \
+                 --query-field options of the captured run",
+                self.query, query
+            )),
+            None => failed(format!(
+                "nvidia-smi {} is not in the capture",
+                args.join(" ")
+            )),
+        };
+        Box::pin(async move { Ok(output) })
+    }
+
+    fn local_devices(&self) -> bool {
+        false
+    }
+}
+
+/// What nvidia-smi exiting with 1 and `stderr` looks like.
+fn failed(stderr: String) -> Output {
+    Output {
+        status: ExitStatusExt::from_raw(1 << 8),
+        stdout: Vec::new(),
+        stderr: stderr.into_bytes(),
+    }
+}
@@ -0,0 +1,51 @@
+use anyhow::{bail, Result};
+use clap::error::ErrorKind;
+use std::ffi::OsString;
+
+use crate::admin;
+use crate::cli;
+use crate::config::Settings;
+use crate::configfile;
+use crate::reload::ConfigFiles;
+
+/// `check-config <file>`: reports unknown keys and invalid or conflicting options in a `--config`
+/// file, resolved together with the environment like at startup, without serving anything.
+pub fn check_config(path: &str) -> Result<()> {
+    let options = configfile::options(path)?;
+    let mut problems = Vec::new();
+    for (name, _) in &options {
+        // 单独解析每个选项，只关心是否认识它，取值和依赖由下面的完整解析检查
+        let probe = cli::command()
+            .try_get_matches_from(["nvidia-smi-exporter".to_string(), format!("--{}", name)]);
+        if name == "config" {
+            problems.push("config: config files cannot include other config files".to_string());
+        } else if probe.is_err_and(|e| e.kind() == ErrorKind::UnknownArgument) {
+            problems.push(format!("{}: unknown option", name));
+        }
+    }
+    if problems.is_empty() {
+        if let Err(e) = check_options(path) {
+            problems.push(format!("{:#}", e));
+        }
+    }
+    if !problems.is_empty() {
+        bail!("{}:\n  {}", path, problems.join("\n  "));
+    }
+    println!("{}: OK", path);
+    Ok(())
+}
+
+fn check_options(path: &str) -> Result<()> {
+    let args: Vec<OsString> = vec![
+        "nvidia-smi-exporter".into(),
+        format!("--config={}", path).into(),
+    ];
+    let cli = cli::parse(&args)?;
+    Settings::from_cli(&cli)?;
+    let (web_config, auth) = ConfigFiles::from_cli(&cli).load()?;
+    admin::check_auth(&cli, &auth)?;
+    if cli.tls_cert.is_some() && web_config.tls_server_config.is_some() {
+        bail!("--tls-cert conflicts with tls_server_config in --web.config.file");
+    }
+    Ok(())
+}
@@ -0,0 +1,2203 @@
+use anyhow::{anyhow, Result};
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use clap_complete::Shell;
+use ipnet::IpNet;
+use regex::Regex;
+use std::ffi::OsString;
+
+use crate::agentx::Oid;
+use crate::alert::Format as AlertFormat;
+use crate::collector::{self, Collector, QueryField};
+use crate::configfile;
+use crate::federate::Downstream;
+use crate::filter::GpuMatcher;
+use crate::graphite::Protocol as GraphiteProtocol;
+use crate::kafka::Format as KafkaFormat;
+use crate::logging::{Format as LogFormat, Output as LogOutput, Rotation};
+use crate::middleware::AccessLogFormat;
+use crate::mock::Backend;
+use crate::nagios::Thresholds;
+use crate::oneshot::OutputFormat;
+use crate::otlp;
+use crate::push;
+use crate::ssh::Target;
+use crate::tls::TlsVersion;
+use crate::version;
+
+/// All command line options. Each can also be set by `NVIDIA_SMI_EXPORTER_<NAME>` (see
+/// `configfile::env_name`) and in `--config` files by its long name, which is also its id.
+#[derive(Debug, Parser)]
+#[command(
+    name = "nvidia-smi-exporter",
+    version,
+    long_version = version::LONG_VERSION.as_str(),
+    about = "Prometheus exporter for NVIDIA GPU metrics collected with nvidia-smi"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// YAML or TOML (by extension) file setting any of these options by long name; flags take
+    /// precedence
+    #[arg(long, env = "NVIDIA_SMI_EXPORTER_CONFIG")]
+    pub config: Option<String>,
+
+    /// Sets the level of verbosity
+    #[arg(short, long, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// List the GPUs, run one collection, print the metrics and exit: 0 on success, 2 if there
+    /// are no GPUs to export, 3 if the collection fails
+    #[arg(id = "dry-run", long = "dry-run")]
+    pub dry_run: bool,
+
+    /// Address to listen on, host:port or unix:/path/to.sock; repeat to serve several (default
+    /// 0.0.0.0:9101)
+    #[arg(short, long, env = "NVIDIA_SMI_EXPORTER_LISTEN", value_delimiter = ',')]
+    pub listen: Vec<String>,
+
+    /// User name or uid to switch to after binding the listen sockets (requires starting as root)
+    #[arg(long, env = "NVIDIA_SMI_EXPORTER_USER")]
+    pub user: Option<String>,
+
+    /// Group name or gid to switch to after binding (default: the --user's primary group)
+    #[arg(long, env = "NVIDIA_SMI_EXPORTER_GROUP")]
+    pub group: Option<String>,
+
+    /// Log as human-readable text or as one JSON object per line
+    #[arg(
+        id = "log-format",
+        long = "log-format",
+        env = "NVIDIA_SMI_EXPORTER_LOG_FORMAT",
+        value_enum,
+        default_value_t = LogFormat::Text
+    )]
+    pub log_format: LogFormat,
+
+    /// Log to stderr, syslog or journald
+    #[arg(
+        id = "log-output",
+        long = "log-output",
+        env = "NVIDIA_SMI_EXPORTER_LOG_OUTPUT",
+        conflicts_with = "log-file",
+        value_enum,
+        default_value_t = LogOutput::Stderr
+    )]
+    pub log_output: LogOutput,
+
+    /// Write logs to this file instead of stderr
+    #[arg(
+        id = "log-file",
+        long = "log-file",
+        env = "NVIDIA_SMI_EXPORTER_LOG_FILE"
+    )]
+    pub log_file: Option<String>,
+
+    /// Rotate --log-file daily, hourly, or when it exceeds a size such as 100MB
+    #[arg(
+        id = "log-rotate",
+        long = "log-rotate",
+        env = "NVIDIA_SMI_EXPORTER_LOG_ROTATE",
+        default_value = "100MB"
+    )]
+    pub log_rotate: Rotation,
+
+    /// Number of rotated log files to keep
+    #[arg(
+        id = "log-max-files",
+        long = "log-max-files",
+        env = "NVIDIA_SMI_EXPORTER_LOG_MAX_FILES",
+        default_value_t = 7
+    )]
+    pub log_max_files: usize,
+
+    /// Detach from the terminal and run in the background, for init scripts; logs are discarded
+    /// unless --log-file is set
+    #[arg(long)]
+    pub daemonize: bool,
+
+    /// Write the process id to this file, and remove it on shutdown
+    #[arg(
+        id = "pid-file",
+        long = "pid-file",
+        env = "NVIDIA_SMI_EXPORTER_PID_FILE"
+    )]
+    pub pid_file: Option<String>,
+
+    /// Save the nvidia-smi output of each --query-gpu collection that fails to parse in this
+    /// directory, for the replay subcommand and bug reports
+    #[arg(
+        id = "capture-dir",
+        long = "capture-dir",
+        env = "NVIDIA_SMI_EXPORTER_CAPTURE_DIR"
+    )]
+    pub capture_dir: Option<String>,
+
+    /// Keep the counters the exporter derives itself (--collect.events, --energy.*) in this
+    /// file, saved every minute and at shutdown, and continue from it after a restart
+    #[arg(
+        id = "state-file",
+        long = "state-file",
+        env = "NVIDIA_SMI_EXPORTER_STATE_FILE"
+    )]
+    pub state_file: Option<String>,
+
+    /// Restrict filesystem access with Landlock and deny unneeded syscalls with seccomp after
+    /// startup
+    #[arg(long)]
+    pub sandbox: bool,
+
+    /// Path under which to expose metrics
+    #[arg(
+        id = "web.telemetry-path",
+        long = "web.telemetry-path",
+        env = "NVIDIA_SMI_EXPORTER_WEB_TELEMETRY_PATH",
+        default_value = "/metrics"
+    )]
+    pub telemetry_path: String,
+
+    /// Maximum number of concurrent scrapes, further ones get 503; 0 disables the limit
+    #[arg(
+        id = "web.max-requests",
+        long = "web.max-requests",
+        env = "NVIDIA_SMI_EXPORTER_WEB_MAX_REQUESTS",
+        default_value_t = 40
+    )]
+    pub max_requests: usize,
+
+    /// Maximum scrapes per minute from one client IP, further ones get 429; 0 disables the limit
+    #[arg(
+        id = "web.rate-limit",
+        long = "web.rate-limit",
+        env = "NVIDIA_SMI_EXPORTER_WEB_RATE_LIMIT",
+        default_value_t = 0
+    )]
+    pub rate_limit: u32,
+
+    /// Seconds after which a scrape is aborted with 503; 0 disables the deadline
+    #[arg(
+        id = "web.request-timeout",
+        long = "web.request-timeout",
+        env = "NVIDIA_SMI_EXPORTER_WEB_REQUEST_TIMEOUT",
+        default_value_t = 0
+    )]
+    pub request_timeout: u64,
+
+    /// Only serve clients from this network, e.g. 10.0.0.0/8; repeatable
+    #[arg(
+        id = "web.allow-cidr",
+        long = "web.allow-cidr",
+        env = "NVIDIA_SMI_EXPORTER_WEB_ALLOW_CIDR",
+        value_delimiter = ','
+    )]
+    pub allow_cidrs: Vec<IpNet>,
+
+    /// Network of a reverse proxy whose X-Forwarded-For is used as the logged and rate limited
+    /// client address; repeatable
+    #[arg(
+        id = "web.trusted-proxy",
+        long = "web.trusted-proxy",
+        env = "NVIDIA_SMI_EXPORTER_WEB_TRUSTED_PROXY",
+        value_delimiter = ','
+    )]
+    pub trusted_proxies: Vec<IpNet>,
+
+    /// Log every HTTP request at info level, whatever --verbose is set to
+    #[arg(id = "web.access-log", long = "web.access-log")]
+    pub access_log: bool,
+
+    /// Access log line, with {client}, {method}, {path}, {status}, {duration} (milliseconds),
+    /// {request_id} and {user_agent} replaced
+    #[arg(
+        id = "web.access-log.format",
+        long = "web.access-log.format",
+        env = "NVIDIA_SMI_EXPORTER_WEB_ACCESS_LOG_FORMAT",
+        default_value = "{client} {method} {path} {status} {duration} {request_id}"
+    )]
+    pub access_log_format: AccessLogFormat,
+
+    /// Comma-separated response compression algorithms (zstd, br, gzip, deflate), or none
+    #[arg(
+        id = "web.compression",
+        long = "web.compression",
+        env = "NVIDIA_SMI_EXPORTER_WEB_COMPRESSION",
+        default_value = "zstd,br,gzip,deflate"
+    )]
+    pub compression: String,
+
+    /// Only compress responses of at least this many bytes
+    #[arg(
+        id = "web.compression-threshold",
+        long = "web.compression-threshold",
+        env = "NVIDIA_SMI_EXPORTER_WEB_COMPRESSION_THRESHOLD",
+        default_value_t = 1024
+    )]
+    pub compression_threshold: usize,
+
+    /// Seconds a connection may send nothing while a request is expected before it is closed
+    #[arg(
+        id = "web.idle-timeout",
+        long = "web.idle-timeout",
+        env = "NVIDIA_SMI_EXPORTER_WEB_IDLE_TIMEOUT",
+        default_value_t = 60
+    )]
+    pub idle_timeout: u64,
+
+    /// Maximum number of open client connections; 0 disables the limit
+    #[arg(
+        id = "web.max-connections",
+        long = "web.max-connections",
+        env = "NVIDIA_SMI_EXPORTER_WEB_MAX_CONNECTIONS",
+        default_value_t = 256
+    )]
+    pub max_connections: usize,
+
+    /// Bind TCP listen addresses with SO_REUSEPORT, so a new exporter can start on the same port
+    /// before the old one exits
+    #[arg(id = "web.reuse-port", long = "web.reuse-port")]
+    pub reuse_port: bool,
+
+    /// PEM certificate chain; serves HTTPS instead of HTTP
+    #[arg(
+        id = "tls-cert",
+        long = "tls-cert",
+        env = "NVIDIA_SMI_EXPORTER_TLS_CERT",
+        requires = "tls-key"
+    )]
+    pub tls_cert: Option<String>,
+
+    /// PEM private key (PKCS#8 or RSA) for --tls-cert
+    #[arg(
+        id = "tls-key",
+        long = "tls-key",
+        env = "NVIDIA_SMI_EXPORTER_TLS_KEY",
+        requires = "tls-cert"
+    )]
+    pub tls_key: Option<String>,
+
+    /// PEM CA bundle; require client certificates signed by it (mutual TLS)
+    #[arg(
+        id = "tls-client-ca",
+        long = "tls-client-ca",
+        env = "NVIDIA_SMI_EXPORTER_TLS_CLIENT_CA",
+        requires = "tls-cert"
+    )]
+    pub tls_client_ca: Option<String>,
+
+    /// Minimum TLS version, TLS12 (default) or TLS13
+    #[arg(
+        id = "tls-min-version",
+        long = "tls-min-version",
+        env = "NVIDIA_SMI_EXPORTER_TLS_MIN_VERSION",
+        requires = "tls-cert"
+    )]
+    pub tls_min_version: Option<TlsVersion>,
+
+    /// Comma-separated TLS 1.2 cipher suites to allow, e.g. TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256
+    #[arg(
+        id = "tls-cipher-suites",
+        long = "tls-cipher-suites",
+        env = "NVIDIA_SMI_EXPORTER_TLS_CIPHER_SUITES",
+        requires = "tls-cert",
+        value_delimiter = ','
+    )]
+    pub tls_cipher_suites: Vec<String>,
+
+    /// exporter-toolkit web config YAML (tls_server_config, basic_auth_users, http_server_config)
+    #[arg(
+        id = "web.config.file",
+        long = "web.config.file",
+        env = "NVIDIA_SMI_EXPORTER_WEB_CONFIG_FILE"
+    )]
+    pub web_config_file: Option<String>,
+
+    /// htpasswd-style file of user:bcrypt-hash lines; require basic auth on every endpoint
+    #[arg(
+        id = "web.basic-auth-file",
+        long = "web.basic-auth-file",
+        env = "NVIDIA_SMI_EXPORTER_WEB_BASIC_AUTH_FILE"
+    )]
+    pub basic_auth_file: Option<String>,
+
+    /// File with one accepted bearer token per line; require one of them on every endpoint
+    #[arg(
+        id = "web.bearer-token-file",
+        long = "web.bearer-token-file",
+        env = "NVIDIA_SMI_EXPORTER_WEB_BEARER_TOKEN_FILE"
+    )]
+    pub bearer_token_file: Option<String>,
+
+    /// YAML file of tenants whose users and bearer tokens only see the GPUs and metrics given
+    /// there; re-read on reload
+    #[arg(
+        id = "web.tenants-file",
+        long = "web.tenants-file",
+        env = "NVIDIA_SMI_EXPORTER_WEB_TENANTS_FILE"
+    )]
+    pub web_tenants_file: Option<String>,
+
+    /// Where the GPUs come from: nvidia-smi, or synthetic GPUs with plausible, varying values
+    #[arg(
+        id = "backend",
+        long = "backend",
+        env = "NVIDIA_SMI_EXPORTER_BACKEND",
+        value_enum,
+        default_value_t = Backend::NvidiaSmi
+    )]
+    pub backend: Backend,
+
+    /// Number of synthetic GPUs with --backend mock
+    #[arg(
+        id = "backend.mock.gpus",
+        long = "backend.mock.gpus",
+        env = "NVIDIA_SMI_EXPORTER_BACKEND_MOCK_GPUS",
+        default_value_t = 2
+    )]
+    pub backend_mock_gpus: usize,
+
+    /// Seconds to wait for nvidia-smi before giving up
+    #[arg(
+        id = "collect.timeout",
+        long = "collect.timeout",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_TIMEOUT",
+        default_value_t = 10
+    )]
+    pub collect_timeout: u64,
+
+    /// Seconds a scrape's collections may take before later scrapes skip the optional ones
+    /// (processes, MIG devices) until they are fast again; 0 disables the budget
+    #[arg(
+        id = "collect.latency-budget",
+        long = "collect.latency-budget",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_LATENCY_BUDGET",
+        default_value_t = 0
+    )]
+    pub collect_latency_budget: u64,
+
+    /// Failed collections in a row after which nvidia-smi is not run for
+    /// --collect.breaker-cooldown, serving the last readings instead; 0 disables the breaker
+    #[arg(
+        id = "collect.breaker-failures",
+        long = "collect.breaker-failures",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_BREAKER_FAILURES",
+        default_value_t = 0
+    )]
+    pub collect_breaker_failures: u32,
+
+    /// Seconds nvidia-smi is not run once the circuit breaker opened
+    #[arg(
+        id = "collect.breaker-cooldown",
+        long = "collect.breaker-cooldown",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_BREAKER_COOLDOWN",
+        default_value_t = 60
+    )]
+    pub collect_breaker_cooldown: u64,
+
+    /// Export the GPU memory used by each compute process (nvidia_process_used_memory), labelled
+    /// with the container it runs in
+    #[arg(
+        id = "collect.processes",
+        long = "collect.processes",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_PROCESSES"
+    )]
+    pub collect_processes: bool,
+
+    /// Docker API socket to look up container names and images in, if it exists; empty to not
+    /// ask Docker
+    #[arg(
+        id = "collect.processes.docker-socket",
+        long = "collect.processes.docker-socket",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_PROCESSES_DOCKER_SOCKET",
+        default_value = "/var/run/docker.sock",
+        requires = "collect.processes"
+    )]
+    pub collect_processes_docker_socket: String,
+
+    /// Podman API socket to look up container names and images in, if it exists; empty to not
+    /// ask Podman
+    #[arg(
+        id = "collect.processes.podman-socket",
+        long = "collect.processes.podman-socket",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_PROCESSES_PODMAN_SOCKET",
+        default_value = "/run/podman/podman.sock",
+        requires = "collect.processes"
+    )]
+    pub collect_processes_podman_socket: String,
+
+    /// containerd CRI socket to look up container names and images in, if it exists; empty to
+    /// not ask containerd
+    #[arg(
+        id = "collect.processes.containerd-socket",
+        long = "collect.processes.containerd-socket",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_PROCESSES_CONTAINERD_SOCKET",
+        default_value = "/run/containerd/containerd.sock",
+        requires = "collect.processes"
+    )]
+    pub collect_processes_containerd_socket: String,
+
+    /// CRI-O socket to look up container names and images in, if it exists; empty to not ask
+    /// CRI-O
+    #[arg(
+        id = "collect.processes.crio-socket",
+        long = "collect.processes.crio-socket",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_PROCESSES_CRIO_SOCKET",
+        default_value = "/var/run/crio/crio.sock",
+        requires = "collect.processes"
+    )]
+    pub collect_processes_crio_socket: String,
+
+    /// Label the processes with their user, short command name and start time, from /proc
+    #[arg(
+        id = "collect.processes.metadata",
+        long = "collect.processes.metadata",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_PROCESSES_METADATA",
+        requires = "collect.processes"
+    )]
+    pub collect_processes_metadata: bool,
+
+    /// Also label the processes with their command line, cut at this many characters; 0 leaves
+    /// it out, since arguments may hold secrets
+    #[arg(
+        id = "collect.processes.cmdline-length",
+        long = "collect.processes.cmdline-length",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_PROCESSES_CMDLINE_LENGTH",
+        default_value_t = 0,
+        requires = "collect.processes.metadata"
+    )]
+    pub collect_processes_cmdline_length: usize,
+
+    /// File with a key to replace the user and slurm_user labels with a keyed hash of the user
+    /// name
+    #[arg(
+        id = "collect.processes.user-hash-key-file",
+        long = "collect.processes.user-hash-key-file",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_PROCESSES_USER_HASH_KEY_FILE"
+    )]
+    pub collect_processes_user_hash_key_file: Option<String>,
+
+    /// Export a series per MIG device (nvidia_mig_device_info) with its profile, and its pod with
+    /// --kubernetes.pod-labels
+    #[arg(
+        id = "collect.mig-devices",
+        long = "collect.mig-devices",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_MIG_DEVICES"
+    )]
+    pub collect_mig_devices: bool,
+
+    /// Export the NVIDIA GPUs bound to vfio-pci for passthrough to VMs
+    /// (nvidia_gpu_passthrough_info), which nvidia-smi cannot see
+    #[arg(
+        id = "collect.passthrough",
+        long = "collect.passthrough",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_PASSTHROUGH"
+    )]
+    pub collect_passthrough: bool,
+
+    /// libvirt's directory of running domain status files, to label passed through GPUs with the
+    /// domain using them; empty to not look
+    #[arg(
+        id = "collect.passthrough.libvirt-dir",
+        long = "collect.passthrough.libvirt-dir",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_PASSTHROUGH_LIBVIRT_DIR",
+        default_value = "/run/libvirt/qemu",
+        requires = "collect.passthrough"
+    )]
+    pub collect_passthrough_libvirt_dir: String,
+
+    /// Label the process metrics, and the metrics of GPUs used by a single job, with the Slurm
+    /// job id and user of the processes
+    #[arg(
+        id = "collect.slurm",
+        long = "collect.slurm",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_SLURM"
+    )]
+    pub collect_slurm: bool,
+
+    /// Also export AMD GPUs, read with amd-smi or rocm-smi, under the same metric names with a
+    /// vendor label
+    #[arg(
+        id = "collect.amd",
+        long = "collect.amd",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_AMD",
+        conflicts_with_all = [
+            "ssh.host",
+            "ssh.hosts-file",
+            "ssh.any-target",
+            "federate.target",
+            "hub.listen",
+        ]
+    )]
+    pub collect_amd: bool,
+
+    /// amd-smi or rocm-smi to run for AMD GPUs [default: amd-smi, else rocm-smi]
+    #[arg(
+        id = "collect.amd.command",
+        long = "collect.amd.command",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_AMD_COMMAND",
+        requires = "collect.amd"
+    )]
+    pub collect_amd_command: Option<String>,
+
+    /// Also export Intel data center GPUs, read with xpu-smi, under the same metric names with a
+    /// vendor label
+    #[arg(
+        id = "collect.intel",
+        long = "collect.intel",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_INTEL",
+        conflicts_with_all = [
+            "ssh.host",
+            "ssh.hosts-file",
+            "ssh.any-target",
+            "federate.target",
+            "hub.listen",
+        ]
+    )]
+    pub collect_intel: bool,
+
+    /// xpu-smi to run for Intel GPUs
+    #[arg(
+        id = "collect.intel.command",
+        long = "collect.intel.command",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_INTEL_COMMAND",
+        default_value = "xpu-smi",
+        requires = "collect.intel"
+    )]
+    pub collect_intel_command: String,
+
+    /// Collect the GPU of a Jetson module, which has no nvidia-smi, from tegrastats, with its
+    /// temperature sensors and power rails
+    #[arg(
+        id = "collect.jetson",
+        long = "collect.jetson",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_JETSON",
+        conflicts_with_all = [
+            "ssh.host",
+            "ssh.hosts-file",
+            "ssh.any-target",
+            "federate.target",
+            "hub.listen",
+        ]
+    )]
+    pub collect_jetson: bool,
+
+    /// tegrastats to run on Jetson modules
+    #[arg(
+        id = "collect.jetson.command",
+        long = "collect.jetson.command",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_JETSON_COMMAND",
+        default_value = "tegrastats",
+        requires = "collect.jetson"
+    )]
+    pub collect_jetson_command: String,
+
+    /// Listen to NVML events (XID errors, ECC errors, clock changes) between scrapes, counting
+    /// them and keeping the last ones as nvidia_recent_event_info
+    #[arg(
+        id = "collect.events",
+        long = "collect.events",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_EVENTS",
+        conflicts_with_all = [
+            "ssh.host",
+            "ssh.hosts-file",
+            "ssh.any-target",
+            "federate.target",
+            "hub.listen",
+        ]
+    )]
+    pub collect_events: bool,
+
+    /// Count the NVRM Xid messages of the kernel log, from /dev/kmsg or else journalctl -k, as
+    /// nvidia_kernel_xid_total
+    #[arg(
+        id = "collect.kernel-xid",
+        long = "collect.kernel-xid",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_KERNEL_XID",
+        conflicts_with_all = [
+            "ssh.host",
+            "ssh.hosts-file",
+            "ssh.any-target",
+            "federate.target",
+            "hub.listen",
+        ]
+    )]
+    pub collect_kernel_xid: bool,
+
+    /// Export the GPU interconnect of nvidia-smi topo -m (links between GPUs and NICs, NUMA
+    /// nodes), read every 10 minutes
+    #[arg(
+        id = "collect.topology",
+        long = "collect.topology",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_TOPOLOGY",
+        conflicts_with_all = [
+            "ssh.host",
+            "ssh.hosts-file",
+            "ssh.any-target",
+            "federate.target",
+            "hub.listen",
+        ]
+    )]
+    pub collect_topology: bool,
+
+    /// Export the chassis serial number, slot and tray of the GPUs from the platform section of
+    /// nvidia-smi -q on DGX/HGX and OEM systems, read every 10 minutes
+    #[arg(
+        id = "collect.platform",
+        long = "collect.platform",
+        env = "NVIDIA_SMI_EXPORTER_COLLECT_PLATFORM",
+        conflicts_with_all = [
+            "ssh.host",
+            "ssh.hosts-file",
+            "ssh.any-target",
+            "federate.target",
+            "hub.listen",
+        ]
+    )]
+    pub collect_platform: bool,
+
+    /// Label the metrics of GPUs allocated to a Kubernetes container with its namespace, pod and
+    /// container, from the kubelet's pod-resources API
+    #[arg(
+        id = "kubernetes.pod-labels",
+        long = "kubernetes.pod-labels",
+        env = "NVIDIA_SMI_EXPORTER_KUBERNETES_POD_LABELS"
+    )]
+    pub kubernetes_pod_labels: bool,
+
+    /// The kubelet's pod-resources socket
+    #[arg(
+        id = "kubernetes.pod-resources-socket",
+        long = "kubernetes.pod-resources-socket",
+        env = "NVIDIA_SMI_EXPORTER_KUBERNETES_POD_RESOURCES_SOCKET",
+        default_value = "/var/lib/kubelet/pod-resources/kubelet.sock",
+        requires = "kubernetes.pod-labels"
+    )]
+    pub kubernetes_pod_resources_socket: String,
+
+    /// Add a node label with the name of the Kubernetes node, e.g. from the Downward API's
+    /// spec.nodeName
+    #[arg(
+        id = "kubernetes.node-name",
+        long = "kubernetes.node-name",
+        env = "NVIDIA_SMI_EXPORTER_KUBERNETES_NODE_NAME"
+    )]
+    pub kubernetes_node_name: Option<String>,
+
+    /// Add a zone label with the topology zone of the node
+    #[arg(
+        id = "kubernetes.zone",
+        long = "kubernetes.zone",
+        env = "NVIDIA_SMI_EXPORTER_KUBERNETES_ZONE"
+    )]
+    pub kubernetes_zone: Option<String>,
+
+    /// Add the labels or annotations of a Downward API volume file as labels, named by the key
+    /// without its prefix; re-read on reload; repeatable
+    #[arg(
+        id = "kubernetes.metadata-file",
+        long = "kubernetes.metadata-file",
+        env = "NVIDIA_SMI_EXPORTER_KUBERNETES_METADATA_FILE",
+        value_delimiter = ','
+    )]
+    pub kubernetes_metadata_files: Vec<String>,
+
+    /// NAME=VALUE label added to every GPU metric; repeatable
+    #[arg(
+        id = "kubernetes.label",
+        long = "kubernetes.label",
+        env = "NVIDIA_SMI_EXPORTER_KUBERNETES_LABEL",
+        value_delimiter = ',',
+        value_parser = push::parse_label
+    )]
+    pub kubernetes_labels: Vec<(String, String)>,
+
+    /// Collect from this [user@]host[:port] over SSH instead of locally, adding a host label;
+    /// repeatable
+    #[arg(
+        id = "ssh.host",
+        long = "ssh.host",
+        env = "NVIDIA_SMI_EXPORTER_SSH_HOST",
+        value_delimiter = ',',
+        conflicts_with_all = [
+            "collect.processes",
+            "collect.mig-devices",
+            "collect.slurm",
+            "collect.passthrough",
+            "kubernetes.pod-labels",
+        ]
+    )]
+    pub ssh_hosts: Vec<Target>,
+
+    /// File with an SSH host per line, like --ssh.host; re-read on reload
+    #[arg(
+        id = "ssh.hosts-file",
+        long = "ssh.hosts-file",
+        env = "NVIDIA_SMI_EXPORTER_SSH_HOSTS_FILE",
+        conflicts_with_all = [
+            "collect.processes",
+            "collect.mig-devices",
+            "collect.slurm",
+            "collect.passthrough",
+            "kubernetes.pod-labels",
+        ]
+    )]
+    pub ssh_hosts_file: Option<String>,
+
+    /// Accept any [user@]host[:port] in /metrics?target=, not only the --ssh.host ones
+    #[arg(
+        id = "ssh.any-target",
+        long = "ssh.any-target",
+        env = "NVIDIA_SMI_EXPORTER_SSH_ANY_TARGET",
+        conflicts_with_all = [
+            "collect.processes",
+            "collect.mig-devices",
+            "collect.slurm",
+            "collect.passthrough",
+            "kubernetes.pod-labels",
+        ]
+    )]
+    pub ssh_any_target: bool,
+
+    /// Serve the GPU metrics of this downstream exporter, host:port or URL, with an instance
+    /// label instead of collecting locally; repeatable
+    #[arg(
+        id = "federate.target",
+        long = "federate.target",
+        env = "NVIDIA_SMI_EXPORTER_FEDERATE_TARGET",
+        value_delimiter = ',',
+        conflicts_with_all = [
+            "collect.processes",
+            "collect.mig-devices",
+            "collect.slurm",
+            "collect.passthrough",
+            "kubernetes.pod-labels",
+            "ssh.host",
+            "ssh.hosts-file",
+            "ssh.any-target",
+        ]
+    )]
+    pub federate_targets: Vec<Downstream>,
+
+    /// BMC to also read GPU temperature and power from over Redfish, e.g. https://bmc1.example.com
+    #[arg(
+        id = "redfish.url",
+        long = "redfish.url",
+        env = "NVIDIA_SMI_EXPORTER_REDFISH_URL",
+        conflicts_with = "federate.target"
+    )]
+    pub redfish_url: Option<String>,
+
+    /// Basic auth username for Redfish
+    #[arg(
+        id = "redfish.username",
+        long = "redfish.username",
+        env = "NVIDIA_SMI_EXPORTER_REDFISH_USERNAME",
+        requires_all = ["redfish.url", "redfish.password-file"]
+    )]
+    pub redfish_username: Option<String>,
+
+    /// File with the basic auth password for Redfish, re-read on every request
+    #[arg(
+        id = "redfish.password-file",
+        long = "redfish.password-file",
+        env = "NVIDIA_SMI_EXPORTER_REDFISH_PASSWORD_FILE",
+        requires = "redfish.username"
+    )]
+    pub redfish_password_file: Option<String>,
+
+    /// CA certificates, or the BMC's own certificate, to verify an https:// BMC with [default:
+    /// the Mozilla root certificates]
+    #[arg(
+        id = "redfish.ca-file",
+        long = "redfish.ca-file",
+        env = "NVIDIA_SMI_EXPORTER_REDFISH_CA_FILE",
+        requires = "redfish.url"
+    )]
+    pub redfish_ca_file: Option<String>,
+
+    /// Address to accept agents' gRPC streams on, e.g. 0.0.0.0:9401, serving their GPU metrics
+    /// with an instance label instead of collecting locally
+    #[arg(
+        id = "hub.listen",
+        long = "hub.listen",
+        env = "NVIDIA_SMI_EXPORTER_HUB_LISTEN",
+        conflicts_with_all = [
+            "collect.processes",
+            "collect.mig-devices",
+            "collect.slurm",
+            "collect.passthrough",
+            "kubernetes.pod-labels",
+            "ssh.host",
+            "ssh.hosts-file",
+            "ssh.any-target",
+            "federate.target",
+            "redfish.url",
+        ]
+    )]
+    pub hub_listen: Option<String>,
+
+    /// File with the token agents must send, re-read on every report
+    #[arg(
+        id = "hub.token-file",
+        long = "hub.token-file",
+        env = "NVIDIA_SMI_EXPORTER_HUB_TOKEN_FILE",
+        requires = "hub.listen"
+    )]
+    pub hub_token_file: Option<String>,
+
+    /// Seconds after which an agent that stopped reporting is no longer served
+    #[arg(
+        id = "hub.stale-after",
+        long = "hub.stale-after",
+        env = "NVIDIA_SMI_EXPORTER_HUB_STALE_AFTER",
+        default_value_t = 300,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub hub_stale_after: u64,
+
+    /// User to log in as on SSH hosts that do not name one [default: from the SSH config]
+    #[arg(
+        id = "ssh.user",
+        long = "ssh.user",
+        env = "NVIDIA_SMI_EXPORTER_SSH_USER"
+    )]
+    pub ssh_user: Option<String>,
+
+    /// Private key for SSH [default: from the SSH config or agent]
+    #[arg(
+        id = "ssh.identity-file",
+        long = "ssh.identity-file",
+        env = "NVIDIA_SMI_EXPORTER_SSH_IDENTITY_FILE"
+    )]
+    pub ssh_identity_file: Option<String>,
+
+    /// Extra ssh -o option, e.g. StrictHostKeyChecking=accept-new; repeatable
+    #[arg(
+        id = "ssh.option",
+        long = "ssh.option",
+        env = "NVIDIA_SMI_EXPORTER_SSH_OPTION"
+    )]
+    pub ssh_options: Vec<String>,
+
+    /// Seconds to wait for an SSH connection
+    #[arg(
+        id = "ssh.connect-timeout",
+        long = "ssh.connect-timeout",
+        env = "NVIDIA_SMI_EXPORTER_SSH_CONNECT_TIMEOUT",
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub ssh_connect_timeout: u64,
+
+    /// Only export GPUs with this index, UUID or name glob (e.g. 'NVIDIA A100*'); repeatable
+    #[arg(
+        id = "gpu-include",
+        long = "gpu-include",
+        env = "NVIDIA_SMI_EXPORTER_GPU_INCLUDE",
+        value_delimiter = ','
+    )]
+    pub gpu_include: Vec<GpuMatcher>,
+
+    /// Do not export GPUs with this index, UUID or name glob; repeatable
+    #[arg(
+        id = "gpu-exclude",
+        long = "gpu-exclude",
+        env = "NVIDIA_SMI_EXPORTER_GPU_EXCLUDE",
+        value_delimiter = ','
+    )]
+    pub gpu_exclude: Vec<GpuMatcher>,
+
+    /// Also export a numeric --query-gpu field as FIELD=METRIC[:HELP], e.g.
+    /// pcie.link.gen.current=nvidia_pcie_link_gen_current; repeatable
+    #[arg(
+        id = "query-field",
+        long = "query-field",
+        env = "NVIDIA_SMI_EXPORTER_QUERY_FIELD"
+    )]
+    pub query_fields: Vec<QueryField>,
+
+    /// Only export metrics whose whole name matches this regex; repeatable
+    #[arg(
+        id = "metric-include",
+        long = "metric-include",
+        env = "NVIDIA_SMI_EXPORTER_METRIC_INCLUDE"
+    )]
+    pub metric_include: Vec<Regex>,
+
+    /// Do not export metrics whose whole name matches this regex, e.g. 'nvidia_clocks_.*';
+    /// repeatable
+    #[arg(
+        id = "metric-exclude",
+        long = "metric-exclude",
+        env = "NVIDIA_SMI_EXPORTER_METRIC_EXCLUDE"
+    )]
+    pub metric_exclude: Vec<Regex>,
+
+    /// YAML list of relabel rules (replace, keep, drop, labeldrop, labelkeep, labelmap) applied to
+    /// the GPU metrics
+    #[arg(
+        id = "relabel.file",
+        long = "relabel.file",
+        env = "NVIDIA_SMI_EXPORTER_RELABEL_FILE"
+    )]
+    pub relabel_file: Option<String>,
+
+    /// Disable all collectors not enabled by --collector.<name>
+    #[arg(id = "collector.disable-defaults", long = "collector.disable-defaults")]
+    pub collector_disable_defaults: bool,
+
+    /// The collectors left enabled by `--[no-]collector.<name>`, which are added at runtime.
+    #[arg(skip)]
+    pub collectors: Vec<&'static Collector>,
+
+    /// Seconds to let in-flight scrapes finish after SIGTERM/SIGINT
+    #[arg(
+        id = "shutdown.timeout",
+        long = "shutdown.timeout",
+        env = "NVIDIA_SMI_EXPORTER_SHUTDOWN_TIMEOUT",
+        default_value_t = 10
+    )]
+    pub shutdown_timeout: u64,
+
+    /// Exclude process_* and other non-nvidia_* metrics from the telemetry path
+    #[arg(id = "disable-exporter-metrics", long = "disable-exporter-metrics")]
+    pub disable_exporter_metrics: bool,
+
+    /// Serve in-flight request and collection state at /debug/runtime
+    #[arg(id = "web.enable-debug-runtime", long = "web.enable-debug-runtime")]
+    pub debug_runtime: bool,
+
+    /// Serve the GPUs every second as Server-Sent Events at /stream
+    #[arg(id = "web.enable-stream", long = "web.enable-stream")]
+    pub enable_stream: bool,
+
+    /// Seconds of GPU values to keep in memory and serve at /history, collected every
+    /// --history.interval whether scraped or not; 0 disables
+    #[arg(
+        id = "history.retention",
+        long = "history.retention",
+        env = "NVIDIA_SMI_EXPORTER_HISTORY_RETENTION",
+        default_value_t = 0
+    )]
+    pub history_retention: u64,
+
+    /// Seconds between the collections kept for /history
+    #[arg(
+        id = "history.interval",
+        long = "history.interval",
+        env = "NVIDIA_SMI_EXPORTER_HISTORY_INTERVAL",
+        default_value_t = 15,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub history_interval: u64,
+
+    /// Price of a kWh, from which the cost of the energy each GPU draws is exported as
+    /// nvidia_energy_cost_total
+    #[arg(
+        id = "energy.price-per-kwh",
+        long = "energy.price-per-kwh",
+        env = "NVIDIA_SMI_EXPORTER_ENERGY_PRICE_PER_KWH",
+        conflicts_with_all = ["federate.target", "hub.listen"]
+    )]
+    pub energy_price_per_kwh: Option<f64>,
+
+    /// Currency of --energy.price-per-kwh, as the currency label
+    #[arg(
+        id = "energy.currency",
+        long = "energy.currency",
+        env = "NVIDIA_SMI_EXPORTER_ENERGY_CURRENCY",
+        default_value = "USD",
+        requires = "energy.price-per-kwh"
+    )]
+    pub energy_currency: String,
+
+    /// Grams of CO2-equivalent emitted per kWh, from which the emissions of each GPU are exported
+    /// as nvidia_co2e_grams_total
+    #[arg(
+        id = "energy.carbon-intensity",
+        long = "energy.carbon-intensity",
+        env = "NVIDIA_SMI_EXPORTER_ENERGY_CARBON_INTENSITY",
+        conflicts_with_all = ["energy.carbon-intensity-url", "federate.target", "hub.listen"]
+    )]
+    pub energy_carbon_intensity: Option<f64>,
+
+    /// URL answering the carbon intensity of the grid as JSON, e.g. Electricity Maps'
+    /// https://api.electricitymap.org/v3/carbon-intensity/latest?zone=DE, fetched instead of a
+    /// static --energy.carbon-intensity
+    #[arg(
+        id = "energy.carbon-intensity-url",
+        long = "energy.carbon-intensity-url",
+        env = "NVIDIA_SMI_EXPORTER_ENERGY_CARBON_INTENSITY_URL",
+        conflicts_with_all = ["federate.target", "hub.listen"]
+    )]
+    pub energy_carbon_intensity_url: Option<String>,
+
+    /// JSON pointer to the grams per kWh in the answer of --energy.carbon-intensity-url
+    #[arg(
+        id = "energy.carbon-intensity-pointer",
+        long = "energy.carbon-intensity-pointer",
+        env = "NVIDIA_SMI_EXPORTER_ENERGY_CARBON_INTENSITY_POINTER",
+        default_value = "/carbonIntensity",
+        requires = "energy.carbon-intensity-url"
+    )]
+    pub energy_carbon_intensity_pointer: String,
+
+    /// File with the API token of --energy.carbon-intensity-url, sent as the auth-token header
+    #[arg(
+        id = "energy.carbon-intensity-token-file",
+        long = "energy.carbon-intensity-token-file",
+        env = "NVIDIA_SMI_EXPORTER_ENERGY_CARBON_INTENSITY_TOKEN_FILE",
+        requires = "energy.carbon-intensity-url"
+    )]
+    pub energy_carbon_intensity_token_file: Option<String>,
+
+    /// Seconds between fetches of --energy.carbon-intensity-url
+    #[arg(
+        id = "energy.carbon-intensity-refresh",
+        long = "energy.carbon-intensity-refresh",
+        env = "NVIDIA_SMI_EXPORTER_ENERGY_CARBON_INTENSITY_REFRESH",
+        default_value_t = 900,
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "energy.carbon-intensity-url"
+    )]
+    pub energy_carbon_intensity_refresh: u64,
+
+    /// Seconds between the power readings the energy is counted from
+    #[arg(
+        id = "energy.interval",
+        long = "energy.interval",
+        env = "NVIDIA_SMI_EXPORTER_ENERGY_INTERVAL",
+        default_value_t = 15,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub energy_interval: u64,
+
+    /// GPU temperature in °C from which /health/gpus warns
+    #[arg(
+        id = "health.temperature-warn",
+        long = "health.temperature-warn",
+        env = "NVIDIA_SMI_EXPORTER_HEALTH_TEMPERATURE_WARN"
+    )]
+    pub health_temperature_warn: Option<f64>,
+
+    /// GPU temperature in °C from which /health/gpus fails
+    #[arg(
+        id = "health.temperature-fail",
+        long = "health.temperature-fail",
+        env = "NVIDIA_SMI_EXPORTER_HEALTH_TEMPERATURE_FAIL"
+    )]
+    pub health_temperature_fail: Option<f64>,
+
+    /// Uncorrected ECC errors since the driver loaded from which /health/gpus warns
+    #[arg(
+        id = "health.ecc-errors-warn",
+        long = "health.ecc-errors-warn",
+        env = "NVIDIA_SMI_EXPORTER_HEALTH_ECC_ERRORS_WARN"
+    )]
+    pub health_ecc_errors_warn: Option<f64>,
+
+    /// Uncorrected ECC errors since the driver loaded from which /health/gpus fails
+    #[arg(
+        id = "health.ecc-errors-fail",
+        long = "health.ecc-errors-fail",
+        env = "NVIDIA_SMI_EXPORTER_HEALTH_ECC_ERRORS_FAIL"
+    )]
+    pub health_ecc_errors_fail: Option<f64>,
+
+    /// Number of GPUs below which /health/gpus fails
+    #[arg(
+        id = "health.gpus",
+        long = "health.gpus",
+        env = "NVIDIA_SMI_EXPORTER_HEALTH_GPUS"
+    )]
+    pub health_gpus: Option<usize>,
+
+    /// Enable POST /-/reload to re-read the web config and auth files, and POST /-/quit to shut down
+    #[arg(id = "web.enable-lifecycle", long = "web.enable-lifecycle")]
+    pub enable_lifecycle: bool,
+
+    /// Only accept lifecycle requests from loopback addresses and Unix sockets
+    #[arg(
+        id = "web.lifecycle.localhost-only",
+        long = "web.lifecycle.localhost-only",
+        requires = "web.enable-lifecycle"
+    )]
+    pub lifecycle_localhost_only: bool,
+
+    /// Enable GET and POST /api/v1/admin/power-limit to read and set the GPUs' power limits;
+    /// needs authentication
+    #[arg(id = "web.enable-admin-api", long = "web.enable-admin-api")]
+    pub enable_admin_api: bool,
+
+    /// Export trace spans to this OTLP/HTTP URL, e.g. http://localhost:4318/v1/traces (requires
+    /// the `otlp` feature)
+    #[arg(
+        id = "otlp.endpoint",
+        long = "otlp.endpoint",
+        env = "NVIDIA_SMI_EXPORTER_OTLP_ENDPOINT"
+    )]
+    pub otlp_endpoint: Option<String>,
+
+    /// Seconds between collections pushed to the --push.* targets
+    #[arg(
+        id = "push.interval",
+        long = "push.interval",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_INTERVAL",
+        default_value_t = 15,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub push_interval: u64,
+
+    /// job label of pushed metrics
+    #[arg(
+        id = "push.job",
+        long = "push.job",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_JOB",
+        default_value = "nvidia-smi-exporter"
+    )]
+    pub push_job: String,
+
+    /// instance label of pushed metrics [default: the host name]
+    #[arg(
+        id = "push.instance",
+        long = "push.instance",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_INSTANCE"
+    )]
+    pub push_instance: Option<String>,
+
+    /// Push to this Prometheus remote-write URL, e.g. http://prometheus:9090/api/v1/write
+    #[arg(
+        id = "push.remote-write.url",
+        long = "push.remote-write.url",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_REMOTE_WRITE_URL"
+    )]
+    pub remote_write_url: Option<String>,
+
+    /// File with a bearer token for remote write, re-read on every push
+    #[arg(
+        id = "push.remote-write.bearer-token-file",
+        long = "push.remote-write.bearer-token-file",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_REMOTE_WRITE_BEARER_TOKEN_FILE",
+        requires = "push.remote-write.url",
+        conflicts_with = "push.remote-write.username"
+    )]
+    pub remote_write_bearer_token_file: Option<String>,
+
+    /// Basic auth user name for remote write
+    #[arg(
+        id = "push.remote-write.username",
+        long = "push.remote-write.username",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_REMOTE_WRITE_USERNAME",
+        requires_all = ["push.remote-write.url", "push.remote-write.password-file"]
+    )]
+    pub remote_write_username: Option<String>,
+
+    /// File with the basic auth password for remote write, re-read on every push
+    #[arg(
+        id = "push.remote-write.password-file",
+        long = "push.remote-write.password-file",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_REMOTE_WRITE_PASSWORD_FILE",
+        requires = "push.remote-write.username"
+    )]
+    pub remote_write_password_file: Option<String>,
+
+    /// Retries of a remote write failing with a connection error, 429 or 5xx, with exponential
+    /// backoff from 0.5s, within --push.interval
+    #[arg(
+        id = "push.remote-write.max-retries",
+        long = "push.remote-write.max-retries",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_REMOTE_WRITE_MAX_RETRIES",
+        default_value_t = 3
+    )]
+    pub remote_write_max_retries: u32,
+
+    /// Push to the Pushgateway at this URL, e.g. http://pushgateway:9091, grouped by job and
+    /// instance
+    #[arg(
+        id = "push.pushgateway.url",
+        long = "push.pushgateway.url",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_PUSHGATEWAY_URL"
+    )]
+    pub pushgateway_url: Option<String>,
+
+    /// Extra NAME=VALUE label of the Pushgateway grouping key; repeatable
+    #[arg(
+        id = "push.pushgateway.grouping",
+        long = "push.pushgateway.grouping",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_PUSHGATEWAY_GROUPING",
+        value_delimiter = ',',
+        value_parser = push::parse_label,
+        requires = "push.pushgateway.url"
+    )]
+    pub pushgateway_grouping: Vec<(String, String)>,
+
+    /// Delete the group from the Pushgateway on shutdown, so machines that are gone leave no
+    /// stale metrics behind
+    #[arg(
+        id = "push.pushgateway.delete-on-shutdown",
+        long = "push.pushgateway.delete-on-shutdown",
+        requires = "push.pushgateway.url"
+    )]
+    pub pushgateway_delete_on_shutdown: bool,
+
+    /// Push InfluxDB line protocol to the InfluxDB at this URL, e.g. http://influxdb:8086
+    #[arg(
+        id = "push.influxdb.url",
+        long = "push.influxdb.url",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_INFLUXDB_URL",
+        requires = "push.influxdb.bucket"
+    )]
+    pub influxdb_url: Option<String>,
+
+    /// InfluxDB organization
+    #[arg(
+        id = "push.influxdb.org",
+        long = "push.influxdb.org",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_INFLUXDB_ORG",
+        requires = "push.influxdb.url"
+    )]
+    pub influxdb_org: Option<String>,
+
+    /// InfluxDB bucket, or database/retention-policy for InfluxDB 1.8
+    #[arg(
+        id = "push.influxdb.bucket",
+        long = "push.influxdb.bucket",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_INFLUXDB_BUCKET",
+        requires = "push.influxdb.url"
+    )]
+    pub influxdb_bucket: Option<String>,
+
+    /// File with the InfluxDB API token, re-read on every push
+    #[arg(
+        id = "push.influxdb.token-file",
+        long = "push.influxdb.token-file",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_INFLUXDB_TOKEN_FILE",
+        requires = "push.influxdb.url"
+    )]
+    pub influxdb_token_file: Option<String>,
+
+    /// Push to the Graphite carbon plaintext receiver at this HOST:PORT, e.g. carbon:2003
+    #[arg(
+        id = "push.graphite.address",
+        long = "push.graphite.address",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_GRAPHITE_ADDRESS"
+    )]
+    pub graphite_address: Option<String>,
+
+    /// Send to Graphite over TCP or UDP
+    #[arg(
+        id = "push.graphite.protocol",
+        long = "push.graphite.protocol",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_GRAPHITE_PROTOCOL",
+        value_enum,
+        default_value_t = GraphiteProtocol::Tcp,
+        requires = "push.graphite.address"
+    )]
+    pub graphite_protocol: GraphiteProtocol,
+
+    /// Prefix of the Graphite metric paths, e.g. servers.gpu
+    #[arg(
+        id = "push.graphite.prefix",
+        long = "push.graphite.prefix",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_GRAPHITE_PREFIX",
+        default_value = "",
+        requires = "push.graphite.address"
+    )]
+    pub graphite_prefix: String,
+
+    /// Send labels as Graphite 1.1 tags instead of path components
+    #[arg(
+        id = "push.graphite.tagged",
+        long = "push.graphite.tagged",
+        requires = "push.graphite.address"
+    )]
+    pub graphite_tagged: bool,
+
+    /// Seconds between pushes to Graphite, to match its storage schema [default: --push.interval]
+    #[arg(
+        id = "push.graphite.interval",
+        long = "push.graphite.interval",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_GRAPHITE_INTERVAL",
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "push.graphite.address"
+    )]
+    pub graphite_interval: Option<u64>,
+
+    /// Send gauges to the StatsD server at this HOST:PORT over UDP, e.g. localhost:8125 for the
+    /// Datadog agent
+    #[arg(
+        id = "push.statsd.address",
+        long = "push.statsd.address",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_STATSD_ADDRESS"
+    )]
+    pub statsd_address: Option<String>,
+
+    /// Prefix of the StatsD metric names, e.g. gpu
+    #[arg(
+        id = "push.statsd.prefix",
+        long = "push.statsd.prefix",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_STATSD_PREFIX",
+        default_value = "",
+        requires = "push.statsd.address"
+    )]
+    pub statsd_prefix: String,
+
+    /// Send labels as DogStatsD tags instead of name components
+    #[arg(
+        id = "push.statsd.dogstatsd",
+        long = "push.statsd.dogstatsd",
+        requires = "push.statsd.address"
+    )]
+    pub statsd_dogstatsd: bool,
+
+    /// Push to this OTLP/HTTP metrics URL, e.g. http://otel-collector:4318/v1/metrics
+    #[arg(
+        id = "push.otlp.url",
+        long = "push.otlp.url",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_OTLP_URL"
+    )]
+    pub otlp_url: Option<String>,
+
+    /// Extra KEY=VALUE OpenTelemetry resource attribute, e.g. deployment.environment=prod;
+    /// repeatable
+    #[arg(
+        id = "push.otlp.resource-attribute",
+        long = "push.otlp.resource-attribute",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_OTLP_RESOURCE_ATTRIBUTE",
+        value_delimiter = ',',
+        value_parser = otlp::parse_attribute,
+        requires = "push.otlp.url"
+    )]
+    pub otlp_resource_attributes: Vec<(String, String)>,
+
+    /// File with a bearer token for OTLP, re-read on every push
+    #[arg(
+        id = "push.otlp.bearer-token-file",
+        long = "push.otlp.bearer-token-file",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_OTLP_BEARER_TOKEN_FILE",
+        requires = "push.otlp.url"
+    )]
+    pub otlp_bearer_token_file: Option<String>,
+
+    /// Send item values to the Zabbix server or proxy at this HOST:PORT, e.g. zabbix:10051
+    #[arg(
+        id = "push.zabbix.address",
+        long = "push.zabbix.address",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_ZABBIX_ADDRESS"
+    )]
+    pub zabbix_address: Option<String>,
+
+    /// Host name of the items in Zabbix [default: --push.instance]
+    #[arg(
+        id = "push.zabbix.host",
+        long = "push.zabbix.host",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_ZABBIX_HOST",
+        requires = "push.zabbix.address"
+    )]
+    pub zabbix_host: Option<String>,
+
+    /// Seconds between sending the GPU discovery to Zabbix while the GPUs stay the same
+    #[arg(
+        id = "push.zabbix.discovery-interval",
+        long = "push.zabbix.discovery-interval",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_ZABBIX_DISCOVERY_INTERVAL",
+        default_value_t = 3600,
+        requires = "push.zabbix.address"
+    )]
+    pub zabbix_discovery_interval: u64,
+
+    /// Publish a JSON message per GPU to the MQTT broker at this URL, e.g. mqtt://broker:1883 or
+    /// mqtts://broker:8883
+    #[arg(
+        id = "push.mqtt.url",
+        long = "push.mqtt.url",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_MQTT_URL"
+    )]
+    pub mqtt_url: Option<String>,
+
+    /// Topic of the messages; {job}, {instance}, {gpu} and {host} are replaced
+    #[arg(
+        id = "push.mqtt.topic",
+        long = "push.mqtt.topic",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_MQTT_TOPIC",
+        default_value = "nvidia-smi-exporter/{instance}/gpu/{gpu}",
+        requires = "push.mqtt.url"
+    )]
+    pub mqtt_topic: String,
+
+    /// QoS of the messages, 0 (at most once) or 1 (at least once)
+    #[arg(
+        id = "push.mqtt.qos",
+        long = "push.mqtt.qos",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_MQTT_QOS",
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u8).range(0..=1),
+        requires = "push.mqtt.url"
+    )]
+    pub mqtt_qos: u8,
+
+    /// Ask the broker to retain the last message of each topic for new subscribers
+    #[arg(
+        id = "push.mqtt.retain",
+        long = "push.mqtt.retain",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_MQTT_RETAIN",
+        requires = "push.mqtt.url"
+    )]
+    pub mqtt_retain: bool,
+
+    /// MQTT client identifier [default: nvidia-smi-exporter-<--push.instance>]
+    #[arg(
+        id = "push.mqtt.client-id",
+        long = "push.mqtt.client-id",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_MQTT_CLIENT_ID",
+        requires = "push.mqtt.url"
+    )]
+    pub mqtt_client_id: Option<String>,
+
+    /// User name for the MQTT broker
+    #[arg(
+        id = "push.mqtt.username",
+        long = "push.mqtt.username",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_MQTT_USERNAME",
+        requires = "push.mqtt.url"
+    )]
+    pub mqtt_username: Option<String>,
+
+    /// File with the password for the MQTT broker, re-read on every push
+    #[arg(
+        id = "push.mqtt.password-file",
+        long = "push.mqtt.password-file",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_MQTT_PASSWORD_FILE",
+        requires = "push.mqtt.username"
+    )]
+    pub mqtt_password_file: Option<String>,
+
+    /// CA certificates to verify an mqtts:// broker with [default: the Mozilla root certificates]
+    #[arg(
+        id = "push.mqtt.ca-file",
+        long = "push.mqtt.ca-file",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_MQTT_CA_FILE",
+        requires = "push.mqtt.url"
+    )]
+    pub mqtt_ca_file: Option<String>,
+
+    /// Client certificate for an mqtts:// broker
+    #[arg(
+        id = "push.mqtt.cert-file",
+        long = "push.mqtt.cert-file",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_MQTT_CERT_FILE",
+        requires_all = ["push.mqtt.url", "push.mqtt.key-file"]
+    )]
+    pub mqtt_cert_file: Option<String>,
+
+    /// Private key of --push.mqtt.cert-file
+    #[arg(
+        id = "push.mqtt.key-file",
+        long = "push.mqtt.key-file",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_MQTT_KEY_FILE",
+        requires = "push.mqtt.cert-file"
+    )]
+    pub mqtt_key_file: Option<String>,
+
+    /// Publish a JSON message per GPU to the NATS server at this URL, e.g. nats://nats:4222 or
+    /// tls://nats:4222
+    #[arg(
+        id = "push.nats.url",
+        long = "push.nats.url",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_NATS_URL"
+    )]
+    pub nats_url: Option<String>,
+
+    /// Subject of the messages; {job}, {instance}, {gpu} and {host} are replaced
+    #[arg(
+        id = "push.nats.subject",
+        long = "push.nats.subject",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_NATS_SUBJECT",
+        default_value = "nvidia-smi-exporter.{instance}.gpu.{gpu}",
+        requires = "push.nats.url"
+    )]
+    pub nats_subject: String,
+
+    /// Publish to JetStream, failing the push unless a stream acknowledges every message
+    #[arg(
+        id = "push.nats.jetstream",
+        long = "push.nats.jetstream",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_NATS_JETSTREAM",
+        requires = "push.nats.url"
+    )]
+    pub nats_jetstream: bool,
+
+    /// User name for the NATS server
+    #[arg(
+        id = "push.nats.username",
+        long = "push.nats.username",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_NATS_USERNAME",
+        requires_all = ["push.nats.url", "push.nats.password-file"],
+        conflicts_with = "push.nats.token-file"
+    )]
+    pub nats_username: Option<String>,
+
+    /// File with the password for the NATS server, re-read on every push
+    #[arg(
+        id = "push.nats.password-file",
+        long = "push.nats.password-file",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_NATS_PASSWORD_FILE",
+        requires = "push.nats.username"
+    )]
+    pub nats_password_file: Option<String>,
+
+    /// File with an authentication token for the NATS server, re-read on every push
+    #[arg(
+        id = "push.nats.token-file",
+        long = "push.nats.token-file",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_NATS_TOKEN_FILE",
+        requires = "push.nats.url"
+    )]
+    pub nats_token_file: Option<String>,
+
+    /// CA certificates to verify a tls:// server with [default: the Mozilla root certificates]
+    #[arg(
+        id = "push.nats.ca-file",
+        long = "push.nats.ca-file",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_NATS_CA_FILE",
+        requires = "push.nats.url"
+    )]
+    pub nats_ca_file: Option<String>,
+
+    /// Client certificate for a tls:// server
+    #[arg(
+        id = "push.nats.cert-file",
+        long = "push.nats.cert-file",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_NATS_CERT_FILE",
+        requires_all = ["push.nats.url", "push.nats.key-file"]
+    )]
+    pub nats_cert_file: Option<String>,
+
+    /// Private key of --push.nats.cert-file
+    #[arg(
+        id = "push.nats.key-file",
+        long = "push.nats.key-file",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_NATS_KEY_FILE",
+        requires = "push.nats.cert-file"
+    )]
+    pub nats_key_file: Option<String>,
+
+    /// Send a record per GPU to the Fluentd or Fluent Bit forward input at this HOST:PORT, e.g.
+    /// localhost:24224
+    #[arg(
+        id = "push.fluentd.address",
+        long = "push.fluentd.address",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_FLUENTD_ADDRESS"
+    )]
+    pub fluentd_address: Option<String>,
+
+    /// Tag of the records
+    #[arg(
+        id = "push.fluentd.tag",
+        long = "push.fluentd.tag",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_FLUENTD_TAG",
+        default_value = "nvidia-smi-exporter.gpu",
+        requires = "push.fluentd.address"
+    )]
+    pub fluentd_tag: String,
+
+    /// Fail the push unless the receiver acknowledges the records
+    #[arg(
+        id = "push.fluentd.require-ack",
+        long = "push.fluentd.require-ack",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_FLUENTD_REQUIRE_ACK",
+        requires = "push.fluentd.address"
+    )]
+    pub fluentd_require_ack: bool,
+
+    /// Produce a record per sample to these Kafka bootstrap brokers, HOST:PORT[,HOST:PORT...]
+    #[arg(
+        id = "push.kafka.brokers",
+        long = "push.kafka.brokers",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_KAFKA_BROKERS",
+        value_delimiter = ','
+    )]
+    pub kafka_brokers: Vec<String>,
+
+    /// Kafka topic of the records
+    #[arg(
+        id = "push.kafka.topic",
+        long = "push.kafka.topic",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_KAFKA_TOPIC",
+        default_value = "nvidia-smi-exporter",
+        requires = "push.kafka.brokers"
+    )]
+    pub kafka_topic: String,
+
+    /// Encoding of the record values
+    #[arg(
+        id = "push.kafka.format",
+        long = "push.kafka.format",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_KAFKA_FORMAT",
+        value_enum,
+        default_value_t = KafkaFormat::Json,
+        requires = "push.kafka.brokers"
+    )]
+    pub kafka_format: KafkaFormat,
+
+    /// Confluent schema registry to register the Avro schema with, required by --push.kafka.format
+    /// avro
+    #[arg(
+        id = "push.kafka.schema-registry-url",
+        long = "push.kafka.schema-registry-url",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_KAFKA_SCHEMA_REGISTRY_URL",
+        requires = "push.kafka.brokers"
+    )]
+    pub kafka_schema_registry_url: Option<String>,
+
+    /// Seconds between records sent to Kafka [default: --push.interval]
+    #[arg(
+        id = "push.kafka.interval",
+        long = "push.kafka.interval",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_KAFKA_INTERVAL",
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "push.kafka.brokers"
+    )]
+    pub kafka_interval: Option<u64>,
+
+    /// Send the GPU metrics to Amazon CloudWatch in this region, e.g. us-east-1
+    #[arg(
+        id = "push.cloudwatch.region",
+        long = "push.cloudwatch.region",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_CLOUDWATCH_REGION"
+    )]
+    pub cloudwatch_region: Option<String>,
+
+    /// CloudWatch namespace of the metrics
+    #[arg(
+        id = "push.cloudwatch.namespace",
+        long = "push.cloudwatch.namespace",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_CLOUDWATCH_NAMESPACE",
+        default_value = "NVIDIA/GPU",
+        requires = "push.cloudwatch.region"
+    )]
+    pub cloudwatch_namespace: String,
+
+    /// Comma-separated labels sent as CloudWatch dimensions
+    #[arg(
+        id = "push.cloudwatch.dimensions",
+        long = "push.cloudwatch.dimensions",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_CLOUDWATCH_DIMENSIONS",
+        value_delimiter = ',',
+        default_value = "instance,gpu",
+        requires = "push.cloudwatch.region"
+    )]
+    pub cloudwatch_dimensions: Vec<String>,
+
+    /// NAME=VALUE dimension added to every metric, e.g. InstanceId=i-0123456789abcdef0;
+    /// repeatable
+    #[arg(
+        id = "push.cloudwatch.static-dimension",
+        long = "push.cloudwatch.static-dimension",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_CLOUDWATCH_STATIC_DIMENSION",
+        value_delimiter = ',',
+        value_parser = push::parse_label,
+        requires = "push.cloudwatch.region"
+    )]
+    pub cloudwatch_static_dimensions: Vec<(String, String)>,
+
+    /// CloudWatch endpoint URL, e.g. for a VPC endpoint [default: https://monitoring.<region>.amazonaws.com/]
+    #[arg(
+        id = "push.cloudwatch.endpoint",
+        long = "push.cloudwatch.endpoint",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_CLOUDWATCH_ENDPOINT",
+        requires = "push.cloudwatch.region"
+    )]
+    pub cloudwatch_endpoint: Option<String>,
+
+    /// Send the GPU metrics to Google Cloud Monitoring, authenticating as the GCE or GKE
+    /// (Workload Identity) service account
+    #[arg(
+        id = "push.cloud-monitoring",
+        long = "push.cloud-monitoring",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_CLOUD_MONITORING"
+    )]
+    pub cloud_monitoring: bool,
+
+    /// Google Cloud project to write the metrics to [default: the node's project]
+    #[arg(
+        id = "push.cloud-monitoring.project",
+        long = "push.cloud-monitoring.project",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_CLOUD_MONITORING_PROJECT",
+        requires = "push.cloud-monitoring"
+    )]
+    pub cloud_monitoring_project: Option<String>,
+
+    /// Prefix of the metric types, followed by / and the metric name
+    #[arg(
+        id = "push.cloud-monitoring.metric-prefix",
+        long = "push.cloud-monitoring.metric-prefix",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_CLOUD_MONITORING_METRIC_PREFIX",
+        default_value = "custom.googleapis.com",
+        requires = "push.cloud-monitoring"
+    )]
+    pub cloud_monitoring_metric_prefix: String,
+
+    /// Cloud Monitoring API endpoint URL [default: https://monitoring.googleapis.com/]
+    #[arg(
+        id = "push.cloud-monitoring.endpoint",
+        long = "push.cloud-monitoring.endpoint",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_CLOUD_MONITORING_ENDPOINT",
+        requires = "push.cloud-monitoring"
+    )]
+    pub cloud_monitoring_endpoint: Option<String>,
+
+    /// Send the GPU metrics to Azure Monitor as custom metrics of this VM, authenticating with
+    /// its managed identity
+    #[arg(
+        id = "push.azure-monitor",
+        long = "push.azure-monitor",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_AZURE_MONITOR"
+    )]
+    pub azure_monitor: bool,
+
+    /// Custom metric namespace in Azure Monitor
+    #[arg(
+        id = "push.azure-monitor.namespace",
+        long = "push.azure-monitor.namespace",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_AZURE_MONITOR_NAMESPACE",
+        default_value = "nvidia-smi-exporter",
+        requires = "push.azure-monitor"
+    )]
+    pub azure_monitor_namespace: String,
+
+    /// Client id of the user-assigned managed identity to use [default: the system-assigned one]
+    #[arg(
+        id = "push.azure-monitor.client-id",
+        long = "push.azure-monitor.client-id",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_AZURE_MONITOR_CLIENT_ID",
+        requires = "push.azure-monitor"
+    )]
+    pub azure_monitor_client_id: Option<String>,
+
+    /// Azure region of the resource, e.g. eastus [default: the VM's]
+    #[arg(
+        id = "push.azure-monitor.region",
+        long = "push.azure-monitor.region",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_AZURE_MONITOR_REGION",
+        requires = "push.azure-monitor"
+    )]
+    pub azure_monitor_region: Option<String>,
+
+    /// Resource id the metrics are attached to [default: the VM's]
+    #[arg(
+        id = "push.azure-monitor.resource-id",
+        long = "push.azure-monitor.resource-id",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_AZURE_MONITOR_RESOURCE_ID",
+        requires = "push.azure-monitor"
+    )]
+    pub azure_monitor_resource_id: Option<String>,
+
+    /// Azure Monitor endpoint URL [default: https://<region>.monitoring.azure.com]
+    #[arg(
+        id = "push.azure-monitor.endpoint",
+        long = "push.azure-monitor.endpoint",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_AZURE_MONITOR_ENDPOINT",
+        requires = "push.azure-monitor"
+    )]
+    pub azure_monitor_endpoint: Option<String>,
+
+    /// Index documents into Elasticsearch or OpenSearch at this URL, e.g. http://localhost:9200
+    #[arg(
+        id = "push.elasticsearch.url",
+        long = "push.elasticsearch.url",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_ELASTICSEARCH_URL"
+    )]
+    pub elasticsearch_url: Option<String>,
+
+    /// Index or data stream written to, with strftime escapes for the collection time in UTC
+    #[arg(
+        id = "push.elasticsearch.index",
+        long = "push.elasticsearch.index",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_ELASTICSEARCH_INDEX",
+        default_value = "nvidia-smi-exporter-%Y.%m.%d",
+        requires = "push.elasticsearch.url"
+    )]
+    pub elasticsearch_index: String,
+
+    /// Basic auth username for Elasticsearch
+    #[arg(
+        id = "push.elasticsearch.username",
+        long = "push.elasticsearch.username",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_ELASTICSEARCH_USERNAME",
+        requires_all = ["push.elasticsearch.url", "push.elasticsearch.password-file"],
+        conflicts_with = "push.elasticsearch.api-key-file"
+    )]
+    pub elasticsearch_username: Option<String>,
+
+    /// File with the basic auth password for Elasticsearch, re-read on every push
+    #[arg(
+        id = "push.elasticsearch.password-file",
+        long = "push.elasticsearch.password-file",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_ELASTICSEARCH_PASSWORD_FILE",
+        requires = "push.elasticsearch.username"
+    )]
+    pub elasticsearch_password_file: Option<String>,
+
+    /// File with an Elasticsearch API key (the base64 encoded id:key), re-read on every push
+    #[arg(
+        id = "push.elasticsearch.api-key-file",
+        long = "push.elasticsearch.api-key-file",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_ELASTICSEARCH_API_KEY_FILE",
+        requires = "push.elasticsearch.url"
+    )]
+    pub elasticsearch_api_key_file: Option<String>,
+
+    /// Seconds between documents sent to Elasticsearch [default: --push.interval]
+    #[arg(
+        id = "push.elasticsearch.interval",
+        long = "push.elasticsearch.interval",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_ELASTICSEARCH_INTERVAL",
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "push.elasticsearch.url"
+    )]
+    pub elasticsearch_interval: Option<u64>,
+
+    /// Stream the metrics over gRPC to the --hub.listen of a hub exporter, host:port or
+    /// https:// URL
+    #[arg(
+        id = "push.hub.url",
+        long = "push.hub.url",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_HUB_URL"
+    )]
+    pub push_hub_url: Option<String>,
+
+    /// File with the token of the hub's --hub.token-file, re-read on every push
+    #[arg(
+        id = "push.hub.token-file",
+        long = "push.hub.token-file",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_HUB_TOKEN_FILE",
+        requires = "push.hub.url"
+    )]
+    pub push_hub_token_file: Option<String>,
+
+    /// CA certificates to verify an https:// hub or the proxy in front of it with [default: the
+    /// Mozilla root certificates]
+    #[arg(
+        id = "push.hub.ca-file",
+        long = "push.hub.ca-file",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_HUB_CA_FILE",
+        requires = "push.hub.url"
+    )]
+    pub push_hub_ca_file: Option<String>,
+
+    /// Seconds between reports to the hub [default: --push.interval]
+    #[arg(
+        id = "push.hub.interval",
+        long = "push.hub.interval",
+        env = "NVIDIA_SMI_EXPORTER_PUSH_HUB_INTERVAL",
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "push.hub.url"
+    )]
+    pub push_hub_interval: Option<u64>,
+
+    /// YAML list of threshold rules evaluated every --push.interval, re-read when it changes
+    #[arg(
+        id = "alert.rules-file",
+        long = "alert.rules-file",
+        env = "NVIDIA_SMI_EXPORTER_ALERT_RULES_FILE",
+        requires = "alert.webhook-url"
+    )]
+    pub alert_rules_file: Option<String>,
+
+    /// URL to POST firing and resolved alerts to
+    #[arg(
+        id = "alert.webhook-url",
+        long = "alert.webhook-url",
+        env = "NVIDIA_SMI_EXPORTER_ALERT_WEBHOOK_URL",
+        requires = "alert.rules-file"
+    )]
+    pub alert_webhook_url: Option<String>,
+
+    /// Payload of the alert notifications
+    #[arg(
+        id = "alert.webhook-format",
+        long = "alert.webhook-format",
+        env = "NVIDIA_SMI_EXPORTER_ALERT_WEBHOOK_FORMAT",
+        value_enum,
+        default_value_t = AlertFormat::Webhook,
+        requires = "alert.webhook-url"
+    )]
+    pub alert_webhook_format: AlertFormat,
+
+    /// Serve the GPU metrics over SNMP as an AgentX subagent of the master agent at this
+    /// host:port or unix:/path, e.g. unix:/var/agentx/master; refreshed every --push.interval
+    #[arg(
+        id = "agentx.master",
+        long = "agentx.master",
+        env = "NVIDIA_SMI_EXPORTER_AGENTX_MASTER"
+    )]
+    pub agentx_master: Option<String>,
+
+    /// OID of the subtree registered with the AgentX master agent
+    #[arg(
+        id = "agentx.oid",
+        long = "agentx.oid",
+        env = "NVIDIA_SMI_EXPORTER_AGENTX_OID",
+        default_value = "1.3.6.1.4.1.8072.9999.9999",
+        requires = "agentx.master"
+    )]
+    pub agentx_oid: Oid,
+
+    /// HOST:PORT Prometheus should scrape, for /api/v1/targets, Consul and mDNS [default: the
+    /// host name and the --listen port]
+    #[arg(
+        id = "sd.advertise-address",
+        long = "sd.advertise-address",
+        env = "NVIDIA_SMI_EXPORTER_SD_ADVERTISE_ADDRESS"
+    )]
+    pub sd_advertise_address: Option<String>,
+
+    /// NAME=VALUE label for /api/v1/targets, Consul service metadata and mDNS TXT records;
+    /// repeatable
+    #[arg(
+        id = "sd.label",
+        long = "sd.label",
+        env = "NVIDIA_SMI_EXPORTER_SD_LABEL",
+        value_delimiter = ',',
+        value_parser = push::parse_label
+    )]
+    pub sd_labels: Vec<(String, String)>,
+
+    /// Register with the Consul agent at this URL, e.g. http://localhost:8500, and deregister on
+    /// shutdown
+    #[arg(
+        id = "consul.address",
+        long = "consul.address",
+        env = "NVIDIA_SMI_EXPORTER_CONSUL_ADDRESS"
+    )]
+    pub consul_address: Option<String>,
+
+    /// Consul service name
+    #[arg(
+        id = "consul.service",
+        long = "consul.service",
+        env = "NVIDIA_SMI_EXPORTER_CONSUL_SERVICE",
+        default_value = "nvidia-smi-exporter",
+        requires = "consul.address"
+    )]
+    pub consul_service: String,
+
+    /// Consul service tag; repeatable
+    #[arg(
+        id = "consul.tag",
+        long = "consul.tag",
+        env = "NVIDIA_SMI_EXPORTER_CONSUL_TAG",
+        value_delimiter = ',',
+        requires = "consul.address"
+    )]
+    pub consul_tags: Vec<String>,
+
+    /// File with a Consul ACL token, re-read on every request
+    #[arg(
+        id = "consul.token-file",
+        long = "consul.token-file",
+        env = "NVIDIA_SMI_EXPORTER_CONSUL_TOKEN_FILE",
+        requires = "consul.address"
+    )]
+    pub consul_token_file: Option<String>,
+
+    /// Announce the exporter on the local network as a _prometheus-http._tcp DNS-SD service over
+    /// mDNS
+    #[arg(id = "mdns", long = "mdns")]
+    pub mdns: bool,
+
+    /// DNS-SD instance name announced over mDNS [default: the advertised HOST:PORT]
+    #[arg(
+        id = "mdns.instance",
+        long = "mdns.instance",
+        env = "NVIDIA_SMI_EXPORTER_MDNS_INSTANCE",
+        requires = "mdns"
+    )]
+    pub mdns_instance: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Serves the metrics over HTTP (the default without a subcommand)
+    Serve,
+    /// Collects once and writes the metrics to stdout
+    Print {
+        /// Write to this file instead, atomically and without the process_* metrics, e.g. for
+        /// node_exporter's textfile collector
+        #[arg(short, long)]
+        output: Option<String>,
+        /// prometheus text format, or influx line protocol, e.g. for Telegraf's exec input
+        #[arg(long, value_enum, default_value_t = OutputFormat::Prometheus)]
+        format: OutputFormat,
+        /// Write the file again every this many seconds until SIGTERM, instead of once
+        #[arg(long, default_value_t = 0, requires = "output")]
+        interval: u64,
+        /// Remove the file once no collection was written for this many seconds, and on
+        /// shutdown, so that node_exporter does not serve stale readings
+        #[arg(long, requires = "interval")]
+        remove_stale_after: Option<u64>,
+    },
+    /// Checks nvidia-smi finds GPUs to export and lists them; exits 2 if there are none, 3 if the
+    /// collection fails. With thresholds, prints a Nagios plugin status line and exits 0 (OK), 1
+    /// (WARNING), 2 (CRITICAL) or 3 (UNKNOWN)
+    Check {
+        #[command(flatten)]
+        thresholds: Thresholds,
+    },
+    /// Runs as a Netdata external plugin, writing charts to stdout every UPDATE_EVERY seconds
+    Netdata {
+        /// Seconds between collections, passed by Netdata
+        #[arg(default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+        update_every: u64,
+    },
+    /// Validates a --config file together with the environment and exits non-zero on problems
+    CheckConfig {
+        /// YAML or TOML config file
+        file: String,
+    },
+    /// Prints a JSON Schema for --config files, for editors and CI
+    ConfigSchema,
+    /// Runs one collection from a --capture-dir capture instead of nvidia-smi and prints the
+    /// metrics, or the error the exporter hit; give it the options of the captured run
+    Replay {
+        /// The .capture file
+        file: String,
+    },
+    /// Prints a shell completion script
+    Completions { shell: Shell },
+    /// Prints a man page in roff format, for packaging
+    #[command(hide = true)]
+    Manpage,
+}
+
+/// Options without a value; clap reads the environment only for the others.
+const SWITCHES: &[&str] = &[
+    "verbose",
+    "dry-run",
+    "daemonize",
+    "sandbox",
+    "web.access-log",
+    "web.reuse-port",
+    "disable-exporter-metrics",
+    "collect.processes",
+    "collect.processes.metadata",
+    "collect.mig-devices",
+    "collect.passthrough",
+    "collect.slurm",
+    "collect.amd",
+    "collect.intel",
+    "collect.jetson",
+    "collect.events",
+    "collect.kernel-xid",
+    "collect.topology",
+    "collect.platform",
+    "kubernetes.pod-labels",
+    "ssh.any-target",
+    "collector.disable-defaults",
+    "web.enable-debug-runtime",
+    "web.enable-stream",
+    "web.enable-lifecycle",
+    "web.lifecycle.localhost-only",
+    "web.enable-admin-api",
+    "push.pushgateway.delete-on-shutdown",
+    "push.graphite.tagged",
+    "push.statsd.dogstatsd",
+    "push.mqtt.retain",
+    "push.nats.jetstream",
+    "push.fluentd.require-ack",
+    "push.cloud-monitoring",
+    "push.azure-monitor",
+    "mdns",
+];
+
+/// The derived options plus the `--[no-]collector.<name>` flags, all also accepted after a
+/// subcommand.
+pub fn command() -> clap::Command {
+    Cli::command()
+        .args(collector::args())
+        .mut_args(|arg| arg.global(true))
+}
+
+/// Parses a command line together with the environment and `--config` file.
+pub fn parse(args: &[OsString]) -> Result<Cli> {
+    let matches = command()
+        .try_get_matches_from(args)
+        .map_err(|e| anyhow!("{}", message(&e)))?;
+    from_matches(args, matches)
+}
+
+/// Adds the options set in the environment and `--config` file to the command line's.
+pub fn from_matches(args: &[OsString], mut matches: ArgMatches) -> Result<Cli> {
+    // 优先级：命令行 > 环境变量 > 配置文件
+    let switches = SWITCHES
+        .iter()
+        .copied()
+        .chain(collector::switches())
+        .collect::<Vec<_>>();
+    let mut extra = configfile::env_switches(&switches, &matches)?;
+    if let Some(path) = matches.get_one::<String>("config") {
+        extra.extend(configfile::args(path, &matches)?);
+    }
+    if !extra.is_empty() {
+        matches = command()
+            .try_get_matches_from(args.iter().cloned().chain(extra))
+            .map_err(|e| anyhow!("Invalid environment or --config options: {}", message(&e)))?;
+    }
+    let mut cli = Cli::from_arg_matches(&matches)?;
+    cli.collectors = collector::enabled(&matches);
+    Ok(cli)
+}
+
+/// A clap error on one line, with the arguments it lists and its tips but without the usage and
+/// help hints.
+fn message(e: &clap::Error) -> String {
+    let rendered = e.to_string();
+    let mut message = String::new();
+    for line in rendered
+        .lines()
+        .map(str::trim)
+        .take_while(|line| !line.starts_with("Usage:"))
+        .filter(|line| !line.is_empty())
+    {
+        if message.is_empty() {
+            message.push_str(line.strip_prefix("error: ").unwrap_or(line));
+            continue;
+        }
+        // 缺少的参数各占一行，接在冒号后面
+        message.push_str(if message.ends_with(':') {
+            " "
+        } else if line.starts_with("tip:") {
+            "; "
+        } else {
+            ", "
+        });
+        message.push_str(line);
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(args: &[&str]) -> String {
+        let args = std::iter::once("nvidia-smi-exporter").chain(args.iter().copied());
+        message(&command().try_get_matches_from(args).unwrap_err())
+    }
+
+    #[test]
+    fn names_missing_arguments() {
+        assert_eq!(
+            error(&["--tls-cert", "cert.pem"]),
+            "the following required arguments were not provided: --tls-key <tls-key>"
+        );
+    }
+
+    #[test]
+    fn keeps_tips() {
+        assert_eq!(
+            error(&["--tls-certs", "cert.pem"]),
+            "unexpected argument '--tls-certs' found; tip: a similar argument exists: '--tls-cert'"
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use surf::Url;
+
+use crate::cli::Cli;
+use crate::push::{Batch, Target};
+
+const ENDPOINT: &str = "https://monitoring.googleapis.com/";
+const METADATA_HOST: &str = "metadata.google.internal";
+/// `timeSeries.create` takes up to 200 time series per request.
+const MAX_SERIES: usize = 200;
+/// Access tokens are renewed this long before they expire.
+const REFRESH_BEFORE: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// The project and monitored resource of this node, from the metadata server.
+#[derive(Clone)]
+struct Node {
+    project: String,
+    resource: Value,
+}
+
+/// Google Cloud Monitoring (`--push.cloud-monitoring`), sent the GPU samples as custom metrics.
+/// The access token, project and monitored resource come from the GCE metadata server, which
+/// on GKE with Workload Identity serves the Kubernetes service account's Google identity.
+pub struct CloudMonitoring {
+    client: surf::Client,
+    endpoint: Url,
+    metadata: String,
+    project: Option<String>,
+    prefix: String,
+    node: Mutex<Option<Node>>,
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl CloudMonitoring {
+    pub fn new(cli: &Cli) -> Result<Self> {
+        let endpoint = cli.cloud_monitoring_endpoint.as_deref().unwrap_or(ENDPOINT);
+        let endpoint = Url::parse(endpoint)
+            .with_context(|| format!("Invalid --push.cloud-monitoring.endpoint {}", endpoint))?;
+        // 与 Google 的客户端库一样，可以用 GCE_METADATA_HOST 换掉元数据服务器
+        let metadata = std::env::var("GCE_METADATA_HOST").unwrap_or(METADATA_HOST.to_string());
+        Ok(CloudMonitoring {
+            client: surf::Client::new(),
+            endpoint,
+            metadata: format!("http://{}/computeMetadata/v1/", metadata),
+            project: cli.cloud_monitoring_project.clone(),
+            prefix: cli
+                .cloud_monitoring_metric_prefix
+                .trim_end_matches('/')
+                .to_string(),
+            node: Mutex::new(None),
+            token: Mutex::new(None),
+        })
+    }
+
+    /// A metadata value, or None when the server does not have it.
+    async fn metadata(&self, path: &str) -> Result<Option<String>> {
+        let mut response = self
+            .client
+            .get(format!("{}{}", self.metadata, path))
+            .header("Metadata-Flavor", "Google")
+            .await
+            .map_err(|e| e.into_inner())
+            .context("Failed to reach the GCE metadata server")?;
+        // 404 的响应体也要读完，连接才能复用
+        let body = response.body_string().await.map_err(|e| e.into_inner())?;
+        if response.status() == 404 {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            bail!(
+                "Metadata server returned {} for {}",
+                response.status(),
+                path
+            );
+        }
+        Ok(Some(body))
+    }
+
+    async fn token(&self) -> Result<String> {
+        if let Some((token, expires)) = &*self.token.lock().unwrap() {
+            if Instant::now() + REFRESH_BEFORE < *expires {
+                return Ok(token.clone());
+            }
+        }
+        let body = self
+            .metadata("instance/service-accounts/default/token")
+            .await?
+            .ok_or_else(|| anyhow!("The instance has no service account"))?;
+        let token: Token = serde_json::from_str(&body).context("Invalid access token")?;
+        let expires = Instant::now() + Duration::from_secs(token.expires_in);
+        *self.token.lock().unwrap() = Some((token.access_token.clone(), expires));
+        Ok(token.access_token)
+    }
+
+    /// A `k8s_node` on GKE, otherwise a `gce_instance`.
+    async fn node(&self) -> Result<Node> {
+        if let Some(node) = &*self.node.lock().unwrap() {
+            return Ok(node.clone());
+        }
+        let required = |value: Option<String>, what: &str| {
+            value.ok_or_else(|| anyhow!("No {} from the metadata server", what))
+        };
+        let project = match &self.project {
+            Some(project) => project.clone(),
+            None => required(self.metadata("project/project-id").await?, "project id")?,
+        };
+        let resource = match self.metadata("instance/attributes/cluster-name").await? {
+            Some(cluster) => json!({
+                "type": "k8s_node",
+                "labels": {
+                    "project_id": project,
+                    "location": required(
+                        self.metadata("instance/attributes/cluster-location").await?,
+                        "cluster location",
+                    )?,
+                    "cluster_name": cluster,
+                    "node_name": required(self.metadata("instance/name").await?, "instance name")?,
+                },
+            }),
+            None => {
+                // 形如 projects/123/zones/us-central1-a
+                let zone = required(self.metadata("instance/zone").await?, "zone")?;
+                json!({
+                    "type": "gce_instance",
+                    "labels": {
+                        "project_id": project,
+                        "instance_id": required(self.metadata("instance/id").await?, "instance id")?,
+                        "zone": zone.rsplit('/').next().unwrap_or_default(),
+                    },
+                })
+            }
+        };
+        let node = Node { project, resource };
+        *self.node.lock().unwrap() = Some(node.clone());
+        Ok(node)
+    }
+}
+
+#[tide::utils::async_trait]
+impl Target for CloudMonitoring {
+    fn name(&self) -> &'static str {
+        "cloud_monitoring"
+    }
+
+    async fn push(&self, batch: &Batch) -> Result<()> {
+        let node = self.node().await?;
+        let end_time =
+            DateTime::<Utc>::from(batch.timestamp).to_rfc3339_opts(SecondsFormat::Millis, true);
+        let series = batch
+            .samples
+            .iter()
+            .filter(|s| s.value.is_finite() && s.labels.iter().any(|(name, _)| name == "gpu"))
+            .map(|sample| {
+                let labels = sample
+                    .labels
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.clone().into()))
+                    .collect::<Map<_, _>>();
+                json!({
+                    "metric": {
+                        "type": format!("{}/{}", self.prefix, sample.name),
+                        "labels": labels,
+                    },
+                    "resource": node.resource,
+                    "metricKind": "GAUGE",
+                    "valueType": "DOUBLE",
+                    "points": [{
+                        "interval": { "endTime": end_time },
+                        "value": { "doubleValue": sample.value },
+                    }],
+                })
+            })
+            .collect::<Vec<_>>();
+        let url = self
+            .endpoint
+            .join(&format!("v3/projects/{}/timeSeries", node.project))
+            .map_err(|e| anyhow!("Invalid Cloud Monitoring URL: {}", e))?;
+        for chunk in series.chunks(MAX_SERIES) {
+            let request = surf::post(url.clone())
+                .header("Authorization", format!("Bearer {}", self.token().await?))
+                .body(json!({ "timeSeries": chunk }));
+            let mut response = self
+                .client
+                .send(request)
+                .await
+                .map_err(|e| e.into_inner())?;
+            // 成功时也读完响应体，下一个请求才能复用连接
+            let text = response.body_string().await.unwrap_or_default();
+            if !response.status().is_success() {
+                bail!("{} {}", response.status(), text.trim());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,320 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use ring::{digest, hmac};
+use serde::Deserialize;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use surf::Url;
+
+use crate::cli::Cli;
+use crate::collector::COLLECTORS;
+use crate::push::{Batch, Target};
+
+/// PutMetricData takes up to 1000 values per request.
+const MAX_DATUMS: usize = 1000;
+const SERVICE: &str = "monitoring";
+const IMDS_ENDPOINT: &str = "http://169.254.169.254";
+/// Instance role credentials are renewed this long before they expire.
+const REFRESH_BEFORE: Duration = Duration::from_secs(300);
+
+/// CloudWatch units of the collectors' metrics; the rest are `None`.
+const UNITS: &[(&str, &str)] = &[
+    ("fan", "Percent"),
+    ("utilization", "Percent"),
+    ("memory", "Megabytes"),
+];
+
+#[derive(Clone)]
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+    /// For instance role credentials.
+    expiration: Option<SystemTime>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct RoleCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    token: String,
+    expiration: String,
+}
+
+/// Amazon CloudWatch (`--push.cloudwatch.region`), sent the GPU samples with PutMetricData. The
+/// credentials come from `AWS_ACCESS_KEY_ID`/`AWS_SECRET_ACCESS_KEY`/`AWS_SESSION_TOKEN`, or the
+/// EC2 instance role through IMDSv2.
+pub struct CloudWatch {
+    client: surf::Client,
+    url: Url,
+    region: String,
+    namespace: String,
+    /// Labels that become dimensions, when the sample has them.
+    dimensions: Vec<String>,
+    static_dimensions: Vec<(String, String)>,
+    /// `job` and `instance`.
+    labels: Vec<(String, String)>,
+    credentials: Mutex<Option<Credentials>>,
+}
+
+impl CloudWatch {
+    pub fn new(region: &str, cli: &Cli, labels: Vec<(String, String)>) -> Result<Self> {
+        let endpoint = cli
+            .cloudwatch_endpoint
+            .clone()
+            .unwrap_or_else(|| format!("https://{}.{}.amazonaws.com/", SERVICE, region));
+        let url = Url::parse(&endpoint)
+            .with_context(|| format!("Invalid --push.cloudwatch.endpoint {}", endpoint))?;
+        if cli.cloudwatch_namespace.starts_with("AWS/") {
+            bail!("--push.cloudwatch.namespace must not start with AWS/");
+        }
+        let count = cli.cloudwatch_dimensions.len() + cli.cloudwatch_static_dimensions.len();
+        if count > 30 {
+            bail!("CloudWatch takes at most 30 dimensions, got {}", count);
+        }
+        Ok(CloudWatch {
+            client: surf::Client::new(),
+            url,
+            region: region.to_string(),
+            namespace: cli.cloudwatch_namespace.clone(),
+            dimensions: cli.cloudwatch_dimensions.clone(),
+            static_dimensions: cli.cloudwatch_static_dimensions.clone(),
+            labels,
+            credentials: Mutex::new(None),
+        })
+    }
+
+    /// The PutMetricData form bodies, up to `MAX_DATUMS` samples each.
+    fn bodies(&self, batch: &Batch) -> Vec<String> {
+        let timestamp =
+            DateTime::<Utc>::from(batch.timestamp).to_rfc3339_opts(SecondsFormat::Millis, true);
+        let samples = batch
+            .samples
+            .iter()
+            .filter(|s| s.value.is_finite() && s.labels.iter().any(|(name, _)| name == "gpu"))
+            .collect::<Vec<_>>();
+        samples
+            .chunks(MAX_DATUMS)
+            .map(|chunk| {
+                let mut form = form_urlencoded::Serializer::new(String::new());
+                form.append_pair("Action", "PutMetricData")
+                    .append_pair("Version", "2010-08-01")
+                    .append_pair("Namespace", &self.namespace);
+                for (i, sample) in chunk.iter().enumerate() {
+                    let member = format!("MetricData.member.{}", i + 1);
+                    let mut dimensions = Vec::new();
+                    for name in &self.dimensions {
+                        let value = sample
+                            .labels
+                            .iter()
+                            .chain(&self.labels)
+                            .find(|(n, _)| n == name)
+                            .map(|(_, value)| value);
+                        if let Some(value) = value.filter(|value| !value.is_empty()) {
+                            dimensions.push((name, value));
+                        }
+                    }
+                    dimensions.extend(self.static_dimensions.iter().map(|(n, v)| (n, v)));
+                    let family = COLLECTORS
+                        .iter()
+                        .find(|c| c.fields.iter().any(|(_, m)| *m == sample.name))
+                        .map_or("", |c| c.name);
+                    let unit = UNITS
+                        .iter()
+                        .find(|(collector, _)| *collector == family)
+                        .map_or("None", |(_, unit)| *unit);
+                    form.append_pair(&format!("{}.MetricName", member), &sample.name)
+                        .append_pair(&format!("{}.Value", member), &sample.value.to_string())
+                        .append_pair(&format!("{}.Unit", member), unit)
+                        .append_pair(&format!("{}.Timestamp", member), &timestamp);
+                    for (j, (name, value)) in dimensions.into_iter().enumerate() {
+                        let dimension = format!("{}.Dimensions.member.{}", member, j + 1);
+                        form.append_pair(&format!("{}.Name", dimension), name)
+                            .append_pair(&format!("{}.Value", dimension), value);
+                    }
+                }
+                form.finish()
+            })
+            .collect()
+    }
+
+    /// The environment's credentials, or the instance role's, cached until shortly before they
+    /// expire.
+    async fn credentials(&self) -> Result<Credentials> {
+        if let (Ok(access_key_id), Ok(secret_access_key)) = (
+            std::env::var("AWS_ACCESS_KEY_ID"),
+            std::env::var("AWS_SECRET_ACCESS_KEY"),
+        ) {
+            return Ok(Credentials {
+                access_key_id,
+                secret_access_key,
+                session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+                expiration: None,
+            });
+        }
+        if let Some(credentials) = &*self.credentials.lock().unwrap() {
+            let fresh = credentials
+                .expiration
+                .is_none_or(|expiration| SystemTime::now() + REFRESH_BEFORE < expiration);
+            if fresh {
+                return Ok(credentials.clone());
+            }
+        }
+        let credentials = instance_credentials()
+            .await
+            .context("No AWS credentials in the environment or from the instance role")?;
+        *self.credentials.lock().unwrap() = Some(credentials.clone());
+        Ok(credentials)
+    }
+}
+
+#[tide::utils::async_trait]
+impl Target for CloudWatch {
+    fn name(&self) -> &'static str {
+        "cloudwatch"
+    }
+
+    async fn push(&self, batch: &Batch) -> Result<()> {
+        let host = self
+            .url
+            .host_str()
+            .ok_or_else(|| anyhow!("Invalid CloudWatch endpoint {}", self.url))?;
+        let host = match self.url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        for body in self.bodies(batch) {
+            let credentials = self.credentials().await?;
+            let content_type = "application/x-www-form-urlencoded; charset=utf-8";
+            let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+            let mut headers = vec![
+                ("content-type", content_type.to_string()),
+                ("host", host.clone()),
+                ("x-amz-date", amz_date.clone()),
+            ];
+            if let Some(token) = &credentials.session_token {
+                headers.push(("x-amz-security-token", token.clone()));
+            }
+            let authorization = sign(
+                &credentials,
+                &self.region,
+                self.url.path(),
+                &headers,
+                &amz_date,
+                body.as_bytes(),
+            );
+            let mut request = surf::post(self.url.clone())
+                .body(body)
+                .header("Authorization", authorization);
+            for (name, value) in &headers {
+                if *name != "host" {
+                    request = request.header(*name, value.as_str());
+                }
+            }
+            let mut response = self
+                .client
+                .send(request)
+                .await
+                .map_err(|e| e.into_inner())?;
+            // 成功时也读完响应体，下一个请求才能复用连接
+            let text = response.body_string().await.unwrap_or_default();
+            if !response.status().is_success() {
+                bail!("{} {}", response.status(), text.trim());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The `Authorization` header of AWS Signature Version 4 for a POST with `headers`, which are
+/// in lowercase and sorted.
+fn sign(
+    credentials: &Credentials,
+    region: &str,
+    path: &str,
+    headers: &[(&str, String)],
+    amz_date: &str,
+    body: &[u8],
+) -> String {
+    let date = &amz_date[..8];
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_headers = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect::<String>();
+    let canonical_request = format!(
+        "POST\n{}\n\n{}\n{}\n{}",
+        path,
+        canonical_headers,
+        signed_headers,
+        hex(digest::digest(&digest::SHA256, body).as_ref())
+    );
+    let scope = format!("{}/{}/{}/aws4_request", date, region, SERVICE);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref())
+    );
+    // 签名密钥：日期、区域、服务、aws4_request 逐层 HMAC
+    let mut key = format!("AWS4{}", credentials.secret_access_key).into_bytes();
+    for part in [date, region, SERVICE, "aws4_request", &string_to_sign] {
+        key = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &key), part.as_bytes())
+            .as_ref()
+            .to_vec();
+    }
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id,
+        scope,
+        signed_headers,
+        hex(&key)
+    )
+}
+
+/// The instance role's credentials from IMDSv2, at `AWS_EC2_METADATA_SERVICE_ENDPOINT` if set.
+async fn instance_credentials() -> Result<Credentials> {
+    let endpoint =
+        std::env::var("AWS_EC2_METADATA_SERVICE_ENDPOINT").unwrap_or(IMDS_ENDPOINT.to_string());
+    let endpoint = endpoint.trim_end_matches('/');
+    let token = surf::put(format!("{}/latest/api/token", endpoint))
+        .header("X-aws-ec2-metadata-token-ttl-seconds", "21600")
+        .recv_string()
+        .await
+        .map_err(|e| e.into_inner())
+        .context("Failed to get an IMDSv2 token")?;
+    let get = |path: String| {
+        surf::get(format!("{}{}", endpoint, path))
+            .header("X-aws-ec2-metadata-token", token.as_str())
+            .recv_string()
+    };
+    let path = "/latest/meta-data/iam/security-credentials/";
+    let role = get(path.to_string())
+        .await
+        .map_err(|e| e.into_inner())
+        .context("The instance has no IAM role")?;
+    let role = role.lines().next().unwrap_or_default().trim();
+    let body = get(format!("{}{}", path, role))
+        .await
+        .map_err(|e| e.into_inner())?;
+    let role: RoleCredentials = serde_json::from_str(&body)
+        .with_context(|| format!("Invalid credentials of role {}", role))?;
+    let expiration = DateTime::parse_from_rfc3339(&role.expiration)
+        .with_context(|| format!("Invalid expiration {}", role.expiration))?;
+    Ok(Credentials {
+        access_key_id: role.access_key_id,
+        secret_access_key: role.secret_access_key,
+        session_token: Some(role.token),
+        expiration: Some(SystemTime::from(expiration)),
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
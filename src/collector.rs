@@ -0,0 +1,1481 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_std::future::timeout;
+use async_std::prelude::*;
+use async_std::process::{Command, Output, Stdio};
+use clap::{Arg, ArgAction, ArgMatches};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_gauge, register_int_counter_vec, register_int_gauge, register_int_gauge_vec, Gauge,
+    IntCounterVec, IntGauge, IntGaugeVec,
+};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, debug_span, info, warn, Instrument};
+
+use crate::amd;
+use crate::breaker;
+use crate::capture;
+use crate::config::Settings;
+use crate::container;
+use crate::environment;
+use crate::exposition::{self, sample};
+use crate::federate;
+use crate::gpu::{self, Gpu};
+use crate::hub;
+use crate::intel;
+use crate::jetson::{self, Reading};
+use crate::nvidia_smi::NvidiaSmi;
+use crate::podresources::{self, Owner};
+use crate::procinfo;
+use crate::redfish;
+use crate::relabel::{self, RelabelConfig};
+use crate::runtime::{ChildGuard, CollectionGuard};
+use crate::slurm::{self, Job};
+use crate::ssh::{Ssh, Target};
+use crate::vfio;
+
+pub use nvidia_smi_exporter::{Collector, COLLECTORS};
+
+/// How long a `/readyz` probe result is reused before running `nvidia-smi -L` again.
+const READY_TTL: Duration = Duration::from_secs(30);
+/// How long the label sets of a GPU are kept without it being scraped, e.g. once removed.
+const LABEL_SET_TTL: Duration = Duration::from_secs(600);
+/// GPU memory of each compute process, with `--collect.processes`.
+pub const PROCESS_METRIC: &str = "nvidia_process_used_memory";
+/// One series per MIG device, with `--collect.mig-devices`.
+pub const MIG_METRIC: &str = "nvidia_mig_device_info";
+/// NVIDIA GPUs bound to vfio-pci, with `--collect.passthrough`.
+pub const PASSTHROUGH_METRIC: &str = "nvidia_gpu_passthrough_info";
+/// Whether the kubelet allocated a GPU, or any of its MIG devices, with `--kubernetes.pod-labels`.
+pub const ALLOCATED_METRIC: &str = "nvidia_gpu_allocated";
+/// Each temperature sensor of a Jetson module, with `--collect.jetson`.
+pub const JETSON_TEMPERATURE_METRIC: &str = "nvidia_jetson_temperature_celsius";
+/// Each power rail of a Jetson module, with `--collect.jetson`.
+pub const JETSON_POWER_METRIC: &str = "nvidia_jetson_power_rail_watts";
+
+/// The length of the last rendering, to size the next one's buffer.
+static RENDERED_LENGTH: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref READY: Mutex<Option<(Instant, Result<(), String>)>> = Mutex::new(None);
+    static ref LAST_COLLECTION: Mutex<Option<LastCollection>> = Mutex::new(None);
+    /// The label sets of each GPU's series, by UUID, kept across scrapes so that these mostly
+    /// only write values.
+    static ref LABEL_SETS: Mutex<LabelSets> = Mutex::new(LabelSets::default());
+    /// Fields nvidia-smi refused to query, by SSH host (empty for the local one); the driver of
+    /// WSL2 and old drivers know fewer fields.
+    static ref UNKNOWN_FIELDS: Mutex<HashSet<(String, String)>> = Mutex::new(HashSet::new());
+    /// `--collector.<name>` and `--no-collector.<name>` for each collector; clap wants `&'static str`.
+    static ref FLAGS: Vec<[String; 4]> = COLLECTORS
+        .iter()
+        .map(|c| {
+            let metrics = c.fields.iter().map(|(_, metric)| *metric).collect::<Vec<_>>().join(", ");
+            [
+                format!("collector.{}", c.name),
+                format!("no-collector.{}", c.name),
+                format!(
+                    "Enable the {} collector ({}){}",
+                    c.name,
+                    metrics,
+                    if c.enabled_by_default { " [default: enabled]" } else { "" }
+                ),
+                format!("Disable the {} collector", c.name),
+            ]
+        })
+        .collect();
+    static ref COLLECT_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "nvidia_smi_exporter_collect_failures_total",
+        "Number of failed nvidia-smi collections by reason (exec, timeout, parse).",
+        &["reason"]
+    )
+    .unwrap();
+    static ref SSH_UP: IntGaugeVec = register_int_gauge_vec!(
+        "nvidia_smi_exporter_ssh_up",
+        "Whether nvidia-smi could be run on each SSH host in the last collection.",
+        &["host"]
+    )
+    .unwrap();
+    static ref LAST_COLLECT_SUCCESS: Gauge = register_gauge!(
+        "nvidia_smi_exporter_last_collect_success_timestamp_seconds",
+        "Unix timestamp of the last successful nvidia-smi collection."
+    )
+    .unwrap();
+    static ref DEGRADED: IntGauge = register_int_gauge!(
+        "nvidia_smi_exporter_degraded",
+        "Whether the optional collections (processes, MIG devices) are skipped because collections exceeded --collect.latency-budget."
+    )
+    .unwrap();
+    static ref SCRAPE_SUCCESS: IntGauge = register_int_gauge!(
+        "nvidia_scrape_success",
+        "Whether the GPUs of the last scrape or push were collected; 0 while the last readings are served with the circuit breaker open."
+    )
+    .unwrap();
+}
+
+/// The `--collector.<name>` and `--no-collector.<name>` options; the later one given wins.
+pub fn args() -> Vec<Arg> {
+    FLAGS
+        .iter()
+        .flat_map(|[enable, disable, enable_help, disable_help]| {
+            vec![
+                Arg::new(enable.as_str())
+                    .long(enable.as_str())
+                    .action(ArgAction::SetTrue)
+                    .overrides_with(disable.as_str())
+                    .help(enable_help.as_str()),
+                Arg::new(disable.as_str())
+                    .long(disable.as_str())
+                    .action(ArgAction::SetTrue)
+                    .overrides_with(enable.as_str())
+                    .help(disable_help.as_str()),
+            ]
+        })
+        .collect()
+}
+
+/// The `--collector.<name>` switches, which can also be set to `false` in the environment and
+/// `--config` files.
+pub fn switches() -> impl Iterator<Item = &'static str> {
+    FLAGS.iter().map(|[enable, ..]| enable.as_str())
+}
+
+/// `no-collector.<name>` for `collector.<name>` and the other way round.
+pub fn counterpart(option: &str) -> Option<&'static str> {
+    FLAGS.iter().find_map(|[enable, disable, ..]| {
+        if option == enable {
+            Some(disable.as_str())
+        } else if option == disable {
+            Some(enable.as_str())
+        } else {
+            None
+        }
+    })
+}
+
+/// The collectors turned on by default or `--collector.<name>` and not by `--no-collector.<name>`.
+pub fn enabled(matches: &ArgMatches) -> Vec<&'static Collector> {
+    let defaults = !matches.get_flag("collector.disable-defaults");
+    COLLECTORS
+        .iter()
+        .zip(FLAGS.iter())
+        .filter(|(collector, [enable, disable, ..])| {
+            matches.get_flag(enable)
+                || (collector.enabled_by_default && defaults && !matches.get_flag(disable))
+        })
+        .map(|(collector, _)| collector)
+        .collect()
+}
+
+/// An extra `--query-gpu` field exported as a gauge, from `--query-field FIELD=METRIC[:HELP]`.
+#[derive(Clone, Debug, Serialize)]
+pub struct QueryField {
+    pub field: String,
+    pub metric: String,
+    pub help: Option<String>,
+}
+
+impl FromStr for QueryField {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (field, rest) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected FIELD=METRIC[:HELP]"))?;
+        let (metric, help) = match rest.split_once(':') {
+            Some((metric, help)) => (metric, Some(help.trim().to_string())),
+            None => (rest, None),
+        };
+        let (field, metric) = (field.trim(), metric.trim());
+        if field.is_empty()
+            || !field
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_')
+        {
+            bail!("invalid field name {:?}", field);
+        }
+        if !metric.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            || !metric
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            bail!("invalid metric name {:?}", metric);
+        }
+        if COLLECTORS
+            .iter()
+            .flat_map(|c| c.fields.iter())
+            .any(|(f, m)| *f == field || *m == metric)
+        {
+            bail!("{} is already exported by a collector", field);
+        }
+        Ok(QueryField {
+            field: field.to_string(),
+            metric: metric.to_string(),
+            help: help.filter(|h| !h.is_empty()),
+        })
+    }
+}
+
+/// Registers the collection metrics up front so they are exported as zero before the first failure.
+pub fn init_metrics() {
+    for reason in &["exec", "timeout", "parse"] {
+        COLLECT_FAILURES.with_label_values(&[reason]);
+    }
+    lazy_static::initialize(&LAST_COLLECT_SUCCESS);
+    lazy_static::initialize(&DEGRADED);
+    lazy_static::initialize(&SCRAPE_SUCCESS);
+    crate::breaker::init_metrics();
+    #[cfg(target_os = "freebsd")]
+    crate::procstat::register();
+}
+
+/// Outcome of the most recent collection, for the landing page.
+#[derive(Clone, Debug)]
+pub struct LastCollection {
+    pub finished_at: SystemTime,
+    pub duration: Duration,
+    pub result: Result<Vec<Gpu>, String>,
+}
+
+pub fn last_collection() -> Option<LastCollection> {
+    LAST_COLLECTION.lock().unwrap().clone()
+}
+
+pub async fn process_nvidia_smi(settings: &Settings) -> Result<String> {
+    if !settings.federate.is_empty() {
+        return federate::collect(settings).await;
+    }
+    if let Some(stale_after) = settings.hub_stale_after {
+        return hub::collect(settings, stale_after);
+    }
+    let fields = fields(settings);
+    if settings.latency_budget.is_none() {
+        DEGRADED.set(0);
+    }
+    let optional = DEGRADED.get() == 0 && !breaker::open();
+    let mig_info = optional && settings.mig_devices && settings.metric_filter.allows(MIG_METRIC);
+    let process_metric =
+        optional && settings.processes && settings.metric_filter.allows(PROCESS_METRIC);
+    let allocations = async {
+        // kubelet 不可用时不导出分配状态，免得把所有 GPU 报成空闲
+        let devices = match &settings.pod_resources_socket {
+            Some(socket) => podresources::owners(socket)
+                .await
+                .map_err(|e| warn!("Failed to list pod resources, {:#}", e))
+                .ok(),
+            None => None,
+        };
+        let allocated_migs = devices
+            .iter()
+            .flatten()
+            .any(|(id, _)| id.starts_with("MIG-"));
+        let migs = match mig_info || allocated_migs {
+            true => Some(mig_devices(&*settings.nvidia_smi, settings.collect_timeout).await),
+            false => None,
+        };
+        (devices, migs)
+    };
+    let processes = async {
+        match process_metric || (optional && settings.slurm) {
+            true => Some(collect_processes(&*settings.nvidia_smi, settings.collect_timeout).await),
+            false => None,
+        }
+    };
+    // 各采集互不依赖，同时进行，总耗时取决于最慢的一个
+    let started = Instant::now();
+    let (((((((gpus, fresh), bmc), amd), intel), jetson), (devices, migs)), processes) =
+        collect_fresh_gpus(settings, &fields)
+            .join(redfish::collect(
+                settings.redfish.as_ref(),
+                settings.collect_timeout,
+            ))
+            .join(amd::collect(
+                settings.amd.as_ref(),
+                settings.collect_timeout,
+            ))
+            .join(intel::collect(
+                settings.intel.as_ref(),
+                settings.collect_timeout,
+            ))
+            .join(jetson::collect(
+                settings.jetson.as_ref(),
+                settings.collect_timeout,
+            ))
+            .join(allocations)
+            .join(processes)
+            .await;
+    // 只反映导出的这次采集，不受能耗、历史等循环的采集影响
+    SCRAPE_SUCCESS.set((fresh && gpus.is_ok()) as i64);
+    if let Some(budget) = settings.latency_budget {
+        update_degraded(started.elapsed(), budget);
+    }
+    let bmc = bmc
+        .into_iter()
+        .filter(|gpu| {
+            gpu.values
+                .iter()
+                .any(|(metric, _)| fields.iter().any(|(_, m)| m == metric))
+        })
+        .collect::<Vec<_>>();
+    let mut sources: HashMap<&str, Vec<(String, String)>> = HashMap::new();
+    for gpu in &bmc {
+        sources.insert(
+            &gpu.uuid,
+            vec![("source".to_string(), "redfish".to_string())],
+        );
+    }
+    let vendors = settings.amd.is_some() || settings.intel.is_some();
+    // 不经 nvidia-smi 读到的 GPU
+    let others = amd
+        .iter()
+        .map(|gpu| (gpu, "amd"))
+        .chain(intel.iter().map(|gpu| (gpu, "intel")))
+        .chain(jetson.iter().map(|reading| (&reading.gpu, "nvidia")))
+        .filter(|(gpu, _)| settings.gpu_filter.allows(gpu))
+        .collect::<Vec<_>>();
+    for (gpu, vendor) in &others {
+        if vendors {
+            sources.insert(&gpu.uuid, vec![("vendor".to_string(), vendor.to_string())]);
+        }
+    }
+    let others = others
+        .into_iter()
+        .map(|(gpu, _)| gpu.clone())
+        .collect::<Vec<_>>();
+    let mut buffer = String::with_capacity(RENDERED_LENGTH.load(Ordering::Relaxed));
+    let gpus = match gpus {
+        Ok(gpus) => gpus,
+        // 驱动挂了时至少还有 BMC 带外读到的温度和功耗
+        Err(e) if !bmc.is_empty() => {
+            warn!(
+                "Failed to collect from nvidia-smi, exporting the BMC's readings only, {:#}",
+                e
+            );
+            let all = bmc.iter().chain(&others).cloned().collect::<Vec<_>>();
+            render(&mut buffer, &all, &fields, &sources, settings);
+            if let Some(reading) = &jetson {
+                render_jetson(&mut buffer, reading, settings);
+            }
+            return Ok(rendered(buffer));
+        }
+        // 只有其他厂商 GPU 或 Jetson 的机器上 nvidia-smi 总会失败，不必每次告警
+        Err(e) if !others.is_empty() => {
+            debug!(
+                "Failed to collect from nvidia-smi, exporting the other GPUs only, {:#}",
+                e
+            );
+            render(&mut buffer, &others, &fields, &sources, settings);
+            if let Some(reading) = &jetson {
+                render_jetson(&mut buffer, reading, settings);
+            }
+            return Ok(rendered(buffer));
+        }
+        Err(e) => return Err(e),
+    };
+    let allocated = devices.is_some() && settings.metric_filter.allows(ALLOCATED_METRIC);
+    let devices = devices.unwrap_or_default();
+    // nvidia-smi 正常时才报告这些失败，否则只是同一个原因
+    let migs = match migs {
+        Some(migs) => migs.unwrap_or_else(|e| {
+            warn!("Failed to list MIG devices, {:#}", e);
+            Vec::new()
+        }),
+        None => Vec::new(),
+    };
+    let processes = match processes {
+        // 进程查询失败不影响 GPU 指标
+        Some(processes) => processes.unwrap_or_else(|e| {
+            warn!("Failed to query compute processes, {:#}", e);
+            Vec::new()
+        }),
+        None => Vec::new(),
+    };
+    let jobs = match settings.slurm {
+        true => processes
+            .iter()
+            .filter_map(|process| {
+                let mut job = slurm::job(process.pid)?;
+                if let (Some(key), false) = (&settings.user_hash_key, job.user.is_empty()) {
+                    job.user = procinfo::hash_user(key, &job.user);
+                }
+                Some((process.pid, job))
+            })
+            .collect(),
+        false => HashMap::new(),
+    };
+    let mut labels = sources;
+    for gpu in &gpus {
+        let mut own = Vec::new();
+        if settings.redfish.is_some() {
+            own.push(("source".to_string(), "nvidia-smi".to_string()));
+        }
+        if vendors {
+            own.push(("vendor".to_string(), "nvidia".to_string()));
+        }
+        if !own.is_empty() {
+            labels.insert(&gpu.uuid, own);
+        }
+    }
+    for (uuid, owner) in owners(&gpus, &devices, &migs) {
+        labels.entry(uuid).or_default().extend(owner.labels());
+    }
+    for (uuid, job) in gpu_jobs(&gpus, &processes, &jobs) {
+        labels.entry(uuid).or_default().extend(job.labels());
+    }
+    let all = gpus
+        .iter()
+        .chain(&bmc)
+        .chain(&others)
+        .cloned()
+        .collect::<Vec<_>>();
+    render(&mut buffer, &all, &fields, &labels, settings);
+    if let Some(reading) = &jetson {
+        render_jetson(&mut buffer, reading, settings);
+    }
+    if allocated {
+        render_allocated(&mut buffer, &gpus, &devices, &migs, &labels, settings);
+    }
+    if mig_info {
+        render_migs(&mut buffer, &gpus, &migs, &devices, settings);
+    }
+    if settings.passthrough && settings.metric_filter.allows(PASSTHROUGH_METRIC) {
+        let devices = vfio::devices(settings.libvirt_dir.as_deref());
+        render_passthrough(&mut buffer, &devices, settings);
+    }
+    if process_metric {
+        render_processes(&mut buffer, &gpus, &processes, &jobs, settings).await;
+    }
+    Ok(rendered(buffer))
+}
+
+/// Skips the optional collections after collections that exceeded `budget`, and resumes them
+/// once the others take less than half of it again.
+fn update_degraded(elapsed: Duration, budget: Duration) {
+    if DEGRADED.get() == 0 && elapsed > budget {
+        warn!(
+            "Collections took {:?}, over the latency budget of {:?}, skipping processes and MIG devices",
+            elapsed, budget
+        );
+        DEGRADED.set(1);
+    } else if DEGRADED.get() == 1 && elapsed < budget / 2 {
+        info!(
+            "Collections took {:?}, resuming processes and MIG devices",
+            elapsed
+        );
+        DEGRADED.set(0);
+    }
+}
+
+/// Remembers the length of `buffer` so the next scrape allocates it once.
+fn rendered(buffer: String) -> String {
+    RENDERED_LENGTH.store(buffer.len(), Ordering::Relaxed);
+    buffer
+}
+
+/// The `--query-gpu` fields of the enabled collectors and `--query-field`s that the metric
+/// filter allows, with the metrics they are exported as.
+pub fn fields(settings: &Settings) -> Vec<(&str, &str)> {
+    settings
+        .collectors
+        .iter()
+        .flat_map(|c| c.fields.iter().copied())
+        .chain(
+            settings
+                .query_fields
+                .iter()
+                .map(|f| (f.field.as_str(), f.metric.as_str())),
+        )
+        .filter(|(_, metric)| settings.metric_filter.allows(metric))
+        .collect()
+}
+
+/// Runs nvidia-smi for `fields` and keeps the GPUs `--gpu-include`/`--gpu-exclude` allow.
+pub async fn collect_gpus(settings: &Settings, fields: &[(&str, &str)]) -> Result<Vec<Gpu>> {
+    collect_fresh_gpus(settings, fields).await.0
+}
+
+/// `collect_gpus`, and whether the GPUs were read now rather than kept from before the circuit
+/// breaker opened.
+pub async fn collect_fresh_gpus(
+    settings: &Settings,
+    fields: &[(&str, &str)],
+) -> (Result<Vec<Gpu>>, bool) {
+    let started = Instant::now();
+    let mut fresh = true;
+    let result = match remote(settings) {
+        Some(ssh) => collect_remote(settings, ssh, fields).await,
+        None => match &settings.mock {
+            Some(mock) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                LAST_COLLECT_SUCCESS.set(now.as_secs_f64());
+                Ok(mock.gpus(fields))
+            }
+            None => match &settings.breaker {
+                // 断路器打开时不再运行 nvidia-smi，返回上次成功的读数
+                Some(breaker) if !breaker.allow() => {
+                    fresh = false;
+                    breaker.cached(fields, &settings.devices)
+                }
+                breaker => {
+                    let result = collect_local(settings, fields).await;
+                    if let Some(breaker) = breaker {
+                        breaker.record(fields, &settings.devices, &result);
+                    }
+                    result
+                }
+            },
+        },
+    };
+    let result = result.map(|gpus| {
+        gpus.into_iter()
+            .filter(|gpu| settings.gpu_filter.allows(gpu))
+            .collect::<Vec<_>>()
+    });
+    *LAST_COLLECTION.lock().unwrap() = Some(LastCollection {
+        finished_at: SystemTime::now(),
+        duration: started.elapsed(),
+        result: result
+            .as_ref()
+            .map(Vec::clone)
+            .map_err(|e| format!("{:#}", e)),
+    });
+    (result, fresh)
+}
+
+async fn collect_local(settings: &Settings, fields: &[(&str, &str)]) -> Result<Vec<Gpu>> {
+    if !settings.nvidia_smi.local_devices() {
+        return collect(
+            &*settings.nvidia_smi,
+            settings.collect_timeout,
+            fields,
+            &settings.devices,
+            None,
+            settings.capture_dir.as_deref(),
+        )
+        .await;
+    }
+    let probe = async_std::task::spawn_blocking(environment::probe).await;
+    // 容器里只放进来部分 GPU 时只问这些，否则 nvidia-smi 会因为打不开其余的而失败
+    let devices = match settings.devices.is_empty() {
+        true => probe.reachable().unwrap_or_default(),
+        false => settings.devices.clone(),
+    };
+    collect(
+        &*settings.nvidia_smi,
+        settings.collect_timeout,
+        fields,
+        &devices,
+        None,
+        settings.capture_dir.as_deref(),
+    )
+    .await
+    .map_err(|e| match probe.problem() {
+        Some(problem) => e.context(problem),
+        None => e,
+    })
+}
+
+/// The SSH hosts to collect from instead of the local GPUs; none with only `--ssh.any-target`.
+fn remote(settings: &Settings) -> Option<&Ssh> {
+    settings.ssh.as_ref().filter(|ssh| !ssh.targets.is_empty())
+}
+
+/// Collects from every SSH host at once, setting `nvidia_smi_exporter_ssh_up`; fails only when
+/// no host answers.
+async fn collect_remote(
+    settings: &Settings,
+    ssh: &Ssh,
+    fields: &[(&str, &str)],
+) -> Result<Vec<Gpu>> {
+    let owned_fields = fields
+        .iter()
+        .map(|(field, metric)| (field.to_string(), metric.to_string()))
+        .collect::<Vec<_>>();
+    let tasks = ssh
+        .targets
+        .iter()
+        .map(|target| {
+            let (ssh, target) = (ssh.clone(), target.clone());
+            let (fields, devices) = (owned_fields.clone(), settings.devices.clone());
+            let collect_timeout = settings.collect_timeout;
+            let nvidia_smi = settings.nvidia_smi.clone();
+            let capture_dir = settings.capture_dir.clone();
+            async_std::task::spawn(async move {
+                let fields = fields
+                    .iter()
+                    .map(|(field, metric)| (field.as_str(), metric.as_str()))
+                    .collect::<Vec<_>>();
+                let result = collect(
+                    &*nvidia_smi,
+                    collect_timeout,
+                    &fields,
+                    &devices,
+                    Some((&ssh, &target)),
+                    capture_dir.as_deref(),
+                )
+                .instrument(debug_span!("ssh", host = %target.label()))
+                .await;
+                (target, result)
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut gpus = Vec::new();
+    let mut errors = Vec::new();
+    let mut up = Vec::new();
+    for task in tasks {
+        let (target, result) = task.await;
+        let host = target.label();
+        up.push((host.clone(), result.is_ok()));
+        match result {
+            Ok(found) => gpus.extend(found.into_iter().map(|gpu| Gpu {
+                host: Some(host.clone()),
+                ..gpu
+            })),
+            Err(e) => {
+                warn!("Failed to collect from {}, {:#}", host, e);
+                errors.push(format!("{}: {:#}", host, e));
+            }
+        }
+    }
+    SSH_UP.reset();
+    for (host, ok) in &up {
+        SSH_UP.with_label_values(&[host]).set(*ok as i64);
+    }
+    if errors.len() == ssh.targets.len() {
+        bail!("No SSH host answered: {}", errors.join("; "));
+    }
+    Ok(gpus)
+}
+
+async fn collect(
+    nvidia_smi: &dyn NvidiaSmi,
+    collect_timeout: Duration,
+    fields: &[(&str, &str)],
+    devices: &[String],
+    remote: Option<(&Ssh, &Target)>,
+    capture_dir: Option<&str>,
+) -> Result<Vec<Gpu>> {
+    let _in_flight = CollectionGuard::new();
+    let host = remote.map(|(_, target)| target.label()).unwrap_or_default();
+    let (output, fields, args) = loop {
+        let fields = {
+            let unknown = UNKNOWN_FIELDS.lock().unwrap();
+            fields
+                .iter()
+                .filter(|(field, _)| !unknown.contains(&(host.clone(), field.to_string())))
+                .copied()
+                .collect::<Vec<_>>()
+        };
+        let args = gpu::query_args(&fields, devices);
+        let output = nvidia_smi
+            .output(&args, remote)
+            .instrument(debug_span!("exec", command = "nvidia-smi"));
+        match timeout(collect_timeout, output).await {
+            Ok(Ok(output)) if output.status.success() => break (output, fields, args),
+            Ok(Ok(output)) => {
+                let message = String::from_utf8_lossy(&output.stdout)
+                    + String::from_utf8_lossy(&output.stderr);
+                // 不认识的字段报在 stdout：Field "fan.speed" is not a valid field to query.
+                let unknown = message
+                    .split("Field \"")
+                    .nth(1)
+                    .filter(|_| message.contains("is not a valid field to query"))
+                    .and_then(|rest| rest.split('"').next())
+                    .filter(|field| fields.iter().any(|(f, _)| f == field));
+                if let Some(field) = unknown {
+                    warn!(
+                        "nvidia-smi{} does not know the field {}, no longer querying it",
+                        remote
+                            .map(|(_, target)| format!(" on {}", target.label()))
+                            .unwrap_or_default(),
+                        field
+                    );
+                    UNKNOWN_FIELDS
+                        .lock()
+                        .unwrap()
+                        .insert((host.clone(), field.to_string()));
+                    continue;
+                }
+                COLLECT_FAILURES.with_label_values(&["exec"]).inc();
+                crate::openmetrics::exemplar(&*COLLECT_FAILURES, &["exec"]);
+                bail!(
+                    "nvidia-smi exited with {}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            Ok(Err(e)) => {
+                COLLECT_FAILURES.with_label_values(&["exec"]).inc();
+                crate::openmetrics::exemplar(&*COLLECT_FAILURES, &["exec"]);
+                return Err(e).with_context(|| "Failed to execute command");
+            }
+            Err(_) => {
+                COLLECT_FAILURES.with_label_values(&["timeout"]).inc();
+                crate::openmetrics::exemplar(&*COLLECT_FAILURES, &["timeout"]);
+                bail!("nvidia-smi timed out after {:?}", collect_timeout);
+            }
+        }
+    };
+
+    let stdout = output.stdout.as_slice();
+    debug!("stdout: {}", String::from_utf8_lossy(stdout));
+    let gpus = debug_span!("parse", bytes = stdout.len())
+        .in_scope(|| gpu::parse_csv(stdout, &fields))
+        .inspect_err(|_| {
+            COLLECT_FAILURES.with_label_values(&["parse"]).inc();
+            crate::openmetrics::exemplar(&*COLLECT_FAILURES, &["parse"]);
+            if let Some(dir) = capture_dir {
+                capture::save(dir, &host, &args, stdout);
+            }
+        })?;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    LAST_COLLECT_SUCCESS.set(now.as_secs_f64());
+    Ok(gpus)
+}
+
+/// The container each GPU is allocated to by the kubelet, by UUID, from the `devices` it
+/// allocated. GPUs shared by several containers, by time-slicing or MIG, are left out.
+fn owners<'a>(
+    gpus: &'a [Gpu],
+    devices: &HashMap<String, Owner>,
+    migs: &[MigDevice],
+) -> HashMap<&'a str, Owner> {
+    let mut owners = HashMap::new();
+    for gpu in gpus {
+        let mut gpu_owners = gpu_owners(gpu, devices, migs);
+        if let Some(owner) = gpu_owners.next() {
+            if gpu_owners.all(|other| other == owner) {
+                owners.insert(gpu.uuid.as_str(), owner.clone());
+            }
+        }
+    }
+    owners
+}
+
+/// The owners of the devices on `gpu`: the GPU itself or its MIG devices.
+fn gpu_owners<'a>(
+    gpu: &'a Gpu,
+    devices: &'a HashMap<String, Owner>,
+    migs: &'a [MigDevice],
+) -> impl Iterator<Item = &'a Owner> {
+    devices.iter().filter_map(move |(id, owner)| {
+        let parent = migs
+            .iter()
+            .find(|mig| mig.uuid == *id)
+            .map(|mig| &mig.gpu_uuid);
+        (*id == gpu.uuid || *id == gpu.index || parent == Some(&gpu.uuid)).then_some(owner)
+    })
+}
+
+/// The Slurm job of each GPU whose processes all belong to one job, by UUID.
+fn gpu_jobs<'a>(
+    gpus: &'a [Gpu],
+    processes: &[Process],
+    jobs: &HashMap<u32, Job>,
+) -> HashMap<&'a str, Job> {
+    let mut gpu_jobs = HashMap::new();
+    for gpu in gpus {
+        let mut on_gpu = processes
+            .iter()
+            .filter(|process| process.gpu_uuid == gpu.uuid)
+            .map(|process| jobs.get(&process.pid));
+        if let Some(Some(job)) = on_gpu.next() {
+            if on_gpu.all(|other| other == Some(job)) {
+                gpu_jobs.insert(gpu.uuid.as_str(), job.clone());
+            }
+        }
+    }
+    gpu_jobs
+}
+
+/// A MIG device as listed by `nvidia-smi -L`.
+#[derive(Clone, Debug)]
+pub struct MigDevice {
+    /// The UUID of the GPU it is a slice of.
+    pub gpu_uuid: String,
+    pub uuid: String,
+    /// E.g. `1g.10gb`.
+    pub profile: String,
+    /// Index within the GPU.
+    pub device: String,
+}
+
+async fn mig_devices(
+    nvidia_smi: &dyn NvidiaSmi,
+    collect_timeout: Duration,
+) -> Result<Vec<MigDevice>> {
+    let args = ["-L".to_string()];
+    let output = timeout(collect_timeout, nvidia_smi.output(&args, None))
+        .await
+        .map_err(|_| anyhow!("nvidia-smi -L timed out after {:?}", collect_timeout))?
+        .with_context(|| "Failed to execute command")?;
+    if !output.status.success() {
+        bail!(
+            "nvidia-smi -L exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let uuid = |line: &str| {
+        let start = line.find("(UUID: ")? + 7;
+        Some(line[start..].trim_end().trim_end_matches(')').to_string())
+    };
+    // GPU 0: NVIDIA A100 (UUID: GPU-…)，其下缩进的行是 MIG 1g.10gb Device 0: (UUID: MIG-…)
+    let mut migs = Vec::new();
+    let mut gpu_uuid = None;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if line.starts_with("GPU ") {
+            gpu_uuid = uuid(line);
+        } else if let (Some(gpu_uuid), Some(mig)) = (&gpu_uuid, line.trim().strip_prefix("MIG ")) {
+            let mut words = mig.split_whitespace();
+            let profile = words.next().unwrap_or_default();
+            let device = words.nth(1).unwrap_or_default().trim_end_matches(':');
+            if let Some(uuid) = uuid(line) {
+                migs.push(MigDevice {
+                    gpu_uuid: gpu_uuid.clone(),
+                    uuid,
+                    profile: profile.to_string(),
+                    device: device.to_string(),
+                });
+            }
+        }
+    }
+    Ok(migs)
+}
+
+/// One row of `nvidia-smi --query-compute-apps`.
+#[derive(Clone, Debug)]
+pub struct Process {
+    pub gpu_uuid: String,
+    pub pid: u32,
+    pub name: String,
+    /// MiB, unless nvidia-smi cannot tell, e.g. under WDDM.
+    pub used_memory: Option<f64>,
+}
+
+async fn collect_processes(
+    nvidia_smi: &dyn NvidiaSmi,
+    collect_timeout: Duration,
+) -> Result<Vec<Process>> {
+    let args = [
+        "--query-compute-apps=gpu_uuid,pid,process_name,used_memory".to_string(),
+        "--format=csv,noheader,nounits".to_string(),
+    ];
+    let output = nvidia_smi
+        .output(&args, None)
+        .instrument(debug_span!("exec", command = "nvidia-smi"));
+    let output = timeout(collect_timeout, output)
+        .await
+        .map_err(|_| anyhow!("nvidia-smi timed out after {:?}", collect_timeout))?
+        .with_context(|| "Failed to execute command")?;
+    if !output.status.success() {
+        bail!(
+            "nvidia-smi exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    // 进程名可能含逗号，nvidia-smi 不加引号，所以名字取中间剩下的部分
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.contains("No running processes"))
+        .map(|line| {
+            let invalid = || anyhow!("Invalid compute process {:?}", line);
+            let (gpu_uuid, rest) = line.split_once(',').ok_or_else(invalid)?;
+            let (pid, rest) = rest.split_once(',').ok_or_else(invalid)?;
+            let (name, used_memory) = rest.rsplit_once(',').ok_or_else(invalid)?;
+            Ok(Process {
+                gpu_uuid: gpu_uuid.trim().to_string(),
+                pid: pid.trim().parse().map_err(|_| invalid())?,
+                name: name.trim().to_string(),
+                used_memory: used_memory.trim().parse().ok(),
+            })
+        })
+        .collect()
+}
+
+/// Checks that nvidia-smi, or amd-smi, xpu-smi or tegrastats if enabled, can list at least one GPU, on any
+/// `--ssh.host` if given, that any `--federate.target` answers, or that any agent reported to
+/// the hub, caching the outcome for `READY_TTL`.
+pub async fn check_ready(settings: &Settings) -> Result<(), String> {
+    let cached = READY.lock().unwrap().clone();
+    if let Some((checked_at, result)) = cached {
+        if checked_at.elapsed() < READY_TTL {
+            return result;
+        }
+    }
+    let result = match remote(settings) {
+        None if !settings.federate.is_empty() => federate::check_ready(settings).await,
+        None if settings.mock.is_some() => Ok(()),
+        None => match settings.hub_stale_after {
+            Some(stale_after) => hub::check_ready(stale_after),
+            None => {
+                let result = match breaker::open() {
+                    true => {
+                        Err("nvidia-smi is not run while the circuit breaker is open".to_string())
+                    }
+                    false => list_gpus(&*settings.nvidia_smi, settings.collect_timeout, None)
+                        .instrument(debug_span!("exec", command = "nvidia-smi -L"))
+                        .await
+                        .map_err(|e| format!("{:#}", e)),
+                };
+                let mut errors = match result {
+                    Ok(()) => Vec::new(),
+                    Err(e) => vec![e],
+                };
+                if let (false, Some(amd)) = (errors.is_empty(), &settings.amd) {
+                    match amd::check_ready(amd, settings.collect_timeout).await {
+                        Ok(()) => errors.clear(),
+                        Err(e) => errors.push(e),
+                    }
+                }
+                if let (false, Some(intel)) = (errors.is_empty(), &settings.intel) {
+                    match intel::check_ready(intel, settings.collect_timeout).await {
+                        Ok(()) => errors.clear(),
+                        Err(e) => errors.push(e),
+                    }
+                }
+                if let (false, Some(jetson)) = (errors.is_empty(), &settings.jetson) {
+                    match jetson::check_ready(jetson, settings.collect_timeout).await {
+                        Ok(()) => errors.clear(),
+                        Err(e) => errors.push(e),
+                    }
+                }
+                match errors.is_empty() {
+                    true => Ok(()),
+                    false => Err(errors.join("; ")),
+                }
+            }
+        },
+        Some(ssh) => {
+            let mut errors = Vec::new();
+            for target in &ssh.targets {
+                match list_gpus(
+                    &*settings.nvidia_smi,
+                    settings.collect_timeout,
+                    Some((ssh, target)),
+                )
+                .instrument(debug_span!("ssh", host = %target.label()))
+                .await
+                {
+                    Ok(()) => break,
+                    Err(e) => errors.push(format!("{}: {:#}", target.label(), e)),
+                }
+            }
+            match errors.len() == ssh.targets.len() {
+                true => Err(errors.join("; ")),
+                false => Ok(()),
+            }
+        }
+    };
+    *READY.lock().unwrap() = Some((Instant::now(), result.clone()));
+    result
+}
+
+async fn list_gpus(
+    nvidia_smi: &dyn NvidiaSmi,
+    collect_timeout: Duration,
+    remote: Option<(&Ssh, &Target)>,
+) -> Result<()> {
+    let args = ["-L".to_string()];
+    let output = timeout(collect_timeout, nvidia_smi.output(&args, remote))
+        .await
+        .map_err(|_| anyhow!("nvidia-smi -L timed out after {:?}", collect_timeout))?
+        .with_context(|| "Failed to execute command")?;
+    if !output.status.success() {
+        bail!(
+            "nvidia-smi -L exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    if !String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|line| line.starts_with("GPU "))
+    {
+        bail!("nvidia-smi -L found no GPUs");
+    }
+    Ok(())
+}
+
+/// Runs a command to completion, registering its pid so shutdown can kill it if still running.
+pub async fn output(command: &mut Command) -> std::io::Result<Output> {
+    let child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let _child = ChildGuard::new(child.id());
+    child.output().await
+}
+
+/// `<program> <args>` as JSON, for the other vendors' tools.
+pub async fn json(program: &str, args: &[&str], collect_timeout: Duration) -> Result<Value> {
+    let mut command = Command::new(program);
+    command.args(args);
+    let output = output(&mut command).instrument(debug_span!("exec", command = program));
+    let output = timeout(collect_timeout, output)
+        .await
+        .map_err(|_| anyhow!("{} timed out after {:?}", program, collect_timeout))??;
+    if !output.status.success() {
+        bail!(
+            "{} exited with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("Invalid JSON from {} {}", program, args.join(" ")))
+}
+
+/// A reading in a tool's JSON: a number, `{"value": …, "unit": …}`, or a string such as `35.0`
+/// or `(1500Mhz)`; None for `N/A`.
+pub fn json_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::Object(object) => json_number(object.get("value")?),
+        Value::String(s) => s
+            .trim_matches(|c: char| !c.is_ascii_digit() && c != '.')
+            .parse()
+            .ok(),
+        _ => None,
+    }
+}
+
+/// Renders the samples grouped by metric into `buffer`, with `# HELP`/`# TYPE` for the
+/// `--query-field` ones and the `extra_labels` of each GPU, e.g. its pod.
+fn render(
+    buffer: &mut String,
+    gpus: &[Gpu],
+    fields: &[(&str, &str)],
+    extra_labels: &HashMap<&str, Vec<(String, String)>>,
+    settings: &Settings,
+) {
+    let mut label_sets = LABEL_SETS.lock().unwrap();
+    label_sets.update(gpus, fields, extra_labels, settings);
+    for (_, metric) in fields {
+        let mut samples = gpus
+            .iter()
+            .filter_map(|gpu| Some((label_sets.get(gpu, metric)?, gpu.value(metric)?)))
+            .peekable();
+        if samples.peek().is_none() {
+            continue;
+        }
+        if let Some(field) = settings.query_fields.iter().find(|f| f.metric == *metric) {
+            let _ = match &field.help {
+                Some(help) => writeln!(buffer, "# HELP {} {}", metric, help),
+                None => writeln!(
+                    buffer,
+                    "# HELP {} nvidia-smi --query-gpu={}",
+                    metric, field.field
+                ),
+            };
+            let _ = writeln!(buffer, "# TYPE {} gauge", metric);
+        }
+        for (labels, value) in samples {
+            buffer.push_str(metric);
+            buffer.push_str(labels);
+            exposition::end_sample(buffer, value);
+        }
+    }
+}
+
+/// The label sets of the GPUs' series, with what they are made of.
+#[derive(Default)]
+struct LabelSets {
+    constant_labels: Vec<(String, String)>,
+    rules: Vec<RelabelConfig>,
+    gpus: HashMap<String, GpuLabelSets>,
+}
+
+/// The labels of one GPU before relabeling, and the rendered label set of each of its metrics.
+struct GpuLabelSets {
+    labels: Vec<(String, String)>,
+    /// `{…}` by metric, None if relabeling drops the series.
+    series: HashMap<String, Option<String>>,
+    used: Instant,
+}
+
+impl LabelSets {
+    /// Starts over when the constant labels or the relabeling rules were reloaded, and renders the
+    /// label sets of new GPUs, or of those whose labels changed, e.g. a pod moved to the GPU.
+    fn update(
+        &mut self,
+        gpus: &[Gpu],
+        fields: &[(&str, &str)],
+        extra_labels: &HashMap<&str, Vec<(String, String)>>,
+        settings: &Settings,
+    ) {
+        let rules = settings.relabel.iter().map(|rule| &rule.config);
+        if self.constant_labels != settings.constant_labels || !rules.clone().eq(&self.rules) {
+            *self = LabelSets {
+                constant_labels: settings.constant_labels.clone(),
+                rules: rules.cloned().collect(),
+                gpus: HashMap::new(),
+            };
+        }
+        let now = Instant::now();
+        self.gpus
+            .retain(|_, sets| now.duration_since(sets.used) < LABEL_SET_TTL);
+        for gpu in gpus {
+            let extra = extra_labels
+                .get(gpu.uuid.as_str())
+                .map_or(&[][..], Vec::as_slice);
+            let current = self
+                .gpus
+                .get(&gpu.uuid)
+                .is_some_and(|sets| sets.matches(gpu, extra));
+            if !current {
+                let mut labels = vec![
+                    ("gpu".to_string(), gpu.index.clone()),
+                    ("name".to_string(), gpu.name.clone()),
+                ];
+                if let Some(host) = &gpu.host {
+                    labels.push(("host".to_string(), host.clone()));
+                }
+                labels.extend(extra.iter().cloned());
+                let sets = GpuLabelSets {
+                    labels,
+                    series: HashMap::new(),
+                    used: now,
+                };
+                self.gpus.insert(gpu.uuid.clone(), sets);
+            }
+            let sets = self.gpus.get_mut(&gpu.uuid).unwrap();
+            sets.used = now;
+            for (_, metric) in fields {
+                if !sets.series.contains_key(*metric) && gpu.value(metric).is_some() {
+                    let mut labels = sets.labels.clone();
+                    add_constant_labels(&mut labels, settings);
+                    let series =
+                        relabel::apply(&settings.relabel, metric, &mut labels).then(|| {
+                            let mut series = String::new();
+                            exposition::label_set(&mut series, &labels);
+                            series
+                        });
+                    sets.series.insert(metric.to_string(), series);
+                }
+            }
+        }
+    }
+
+    /// The label set of `metric` on `gpu`, after `update`; none if the series is dropped.
+    fn get(&self, gpu: &Gpu, metric: &str) -> Option<&str> {
+        self.gpus.get(&gpu.uuid)?.series.get(metric)?.as_deref()
+    }
+}
+
+impl GpuLabelSets {
+    fn matches(&self, gpu: &Gpu, extra: &[(String, String)]) -> bool {
+        let host = gpu.host.as_ref().map(|host| ("host", host));
+        let labels = IntoIterator::into_iter([("gpu", &gpu.index), ("name", &gpu.name)])
+            .chain(host)
+            .chain(extra.iter().map(|(name, value)| (name.as_str(), value)));
+        labels.eq(self
+            .labels
+            .iter()
+            .map(|(name, value)| (name.as_str(), value)))
+    }
+}
+
+/// `ALLOCATED_METRIC` for each of `gpus`, with the same labels as its other metrics.
+fn render_allocated(
+    buffer: &mut String,
+    gpus: &[Gpu],
+    devices: &HashMap<String, Owner>,
+    migs: &[MigDevice],
+    extra_labels: &HashMap<&str, Vec<(String, String)>>,
+    settings: &Settings,
+) {
+    for gpu in gpus {
+        let mut labels = vec![
+            ("gpu".to_string(), gpu.index.clone()),
+            ("name".to_string(), gpu.name.clone()),
+        ];
+        if let Some(extra) = extra_labels.get(gpu.uuid.as_str()) {
+            labels.extend(extra.iter().cloned());
+        }
+        add_constant_labels(&mut labels, settings);
+        if !relabel::apply(&settings.relabel, ALLOCATED_METRIC, &mut labels) {
+            continue;
+        }
+        let allocated = gpu_owners(gpu, devices, migs).next().is_some();
+        sample(buffer, ALLOCATED_METRIC, &labels, allocated as u8 as f64);
+    }
+}
+
+/// `MIG_METRIC` for the MIG devices of `gpus`, with the pod labels of those the kubelet
+/// allocated.
+fn render_migs(
+    buffer: &mut String,
+    gpus: &[Gpu],
+    migs: &[MigDevice],
+    devices: &HashMap<String, Owner>,
+    settings: &Settings,
+) {
+    for mig in migs {
+        let gpu = match gpus.iter().find(|gpu| gpu.uuid == mig.gpu_uuid) {
+            Some(gpu) => gpu,
+            None => continue,
+        };
+        let mut labels = vec![
+            ("gpu".to_string(), gpu.index.clone()),
+            ("name".to_string(), gpu.name.clone()),
+            ("mig_uuid".to_string(), mig.uuid.clone()),
+            ("mig_profile".to_string(), mig.profile.clone()),
+            ("mig_device".to_string(), mig.device.clone()),
+        ];
+        if let Some(owner) = devices.get(&mig.uuid) {
+            labels.extend(owner.labels());
+        }
+        add_constant_labels(&mut labels, settings);
+        if !relabel::apply(&settings.relabel, MIG_METRIC, &mut labels) {
+            continue;
+        }
+        sample(buffer, MIG_METRIC, &labels, 1.0);
+    }
+}
+
+/// `PASSTHROUGH_METRIC` for GPUs passed through to VMs, which have no other metrics.
+fn render_passthrough(buffer: &mut String, devices: &[vfio::Device], settings: &Settings) {
+    for device in devices {
+        let mut labels = vec![
+            ("pci_bus_id".to_string(), device.bus_id.clone()),
+            ("device_id".to_string(), device.device_id.clone()),
+            ("passthrough".to_string(), "true".to_string()),
+        ];
+        if let Some(domain) = &device.domain {
+            labels.push(("libvirt_domain".to_string(), domain.clone()));
+        }
+        add_constant_labels(&mut labels, settings);
+        if relabel::apply(&settings.relabel, PASSTHROUGH_METRIC, &mut labels) {
+            sample(buffer, PASSTHROUGH_METRIC, &labels, 1.0);
+        }
+    }
+}
+
+/// `JETSON_TEMPERATURE_METRIC` and `JETSON_POWER_METRIC` for the sensors and rails of the
+/// Jetson module, which are not the GPU's alone.
+fn render_jetson(buffer: &mut String, reading: &Reading, settings: &Settings) {
+    let series = [
+        (JETSON_TEMPERATURE_METRIC, "sensor", &reading.temperatures),
+        (JETSON_POWER_METRIC, "rail", &reading.rails),
+    ];
+    for (metric, label, values) in series {
+        if !settings.metric_filter.allows(metric) {
+            continue;
+        }
+        for (name, value) in values {
+            let mut labels = vec![(label.to_string(), name.clone())];
+            add_constant_labels(&mut labels, settings);
+            if relabel::apply(&settings.relabel, metric, &mut labels) {
+                sample(buffer, metric, &labels, *value);
+            }
+        }
+    }
+}
+
+/// `PROCESS_METRIC` for the processes on `gpus`, labelled with the container or Slurm job they
+/// run in when there is one.
+async fn render_processes(
+    buffer: &mut String,
+    gpus: &[Gpu],
+    processes: &[Process],
+    jobs: &HashMap<u32, Job>,
+    settings: &Settings,
+) {
+    let processes = processes
+        .iter()
+        .filter_map(|process| {
+            let gpu = gpus.iter().find(|gpu| gpu.uuid == process.gpu_uuid)?;
+            Some((gpu, process, container::container(process.pid)))
+        })
+        .collect::<Vec<_>>();
+    let containers = processes
+        .iter()
+        .filter_map(|(_, _, container)| container.as_ref())
+        .collect::<Vec<_>>();
+    let runtimes = container::detect(&settings.runtime_sockets);
+    let infos = match containers.is_empty() {
+        true => Default::default(),
+        false => container::inspect_all(&runtimes, &containers).await,
+    };
+    for (gpu, process, container) in &processes {
+        let value = match process.used_memory {
+            Some(value) => value,
+            None => continue,
+        };
+        let mut labels = vec![
+            ("gpu".to_string(), gpu.index.clone()),
+            ("name".to_string(), gpu.name.clone()),
+            ("pid".to_string(), process.pid.to_string()),
+            ("process_name".to_string(), process.name.clone()),
+        ];
+        let metadata = match settings.process_metadata {
+            true => procinfo::metadata(process.pid, settings.cmdline_length),
+            false => None,
+        };
+        if let Some(metadata) = metadata {
+            let user = procinfo::user(metadata.uid);
+            let user = match &settings.user_hash_key {
+                Some(key) => procinfo::hash_user(key, &user),
+                None => user,
+            };
+            labels.push(("user".to_string(), user));
+            labels.push(("command".to_string(), metadata.command));
+            if let Some(start_time) = metadata.start_time {
+                labels.push(("start_time".to_string(), start_time.to_string()));
+            }
+            if let Some(cmdline) = metadata.cmdline {
+                labels.push(("cmdline".to_string(), cmdline));
+            }
+        }
+        if let Some(container) = container {
+            labels.push(("container_id".to_string(), container.id.clone()));
+            let info = infos.get(&container.id).and_then(Option::as_ref);
+            if let Some(runtime) = info.map(|info| info.runtime).or(container.runtime) {
+                labels.push(("container_runtime".to_string(), runtime.name().to_string()));
+            }
+            if let Some(info) = info {
+                labels.push(("container_name".to_string(), info.name.clone()));
+                labels.push(("container_image".to_string(), info.image.clone()));
+            }
+        }
+        if let Some(job) = jobs.get(&process.pid) {
+            labels.extend(job.labels());
+        }
+        add_constant_labels(&mut labels, settings);
+        if !relabel::apply(&settings.relabel, PROCESS_METRIC, &mut labels) {
+            continue;
+        }
+        sample(buffer, PROCESS_METRIC, &labels, value);
+    }
+}
+
+/// `--kubernetes.*` node metadata, before relabeling so rules can use it.
+fn add_constant_labels(labels: &mut Vec<(String, String)>, settings: &Settings) {
+    for (name, value) in &settings.constant_labels {
+        if !labels.iter().any(|(n, _)| n == name) {
+            labels.push((name.clone(), value.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_query_fields() {
+        let field: QueryField = "clocks.max.sm=nvidia_clocks_max_sm".parse().unwrap();
+        assert_eq!(field.field, "clocks.max.sm");
+        assert_eq!(field.metric, "nvidia_clocks_max_sm");
+        assert_eq!(field.help, None);
+
+        let field: QueryField =
+            " clocks.max.gr = nvidia_clocks_max_gr : Max clock, in MHz: of the GPU "
+                .parse()
+                .unwrap();
+        assert_eq!(field.field, "clocks.max.gr");
+        assert_eq!(field.metric, "nvidia_clocks_max_gr");
+        assert_eq!(field.help.as_deref(), Some("Max clock, in MHz: of the GPU"));
+
+        // 空的说明视同没有
+        let field: QueryField = "clocks.max.gr=nvidia_clocks_max_gr:".parse().unwrap();
+        assert_eq!(field.help, None);
+    }
+
+    #[test]
+    fn rejects_invalid_query_fields() {
+        for invalid in [
+            "",
+            "fan.speed",
+            "=nvidia_fan",
+            "fan speed=nvidia_fan",
+            "fan.speed;x=nvidia_fan",
+            "fan.speed=",
+            "fan.speed=1nvidia_fan",
+            "fan.speed=nvidia-fan",
+            "fan.speed=nvidia.fan",
+        ] {
+            assert!(invalid.parse::<QueryField>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn rejects_fields_of_collectors() {
+        let (field, metric) = COLLECTORS[0].fields[0];
+        let taken = format!("{}=nvidia_custom", field);
+        assert!(taken.parse::<QueryField>().is_err());
+        let taken = format!("custom.field={}", metric);
+        assert!(taken.parse::<QueryField>().is_err());
+    }
+
+    /// Answers every command with `stdout` and `code`.
+    #[derive(Debug)]
+    struct Fixed {
+        code: i32,
+        stdout: &'static str,
+    }
+
+    impl NvidiaSmi for Fixed {
+        fn output<'a>(
+            &'a self,
+            _args: &'a [String],
+            _remote: Option<(&'a Ssh, &'a Target)>,
+        ) -> crate::nvidia_smi::OutputFuture<'a> {
+            use std::os::unix::process::ExitStatusExt;
+            let output = Output {
+                status: ExitStatusExt::from_raw(self.code << 8),
+                stdout: self.stdout.as_bytes().to_vec(),
+                stderr: b"Failed to initialize NVML: Driver/library version mismatch".to_vec(),
+            };
+            Box::pin(async move { Ok(output) })
+        }
+    }
+
+    #[async_std::test]
+    async fn lists_mig_devices() {
+        let list = "GPU 0: NVIDIA A100-SXM4-40GB (UUID: GPU-5fd4c2a1-0b1e-4c3a-9f7e-2d7a1b6c8e90)
+  MIG 3g.20gb     Device  0: (UUID: MIG-4b4e4c56-8f1e-5c7a-b2d3-0e9f8a7b6c5d)
+  MIG 1g.5gb      Device  1: (UUID: MIG-9a8b7c6d-5e4f-5a3b-8c2d-1e0f9a8b7c6d)
+GPU 1: NVIDIA A100-SXM4-40GB (UUID: GPU-0c1d2e3f-4a5b-4c6d-8e7f-9a0b1c2d3e4f)
+";
+        let migs = mig_devices(
+            &Fixed {
+                code: 0,
+                stdout: list,
+            },
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap();
+        assert_eq!(migs.len(), 2);
+        assert_eq!(migs[1].gpu_uuid, "GPU-5fd4c2a1-0b1e-4c3a-9f7e-2d7a1b6c8e90");
+        assert_eq!(migs[1].uuid, "MIG-9a8b7c6d-5e4f-5a3b-8c2d-1e0f9a8b7c6d");
+        assert_eq!(migs[1].profile, "1g.5gb");
+        assert_eq!(migs[1].device, "1");
+
+        // 失败时的输出不能当作没有 MIG 设备
+        let error = mig_devices(
+            &Fixed {
+                code: 9,
+                stdout: "",
+            },
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains("exited with"), "{}", error);
+        assert!(error.to_string().contains("version mismatch"), "{}", error);
+    }
+}
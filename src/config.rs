@@ -0,0 +1,466 @@
+use anyhow::{bail, Result};
+use ring::hmac;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tide::http::Mime;
+use tide::{Body, Request, Response, StatusCode};
+
+use crate::alert;
+use crate::amd::Amd;
+use crate::breaker::Breaker;
+use crate::cli::Cli;
+use crate::collector::{Collector, QueryField};
+use crate::container::Runtime;
+use crate::downward;
+use crate::energy::Energy;
+use crate::federate::Downstream;
+use crate::filter::{GpuFilter, MetricFilter};
+use crate::graphite;
+use crate::health::Thresholds;
+use crate::intel::Intel;
+use crate::jetson::Jetson;
+use crate::kafka;
+use crate::mock::{Backend, Mock};
+use crate::nvidia_smi::{Exec, NvidiaSmi};
+use crate::procinfo;
+use crate::redfish::Redfish;
+use crate::relabel::{self, RelabelConfig, Rule};
+use crate::ssh::Ssh;
+use crate::tls::TlsServerConfig;
+use crate::State;
+
+pub const REDACTED: &str = "<secret>";
+
+/// Effective configuration after resolving the flags and `--web.config.file`, served at `/config`.
+#[derive(Debug, Serialize)]
+pub struct Config {
+    pub config_file: Option<String>,
+    pub log_level: String,
+    pub log_file: Option<String>,
+    pub listen: Vec<String>,
+    /// Number of sockets passed by systemd; `listen` is ignored when non-zero.
+    pub socket_activated: usize,
+    pub telemetry_path: String,
+    pub max_requests: usize,
+    pub rate_limit: u32,
+    pub request_timeout_seconds: u64,
+    pub allow_cidrs: Vec<String>,
+    pub trusted_proxies: Vec<String>,
+    /// Set when `--web.access-log` is on.
+    pub access_log_format: Option<String>,
+    pub compression: Vec<String>,
+    pub compression_threshold: usize,
+    pub idle_timeout_seconds: u64,
+    pub max_connections: usize,
+    pub reuse_port: bool,
+    pub tls: Option<TlsServerConfig>,
+    pub web_config_file: Option<String>,
+    pub headers: BTreeMap<String, String>,
+    /// User names only, the hashes are never shown.
+    pub basic_auth_users: BTreeMap<String, &'static str>,
+    pub basic_auth_file: Option<String>,
+    pub bearer_token_file: Option<String>,
+    pub tenants_file: Option<String>,
+    pub backend: &'static str,
+    /// Set with `--backend mock`.
+    pub backend_mock_gpus: Option<usize>,
+    pub collect_timeout_seconds: u64,
+    pub collect_latency_budget_seconds: u64,
+    pub collect_breaker_failures: u32,
+    pub collect_breaker_cooldown_seconds: Option<u64>,
+    pub collect_processes: bool,
+    /// By runtime, those not disabled with an empty `--collect.processes.<runtime>-socket`.
+    pub collect_processes_runtime_sockets: BTreeMap<String, String>,
+    pub collect_processes_metadata: bool,
+    pub collect_processes_cmdline_length: usize,
+    pub collect_processes_user_hash_key_file: Option<String>,
+    pub collect_mig_devices: bool,
+    pub collect_passthrough: bool,
+    pub collect_passthrough_libvirt_dir: Option<String>,
+    pub collect_slurm: bool,
+    pub collect_amd: bool,
+    pub collect_amd_command: Option<String>,
+    pub collect_intel: bool,
+    pub collect_intel_command: String,
+    pub collect_jetson: bool,
+    pub collect_jetson_command: String,
+    pub collect_events: bool,
+    pub collect_kernel_xid: bool,
+    pub collect_topology: bool,
+    pub collect_platform: bool,
+    pub kubernetes_pod_resources_socket: Option<String>,
+    pub kubernetes_metadata_files: Vec<String>,
+    /// The `host` labels of `--ssh.host` and `--ssh.hosts-file`.
+    pub ssh_hosts: Vec<String>,
+    pub ssh_hosts_file: Option<String>,
+    pub ssh_any_target: bool,
+    pub federate_targets: Vec<String>,
+    pub redfish_url: Option<String>,
+    pub redfish_username: Option<String>,
+    pub redfish_ca_file: Option<String>,
+    pub hub_listen: Option<String>,
+    pub hub_token_file: Option<String>,
+    pub hub_stale_after_seconds: u64,
+    /// Added to every GPU series, from `--kubernetes.node-name`, `--kubernetes.zone`,
+    /// `--kubernetes.metadata-file` and `--kubernetes.label`.
+    pub constant_labels: BTreeMap<String, String>,
+    pub shutdown_timeout_seconds: u64,
+    pub disable_exporter_metrics: bool,
+    pub collectors: Vec<&'static str>,
+    pub gpu_filter: GpuFilter,
+    pub metric_filter: MetricFilter,
+    pub query_fields: Vec<QueryField>,
+    pub relabel_file: Option<String>,
+    pub relabel_configs: Vec<RelabelConfig>,
+    pub debug_runtime: bool,
+    pub enable_stream: bool,
+    pub history_retention_seconds: u64,
+    pub history_interval_seconds: u64,
+    pub energy_price_per_kwh: Option<f64>,
+    pub energy_currency: String,
+    pub energy_carbon_intensity: Option<f64>,
+    pub energy_carbon_intensity_url: Option<String>,
+    pub energy_interval_seconds: u64,
+    pub health: Thresholds,
+    pub otlp_endpoint: Option<String>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub daemonize: bool,
+    pub pid_file: Option<String>,
+    pub capture_dir: Option<String>,
+    pub state_file: Option<String>,
+    pub sandbox: bool,
+    pub enable_lifecycle: bool,
+    pub lifecycle_localhost_only: bool,
+    pub enable_admin_api: bool,
+    pub push_interval_seconds: u64,
+    pub push_job: String,
+    pub push_instance: String,
+    pub remote_write_url: Option<String>,
+    pub remote_write_bearer_token_file: Option<String>,
+    pub remote_write_username: Option<String>,
+    pub remote_write_password_file: Option<String>,
+    pub pushgateway_url: Option<String>,
+    pub pushgateway_grouping: BTreeMap<String, String>,
+    pub pushgateway_delete_on_shutdown: bool,
+    pub influxdb_url: Option<String>,
+    pub influxdb_org: Option<String>,
+    pub influxdb_bucket: Option<String>,
+    pub influxdb_token_file: Option<String>,
+    pub graphite_address: Option<String>,
+    pub graphite_protocol: graphite::Protocol,
+    pub graphite_prefix: String,
+    pub graphite_tagged: bool,
+    pub graphite_interval_seconds: Option<u64>,
+    pub statsd_address: Option<String>,
+    pub statsd_prefix: String,
+    pub statsd_dogstatsd: bool,
+    pub otlp_url: Option<String>,
+    pub otlp_resource_attributes: BTreeMap<String, String>,
+    pub otlp_bearer_token_file: Option<String>,
+    pub zabbix_address: Option<String>,
+    pub zabbix_host: Option<String>,
+    pub zabbix_discovery_interval_seconds: u64,
+    pub mqtt_url: Option<String>,
+    pub mqtt_topic: String,
+    pub mqtt_qos: u8,
+    pub mqtt_retain: bool,
+    pub mqtt_client_id: Option<String>,
+    pub mqtt_username: Option<String>,
+    pub mqtt_password_file: Option<String>,
+    pub mqtt_ca_file: Option<String>,
+    pub mqtt_cert_file: Option<String>,
+    pub mqtt_key_file: Option<String>,
+    pub nats_url: Option<String>,
+    pub nats_subject: String,
+    pub nats_jetstream: bool,
+    pub nats_username: Option<String>,
+    pub nats_password_file: Option<String>,
+    pub nats_token_file: Option<String>,
+    pub nats_ca_file: Option<String>,
+    pub nats_cert_file: Option<String>,
+    pub nats_key_file: Option<String>,
+    pub fluentd_address: Option<String>,
+    pub fluentd_tag: String,
+    pub fluentd_require_ack: bool,
+    pub kafka_brokers: Vec<String>,
+    pub kafka_topic: String,
+    pub kafka_format: kafka::Format,
+    pub kafka_schema_registry_url: Option<String>,
+    pub kafka_interval_seconds: Option<u64>,
+    pub cloudwatch_region: Option<String>,
+    pub cloudwatch_namespace: String,
+    pub cloudwatch_dimensions: Vec<String>,
+    pub cloudwatch_static_dimensions: BTreeMap<String, String>,
+    pub cloudwatch_endpoint: Option<String>,
+    pub cloud_monitoring: bool,
+    pub cloud_monitoring_project: Option<String>,
+    pub cloud_monitoring_metric_prefix: String,
+    pub cloud_monitoring_endpoint: Option<String>,
+    pub azure_monitor: bool,
+    pub azure_monitor_namespace: String,
+    pub azure_monitor_client_id: Option<String>,
+    pub azure_monitor_region: Option<String>,
+    pub azure_monitor_resource_id: Option<String>,
+    pub azure_monitor_endpoint: Option<String>,
+    pub elasticsearch_url: Option<String>,
+    pub elasticsearch_index: String,
+    pub elasticsearch_username: Option<String>,
+    pub elasticsearch_password_file: Option<String>,
+    pub elasticsearch_api_key_file: Option<String>,
+    pub elasticsearch_interval_seconds: Option<u64>,
+    pub push_hub_url: Option<String>,
+    pub push_hub_token_file: Option<String>,
+    pub push_hub_ca_file: Option<String>,
+    pub push_hub_interval_seconds: Option<u64>,
+    pub alert_rules_file: Option<String>,
+    /// May hold a secret, as Slack webhook URLs do.
+    pub alert_webhook_url: Option<&'static str>,
+    pub alert_webhook_format: alert::Format,
+    pub agentx_master: Option<String>,
+    pub agentx_oid: String,
+    pub sd_advertise_address: String,
+    pub sd_labels: BTreeMap<String, String>,
+    pub consul_address: Option<String>,
+    pub consul_service: String,
+    pub consul_tags: Vec<String>,
+    pub consul_token_file: Option<String>,
+    /// The DNS-SD instance name announced with `--mdns`.
+    pub mdns_instance: Option<String>,
+}
+
+impl Config {
+    pub fn redact(users: &BTreeMap<String, String>) -> BTreeMap<String, &'static str> {
+        users.keys().map(|user| (user.clone(), REDACTED)).collect()
+    }
+}
+
+/// Options `/-/reload` and SIGHUP re-read from the `--config` file.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub collect_timeout: Duration,
+    /// `--collect.latency-budget`, unless 0.
+    pub latency_budget: Option<Duration>,
+    /// `--collect.breaker-failures`, unless 0.
+    pub breaker: Option<Breaker>,
+    pub disable_exporter_metrics: bool,
+    pub collectors: Vec<&'static Collector>,
+    pub gpu_filter: GpuFilter,
+    pub metric_filter: MetricFilter,
+    pub query_fields: Vec<QueryField>,
+    pub relabel: Vec<Rule>,
+    /// `?gpu=`/`?uuid=` of the current scrape, passed to nvidia-smi as `--id`.
+    pub devices: Vec<String>,
+    pub processes: bool,
+    /// Where container names and images are looked up, unless the
+    /// `--collect.processes.<runtime>-socket` is empty.
+    pub runtime_sockets: Vec<(Runtime, String)>,
+    /// `--collect.processes.metadata`.
+    pub process_metadata: bool,
+    /// `--collect.processes.cmdline-length`.
+    pub cmdline_length: usize,
+    /// Replaces the user names of the labels with their hash, from
+    /// `--collect.processes.user-hash-key-file`.
+    pub user_hash_key: Option<hmac::Key>,
+    pub mig_devices: bool,
+    pub passthrough: bool,
+    /// Where libvirt domains are looked up, unless `--collect.passthrough.libvirt-dir` is empty.
+    pub libvirt_dir: Option<String>,
+    pub slurm: bool,
+    /// The kubelet's pod-resources socket, with `--kubernetes.pod-labels`.
+    pub pod_resources_socket: Option<String>,
+    /// Node metadata added to every GPU series unless it already has the label.
+    pub constant_labels: Vec<(String, String)>,
+    /// Collect over SSH from these hosts rather than locally.
+    pub ssh: Option<Ssh>,
+    /// Serve the GPU metrics of these exporters rather than collecting.
+    pub federate: Vec<Downstream>,
+    /// Also read the GPUs' sensors from this BMC.
+    pub redfish: Option<Redfish>,
+    /// Also collect AMD GPUs.
+    pub amd: Option<Amd>,
+    /// Also collect Intel GPUs.
+    pub intel: Option<Intel>,
+    /// Collect the GPU of this Jetson module.
+    pub jetson: Option<Jetson>,
+    /// Synthetic GPUs instead of nvidia-smi's.
+    pub mock: Option<Mock>,
+    /// Runs nvidia-smi for the collections.
+    pub nvidia_smi: Arc<dyn NvidiaSmi>,
+    /// Where the nvidia-smi outputs that fail to parse are saved.
+    pub capture_dir: Option<String>,
+    /// Serve the GPU metrics agents reported within this long rather than collecting.
+    pub hub_stale_after: Option<Duration>,
+    /// What `/health/gpus` checks.
+    pub health: Thresholds,
+    /// `--energy.*`, unless neither a price nor a carbon intensity is given.
+    pub energy: Option<Energy>,
+}
+
+impl Settings {
+    pub fn from_cli(cli: &Cli) -> Result<Self> {
+        for (i, target) in cli.federate_targets.iter().enumerate() {
+            if cli.federate_targets[..i]
+                .iter()
+                .any(|t| t.instance == target.instance)
+            {
+                bail!(
+                    "--federate.target {} is given more than once",
+                    target.instance
+                );
+            }
+        }
+        // 这些读的是 Linux 的 /proc、/sys，或者用只有 Linux 版的工具
+        #[cfg(not(target_os = "linux"))]
+        for (option, given) in [
+            ("--collect.passthrough", cli.collect_passthrough),
+            ("--collect.slurm", cli.collect_slurm),
+            (
+                "--collect.processes.metadata",
+                cli.collect_processes_metadata,
+            ),
+            ("--collect.amd", cli.collect_amd),
+            ("--collect.intel", cli.collect_intel),
+            ("--collect.jetson", cli.collect_jetson),
+            ("--collect.kernel-xid", cli.collect_kernel_xid),
+        ] {
+            if given {
+                bail!("{} is only available on Linux", option);
+            }
+        }
+        // 这些都要问真的 nvidia-smi
+        if cli.backend == Backend::Mock {
+            let real = [
+                ("--collect.processes", cli.collect_processes),
+                ("--collect.mig-devices", cli.collect_mig_devices),
+                ("--collect.slurm", cli.collect_slurm),
+                ("--collect.events", cli.collect_events),
+                ("--collect.kernel-xid", cli.collect_kernel_xid),
+                (
+                    "--ssh.host",
+                    !cli.ssh_hosts.is_empty() || cli.ssh_hosts_file.is_some(),
+                ),
+                ("--ssh.any-target", cli.ssh_any_target),
+                ("--federate.target", !cli.federate_targets.is_empty()),
+                ("--hub.listen", cli.hub_listen.is_some()),
+            ];
+            if let Some((option, _)) = real.iter().find(|(_, given)| *given) {
+                bail!("--backend mock cannot be combined with {}", option);
+            }
+        }
+        // 只有这两处有用户名
+        if cli.collect_processes_user_hash_key_file.is_some()
+            && !cli.collect_processes_metadata
+            && !cli.collect_slurm
+        {
+            bail!("--collect.processes.user-hash-key-file needs --collect.processes.metadata or --collect.slurm");
+        }
+        for (i, field) in cli.query_fields.iter().enumerate() {
+            if cli.query_fields[..i]
+                .iter()
+                .any(|f| f.metric == field.metric)
+            {
+                bail!("--query-field {} is given more than once", field.metric);
+            }
+        }
+        Ok(Settings {
+            collect_timeout: Duration::from_secs(cli.collect_timeout),
+            latency_budget: (cli.collect_latency_budget > 0)
+                .then(|| Duration::from_secs(cli.collect_latency_budget)),
+            breaker: (cli.collect_breaker_failures > 0).then(|| Breaker {
+                failures: cli.collect_breaker_failures,
+                cooldown: Duration::from_secs(cli.collect_breaker_cooldown),
+            }),
+            disable_exporter_metrics: cli.disable_exporter_metrics,
+            collectors: cli.collectors.clone(),
+            gpu_filter: GpuFilter {
+                include: cli.gpu_include.clone(),
+                exclude: cli.gpu_exclude.clone(),
+                tenant: None,
+            },
+            metric_filter: MetricFilter::new(&cli.metric_include, &cli.metric_exclude)?,
+            query_fields: cli.query_fields.clone(),
+            relabel: match &cli.relabel_file {
+                Some(path) => relabel::from_file(path)?,
+                None => Vec::new(),
+            },
+            devices: Vec::new(),
+            processes: cli.collect_processes,
+            // cgroup 看不出运行时的容器（如 cgroupfs 驱动的 kubepods）按此顺序去问
+            runtime_sockets: [
+                (
+                    Runtime::Containerd,
+                    &cli.collect_processes_containerd_socket,
+                ),
+                (Runtime::CriO, &cli.collect_processes_crio_socket),
+                (Runtime::Docker, &cli.collect_processes_docker_socket),
+                (Runtime::Podman, &cli.collect_processes_podman_socket),
+            ]
+            .iter()
+            .filter(|(_, socket)| !socket.is_empty())
+            .map(|(runtime, socket)| (*runtime, socket.to_string()))
+            .collect(),
+            process_metadata: cli.collect_processes_metadata,
+            cmdline_length: cli.collect_processes_cmdline_length,
+            user_hash_key: cli
+                .collect_processes_user_hash_key_file
+                .as_deref()
+                .map(procinfo::load_key)
+                .transpose()?,
+            mig_devices: cli.collect_mig_devices,
+            passthrough: cli.collect_passthrough,
+            libvirt_dir: Some(cli.collect_passthrough_libvirt_dir.clone())
+                .filter(|dir| !dir.is_empty()),
+            slurm: cli.collect_slurm,
+            pod_resources_socket: Some(cli.kubernetes_pod_resources_socket.clone())
+                .filter(|_| cli.kubernetes_pod_labels),
+            constant_labels: downward::labels(cli)?,
+            ssh: Ssh::from_cli(cli)?,
+            federate: cli.federate_targets.clone(),
+            redfish: Redfish::from_cli(cli)?,
+            amd: Amd::from_cli(cli),
+            intel: Intel::from_cli(cli),
+            jetson: Jetson::from_cli(cli),
+            mock: Mock::from_cli(cli),
+            nvidia_smi: Arc::new(Exec),
+            capture_dir: cli.capture_dir.clone(),
+            hub_stale_after: cli
+                .hub_listen
+                .as_ref()
+                .map(|_| Duration::from_secs(cli.hub_stale_after)),
+            health: Thresholds::from_cli(cli)?,
+            energy: Energy::from_cli(cli)?,
+        })
+    }
+
+    pub fn collector_names(&self) -> Vec<&'static str> {
+        self.collectors.iter().map(|c| c.name).collect()
+    }
+
+    pub fn relabel_configs(&self) -> Vec<RelabelConfig> {
+        self.relabel
+            .iter()
+            .map(|rule| rule.config.clone())
+            .collect()
+    }
+}
+
+/// Serves the effective configuration as JSON, or YAML with `?format=yaml`.
+pub async fn handle_config(req: Request<State>) -> tide::Result {
+    let config = req.state().config.read().unwrap();
+    let yaml = req
+        .url()
+        .query_pairs()
+        .any(|(key, value)| key == "format" && value == "yaml");
+    if yaml {
+        return Ok(Response::builder(StatusCode::Ok)
+            .content_type(Mime::from("application/yaml"))
+            .body(serde_yaml::to_string(&*config)?)
+            .build());
+    }
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&*config)?)
+        .build())
+}
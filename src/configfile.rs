@@ -0,0 +1,144 @@
+use anyhow::{bail, Context, Result};
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde_yaml::Value;
+use std::ffi::OsString;
+use std::path::Path;
+
+use crate::collector;
+
+/// The environment variable for an option, e.g. `NVIDIA_SMI_EXPORTER_WEB_TELEMETRY_PATH`.
+pub fn env_name(option: &str) -> String {
+    format!(
+        "NVIDIA_SMI_EXPORTER_{}",
+        option.to_uppercase().replace(['.', '-'], "_")
+    )
+}
+
+/// Whether the option or, for `collector.<name>`, its negation is on the command line.
+fn given(name: &str, matches: &ArgMatches) -> bool {
+    let on_command_line = |name: &str| matches.value_source(name) == Some(ValueSource::CommandLine);
+    on_command_line(name) || collector::counterpart(name).is_some_and(on_command_line)
+}
+
+/// Switches set through the environment, which clap only supports for options taking values.
+/// `true` or a count (for `verbose`) turns them on, `false`, `0` or empty leaves them off, except
+/// `collector.<name>` which `false` turns off.
+pub fn env_switches(switches: &[&str], matches: &ArgMatches) -> Result<Vec<OsString>> {
+    let mut args = Vec::new();
+    for name in switches {
+        let var = env_name(name);
+        let value = match std::env::var(&var) {
+            Ok(value) if !given(name, matches) => value,
+            _ => continue,
+        };
+        let count = match (value.trim(), collector::counterpart(name)) {
+            ("false", Some(negation)) => {
+                args.push(format!("--{}", negation).into());
+                continue;
+            }
+            ("" | "false", _) => 0,
+            ("true", _) => 1,
+            (n, _) => n
+                .parse()
+                .with_context(|| format!("Invalid {}={:?}, expected true or false", var, value))?,
+        };
+        for _ in 0..count {
+            args.push(format!("--{}", name).into());
+        }
+    }
+    Ok(args)
+}
+
+/// Reads a `--config` file as option names and values. Nested keys are joined with dots, so
+/// `web: {telemetry-path: /m}` sets `web.telemetry-path`.
+pub fn options(path: &str) -> Result<Vec<(String, Value)>> {
+    let content =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+    let value: Value = match Path::new(path).extension().and_then(|e| e.to_str()) {
+        Some("toml") => {
+            let value: toml::Value =
+                toml::from_str(&content).with_context(|| format!("Invalid TOML in {}", path))?;
+            serde_yaml::to_value(value)?
+        }
+        _ => serde_yaml::from_str(&content).with_context(|| format!("Invalid YAML in {}", path))?,
+    };
+
+    let mut options = Vec::new();
+    flatten(String::new(), value, &mut options)
+        .with_context(|| format!("Invalid --config {}", path))?;
+    Ok(options)
+}
+
+/// Turns a `--config` file into command line arguments for the options set neither on the command
+/// line nor in the environment, as `--name=value` so values starting with `-` are not taken for
+/// flags.
+pub fn args(path: &str, matches: &ArgMatches) -> Result<Vec<OsString>> {
+    let options = options(path)?;
+    let mut args = Vec::new();
+    for (name, value) in options {
+        if name == "config" {
+            bail!("{}: config files cannot include other config files", path);
+        }
+        let counterpart = collector::counterpart(&name);
+        if given(&name, matches)
+            || std::env::var_os(env_name(&name)).is_some()
+            || counterpart.is_some_and(|other| std::env::var_os(env_name(other)).is_some())
+        {
+            continue;
+        }
+        let flag = OsString::from(format!("--{}", name));
+        match value {
+            Value::Bool(true) => args.push(flag),
+            // collector.<name>: false 关闭默认开启的采集器
+            Value::Bool(false) if counterpart.is_some() => {
+                args.push(format!("--{}", counterpart.unwrap()).into())
+            }
+            Value::Bool(false) | Value::Null => {}
+            // verbose: 2 等同于 -vv
+            Value::Number(n) if name == "verbose" => {
+                for _ in 0..n.as_u64().unwrap_or(0) {
+                    args.push(flag.clone());
+                }
+            }
+            Value::Sequence(values) => {
+                for value in values {
+                    args.push(format!("--{}={}", name, scalar(&name, value)?).into());
+                }
+            }
+            value => args.push(format!("--{}={}", name, scalar(&name, value)?).into()),
+        }
+    }
+    Ok(args)
+}
+
+fn flatten(prefix: String, value: Value, options: &mut Vec<(String, Value)>) -> Result<()> {
+    match value {
+        Value::Mapping(map) => {
+            for (key, value) in map {
+                let key = match key {
+                    Value::String(key) => key,
+                    key => bail!("Option names must be strings, got {:?}", key),
+                };
+                let name = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(name, value, options)?;
+            }
+        }
+        value if prefix.is_empty() => bail!("Expected a mapping of options, got {:?}", value),
+        value => options.push((prefix, value)),
+    }
+    Ok(())
+}
+
+fn scalar(name: &str, value: Value) -> Result<String> {
+    match value {
+        Value::String(s) => Ok(s),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        value => bail!("Unsupported value for {}: {:?}", name, value),
+    }
+}
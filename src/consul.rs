@@ -0,0 +1,127 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{json, Map};
+use std::time::Duration;
+use surf::Url;
+use tracing::{info, warn};
+
+use crate::cli::Cli;
+use crate::discovery::Advertised;
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+const CHECK_INTERVAL: &str = "15s";
+const CHECK_TIMEOUT: &str = "5s";
+/// Consul removes a service whose check stays critical this long, e.g. after a crash.
+const DEREGISTER_AFTER: &str = "10m";
+
+/// The local Consul agent at `--consul.address`, which the exporter registers itself with.
+pub struct Consul {
+    client: surf::Client,
+    url: Url,
+    token_file: Option<String>,
+    /// `<service>-<host>-<port>`
+    id: String,
+    registration: serde_json::Value,
+}
+
+impl Consul {
+    /// `http_check` is false when the endpoints require authentication, which the check cannot
+    /// provide; Consul then only checks the port accepts connections.
+    pub fn new(
+        address: &str,
+        cli: &Cli,
+        advertised: &Advertised,
+        http_check: bool,
+    ) -> Result<Self> {
+        let url =
+            Url::parse(address).with_context(|| format!("Invalid --consul.address {}", address))?;
+        let id = format!(
+            "{}-{}-{}",
+            cli.consul_service, advertised.host, advertised.port
+        );
+        let mut meta = Map::new();
+        meta.insert("scheme".to_string(), advertised.scheme.into());
+        meta.insert(
+            "metrics_path".to_string(),
+            advertised.metrics_path.clone().into(),
+        );
+        for (name, value) in &advertised.labels {
+            meta.insert(name.clone(), value.clone().into());
+        }
+        let mut check = json!({
+            "Interval": CHECK_INTERVAL,
+            "Timeout": CHECK_TIMEOUT,
+            "DeregisterCriticalServiceAfter": DEREGISTER_AFTER,
+        });
+        if http_check {
+            check["HTTP"] =
+                format!("{}://{}/readyz", advertised.scheme, advertised.address()).into();
+        } else {
+            check["TCP"] = advertised.address().into();
+        }
+        let registration = json!({
+            "ID": id,
+            "Name": cli.consul_service,
+            "Address": advertised.host,
+            "Port": advertised.port,
+            "Tags": cli.consul_tags,
+            "Meta": meta,
+            "Check": check,
+        });
+        Ok(Consul {
+            client: surf::Client::new(),
+            url,
+            token_file: cli.consul_token_file.clone(),
+            id,
+            registration,
+        })
+    }
+
+    async fn put(&self, path: &str, body: Option<&serde_json::Value>) -> Result<()> {
+        let url = self
+            .url
+            .join(path)
+            .map_err(|e| anyhow!("Invalid Consul URL: {}", e))?;
+        let mut request = surf::put(url);
+        if let Some(body) = body {
+            request = request.body_json(body).map_err(|e| e.into_inner())?;
+        }
+        // 每次都重新读取，令牌轮换后不必重启
+        if let Some(file) = &self.token_file {
+            let token = std::fs::read_to_string(file)
+                .with_context(|| format!("Failed to read {}", file))?;
+            request = request.header("X-Consul-Token", token.trim());
+        }
+        let mut response = self
+            .client
+            .send(request)
+            .await
+            .map_err(|e| e.into_inner())?;
+        if !response.status().is_success() {
+            let text = response.body_string().await.unwrap_or_default();
+            bail!("{} {}", response.status(), text.trim());
+        }
+        Ok(())
+    }
+
+    /// Registers the service, retrying every 30 seconds until Consul accepts it.
+    pub async fn register(&self) {
+        loop {
+            match self
+                .put("/v1/agent/service/register", Some(&self.registration))
+                .await
+            {
+                Ok(()) => {
+                    info!("Registered {} with Consul", self.id);
+                    return;
+                }
+                Err(e) => warn!("Failed to register with Consul, {:#}", e),
+            }
+            async_std::task::sleep(RETRY_INTERVAL).await;
+        }
+    }
+
+    pub async fn deregister(&self) -> Result<()> {
+        self.put(&format!("/v1/agent/service/deregister/{}", self.id), None)
+            .await
+    }
+}
@@ -0,0 +1,251 @@
+use anyhow::{bail, Context, Result};
+use async_std::future::timeout;
+use async_std::io::{ReadExt, WriteExt};
+use async_std::os::unix::net::UnixStream;
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::debug;
+
+use crate::grpc::{self, messages, string};
+
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+const CONTAINER_STATUS: &str = "/runtime.v1.RuntimeService/ContainerStatus";
+
+lazy_static! {
+    /// Containers by id, so each runtime is asked once per container.
+    static ref CONTAINERS: Mutex<HashMap<String, Option<Info>>> = Mutex::new(HashMap::new());
+    static ref RUNTIMES: IntGaugeVec = register_int_gauge_vec!(
+        "nvidia_smi_exporter_container_runtime_info",
+        "Container runtimes whose API socket was found, by runtime and socket.",
+        &["runtime", "socket"]
+    )
+    .unwrap();
+}
+
+/// A container runtime whose API names the containers of GPU processes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Runtime {
+    Docker,
+    Podman,
+    Containerd,
+    CriO,
+}
+
+impl Runtime {
+    pub fn name(self) -> &'static str {
+        match self {
+            Runtime::Docker => "docker",
+            Runtime::Podman => "podman",
+            Runtime::Containerd => "containerd",
+            Runtime::CriO => "cri-o",
+        }
+    }
+
+    /// The runtime of a container cgroup `component` and the prefix it gives the id, e.g.
+    /// `docker-<id>.scope` under systemd, or from the `parent` under cgroupfs, e.g. `/docker/<id>`.
+    fn from_cgroup(component: &str, parent: &str) -> Option<(Runtime, &'static str)> {
+        const PREFIXES: &[(&str, Runtime)] = &[
+            ("docker-", Runtime::Docker),
+            ("libpod-", Runtime::Podman),
+            ("cri-containerd-", Runtime::Containerd),
+            ("crio-", Runtime::CriO),
+            ("containerd-", Runtime::Containerd),
+        ];
+        if let Some((prefix, runtime)) = PREFIXES.iter().find(|(p, _)| component.starts_with(p)) {
+            return Some((*runtime, prefix));
+        }
+        match parent {
+            "docker" => Some((Runtime::Docker, "")),
+            "libpod_parent" => Some((Runtime::Podman, "")),
+            _ => None,
+        }
+    }
+
+    /// Docker's and Podman's Docker-compatible API, or CRI for the Kubernetes runtimes.
+    async fn inspect(self, socket: &str, id: &str) -> Result<Info> {
+        let (name, image) = match self {
+            Runtime::Docker | Runtime::Podman => inspect(socket, id).await?,
+            Runtime::Containerd | Runtime::CriO => container_status(socket, id).await?,
+        };
+        Ok(Info {
+            name,
+            image,
+            runtime: self,
+        })
+    }
+}
+
+/// A container a process runs in, and the runtime that made it if its cgroup tells.
+#[derive(Clone, Debug)]
+pub struct Container {
+    pub id: String,
+    pub runtime: Option<Runtime>,
+}
+
+/// The container `pid` runs in, from the innermost 64 hex digit component of its cgroup path,
+/// e.g. `/kubepods/besteffort/pod<uid>/<id>` or `/system.slice/docker-<id>.scope`.
+pub fn container(pid: u32) -> Option<Container> {
+    let cgroup = std::fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    // 每行是 hierarchy-ID:controllers:path，v2 只有一行 0::path
+    cgroup.lines().find_map(|line| {
+        let path = line.splitn(3, ':').nth(2)?;
+        let components = path.split('/').collect::<Vec<_>>();
+        components
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(i, component)| {
+                let component = component.trim_end_matches(".scope");
+                let parent = if i > 0 { components[i - 1] } else { "" };
+                let runtime = Runtime::from_cgroup(component, parent);
+                let id = component
+                    .strip_prefix(runtime.map_or("", |(_, prefix)| prefix))
+                    .unwrap_or(component);
+                (id.len() == 64 && id.chars().all(|c| c.is_ascii_hexdigit())).then(|| Container {
+                    id: id.to_string(),
+                    runtime: runtime.map(|(runtime, _)| runtime),
+                })
+            })
+    })
+}
+
+/// What a container runtime says about a container.
+#[derive(Clone, Debug)]
+pub struct Info {
+    /// Without Docker's leading `/`.
+    pub name: String,
+    /// As the container was created from, e.g. `pytorch/pytorch:2.3.0-cuda12.1-cudnn8-runtime`.
+    pub image: String,
+    pub runtime: Runtime,
+}
+
+/// The runtimes of `sockets` whose socket exists, exported as
+/// `nvidia_smi_exporter_container_runtime_info`.
+pub fn detect(sockets: &[(Runtime, String)]) -> Vec<(Runtime, &str)> {
+    let found = sockets
+        .iter()
+        .filter(|(_, socket)| Path::new(socket).exists())
+        .map(|(runtime, socket)| (*runtime, socket.as_str()))
+        .collect::<Vec<_>>();
+    RUNTIMES.reset();
+    for (runtime, socket) in &found {
+        RUNTIMES.with_label_values(&[runtime.name(), socket]).set(1);
+    }
+    found
+}
+
+/// The `containers` from the API of the runtime that made them, or of each of `runtimes` in
+/// turn when their cgroup does not tell, e.g. under Kubernetes' cgroupfs driver. None for the
+/// containers no runtime knows. Forgets the containers not in `containers`.
+pub async fn inspect_all(
+    runtimes: &[(Runtime, &str)],
+    containers: &[&Container],
+) -> HashMap<String, Option<Info>> {
+    let mut known = CONTAINERS.lock().unwrap().clone();
+    known.retain(|id, _| containers.iter().any(|c| c.id == *id));
+    for container in containers {
+        if known.contains_key(&container.id) {
+            continue;
+        }
+        let candidates = runtimes.iter().filter(|(runtime, _)| {
+            container.runtime.is_none() || container.runtime == Some(*runtime)
+        });
+        let mut info = None;
+        let mut timed_out = false;
+        for (runtime, socket) in candidates {
+            match timeout(LOOKUP_TIMEOUT, runtime.inspect(socket, &container.id)).await {
+                Ok(Ok(found)) => {
+                    info = Some(found);
+                    break;
+                }
+                Ok(Err(e)) => debug!(
+                    "{} does not know container {}, {:#}",
+                    runtime.name(),
+                    container.id,
+                    e
+                ),
+                Err(_) => {
+                    debug!(
+                        "{} timed out for container {}",
+                        runtime.name(),
+                        container.id
+                    );
+                    timed_out = true;
+                }
+            }
+        }
+        // 超时的下次再问
+        if info.is_some() || !timed_out {
+            known.insert(container.id.clone(), info);
+        }
+    }
+    *CONTAINERS.lock().unwrap() = known.clone();
+    known
+}
+
+/// `GET /containers/<id>/json`.
+async fn inspect(socket: &str, id: &str) -> Result<(String, String)> {
+    let mut stream = UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("Failed to connect to {}", socket))?;
+    // HTTP/1.0：响应读到连接关闭为止，不必处理分块编码
+    let request = format!(
+        "GET /containers/{}/json HTTP/1.0\r\nHost: docker\r\n\r\n",
+        id
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .context("Invalid response from the Docker API")?;
+    let status = head.lines().next().unwrap_or_default();
+    if !status.contains(" 200 ") {
+        bail!("Docker API returned {}", status);
+    }
+    let container: Value = serde_json::from_str(body).context("Invalid container JSON")?;
+    let name = match container["Name"].as_str() {
+        Some(name) => name.trim_start_matches('/').to_string(),
+        None => bail!("No name in the container JSON"),
+    };
+    // Config.Image 是创建时写的镜像名，Image 只是镜像 ID
+    let image = container["Config"]["Image"]
+        .as_str()
+        .or_else(|| container["Image"].as_str())
+        .unwrap_or_default()
+        .to_string();
+    Ok((name, image))
+}
+
+/// CRI `ContainerStatus`, as containerd and CRI-O serve it to the kubelet.
+async fn container_status(socket: &str, id: &str) -> Result<(String, String)> {
+    // ContainerStatusRequest { string container_id = 1 }
+    let mut request = Vec::new();
+    grpc::put_string(&mut request, 1, id);
+    let response = grpc::call(socket, CONTAINER_STATUS, &request).await?;
+    // ContainerStatusResponse { ContainerStatus status = 1 }
+    let status = match messages(&response, 1)?.last() {
+        Some(status) => *status,
+        None => bail!("No status in the CRI response"),
+    };
+    // ContainerStatus { ContainerMetadata metadata = 2; ImageSpec image = 8; string image_ref = 9 }
+    let name = match messages(status, 2)?.last() {
+        Some(metadata) => string(metadata, 1)?,
+        None => bail!("No metadata in the CRI response"),
+    };
+    let image = match messages(status, 8)?.last() {
+        Some(spec) => string(spec, 1)?,
+        None => String::new(),
+    };
+    let image = match image.is_empty() {
+        true => string(status, 9)?,
+        false => image,
+    };
+    Ok((name, image))
+}
@@ -0,0 +1,95 @@
+use anyhow::{bail, Context, Result};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use tracing::{debug, warn};
+
+/// Detaches from the terminal with the usual double fork, then writes `pid_file` if given. The
+/// original process waits for this and exits 0 once the daemon is set up, or 1 if it failed, so
+/// init scripts see startup errors. Must run before other threads start, as fork only keeps the
+/// calling one.
+pub fn daemonize(pid_file: Option<&str>) -> Result<()> {
+    // 其他系统没有 /proc/self/task，数不了线程
+    #[cfg(target_os = "linux")]
+    if crate::sandbox::thread_count()? > 1 {
+        bail!("--daemonize must happen before other threads start (not possible with --otlp.endpoint)");
+    }
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error()).with_context(|| "Failed to create pipe");
+    }
+    let (read_fd, write_fd) = (fds[0], fds[1]);
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()).with_context(|| "Failed to fork"),
+        0 => {}
+        _ => {
+            // 父进程：等待守护进程准备好；管道关闭而没有收到数据说明它启动失败
+            unsafe { libc::close(write_fd) };
+            let mut ready = [0; 1];
+            let mut pipe = unsafe { std::fs::File::from_raw_fd(read_fd) };
+            let code = match pipe.read(&mut ready) {
+                Ok(1) => 0,
+                _ => 1,
+            };
+            std::process::exit(code);
+        }
+    }
+    unsafe { libc::close(read_fd) };
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error()).with_context(|| "Failed to start a new session");
+    }
+    // 再 fork 一次，保证不再是会话首进程，不会重新获得控制终端
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()).with_context(|| "Failed to fork"),
+        0 => {}
+        _ => unsafe { libc::_exit(0) },
+    }
+
+    if let Some(path) = pid_file {
+        write_pid_file(path)?;
+    }
+    // 不切换到 /：--config 等相对路径在 reload 时还要用
+    redirect_stdio()?;
+    let mut pipe = unsafe { std::fs::File::from_raw_fd(write_fd) };
+    pipe.write_all(b"1")
+        .with_context(|| "Failed to notify the parent")?;
+    Ok(())
+}
+
+/// Writes the current pid to `path`, refusing to start if it names another running process.
+pub fn write_pid_file(path: &str) -> Result<()> {
+    if let Ok(content) = std::fs::read_to_string(path) {
+        if let Ok(pid) = content.trim().parse::<libc::pid_t>() {
+            if pid != std::process::id() as libc::pid_t && unsafe { libc::kill(pid, 0) } == 0 {
+                bail!("{} names running process {}, already running?", path, pid);
+            }
+        }
+    }
+    std::fs::write(path, format!("{}\n", std::process::id()))
+        .with_context(|| format!("Failed to write --pid-file {}", path))
+}
+
+/// Removes the pid file on shutdown; after dropping privileges or with `--sandbox` this may not
+/// be allowed, which is left to the init script.
+pub fn remove_pid_file(path: &str) {
+    if let Err(e) = std::fs::remove_file(path) {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => debug!("Cannot remove --pid-file {}, {}", path, e),
+            _ => warn!("Failed to remove --pid-file {}, {}", path, e),
+        }
+    }
+}
+
+/// Points stdin, stdout and stderr at `/dev/null`.
+fn redirect_stdio() -> Result<()> {
+    let null = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .with_context(|| "Failed to open /dev/null")?;
+    for fd in 0..3 {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error()).with_context(|| "Failed to redirect stdio");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,169 @@
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use tide::{Body, Request, Response, StatusCode};
+use tracing::error;
+
+use crate::collector::{self, COLLECTORS};
+use crate::netdata;
+use crate::push;
+use crate::State;
+
+/// Grafana units of the collectors' metrics.
+const UNITS: &[(&str, &str)] = &[
+    ("fan", "percent"),
+    ("temperature", "celsius"),
+    ("clocks", "rotmhz"),
+    ("power", "watt"),
+    ("utilization", "percent"),
+    ("memory", "mbytes"),
+];
+/// Labels identifying a GPU rather than a series of it, left out of the legends.
+const GPU_LABELS: &[&str] = &["name", "uuid"];
+
+/// `GET /dashboard.json`: a Grafana dashboard with a panel per metric of a collection, so that
+/// the metric and label names are the ones served after `--query-field`, the constant labels
+/// and relabeling, with `instance` and `gpu` variables.
+pub async fn handle_dashboard(req: Request<State>) -> tide::Result {
+    let settings = match crate::scrape_settings(&req) {
+        Ok(settings) => settings,
+        Err(e) => return Ok(crate::bad_request(e)),
+    };
+    let exposition = match collector::process_nvidia_smi(&settings).await {
+        Ok(exposition) => exposition,
+        Err(e) => {
+            error!("Failed to process nvidia-smi, {:#}", e);
+            return Ok(Response::builder(StatusCode::ServiceUnavailable)
+                .body(Body::from_json(&json!({ "error": format!("{:#}", e) }))?)
+                .build());
+        }
+    };
+
+    // 每个指标的标签及各自出现过的值，按首次出现的顺序
+    let mut metrics: Vec<(String, BTreeMap<String, Vec<String>>)> = Vec::new();
+    for sample in push::parse(&exposition) {
+        let index = match metrics.iter().position(|(name, _)| *name == sample.name) {
+            Some(index) => index,
+            None => {
+                metrics.push((sample.name.clone(), BTreeMap::new()));
+                metrics.len() - 1
+            }
+        };
+        for (label, value) in sample.labels {
+            let values = metrics[index].1.entry(label).or_default();
+            if !values.contains(&value) {
+                values.push(value);
+            }
+        }
+    }
+    // info 指标的值恒为 1，画出来没有意义
+    metrics.retain(|(name, _)| !name.ends_with("_info"));
+
+    let fields = collector::fields(&settings);
+    let panels = metrics
+        .iter()
+        .enumerate()
+        .map(|(i, (metric, labels))| {
+            let field = fields.iter().find(|(_, m)| m == metric).map(|(f, _)| *f);
+            let family = COLLECTORS
+                .iter()
+                .find(|c| c.fields.iter().any(|(_, m)| m == metric))
+                .map_or("other", |c| c.name);
+            let unit = UNITS
+                .iter()
+                .find(|(collector, _)| *collector == family)
+                .map_or("short", |(_, unit)| *unit);
+            let description = netdata::help(&exposition, metric)
+                .map(str::to_string)
+                .or_else(|| field.map(|field| format!("nvidia-smi --query-gpu={}", field)))
+                .unwrap_or_default();
+            let mut selector = vec!["instance=~\"$instance\""];
+            if labels.contains_key("gpu") {
+                selector.push("gpu=~\"$gpu\"");
+            }
+            json!({
+                "id": i + 1,
+                "type": "timeseries",
+                "title": metric,
+                "description": description,
+                "datasource": {"type": "prometheus", "uid": "${datasource}"},
+                "gridPos": {"h": 8, "w": 12, "x": (i % 2) * 12, "y": (i / 2) * 8},
+                "fieldConfig": {"defaults": {"unit": unit}, "overrides": []},
+                "targets": [{
+                    "refId": "A",
+                    "expr": format!("{}{{{}}}", metric, selector.join(", ")),
+                    "legendFormat": legend(labels),
+                }],
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // label_values 不限定指标时会扫描所有时间序列
+    let mut variables = vec![json!({
+        "name": "datasource",
+        "type": "datasource",
+        "query": "prometheus",
+    })];
+    if let Some((metric, _)) = metrics.first() {
+        variables.push(variable(
+            "instance",
+            format!("label_values({}, instance)", metric),
+        ));
+    }
+    if let Some((metric, _)) = metrics
+        .iter()
+        .find(|(_, labels)| labels.contains_key("gpu"))
+    {
+        variables.push(variable(
+            "gpu",
+            format!("label_values({}{{instance=~\"$instance\"}}, gpu)", metric),
+        ));
+    }
+    let dashboard = json!({
+        "uid": "nvidia-smi-exporter",
+        "title": "NVIDIA GPUs",
+        "tags": ["nvidia-smi-exporter"],
+        "editable": true,
+        "refresh": "30s",
+        "time": {"from": "now-6h", "to": "now"},
+        "schemaVersion": 39,
+        "version": 1,
+        "templating": {"list": variables},
+        "panels": panels,
+    });
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&dashboard)?)
+        .build())
+}
+
+/// A multi-value query variable, all selected.
+fn variable(name: &str, query: String) -> Value {
+    json!({
+        "name": name,
+        "type": "query",
+        "datasource": {"type": "prometheus", "uid": "${datasource}"},
+        "query": query,
+        "refresh": 2,
+        "multi": true,
+        "includeAll": true,
+        "current": {"text": "All", "value": "$__all"},
+        "sort": 3,
+    })
+}
+
+/// `{{instance}} {{gpu}}` and the other labels telling the metric's series apart.
+fn legend(labels: &BTreeMap<String, Vec<String>>) -> String {
+    let mut names = labels
+        .iter()
+        .filter(|(name, values)| {
+            *name == "gpu" || (values.len() > 1 && !GPU_LABELS.contains(&name.as_str()))
+        })
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>();
+    // gpu 放在最前
+    names.sort_by_key(|name| *name != "gpu");
+    std::iter::once("instance")
+        .chain(names)
+        .map(|name| format!("{{{{{}}}}}", name))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
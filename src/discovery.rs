@@ -0,0 +1,83 @@
+use anyhow::{anyhow, Context, Result};
+use serde_json::{json, Map, Value};
+use tide::{Body, Request, Response, StatusCode};
+
+use crate::cli::Cli;
+use crate::push;
+use crate::State;
+
+/// Where Prometheus reaches this exporter, for `/api/v1/targets` and Consul.
+#[derive(Clone, Debug)]
+pub struct Advertised {
+    pub host: String,
+    pub port: u16,
+    pub scheme: &'static str,
+    pub metrics_path: String,
+    /// `--sd.label`
+    pub labels: Vec<(String, String)>,
+}
+
+impl Advertised {
+    /// `--sd.advertise-address`, or the host name and the port of the first TCP `--listen`
+    /// address.
+    pub fn new(cli: &Cli, listen_addrs: &[String], tls: bool) -> Result<Self> {
+        let (host, port) = match &cli.sd_advertise_address {
+            Some(address) => {
+                let (host, port) = address
+                    .rsplit_once(':')
+                    .ok_or_else(|| anyhow!("--sd.advertise-address must be HOST:PORT"))?;
+                (host.to_string(), port)
+            }
+            None => {
+                let port = listen_addrs
+                    .iter()
+                    .filter(|addr| !addr.starts_with("unix:"))
+                    .find_map(|addr| addr.rsplit_once(':'))
+                    .map_or("9101", |(_, port)| port);
+                (push::hostname(), port)
+            }
+        };
+        Ok(Advertised {
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port: port
+                .parse()
+                .with_context(|| format!("Invalid advertised port {:?}", port))?,
+            scheme: if tls { "https" } else { "http" },
+            metrics_path: cli.telemetry_path.clone(),
+            labels: cli.sd_labels.clone(),
+        })
+    }
+
+    /// `host:port`, with brackets around IPv6 addresses.
+    pub fn address(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// `GET /api/v1/targets`: this exporter as a Prometheus `http_sd_configs` target group.
+pub async fn handle_targets(req: Request<State>) -> tide::Result {
+    let advertised = &req.state().advertised;
+    let mut labels = Map::new();
+    labels.insert("__scheme__".to_string(), advertised.scheme.into());
+    labels.insert(
+        "__metrics_path__".to_string(),
+        advertised.metrics_path.clone().into(),
+    );
+    for (name, value) in &advertised.labels {
+        labels.insert(name.clone(), value.clone().into());
+    }
+    let body = json!([{
+        "targets": [advertised.address()],
+        "labels": Value::Object(labels),
+    }]);
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&body)?)
+        .build())
+}
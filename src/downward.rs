@@ -0,0 +1,78 @@
+use anyhow::{anyhow, Context, Result};
+
+use crate::cli::Cli;
+
+/// The constant labels of every GPU series: the labels of the Downward API files, `node` and
+/// `zone`, then `--kubernetes.label`s, later ones replacing earlier ones of the same name.
+pub fn labels(cli: &Cli) -> Result<Vec<(String, String)>> {
+    let mut labels = Vec::new();
+    for path in &cli.kubernetes_metadata_files {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let label = parse_line(line).with_context(|| format!("{}:{}", path, i + 1))?;
+            labels.push(label);
+        }
+    }
+    if let Some(node) = &cli.kubernetes_node_name {
+        labels.push(("node".to_string(), node.clone()));
+    }
+    if let Some(zone) = &cli.kubernetes_zone {
+        labels.push(("zone".to_string(), zone.clone()));
+    }
+    labels.extend(cli.kubernetes_labels.iter().cloned());
+    let mut deduped: Vec<(String, String)> = Vec::new();
+    for (name, value) in labels {
+        deduped.retain(|(n, _)| *n != name);
+        deduped.push((name, value));
+    }
+    Ok(deduped)
+}
+
+/// `topology.kubernetes.io/zone="us-east-1a"` as the Downward API writes labels and annotations,
+/// the value quoted like Go's `%q`; the label name is the key without its prefix.
+fn parse_line(line: &str) -> Result<(String, String)> {
+    let (key, value) = line
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected KEY=\"VALUE\""))?;
+    let value = value
+        .trim()
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .ok_or_else(|| anyhow!("expected a quoted value for {}", key))?;
+    Ok((label_name(key.trim()), unquote(value)))
+}
+
+/// `app.kubernetes.io/part-of` → `part_of`.
+fn label_name(key: &str) -> String {
+    let name = key.rsplit('/').next().unwrap_or(key);
+    let name = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    match name.starts_with(|c: char| c.is_ascii_digit()) {
+        true => format!("_{}", name),
+        false => name,
+    }
+}
+
+fn unquote(value: &str) -> String {
+    let mut out = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some(c) => out.push(c),
+            None => {}
+        }
+    }
+    out
+}
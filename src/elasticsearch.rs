@@ -0,0 +1,161 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Value};
+use std::time::Duration;
+use surf::http::auth::BasicAuth;
+use surf::http::headers::AUTHORIZATION;
+use surf::Url;
+
+use crate::cli::Cli;
+use crate::push::{Batch, Target};
+
+enum Auth {
+    None,
+    Basic {
+        username: String,
+        password_file: String,
+    },
+    ApiKey {
+        key_file: String,
+    },
+}
+
+/// An Elasticsearch or OpenSearch cluster (`--push.elasticsearch.url`), sent a document per GPU
+/// with the bulk API.
+pub struct Elasticsearch {
+    client: surf::Client,
+    url: Url,
+    /// With strftime escapes, formatted with the collection time in UTC.
+    index: String,
+    auth: Auth,
+    /// `job` and `instance`.
+    labels: Vec<(String, String)>,
+    interval: Option<Duration>,
+}
+
+impl Elasticsearch {
+    pub fn new(url: &str, cli: &Cli, labels: Vec<(String, String)>) -> Result<Self> {
+        let url = Url::parse(&format!("{}/_bulk", url.trim_end_matches('/')))
+            .with_context(|| format!("Invalid --push.elasticsearch.url {}", url))?;
+        let auth = match (
+            &cli.elasticsearch_api_key_file,
+            &cli.elasticsearch_username,
+            &cli.elasticsearch_password_file,
+        ) {
+            (Some(key_file), _, _) => Auth::ApiKey {
+                key_file: key_file.clone(),
+            },
+            (None, Some(username), Some(password_file)) => Auth::Basic {
+                username: username.clone(),
+                password_file: password_file.clone(),
+            },
+            _ => Auth::None,
+        };
+        Ok(Elasticsearch {
+            client: surf::Client::new(),
+            url,
+            index: cli.elasticsearch_index.clone(),
+            auth,
+            labels,
+            interval: cli.elasticsearch_interval.map(Duration::from_secs),
+        })
+    }
+
+    /// The NDJSON body: a `create` action and a document for each GPU.
+    fn body(&self, batch: &Batch) -> String {
+        let time = DateTime::<Utc>::from(batch.timestamp);
+        let index = time.format(&self.index).to_string();
+        let label = |name: &str| {
+            self.labels
+                .iter()
+                .find(|(n, _)| n == name)
+                .map_or("", |(_, value)| value.as_str())
+        };
+        let mut body = String::new();
+        for gpu in batch.gpus() {
+            let action = json!({ "create": { "_index": index } });
+            let document = json!({
+                "@timestamp": time.to_rfc3339_opts(SecondsFormat::Millis, true),
+                "host": { "name": gpu.host.unwrap_or_else(|| label("instance")) },
+                "service": { "name": label("job") },
+                "gpu": { "index": gpu.index, "name": gpu.name },
+                "metrics": gpu.metrics,
+            });
+            body += &format!("{}\n{}\n", action, document);
+        }
+        body
+    }
+}
+
+#[tide::utils::async_trait]
+impl Target for Elasticsearch {
+    fn name(&self) -> &'static str {
+        "elasticsearch"
+    }
+
+    fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    async fn push(&self, batch: &Batch) -> Result<()> {
+        let body = self.body(batch);
+        if body.is_empty() {
+            return Ok(());
+        }
+        let mut request = surf::post(self.url.clone())
+            .body(body)
+            .header("Content-Type", "application/x-ndjson")
+            .build();
+        // 每次推送都重新读取，凭据轮换后不必重启
+        let read = |file: &String| {
+            std::fs::read_to_string(file)
+                .map(|s| s.trim().to_string())
+                .with_context(|| format!("Failed to read {}", file))
+        };
+        match &self.auth {
+            Auth::None => {}
+            Auth::Basic {
+                username,
+                password_file,
+            } => {
+                let password = read(password_file)?;
+                request.insert_header(AUTHORIZATION, BasicAuth::new(username, password).value());
+            }
+            Auth::ApiKey { key_file } => {
+                request.insert_header(AUTHORIZATION, format!("ApiKey {}", read(key_file)?));
+            }
+        }
+        let mut response = self
+            .client
+            .send(request)
+            .await
+            .map_err(|e| e.into_inner())?;
+        let text = response.body_string().await.unwrap_or_default();
+        if !response.status().is_success() {
+            bail!("{} {}", response.status(), text.trim());
+        }
+        // 批量请求整体成功时，单个文档仍可能失败
+        let result: Value = serde_json::from_str(&text).context("Invalid bulk response")?;
+        if result["errors"].as_bool() == Some(true) {
+            let items = result["items"].as_array().map_or(&[][..], Vec::as_slice);
+            let failed = items
+                .iter()
+                .filter_map(|item| item["create"]["error"].as_object())
+                .collect::<Vec<_>>();
+            let reason = failed
+                .first()
+                .map(|error| {
+                    let field = |name: &str| error.get(name).and_then(Value::as_str).unwrap_or("");
+                    format!("{}: {}", field("type"), field("reason"))
+                })
+                .unwrap_or_default();
+            bail!(
+                "{} of {} documents failed, {}",
+                failed.len(),
+                items.len(),
+                reason
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,371 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_std::future::timeout;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_counter_vec, register_gauge, register_int_gauge, CounterVec, Gauge, IntGauge,
+};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use surf::Url;
+use tracing::{debug, info, warn};
+
+use crate::cli::Cli;
+use crate::collector;
+use crate::config::Settings;
+use crate::gpu::Gpu;
+use crate::state;
+
+/// nvidia-smi's power reading, in W.
+const POWER_FIELD: (&str, &str) = ("power.draw", "nvidia_power_draw");
+/// The driver's energy counter, in mJ since it loaded, on Volta and newer.
+const ENERGY_FIELD: (&str, &str) = (
+    "total_energy_consumption",
+    "nvidia_energy_consumption_millijoules_total",
+);
+/// Intervals between two power readings over which no energy is counted from them, e.g. after
+/// failed collections.
+const MAX_GAP_INTERVALS: u32 = 4;
+/// How soon the carbon intensity is fetched again after a failure.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const JOULES_PER_KWH: f64 = 3.6e6;
+
+lazy_static! {
+    static ref COST: CounterVec = register_counter_vec!(
+        "nvidia_energy_cost_total",
+        "Cost of the energy the GPU drew at --energy.price-per-kwh, since the exporter started or, with --state-file, first ran.",
+        &["gpu", "uuid", "currency"]
+    )
+    .unwrap();
+    static ref CO2E: CounterVec = register_counter_vec!(
+        "nvidia_co2e_grams_total",
+        "Grams of CO2-equivalent emitted for the energy the GPU drew at the carbon intensity, since the exporter started or, with --state-file, first ran.",
+        &["gpu", "uuid"]
+    )
+    .unwrap();
+    static ref CARBON_INTENSITY: Gauge = register_gauge!(
+        "nvidia_carbon_intensity_grams_per_kwh",
+        "Grams of CO2-equivalent per kWh the emissions are counted at."
+    )
+    .unwrap();
+    static ref CARBON_INTENSITY_UP: IntGauge = register_int_gauge!(
+        "nvidia_smi_exporter_carbon_intensity_up",
+        "Whether --energy.carbon-intensity-url answered the last fetch."
+    )
+    .unwrap();
+}
+
+/// The counters `--state-file` keeps across restarts.
+pub fn counters() -> [state::Counter; 2] {
+    [state::Counter::Float(&COST), state::Counter::Float(&CO2E)]
+}
+
+/// `--energy.*`: the cost and emissions of the energy the GPUs draw.
+#[derive(Clone, Debug)]
+pub struct Energy {
+    /// `--energy.price-per-kwh` and `--energy.currency`.
+    pub price: Option<(f64, String)>,
+    pub carbon_intensity: Option<CarbonIntensity>,
+    /// `--energy.interval`
+    pub interval: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub enum CarbonIntensity {
+    /// `--energy.carbon-intensity`, in g/kWh.
+    Static(f64),
+    /// `--energy.carbon-intensity-url`, fetched every `refresh`.
+    Provider {
+        url: Url,
+        pointer: String,
+        token_file: Option<String>,
+        refresh: Duration,
+    },
+}
+
+impl Energy {
+    pub fn from_cli(cli: &Cli) -> Result<Option<Self>> {
+        let carbon_intensity = match (
+            &cli.energy_carbon_intensity_url,
+            cli.energy_carbon_intensity,
+        ) {
+            (Some(url), _) => Some(CarbonIntensity::Provider {
+                url: Url::parse(url)
+                    .with_context(|| format!("Invalid --energy.carbon-intensity-url {}", url))?,
+                pointer: cli.energy_carbon_intensity_pointer.clone(),
+                token_file: cli.energy_carbon_intensity_token_file.clone(),
+                refresh: Duration::from_secs(cli.energy_carbon_intensity_refresh),
+            }),
+            (None, Some(grams)) if grams < 0.0 => {
+                bail!("--energy.carbon-intensity must not be negative")
+            }
+            (None, Some(grams)) => Some(CarbonIntensity::Static(grams)),
+            (None, None) => None,
+        };
+        let price = match cli.energy_price_per_kwh {
+            Some(price) if price < 0.0 => bail!("--energy.price-per-kwh must not be negative"),
+            Some(price) => Some((price, cli.energy_currency.clone())),
+            None => None,
+        };
+        if price.is_none() && carbon_intensity.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Energy {
+            price,
+            carbon_intensity,
+            interval: Duration::from_secs(cli.energy_interval),
+        }))
+    }
+}
+
+/// The last reading of a GPU.
+struct Reading {
+    at: Instant,
+    /// mJ
+    energy: Option<f64>,
+    /// W
+    power: Option<f64>,
+}
+
+/// Counts the energy of each GPU between its readings.
+#[derive(Default)]
+struct Meter {
+    readings: HashMap<String, Reading>,
+}
+
+impl Meter {
+    /// The joules `gpu` drew between its previous reading and this one, taken `at`: the
+    /// difference of the driver's counter when it has one, else the mean of the two power
+    /// readings over the time between them.
+    fn joules(
+        &mut self,
+        gpu: &Gpu,
+        at: Instant,
+        energy_metric: &str,
+        power_metric: &str,
+        max_gap: Duration,
+    ) -> f64 {
+        let current = Reading {
+            at,
+            energy: gpu.value(energy_metric),
+            power: gpu.value(power_metric),
+        };
+        let joules = match self.readings.get(&gpu.uuid) {
+            Some(previous) => match (previous.energy, current.energy) {
+                // 驱动重新加载后计数器归零，这一段不计
+                (Some(before), Some(after)) => (after - before).max(0.0) / 1000.0,
+                (None, None) => match (previous.power, current.power) {
+                    (Some(before), Some(after)) if current.at - previous.at <= max_gap => {
+                        (before + after) / 2.0 * (current.at - previous.at).as_secs_f64()
+                    }
+                    _ => 0.0,
+                },
+                _ => 0.0,
+            },
+            None => 0.0,
+        };
+        self.readings.insert(gpu.uuid.clone(), current);
+        joules
+    }
+}
+
+/// `GET url` and the number at `pointer` of its JSON.
+async fn fetch(url: &Url, pointer: &str, token_file: Option<&str>, limit: Duration) -> Result<f64> {
+    let mut request = surf::get(url.clone()).header("Accept", "application/json");
+    // 每次都重新读取，令牌轮换后不必重启
+    if let Some(file) = token_file {
+        let token =
+            std::fs::read_to_string(file).with_context(|| format!("Failed to read {}", file))?;
+        request = request.header("auth-token", token.trim());
+    }
+    let mut response = timeout(limit, surf::Client::new().send(request))
+        .await
+        .map_err(|_| anyhow!("Timed out after {:?}", limit))?
+        .map_err(|e| e.into_inner())?;
+    if !response.status().is_success() {
+        let text = response.body_string().await.unwrap_or_default();
+        bail!("{} {}", response.status(), text.trim());
+    }
+    let body: Value = response.body_json().await.map_err(|e| e.into_inner())?;
+    body.pointer(pointer)
+        .and_then(Value::as_f64)
+        .filter(|grams| *grams >= 0.0)
+        .ok_or_else(|| anyhow!("No carbon intensity at {} in {}", pointer, body))
+}
+
+/// Reads the power of the GPUs every `--energy.interval` and adds the cost and emissions of the
+/// energy they drew since the previous reading.
+pub fn start(settings: &Arc<RwLock<Settings>>) {
+    async_std::task::spawn(run(settings.clone()));
+}
+
+async fn run(settings: Arc<RwLock<Settings>>) {
+    let mut meter = Meter::default();
+    let mut intensity = None;
+    let mut next_fetch = Instant::now();
+    loop {
+        let started = Instant::now();
+        let settings = settings.read().unwrap().clone();
+        let energy = match &settings.energy {
+            Some(energy) => energy,
+            // 重新加载时去掉了 --energy.*
+            None => {
+                async_std::task::sleep(RETRY_INTERVAL).await;
+                continue;
+            }
+        };
+        match &energy.carbon_intensity {
+            Some(CarbonIntensity::Static(grams)) => intensity = Some(*grams),
+            Some(CarbonIntensity::Provider {
+                url,
+                pointer,
+                token_file,
+                refresh,
+            }) if Instant::now() >= next_fetch => {
+                match fetch(
+                    url,
+                    pointer,
+                    token_file.as_deref(),
+                    settings.collect_timeout,
+                )
+                .await
+                {
+                    Ok(grams) => {
+                        if intensity.is_none() {
+                            info!("Counting emissions at {} g/kWh from {}", grams, url);
+                        }
+                        intensity = Some(grams);
+                        CARBON_INTENSITY_UP.set(1);
+                        next_fetch = Instant::now() + *refresh;
+                    }
+                    // 沿用上次的强度，没有时不计排放
+                    Err(e) => {
+                        warn!("Failed to fetch the carbon intensity from {}, {:#}", url, e);
+                        CARBON_INTENSITY_UP.set(0);
+                        next_fetch = Instant::now() + RETRY_INTERVAL.min(*refresh);
+                    }
+                }
+            }
+            Some(CarbonIntensity::Provider { .. }) => {}
+            None => intensity = None,
+        }
+        if let Some(grams) = intensity {
+            CARBON_INTENSITY.set(grams);
+        }
+
+        let mut fields = collector::fields(&settings);
+        for field in [POWER_FIELD, ENERGY_FIELD] {
+            if !fields.iter().any(|(f, _)| *f == field.0) {
+                fields.push(field);
+            }
+        }
+        let metric = |field: &str| {
+            fields
+                .iter()
+                .find(|(f, _)| *f == field)
+                .map(|(_, metric)| metric.to_string())
+                .unwrap_or_default()
+        };
+        let (energy_metric, power_metric) = (metric(ENERGY_FIELD.0), metric(POWER_FIELD.0));
+        match collector::collect_fresh_gpus(&settings, &fields).await {
+            // 断路器打开时是旧读数，不计；恢复后计数器之差仍算得出这段能耗，功率则超出间隔不计
+            (Ok(_), false) => {
+                debug!("Not counting the energy of readings kept by the circuit breaker")
+            }
+            (Ok(gpus), true) => {
+                let at = Instant::now();
+                for gpu in &gpus {
+                    let kwh = meter.joules(
+                        gpu,
+                        at,
+                        &energy_metric,
+                        &power_metric,
+                        energy.interval * MAX_GAP_INTERVALS,
+                    ) / JOULES_PER_KWH;
+                    if let Some((price, currency)) = &energy.price {
+                        COST.with_label_values(&[&gpu.index, &gpu.uuid, currency])
+                            .inc_by(kwh * price);
+                    }
+                    if let Some(grams) = intensity {
+                        CO2E.with_label_values(&[&gpu.index, &gpu.uuid])
+                            .inc_by(kwh * grams);
+                    }
+                }
+            }
+            (Err(e), _) => warn!("Failed to read the power of the GPUs, {:#}", e),
+        }
+        async_std::task::sleep(energy.interval.saturating_sub(started.elapsed())).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENERGY: &str = "nvidia_energy_consumption_millijoules_total";
+    const POWER: &str = "nvidia_power_draw";
+    const MAX_GAP: Duration = Duration::from_secs(60);
+
+    fn gpu(uuid: &str, energy: Option<f64>, power: Option<f64>) -> Gpu {
+        let values = [(ENERGY, energy), (POWER, power)]
+            .iter()
+            .filter_map(|(metric, value)| Some((metric.to_string(), (*value)?)))
+            .collect();
+        Gpu {
+            index: "0".to_string(),
+            uuid: uuid.to_string(),
+            name: "NVIDIA A100-SXM4-80GB".to_string(),
+            driver_version: "550.90.07".to_string(),
+            values,
+            host: None,
+        }
+    }
+
+    #[test]
+    fn counts_the_energy_counter() {
+        let mut meter = Meter::default();
+        let start = Instant::now();
+        let mut joules = |energy: f64, power: f64, seconds: u64| {
+            meter.joules(
+                &gpu("GPU-0", Some(energy), Some(power)),
+                start + Duration::from_secs(seconds),
+                ENERGY,
+                POWER,
+                MAX_GAP,
+            )
+        };
+        assert_eq!(joules(1_000_000.0, 300.0, 0), 0.0);
+        // 有计数器时不看功率
+        assert_eq!(joules(1_004_500.0, 100.0, 15), 4.5);
+        // 间隔再长，计数器之差也照算
+        assert_eq!(joules(1_304_500.0, 100.0, 1015), 300.0);
+        // 驱动重新加载，计数器归零
+        assert_eq!(joules(2_000.0, 100.0, 1030), 0.0);
+        assert_eq!(joules(5_000.0, 100.0, 1045), 3.0);
+    }
+
+    #[test]
+    fn integrates_the_power() {
+        let mut meter = Meter::default();
+        let start = Instant::now();
+        let mut joules = |uuid: &str, power: Option<f64>, seconds: u64| {
+            meter.joules(
+                &gpu(uuid, None, power),
+                start + Duration::from_secs(seconds),
+                ENERGY,
+                POWER,
+                MAX_GAP,
+            )
+        };
+        assert_eq!(joules("GPU-0", Some(100.0), 0), 0.0);
+        assert_eq!(joules("GPU-1", Some(50.0), 0), 0.0);
+        assert_eq!(joules("GPU-0", Some(300.0), 15), 3000.0);
+        assert_eq!(joules("GPU-0", Some(300.0), 75), 18000.0);
+        // 超过 max_gap 的两次读数之间不计
+        assert_eq!(joules("GPU-0", Some(300.0), 136), 0.0);
+        assert_eq!(joules("GPU-0", None, 151), 0.0);
+        assert_eq!(joules("GPU-0", Some(300.0), 166), 0.0);
+        assert_eq!(joules("GPU-1", Some(150.0), 10), 1000.0);
+    }
+}
@@ -0,0 +1,308 @@
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// How long a probe of the device nodes is reused; opening a GPU without persistence mode
+/// initializes it, which takes a while.
+const PROBE_TTL: Duration = Duration::from_secs(300);
+const DRIVER_GPUS: &str = "/proc/driver/nvidia/gpus";
+/// Needed for anything, whatever GPUs are visible.
+const CONTROL_DEVICE: &str = "/dev/nvidiactl";
+/// WSL2 has no NVIDIA device nodes, GPUs are reached through the DirectX paravirtualization
+/// device.
+const WSL_DEVICE: &str = "/dev/dxg";
+/// Where WSL2 mounts the Windows driver's user-space libraries and nvidia-smi.
+const WSL_NVIDIA_SMI: &str = "/usr/lib/wsl/lib/nvidia-smi";
+
+lazy_static! {
+    static ref ENVIRONMENT: Environment = detect();
+    static ref NVIDIA_SMI: String = locate_nvidia_smi();
+    static ref PROBE: Mutex<Option<(Instant, Probe)>> = Mutex::new(None);
+    static ref ENVIRONMENT_INFO: IntGaugeVec = register_int_gauge_vec!(
+        "nvidia_smi_exporter_environment_info",
+        "The container the exporter runs in, if any, and whether it is in a user namespace (unprivileged).",
+        &["container", "user_namespace"]
+    )
+    .unwrap();
+    static ref PLATFORM_INFO: IntGaugeVec = register_int_gauge_vec!(
+        "nvidia_smi_exporter_platform_info",
+        "The platform the exporter runs on (linux, wsl2), and whether its driver reports only part of the metrics.",
+        &["platform", "partial"]
+    )
+    .unwrap();
+    static ref DEVICE_ACCESS: IntGaugeVec = register_int_gauge_vec!(
+        "nvidia_smi_exporter_device_access",
+        "NVIDIA device nodes by whether the exporter can open them (ok, denied, missing).",
+        &["device", "pci_bus_id", "access"]
+    )
+    .unwrap();
+}
+
+/// Where the exporter runs.
+#[derive(Clone, Debug)]
+pub struct Environment {
+    /// `lxc`, `docker`, `podman`, `kubernetes`…, or none on the host.
+    pub container: Option<String>,
+    /// Root in the container is not root on the host, as in unprivileged LXC.
+    pub user_namespace: bool,
+    /// Under WSL2, where the Windows driver leaves many fields unsupported.
+    pub wsl: bool,
+}
+
+pub fn environment() -> &'static Environment {
+    &ENVIRONMENT
+}
+
+fn detect() -> Environment {
+    // systemd 和 LXC 都会写 container=；Docker 不写，只能看 /.dockerenv
+    let container = std::fs::read_to_string("/run/systemd/container")
+        .ok()
+        .map(|s| s.trim().to_string())
+        .or_else(|| {
+            let environ = std::fs::read("/proc/1/environ").ok()?;
+            environ
+                .split(|&b| b == 0)
+                .find_map(|entry| entry.strip_prefix(b"container="))
+                .map(|value| String::from_utf8_lossy(value).into_owned())
+        })
+        .or_else(|| std::env::var("container").ok())
+        .filter(|container| !container.is_empty())
+        .or_else(|| {
+            [("/run/.containerenv", "podman"), ("/.dockerenv", "docker")]
+                .iter()
+                .find(|(path, _)| Path::new(path).exists())
+                .map(|(_, container)| container.to_string())
+        })
+        .or_else(|| std::env::var_os("KUBERNETES_SERVICE_HOST").map(|_| "kubernetes".to_string()));
+    // 初始用户命名空间的映射是 0 0 4294967295
+    let user_namespace = std::fs::read_to_string("/proc/self/uid_map")
+        .map(|map| map.split_whitespace().collect::<Vec<_>>() != ["0", "0", "4294967295"])
+        .unwrap_or(false);
+    // WSL2 的内核版本形如 5.15.153.1-microsoft-standard-WSL2
+    let wsl = std::fs::read_to_string("/proc/sys/kernel/osrelease")
+        .map(|release| release.to_lowercase().contains("microsoft"))
+        .unwrap_or(false);
+    let environment = Environment {
+        container,
+        user_namespace,
+        wsl,
+    };
+    ENVIRONMENT_INFO
+        .with_label_values(&[
+            environment.container.as_deref().unwrap_or_default(),
+            &environment.user_namespace.to_string(),
+        ])
+        .set(1);
+    let platform = if wsl { "wsl2" } else { "linux" };
+    PLATFORM_INFO
+        .with_label_values(&[platform, &wsl.to_string()])
+        .set(1);
+    environment
+}
+
+/// The nvidia-smi to run: from `$PATH`, else under WSL2 the one the Windows driver provides,
+/// which is not on the `$PATH` of services.
+pub fn nvidia_smi() -> &'static str {
+    &NVIDIA_SMI
+}
+
+fn locate_nvidia_smi() -> String {
+    let on_path = std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).any(|dir| dir.join("nvidia-smi").is_file()))
+        .unwrap_or(false);
+    if !on_path && environment().wsl && Path::new(WSL_NVIDIA_SMI).is_file() {
+        info!("nvidia-smi is not on the PATH, running {}", WSL_NVIDIA_SMI);
+        return WSL_NVIDIA_SMI.to_string();
+    }
+    "nvidia-smi".to_string()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Ok,
+    /// By file permissions, or by the device cgroup as in LXC without `c 195:* rwm`.
+    Denied,
+    /// Not bind-mounted into the container.
+    Missing,
+}
+
+impl Access {
+    fn name(self) -> &'static str {
+        match self {
+            Access::Ok => "ok",
+            Access::Denied => "denied",
+            Access::Missing => "missing",
+        }
+    }
+}
+
+/// A device node of the driver.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Node {
+    pub path: String,
+    /// For the node of a GPU, when the driver's `/proc` entries are visible.
+    pub bus_id: Option<String>,
+    pub access: Access,
+}
+
+/// What the exporter can open of the driver's device nodes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Probe {
+    pub nodes: Vec<Node>,
+}
+
+impl Probe {
+    fn gpus(&self) -> impl Iterator<Item = &Node> {
+        self.nodes.iter().filter(|node| node.path != CONTROL_DEVICE)
+    }
+
+    /// The bus ids of the GPUs that can be opened when others cannot, so nvidia-smi is only
+    /// asked for those; none when all can or the bus ids are unknown.
+    pub fn reachable(&self) -> Option<Vec<String>> {
+        if self.gpus().all(|node| node.access == Access::Ok) {
+            return None;
+        }
+        let reachable = self
+            .gpus()
+            .filter(|node| node.access == Access::Ok)
+            .map(|node| node.bus_id.clone())
+            .collect::<Option<Vec<_>>>()?;
+        Some(reachable).filter(|reachable| !reachable.is_empty())
+    }
+
+    /// Why nvidia-smi cannot work here, if no GPU or the control device cannot be opened.
+    pub fn problem(&self) -> Option<String> {
+        let denied = self
+            .nodes
+            .iter()
+            .filter(|node| node.access != Access::Ok)
+            .map(|node| format!("{} {}", node.path, node.access.name()))
+            .collect::<Vec<_>>();
+        let unusable = self.nodes.is_empty()
+            || self.gpus().all(|node| node.access != Access::Ok)
+            || self
+                .nodes
+                .iter()
+                .any(|node| node.path == CONTROL_DEVICE && node.access != Access::Ok);
+        match unusable {
+            true if denied.is_empty() => Some("no NVIDIA device node found".to_string()),
+            true => Some(format!(
+                "NVIDIA device nodes not accessible: {}",
+                denied.join(", ")
+            )),
+            false => None,
+        }
+    }
+}
+
+/// Opens each device node, at most every `PROBE_TTL`, and exports the result as
+/// `nvidia_smi_exporter_device_access`.
+pub fn probe() -> Probe {
+    let mut cached = PROBE.lock().unwrap();
+    if let Some((at, probe)) = &*cached {
+        if at.elapsed() < PROBE_TTL {
+            return probe.clone();
+        }
+    }
+    let probe = Probe {
+        nodes: nodes()
+            .into_iter()
+            .map(|(path, bus_id)| Node {
+                access: access(&path),
+                path,
+                bus_id,
+            })
+            .collect(),
+    };
+    if cached.as_ref().map(|(_, old)| old) != Some(&probe) {
+        if let Some(reachable) = probe.reachable() {
+            info!(
+                "Only GPUs {} are accessible{}, collecting those",
+                reachable.join(", "),
+                environment()
+                    .container
+                    .as_ref()
+                    .map(|c| format!(" in this {} container", c))
+                    .unwrap_or_default()
+            );
+        }
+        DEVICE_ACCESS.reset();
+        for node in &probe.nodes {
+            DEVICE_ACCESS
+                .with_label_values(&[
+                    &node.path,
+                    node.bus_id.as_deref().unwrap_or_default(),
+                    node.access.name(),
+                ])
+                .set(1);
+        }
+    }
+    *cached = Some((Instant::now(), probe.clone()));
+    probe
+}
+
+/// `/dev/nvidiactl` and the node of each GPU the driver has, with its bus id from
+/// `/proc/driver/nvidia/gpus/<bus id>/information`, or else the GPU nodes in `/dev`; under
+/// WSL2 `/dev/dxg` only.
+fn nodes() -> Vec<(String, Option<String>)> {
+    if environment().wsl {
+        return vec![(WSL_DEVICE.to_string(), None)];
+    }
+    let mut nodes = vec![(CONTROL_DEVICE.to_string(), None)];
+    let mut gpus = std::fs::read_dir(DRIVER_GPUS)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let information = std::fs::read_to_string(entry.path().join("information")).ok()?;
+            let minor = information
+                .lines()
+                .find_map(|line| line.strip_prefix("Device Minor:"))?
+                .trim()
+                .parse::<u32>()
+                .ok()?;
+            let bus_id = entry.file_name().to_string_lossy().into_owned();
+            Some((minor, Some(bus_id)))
+        })
+        .collect::<Vec<_>>();
+    if gpus.is_empty() {
+        // LXC 里 /proc/driver 可能看不到，只能列出 /dev 里有的节点
+        gpus = std::fs::read_dir("/dev")
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name();
+                let minor = name.to_str()?.strip_prefix("nvidia")?.parse::<u32>().ok()?;
+                Some((minor, None))
+            })
+            .collect();
+    }
+    gpus.sort();
+    nodes.extend(
+        gpus.into_iter()
+            .map(|(minor, bus_id)| (format!("/dev/nvidia{}", minor), bus_id)),
+    );
+    nodes
+}
+
+fn access(path: &str) -> Access {
+    // 设备 cgroup 只在 open 时检查，access(2) 看不出来
+    let result = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path);
+    match result {
+        Ok(_) => Access::Ok,
+        Err(e) if e.kind() == ErrorKind::NotFound => Access::Missing,
+        Err(e) if e.raw_os_error() == Some(libc::ENXIO) => Access::Missing,
+        Err(_) => Access::Denied,
+    }
+}
@@ -0,0 +1,344 @@
+use anyhow::{bail, Result};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_int_counter_vec, register_int_gauge, register_int_gauge_vec, IntCounterVec, IntGauge,
+    IntGaugeVec,
+};
+use std::collections::VecDeque;
+use std::ffi::{c_void, CStr, CString};
+use std::os::raw::{c_char, c_int, c_uint, c_ulonglong};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+use crate::state;
+
+/// The NVML that nvidia-smi itself uses, installed with the driver.
+const LIBRARY: &str = "libnvidia-ml.so.1";
+/// The event types listened to, by their `nvmlEventType*` bit.
+const EVENT_TYPES: &[(c_ulonglong, &str)] = &[
+    (0x1, "ecc_single_bit"),
+    (0x2, "ecc_double_bit"),
+    (0x8, "xid"),
+    (0x10, "clock"),
+];
+/// Length of `nvidia_recent_event_info`.
+const RECENT_EVENTS: usize = 20;
+/// How long to wait before loading NVML again after it failed.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+const NVML_SUCCESS: c_int = 0;
+const NVML_ERROR_NOT_SUPPORTED: c_int = 3;
+const NVML_ERROR_TIMEOUT: c_int = 10;
+
+lazy_static! {
+    static ref EVENTS: IntCounterVec = register_int_counter_vec!(
+        "nvidia_events_total",
+        "Number of NVML events by type (ecc_single_bit, ecc_double_bit, xid, clock), with --collect.events.",
+        &["gpu", "uuid", "event"]
+    )
+    .unwrap();
+    static ref XID_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "nvidia_xid_errors_total",
+        "Number of XID critical errors by XID, with --collect.events.",
+        &["gpu", "uuid", "xid"]
+    )
+    .unwrap();
+    static ref RECENT: IntGaugeVec = register_int_gauge_vec!(
+        "nvidia_recent_event_info",
+        "The last 20 NVML events, with their data (the XID of xid events) and Unix time.",
+        &["gpu", "uuid", "event", "data", "timestamp"]
+    )
+    .unwrap();
+    static ref UP: IntGauge = register_int_gauge!(
+        "nvidia_smi_exporter_nvml_events_up",
+        "Whether NVML events are being listened to, with --collect.events."
+    )
+    .unwrap();
+}
+
+/// The counters `--state-file` keeps across restarts.
+pub fn counters() -> [state::Counter; 2] {
+    [
+        state::Counter::Int(&EVENTS),
+        state::Counter::Int(&XID_ERRORS),
+    ]
+}
+
+type Device = *mut c_void;
+type EventSet = *mut c_void;
+
+/// `nvmlEventData_t`.
+#[repr(C)]
+struct EventData {
+    device: Device,
+    event_type: c_ulonglong,
+    event_data: c_ulonglong,
+    gpu_instance_id: c_uint,
+    compute_instance_id: c_uint,
+}
+
+/// The NVML functions used, loaded at runtime so that the exporter still starts without the
+/// driver.
+struct Nvml {
+    init: unsafe extern "C" fn() -> c_int,
+    device_count: unsafe extern "C" fn(*mut c_uint) -> c_int,
+    device_handle: unsafe extern "C" fn(c_uint, *mut Device) -> c_int,
+    device_uuid: unsafe extern "C" fn(Device, *mut c_char, c_uint) -> c_int,
+    supported_events: unsafe extern "C" fn(Device, *mut c_ulonglong) -> c_int,
+    event_set_create: unsafe extern "C" fn(*mut EventSet) -> c_int,
+    register_events: unsafe extern "C" fn(Device, c_ulonglong, EventSet) -> c_int,
+    event_set_wait: unsafe extern "C" fn(EventSet, *mut EventData, c_uint) -> c_int,
+    error_string: unsafe extern "C" fn(c_int) -> *const c_char,
+}
+
+impl Nvml {
+    fn load() -> Result<Self> {
+        let name = CString::new(LIBRARY).unwrap();
+        // 不 dlclose，监听线程一直在用
+        let lib = unsafe { libc::dlopen(name.as_ptr(), libc::RTLD_NOW) };
+        if lib.is_null() {
+            let error = unsafe { CStr::from_ptr(libc::dlerror()) };
+            bail!("Failed to load {}: {}", LIBRARY, error.to_string_lossy());
+        }
+        unsafe {
+            Ok(Nvml {
+                init: symbol(lib, "nvmlInit_v2")?,
+                device_count: symbol(lib, "nvmlDeviceGetCount_v2")?,
+                device_handle: symbol(lib, "nvmlDeviceGetHandleByIndex_v2")?,
+                device_uuid: symbol(lib, "nvmlDeviceGetUUID")?,
+                supported_events: symbol(lib, "nvmlDeviceGetSupportedEventTypes")?,
+                event_set_create: symbol(lib, "nvmlEventSetCreate")?,
+                register_events: symbol(lib, "nvmlDeviceRegisterEvents")?,
+                event_set_wait: symbol(lib, "nvmlEventSetWait_v2")?,
+                error_string: symbol(lib, "nvmlErrorString")?,
+            })
+        }
+    }
+
+    fn check(&self, ret: c_int, function: &str) -> Result<()> {
+        if ret == NVML_SUCCESS {
+            return Ok(());
+        }
+        let message = unsafe { CStr::from_ptr((self.error_string)(ret)) };
+        bail!("{} failed: {}", function, message.to_string_lossy());
+    }
+}
+
+/// The function `name` of `lib`, as the function pointer type `T`.
+unsafe fn symbol<T: Copy>(lib: *mut c_void, name: &str) -> Result<T> {
+    let cname = CString::new(name).unwrap();
+    let ptr = libc::dlsym(lib, cname.as_ptr());
+    if ptr.is_null() {
+        bail!("{} has no {}", LIBRARY, name);
+    }
+    Ok(std::mem::transmute_copy(&ptr))
+}
+
+/// `--collect.events`: listens to NVML events on a thread of its own, as they happen rather
+/// than at scrapes, and loads NVML again a minute after it failed, e.g. while the driver is
+/// reloaded.
+pub fn start() {
+    lazy_static::initialize(&RECENT);
+    lazy_static::initialize(&UP);
+    let spawned = std::thread::Builder::new()
+        .name("nvml-events".to_string())
+        .spawn(|| loop {
+            if let Err(e) = listen() {
+                warn!("Not listening to NVML events, {:#}", e);
+            }
+            UP.set(0);
+            std::thread::sleep(RETRY_INTERVAL);
+        });
+    if let Err(e) = spawned {
+        warn!("Failed to start listening to NVML events, {}", e);
+    }
+}
+
+/// Registers the events of every GPU supporting them and counts them until NVML fails.
+fn listen() -> Result<()> {
+    let nvml = Nvml::load()?;
+    unsafe {
+        nvml.check((nvml.init)(), "nvmlInit")?;
+        let mut count = 0;
+        nvml.check((nvml.device_count)(&mut count), "nvmlDeviceGetCount")?;
+        let mut set: EventSet = std::ptr::null_mut();
+        nvml.check((nvml.event_set_create)(&mut set), "nvmlEventSetCreate")?;
+
+        // 事件里只有设备句柄，记下它对应的序号和 UUID
+        let mut gpus = Vec::new();
+        for index in 0..count {
+            let mut device: Device = std::ptr::null_mut();
+            nvml.check(
+                (nvml.device_handle)(index, &mut device),
+                "nvmlDeviceGetHandleByIndex",
+            )?;
+            let mut uuid = [0 as c_char; 96];
+            nvml.check(
+                (nvml.device_uuid)(device, uuid.as_mut_ptr(), uuid.len() as c_uint),
+                "nvmlDeviceGetUUID",
+            )?;
+            let uuid = CStr::from_ptr(uuid.as_ptr()).to_string_lossy().into_owned();
+            let gpu = index.to_string();
+
+            let mut supported = 0;
+            let ret = (nvml.supported_events)(device, &mut supported);
+            // 消费级显卡等不支持事件
+            if ret == NVML_ERROR_NOT_SUPPORTED {
+                debug!("GPU {} does not support NVML events", gpu);
+                continue;
+            }
+            nvml.check(ret, "nvmlDeviceGetSupportedEventTypes")?;
+            let wanted = EVENT_TYPES
+                .iter()
+                .filter(|(bit, _)| supported & bit != 0)
+                .collect::<Vec<_>>();
+            if wanted.is_empty() {
+                continue;
+            }
+            let mask = wanted.iter().fold(0, |mask, (bit, _)| mask | bit);
+            let ret = (nvml.register_events)(device, mask, set);
+            if ret == NVML_ERROR_NOT_SUPPORTED {
+                debug!("GPU {} does not support NVML events", gpu);
+                continue;
+            }
+            nvml.check(ret, "nvmlDeviceRegisterEvents")?;
+            for (_, event) in &wanted {
+                EVENTS.with_label_values(&[&gpu, &uuid, event]);
+            }
+            gpus.push((device, gpu, uuid));
+        }
+        info!("Listening to NVML events of {} GPUs", gpus.len());
+        UP.set(1);
+
+        let mut recent = VecDeque::new();
+        loop {
+            let mut data = EventData {
+                device: std::ptr::null_mut(),
+                event_type: 0,
+                event_data: 0,
+                gpu_instance_id: 0,
+                compute_instance_id: 0,
+            };
+            let ret = (nvml.event_set_wait)(set, &mut data, 10_000);
+            if ret == NVML_ERROR_TIMEOUT {
+                continue;
+            }
+            nvml.check(ret, "nvmlEventSetWait")?;
+            let (gpu, uuid) = match gpus.iter().find(|(device, _, _)| *device == data.device) {
+                Some((_, gpu, uuid)) => (gpu.as_str(), uuid.as_str()),
+                None => ("", ""),
+            };
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            record(&mut recent, gpu, uuid, &data, timestamp);
+        }
+    }
+}
+
+/// Counts an event of the GPU `gpu` and keeps it in `recent`, the labels of
+/// `nvidia_recent_event_info` oldest first.
+fn record(
+    recent: &mut VecDeque<[String; 5]>,
+    gpu: &str,
+    uuid: &str,
+    data: &EventData,
+    timestamp: u64,
+) {
+    let event = match EVENT_TYPES
+        .iter()
+        .find(|(bit, _)| data.event_type & bit != 0)
+    {
+        Some((_, event)) => *event,
+        None => return,
+    };
+    EVENTS.with_label_values(&[gpu, uuid, event]).inc();
+    let value = data.event_data.to_string();
+    if event == "xid" {
+        XID_ERRORS.with_label_values(&[gpu, uuid, &value]).inc();
+        warn!("XID {} on GPU {} ({})", value, gpu, uuid);
+    } else {
+        debug!("NVML {} event on GPU {}, data {}", event, gpu, value);
+    }
+
+    let labels = [
+        gpu.to_string(),
+        uuid.to_string(),
+        event.to_string(),
+        value,
+        timestamp.to_string(),
+    ];
+    // 同一秒内相同的事件只占一条序列
+    if recent.contains(&labels) {
+        return;
+    }
+    RECENT
+        .with_label_values(&labels.each_ref().map(String::as_str))
+        .set(1);
+    recent.push_back(labels);
+    if recent.len() > RECENT_EVENTS {
+        let oldest = recent.pop_front().unwrap();
+        let _ = RECENT.remove_label_values(&oldest.each_ref().map(String::as_str));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::core::Collector;
+
+    fn event(event_type: c_ulonglong, event_data: c_ulonglong) -> EventData {
+        EventData {
+            device: std::ptr::null_mut(),
+            event_type,
+            event_data,
+            gpu_instance_id: 0,
+            compute_instance_id: 0,
+        }
+    }
+
+    #[test]
+    fn records_events() {
+        let uuid = "GPU-events";
+        let mut recent = VecDeque::new();
+        record(&mut recent, "0", uuid, &event(0x8, 79), 1700000000);
+        // 同一秒内重复的 XID 照样计数，但只占一条最近事件
+        record(&mut recent, "0", uuid, &event(0x8, 79), 1700000000);
+        record(&mut recent, "0", uuid, &event(0x2, 0), 1700000001);
+        record(&mut recent, "0", uuid, &event(0x400, 0), 1700000002);
+        assert_eq!(EVENTS.with_label_values(&["0", uuid, "xid"]).get(), 2);
+        assert_eq!(
+            EVENTS
+                .with_label_values(&["0", uuid, "ecc_double_bit"])
+                .get(),
+            1
+        );
+        assert_eq!(XID_ERRORS.with_label_values(&["0", uuid, "79"]).get(), 2);
+        assert_eq!(
+            recent
+                .iter()
+                .map(|labels| labels[2].as_str())
+                .collect::<Vec<_>>(),
+            ["xid", "ecc_double_bit"]
+        );
+        assert_eq!(
+            RECENT
+                .with_label_values(&["0", uuid, "xid", "79", "1700000000"])
+                .get(),
+            1
+        );
+
+        for second in 0..RECENT_EVENTS as u64 {
+            record(&mut recent, "0", uuid, &event(0x10, 0), 1700000010 + second);
+        }
+        assert_eq!(recent.len(), RECENT_EVENTS);
+        assert_eq!(recent[0][2], "clock");
+        let series = RECENT.collect()[0]
+            .get_metric()
+            .iter()
+            .filter(|metric| metric.get_label().iter().any(|l| l.get_value() == uuid))
+            .count();
+        assert_eq!(series, RECENT_EVENTS);
+    }
+}
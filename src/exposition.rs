@@ -0,0 +1,58 @@
+/// Appends one line of the text format to `buffer`, without allocating once it has room.
+pub fn sample(buffer: &mut String, metric: &str, labels: &[(String, String)], value: f64) {
+    buffer.push_str(metric);
+    label_set(buffer, labels);
+    end_sample(buffer, value);
+}
+
+/// `{name="value", …}`, or nothing without labels.
+pub fn label_set(buffer: &mut String, labels: &[(String, String)]) {
+    for (i, (name, value)) in labels.iter().enumerate() {
+        buffer.push_str(if i == 0 { "{" } else { ", " });
+        buffer.push_str(name);
+        buffer.push_str("=\"");
+        escape(buffer, value);
+        buffer.push('"');
+    }
+    if !labels.is_empty() {
+        buffer.push('}');
+    }
+}
+
+/// The value after the metric and its label set, ending the line.
+pub fn end_sample(buffer: &mut String, value: f64) {
+    buffer.push(' ');
+    push_value(buffer, value);
+    buffer.push('\n');
+}
+
+fn escape(buffer: &mut String, value: &str) {
+    let mut rest = value;
+    while let Some(i) = rest.find(['\\', '"', '\n']) {
+        buffer.push_str(&rest[..i]);
+        buffer.push_str(match rest.as_bytes()[i] {
+            b'\\' => "\\\\",
+            b'"' => "\\\"",
+            _ => "\\n",
+        });
+        rest = &rest[i + 1..];
+    }
+    buffer.push_str(rest);
+}
+
+/// Whole numbers, most readings, as integers like `{}` prints them; others in the shortest form
+/// that reads back the same.
+fn push_value(buffer: &mut String, value: f64) {
+    // 2^53 以内的整数可以精确转换
+    if value.fract() == 0.0 && value.abs() < 9007199254740992.0 {
+        buffer.push_str(itoa::Buffer::new().format(value as i64));
+    } else if value.is_finite() {
+        buffer.push_str(ryu::Buffer::new().format_finite(value));
+    } else if value.is_nan() {
+        buffer.push_str("NaN");
+    } else if value > 0.0 {
+        buffer.push_str("+Inf");
+    } else {
+        buffer.push_str("-Inf");
+    }
+}
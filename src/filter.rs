@@ -0,0 +1,213 @@
+use anyhow::{bail, Context, Result};
+use regex::{Regex, RegexSet};
+use serde::Serialize;
+use std::str::FromStr;
+
+use crate::gpu::Gpu;
+
+/// One `--gpu-include`/`--gpu-exclude` value: an index, a UUID or a glob over the GPU name.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum GpuMatcher {
+    Index(u32),
+    Uuid(String),
+    /// `*` matches any run of characters, `?` any single one.
+    Name(String),
+}
+
+impl FromStr for GpuMatcher {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let value = value.trim();
+        if value.is_empty() {
+            bail!("Empty GPU selector");
+        }
+        Ok(if let Ok(index) = value.parse() {
+            GpuMatcher::Index(index)
+        } else if value.starts_with("GPU-") || value.starts_with("MIG-") {
+            GpuMatcher::Uuid(value.to_string())
+        } else {
+            GpuMatcher::Name(value.to_string())
+        })
+    }
+}
+
+impl GpuMatcher {
+    pub fn matches(&self, gpu: &Gpu) -> bool {
+        match self {
+            GpuMatcher::Index(index) => gpu.index == index.to_string(),
+            GpuMatcher::Uuid(uuid) => gpu.uuid.eq_ignore_ascii_case(uuid),
+            GpuMatcher::Name(pattern) => glob(pattern.as_bytes(), gpu.name.as_bytes()),
+        }
+    }
+}
+
+/// GPUs to export: those matching any include (all when there is none) and no exclude.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct GpuFilter {
+    pub include: Vec<GpuMatcher>,
+    pub exclude: Vec<GpuMatcher>,
+    /// The GPUs of the tenant scraping, if any.
+    #[serde(skip)]
+    pub tenant: Option<Vec<GpuMatcher>>,
+}
+
+impl GpuFilter {
+    pub fn allows(&self, gpu: &Gpu) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|m| m.matches(gpu)))
+            && !self.exclude.iter().any(|m| m.matches(gpu))
+            && self
+                .tenant
+                .as_ref()
+                .is_none_or(|gpus| gpus.iter().any(|m| m.matches(gpu)))
+    }
+}
+
+fn glob(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob(&pattern[1..], name) || (!name.is_empty() && glob(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => glob(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Metric names matching any `--metric-include` (all when there is none) and no
+/// `--metric-exclude`; the regexes are anchored at both ends.
+#[derive(Clone, Debug, Serialize)]
+pub struct MetricFilter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    #[serde(skip)]
+    include_set: RegexSet,
+    #[serde(skip)]
+    exclude_set: RegexSet,
+    /// The metrics of the tenant scraping, if it has a list.
+    #[serde(skip)]
+    pub tenant: Option<RegexSet>,
+}
+
+impl MetricFilter {
+    pub fn new(include: &[Regex], exclude: &[Regex]) -> Result<Self> {
+        let patterns =
+            |regexes: &[Regex]| regexes.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+        let set = |patterns: &[String]| {
+            RegexSet::new(patterns.iter().map(|p| format!("^(?:{})$", p)))
+                .with_context(|| "Invalid --metric-include/--metric-exclude")
+        };
+        let (include, exclude) = (patterns(include), patterns(exclude));
+        Ok(MetricFilter {
+            include_set: set(&include)?,
+            exclude_set: set(&exclude)?,
+            include,
+            exclude,
+            tenant: None,
+        })
+    }
+
+    pub fn allows(&self, metric: &str) -> bool {
+        (self.include.is_empty() || self.include_set.is_match(metric))
+            && !self.exclude_set.is_match(metric)
+            && self.tenant.as_ref().is_none_or(|set| set.is_match(metric))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gpu(index: &str, uuid: &str, name: &str) -> Gpu {
+        Gpu {
+            index: index.to_string(),
+            uuid: uuid.to_string(),
+            name: name.to_string(),
+            driver_version: "550.54.15".to_string(),
+            values: Vec::new(),
+            host: None,
+        }
+    }
+
+    #[test]
+    fn parses_matchers() {
+        assert_eq!("3".parse::<GpuMatcher>().unwrap(), GpuMatcher::Index(3));
+        assert_eq!(
+            " GPU-1a2b ".parse::<GpuMatcher>().unwrap(),
+            GpuMatcher::Uuid("GPU-1a2b".to_string())
+        );
+        assert_eq!(
+            "MIG-3c4d".parse::<GpuMatcher>().unwrap(),
+            GpuMatcher::Uuid("MIG-3c4d".to_string())
+        );
+        assert_eq!(
+            "*A100*".parse::<GpuMatcher>().unwrap(),
+            GpuMatcher::Name("*A100*".to_string())
+        );
+        assert!("".parse::<GpuMatcher>().is_err());
+        assert!("  ".parse::<GpuMatcher>().is_err());
+    }
+
+    #[test]
+    fn matches_index_uuid_and_name() {
+        let a100 = gpu("1", "GPU-AbCd", "NVIDIA A100-SXM4-80GB");
+        assert!(GpuMatcher::Index(1).matches(&a100));
+        assert!(!GpuMatcher::Index(0).matches(&a100));
+        assert!(GpuMatcher::Uuid("gpu-abcd".to_string()).matches(&a100));
+        assert!(GpuMatcher::Name("*A100*".to_string()).matches(&a100));
+        assert!(GpuMatcher::Name("NVIDIA A?00-SXM4-80GB".to_string()).matches(&a100));
+        assert!(!GpuMatcher::Name("A100*".to_string()).matches(&a100));
+        assert!(!GpuMatcher::Name("*H100*".to_string()).matches(&a100));
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob(b"", b""));
+        assert!(glob(b"*", b""));
+        assert!(glob(b"a*c", b"abbbc"));
+        assert!(!glob(b"a*c", b"abbb"));
+        assert!(!glob(b"?", b""));
+        assert!(!glob(b"", b"a"));
+    }
+
+    #[test]
+    fn filters_gpus() {
+        let gpus = [
+            gpu("0", "GPU-0", "Tesla T4"),
+            gpu("1", "GPU-1", "NVIDIA A100-SXM4-80GB"),
+            gpu("2", "GPU-2", "NVIDIA A100-SXM4-80GB"),
+        ];
+        let allowed = |filter: &GpuFilter| {
+            gpus.iter()
+                .filter(|g| filter.allows(g))
+                .map(|g| g.index.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(allowed(&GpuFilter::default()), ["0", "1", "2"]);
+        let mut filter = GpuFilter {
+            include: vec!["*A100*".parse().unwrap()],
+            exclude: vec!["GPU-2".parse().unwrap()],
+            tenant: None,
+        };
+        assert_eq!(allowed(&filter), ["1"]);
+        filter.include.clear();
+        filter.tenant = Some(vec!["0".parse().unwrap(), "2".parse().unwrap()]);
+        assert_eq!(allowed(&filter), ["0"]);
+    }
+
+    #[test]
+    fn filters_metrics() {
+        let regex = |r: &str| Regex::new(r).unwrap();
+        let filter =
+            MetricFilter::new(&[regex("nvidia_clocks_.*")], &[regex(".*_max_.*")]).unwrap();
+        assert!(filter.allows("nvidia_clocks_gr"));
+        assert!(!filter.allows("nvidia_clocks_max_gr"));
+        // 两端锚定
+        assert!(!filter.allows("x_nvidia_clocks_gr"));
+        assert!(!filter.allows("nvidia_temperature_gpu"));
+        let all = MetricFilter::new(&[], &[]).unwrap();
+        assert!(all.allows("nvidia_temperature_gpu"));
+    }
+}
@@ -0,0 +1,279 @@
+use anyhow::{bail, Context, Result};
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::TcpStream;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cli::Cli;
+use crate::push::{Batch, Target};
+
+/// A Fluentd or Fluent Bit `forward` input (`--push.fluentd.address`), sent a record per GPU in
+/// Forward mode: `[tag, [[time, record], ...], option]` in MessagePack.
+pub struct Fluentd {
+    address: String,
+    tag: String,
+    require_ack: bool,
+    /// `job` and `instance`.
+    labels: Vec<(String, String)>,
+}
+
+impl Fluentd {
+    pub fn new(address: &str, cli: &Cli, labels: Vec<(String, String)>) -> Self {
+        Fluentd {
+            address: address.to_string(),
+            tag: cli.fluentd_tag.clone(),
+            require_ack: cli.fluentd_require_ack,
+            labels,
+        }
+    }
+
+    /// The Forward mode message and its chunk id, if an acknowledgement is requested.
+    fn message(&self, batch: &Batch) -> Result<Option<(Vec<u8>, String)>> {
+        let time = batch.timestamp.duration_since(UNIX_EPOCH)?;
+        let gpus = batch.gpus();
+        if gpus.is_empty() {
+            return Ok(None);
+        }
+        let mut buf = Vec::new();
+        put_array_len(&mut buf, 3);
+        put_str(&mut buf, &self.tag);
+        put_array_len(&mut buf, gpus.len());
+        for gpu in &gpus {
+            put_array_len(&mut buf, 2);
+            // EventTime：扩展类型 0，秒和纳秒各 32 位
+            buf.extend_from_slice(&[0xd7, 0x00]);
+            buf.extend_from_slice(&(time.as_secs() as u32).to_be_bytes());
+            buf.extend_from_slice(&time.subsec_nanos().to_be_bytes());
+            let host = gpu.host.is_some() as usize;
+            put_map_len(&mut buf, self.labels.len() + 2 + host + gpu.metrics.len());
+            for (name, value) in &self.labels {
+                put_str(&mut buf, name);
+                put_str(&mut buf, value);
+            }
+            put_str(&mut buf, "gpu");
+            put_str(&mut buf, gpu.index);
+            put_str(&mut buf, "name");
+            put_str(&mut buf, gpu.name);
+            if let Some(host) = gpu.host {
+                put_str(&mut buf, "host");
+                put_str(&mut buf, host);
+            }
+            for (name, value) in &gpu.metrics {
+                put_str(&mut buf, name);
+                buf.push(0xcb);
+                buf.extend_from_slice(&value.as_f64().unwrap_or_default().to_be_bytes());
+            }
+        }
+        let chunk = chunk_id(batch.timestamp);
+        match self.require_ack {
+            true => {
+                put_map_len(&mut buf, 2);
+                put_str(&mut buf, "chunk");
+                put_str(&mut buf, &chunk);
+            }
+            false => put_map_len(&mut buf, 1),
+        }
+        put_str(&mut buf, "size");
+        buf.push(0xce);
+        buf.extend_from_slice(&(gpus.len() as u32).to_be_bytes());
+        Ok(Some((buf, chunk)))
+    }
+}
+
+#[tide::utils::async_trait]
+impl Target for Fluentd {
+    fn name(&self) -> &'static str {
+        "fluentd"
+    }
+
+    async fn push(&self, batch: &Batch) -> Result<()> {
+        let (message, chunk) = match self.message(batch)? {
+            Some(message) => message,
+            None => return Ok(()),
+        };
+        let mut stream = TcpStream::connect(&self.address)
+            .await
+            .with_context(|| format!("Failed to connect to {}", self.address))?;
+        stream.write_all(&message).await?;
+        stream.flush().await?;
+        if self.require_ack {
+            // 应答是 {"ack": chunk}
+            let mut byte = [0u8; 1];
+            stream
+                .read_exact(&mut byte)
+                .await
+                .context("No acknowledgement")?;
+            if byte[0] != 0x81 || read_str(&mut stream).await? != "ack" {
+                bail!("Unexpected response from {}", self.address);
+            }
+            let ack = read_str(&mut stream).await?;
+            if ack != chunk {
+                bail!("Acknowledgement for chunk {} instead of {}", ack, chunk);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Unique per push; the protocol asks for the base64 of 128 random bits, but any string works.
+fn chunk_id(timestamp: SystemTime) -> String {
+    let nanos = timestamp
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    format!("{:x}{:x}", std::process::id(), nanos)
+}
+
+fn put_array_len(buf: &mut Vec<u8>, len: usize) {
+    match len {
+        0..=15 => buf.push(0x90 | len as u8),
+        16..=0xffff => {
+            buf.push(0xdc);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        _ => {
+            buf.push(0xdd);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+}
+
+fn put_map_len(buf: &mut Vec<u8>, len: usize) {
+    match len {
+        0..=15 => buf.push(0x80 | len as u8),
+        16..=0xffff => {
+            buf.push(0xde);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        _ => {
+            buf.push(0xdf);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    let len = s.len();
+    match len {
+        0..=31 => buf.push(0xa0 | len as u8),
+        32..=0xff => buf.extend_from_slice(&[0xd9, len as u8]),
+        0x100..=0xffff => {
+            buf.push(0xda);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        _ => {
+            buf.push(0xdb);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// A MessagePack string of up to 255 bytes.
+async fn read_str<S: ReadExt + Unpin>(stream: &mut S) -> Result<String> {
+    let mut byte = [0u8; 1];
+    stream.read_exact(&mut byte).await?;
+    let len = match byte[0] {
+        b @ 0xa0..=0xbf => (b & 0x1f) as usize,
+        0xd9 => {
+            stream.read_exact(&mut byte).await?;
+            byte[0] as usize
+        }
+        _ => bail!("Expected a string in the acknowledgement"),
+    };
+    let mut s = vec![0u8; len];
+    stream.read_exact(&mut s).await?;
+    Ok(String::from_utf8_lossy(&s).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::push::Sample;
+    use async_std::net::TcpListener;
+    use std::time::Duration;
+
+    fn fluentd(address: &str, require_ack: bool) -> Fluentd {
+        Fluentd {
+            address: address.to_string(),
+            tag: "nvidia".to_string(),
+            require_ack,
+            labels: vec![("job".to_string(), "nvidia".to_string())],
+        }
+    }
+
+    fn batch() -> Batch {
+        let labels = [("gpu", "0"), ("name", "A100")]
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect();
+        Batch {
+            exposition: String::new(),
+            samples: vec![Sample {
+                name: "nvidia_temperature_gpu".to_string(),
+                labels,
+                value: 45.0,
+            }],
+            timestamp: UNIX_EPOCH + Duration::from_millis(1_700_000_000_500),
+        }
+    }
+
+    /// `[tag, [[EventTime, {job, gpu, name, nvidia_temperature_gpu}]], `.
+    const ENTRIES: &[u8] = b"\x93\xa6nvidia\x91\x92\
+        \xd7\x00\x65\x53\xf1\x00\x1d\xcd\x65\x00\
+        \x84\xa3job\xa6nvidia\xa3gpu\xa10\xa4name\xa4A100\
+        \xb6nvidia_temperature_gpu\xcb\x40\x46\x80\x00\x00\x00\x00\x00";
+
+    #[test]
+    fn encodes_forward_message() {
+        let (message, _) = fluentd("", false).message(&batch()).unwrap().unwrap();
+        let mut expected = ENTRIES.to_vec();
+        expected.extend_from_slice(b"\x81\xa4size\xce\x00\x00\x00\x01");
+        assert_eq!(message, expected);
+        let empty = Batch {
+            samples: Vec::new(),
+            ..batch()
+        };
+        assert!(fluentd("", false).message(&empty).unwrap().is_none());
+    }
+
+    #[test]
+    fn encodes_lengths() {
+        let mut buf = Vec::new();
+        put_str(&mut buf, &"x".repeat(31));
+        assert_eq!(buf[0], 0xbf);
+        for (len, header) in [(32, &[0xd9, 0x20][..]), (256, &[0xda, 0x01, 0x00])] {
+            let mut buf = Vec::new();
+            put_str(&mut buf, &"x".repeat(len));
+            assert_eq!(&buf[..header.len()], header);
+            assert_eq!(buf.len(), header.len() + len);
+        }
+        let mut buf = Vec::new();
+        put_array_len(&mut buf, 15);
+        put_array_len(&mut buf, 16);
+        put_map_len(&mut buf, 0x10000);
+        assert_eq!(buf, [0x9f, 0xdc, 0x00, 0x10, 0xdf, 0x00, 0x01, 0x00, 0x00]);
+    }
+
+    #[async_std::test]
+    async fn waits_for_acknowledgement() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let fluentd = fluentd(&listener.local_addr().unwrap().to_string(), true);
+        let chunk = chunk_id(batch().timestamp);
+        let mut expected = ENTRIES.to_vec();
+        expected.extend_from_slice(&[0x82, 0xa5]);
+        expected.extend_from_slice(b"chunk");
+        put_str(&mut expected, &chunk);
+        expected.extend_from_slice(b"\xa4size\xce\x00\x00\x00\x01");
+        let server = async_std::task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut message = vec![0u8; expected.len()];
+            stream.read_exact(&mut message).await.unwrap();
+            assert_eq!(message, expected);
+            let mut ack = vec![0x81, 0xa3];
+            ack.extend_from_slice(b"ack");
+            put_str(&mut ack, &chunk);
+            stream.write_all(&ack).await.unwrap();
+        });
+        fluentd.push(&batch()).await.unwrap();
+        server.await;
+    }
+}
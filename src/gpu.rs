@@ -0,0 +1,82 @@
+use anyhow::{anyhow, bail, Result};
+use tracing::debug;
+
+/// One GPU row of `nvidia-smi --query-gpu`.
+#[derive(Clone, Debug)]
+pub struct Gpu {
+    pub index: String,
+    pub uuid: String,
+    pub name: String,
+    pub driver_version: String,
+    /// Metric name and value, without the fields nvidia-smi reports as unavailable.
+    pub values: Vec<(String, f64)>,
+    /// The `--ssh.host` the GPU is on, if collected over SSH.
+    pub host: Option<String>,
+}
+
+impl Gpu {
+    pub fn value(&self, metric: &str) -> Option<f64> {
+        self.values
+            .iter()
+            .find(|(name, _)| *name == metric)
+            .map(|(_, value)| *value)
+    }
+}
+
+/// The arguments of `nvidia-smi` for `fields` of `devices` (indexes, UUIDs or PCI bus ids), or
+/// of every GPU if none, as `parse_csv` reads them.
+pub fn query_args(fields: &[(&str, &str)], devices: &[String]) -> Vec<String> {
+    let mut query = String::from("--query-gpu=name,index,uuid,driver_version");
+    for (field, _) in fields {
+        query.push(',');
+        query.push_str(field);
+    }
+    let mut args = vec![query, "--format=csv,noheader,nounits".to_string()];
+    if !devices.is_empty() {
+        args.push(format!("--id={}", devices.join(",")));
+    }
+    args
+}
+
+/// The rows of `nvidia-smi --query-gpu=name,index,uuid,driver_version,<fields>
+/// --format=csv,noheader,nounits`.
+pub fn parse_csv(stdout: &[u8], fields: &[(&str, &str)]) -> Result<Vec<Gpu>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .trim(csv::Trim::All)
+        .from_reader(stdout);
+    let mut gpus = Vec::new();
+    for result in rdr.records() {
+        let record = result?;
+        debug!("{:?}", record);
+        if record.len() != fields.len() + 4 {
+            bail!(
+                "Expected {} fields, got {}: {:?}",
+                fields.len() + 4,
+                record.len(),
+                record
+            );
+        }
+        let mut values = Vec::new();
+        for ((_, metric), value) in fields.iter().zip(record.iter().skip(4)) {
+            // [N/A] / [Not Supported] 之类的值直接跳过
+            if value.starts_with('[') {
+                continue;
+            }
+            let value: f64 = value
+                .parse()
+                .map_err(|_| anyhow!("Invalid value {:?} for {}", value, metric))?;
+            values.push((metric.to_string(), value));
+        }
+        gpus.push(Gpu {
+            name: record[0].to_string(),
+            index: record[1].to_string(),
+            uuid: record[2].to_string(),
+            driver_version: record[3].to_string(),
+            values,
+            host: None,
+        });
+    }
+
+    Ok(gpus)
+}
@@ -0,0 +1,122 @@
+use anyhow::{Context, Result};
+use async_std::io::WriteExt;
+use async_std::net::TcpStream;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::cli::Cli;
+use crate::push::{self, Batch, Sample, Target};
+
+#[derive(Clone, Copy, Debug, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// A carbon plaintext receiver (`--push.graphite.address`).
+pub struct Graphite {
+    address: String,
+    protocol: Protocol,
+    prefix: String,
+    tagged: bool,
+    /// `job` and `instance`.
+    labels: Vec<(String, String)>,
+    interval: Option<Duration>,
+}
+
+impl Graphite {
+    pub fn new(address: &str, cli: &Cli, labels: Vec<(String, String)>) -> Self {
+        Graphite {
+            address: address.to_string(),
+            protocol: cli.graphite_protocol,
+            prefix: cli.graphite_prefix.trim_end_matches('.').to_string(),
+            tagged: cli.graphite_tagged,
+            labels,
+            interval: cli.graphite_interval.map(Duration::from_secs),
+        }
+    }
+
+    /// `<prefix>.<instance>.<metric>.<label>.<value>...`, or with `--push.graphite.tagged`
+    /// `<prefix>.<metric>;<label>=<value>...` including `job` and `instance`.
+    fn path(&self, sample: &Sample) -> String {
+        let mut path = self.prefix.clone();
+        let mut push = |component: &str| {
+            if !path.is_empty() {
+                path.push('.');
+            }
+            path.push_str(component);
+        };
+        if self.tagged {
+            push(&sanitize(&sample.name));
+            let mut tags = sample.labels.iter().chain(&self.labels).collect::<Vec<_>>();
+            tags.sort_by(|a, b| a.0.cmp(&b.0));
+            tags.dedup_by(|a, b| a.0 == b.0);
+            for (name, value) in tags.into_iter().filter(|(_, value)| !value.is_empty()) {
+                path.push_str(&format!(";{}={}", sanitize(name), sanitize(value)));
+            }
+        } else {
+            let instance = self.labels.iter().find(|(name, _)| name == "instance");
+            push(&sanitize(instance.map_or("", |(_, value)| value.as_str())));
+            push(&sanitize(&sample.name));
+            let mut labels = sample.labels.iter().collect::<Vec<_>>();
+            labels.sort();
+            for (name, value) in labels {
+                push(&sanitize(name));
+                push(&sanitize(value));
+            }
+        }
+        path
+    }
+
+    fn lines(&self, batch: &Batch) -> Result<Vec<String>> {
+        let timestamp = batch.timestamp.duration_since(UNIX_EPOCH)?.as_secs();
+        Ok(batch
+            .samples
+            .iter()
+            .filter(|sample| sample.value.is_finite())
+            .map(|sample| format!("{} {} {}\n", self.path(sample), sample.value, timestamp))
+            .collect())
+    }
+}
+
+/// Graphite paths only keep letters, digits, `_` and `-`; dots would add levels.
+pub fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[tide::utils::async_trait]
+impl Target for Graphite {
+    fn name(&self) -> &'static str {
+        "graphite"
+    }
+
+    fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    async fn push(&self, batch: &Batch) -> Result<()> {
+        let lines = self.lines(batch)?;
+        match self.protocol {
+            // 每次推送新建连接，carbon 重启后不用处理断开的旧连接
+            Protocol::Tcp => {
+                let mut stream = TcpStream::connect(&self.address)
+                    .await
+                    .with_context(|| format!("Failed to connect to {}", self.address))?;
+                stream.write_all(lines.concat().as_bytes()).await?;
+                stream.flush().await?;
+            }
+            Protocol::Udp => push::send_datagrams(&self.address, &lines).await?,
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,550 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_std::future::timeout;
+use async_std::io::{Read, ReadExt, Write, WriteExt};
+use async_std::os::unix::net::UnixStream;
+use std::convert::TryInto;
+use std::time::Duration;
+
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+// HTTP/2 frame types and flags
+pub const DATA: u8 = 0x0;
+pub const HEADERS: u8 = 0x1;
+pub const RST_STREAM: u8 = 0x3;
+pub const SETTINGS: u8 = 0x4;
+pub const PING: u8 = 0x6;
+pub const GOAWAY: u8 = 0x7;
+pub const WINDOW_UPDATE: u8 = 0x8;
+pub const END_STREAM: u8 = 0x1;
+pub const ACK: u8 = 0x1;
+pub const END_HEADERS: u8 = 0x4;
+pub const PADDED: u8 = 0x8;
+pub const MAX_WINDOW: u32 = 0x7fff_ffff;
+/// SETTINGS_INITIAL_WINDOW_SIZE
+const INITIAL_WINDOW_SIZE: u16 = 0x4;
+/// The initial flow-control window and the largest frame, until the peer's SETTINGS say more.
+const DEFAULT_WINDOW: i64 = 65535;
+pub const MAX_FRAME: usize = 16384;
+
+/// Calls the unary gRPC `method`, e.g. `/v1.PodResourcesLister/List`, over HTTP/2 without TLS on
+/// the unix socket `socket`, returning the response message.
+pub async fn call(socket: &str, method: &str, message: &[u8]) -> Result<Vec<u8>> {
+    let mut stream = UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("Failed to connect to {}", socket))?;
+    let mut request = PREFACE.to_vec();
+    // 把流和连接的接收窗口都开到最大，响应再大也不必发 WINDOW_UPDATE
+    let mut settings = Vec::new();
+    settings.extend_from_slice(&0x2u16.to_be_bytes()); // ENABLE_PUSH
+    settings.extend_from_slice(&0u32.to_be_bytes());
+    settings.extend_from_slice(&0x4u16.to_be_bytes()); // INITIAL_WINDOW_SIZE
+    settings.extend_from_slice(&MAX_WINDOW.to_be_bytes());
+    put_frame(&mut request, SETTINGS, 0, 0, &settings);
+    put_frame(
+        &mut request,
+        WINDOW_UPDATE,
+        0,
+        0,
+        &(MAX_WINDOW - 65535).to_be_bytes(),
+    );
+    let mut headers = Vec::new();
+    for (name, value) in [
+        (":method", "POST"),
+        (":scheme", "http"),
+        (":path", method),
+        (":authority", "localhost"),
+        ("content-type", "application/grpc"),
+        ("te", "trailers"),
+    ] {
+        put_header(&mut headers, name, value);
+    }
+    put_frame(&mut request, HEADERS, END_HEADERS, 1, &headers);
+    // gRPC 消息：未压缩，4 字节长度；请求不超过默认的 16 KiB 帧大小
+    let mut data = vec![0];
+    data.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    data.extend_from_slice(message);
+    put_frame(&mut request, DATA, END_STREAM, 1, &data);
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+
+    let mut body = Vec::new();
+    loop {
+        let mut header = [0u8; 9];
+        stream.read_exact(&mut header).await?;
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let (frame_type, flags) = (header[3], header[4]);
+        let stream_id = u32::from_be_bytes(header[5..].try_into().unwrap()) & MAX_WINDOW;
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).await?;
+        match frame_type {
+            SETTINGS if flags & ACK == 0 => {
+                let mut ack = Vec::new();
+                put_frame(&mut ack, SETTINGS, ACK, 0, &[]);
+                stream.write_all(&ack).await?;
+            }
+            PING if flags & ACK == 0 => {
+                let mut ack = Vec::new();
+                put_frame(&mut ack, PING, ACK, 0, &payload);
+                stream.write_all(&ack).await?;
+            }
+            GOAWAY => bail!("The server closed the connection"),
+            RST_STREAM if stream_id == 1 => bail!("The server reset the request"),
+            DATA if stream_id == 1 => {
+                let data = match flags & PADDED {
+                    0 => &payload[..],
+                    _ => {
+                        let pad = *payload.first().unwrap_or(&0) as usize;
+                        payload
+                            .get(1..len.saturating_sub(pad))
+                            .ok_or_else(|| anyhow!("Invalid padding"))?
+                    }
+                };
+                body.extend_from_slice(data);
+            }
+            _ => {}
+        }
+        if stream_id == 1 && matches!(frame_type, DATA | HEADERS) && flags & END_STREAM != 0 {
+            break;
+        }
+    }
+    // 出错时只有 trailers（grpc-status），没有消息
+    if body.len() < 5 {
+        bail!("No response message");
+    }
+    if body[0] != 0 {
+        bail!("Compressed responses are not supported");
+    }
+    let len = u32::from_be_bytes(body[1..5].try_into().unwrap()) as usize;
+    body.drain(..5);
+    if body.len() < len {
+        bail!("Truncated response");
+    }
+    body.truncate(len);
+    Ok(body)
+}
+
+/// Something to speak HTTP/2 over, a TCP stream with or without TLS.
+pub trait Io: Read + Write + Unpin + Send {}
+
+impl<T: Read + Write + Unpin + Send> Io for T {}
+
+/// A client-streaming gRPC call, whose request messages are sent as they come over one
+/// long-lived HTTP/2 stream.
+pub struct Stream {
+    io: Box<dyn Io>,
+    /// Received bytes not yet handled as frames.
+    pending: Vec<u8>,
+    /// What the server lets us send on the connection and on the stream.
+    connection_window: i64,
+    stream_window: i64,
+    initial_window: i64,
+    /// Whether the server sent the trailers with an OK status.
+    ended: bool,
+}
+
+impl Stream {
+    /// Starts calling `method` of the server at `authority` over `io`.
+    pub async fn open(
+        mut io: Box<dyn Io>,
+        scheme: &str,
+        authority: &str,
+        method: &str,
+    ) -> Result<Stream> {
+        let mut request = PREFACE.to_vec();
+        put_frame(&mut request, SETTINGS, 0, 0, &[0, 0x2, 0, 0, 0, 0]); // ENABLE_PUSH 0
+        let mut headers = Vec::new();
+        for (name, value) in [
+            (":method", "POST"),
+            (":scheme", scheme),
+            (":path", method),
+            (":authority", authority),
+            ("content-type", "application/grpc"),
+            ("te", "trailers"),
+        ] {
+            put_header(&mut headers, name, value);
+        }
+        put_frame(&mut request, HEADERS, END_HEADERS, 1, &headers);
+        io.write_all(&request).await?;
+        io.flush().await?;
+        Ok(Stream {
+            io,
+            pending: Vec::new(),
+            connection_window: DEFAULT_WINDOW,
+            stream_window: DEFAULT_WINDOW,
+            initial_window: DEFAULT_WINDOW,
+            ended: false,
+        })
+    }
+
+    /// Sends a request message, waiting for the server to open its flow-control window as
+    /// needed.
+    pub async fn send(&mut self, message: &[u8]) -> Result<()> {
+        self.receive(false).await?;
+        if self.ended {
+            bail!("The server ended the call");
+        }
+        let mut data = vec![0];
+        data.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        data.extend_from_slice(message);
+        for chunk in data.chunks(MAX_FRAME) {
+            while self.connection_window.min(self.stream_window) < chunk.len() as i64 {
+                self.receive(true).await?;
+            }
+            let mut frame = Vec::new();
+            put_frame(&mut frame, DATA, 0, 1, chunk);
+            self.io.write_all(&frame).await?;
+            self.connection_window -= chunk.len() as i64;
+            self.stream_window -= chunk.len() as i64;
+        }
+        self.io.flush().await?;
+        Ok(())
+    }
+
+    /// Ends the call and waits for the response.
+    pub async fn finish(mut self) -> Result<()> {
+        let mut frame = Vec::new();
+        put_frame(&mut frame, DATA, END_STREAM, 1, &[]);
+        self.io.write_all(&frame).await?;
+        self.io.flush().await?;
+        while !self.ended {
+            self.receive(true).await?;
+        }
+        Ok(())
+    }
+
+    /// Handles the frames the server has sent, waiting for at least one more if `wait`.
+    async fn receive(&mut self, wait: bool) -> Result<()> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            // 读 socket 在挂起时不消耗数据，超时取消是安全的
+            let n = match wait {
+                true => self.io.read(&mut chunk).await?,
+                false => match timeout(Duration::from_millis(1), self.io.read(&mut chunk)).await {
+                    Ok(n) => n?,
+                    Err(_) => return Ok(()),
+                },
+            };
+            if n == 0 {
+                bail!("The server closed the connection");
+            }
+            self.pending.extend_from_slice(&chunk[..n]);
+            self.handle_frames().await?;
+            if wait {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn handle_frames(&mut self) -> Result<()> {
+        while self.pending.len() >= 9 {
+            let len =
+                u32::from_be_bytes([0, self.pending[0], self.pending[1], self.pending[2]]) as usize;
+            if self.pending.len() < 9 + len {
+                break;
+            }
+            let frame = self.pending.drain(..9 + len).collect::<Vec<_>>();
+            let (frame_type, flags) = (frame[3], frame[4]);
+            let stream_id = u32::from_be_bytes(frame[5..9].try_into().unwrap()) & MAX_WINDOW;
+            let payload = &frame[9..];
+            let mut reply = Vec::new();
+            match frame_type {
+                SETTINGS if flags & ACK == 0 => {
+                    for setting in payload.chunks_exact(6) {
+                        let id = u16::from_be_bytes([setting[0], setting[1]]);
+                        let value = u32::from_be_bytes(setting[2..].try_into().unwrap()) as i64;
+                        if id == INITIAL_WINDOW_SIZE {
+                            self.stream_window += value - self.initial_window;
+                            self.initial_window = value;
+                        }
+                    }
+                    put_frame(&mut reply, SETTINGS, ACK, 0, &[]);
+                }
+                PING if flags & ACK == 0 => put_frame(&mut reply, PING, ACK, 0, payload),
+                WINDOW_UPDATE if payload.len() == 4 => {
+                    let increment =
+                        (u32::from_be_bytes(payload.try_into().unwrap()) & MAX_WINDOW) as i64;
+                    match stream_id {
+                        0 => self.connection_window += increment,
+                        _ => self.stream_window += increment,
+                    }
+                }
+                GOAWAY => bail!("The server closed the connection"),
+                RST_STREAM if stream_id == 1 => bail!("The server reset the call"),
+                // 请求结束前就发来 trailers，说明服务端拒绝了这次调用
+                HEADERS if stream_id == 1 && flags & END_STREAM != 0 => {
+                    let headers = literal_headers(payload);
+                    let header = |name: &str| {
+                        headers
+                            .iter()
+                            .find(|(n, _)| n == name)
+                            .map_or("", |(_, value)| value.as_str())
+                    };
+                    if header("grpc-status") == "0" {
+                        self.ended = true;
+                        continue;
+                    }
+                    bail!(
+                        "The server ended the call with status {:?} {:?}",
+                        header("grpc-status"),
+                        header("grpc-message")
+                    )
+                }
+                _ => {}
+            }
+            if !reply.is_empty() {
+                self.io.write_all(&reply).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A header field as a literal without indexing, as both ends here write them.
+pub fn put_header(buf: &mut Vec<u8>, name: &str, value: &str) {
+    // HPACK：不索引的字面量，名字和值都不用 Huffman 编码
+    buf.push(0);
+    put_hpack_string(buf, name);
+    put_hpack_string(buf, value);
+}
+
+/// The fields of a header block as `put_header` writes them, up to the first written otherwise,
+/// e.g. with Huffman coding.
+pub fn literal_headers(mut block: &[u8]) -> Vec<(String, String)> {
+    fn string(block: &mut &[u8]) -> Option<String> {
+        let (&first, rest) = block.split_first()?;
+        if first & 0x80 != 0 {
+            return None;
+        }
+        let mut len = (first & 0x7f) as usize;
+        let mut rest = rest;
+        if len == 127 {
+            let mut shift = 0;
+            loop {
+                let (&byte, after) = rest.split_first()?;
+                rest = after;
+                len += ((byte & 0x7f) as usize) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 || shift > 28 {
+                    break;
+                }
+            }
+        }
+        let value = rest.get(..len)?;
+        *block = &rest[len..];
+        Some(String::from_utf8_lossy(value).into_owned())
+    }
+    let mut headers = Vec::new();
+    while block.first() == Some(&0) {
+        block = &block[1..];
+        match (string(&mut block), string(&mut block)) {
+            (Some(name), Some(value)) => headers.push((name, value)),
+            _ => break,
+        }
+    }
+    headers
+}
+
+pub fn put_frame(buf: &mut Vec<u8>, frame_type: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    buf.push(frame_type);
+    buf.push(flags);
+    buf.extend_from_slice(&stream_id.to_be_bytes());
+    buf.extend_from_slice(payload);
+}
+
+/// A string literal with a 7-bit prefix length.
+fn put_hpack_string(buf: &mut Vec<u8>, s: &str) {
+    let mut len = s.len();
+    if len < 127 {
+        buf.push(len as u8);
+    } else {
+        buf.push(127);
+        len -= 127;
+        while len >= 128 {
+            buf.push(len as u8 | 0x80);
+            len >>= 7;
+        }
+        buf.push(len as u8);
+    }
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// Appends string field `number` to a protobuf message.
+pub fn put_string(buf: &mut Vec<u8>, number: u64, s: &str) {
+    put_varint(buf, number << 3 | 2);
+    put_varint(buf, s.len() as u64);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// The length-delimited values of field `number` in a protobuf message.
+pub fn messages(buf: &[u8], number: u64) -> Result<Vec<&[u8]>> {
+    let mut values = Vec::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        let key = read_varint(buf, &mut pos)?;
+        let len = match key & 7 {
+            0 => {
+                read_varint(buf, &mut pos)?;
+                0
+            }
+            1 => 8,
+            2 => read_varint(buf, &mut pos)? as usize,
+            5 => 4,
+            wire_type => bail!("Unsupported protobuf wire type {}", wire_type),
+        };
+        let value = buf
+            .get(pos..pos + len)
+            .ok_or_else(|| anyhow!("Truncated protobuf message"))?;
+        if key >> 3 == number && key & 7 == 2 {
+            values.push(value);
+        }
+        pos += len;
+    }
+    Ok(values)
+}
+
+/// The last value of string field `number`, or empty.
+pub fn string(buf: &[u8], number: u64) -> Result<String> {
+    Ok(messages(buf, number)?
+        .last()
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+        .unwrap_or_default())
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *buf
+            .get(*pos)
+            .ok_or_else(|| anyhow!("Truncated protobuf varint"))?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("Invalid protobuf varint")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn encodes_literal_headers() {
+        // RFC 7541 C.2.1，不索引的形式首字节为 0
+        let mut block = Vec::new();
+        put_header(&mut block, "custom-key", "custom-header");
+        assert_eq!(block, b"\x00\x0acustom-key\x0dcustom-header");
+        // 长度 200 = 127 + 73
+        let long = "x".repeat(200);
+        put_header(&mut block, "grpc-message", &long);
+        assert_eq!(&block[26..42], b"\x00\x0cgrpc-message\x7f\x49");
+        assert_eq!(
+            literal_headers(&block),
+            [
+                ("custom-key".to_string(), "custom-header".to_string()),
+                ("grpc-message".to_string(), long)
+            ]
+        );
+        // 带索引或 Huffman 编码的字段之后不再解析
+        assert!(literal_headers(b"\x88\x00\x01a\x01b").is_empty());
+        assert!(literal_headers(b"\x00\x81a\x01b").is_empty());
+    }
+
+    #[test]
+    fn encodes_frames() {
+        let mut frame = Vec::new();
+        put_frame(&mut frame, WINDOW_UPDATE, 0, 1, &[0, 0, 0x10, 0]);
+        assert_eq!(
+            frame,
+            b"\x00\x00\x04\x08\x00\x00\x00\x00\x01\x00\x00\x10\x00"
+        );
+    }
+
+    #[test]
+    fn decodes_protobuf() {
+        let mut message = Vec::new();
+        put_string(&mut message, 1, "a");
+        // varint 和 fixed64、fixed32 字段被跳过
+        message.extend_from_slice(
+            b"\x10\x96\x01\x19\x00\x00\x00\x00\x00\x00\x00\x00\x25\x00\x00\x00\x00",
+        );
+        put_string(&mut message, 1, "bc");
+        assert_eq!(&message[..3], b"\x0a\x01a");
+        assert_eq!(messages(&message, 1).unwrap(), [&b"a"[..], b"bc"]);
+        assert_eq!(string(&message, 1).unwrap(), "bc");
+        assert_eq!(string(&message, 2).unwrap(), "");
+        assert!(messages(b"\x0a\x05ab", 1).is_err());
+        assert!(messages(b"\x0b", 1).is_err());
+        assert!(messages(b"\x08\x80", 1).is_err());
+    }
+
+    async fn read(stream: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        stream.read_exact(&mut buf).await.unwrap();
+        buf
+    }
+
+    #[async_std::test]
+    async fn streams_messages_within_the_window() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        // 服务端把流的初始窗口设为 8 字节
+        server
+            .write_all(b"\x00\x00\x06\x04\x00\x00\x00\x00\x00\x00\x04\x00\x00\x00\x08")
+            .await
+            .unwrap();
+        let call = async_std::task::spawn(async move {
+            let mut stream = Stream::open(Box::new(client), "http", "hub:9400", "/svc.Svc/Push")
+                .await
+                .unwrap();
+            stream.send(b"0123456789").await.unwrap();
+            stream.finish().await
+        });
+
+        let mut expected = PREFACE.to_vec();
+        expected.extend_from_slice(b"\x00\x00\x06\x04\x00\x00\x00\x00\x00\x00\x02\x00\x00\x00\x00");
+        expected.extend_from_slice(
+            b"\x00\x00\x72\x01\x04\x00\x00\x00\x01\
+              \x00\x07:method\x04POST\x00\x07:scheme\x04http\x00\x05:path\x0d/svc.Svc/Push\
+              \x00\x0a:authority\x08hub:9400\x00\x0ccontent-type\x10application/grpc\
+              \x00\x02te\x08trailers",
+        );
+        assert_eq!(read(&mut server, expected.len()).await, expected);
+        // SETTINGS 的 ACK；15 字节的消息超出窗口，等 WINDOW_UPDATE
+        assert_eq!(
+            read(&mut server, 9).await,
+            b"\x00\x00\x00\x04\x01\x00\x00\x00\x00"
+        );
+        server
+            .write_all(b"\x00\x00\x04\x08\x00\x00\x00\x00\x01\x00\x00\x00\x10")
+            .await
+            .unwrap();
+        assert_eq!(
+            read(&mut server, 9 + 15).await,
+            b"\x00\x00\x0f\x00\x00\x00\x00\x00\x01\x00\x00\x00\x00\x0a0123456789"
+        );
+        assert_eq!(
+            read(&mut server, 9).await,
+            b"\x00\x00\x00\x00\x01\x00\x00\x00\x01"
+        );
+        // trailers：grpc-status 0
+        server
+            .write_all(b"\x00\x00\x0f\x01\x05\x00\x00\x00\x01\x00\x0bgrpc-status\x010")
+            .await
+            .unwrap();
+        call.await.unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+use anyhow::{bail, Result};
+use serde::Serialize;
+use serde_json::json;
+use tide::{Body, Request, Response, StatusCode};
+use tracing::error;
+
+use crate::cli::Cli;
+use crate::collector;
+use crate::State;
+
+const TEMPERATURE: (&str, &str) = ("temperature.gpu", "nvidia_temperature_gpu");
+/// Uncorrected errors since the driver loaded; the aggregate counter also has those of earlier
+/// boots, which a reset does not clear.
+const ECC_ERRORS: (&str, &str) = (
+    "ecc.errors.uncorrected.volatile.total",
+    "nvidia_ecc_errors_uncorrected_volatile_total",
+);
+
+/// The `--health.*` thresholds of `/health/gpus`.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Thresholds {
+    pub temperature_warn: Option<f64>,
+    pub temperature_fail: Option<f64>,
+    pub ecc_errors_warn: Option<f64>,
+    pub ecc_errors_fail: Option<f64>,
+    /// Fails with fewer GPUs.
+    pub gpus: Option<usize>,
+}
+
+impl Thresholds {
+    pub fn from_cli(cli: &Cli) -> Result<Self> {
+        let thresholds = Thresholds {
+            temperature_warn: cli.health_temperature_warn,
+            temperature_fail: cli.health_temperature_fail,
+            ecc_errors_warn: cli.health_ecc_errors_warn,
+            ecc_errors_fail: cli.health_ecc_errors_fail,
+            gpus: cli.health_gpus,
+        };
+        for (name, warn, fail) in [
+            (
+                "temperature",
+                thresholds.temperature_warn,
+                thresholds.temperature_fail,
+            ),
+            (
+                "ecc-errors",
+                thresholds.ecc_errors_warn,
+                thresholds.ecc_errors_fail,
+            ),
+        ] {
+            if let (Some(warn), Some(fail)) = (warn, fail) {
+                if warn > fail {
+                    bail!(
+                        "--health.{}-warn {} is above --health.{}-fail {}",
+                        name,
+                        warn,
+                        name,
+                        fail
+                    );
+                }
+            }
+        }
+        Ok(thresholds)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// One check of `/health/gpus`, of the collection or of a GPU.
+#[derive(Serialize)]
+struct Check {
+    check: &'static str,
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gpu: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uuid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warn: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fail: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+impl Check {
+    fn new(check: &'static str, status: Status) -> Self {
+        Check {
+            check,
+            status,
+            gpu: None,
+            uuid: None,
+            host: None,
+            value: None,
+            warn: None,
+            fail: None,
+            message: None,
+        }
+    }
+}
+
+/// `GET /health/gpus`: runs nvidia-smi and checks the `--health.*` thresholds, answering `503`
+/// when a check fails and `200` otherwise, with every check and the overall status as JSON.
+pub async fn handle_health(req: Request<State>) -> tide::Result {
+    let settings = req.state().settings.read().unwrap().clone();
+    let thresholds = &settings.health;
+    let mut fields = Vec::new();
+    if thresholds.temperature_warn.is_some() || thresholds.temperature_fail.is_some() {
+        fields.push(TEMPERATURE);
+    }
+    if thresholds.ecc_errors_warn.is_some() || thresholds.ecc_errors_fail.is_some() {
+        fields.push(ECC_ERRORS);
+    }
+
+    let mut checks = Vec::new();
+    match collector::collect_gpus(&settings, &fields).await {
+        Ok(gpus) => {
+            let mut check = Check::new("nvidia-smi", Status::Pass);
+            check.value = Some(gpus.len() as f64);
+            checks.push(check);
+            if let Some(expected) = thresholds.gpus {
+                let mut check = Check::new(
+                    "gpus",
+                    match gpus.len() < expected {
+                        true => Status::Fail,
+                        false => Status::Pass,
+                    },
+                );
+                check.value = Some(gpus.len() as f64);
+                check.fail = Some(expected as f64);
+                check.message = Some(format!("{} of {} GPUs", gpus.len(), expected));
+                checks.push(check);
+            }
+            for gpu in &gpus {
+                for (name, (_, metric), warn, fail) in [
+                    (
+                        "temperature",
+                        TEMPERATURE,
+                        thresholds.temperature_warn,
+                        thresholds.temperature_fail,
+                    ),
+                    (
+                        "ecc-errors",
+                        ECC_ERRORS,
+                        thresholds.ecc_errors_warn,
+                        thresholds.ecc_errors_fail,
+                    ),
+                ] {
+                    // 未设阈值，或者 GPU 不支持（如消费级显卡没有 ECC）
+                    let value = match gpu.value(metric) {
+                        Some(value) if warn.is_some() || fail.is_some() => value,
+                        _ => continue,
+                    };
+                    let status = if fail.is_some_and(|fail| value >= fail) {
+                        Status::Fail
+                    } else if warn.is_some_and(|warn| value >= warn) {
+                        Status::Warn
+                    } else {
+                        Status::Pass
+                    };
+                    let mut check = Check::new(name, status);
+                    check.gpu = Some(gpu.index.clone());
+                    check.uuid = Some(gpu.uuid.clone());
+                    check.host = gpu.host.clone();
+                    check.value = Some(value);
+                    check.warn = warn;
+                    check.fail = fail;
+                    checks.push(check);
+                }
+            }
+        }
+        Err(e) => {
+            error!("Failed to process nvidia-smi, {:#}", e);
+            let mut check = Check::new("nvidia-smi", Status::Fail);
+            check.message = Some(format!("{:#}", e));
+            checks.push(check);
+        }
+    }
+
+    let status = checks
+        .iter()
+        .map(|check| check.status)
+        .max()
+        .unwrap_or(Status::Pass);
+    let code = match status {
+        Status::Fail => StatusCode::ServiceUnavailable,
+        _ => StatusCode::Ok,
+    };
+    Ok(Response::builder(code)
+        .body(Body::from_json(
+            &json!({ "status": status, "checks": checks }),
+        )?)
+        .build())
+}
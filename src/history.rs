@@ -0,0 +1,347 @@
+use lazy_static::lazy_static;
+use prometheus::{register_gauge_vec, GaugeVec};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tide::{Body, Request, Response, StatusCode};
+use tracing::warn;
+
+use crate::collector::{self, COLLECTORS};
+use crate::config::Settings;
+use crate::gpu::Gpu;
+use crate::State;
+
+/// Fewest earlier values a z-score is computed from.
+const MIN_SAMPLES: usize = 10;
+/// How far back the utilization is compared with the last one.
+const COLLAPSE_WINDOW: Duration = Duration::from_secs(300);
+/// Mean utilization over `COLLAPSE_WINDOW` from which a GPU was busy, in %.
+const BUSY_UTILIZATION: f64 = 50.0;
+/// Utilization up to which a busy GPU has collapsed, in %.
+const IDLE_UTILIZATION: f64 = 5.0;
+
+lazy_static! {
+    /// The collections of the last `--history.retention`, oldest first.
+    static ref HISTORY: Mutex<History> = Mutex::new(History::default());
+    static ref TEMPERATURE_ZSCORE: GaugeVec = register_gauge_vec!(
+        "nvidia_temperature_gpu_zscore",
+        "Standard deviations of the last GPU temperature from its mean over --history.retention.",
+        &["gpu", "uuid"]
+    )
+    .unwrap();
+    static ref POWER_ZSCORE: GaugeVec = register_gauge_vec!(
+        "nvidia_power_draw_zscore",
+        "Standard deviations of the last power draw from its mean over --history.retention.",
+        &["gpu", "uuid"]
+    )
+    .unwrap();
+    static ref UTILIZATION_COLLAPSED: GaugeVec = register_gauge_vec!(
+        "nvidia_utilization_gpu_collapsed",
+        "Whether the GPU utilization dropped to 5% or less after averaging 50% or more over the previous 5 minutes.",
+        &["gpu", "uuid"]
+    )
+    .unwrap();
+}
+
+#[derive(Default)]
+struct History {
+    interval: Duration,
+    retention: Duration,
+    /// Unix time in seconds and the GPUs collected then.
+    snapshots: VecDeque<(f64, Vec<Gpu>)>,
+}
+
+impl History {
+    fn record(&mut self, at: f64, gpus: Vec<Gpu>) {
+        self.snapshots.push_back((at, gpus));
+        let oldest = at - self.retention.as_secs_f64();
+        while self.snapshots.front().is_some_and(|(at, _)| *at < oldest) {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// The values of `metric` the GPU had before the last collection, oldest first.
+    fn earlier(&self, uuid: &str, metric: &str, since: f64) -> Vec<f64> {
+        let last = self.snapshots.len().saturating_sub(1);
+        self.snapshots
+            .iter()
+            .take(last)
+            .filter(|(at, _)| *at >= since)
+            .filter_map(|(_, gpus)| gpus.iter().find(|g| g.uuid == uuid)?.value(metric))
+            .collect()
+    }
+
+    /// Sets the anomaly gauges of the GPUs of the last collection; the others' are removed.
+    fn update_anomalies(&self) {
+        let (at, gpus) = match self.snapshots.back() {
+            Some(last) => last,
+            None => return,
+        };
+        for (gauge, metric) in [
+            (&*TEMPERATURE_ZSCORE, "nvidia_temperature_gpu"),
+            (&*POWER_ZSCORE, "nvidia_power_draw"),
+        ] {
+            gauge.reset();
+            for gpu in gpus {
+                let value = match gpu.value(metric) {
+                    Some(value) => value,
+                    None => continue,
+                };
+                if let Some(zscore) = zscore(value, &self.earlier(&gpu.uuid, metric, 0.0)) {
+                    gauge
+                        .with_label_values(&[&gpu.index, &gpu.uuid])
+                        .set(zscore);
+                }
+            }
+        }
+        UTILIZATION_COLLAPSED.reset();
+        let since = at - COLLAPSE_WINDOW.as_secs_f64();
+        for gpu in gpus {
+            let value = match gpu.value("nvidia_utilization_gpu") {
+                Some(value) => value,
+                None => continue,
+            };
+            let earlier = self.earlier(&gpu.uuid, "nvidia_utilization_gpu", since);
+            if earlier.is_empty() {
+                continue;
+            }
+            let busy = mean(&earlier) >= BUSY_UTILIZATION;
+            let collapsed = busy && value <= IDLE_UTILIZATION;
+            UTILIZATION_COLLAPSED
+                .with_label_values(&[&gpu.index, &gpu.uuid])
+                .set(if collapsed { 1.0 } else { 0.0 });
+        }
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// `value`'s distance from the mean of `earlier` in standard deviations; none with too few
+/// values, or when they were all the same.
+fn zscore(value: f64, earlier: &[f64]) -> Option<f64> {
+    if earlier.len() < MIN_SAMPLES {
+        return None;
+    }
+    let mean = mean(earlier);
+    let variance = earlier.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / earlier.len() as f64;
+    let deviation = variance.sqrt();
+    (deviation > 0.0).then(|| (value - mean) / deviation)
+}
+
+/// `GET /history` output: one series per GPU and metric.
+#[derive(Serialize)]
+struct Series<'a> {
+    gpu: &'a str,
+    uuid: &'a str,
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host: Option<&'a str>,
+    metric: &'a str,
+    /// `[unix seconds, value]`, like Prometheus range queries.
+    values: Vec<(f64, f64)>,
+}
+
+/// `--history.retention`: collects every `interval`, independently of scrapes, and keeps the
+/// GPUs of the last `retention` in memory for `/history`.
+pub fn start(settings: &Arc<RwLock<Settings>>, interval: Duration, retention: Duration) {
+    {
+        let mut history = HISTORY.lock().unwrap();
+        history.interval = interval;
+        history.retention = retention;
+    }
+    async_std::task::spawn(run(settings.clone(), interval));
+}
+
+async fn run(settings: Arc<RwLock<Settings>>, interval: Duration) {
+    loop {
+        let started = Instant::now();
+        let settings = settings.read().unwrap().clone();
+        let fields = collector::fields(&settings);
+        match collector::collect_gpus(&settings, &fields).await {
+            Ok(gpus) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                let mut history = HISTORY.lock().unwrap();
+                history.record(now.as_secs_f64(), gpus);
+                history.update_anomalies();
+            }
+            // 采集失败留下空档，正好能看出何时出的问题；异常指标不再反映现状，一并去掉
+            Err(e) => {
+                warn!("Failed to collect for the history, {:#}", e);
+                for gauge in [
+                    &*TEMPERATURE_ZSCORE,
+                    &*POWER_ZSCORE,
+                    &*UTILIZATION_COLLAPSED,
+                ] {
+                    gauge.reset();
+                }
+            }
+        }
+        async_std::task::sleep(interval.saturating_sub(started.elapsed())).await;
+    }
+}
+
+/// `GET /history?gpu=0&metric=temperature`: the values kept for the GPUs with that index or UUID
+/// (all without `gpu`) of the metrics named, by exported name (`nvidia_temperature_gpu`),
+/// nvidia-smi field (`temperature.gpu`) or collector (`temperature`); all without `metric`.
+pub async fn handle_history(req: Request<State>) -> tide::Result {
+    let mut gpu = None;
+    let mut metric = None;
+    for (key, value) in req.url().query_pairs() {
+        match &*key {
+            "gpu" => gpu = Some(value.into_owned()),
+            "metric" => metric = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+    let settings = req.state().settings.read().unwrap().clone();
+    let fields = collector::fields(&settings);
+    let metrics = match &metric {
+        None => fields.iter().map(|(_, metric)| *metric).collect::<Vec<_>>(),
+        Some(name) => {
+            let collector = COLLECTORS.iter().find(|c| c.name == name.as_str());
+            let metrics = fields
+                .iter()
+                .filter(|(field, metric)| {
+                    field == name
+                        || metric == name
+                        || collector.is_some_and(|c| c.fields.iter().any(|(_, m)| m == metric))
+                })
+                .map(|(_, metric)| *metric)
+                .collect::<Vec<_>>();
+            if metrics.is_empty() {
+                return Ok(crate::bad_request(format!(
+                    "Unknown or disabled metric {:?}",
+                    name
+                )));
+            }
+            metrics
+        }
+    };
+    let history = HISTORY.lock().unwrap();
+    let mut series: Vec<Series> = Vec::new();
+    for (at, gpus) in &history.snapshots {
+        let gpus = gpus.iter().filter(|g| {
+            gpu.as_ref()
+                .is_none_or(|gpu| g.index == *gpu || g.uuid == *gpu)
+        });
+        for g in gpus {
+            for metric in &metrics {
+                let value = match g.value(metric) {
+                    Some(value) => value,
+                    None => continue,
+                };
+                let host = g.host.as_deref();
+                match series
+                    .iter_mut()
+                    .find(|s| s.uuid == g.uuid && s.host == host && s.metric == *metric)
+                {
+                    Some(s) => s.values.push((*at, value)),
+                    None => series.push(Series {
+                        gpu: &g.index,
+                        uuid: &g.uuid,
+                        name: &g.name,
+                        host,
+                        metric,
+                        values: vec![(*at, value)],
+                    }),
+                }
+            }
+        }
+    }
+    let body = serde_json::json!({
+        "interval_seconds": history.interval.as_secs(),
+        "retention_seconds": history.retention.as_secs(),
+        "series": series,
+    });
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&body)?)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::core::Collector;
+
+    fn gpu(temperature: f64, power: f64, utilization: f64) -> Gpu {
+        Gpu {
+            index: "0".to_string(),
+            uuid: "GPU-0".to_string(),
+            name: "NVIDIA A100-SXM4-80GB".to_string(),
+            driver_version: "550.90.07".to_string(),
+            values: vec![
+                ("nvidia_temperature_gpu".to_string(), temperature),
+                ("nvidia_power_draw".to_string(), power),
+                ("nvidia_utilization_gpu".to_string(), utilization),
+            ],
+            host: None,
+        }
+    }
+
+    fn gauge(gauge: &GaugeVec) -> Option<f64> {
+        let families = gauge.collect();
+        let metric = families[0].get_metric().first()?;
+        Some(metric.get_gauge().get_value())
+    }
+
+    #[test]
+    fn zscores() {
+        let earlier = [60.0, 62.0].repeat(MIN_SAMPLES / 2);
+        assert_eq!(zscore(70.0, &earlier), Some(9.0));
+        assert_eq!(zscore(58.0, &earlier), Some(-3.0));
+        assert_eq!(zscore(70.0, &earlier[1..]), None);
+        assert_eq!(zscore(70.0, &[60.0; MIN_SAMPLES]), None);
+    }
+
+    #[test]
+    fn keeps_the_retention() {
+        let mut history = History {
+            retention: Duration::from_secs(60),
+            ..History::default()
+        };
+        for at in [0.0, 30.0, 60.0, 90.0] {
+            history.record(at, vec![gpu(60.0, 100.0, 80.0)]);
+        }
+        let kept = history
+            .snapshots
+            .iter()
+            .map(|(at, _)| *at)
+            .collect::<Vec<_>>();
+        assert_eq!(kept, [30.0, 60.0, 90.0]);
+        assert_eq!(
+            history.earlier("GPU-0", "nvidia_temperature_gpu", 60.0),
+            [60.0]
+        );
+    }
+
+    #[test]
+    fn flags_anomalies() {
+        let mut history = History {
+            retention: Duration::from_secs(3600),
+            ..History::default()
+        };
+        for i in 0..MIN_SAMPLES {
+            let temperature = if i % 2 == 0 { 60.0 } else { 62.0 };
+            history.record(i as f64 * 30.0, vec![gpu(temperature, 100.0, 80.0)]);
+        }
+        history.record(MIN_SAMPLES as f64 * 30.0, vec![gpu(70.0, 100.0, 2.0)]);
+        history.update_anomalies();
+        assert_eq!(gauge(&TEMPERATURE_ZSCORE), Some(9.0));
+        // 功率一直不变，没有 z-score
+        assert_eq!(gauge(&POWER_ZSCORE), None);
+        assert_eq!(gauge(&UTILIZATION_COLLAPSED), Some(1.0));
+
+        // 利用率回升后不再算作崩塌
+        history.record(
+            MIN_SAMPLES as f64 * 30.0 + 30.0,
+            vec![gpu(70.0, 100.0, 40.0)],
+        );
+        history.update_anomalies();
+        assert_eq!(gauge(&UTILIZATION_COLLAPSED), Some(0.0));
+    }
+}
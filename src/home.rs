@@ -0,0 +1,120 @@
+use std::fmt::Write;
+use std::time::SystemTime;
+use tide::{http::mime, Body, Request, Response, StatusCode};
+
+use crate::collector;
+use crate::gpu::Gpu;
+use crate::{version, State};
+
+/// Landing page summarising the last collection, for debugging a node by hand.
+pub async fn handle_home(req: Request<State>) -> tide::Result {
+    let state = req.state();
+    let mut body = String::new();
+    writeln!(
+        body,
+        "<html>
+        <head><title>Nvidia SMI exporter</title></head>
+        <body>
+        <h1>Nvidia SMI exporter</h1>
+        <p>Version {}</p>",
+        version::VERSION
+    )?;
+
+    match collector::last_collection() {
+        None => writeln!(body, "<p>No collection yet.</p>")?,
+        Some(last) => {
+            // 页面展示的是最近一次采集的结果，本身不触发采集
+            let ago = SystemTime::now()
+                .duration_since(last.finished_at)
+                .unwrap_or_default();
+            let status = match &last.result {
+                Ok(_) => "succeeded".to_string(),
+                Err(e) => format!("failed: {}", escape(e)),
+            };
+            writeln!(
+                body,
+                "<p>Last collection {} {:.0}s ago, took {:.3}s.</p>",
+                status,
+                ago.as_secs_f64(),
+                last.duration.as_secs_f64()
+            )?;
+            if let Ok(gpus) = &last.result {
+                render_gpus(&mut body, gpus)?;
+            }
+        }
+    }
+
+    writeln!(body, "<h2>Endpoints</h2>\n<ul>")?;
+    let mut links = vec![
+        (state.telemetry_path.as_str(), "Metrics"),
+        ("/api/v1/gpus", "GPUs as JSON"),
+        ("/api/v1/targets", "Prometheus HTTP SD"),
+        ("/api/v1/metadata", "Metric metadata"),
+        ("/dashboard.json", "Grafana dashboard"),
+        ("/topology", "GPU topology"),
+        ("/healthz", "Liveness"),
+        ("/readyz", "Readiness"),
+        ("/health/gpus", "GPU health checks"),
+        ("/version", "Version"),
+        ("/config", "Configuration"),
+    ];
+    if state.debug_runtime {
+        links.push(("/debug/runtime", "Runtime state"));
+    }
+    if state.stream {
+        links.push(("/stream", "Live values as Server-Sent Events"));
+    }
+    if state.history {
+        links.push(("/history", "Recent values as JSON"));
+    }
+    for (path, title) in links {
+        writeln!(body, "<li><a href='{}'>{}</a></li>", escape(path), title)?;
+    }
+    writeln!(body, "</ul>\n</body>\n</html>")?;
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from(body))
+        .content_type(mime::HTML)
+        .build())
+}
+
+fn render_gpus(body: &mut String, gpus: &[Gpu]) -> std::fmt::Result {
+    if let Some(gpu) = gpus.first() {
+        writeln!(body, "<p>Driver {}</p>", escape(&gpu.driver_version))?;
+    }
+    writeln!(
+        body,
+        "<table border='1' cellpadding='4'>
+        <tr><th>GPU</th><th>Name</th><th>Temperature (C)</th><th>Utilization (%)</th><th>Memory used / total (MiB)</th><th>Power (W)</th></tr>"
+    )?;
+    let value = |gpu: &Gpu, metric| {
+        gpu.value(metric)
+            .map_or_else(|| "N/A".to_string(), |v| v.to_string())
+    };
+    for gpu in gpus {
+        let index = match &gpu.host {
+            Some(host) => format!("{} {}", host, gpu.index),
+            None => gpu.index.clone(),
+        };
+        writeln!(
+            body,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{} / {}</td><td>{}</td></tr>",
+            escape(&index),
+            escape(&gpu.name),
+            value(gpu, "nvidia_temperature_gpu"),
+            value(gpu, "nvidia_utilization_gpu"),
+            value(gpu, "nvidia_memory_used"),
+            value(gpu, "nvidia_memory_total"),
+            value(gpu, "nvidia_power_draw"),
+        )?;
+    }
+    writeln!(body, "</table>")
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\'', "&#39;")
+        .replace('"', "&quot;")
+}
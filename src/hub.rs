@@ -0,0 +1,524 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_lock::Mutex as AsyncMutex;
+use async_rustls::TlsConnector;
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use lazy_static::lazy_static;
+use prometheus::{register_gauge_vec, register_int_gauge, GaugeVec, IntGauge};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use surf::Url;
+use tracing::{debug, info, warn};
+
+use crate::cli::Cli;
+use crate::config::Settings;
+use crate::federate;
+use crate::grpc::{self, Io, Stream};
+use crate::grpc::{
+    ACK, DATA, END_HEADERS, END_STREAM, GOAWAY, HEADERS, MAX_FRAME, MAX_WINDOW, PADDED, PING,
+    PREFACE, RST_STREAM, SETTINGS, WINDOW_UPDATE,
+};
+use crate::push::{Batch, Target};
+use crate::tls;
+
+/// `service Hub { rpc Report(stream Report) returns (Empty); }`
+const METHOD: &str = "/nvidia_smi_exporter.v1.Hub/Report";
+// gRPC status codes
+const OK: &str = "0";
+const INVALID_ARGUMENT: &str = "3";
+const UNAUTHENTICATED: &str = "16";
+
+lazy_static! {
+    /// The last exposition of each agent, by node name.
+    static ref NODES: Mutex<HashMap<String, (Instant, String)>> = Mutex::new(HashMap::new());
+    static ref CONNECTIONS: IntGauge = register_int_gauge!(
+        "nvidia_smi_exporter_hub_connections",
+        "Agents connected to the hub."
+    )
+    .unwrap();
+    static ref LAST_REPORT: GaugeVec = register_gauge_vec!(
+        "nvidia_smi_exporter_hub_last_report_timestamp_seconds",
+        "Unix timestamp of the last report of each agent the hub has not forgotten.",
+        &["instance"]
+    )
+    .unwrap();
+}
+
+/// `message Report { string node = 1; string exposition = 2; string token = 3; }`
+struct Report {
+    node: String,
+    exposition: String,
+    token: String,
+}
+
+impl Report {
+    fn encode(&self) -> Vec<u8> {
+        let mut message = Vec::new();
+        grpc::put_string(&mut message, 1, &self.node);
+        grpc::put_string(&mut message, 2, &self.exposition);
+        if !self.token.is_empty() {
+            grpc::put_string(&mut message, 3, &self.token);
+        }
+        message
+    }
+
+    fn decode(message: &[u8]) -> Result<Self> {
+        Ok(Report {
+            node: grpc::string(message, 1)?,
+            exposition: grpc::string(message, 2)?,
+            token: grpc::string(message, 3)?,
+        })
+    }
+}
+
+/// Binds `--hub.listen` before the sandbox is applied.
+pub fn bind(address: &str) -> Result<TcpListener> {
+    let listener = std::net::TcpListener::bind(address)
+        .with_context(|| format!("Failed to bind --hub.listen {}", address))?;
+    info!("Hub listening on {}", address);
+    Ok(TcpListener::from(listener))
+}
+
+/// Accepts agents, each streaming its reports over a connection of its own.
+pub async fn serve(listener: TcpListener, token_file: Option<String>) {
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to accept an agent connection, {}", e);
+                continue;
+            }
+        };
+        let token_file = token_file.clone();
+        async_std::task::spawn(async move {
+            let peer = stream
+                .peer_addr()
+                .map_or_else(|_| "unknown".to_string(), |a| a.to_string());
+            CONNECTIONS.inc();
+            if let Err(e) = connection(stream, token_file.as_deref()).await {
+                warn!("Agent connection from {} ended, {:#}", peer, e);
+            }
+            CONNECTIONS.dec();
+        });
+    }
+}
+
+/// Serves one HTTP/2 connection without TLS, taking every stream as a `Report` call.
+async fn connection(mut stream: TcpStream, token_file: Option<&str>) -> Result<()> {
+    let mut preface = [0u8; 24];
+    stream.read_exact(&mut preface).await?;
+    if preface != PREFACE {
+        bail!("Not an HTTP/2 client");
+    }
+    // 窗口开到最大，每收到一帧 DATA 再补回去
+    let mut greeting = Vec::new();
+    let mut settings = Vec::new();
+    settings.extend_from_slice(&0x4u16.to_be_bytes()); // INITIAL_WINDOW_SIZE
+    settings.extend_from_slice(&MAX_WINDOW.to_be_bytes());
+    grpc::put_frame(&mut greeting, SETTINGS, 0, 0, &settings);
+    grpc::put_frame(
+        &mut greeting,
+        WINDOW_UPDATE,
+        0,
+        0,
+        &(MAX_WINDOW - 65535).to_be_bytes(),
+    );
+    stream.write_all(&greeting).await?;
+    // 每个流还没拼成完整 gRPC 消息的数据
+    let mut bodies: HashMap<u32, Vec<u8>> = HashMap::new();
+    loop {
+        let mut header = [0u8; 9];
+        match stream.read_exact(&mut header).await {
+            Ok(()) => {}
+            // agent 结束调用后关闭连接
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        if len > MAX_FRAME {
+            bail!("Frame of {} bytes is over the maximum frame size", len);
+        }
+        let (frame_type, flags) = (header[3], header[4]);
+        let stream_id = u32::from_be_bytes(header[5..].try_into().unwrap()) & MAX_WINDOW;
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).await?;
+        let mut reply = Vec::new();
+        match frame_type {
+            SETTINGS if flags & ACK == 0 => grpc::put_frame(&mut reply, SETTINGS, ACK, 0, &[]),
+            PING if flags & ACK == 0 => grpc::put_frame(&mut reply, PING, ACK, 0, &payload),
+            GOAWAY => return Ok(()),
+            RST_STREAM => {
+                bodies.remove(&stream_id);
+            }
+            HEADERS => {
+                bodies.entry(stream_id).or_default();
+            }
+            DATA => {
+                let data = match flags & PADDED {
+                    0 => &payload[..],
+                    _ => {
+                        let pad = *payload.first().unwrap_or(&0) as usize;
+                        payload
+                            .get(1..len.saturating_sub(pad))
+                            .ok_or_else(|| anyhow!("Invalid padding"))?
+                    }
+                };
+                let body = bodies.entry(stream_id).or_default();
+                body.extend_from_slice(data);
+                if len > 0 {
+                    let increment = (len as u32).to_be_bytes();
+                    grpc::put_frame(&mut reply, WINDOW_UPDATE, 0, 0, &increment);
+                    if flags & END_STREAM == 0 {
+                        grpc::put_frame(&mut reply, WINDOW_UPDATE, 0, stream_id, &increment);
+                    }
+                }
+                while let Some(message) = take_message(body)? {
+                    if let Err((status, e)) = receive(&message, token_file) {
+                        respond(&mut reply, stream_id, status, &format!("{:#}", e));
+                        stream.write_all(&reply).await?;
+                        return Err(e);
+                    }
+                }
+            }
+            _ => {}
+        }
+        if matches!(frame_type, DATA | HEADERS) && flags & END_STREAM != 0 {
+            bodies.remove(&stream_id);
+            respond(&mut reply, stream_id, OK, "");
+        }
+        if !reply.is_empty() {
+            stream.write_all(&reply).await?;
+        }
+    }
+}
+
+/// The next complete length-prefixed gRPC message of `body`, if any.
+fn take_message(body: &mut Vec<u8>) -> Result<Option<Vec<u8>>> {
+    if body.len() < 5 {
+        return Ok(None);
+    }
+    if body[0] != 0 {
+        bail!("Compressed messages are not supported");
+    }
+    let len = u32::from_be_bytes(body[1..5].try_into().unwrap()) as usize;
+    if body.len() < 5 + len {
+        return Ok(None);
+    }
+    let message = body[5..5 + len].to_vec();
+    body.drain(..5 + len);
+    Ok(Some(message))
+}
+
+/// Keeps a report as its node's latest, or why it is refused with which gRPC status.
+fn receive(message: &[u8], token_file: Option<&str>) -> Result<(), (&'static str, anyhow::Error)> {
+    let report = Report::decode(message).map_err(|e| (INVALID_ARGUMENT, e))?;
+    if let Some(file) = token_file {
+        // 每次都重新读取，令牌轮换后不必重启
+        let token = std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to read {}", file))
+            .map_err(|e| (UNAUTHENTICATED, e))?;
+        if report.token != token.trim() {
+            return Err((
+                UNAUTHENTICATED,
+                anyhow!("Invalid token from {:?}", report.node),
+            ));
+        }
+    }
+    if report.node.is_empty() {
+        return Err((INVALID_ARGUMENT, anyhow!("Report without a node name")));
+    }
+    debug!("Report from {}", report.node);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    LAST_REPORT
+        .with_label_values(&[&report.node])
+        .set(now.as_secs_f64());
+    NODES
+        .lock()
+        .unwrap()
+        .insert(report.node, (Instant::now(), report.exposition));
+    Ok(())
+}
+
+/// The response of a call: its headers and the trailers with `status`.
+fn respond(buf: &mut Vec<u8>, stream_id: u32, status: &str, message: &str) {
+    let mut headers = Vec::new();
+    grpc::put_header(&mut headers, ":status", "200");
+    grpc::put_header(&mut headers, "content-type", "application/grpc");
+    grpc::put_frame(buf, HEADERS, END_HEADERS, stream_id, &headers);
+    let mut trailers = Vec::new();
+    grpc::put_header(&mut trailers, "grpc-status", status);
+    if !message.is_empty() {
+        grpc::put_header(&mut trailers, "grpc-message", message);
+    }
+    grpc::put_frame(buf, HEADERS, END_HEADERS | END_STREAM, stream_id, &trailers);
+}
+
+/// The agents that reported within `--hub.stale-after`, forgetting the others.
+fn fresh(stale_after: Duration) -> Vec<(String, String)> {
+    let mut nodes = NODES.lock().unwrap();
+    nodes.retain(|node, (at, _)| {
+        let fresh = at.elapsed() < stale_after;
+        if !fresh {
+            info!(
+                "Forgetting {}, which has not reported for {:?}",
+                node, stale_after
+            );
+            let _ = LAST_REPORT.remove_label_values(&[node]);
+        }
+        fresh
+    });
+    let mut fresh = nodes
+        .iter()
+        .map(|(node, (_, exposition))| (node.clone(), exposition.clone()))
+        .collect::<Vec<_>>();
+    fresh.sort();
+    fresh
+}
+
+/// The GPU metrics of every agent, with its node name as `instance`, like federation.
+pub fn collect(settings: &Settings, stale_after: Duration) -> Result<String> {
+    let nodes = fresh(stale_after);
+    if nodes.is_empty() {
+        bail!("No agent has reported in the last {:?}", stale_after);
+    }
+    Ok(federate::merge(&nodes, settings))
+}
+
+/// Whether any agent has reported recently, for `/readyz`.
+pub fn check_ready(stale_after: Duration) -> Result<(), String> {
+    match fresh(stale_after).is_empty() {
+        true => Err(format!(
+            "No agent has reported in the last {:?}",
+            stale_after
+        )),
+        false => Ok(()),
+    }
+}
+
+/// The hub at `--push.hub.url`, sent every collection over one long-lived gRPC call.
+pub struct Hub {
+    host: String,
+    port: u16,
+    tls: Option<TlsConnector>,
+    scheme: &'static str,
+    node: String,
+    token_file: Option<String>,
+    interval: Option<Duration>,
+    /// None until connected, and again after a failure.
+    stream: AsyncMutex<Option<Stream>>,
+}
+
+impl Hub {
+    pub fn new(address: &str, cli: &Cli, labels: Vec<(String, String)>) -> Result<Self> {
+        // host:port 简写为 http://host:port
+        let url = match address.contains("://") {
+            true => Url::parse(address),
+            false => Url::parse(&format!("http://{}", address)),
+        }
+        .with_context(|| format!("Invalid --push.hub.url {}", address))?;
+        let (tls, scheme) = match url.scheme() {
+            "http" => (None, "http"),
+            "https" => {
+                let mut config = tls::client_config(cli.push_hub_ca_file.as_deref(), None, None)?;
+                config.alpn_protocols = vec![b"h2".to_vec()];
+                (Some(TlsConnector::from(Arc::new(config))), "https")
+            }
+            scheme => bail!("Unsupported --push.hub.url scheme {:?}", scheme),
+        };
+        let node = labels
+            .iter()
+            .find(|(name, _)| name == "instance")
+            .map_or_else(String::new, |(_, value)| value.clone());
+        Ok(Hub {
+            host: url
+                .host_str()
+                .ok_or_else(|| anyhow!("Invalid --push.hub.url {}", address))?
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port: url
+                .port_or_known_default()
+                .ok_or_else(|| anyhow!("No port in --push.hub.url {}", address))?,
+            tls,
+            scheme,
+            node,
+            token_file: cli.push_hub_token_file.clone(),
+            interval: cli.push_hub_interval.map(Duration::from_secs),
+            stream: AsyncMutex::new(None),
+        })
+    }
+
+    async fn connect(&self) -> Result<Stream> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .with_context(|| format!("Failed to connect to {}:{}", self.host, self.port))?;
+        let io: Box<dyn Io> = match &self.tls {
+            Some(connector) => {
+                let name = webpki::DNSNameRef::try_from_ascii_str(&self.host)
+                    .map_err(|_| anyhow!("{} is not a valid TLS server name", self.host))?;
+                Box::new(
+                    connector
+                        .connect(name, stream)
+                        .await
+                        .with_context(|| format!("TLS handshake with {} failed", self.host))?,
+                )
+            }
+            None => Box::new(stream),
+        };
+        let authority = format!("{}:{}", self.host, self.port);
+        let stream = Stream::open(io, self.scheme, &authority, METHOD).await?;
+        info!("Connected to the hub at {}", authority);
+        Ok(stream)
+    }
+}
+
+#[tide::utils::async_trait]
+impl Target for Hub {
+    fn name(&self) -> &'static str {
+        "hub"
+    }
+
+    fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    async fn push(&self, batch: &Batch) -> Result<()> {
+        let token = match &self.token_file {
+            Some(file) => std::fs::read_to_string(file)
+                .with_context(|| format!("Failed to read {}", file))?
+                .trim()
+                .to_string(),
+            None => String::new(),
+        };
+        let report = Report {
+            node: self.node.clone(),
+            exposition: batch.exposition.clone(),
+            token,
+        };
+        // 发送中途失败或超时被取消时连接作废，下次重连
+        let mut current = self.stream.lock().await;
+        let mut stream = match current.take() {
+            Some(stream) => stream,
+            None => self.connect().await?,
+        };
+        stream.send(&report.encode()).await?;
+        *current = Some(stream);
+        Ok(())
+    }
+
+    async fn stop(&self) -> Result<()> {
+        match self.stream.lock().await.take() {
+            Some(stream) => stream.finish().await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_reports() {
+        let report = Report {
+            node: "n1".to_string(),
+            exposition: "up 1\n".to_string(),
+            token: "t".to_string(),
+        };
+        let message = report.encode();
+        assert_eq!(message, b"\x0a\x02n1\x12\x05up 1\n\x1a\x01t");
+        let decoded = Report::decode(&message).unwrap();
+        assert_eq!(
+            (decoded.node, decoded.exposition, decoded.token),
+            ("n1".to_string(), "up 1\n".to_string(), "t".to_string())
+        );
+        let anonymous = Report {
+            token: String::new(),
+            ..report
+        };
+        assert_eq!(anonymous.encode(), b"\x0a\x02n1\x12\x05up 1\n");
+        assert!(Report::decode(b"\x0a\x05n1").is_err());
+    }
+
+    #[test]
+    fn takes_complete_messages() {
+        let mut body = b"\x00\x00\x00\x00\x02ab\x00\x00\x00\x00\x03c".to_vec();
+        assert_eq!(take_message(&mut body).unwrap().unwrap(), b"ab");
+        assert_eq!(take_message(&mut body).unwrap(), None);
+        assert_eq!(body, b"\x00\x00\x00\x00\x03c");
+        assert!(take_message(&mut b"\x01\x00\x00\x00\x00".to_vec()).is_err());
+    }
+
+    /// An agent pushing one batch to a hub connection, and ending the call.
+    async fn report(node: &str, agent_token: Option<&str>, hub_token: Option<&str>) -> Result<()> {
+        let dir = std::env::temp_dir().join(format!(
+            "nvidia-smi-exporter-hub-{}-{}",
+            std::process::id(),
+            node
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let token_file = |token: Option<&str>, name: &str| {
+            token.map(|token| {
+                let path = dir.join(name);
+                std::fs::write(&path, format!("{}\n", token)).unwrap();
+                path.to_string_lossy().into_owned()
+            })
+        };
+        let hub_token = token_file(hub_token, "hub");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let agent = Hub {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            tls: None,
+            scheme: "http",
+            node: node.to_string(),
+            token_file: token_file(agent_token, "agent"),
+            interval: None,
+            stream: AsyncMutex::new(None),
+        };
+        let hub = async_std::task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            connection(stream, hub_token.as_deref()).await
+        });
+        let batch = Batch {
+            exposition: "nvidia_temperature_gpu{gpu=\"0\"} 45\n".to_string(),
+            samples: Vec::new(),
+            timestamp: SystemTime::now(),
+        };
+        let pushed = agent.push(&batch).await;
+        let stopped = agent.stop().await;
+        drop(agent);
+        let served = hub.await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        pushed.and(stopped).and(served)
+    }
+
+    #[async_std::test]
+    async fn keeps_the_reports_of_agents() {
+        report("hub-test-1", Some("secret"), Some("secret"))
+            .await
+            .unwrap();
+        let nodes = fresh(Duration::from_secs(60));
+        let (_, exposition) = nodes.iter().find(|(n, _)| n == "hub-test-1").unwrap();
+        assert_eq!(exposition, "nvidia_temperature_gpu{gpu=\"0\"} 45\n");
+    }
+
+    #[async_std::test]
+    async fn refuses_invalid_tokens() {
+        assert!(report("hub-test-2", Some("guess"), Some("secret"))
+            .await
+            .is_err());
+        assert!(report("hub-test-3", None, Some("secret")).await.is_err());
+        let nodes = fresh(Duration::from_secs(60));
+        assert!(!nodes
+            .iter()
+            .any(|(n, _)| n.starts_with("hub-test-") && n != "hub-test-1"));
+    }
+}
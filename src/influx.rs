@@ -0,0 +1,140 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::time::UNIX_EPOCH;
+use surf::http::headers::AUTHORIZATION;
+use surf::Url;
+
+use crate::cli::Cli;
+use crate::push::{Batch, Sample, Target};
+use crate::version;
+
+const MEASUREMENT: &str = "nvidia_smi";
+
+type Tags<'a> = Vec<(&'a str, &'a str)>;
+
+/// InfluxDB line protocol: samples with the same labels share a line of the `nvidia_smi`
+/// measurement, with the labels as tags and a field per metric. Tags in `extra_tags` are added
+/// to lines that do not have them; NaN and infinite values, which the protocol cannot express,
+/// are left out.
+pub fn line_protocol(
+    samples: &[Sample],
+    extra_tags: &[(String, String)],
+    timestamp_ms: Option<i64>,
+) -> String {
+    let mut lines: Vec<(Tags, Vec<(&str, f64)>)> = Vec::new();
+    for sample in samples.iter().filter(|s| s.value.is_finite()) {
+        // 空值的 tag 在行协议里不合法
+        let mut tags = sample
+            .labels
+            .iter()
+            .chain(extra_tags)
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect::<Vec<_>>();
+        tags.sort_by_key(|(name, _)| *name);
+        tags.dedup_by_key(|(name, _)| *name);
+        let field = (sample.name.as_str(), sample.value);
+        match lines.iter_mut().find(|(other, _)| *other == tags) {
+            Some((_, fields)) => fields.push(field),
+            None => lines.push((tags, vec![field])),
+        }
+    }
+
+    let mut out = String::new();
+    for (tags, fields) in lines {
+        out.push_str(MEASUREMENT);
+        for (name, value) in tags {
+            out.push_str(&format!(",{}={}", escape(name), escape(value)));
+        }
+        let fields = fields
+            .iter()
+            .map(|(name, value)| format!("{}={}", escape(name), value))
+            .collect::<Vec<_>>()
+            .join(",");
+        out.push(' ');
+        out.push_str(&fields);
+        if let Some(timestamp) = timestamp_ms {
+            out.push_str(&format!(" {}", timestamp));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn escape(s: &str) -> String {
+    s.replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+/// The `/api/v2/write` endpoint of InfluxDB 2 (or 1.8+) at `--push.influxdb.url`.
+pub struct InfluxDb {
+    client: surf::Client,
+    /// With `org`, `bucket` and `precision=ms`.
+    url: Url,
+    token_file: Option<String>,
+    /// `job` and `instance`.
+    tags: Vec<(String, String)>,
+}
+
+impl InfluxDb {
+    pub fn new(address: &str, cli: &Cli, tags: Vec<(String, String)>) -> Result<Self> {
+        let mut url = Url::parse(address)
+            .with_context(|| format!("Invalid --push.influxdb.url {}", address))?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Invalid --push.influxdb.url {}", address))?
+            .pop_if_empty()
+            .extend(&["api", "v2", "write"]);
+        {
+            let mut query = url.query_pairs_mut();
+            if let Some(org) = &cli.influxdb_org {
+                query.append_pair("org", org);
+            }
+            if let Some(bucket) = &cli.influxdb_bucket {
+                query.append_pair("bucket", bucket);
+            }
+            query.append_pair("precision", "ms");
+        }
+        Ok(InfluxDb {
+            client: surf::Client::new(),
+            url,
+            token_file: cli.influxdb_token_file.clone(),
+            tags,
+        })
+    }
+}
+
+#[tide::utils::async_trait]
+impl Target for InfluxDb {
+    fn name(&self) -> &'static str {
+        "influxdb"
+    }
+
+    async fn push(&self, batch: &Batch) -> Result<()> {
+        let timestamp = batch.timestamp.duration_since(UNIX_EPOCH)?.as_millis() as i64;
+        let body = line_protocol(&batch.samples, &self.tags, Some(timestamp));
+        let mut request = surf::post(self.url.clone())
+            .body(body)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .header(
+                "User-Agent",
+                format!("nvidia-smi-exporter/{}", version::VERSION),
+            )
+            .build();
+        // 每次推送都重新读取，令牌轮换后不必重启
+        if let Some(file) = &self.token_file {
+            let token = std::fs::read_to_string(file)
+                .with_context(|| format!("Failed to read {}", file))?;
+            request.insert_header(AUTHORIZATION, format!("Token {}", token.trim()));
+        }
+        let mut response = self
+            .client
+            .send(request)
+            .await
+            .map_err(|e| e.into_inner())?;
+        if !response.status().is_success() {
+            let text = response.body_string().await.unwrap_or_default();
+            bail!("{} {}", response.status(), text.trim());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,262 @@
+use anyhow::Result;
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge, IntGauge};
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::cli::Cli;
+use crate::collector;
+use crate::gpu::Gpu;
+
+/// How long the devices from `xpu-smi discovery` are reused.
+const DISCOVERY_TTL: Duration = Duration::from_secs(600);
+
+/// The `metrics_type` of each metric in `xpu-smi stats --json`.
+const METRICS: &[(&str, &str)] = &[
+    ("nvidia_temperature_gpu", "XPUM_STATS_GPU_CORE_TEMPERATURE"),
+    ("nvidia_clocks_gr", "XPUM_STATS_GPU_FREQUENCY"),
+    ("nvidia_power_draw", "XPUM_STATS_POWER"),
+    ("nvidia_utilization_gpu", "XPUM_STATS_GPU_UTILIZATION"),
+    // 和 nvidia-smi 的 utilization.memory 一样是显存带宽占用
+    ("nvidia_utilization_memory", "XPUM_STATS_MEMORY_BANDWIDTH"),
+    ("nvidia_memory_used", "XPUM_STATS_MEMORY_USED"),
+];
+
+lazy_static! {
+    /// The devices of `xpu-smi discovery`, with the total memory as their only reading.
+    static ref DEVICES: Mutex<Option<(Instant, Vec<Gpu>)>> = Mutex::new(None);
+    static ref UP: IntGauge = register_int_gauge!(
+        "nvidia_smi_exporter_xpu_smi_up",
+        "Whether xpu-smi answered the last collection of Intel GPUs."
+    )
+    .unwrap();
+}
+
+/// `--collect.intel`: Intel data center GPUs read with xpu-smi.
+#[derive(Clone, Debug)]
+pub struct Intel {
+    /// `--collect.intel.command`
+    command: String,
+}
+
+impl Intel {
+    pub fn from_cli(cli: &Cli) -> Option<Self> {
+        cli.collect_intel.then(|| Intel {
+            command: cli.collect_intel_command.clone(),
+        })
+    }
+
+    async fn devices(&self, collect_timeout: Duration) -> Result<Vec<Gpu>> {
+        if let Some((at, devices)) = &*DEVICES.lock().unwrap() {
+            if at.elapsed() < DISCOVERY_TTL {
+                return Ok(devices.clone());
+            }
+        }
+        let list =
+            collector::json(&self.command, &["discovery", "--json"], collect_timeout).await?;
+        let mut devices = Vec::new();
+        for device in list["device_list"].as_array().into_iter().flatten() {
+            let index = id(device);
+            // 显存大小只在单个设备的详情里
+            let detail = collector::json(
+                &self.command,
+                &["discovery", "--device", &index, "--json"],
+                collect_timeout,
+            )
+            .await?;
+            devices.push(self::device(device, &detail));
+        }
+        *DEVICES.lock().unwrap() = Some((Instant::now(), devices.clone()));
+        Ok(devices)
+    }
+
+    /// The Intel GPUs, as `Gpu`s whose values are named like the NVIDIA metrics they match.
+    async fn gpus(&self, collect_timeout: Duration) -> Result<Vec<Gpu>> {
+        let mut gpus = self.devices(collect_timeout).await?;
+        for gpu in &mut gpus {
+            let stats = collector::json(
+                &self.command,
+                &["stats", "--device", &gpu.index, "--json"],
+                collect_timeout,
+            )
+            .await?;
+            add_stats(gpu, &stats);
+        }
+        Ok(gpus)
+    }
+}
+
+/// The Intel GPUs, within `collect_timeout` per xpu-smi run, or none if `--collect.intel` is
+/// not given or xpu-smi does not answer; exported as `nvidia_smi_exporter_xpu_smi_up`.
+pub async fn collect(intel: Option<&Intel>, collect_timeout: Duration) -> Vec<Gpu> {
+    let intel = match intel {
+        Some(intel) => intel,
+        None => return Vec::new(),
+    };
+    let result = intel.gpus(collect_timeout).await;
+    UP.set(result.is_ok() as i64);
+    result.unwrap_or_else(|e| {
+        warn!("Failed to collect Intel GPUs, {:#}", e);
+        // 下次重新发现，设备可能变了
+        *DEVICES.lock().unwrap() = None;
+        Vec::new()
+    })
+}
+
+/// Whether xpu-smi lists any GPU, for `/readyz`.
+pub async fn check_ready(intel: &Intel, collect_timeout: Duration) -> Result<(), String> {
+    match intel.devices(collect_timeout).await {
+        Ok(devices) if !devices.is_empty() => Ok(()),
+        Ok(_) => Err("No Intel GPU found".to_string()),
+        Err(e) => Err(format!("{:#}", e)),
+    }
+}
+
+/// A device of `xpu-smi discovery --json`, with the memory size and driver from its `detail`.
+fn device(device: &Value, detail: &Value) -> Gpu {
+    let index = id(device);
+    let values = collector::json_number(&detail["memory_physical_size_byte"])
+        .map(|bytes| ("nvidia_memory_total".to_string(), bytes / 1048576.0))
+        .into_iter()
+        .collect();
+    Gpu {
+        uuid: match device["uuid"].as_str() {
+            Some(uuid) => uuid.to_string(),
+            None => format!("intel:{}", index),
+        },
+        index,
+        name: device["device_name"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        driver_version: detail["driver_version"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        values,
+        host: None,
+    }
+}
+
+/// Adds the readings of `xpu-smi stats --json` to `gpu`.
+fn add_stats(gpu: &mut Gpu, stats: &Value) {
+    for (metric, metrics_type) in METRICS {
+        if let Some(value) = stat(stats, metrics_type) {
+            gpu.values.push((metric.to_string(), value));
+        }
+    }
+    if let (Some(total), Some(used)) = (
+        gpu.value("nvidia_memory_total"),
+        gpu.value("nvidia_memory_used"),
+    ) {
+        gpu.values
+            .push(("nvidia_memory_free".to_string(), total - used));
+    }
+}
+
+fn id(device: &Value) -> String {
+    match &device["device_id"] {
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => s.clone(),
+        _ => String::new(),
+    }
+}
+
+/// A device-level statistic, or that of the first tile reporting it, e.g. the temperature of
+/// multi-tile GPUs.
+fn stat(stats: &Value, metrics_type: &str) -> Option<f64> {
+    let find = |level: &Value| {
+        level
+            .as_array()?
+            .iter()
+            .find(|stat| stat["metrics_type"] == metrics_type)
+            .and_then(|stat| collector::json_number(&stat["value"]))
+    };
+    find(&stats["device_level"]).or_else(|| {
+        stats["tile_level"]
+            .as_array()?
+            .iter()
+            .find_map(|tile| find(&tile["data_list"]))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `xpu-smi discovery --json` and `xpu-smi discovery --device 0 --json` of a Data Center
+    /// GPU Max 1550 node with xpu-smi 1.2.
+    const DISCOVERY: &str = r#"{"device_list": [
+        {"device_function_type": "physical", "device_id": 0, "device_name": "Intel(R) Data Center GPU Max 1550", "device_type": "GPU", "drm_device": "/dev/dri/card1", "pci_bdf_address": "0000:29:00.0", "pci_device_id": "0xbd5", "uuid": "01000000-0000-0000-0000-000000290000", "vendor_name": "Intel(R) Corporation"},
+        {"device_function_type": "physical", "device_id": 1, "device_name": "Intel(R) Data Center GPU Max 1550", "device_type": "GPU", "drm_device": "/dev/dri/card2", "pci_bdf_address": "0000:3a:00.0", "pci_device_id": "0xbd5", "vendor_name": "Intel(R) Corporation"}
+    ]}"#;
+    const DETAIL: &str = r#"{
+        "device_id": 0,
+        "device_name": "Intel(R) Data Center GPU Max 1550",
+        "driver_version": "I915_23.10.72_PSB_230525.43",
+        "gfx_firmware_version": "PVC2_1.23166",
+        "memory_physical_size_byte": "137438953472",
+        "memory_free_size_byte": "135994126336",
+        "number_of_tiles": 2,
+        "pcie_generation": "4",
+        "uuid": "01000000-0000-0000-0000-000000290000"
+    }"#;
+
+    /// `xpu-smi stats --device 0 --json`: the temperature only per tile, the memory bandwidth
+    /// missing and the power `N/A`, as while the GPU is reset.
+    const STATS: &str = r#"{
+        "device_id": 0,
+        "device_level": [
+            {"metrics_type": "XPUM_STATS_GPU_UTILIZATION", "value": 98.56},
+            {"metrics_type": "XPUM_STATS_POWER", "value": "N/A"},
+            {"metrics_type": "XPUM_STATS_GPU_FREQUENCY", "value": 1600},
+            {"metrics_type": "XPUM_STATS_MEMORY_USED", "value": 65536.5},
+            {"metrics_type": "XPUM_STATS_MEMORY_UTILIZATION", "value": 50.01}
+        ],
+        "tile_level": [
+            {"tile_id": 0, "data_list": [
+                {"metrics_type": "XPUM_STATS_GPU_UTILIZATION", "value": 99.12},
+                {"metrics_type": "XPUM_STATS_GPU_FREQUENCY", "value": 1600}
+            ]},
+            {"tile_id": 1, "data_list": [
+                {"metrics_type": "XPUM_STATS_GPU_CORE_TEMPERATURE", "value": 52.5},
+                {"metrics_type": "XPUM_STATS_MEMORY_TEMPERATURE", "value": 48}
+            ]}
+        ]
+    }"#;
+
+    #[test]
+    fn parses_xpu_smi() {
+        let list: Value = serde_json::from_str(DISCOVERY).unwrap();
+        let devices = list["device_list"].as_array().unwrap();
+        let mut gpu = device(&devices[0], &serde_json::from_str(DETAIL).unwrap());
+        assert_eq!(gpu.index, "0");
+        assert_eq!(gpu.uuid, "01000000-0000-0000-0000-000000290000");
+        assert_eq!(gpu.name, "Intel(R) Data Center GPU Max 1550");
+        assert_eq!(gpu.driver_version, "I915_23.10.72_PSB_230525.43");
+        assert_eq!(gpu.value("nvidia_memory_total"), Some(131072.0));
+
+        add_stats(&mut gpu, &serde_json::from_str(STATS).unwrap());
+        assert_eq!(gpu.value("nvidia_utilization_gpu"), Some(98.56));
+        assert_eq!(gpu.value("nvidia_clocks_gr"), Some(1600.0));
+        assert_eq!(gpu.value("nvidia_power_draw"), None);
+        assert_eq!(gpu.value("nvidia_utilization_memory"), None);
+        // 设备级没有的取第一个报告它的 tile
+        assert_eq!(gpu.value("nvidia_temperature_gpu"), Some(52.5));
+        assert_eq!(gpu.value("nvidia_memory_used"), Some(65536.5));
+        assert_eq!(gpu.value("nvidia_memory_free"), Some(65535.5));
+
+        // 没有 UUID 和详情的设备
+        let mut gpu = device(&devices[1], &Value::Null);
+        assert_eq!(gpu.uuid, "intel:1");
+        assert_eq!(gpu.driver_version, "");
+        assert!(gpu.values.is_empty());
+        add_stats(
+            &mut gpu,
+            &serde_json::from_str(r#"{"device_id": 1}"#).unwrap(),
+        );
+        assert!(gpu.values.is_empty());
+    }
+}
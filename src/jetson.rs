@@ -0,0 +1,332 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_std::future::timeout;
+use async_std::io::BufReader;
+use async_std::prelude::*;
+use async_std::process::{Command, Stdio};
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge, IntGauge};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::cli::Cli;
+use crate::gpu::Gpu;
+use crate::runtime::ChildGuard;
+
+/// tegrastats averages the utilizations over its interval.
+const INTERVAL_MS: &str = "1000";
+/// Sensors of modules without that part, e.g. the CV engines of Orin Nano, read -256.
+const ABSENT_TEMPERATURE: f64 = -256.0;
+
+lazy_static! {
+    /// The module, from the device tree, and the L4T release, e.g. `35.4.1`.
+    static ref BOARD: (String, String) = board();
+    static ref UP: IntGauge = register_int_gauge!(
+        "nvidia_smi_exporter_tegrastats_up",
+        "Whether tegrastats answered the last collection of the Jetson GPU."
+    )
+    .unwrap();
+}
+
+/// `--collect.jetson`: the integrated GPU of a Jetson module, read with tegrastats.
+#[derive(Clone, Debug)]
+pub struct Jetson {
+    /// `--collect.jetson.command`
+    command: String,
+}
+
+/// One line of tegrastats.
+#[derive(Clone, Debug)]
+pub struct Reading {
+    /// The GPU, with values named like the NVIDIA metrics they match.
+    pub gpu: Gpu,
+    /// Celsius by sensor, e.g. `CPU`, `GPU`, `SOC0`, `tj`.
+    pub temperatures: Vec<(String, f64)>,
+    /// Watts drawn by power rail, e.g. `VDD_GPU_SOC`, `VDD_CPU_CV`, `VIN_SYS_5V0`.
+    pub rails: Vec<(String, f64)>,
+}
+
+impl Jetson {
+    pub fn from_cli(cli: &Cli) -> Option<Self> {
+        cli.collect_jetson.then(|| Jetson {
+            command: cli.collect_jetson_command.clone(),
+        })
+    }
+
+    /// The first line tegrastats prints, after one interval; it keeps printing until killed.
+    async fn line(&self, collect_timeout: Duration) -> Result<String> {
+        let mut child = Command::new(&self.command)
+            .args(["--interval", INTERVAL_MS])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Failed to execute {}", self.command))?;
+        let _child = ChildGuard::new(child.id());
+        let stdout = child.stdout.take().context("No stdout")?;
+        let mut lines = BufReader::new(stdout).lines();
+        let line = timeout(collect_timeout, lines.next())
+            .await
+            .map_err(|_| anyhow!("tegrastats timed out after {:?}", collect_timeout))?;
+        match line {
+            Some(line) => Ok(line?),
+            None => bail!("tegrastats exited with {}", child.status().await?),
+        }
+    }
+}
+
+/// The Jetson GPU, within `collect_timeout`, or none if `--collect.jetson` is not given or
+/// tegrastats does not answer; exported as `nvidia_smi_exporter_tegrastats_up`.
+pub async fn collect(jetson: Option<&Jetson>, collect_timeout: Duration) -> Option<Reading> {
+    let jetson = jetson?;
+    let result = jetson.line(collect_timeout).await.and_then(|line| {
+        debug!("tegrastats: {}", line);
+        parse(&line).with_context(|| format!("Unrecognized tegrastats output {:?}", line))
+    });
+    UP.set(result.is_ok() as i64);
+    match result {
+        Ok(reading) => Some(reading),
+        Err(e) => {
+            warn!("Failed to collect the Jetson GPU, {:#}", e);
+            None
+        }
+    }
+}
+
+/// Whether tegrastats answers, for `/readyz`.
+pub async fn check_ready(jetson: &Jetson, collect_timeout: Duration) -> Result<(), String> {
+    jetson
+        .line(collect_timeout)
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("{:#}", e))
+}
+
+/// `RAM 2448/62841MB (lfb 14616x4MB) SWAP 0/31421MB (cached 0MB) CPU [0%@729,…] EMC_FREQ 0%@2133
+/// GR3D_FREQ 0%@[305,305] … CPU@46.281C … GPU@-256C tj@46.281C VDD_GPU_SOC 3206mW/3206mW …`
+/// on Orin; older releases write `GR3D`/`EMC` and rails in mW without the unit. None if the line
+/// has none of these.
+fn parse(line: &str) -> Option<Reading> {
+    let (name, release) = &*BOARD;
+    let mut values = Vec::new();
+    let mut temperatures = Vec::new();
+    let mut rails = Vec::new();
+    let tokens = line.split_whitespace().collect::<Vec<_>>();
+    for (i, token) in tokens.iter().enumerate() {
+        let next = tokens.get(i + 1).copied().unwrap_or_default();
+        match *token {
+            // Jetson 的显存就是整机内存
+            "RAM" => {
+                let mut memory = next.trim_end_matches("MB").split('/');
+                if let (Some(Ok(used)), Some(Ok(total))) = (
+                    memory.next().map(str::parse::<f64>),
+                    memory.next().map(str::parse::<f64>),
+                ) {
+                    values.push(("nvidia_memory_total".to_string(), total));
+                    values.push(("nvidia_memory_used".to_string(), used));
+                    values.push(("nvidia_memory_free".to_string(), total - used));
+                }
+            }
+            "GR3D_FREQ" | "GR3D" => push_frequency(
+                &mut values,
+                next,
+                "nvidia_utilization_gpu",
+                "nvidia_clocks_gr",
+            ),
+            "EMC_FREQ" | "EMC" => push_frequency(
+                &mut values,
+                next,
+                "nvidia_utilization_memory",
+                "nvidia_clocks_mem",
+            ),
+            _ => {
+                if let Some((sensor, celsius)) = temperature(token) {
+                    if celsius > ABSENT_TEMPERATURE {
+                        temperatures.push((sensor.to_string(), celsius));
+                    }
+                } else if let Some(milliwatts) = rail(token, next) {
+                    rails.push((token.to_string(), milliwatts / 1000.0));
+                }
+            }
+        }
+    }
+    if let Some((_, celsius)) = temperatures.iter().find(|(sensor, _)| sensor == "GPU") {
+        values.push(("nvidia_temperature_gpu".to_string(), *celsius));
+    }
+    if let Some((_, watts)) = rails.iter().find(|(rail, _)| rail.contains("GPU")) {
+        values.push(("nvidia_power_draw".to_string(), *watts));
+    }
+    if values.is_empty() && temperatures.is_empty() && rails.is_empty() {
+        return None;
+    }
+    Some(Reading {
+        gpu: Gpu {
+            index: "0".to_string(),
+            uuid: "jetson:0".to_string(),
+            name: name.clone(),
+            driver_version: release.clone(),
+            values,
+            host: None,
+        },
+        temperatures,
+        rails,
+    })
+}
+
+/// `0%@1600`, `0%@[305,305]` (per GPC on Orin), or `0%` while the engine is off.
+fn push_frequency(values: &mut Vec<(String, f64)>, token: &str, utilization: &str, clock: &str) {
+    let mut parts = token.splitn(2, '@');
+    if let Some(Ok(percent)) = parts.next().map(|p| p.trim_end_matches('%').parse::<f64>()) {
+        values.push((utilization.to_string(), percent));
+    }
+    let mhz = parts
+        .next()
+        .and_then(|f| f.trim_matches(|c| c == '[' || c == ']').split(',').next())
+        .and_then(|f| f.parse::<f64>().ok());
+    if let Some(mhz) = mhz {
+        values.push((clock.to_string(), mhz));
+    }
+}
+
+/// `CPU@46.281C`
+fn temperature(token: &str) -> Option<(&str, f64)> {
+    let (sensor, celsius) = token.strip_suffix('C')?.split_once('@')?;
+    Some((sensor, celsius.parse().ok()?))
+}
+
+/// The current milliwatts of `VDD_IN 5000mW/5000mW` or `POM_5V_IN 2135/2135`, the second
+/// number being the average since tegrastats started.
+fn rail(name: &str, reading: &str) -> Option<f64> {
+    // NC 是没有接的通道
+    if name == "NC"
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+    {
+        return None;
+    }
+    let (current, average) = reading.split_once('/')?;
+    average.trim_end_matches("mW").parse::<f64>().ok()?;
+    current.trim_end_matches("mW").parse().ok()
+}
+
+/// `NVIDIA Jetson AGX Orin Developer Kit` and `35.4.1` from `# R35 (release), REVISION: 4.1, …`.
+fn board() -> (String, String) {
+    let name = std::fs::read_to_string("/proc/device-tree/model")
+        .map(|model| model.trim_end_matches('\0').trim().to_string())
+        .unwrap_or_else(|_| "NVIDIA Jetson".to_string());
+    let release = std::fs::read_to_string("/etc/nv_tegra_release")
+        .ok()
+        .and_then(|release| {
+            let line = release.lines().next()?;
+            let major = line.strip_prefix("# R")?.split_whitespace().next()?;
+            let revision = line.split("REVISION: ").nth(1)?.split(',').next()?;
+            Some(format!("{}.{}", major, revision.trim()))
+        })
+        .unwrap_or_default();
+    (name, release)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(reading: &Reading, metric: &str) -> Option<f64> {
+        reading.gpu.value(metric)
+    }
+
+    /// Jetson Nano, L4T 32.7: rails in mW without the unit, one GPU clock.
+    #[test]
+    fn parses_nano() {
+        let line =
+            "RAM 1516/3964MB (lfb 77x4MB) SWAP 0/1982MB (cached 0MB) IRAM 0/252kB(lfb 252kB) \
+            CPU [12%@1479,8%@1479,5%@1479,3%@1479] EMC_FREQ 3%@1600 GR3D_FREQ 42%@921 APE 25 \
+            PLL@31C CPU@34C PMIC@100C GPU@32C AO@39.5C thermal@33C \
+            POM_5V_IN 2135/2135 POM_5V_GPU 412/398 POM_5V_CPU 490/490";
+        let reading = parse(line).unwrap();
+        assert_eq!(value(&reading, "nvidia_memory_total"), Some(3964.0));
+        assert_eq!(value(&reading, "nvidia_memory_used"), Some(1516.0));
+        assert_eq!(value(&reading, "nvidia_memory_free"), Some(2448.0));
+        assert_eq!(value(&reading, "nvidia_utilization_gpu"), Some(42.0));
+        assert_eq!(value(&reading, "nvidia_clocks_gr"), Some(921.0));
+        assert_eq!(value(&reading, "nvidia_utilization_memory"), Some(3.0));
+        assert_eq!(value(&reading, "nvidia_clocks_mem"), Some(1600.0));
+        assert_eq!(value(&reading, "nvidia_temperature_gpu"), Some(32.0));
+        assert_eq!(value(&reading, "nvidia_power_draw"), Some(0.412));
+        assert_eq!(reading.temperatures.len(), 6);
+        assert_eq!(
+            reading.rails,
+            [
+                ("POM_5V_IN".to_string(), 2.135),
+                ("POM_5V_GPU".to_string(), 0.412),
+                ("POM_5V_CPU".to_string(), 0.49),
+            ]
+        );
+    }
+
+    /// Jetson AGX Orin, L4T 35.4: rails in mW with the unit, a clock per GPC, absent sensors at
+    /// -256C and unconnected rails.
+    #[test]
+    fn parses_orin() {
+        let line = "RAM 2448/62841MB (lfb 14616x4MB) SWAP 0/31421MB (cached 0MB) \
+            CPU [0%@729,0%@729,0%@729,0%@729,0%@729,0%@729,0%@729,0%@729,0%@729,0%@729,0%@729,0%@729] \
+            EMC_FREQ 0%@2133 GR3D_FREQ 17%@[305,305] VIC_FREQ 729 APE 174 CV0@-256C CPU@46.281C \
+            SOC2@43.781C SOC0@44.125C CV1@-256C GPU@45.125C tj@46.281C SOC1@43.468C CV2@-256C \
+            VDD_GPU_SOC 3206mW/3206mW VDD_CPU_CV 801mW/801mW VIN_SYS_5V0 4121mW/4121mW \
+            NC 0mW/0mW VDDQ_VDD2_1V8AO 503mW/503mW NC 0mW/0mW";
+        let reading = parse(line).unwrap();
+        assert_eq!(value(&reading, "nvidia_memory_total"), Some(62841.0));
+        assert_eq!(value(&reading, "nvidia_utilization_gpu"), Some(17.0));
+        assert_eq!(value(&reading, "nvidia_clocks_gr"), Some(305.0));
+        assert_eq!(value(&reading, "nvidia_utilization_memory"), Some(0.0));
+        assert_eq!(value(&reading, "nvidia_clocks_mem"), Some(2133.0));
+        assert_eq!(value(&reading, "nvidia_temperature_gpu"), Some(45.125));
+        assert_eq!(value(&reading, "nvidia_power_draw"), Some(3.206));
+        // CV 引擎不存在的传感器不导出
+        assert!(!reading
+            .temperatures
+            .iter()
+            .any(|(sensor, _)| sensor.starts_with("CV")));
+        assert_eq!(reading.temperatures.len(), 6);
+        let rails = reading
+            .rails
+            .iter()
+            .map(|(rail, _)| rail.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rails,
+            [
+                "VDD_GPU_SOC",
+                "VDD_CPU_CV",
+                "VIN_SYS_5V0",
+                "VDDQ_VDD2_1V8AO"
+            ]
+        );
+    }
+
+    /// Jetson TX2, L4T 28: `GR3D` and `EMC` without `_FREQ`, and an engine that is off.
+    #[test]
+    fn parses_tx2() {
+        let line = "RAM 1858/7854MB (lfb 1012x4MB) CPU [1%@345,off,off,0%@345,0%@345,0%@345] \
+            EMC 2%@1600 APE 150 GR3D 0% BCPU@34.5C MCPU@34.5C GPU@32.5C PLL@34.5C \
+            Tboard@30C Tdiode@34.25C PMIC@100C thermal@33.5C";
+        let reading = parse(line).unwrap();
+        assert_eq!(value(&reading, "nvidia_utilization_gpu"), Some(0.0));
+        assert_eq!(value(&reading, "nvidia_clocks_gr"), None);
+        assert_eq!(value(&reading, "nvidia_clocks_mem"), Some(1600.0));
+        assert_eq!(value(&reading, "nvidia_temperature_gpu"), Some(32.5));
+        assert_eq!(value(&reading, "nvidia_power_draw"), None);
+        assert!(reading.rails.is_empty());
+    }
+
+    #[test]
+    fn skips_malformed_lines() {
+        for line in [
+            "",
+            "Error: failed to open /sys/kernel/debug/bpmp/debug/clk/emc/rate",
+            "RAM x/yMB GR3D_FREQ %@ EMC_FREQ @[ GPU@C VDD_GPU_SOC mW/ NC 1/1",
+        ] {
+            assert!(parse(line).is_none(), "{:?}", line);
+        }
+    }
+}
@@ -0,0 +1,529 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::TcpStream;
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::{json, Map};
+use std::convert::TryInto;
+use std::sync::Mutex;
+use std::time::{Duration, UNIX_EPOCH};
+use surf::Url;
+
+use crate::cli::Cli;
+use crate::push::{Batch, Target};
+use crate::remotewrite::varint;
+
+#[derive(Clone, Copy, Debug, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Json,
+    Avro,
+}
+
+const CLIENT_ID: &str = "nvidia-smi-exporter";
+const METADATA: i16 = 3;
+const PRODUCE: i16 = 0;
+/// Wait for all in-sync replicas.
+const ACKS: i16 = -1;
+const PRODUCE_TIMEOUT_MS: i32 = 10_000;
+
+/// Schema of the Avro records, registered as `<topic>-value`.
+const AVRO_SCHEMA: &str = r#"{"type":"record","name":"Sample","namespace":"nvidia_smi_exporter","fields":[{"name":"name","type":"string"},{"name":"labels","type":{"type":"map","values":"string"}},{"name":"value","type":"double"},{"name":"timestamp","type":{"type":"long","logicalType":"timestamp-millis"}}]}"#;
+
+/// A Kafka cluster (`--push.kafka.brokers`), sent a record per sample. Looks up the partition
+/// leader and connects for each push, so leader changes need no handling.
+pub struct Kafka {
+    brokers: Vec<String>,
+    topic: String,
+    format: Format,
+    schema_registry: Option<Url>,
+    /// Avro schema id from the registry, once registered.
+    schema_id: Mutex<Option<u32>>,
+    /// Record key, also picks the partition.
+    key: String,
+    /// `job` and `instance`, added to samples that do not have them.
+    labels: Vec<(String, String)>,
+    interval: Option<Duration>,
+}
+
+impl Kafka {
+    pub fn new(brokers: &[String], cli: &Cli, labels: Vec<(String, String)>) -> Result<Self> {
+        let schema_registry = match (&cli.kafka_format, &cli.kafka_schema_registry_url) {
+            (Format::Avro, None) => {
+                bail!("--push.kafka.format avro needs --push.kafka.schema-registry-url")
+            }
+            (_, Some(url)) => Some(
+                Url::parse(url)
+                    .with_context(|| format!("Invalid --push.kafka.schema-registry-url {}", url))?,
+            ),
+            _ => None,
+        };
+        let key = labels
+            .iter()
+            .find(|(name, _)| name == "instance")
+            .map_or_else(String::new, |(_, value)| value.clone());
+        Ok(Kafka {
+            brokers: brokers.to_vec(),
+            topic: cli.kafka_topic.clone(),
+            format: cli.kafka_format,
+            schema_registry,
+            schema_id: Mutex::new(None),
+            key,
+            labels,
+            interval: cli.kafka_interval.map(Duration::from_secs),
+        })
+    }
+
+    /// The record values, one per sample with a finite value.
+    async fn values(&self, batch: &Batch) -> Result<Vec<Vec<u8>>> {
+        let timestamp = batch.timestamp.duration_since(UNIX_EPOCH)?.as_millis() as i64;
+        let schema_id = match self.format {
+            Format::Json => None,
+            Format::Avro => Some(self.schema_id().await?),
+        };
+        let mut values = Vec::new();
+        for sample in batch.samples.iter().filter(|s| s.value.is_finite()) {
+            let mut labels = sample.labels.clone();
+            for (name, value) in &self.labels {
+                if !labels.iter().any(|(n, _)| n == name) {
+                    labels.push((name.clone(), value.clone()));
+                }
+            }
+            let value = match schema_id {
+                None => {
+                    let labels = labels
+                        .into_iter()
+                        .map(|(name, value)| (name, value.into()))
+                        .collect::<Map<_, _>>();
+                    json!({
+                        "name": sample.name,
+                        "labels": labels,
+                        "value": sample.value,
+                        "timestamp": timestamp,
+                    })
+                    .to_string()
+                    .into_bytes()
+                }
+                // Confluent 格式：0、4 字节 schema id，然后是 Avro 二进制
+                Some(id) => {
+                    let mut buf = vec![0];
+                    buf.extend_from_slice(&id.to_be_bytes());
+                    avro_string(&mut buf, &sample.name);
+                    if !labels.is_empty() {
+                        zigzag(&mut buf, labels.len() as i64);
+                        for (name, value) in &labels {
+                            avro_string(&mut buf, name);
+                            avro_string(&mut buf, value);
+                        }
+                    }
+                    zigzag(&mut buf, 0);
+                    buf.extend_from_slice(&sample.value.to_le_bytes());
+                    zigzag(&mut buf, timestamp);
+                    buf
+                }
+            };
+            values.push(value);
+        }
+        Ok(values)
+    }
+
+    /// Registers the Avro schema under `<topic>-value` the first time; the registry returns the
+    /// existing id when it already has the schema.
+    async fn schema_id(&self) -> Result<u32> {
+        if let Some(id) = *self.schema_id.lock().unwrap() {
+            return Ok(id);
+        }
+        let registry = self
+            .schema_registry
+            .as_ref()
+            .ok_or_else(|| anyhow!("No schema registry"))?;
+        let url = registry
+            .join(&format!("subjects/{}-value/versions", self.topic))
+            .map_err(|e| anyhow!("Invalid schema registry URL: {}", e))?;
+        let mut response = surf::post(url)
+            .header("Content-Type", "application/vnd.schemaregistry.v1+json")
+            .body(json!({ "schema": AVRO_SCHEMA }).to_string())
+            .await
+            .map_err(|e| e.into_inner())
+            .context("Failed to register the Avro schema")?;
+        let body = response.body_string().await.map_err(|e| e.into_inner())?;
+        if !response.status().is_success() {
+            bail!(
+                "Schema registry returned {} {}",
+                response.status(),
+                body.trim()
+            );
+        }
+        let id = serde_json::from_str::<serde_json::Value>(&body)?["id"]
+            .as_u64()
+            .ok_or_else(|| anyhow!("No id in the schema registry's response {}", body.trim()))?
+            as u32;
+        *self.schema_id.lock().unwrap() = Some(id);
+        Ok(id)
+    }
+
+    /// The partition for this exporter's records and the address of its leader, from the first
+    /// broker that answers.
+    async fn leader(&self) -> Result<(i32, String)> {
+        let mut last_error = anyhow!("No --push.kafka.brokers");
+        for broker in &self.brokers {
+            match self.metadata(broker).await {
+                Ok(leader) => return Ok(leader),
+                Err(e) => last_error = e.context(format!("Metadata from {} failed", broker)),
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn metadata(&self, broker: &str) -> Result<(i32, String)> {
+        let mut stream = TcpStream::connect(broker).await?;
+        // Metadata v1: topics
+        let mut body = Vec::new();
+        body.extend_from_slice(&1i32.to_be_bytes());
+        kafka_string(&mut body, &self.topic);
+        let response = request(&mut stream, METADATA, 1, &body).await?;
+
+        let mut r = Reader(&response);
+        let mut brokers = Vec::new();
+        for _ in 0..r.i32()? {
+            let node_id = r.i32()?;
+            let host = r.string()?;
+            let port = r.i32()?;
+            r.string()?; // rack
+            brokers.push((node_id, format!("{}:{}", host, port)));
+        }
+        r.i32()?; // controller id
+        let mut partitions = Vec::new();
+        for _ in 0..r.i32()? {
+            let error = r.i16()?;
+            let name = r.string()?;
+            r.take(1)?; // is internal
+            if error != 0 {
+                bail!("Topic {}: {}", name, error_name(error));
+            }
+            for _ in 0..r.i32()? {
+                let error = r.i16()?;
+                let partition = r.i32()?;
+                let leader = r.i32()?;
+                for _ in 0..2 {
+                    // replicas, in-sync replicas
+                    let n = r.i32()?;
+                    r.take(4 * n.max(0) as usize)?;
+                }
+                if name == self.topic {
+                    partitions.push((partition, leader, error));
+                }
+            }
+        }
+        if partitions.is_empty() {
+            bail!("Topic {} has no partitions", self.topic);
+        }
+        partitions.sort_unstable();
+        let (partition, leader, error) =
+            partitions[crc32c(self.key.as_bytes()) as usize % partitions.len()];
+        if error != 0 {
+            bail!("Partition {}: {}", partition, error_name(error));
+        }
+        let address = brokers
+            .into_iter()
+            .find(|(node_id, _)| *node_id == leader)
+            .map(|(_, address)| address)
+            .ok_or_else(|| anyhow!("Leader {} of partition {} is unknown", leader, partition))?;
+        Ok((partition, address))
+    }
+
+    /// A v2 record batch of uncompressed records with the exporter's key.
+    fn record_batch(&self, values: &[Vec<u8>], timestamp: i64) -> Vec<u8> {
+        // attributes 之后的部分，CRC 只覆盖这些
+        let mut batch = Vec::new();
+        batch.extend_from_slice(&0i16.to_be_bytes()); // attributes
+        batch.extend_from_slice(&(values.len() as i32 - 1).to_be_bytes()); // last offset delta
+        batch.extend_from_slice(&timestamp.to_be_bytes()); // base timestamp
+        batch.extend_from_slice(&timestamp.to_be_bytes()); // max timestamp
+        batch.extend_from_slice(&(-1i64).to_be_bytes()); // producer id
+        batch.extend_from_slice(&(-1i16).to_be_bytes()); // producer epoch
+        batch.extend_from_slice(&(-1i32).to_be_bytes()); // base sequence
+        batch.extend_from_slice(&(values.len() as i32).to_be_bytes());
+        for (i, value) in values.iter().enumerate() {
+            let mut record = vec![0]; // attributes
+            zigzag(&mut record, 0); // timestamp delta
+            zigzag(&mut record, i as i64); // offset delta
+            zigzag(&mut record, self.key.len() as i64);
+            record.extend_from_slice(self.key.as_bytes());
+            zigzag(&mut record, value.len() as i64);
+            record.extend_from_slice(value);
+            zigzag(&mut record, 0); // headers
+            zigzag(&mut batch, record.len() as i64);
+            batch.extend_from_slice(&record);
+        }
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&0i64.to_be_bytes()); // base offset
+        buf.extend_from_slice(&(batch.len() as i32 + 9).to_be_bytes()); // length
+        buf.extend_from_slice(&(-1i32).to_be_bytes()); // partition leader epoch
+        buf.push(2); // magic
+        buf.extend_from_slice(&crc32c(&batch).to_be_bytes());
+        buf.extend_from_slice(&batch);
+        buf
+    }
+}
+
+#[tide::utils::async_trait]
+impl Target for Kafka {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    async fn push(&self, batch: &Batch) -> Result<()> {
+        let values = self.values(batch).await?;
+        if values.is_empty() {
+            return Ok(());
+        }
+        let timestamp = batch.timestamp.duration_since(UNIX_EPOCH)?.as_millis() as i64;
+        let records = self.record_batch(&values, timestamp);
+        let (partition, leader) = self.leader().await?;
+
+        // Produce v3: transactional id, acks, timeout, topics
+        let mut body = Vec::new();
+        body.extend_from_slice(&(-1i16).to_be_bytes());
+        body.extend_from_slice(&ACKS.to_be_bytes());
+        body.extend_from_slice(&PRODUCE_TIMEOUT_MS.to_be_bytes());
+        body.extend_from_slice(&1i32.to_be_bytes());
+        kafka_string(&mut body, &self.topic);
+        body.extend_from_slice(&1i32.to_be_bytes());
+        body.extend_from_slice(&partition.to_be_bytes());
+        body.extend_from_slice(&(records.len() as i32).to_be_bytes());
+        body.extend_from_slice(&records);
+        let mut stream = TcpStream::connect(&leader)
+            .await
+            .with_context(|| format!("Failed to connect to {}", leader))?;
+        let response = request(&mut stream, PRODUCE, 3, &body).await?;
+
+        let mut r = Reader(&response);
+        for _ in 0..r.i32()? {
+            r.string()?;
+            for _ in 0..r.i32()? {
+                let partition = r.i32()?;
+                let error = r.i16()?;
+                r.take(16)?; // base offset, log append time
+                if error != 0 {
+                    bail!(
+                        "Producing to {}-{} failed: {}",
+                        self.topic,
+                        partition,
+                        error_name(error)
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sends a request with header v1 and returns the response after its header.
+async fn request(
+    stream: &mut TcpStream,
+    api_key: i16,
+    version: i16,
+    body: &[u8],
+) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&api_key.to_be_bytes());
+    buf.extend_from_slice(&version.to_be_bytes());
+    buf.extend_from_slice(&1i32.to_be_bytes()); // correlation id
+    kafka_string(&mut buf, CLIENT_ID);
+    buf.extend_from_slice(body);
+    stream.write_all(&(buf.len() as i32).to_be_bytes()).await?;
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).await?;
+    let len = i32::from_be_bytes(len);
+    if !(4..=64 << 20).contains(&len) {
+        bail!("Invalid Kafka response length {}", len);
+    }
+    let mut response = vec![0u8; len as usize];
+    stream.read_exact(&mut response).await?;
+    Ok(response.split_off(4))
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            bail!("Truncated Kafka response");
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn i16(&mut self) -> Result<i16> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    /// A nullable string; null is empty.
+    fn string(&mut self) -> Result<String> {
+        let len = self.i16()?;
+        Ok(String::from_utf8_lossy(self.take(len.max(0) as usize)?).into_owned())
+    }
+}
+
+fn kafka_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as i16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// A zigzag varint, as in both record batches and Avro.
+fn zigzag(buf: &mut Vec<u8>, n: i64) {
+    varint(buf, ((n << 1) ^ (n >> 63)) as u64);
+}
+
+fn avro_string(buf: &mut Vec<u8>, s: &str) {
+    zigzag(buf, s.len() as i64);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// CRC-32C (Castagnoli) of record batches.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                crc >> 1 ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn error_name(code: i16) -> String {
+    match code {
+        3 => "UNKNOWN_TOPIC_OR_PARTITION".to_string(),
+        5 => "LEADER_NOT_AVAILABLE".to_string(),
+        6 => "NOT_LEADER_OR_FOLLOWER".to_string(),
+        7 => "REQUEST_TIMED_OUT".to_string(),
+        10 => "MESSAGE_TOO_LARGE".to_string(),
+        17 => "INVALID_TOPIC_EXCEPTION".to_string(),
+        19 => "NOT_ENOUGH_REPLICAS".to_string(),
+        20 => "NOT_ENOUGH_REPLICAS_AFTER_APPEND".to_string(),
+        29 => "TOPIC_AUTHORIZATION_FAILED".to_string(),
+        87 => "INVALID_RECORD".to_string(),
+        code => format!("error code {}", code),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::push::Sample;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let digits = s.split_whitespace().collect::<String>();
+        (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn kafka(format: Format) -> Kafka {
+        Kafka {
+            brokers: Vec::new(),
+            topic: "nvidia".to_string(),
+            format,
+            schema_registry: None,
+            schema_id: Mutex::new(Some(7)),
+            key: "node1".to_string(),
+            labels: vec![("instance".to_string(), "node1".to_string())],
+            interval: None,
+        }
+    }
+
+    fn batch() -> Batch {
+        let sample = |labels: &[(&str, &str)], value| Sample {
+            name: "up".to_string(),
+            labels: labels
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+            value,
+        };
+        Batch {
+            exposition: String::new(),
+            samples: vec![
+                sample(&[("gpu", "0")], 1.0),
+                sample(&[("instance", "other")], -2.5),
+                sample(&[], f64::NAN),
+            ],
+            timestamp: UNIX_EPOCH + Duration::from_millis(1_700_000_000_500),
+        }
+    }
+
+    #[async_std::test]
+    async fn encodes_avro_values() {
+        let values = kafka(Format::Avro).values(&batch()).await.unwrap();
+        // magic 0、schema id、name、labels（一个块加结束的 0）、value、timestamp
+        assert_eq!(
+            values,
+            [
+                hex("00 00000007 04 7570
+                     04 06 677075 02 30 10 696e7374616e6365 0a 6e6f646531 00
+                     000000000000f03f e8a7abfef962"),
+                hex("00 00000007 04 7570
+                     02 10 696e7374616e6365 0a 6f74686572 00
+                     00000000000004c0 e8a7abfef962"),
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn encodes_json_values() {
+        let values = kafka(Format::Json).values(&batch()).await.unwrap();
+        assert_eq!(
+            String::from_utf8(values[0].clone()).unwrap(),
+            r#"{"labels":{"gpu":"0","instance":"node1"},"name":"up","timestamp":1700000000500,"value":1.0}"#
+        );
+        assert_eq!(values.len(), 2);
+    }
+
+    #[test]
+    fn encodes_record_batch() {
+        let batch = kafka(Format::Json).record_batch(&[b"v".to_vec()], 1000);
+        assert_eq!(
+            batch,
+            hex("0000000000000000 0000003e ffffffff 02 0945cd6c
+                 0000 00000000 00000000000003e8 00000000000003e8
+                 ffffffffffffffff ffff ffffffff 00000001
+                 18 00 00 00 0a 6e6f646531 02 76 00")
+        );
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(b""), 0);
+        for (n, expected) in [
+            (0, &[0x00][..]),
+            (-1, &[0x01]),
+            (1, &[0x02]),
+            (-64, &[0x7f]),
+        ] {
+            let mut buf = Vec::new();
+            zigzag(&mut buf, n);
+            assert_eq!(buf, expected, "{}", n);
+        }
+    }
+}
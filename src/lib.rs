@@ -0,0 +1,128 @@
+//! Reading NVIDIA GPUs with `nvidia-smi` and rendering them in the Prometheus text format, as
+//! the nvidia-smi-exporter binary does, for services that embed the collection.
+
+use anyhow::{anyhow, bail, Result};
+use async_std::future::timeout;
+use async_std::process::{Command, Stdio};
+use std::time::Duration;
+
+pub mod exposition;
+pub mod gpu;
+
+/// One GPU as read by one collection, with a value for each field it reported.
+pub use gpu::Gpu as GpuSnapshot;
+
+/// A group of `--query-gpu` fields, turned on and off together by the exporter's
+/// `--[no-]collector.<name>`.
+#[derive(Debug)]
+pub struct Collector {
+    pub name: &'static str,
+    pub enabled_by_default: bool,
+    /// nvidia-smi field and the metric it is exported as.
+    pub fields: &'static [(&'static str, &'static str)],
+}
+
+/// Every collector, in the order their metrics are rendered.
+pub const COLLECTORS: &[Collector] = &[
+    Collector {
+        name: "fan",
+        enabled_by_default: true,
+        fields: &[("fan.speed", "nvidia_fan_speed")],
+    },
+    Collector {
+        name: "temperature",
+        enabled_by_default: true,
+        fields: &[("temperature.gpu", "nvidia_temperature_gpu")],
+    },
+    Collector {
+        name: "clocks",
+        enabled_by_default: true,
+        fields: &[
+            ("clocks.gr", "nvidia_clocks_gr"),
+            ("clocks.sm", "nvidia_clocks_sm"),
+            ("clocks.mem", "nvidia_clocks_mem"),
+        ],
+    },
+    Collector {
+        name: "power",
+        enabled_by_default: true,
+        fields: &[("power.draw", "nvidia_power_draw")],
+    },
+    Collector {
+        name: "utilization",
+        enabled_by_default: true,
+        fields: &[
+            ("utilization.gpu", "nvidia_utilization_gpu"),
+            ("utilization.memory", "nvidia_utilization_memory"),
+        ],
+    },
+    Collector {
+        name: "memory",
+        enabled_by_default: true,
+        fields: &[
+            ("memory.total", "nvidia_memory_total"),
+            ("memory.free", "nvidia_memory_free"),
+            ("memory.used", "nvidia_memory_used"),
+        ],
+    },
+];
+
+/// The collectors the exporter enables unless told otherwise.
+pub fn default_collectors() -> Vec<&'static Collector> {
+    COLLECTORS.iter().filter(|c| c.enabled_by_default).collect()
+}
+
+/// The fields of `collectors`, with the metrics they are exported as.
+pub fn fields(collectors: &[&Collector]) -> Vec<(&'static str, &'static str)> {
+    collectors
+        .iter()
+        .flat_map(|c| c.fields.iter().copied())
+        .collect()
+}
+
+/// Runs `nvidia-smi` from `PATH` once for the fields of `collectors`, giving up after
+/// `collect_timeout`. Unlike the exporter, there is no SSH, WSL2 lookup or retry without fields
+/// the driver does not know.
+pub async fn collect(
+    collectors: &[&Collector],
+    collect_timeout: Duration,
+) -> Result<Vec<GpuSnapshot>> {
+    let fields = fields(collectors);
+    let output = Command::new("nvidia-smi")
+        .args(gpu::query_args(&fields, &[]))
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = timeout(collect_timeout, output)
+        .await
+        .map_err(|_| anyhow!("nvidia-smi timed out after {:?}", collect_timeout))??;
+    if !output.status.success() {
+        bail!(
+            "nvidia-smi exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    gpu::parse_csv(&output.stdout, &fields)
+}
+
+/// The samples of `gpus` for the fields of `collectors`, grouped by metric and labelled with the
+/// GPU's index and name (and host, if any), like the exporter's without relabeling or pod labels.
+pub fn render_prometheus(gpus: &[GpuSnapshot], collectors: &[&Collector]) -> String {
+    let mut buffer = String::new();
+    for (_, metric) in fields(collectors) {
+        for gpu in gpus {
+            if let Some(value) = gpu.value(metric) {
+                let mut labels = vec![
+                    ("gpu".to_string(), gpu.index.clone()),
+                    ("name".to_string(), gpu.name.clone()),
+                ];
+                if let Some(host) = &gpu.host {
+                    labels.push(("host".to_string(), host.clone()));
+                }
+                exposition::sample(&mut buffer, metric, &labels, value);
+            }
+        }
+    }
+    buffer
+}
@@ -0,0 +1,598 @@
+use anyhow::{bail, Context, Result};
+use async_io::Timer;
+use async_lock::Semaphore;
+use async_rustls::TlsAcceptor;
+use async_std::io::{self, Read, Write};
+use async_std::net::TcpListener;
+use async_std::os::unix::net::UnixListener as AsyncUnixListener;
+use async_std::prelude::*;
+use async_std::task;
+use socket2::{Domain, Type};
+use std::fmt::{self, Debug, Display, Formatter};
+use std::net::ToSocketAddrs;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use tide::listener::{ListenInfo, Listener, ToListener};
+use tide::Server;
+use tracing::{debug, error};
+
+use crate::tls::TlsServerConfig;
+
+/// sd_listen_fds(3): passed sockets start at fd 3.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// A bound socket, so privileges can be dropped before serving.
+pub enum Endpoint {
+    Tcp(std::net::TcpListener),
+    Unix(UnixListener),
+}
+
+impl Endpoint {
+    /// Parses a `--listen` value: `host:port`, or `unix:/path/to.sock` for a Unix socket.
+    /// `reuse_port` binds TCP addresses with `SO_REUSEPORT`.
+    pub fn parse(addr: &str, reuse_port: bool) -> Result<Self> {
+        let path = match addr.strip_prefix("unix:") {
+            Some(path) => Path::new(path),
+            None => {
+                let socket = match reuse_port {
+                    true => bind_reuse_port(addr),
+                    false => std::net::TcpListener::bind(addr),
+                }
+                .with_context(|| format!("Failed to bind {}", addr))?;
+                return Ok(Endpoint::Tcp(socket));
+            }
+        };
+        // 上次退出时留下的 socket 文件会导致 bind 失败
+        if let Ok(meta) = std::fs::symlink_metadata(path) {
+            if meta.file_type().is_socket() {
+                std::fs::remove_file(path)
+                    .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+            }
+        }
+        let socket = UnixListener::bind(path)
+            .with_context(|| format!("Failed to bind {}", path.display()))?;
+        Ok(Endpoint::Unix(socket))
+    }
+}
+
+/// Binds the first address `addr` resolves to that can be bound, like `TcpListener::bind`, with
+/// `SO_REUSEPORT`: the kernel spreads connections over every socket bound so, e.g. of the old and
+/// the new exporter during an upgrade. FreeBSD needs `SO_REUSEPORT_LB` for that.
+fn bind_reuse_port(addr: &str) -> std::io::Result<std::net::TcpListener> {
+    let mut last_error = None;
+    for addr in addr.to_socket_addrs()? {
+        let socket = socket2::Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        #[cfg(target_os = "freebsd")]
+        socket.set_reuse_port_lb(true)?;
+        #[cfg(not(target_os = "freebsd"))]
+        socket.set_reuse_port(true)?;
+        match socket.bind(&addr.into()).and_then(|()| socket.listen(128)) {
+            Ok(()) => return Ok(socket.into()),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        )
+    }))
+}
+
+/// Takes the sockets passed by systemd socket activation, if the exporter was started that way.
+pub fn systemd_endpoints() -> Result<Vec<Endpoint>> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    let (pid, fds) = match (pid, fds) {
+        (Some(pid), Some(fds)) => (pid, fds),
+        _ => return Ok(Vec::new()),
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(Vec::new());
+    }
+    let fds: RawFd = fds.parse().with_context(|| "Invalid LISTEN_FDS")?;
+
+    let mut endpoints = Vec::new();
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds {
+        unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+        let endpoint = match socket_family(fd)? {
+            libc::AF_INET | libc::AF_INET6 => {
+                Endpoint::Tcp(unsafe { std::net::TcpListener::from_raw_fd(fd) })
+            }
+            libc::AF_UNIX => Endpoint::Unix(unsafe { UnixListener::from_raw_fd(fd) }),
+            family => bail!("Unsupported socket family {} on fd {}", family, fd),
+        };
+        endpoints.push(endpoint);
+    }
+    Ok(endpoints)
+}
+
+fn socket_family(fd: RawFd) -> Result<libc::c_int> {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ret =
+        unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("fd {} passed by systemd is not a socket", fd));
+    }
+    Ok(addr.ss_family as libc::c_int)
+}
+
+/// Connection handling shared by every listener.
+#[derive(Clone)]
+pub struct ConnectionOptions {
+    /// A read waiting longer than this for data closes the connection.
+    pub idle_timeout: Duration,
+    /// Accepting pauses while all permits are held; `None` is unlimited.
+    pub max_connections: Option<Arc<Semaphore>>,
+}
+
+/// One listener per endpoint, wrapping TCP sockets in TLS when configured.
+pub fn listeners<State>(
+    endpoints: Vec<Endpoint>,
+    tls: Option<&TlsServerConfig>,
+    options: ConnectionOptions,
+) -> Result<Vec<HttpListener<State>>> {
+    let tls = match tls {
+        Some(tls) => Some(TlsAcceptor::from(Arc::new(tls.build()?))),
+        None => None,
+    };
+    endpoints
+        .into_iter()
+        .map(|endpoint| {
+            if matches!(endpoint, Endpoint::Unix(_)) && tls.is_some() {
+                bail!("TLS is not supported on unix sockets");
+            }
+            Ok(HttpListener {
+                endpoint: Some(endpoint),
+                socket: None,
+                tls: tls.clone(),
+                options: options.clone(),
+                server: None,
+                info: None,
+            })
+        })
+        .collect()
+}
+
+enum Socket {
+    Tcp(TcpListener),
+    Unix(AsyncUnixListener),
+}
+
+/// Replaces tide's own TCP/Unix listeners so idle timeouts and the connection cap can be applied.
+pub struct HttpListener<State> {
+    endpoint: Option<Endpoint>,
+    socket: Option<Socket>,
+    tls: Option<TlsAcceptor>,
+    options: ConnectionOptions,
+    server: Option<Server<State>>,
+    info: Option<ListenInfo>,
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Listener<State> for HttpListener<State> {
+    async fn bind(&mut self, server: Server<State>) -> io::Result<()> {
+        self.server = Some(server);
+        let socket = match self
+            .endpoint
+            .take()
+            .expect("`bind` should only be called once")
+        {
+            Endpoint::Tcp(socket) => Socket::Tcp(socket.into()),
+            Endpoint::Unix(socket) => Socket::Unix(socket.into()),
+        };
+        let info = match &socket {
+            Socket::Tcp(socket) => {
+                let scheme = if self.tls.is_some() { "https" } else { "http" };
+                let conn = format!("{}://{}", scheme, socket.local_addr()?);
+                ListenInfo::new(conn, "tcp".to_string(), self.tls.is_some())
+            }
+            Socket::Unix(socket) => {
+                let addr = socket.local_addr()?;
+                let path = addr.as_pathname().map(|p| p.display().to_string());
+                let conn = format!("http+unix://{}", path.unwrap_or_default());
+                ListenInfo::new(conn, "uds".to_string(), false)
+            }
+        };
+        self.info = Some(info);
+        self.socket = Some(socket);
+        Ok(())
+    }
+
+    async fn accept(&mut self) -> io::Result<()> {
+        let server = self
+            .server
+            .take()
+            .expect("`Listener::bind` must be called before `Listener::accept`");
+        let socket = self
+            .socket
+            .take()
+            .expect("`Listener::bind` must be called before `Listener::accept`");
+        loop {
+            let permit = match &self.options.max_connections {
+                Some(semaphore) => Some(semaphore.acquire_arc().await),
+                None => None,
+            };
+            let accepted = match &socket {
+                Socket::Tcp(socket) => socket.accept().await.map(|(stream, peer)| {
+                    let local = stream.local_addr().ok();
+                    let stream = IdleTimeout::new(stream, self.options.idle_timeout);
+                    (
+                        Connection::Tcp(stream),
+                        local.map(|a| a.to_string()),
+                        Some(peer.to_string()),
+                    )
+                }),
+                Socket::Unix(socket) => socket.accept().await.map(|(stream, _)| {
+                    let stream = IdleTimeout::new(stream, self.options.idle_timeout);
+                    (Connection::Unix(stream), None, None)
+                }),
+            };
+            let (connection, local, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) if is_transient_error(&e) => continue,
+                Err(e) => {
+                    let delay = Duration::from_millis(500);
+                    error!(
+                        "Failed to accept connection, {}. Pausing for {:?}",
+                        e, delay
+                    );
+                    task::sleep(delay).await;
+                    continue;
+                }
+            };
+            let server = server.clone();
+            let tls = self.tls.clone();
+            task::spawn(async move {
+                let _permit = permit;
+                match (connection, tls) {
+                    (Connection::Tcp(stream), Some(tls)) => match tls.accept(stream).await {
+                        Ok(stream) => {
+                            let stream = async_dup::Arc::new(async_dup::Mutex::new(stream));
+                            serve(server, stream, local, peer).await
+                        }
+                        Err(e) => debug!("TLS handshake with {:?} failed, {}", peer, e),
+                    },
+                    (Connection::Tcp(stream), None) => serve(server, stream, local, peer).await,
+                    (Connection::Unix(stream), _) => serve(server, stream, local, peer).await,
+                }
+            });
+        }
+    }
+
+    fn info(&self) -> Vec<ListenInfo> {
+        self.info.iter().cloned().collect()
+    }
+}
+
+impl<State: Clone + Send + Sync + 'static> ToListener<State> for HttpListener<State> {
+    type Listener = Self;
+
+    fn to_listener(self) -> io::Result<Self::Listener> {
+        Ok(self)
+    }
+}
+
+impl<State> Debug for HttpListener<State> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpListener")
+            .field("info", &self.info)
+            .field("tls", &self.tls.is_some())
+            .finish()
+    }
+}
+
+impl<State> Display for HttpListener<State> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.info {
+            Some(info) => write!(f, "{}", info),
+            None => write!(f, "(not bound)"),
+        }
+    }
+}
+
+enum Connection {
+    Tcp(IdleTimeout<async_std::net::TcpStream>),
+    Unix(IdleTimeout<async_std::os::unix::net::UnixStream>),
+}
+
+#[cfg(not(feature = "hyper"))]
+async fn serve<State, RW>(
+    server: Server<State>,
+    io: RW,
+    local: Option<String>,
+    peer: Option<String>,
+) where
+    State: Clone + Send + Sync + 'static,
+    RW: Read + Write + Clone + Send + Sync + Unpin + 'static,
+{
+    let result = async_h1::accept(io, |mut req| async {
+        req.set_local_addr(local.as_ref());
+        req.set_peer_addr(peer.as_ref());
+        server.respond(req).await
+    })
+    .await;
+    if let Err(e) = result {
+        debug!("Connection from {:?} closed, {}", peer, e);
+    }
+}
+
+/// Serves the connection with hyper, handing each request to tide as an http-types request.
+#[cfg(feature = "hyper")]
+async fn serve<State, RW>(
+    server: Server<State>,
+    io: RW,
+    local: Option<String>,
+    peer: Option<String>,
+) where
+    State: Clone + Send + Sync + 'static,
+    RW: Read + Write + Clone + Send + Sync + Unpin + 'static,
+{
+    let service = hyper::service::service_fn(|req| respond(&server, req, &local, &peer));
+    let result = hyper::server::conn::http1::Builder::new()
+        .serve_connection(HyperIo(io), service)
+        .await;
+    if let Err(e) = result {
+        debug!("Connection from {:?} closed, {}", peer, e);
+    }
+}
+
+/// Request bodies hyper reads for tide, which reads them only when a handler asks; no endpoint
+/// takes more than a few bytes.
+#[cfg(feature = "hyper")]
+const MAX_REQUEST_BODY: usize = 64 * 1024;
+
+#[cfg(feature = "hyper")]
+async fn respond<State: Clone + Send + Sync + 'static>(
+    server: &Server<State>,
+    req: hyper::Request<hyper::body::Incoming>,
+    local: &Option<String>,
+    peer: &Option<String>,
+) -> Result<hyper::Response<ResponseBody>> {
+    use http_body_util::{BodyExt, LengthLimitError, Limited};
+    use std::str::FromStr;
+    use tide::http::{Method, Request, StatusCode, Url, Version};
+
+    let (parts, body) = req.into_parts();
+    // async-h1 同样用 Host 头拼出绝对 URL
+    let host = parts
+        .headers
+        .get(hyper::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or("localhost");
+    let path = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+    let url = Url::parse(&format!("http://{}{}", host, path))?;
+    let method = Method::from_str(parts.method.as_str()).map_err(|e| e.into_inner())?;
+    let mut request = Request::new(method, url);
+    for (name, value) in &parts.headers {
+        // http-types 只收 ASCII 的值
+        if let Ok(value) = value.to_str() {
+            request.append_header(name.as_str(), value);
+        }
+    }
+    request.set_version(match parts.version {
+        hyper::Version::HTTP_10 => Some(Version::Http1_0),
+        _ => Some(Version::Http1_1),
+    });
+    request.set_local_addr(local.as_ref());
+    request.set_peer_addr(peer.as_ref());
+    let body = match Limited::new(body, MAX_REQUEST_BODY).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) if e.is::<LengthLimitError>() => {
+            debug!(
+                "Request body from {:?} exceeds {} bytes",
+                peer, MAX_REQUEST_BODY
+            );
+            return Ok(hyper::Response::builder()
+                .status(u16::from(StatusCode::PayloadTooLarge))
+                .header(hyper::header::CONNECTION, "close")
+                .body(ResponseBody::new(tide::http::Body::empty()))?);
+        }
+        Err(e) => return Err(anyhow::anyhow!(e)),
+    };
+    request.set_body(body.to_vec());
+
+    let mut response: tide::http::Response =
+        server.respond(request).await.map_err(|e| e.into_inner())?;
+    let mut builder = hyper::Response::builder().status(u16::from(response.status()));
+    for (name, values) in response.iter() {
+        for value in values {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+    }
+    Ok(builder.body(ResponseBody::new(response.take_body()))?)
+}
+
+/// A tide response body sent as it is read, so that `/stream` delivers each event.
+#[cfg(feature = "hyper")]
+struct ResponseBody {
+    body: tide::http::Body,
+    buffer: Vec<u8>,
+    done: bool,
+}
+
+#[cfg(feature = "hyper")]
+impl ResponseBody {
+    fn new(body: tide::http::Body) -> Self {
+        ResponseBody {
+            done: body.is_empty() == Some(true),
+            body,
+            buffer: vec![0; 16 * 1024],
+        }
+    }
+}
+
+#[cfg(feature = "hyper")]
+impl hyper::body::Body for ResponseBody {
+    type Data = hyper::body::Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<io::Result<hyper::body::Frame<Self::Data>>>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        let this = &mut *self;
+        match Pin::new(&mut this.body).poll_read(cx, &mut this.buffer) {
+            Poll::Ready(Ok(0)) => {
+                this.done = true;
+                Poll::Ready(None)
+            }
+            Poll::Ready(Ok(n)) => {
+                let chunk = hyper::body::Bytes::copy_from_slice(&this.buffer[..n]);
+                Poll::Ready(Some(Ok(hyper::body::Frame::data(chunk))))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done
+    }
+
+    /// The length tide knows sets `Content-Length`; otherwise the body is chunked.
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        match self.body.len() {
+            Some(len) => hyper::body::SizeHint::with_exact(len as u64),
+            None => hyper::body::SizeHint::default(),
+        }
+    }
+}
+
+/// hyper's IO traits over async-std's, which tide's connections implement.
+#[cfg(feature = "hyper")]
+struct HyperIo<RW>(RW);
+
+#[cfg(feature = "hyper")]
+impl<RW: Read + Unpin> hyper::rt::Read for HyperIo<RW> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        mut buf: hyper::rt::ReadBufCursor<'_>,
+    ) -> Poll<io::Result<()>> {
+        let unfilled = unsafe { buf.as_mut() };
+        for byte in unfilled.iter_mut() {
+            byte.write(0);
+        }
+        // 上面已经全部初始化
+        let unfilled = unsafe { &mut *(unfilled as *mut [_] as *mut [u8]) };
+        let n = match Pin::new(&mut self.0).poll_read(cx, unfilled) {
+            Poll::Ready(Ok(n)) => n,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        };
+        unsafe { buf.advance(n) };
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "hyper")]
+impl<RW: Write + Unpin> hyper::rt::Write for HyperIo<RW> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_close(cx)
+    }
+}
+
+fn is_transient_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Fails a read that sees no data for `timeout`, closing idle keep-alive and trickling connections.
+struct IdleTimeout<S> {
+    inner: S,
+    timeout: Duration,
+    timer: Option<Timer>,
+}
+
+impl<S> IdleTimeout<S> {
+    fn new(inner: S, timeout: Duration) -> Self {
+        IdleTimeout {
+            inner,
+            timeout,
+            timer: None,
+        }
+    }
+}
+
+// async-h1 需要 Clone 的流，各个副本各自计时
+impl<S: Clone> Clone for IdleTimeout<S> {
+    fn clone(&self) -> Self {
+        IdleTimeout::new(self.inner.clone(), self.timeout)
+    }
+}
+
+impl<S: Read + Unpin> Read for IdleTimeout<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if let Poll::Ready(result) = Pin::new(&mut this.inner).poll_read(cx, buf) {
+            this.timer = None;
+            return Poll::Ready(result);
+        }
+        let timeout = this.timeout;
+        let timer = this.timer.get_or_insert_with(|| Timer::after(timeout));
+        match Pin::new(timer).poll(cx) {
+            Poll::Ready(_) => {
+                this.timer = None;
+                Poll::Ready(Err(io::ErrorKind::TimedOut.into()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: Write + Unpin> Write for IdleTimeout<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
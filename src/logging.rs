@@ -0,0 +1,312 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use file_rotate::compression::Compression;
+use file_rotate::suffix::{AppendCount, AppendTimestamp, DateFrom, FileLimit};
+use file_rotate::{ContentLimit, FileRotate, TimeFrequency};
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Mutex;
+use tracing::{Level, Subscriber};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// `--log-format`: human-readable lines, or one JSON object per line for log pipelines.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+/// `--log-output`: where logs go besides `--log-file`.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Output {
+    Stderr,
+    /// The local syslog daemon, facility daemon.
+    Syslog,
+    /// The systemd journal, with span and event fields as journal fields.
+    Journald,
+}
+
+/// When `--log-file` is rotated: `daily`, `hourly`, or once it exceeds a size such as `100MB`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rotation {
+    Daily,
+    Hourly,
+    Size(usize),
+}
+
+impl FromStr for Rotation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "daily" => return Ok(Rotation::Daily),
+            "hourly" => return Ok(Rotation::Hourly),
+            _ => {}
+        }
+        let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let unit = match &s[digits..] {
+            "" | "B" => 1,
+            "KB" => 1 << 10,
+            "MB" => 1 << 20,
+            "GB" => 1 << 30,
+            _ => bail!(
+                "expected daily, hourly or a size such as 100MB, got {:?}",
+                s
+            ),
+        };
+        match s[..digits].parse::<usize>() {
+            Ok(n) if n > 0 => Ok(Rotation::Size(n * unit)),
+            _ => bail!(
+                "expected daily, hourly or a size such as 100MB, got {:?}",
+                s
+            ),
+        }
+    }
+}
+
+/// `--log-file` and how it is rotated; `max_files` rotated files are kept besides the current one.
+pub struct LogFile {
+    pub path: String,
+    pub rotation: Rotation,
+    pub max_files: usize,
+}
+
+impl LogFile {
+    /// Rotated files are named `<path>.1` (newest) and up for size rotation, and
+    /// `<path>.<date>` for daily and hourly rotation.
+    fn open(&self) -> Result<Box<dyn Write + Send>> {
+        // FileRotate 打不开文件时不报错，先自己试一次
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open --log-file {}", self.path))?;
+        let timestamped = |format, frequency, date_from| -> Box<dyn Write + Send> {
+            Box::new(FileRotate::new(
+                &self.path,
+                AppendTimestamp::with_format(
+                    format,
+                    FileLimit::MaxFiles(self.max_files),
+                    date_from,
+                ),
+                ContentLimit::Time(frequency),
+                Compression::None,
+                None,
+            ))
+        };
+        Ok(match self.rotation {
+            Rotation::Daily => {
+                timestamped("%Y-%m-%d", TimeFrequency::Daily, DateFrom::DateYesterday)
+            }
+            Rotation::Hourly => {
+                timestamped("%Y-%m-%dT%H", TimeFrequency::Hourly, DateFrom::DateHourAgo)
+            }
+            Rotation::Size(bytes) => Box::new(FileRotate::new(
+                &self.path,
+                AppendCount::new(self.max_files),
+                ContentLimit::BytesSurpassed(bytes),
+                Compression::None,
+                None,
+            )),
+        })
+    }
+}
+
+pub fn init(
+    level: Level,
+    format: Format,
+    output: Output,
+    otlp_endpoint: Option<&str>,
+    log_file: Option<&LogFile>,
+) -> Result<()> {
+    // -vv 起在 span 结束时输出耗时
+    let span_events = if level >= Level::DEBUG {
+        FmtSpan::CLOSE
+    } else {
+        FmtSpan::NONE
+    };
+    let layer = match (output, log_file) {
+        (Output::Journald, _) => tracing_journald::layer()
+            .with_context(|| "Failed to connect to journald")?
+            .boxed(),
+        // syslog 自己加时间戳
+        (Output::Syslog, _) => {
+            syslog::open();
+            fmt_layer(
+                format,
+                BoxMakeWriter::new(syslog::Syslog),
+                false,
+                (),
+                span_events,
+            )
+        }
+        (Output::Stderr, Some(log_file)) => fmt_layer(
+            format,
+            BoxMakeWriter::new(Mutex::new(log_file.open()?)),
+            false,
+            SystemTime,
+            span_events,
+        ),
+        (Output::Stderr, None) => fmt_layer(
+            format,
+            BoxMakeWriter::new(std::io::stderr),
+            true,
+            SystemTime,
+            span_events,
+        ),
+    }
+    // --web.access-log 的请求日志不受 -v 影响；tide 自带的请求日志由它和 TraceMiddleware 取代
+    .with_filter(
+        Targets::new()
+            .with_default(level)
+            .with_target("access", Level::INFO)
+            .with_target("tide::log::middleware", LevelFilter::OFF),
+    );
+    let registry = tracing_subscriber::registry().with(layer);
+
+    match otlp_endpoint {
+        #[cfg(feature = "otlp")]
+        Some(endpoint) => registry.with(otlp::layer(endpoint)?).init(),
+        #[cfg(not(feature = "otlp"))]
+        Some(_) => anyhow::bail!("--otlp.endpoint requires building with the `otlp` feature"),
+        None => registry.init(),
+    }
+    Ok(())
+}
+
+fn fmt_layer<S, T>(
+    format: Format,
+    writer: BoxMakeWriter,
+    ansi: bool,
+    timer: T,
+    span_events: FmtSpan,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    T: FormatTime + Send + Sync + 'static,
+{
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi)
+        .with_timer(timer)
+        .with_span_events(span_events);
+    // json：事件字段放在顶层，当前 span（含 request_id）放在 span 字段
+    match format {
+        Format::Text => fmt.boxed(),
+        Format::Json => fmt
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    }
+}
+
+/// Flushes spans still buffered for OTLP export.
+pub fn shutdown() {
+    #[cfg(feature = "otlp")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+mod syslog {
+    use std::ffi::CString;
+    use std::io::{self, Write};
+    use tracing::{Level, Metadata};
+    use tracing_subscriber::fmt::MakeWriter;
+
+    /// Connects to `/dev/log` now, before privileges are dropped and the sandbox applies.
+    pub fn open() {
+        unsafe {
+            libc::openlog(
+                b"nvidia-smi-exporter\0".as_ptr() as *const libc::c_char,
+                libc::LOG_PID | libc::LOG_NDELAY,
+                libc::LOG_DAEMON,
+            );
+        }
+    }
+
+    /// Sends each formatted event to syslog with the priority of its level.
+    pub struct Syslog;
+
+    impl<'a> MakeWriter<'a> for Syslog {
+        type Writer = Writer;
+
+        fn make_writer(&'a self) -> Writer {
+            Writer(libc::LOG_INFO)
+        }
+
+        fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Writer {
+            Writer(match *meta.level() {
+                Level::ERROR => libc::LOG_ERR,
+                Level::WARN => libc::LOG_WARNING,
+                Level::INFO => libc::LOG_INFO,
+                _ => libc::LOG_DEBUG,
+            })
+        }
+    }
+
+    pub struct Writer(libc::c_int);
+
+    impl Write for Writer {
+        // fmt 层每条事件只调用一次 write_all
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let line = String::from_utf8_lossy(buf).trim_end().replace('\0', "");
+            let line = CString::new(line).unwrap_or_default();
+            unsafe {
+                libc::syslog(
+                    self.0,
+                    b"%s\0".as_ptr() as *const libc::c_char,
+                    line.as_ptr(),
+                );
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use anyhow::Result;
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+    use tracing::Subscriber;
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::Layer;
+
+    /// Exports spans over OTLP/HTTP regardless of the console log level.
+    pub fn layer<S>(endpoint: &str) -> Result<impl Layer<S>>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()?;
+        let provider = TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::AsyncStd)
+            .with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                env!("CARGO_PKG_NAME"),
+            )]))
+            .build();
+        let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+        opentelemetry::global::set_tracer_provider(provider);
+
+        Ok(tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(LevelFilter::DEBUG))
+    }
+}
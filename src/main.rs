@@ -1,133 +1,995 @@
-use anyhow::{Context, Result};
-use clap::{App, Arg};
-use lazy_static::lazy_static;
-use log::*;
+use anyhow::{bail, Result};
+use async_lock::Semaphore;
+use async_std::io::Cursor;
+use async_std::prelude::*;
+use clap::FromArgMatches;
+use ipnet::IpNet;
 use prometheus::Encoder;
-use std::process::Command;
-use tide::log::LogMiddleware;
+use std::collections::hash_map::DefaultHasher;
+use std::ffi::OsString;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tide::http::headers::{ACCEPT, CACHE_CONTROL, ETAG, IF_NONE_MATCH, VARY};
 use tide::{http::mime, Body, Request, Response, Server, StatusCode};
+use tracing::{error, info, warn, Level};
 
-lazy_static! {
-    static ref METRIC_LIST: Vec<&'static str> = vec![
-        "nvidia_fan_speed",
-        "nvidia_temperature_gpu",
-        "nvidia_clocks_gr",
-        "nvidia_clocks_sm",
-        "nvidia_clocks_mem",
-        "nvidia_power_draw",
-        "nvidia_utilization_gpu",
-        "nvidia_utilization_memory",
-        "nvidia_memory_total",
-        "nvidia_memory_free",
-        "nvidia_memory_used"
-    ];
+use nvidia_smi_exporter::{exposition, gpu};
+
+mod admin;
+mod agentx;
+mod alert;
+mod amd;
+mod api;
+mod auth;
+mod azuremonitor;
+mod breaker;
+mod check;
+mod capture;
+mod cli;
+mod cloudmonitoring;
+mod cloudwatch;
+mod collector;
+mod config;
+mod configfile;
+mod consul;
+mod container;
+mod daemon;
+mod dashboard;
+mod discovery;
+mod downward;
+mod elasticsearch;
+mod energy;
+mod environment;
+mod events;
+mod federate;
+mod filter;
+mod fluentd;
+mod graphite;
+mod grpc;
+mod health;
+mod history;
+mod home;
+mod hub;
+mod influx;
+mod intel;
+mod jetson;
+mod kafka;
+mod listen;
+mod logging;
+mod mdns;
+mod metadata;
+mod middleware;
+mod mock;
+mod mqtt;
+mod nagios;
+mod nats;
+mod netdata;
+mod notify;
+mod nvidia_smi;
+mod oneshot;
+mod openmetrics;
+mod otlp;
+mod platform;
+mod podresources;
+mod privileges;
+mod procinfo;
+#[cfg(target_os = "freebsd")]
+mod procstat;
+mod push;
+mod pushgateway;
+mod redfish;
+mod relabel;
+mod reload;
+mod remotewrite;
+mod runtime;
+mod sandbox;
+mod schema;
+mod shutdown;
+mod slurm;
+mod ssh;
+mod state;
+mod statsd;
+mod stream;
+mod tenant;
+mod textfile;
+mod tls;
+mod topology;
+mod version;
+mod vfio;
+mod webconfig;
+mod xid;
+mod zabbix;
+
+#[derive(Clone)]
+struct State {
+    settings: Arc<RwLock<config::Settings>>,
+    telemetry_path: String,
+    debug_runtime: bool,
+    stream: bool,
+    history: bool,
+    /// Serve OpenMetrics with exemplars to scrapers asking for it, with `--otlp.endpoint`.
+    openmetrics: bool,
+    config: Arc<RwLock<config::Config>>,
+    reloader: Arc<reload::Reloader>,
+    advertised: Arc<discovery::Advertised>,
 }
 
-#[async_std::main]
-async fn main() -> Result<()> {
-    let matches = App::new("Nvidia SMI Exporter")
-        .arg(
-            Arg::with_name("verbose")
-                .short("v")
-                .multiple(true)
-                .help("Sets the level of verbosity"),
-        )
-        .arg(
-            Arg::with_name("listen")
-                .short("l")
-                .long("listen")
-                .takes_value(true)
-                .help("Sets the level of verbosity"),
+fn main() -> Result<()> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let matches = cli::command().get_matches_from(&args);
+    match cli::Cli::from_arg_matches(&matches)?.command {
+        Some(cli::Command::CheckConfig { file }) => return check::check_config(&file),
+        Some(cli::Command::ConfigSchema) => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&schema::config_schema(&cli::command()))?
+            );
+            return Ok(());
+        }
+        Some(cli::Command::Completions { shell }) => {
+            let mut command = cli::command();
+            clap_complete::generate(
+                shell,
+                &mut command,
+                "nvidia-smi-exporter",
+                &mut std::io::stdout(),
+            );
+            return Ok(());
+        }
+        Some(cli::Command::Manpage) => {
+            clap_mangen::Man::new(cli::command()).render(&mut std::io::stdout())?;
+            return Ok(());
+        }
+        Some(
+            cli::Command::Serve
+            | cli::Command::Print { .. }
+            | cli::Command::Check { .. }
+            | cli::Command::Replay { .. }
+            | cli::Command::Netdata { .. },
         )
-        .get_matches();
+        | None => {}
+    }
+    let cli = cli::from_matches(&args, matches)?;
 
-    match matches.occurrences_of("verbose") {
-        0 => tide::log::with_level(log::LevelFilter::Warn),
-        1 => tide::log::with_level(log::LevelFilter::Info),
-        2 => tide::log::with_level(log::LevelFilter::Debug),
-        3 | _ => tide::log::with_level(log::LevelFilter::Trace),
+    let level = match cli.verbose {
+        0 => Level::WARN,
+        1 => Level::INFO,
+        2 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+    let log_file = cli.log_file.as_ref().map(|path| logging::LogFile {
+        path: path.clone(),
+        rotation: cli.log_rotate,
+        max_files: cli.log_max_files,
+    });
+    logging::init(
+        level,
+        cli.log_format,
+        cli.log_output,
+        cli.otlp_endpoint.as_deref(),
+        log_file.as_ref(),
+    )?;
+
+    let mut settings = config::Settings::from_cli(&cli)?;
+    if let Some(cli::Command::Replay { file }) = &cli.command {
+        settings.nvidia_smi = Arc::new(capture::Replay::load(file)?);
+        settings.mock = None;
+    }
+    if let Some(cli::Command::Print {
+        output: Some(path),
+        format,
+        interval,
+        remove_stale_after,
+    }) = &cli.command
+    {
+        if *interval > 0 && !cli.dry_run {
+            collector::init_metrics();
+            std::process::exit(async_std::task::block_on(textfile::run(
+                &settings,
+                path,
+                *format,
+                Duration::from_secs(*interval),
+                remove_stale_after.map(Duration::from_secs),
+            )));
+        }
     }
+    let oneshot = match &cli.command {
+        _ if cli.dry_run => Some(oneshot::Mode::DryRun),
+        Some(cli::Command::Print {
+            output: None,
+            format,
+            ..
+        }) => Some(oneshot::Mode::Print(*format)),
+        Some(cli::Command::Print {
+            output: Some(path),
+            format,
+            ..
+        }) => Some(oneshot::Mode::Textfile(path.clone(), *format)),
+        Some(cli::Command::Check { thresholds }) if thresholds.is_empty() => {
+            Some(oneshot::Mode::Check)
+        }
+        Some(cli::Command::Check { thresholds }) => Some(oneshot::Mode::Nagios(thresholds.clone())),
+        Some(cli::Command::Replay { .. }) => Some(oneshot::Mode::Replay),
+        _ => None,
+    };
+    if let Some(mode) = oneshot {
+        collector::init_metrics();
+        std::process::exit(async_std::task::block_on(oneshot::run(mode, &settings)));
+    }
+    if let Some(cli::Command::Netdata { update_every }) = cli.command {
+        collector::init_metrics();
+        std::process::exit(async_std::task::block_on(netdata::run(
+            &settings,
+            update_every,
+        )));
+    }
+    let shutdown_timeout = Duration::from_secs(cli.shutdown_timeout);
 
-    let mut app = Server::new();
+    let config_files = reload::ConfigFiles::from_cli(&cli);
+    let (mut web_config, auth) = config_files.load()?;
+    admin::check_auth(&cli, &auth)?;
+    let tls_config = match (&cli.tls_cert, &cli.tls_key) {
+        (Some(cert), Some(key)) => {
+            if web_config.tls_server_config.is_some() {
+                bail!("--tls-cert conflicts with tls_server_config in --web.config.file");
+            }
+            let client_ca_file = cli.tls_client_ca.clone();
+            Some(tls::TlsServerConfig {
+                cert_file: cert.clone(),
+                key_file: key.clone(),
+                client_auth_type: if client_ca_file.is_some() {
+                    tls::ClientAuthType::RequireAndVerifyClientCert
+                } else {
+                    tls::ClientAuthType::NoClientCert
+                },
+                client_ca_file,
+                min_version: cli.tls_min_version,
+                max_version: None,
+                cipher_suites: cli
+                    .tls_cipher_suites
+                    .iter()
+                    .map(|s| s.trim().to_string())
+                    .collect(),
+                prefer_server_cipher_suites: true,
+            })
+        }
+        _ => web_config.tls_server_config.take(),
+    };
 
-    app.with(LogMiddleware::new()); // 日志中间件
-    app.with(tide_compress::CompressMiddleware::new()); // Outgoing compression middleware
-    app.at("/").get(handle_home);
-    app.at("/metrics").get(handle_metrics);
+    let telemetry_path = cli.telemetry_path.as_str();
+    if !telemetry_path.starts_with('/') || telemetry_path == "/" {
+        bail!("--web.telemetry-path must start with / and not be the root");
+    }
 
-    let addr = matches.value_of("listen").unwrap_or_else(|| "0.0.0.0:9101");
-    info!("Listen on {}", addr);
-    app.listen(addr).await?;
+    let max_requests = cli.max_requests;
+    let rate_limit = cli.rate_limit;
+    let request_timeout = Duration::from_secs(cli.request_timeout);
+    let allow_cidrs = cli.allow_cidrs.clone();
+    let trusted_proxies = cli.trusted_proxies.clone();
+    let access_log = Some(cli.access_log_format.clone()).filter(|_| cli.access_log);
 
-    Ok(())
-}
+    let compression = match cli.compression.as_str() {
+        "none" => Vec::new(),
+        algorithms => algorithms
+            .split(',')
+            .map(|a| match a.trim() {
+                a @ ("zstd" | "br" | "gzip" | "deflate") => Ok(a.to_string()),
+                a => bail!("Unsupported --web.compression algorithm {:?}", a),
+            })
+            .collect::<Result<Vec<_>>>()?,
+    };
+    let compression_threshold = cli.compression_threshold;
+
+    let idle_timeout = Duration::from_secs(cli.idle_timeout);
+    let max_connections = cli.max_connections;
+
+    let push_targets = push::targets(&cli)?;
+    let hub_listener = cli.hub_listen.as_deref().map(hub::bind).transpose()?;
+    let push_interval = Duration::from_secs(cli.push_interval);
+
+    let listen_addrs = if cli.listen.is_empty() {
+        vec!["0.0.0.0:9101".to_string()]
+    } else {
+        cli.listen.clone()
+    };
+    let environment = environment::environment();
+    if let Some(container) = &environment.container {
+        info!(
+            "Running in a {} container{}",
+            container,
+            if environment.user_namespace {
+                ", in a user namespace"
+            } else {
+                ""
+            }
+        );
+    }
+    let notifier = notify::from_env()?;
+    let mut endpoints = listen::systemd_endpoints()?;
+    let socket_activated = endpoints.len();
+    if endpoints.is_empty() {
+        for addr in &listen_addrs {
+            info!("Listen on {}", addr);
+            endpoints.push(listen::Endpoint::parse(addr, cli.reuse_port)?);
+        }
+    } else {
+        info!("Listen on {} sockets passed by systemd", endpoints.len());
+        if !cli.listen.is_empty() {
+            warn!("Socket activated, ignoring --listen");
+        }
+    }
+    let advertised = Arc::new(discovery::Advertised::new(
+        &cli,
+        &listen_addrs,
+        tls_config.is_some(),
+    )?);
+    let consul = match &cli.consul_address {
+        Some(address) => Some(Arc::new(consul::Consul::new(
+            address,
+            &cli,
+            &advertised,
+            auth.is_empty(),
+        )?)),
+        None => None,
+    };
+    let mdns = match cli.mdns {
+        true => Some(Arc::new(mdns::Mdns::new(&cli, &advertised)?)),
+        false => None,
+    };
+
+    let config = Arc::new(RwLock::new(config::Config {
+        config_file: cli.config.clone(),
+        log_level: level.to_string(),
+        log_file: cli.log_file.clone(),
+        listen: listen_addrs.clone(),
+        socket_activated,
+        telemetry_path: telemetry_path.to_string(),
+        max_requests,
+        rate_limit,
+        request_timeout_seconds: request_timeout.as_secs(),
+        allow_cidrs: allow_cidrs.iter().map(IpNet::to_string).collect(),
+        trusted_proxies: trusted_proxies.iter().map(IpNet::to_string).collect(),
+        access_log_format: access_log.as_ref().map(ToString::to_string),
+        compression: compression.clone(),
+        compression_threshold,
+        idle_timeout_seconds: idle_timeout.as_secs(),
+        max_connections,
+        reuse_port: cli.reuse_port,
+        tls: tls_config,
+        web_config_file: config_files.web_config_file.clone(),
+        headers: web_config.http_server_config.headers.clone(),
+        basic_auth_users: config::Config::redact(&web_config.basic_auth_users),
+        basic_auth_file: config_files.basic_auth_file.clone(),
+        bearer_token_file: config_files.bearer_token_file.clone(),
+        tenants_file: config_files.tenants_file.clone(),
+        backend: cli.backend.name(),
+        backend_mock_gpus: settings.mock.as_ref().map(|_| cli.backend_mock_gpus),
+        collect_timeout_seconds: settings.collect_timeout.as_secs(),
+        collect_latency_budget_seconds: settings
+            .latency_budget
+            .map_or(0, |budget| budget.as_secs()),
+        collect_breaker_failures: settings.breaker.as_ref().map_or(0, |b| b.failures),
+        collect_breaker_cooldown_seconds: settings
+            .breaker
+            .as_ref()
+            .map(|b| b.cooldown.as_secs()),
+        collect_processes: settings.processes,
+        collect_processes_runtime_sockets: settings
+            .runtime_sockets
+            .iter()
+            .map(|(runtime, socket)| (runtime.name().to_string(), socket.clone()))
+            .collect(),
+        collect_processes_metadata: settings.process_metadata,
+        collect_processes_cmdline_length: settings.cmdline_length,
+        collect_processes_user_hash_key_file: cli.collect_processes_user_hash_key_file.clone(),
+        collect_mig_devices: settings.mig_devices,
+        collect_passthrough: settings.passthrough,
+        collect_passthrough_libvirt_dir: settings.libvirt_dir.clone(),
+        collect_slurm: settings.slurm,
+        collect_amd: cli.collect_amd,
+        collect_amd_command: cli.collect_amd_command.clone(),
+        collect_intel: cli.collect_intel,
+        collect_intel_command: cli.collect_intel_command.clone(),
+        collect_jetson: cli.collect_jetson,
+        collect_jetson_command: cli.collect_jetson_command.clone(),
+        collect_events: cli.collect_events,
+        collect_kernel_xid: cli.collect_kernel_xid,
+        collect_topology: cli.collect_topology,
+        collect_platform: cli.collect_platform,
+        kubernetes_pod_resources_socket: settings.pod_resources_socket.clone(),
+        kubernetes_metadata_files: cli.kubernetes_metadata_files.clone(),
+        ssh_hosts: settings
+            .ssh
+            .iter()
+            .flat_map(|ssh| ssh.targets.iter().map(|target| target.label()))
+            .collect(),
+        ssh_hosts_file: cli.ssh_hosts_file.clone(),
+        ssh_any_target: cli.ssh_any_target,
+        federate_targets: settings
+            .federate
+            .iter()
+            .map(|downstream| {
+                let mut url = downstream.url.clone();
+                if url.password().is_some() {
+                    let _ = url.set_password(Some(config::REDACTED));
+                }
+                url.to_string()
+            })
+            .collect(),
+        redfish_url: cli.redfish_url.clone(),
+        redfish_username: cli.redfish_username.clone(),
+        redfish_ca_file: cli.redfish_ca_file.clone(),
+        hub_listen: cli.hub_listen.clone(),
+        hub_token_file: cli.hub_token_file.clone(),
+        hub_stale_after_seconds: cli.hub_stale_after,
+        constant_labels: settings.constant_labels.iter().cloned().collect(),
+        shutdown_timeout_seconds: shutdown_timeout.as_secs(),
+        disable_exporter_metrics: settings.disable_exporter_metrics,
+        collectors: settings.collector_names(),
+        gpu_filter: settings.gpu_filter.clone(),
+        metric_filter: settings.metric_filter.clone(),
+        query_fields: settings.query_fields.clone(),
+        relabel_file: cli.relabel_file.clone(),
+        relabel_configs: settings.relabel_configs(),
+        debug_runtime: cli.debug_runtime,
+        enable_stream: cli.enable_stream,
+        history_retention_seconds: cli.history_retention,
+        history_interval_seconds: cli.history_interval,
+        energy_price_per_kwh: cli.energy_price_per_kwh,
+        energy_currency: cli.energy_currency.clone(),
+        energy_carbon_intensity: cli.energy_carbon_intensity,
+        energy_carbon_intensity_url: cli.energy_carbon_intensity_url.clone(),
+        energy_interval_seconds: cli.energy_interval,
+        health: settings.health.clone(),
+        otlp_endpoint: cli.otlp_endpoint.clone(),
+        user: cli.user.clone(),
+        group: cli.group.clone(),
+        daemonize: cli.daemonize,
+        pid_file: cli.pid_file.clone(),
+        capture_dir: cli.capture_dir.clone(),
+        state_file: cli.state_file.clone(),
+        sandbox: cli.sandbox,
+        enable_lifecycle: cli.enable_lifecycle,
+        lifecycle_localhost_only: cli.lifecycle_localhost_only,
+        enable_admin_api: cli.enable_admin_api,
+        push_interval_seconds: push_interval.as_secs(),
+        push_job: cli.push_job.clone(),
+        push_instance: push::instance(&cli),
+        remote_write_url: cli.remote_write_url.clone(),
+        remote_write_bearer_token_file: cli.remote_write_bearer_token_file.clone(),
+        remote_write_username: cli.remote_write_username.clone(),
+        remote_write_password_file: cli.remote_write_password_file.clone(),
+        pushgateway_url: cli.pushgateway_url.clone(),
+        pushgateway_grouping: cli.pushgateway_grouping.iter().cloned().collect(),
+        pushgateway_delete_on_shutdown: cli.pushgateway_delete_on_shutdown,
+        influxdb_url: cli.influxdb_url.clone(),
+        influxdb_org: cli.influxdb_org.clone(),
+        influxdb_bucket: cli.influxdb_bucket.clone(),
+        influxdb_token_file: cli.influxdb_token_file.clone(),
+        graphite_address: cli.graphite_address.clone(),
+        graphite_protocol: cli.graphite_protocol,
+        graphite_prefix: cli.graphite_prefix.clone(),
+        graphite_tagged: cli.graphite_tagged,
+        graphite_interval_seconds: cli.graphite_interval,
+        statsd_address: cli.statsd_address.clone(),
+        statsd_prefix: cli.statsd_prefix.clone(),
+        statsd_dogstatsd: cli.statsd_dogstatsd,
+        otlp_url: cli.otlp_url.clone(),
+        otlp_resource_attributes: cli.otlp_resource_attributes.iter().cloned().collect(),
+        otlp_bearer_token_file: cli.otlp_bearer_token_file.clone(),
+        zabbix_address: cli.zabbix_address.clone(),
+        zabbix_host: cli.zabbix_host.clone(),
+        zabbix_discovery_interval_seconds: cli.zabbix_discovery_interval,
+        mqtt_url: cli.mqtt_url.clone(),
+        mqtt_topic: cli.mqtt_topic.clone(),
+        mqtt_qos: cli.mqtt_qos,
+        mqtt_retain: cli.mqtt_retain,
+        mqtt_client_id: cli.mqtt_client_id.clone(),
+        mqtt_username: cli.mqtt_username.clone(),
+        mqtt_password_file: cli.mqtt_password_file.clone(),
+        mqtt_ca_file: cli.mqtt_ca_file.clone(),
+        mqtt_cert_file: cli.mqtt_cert_file.clone(),
+        mqtt_key_file: cli.mqtt_key_file.clone(),
+        nats_url: cli.nats_url.clone(),
+        nats_subject: cli.nats_subject.clone(),
+        nats_jetstream: cli.nats_jetstream,
+        nats_username: cli.nats_username.clone(),
+        nats_password_file: cli.nats_password_file.clone(),
+        nats_token_file: cli.nats_token_file.clone(),
+        nats_ca_file: cli.nats_ca_file.clone(),
+        nats_cert_file: cli.nats_cert_file.clone(),
+        nats_key_file: cli.nats_key_file.clone(),
+        fluentd_address: cli.fluentd_address.clone(),
+        fluentd_tag: cli.fluentd_tag.clone(),
+        fluentd_require_ack: cli.fluentd_require_ack,
+        kafka_brokers: cli.kafka_brokers.clone(),
+        kafka_topic: cli.kafka_topic.clone(),
+        kafka_format: cli.kafka_format,
+        kafka_schema_registry_url: cli.kafka_schema_registry_url.clone(),
+        kafka_interval_seconds: cli.kafka_interval,
+        cloudwatch_region: cli.cloudwatch_region.clone(),
+        cloudwatch_namespace: cli.cloudwatch_namespace.clone(),
+        cloudwatch_dimensions: cli.cloudwatch_dimensions.clone(),
+        cloudwatch_static_dimensions: cli.cloudwatch_static_dimensions.iter().cloned().collect(),
+        cloudwatch_endpoint: cli.cloudwatch_endpoint.clone(),
+        cloud_monitoring: cli.cloud_monitoring,
+        cloud_monitoring_project: cli.cloud_monitoring_project.clone(),
+        cloud_monitoring_metric_prefix: cli.cloud_monitoring_metric_prefix.clone(),
+        cloud_monitoring_endpoint: cli.cloud_monitoring_endpoint.clone(),
+        azure_monitor: cli.azure_monitor,
+        azure_monitor_namespace: cli.azure_monitor_namespace.clone(),
+        azure_monitor_client_id: cli.azure_monitor_client_id.clone(),
+        azure_monitor_region: cli.azure_monitor_region.clone(),
+        azure_monitor_resource_id: cli.azure_monitor_resource_id.clone(),
+        azure_monitor_endpoint: cli.azure_monitor_endpoint.clone(),
+        elasticsearch_url: cli.elasticsearch_url.clone(),
+        elasticsearch_index: cli.elasticsearch_index.clone(),
+        elasticsearch_username: cli.elasticsearch_username.clone(),
+        elasticsearch_password_file: cli.elasticsearch_password_file.clone(),
+        elasticsearch_api_key_file: cli.elasticsearch_api_key_file.clone(),
+        elasticsearch_interval_seconds: cli.elasticsearch_interval,
+        push_hub_url: cli.push_hub_url.clone(),
+        push_hub_token_file: cli.push_hub_token_file.clone(),
+        push_hub_ca_file: cli.push_hub_ca_file.clone(),
+        push_hub_interval_seconds: cli.push_hub_interval,
+        alert_rules_file: cli.alert_rules_file.clone(),
+        alert_webhook_url: cli.alert_webhook_url.as_ref().map(|_| config::REDACTED),
+        alert_webhook_format: cli.alert_webhook_format,
+        agentx_master: cli.agentx_master.clone(),
+        agentx_oid: cli.agentx_oid.to_string(),
+        sd_advertise_address: advertised.address(),
+        sd_labels: cli.sd_labels.iter().cloned().collect(),
+        consul_address: cli.consul_address.clone(),
+        consul_service: cli.consul_service.clone(),
+        consul_tags: cli.consul_tags.clone(),
+        consul_token_file: cli.consul_token_file.clone(),
+        mdns_instance: mdns.as_ref().map(|mdns| mdns.instance.clone()),
+    }));
+    let headers = middleware::Swappable::new(middleware::HeadersMiddleware(std::mem::take(
+        &mut web_config.http_server_config.headers,
+    )));
+    let auth = middleware::Swappable::new(auth);
+    let settings = Arc::new(RwLock::new(settings));
+    let reloader = Arc::new(reload::Reloader {
+        args: args.clone(),
+        settings: settings.clone(),
+        files: config_files,
+        auth: auth.clone(),
+        headers: headers.clone(),
+        config: config.clone(),
+    });
+
+    collector::init_metrics();
+    lazy_static::initialize(&openmetrics::SCRAPE_DURATION);
+
+    let mut app = Server::with_state(State {
+        settings: settings.clone(),
+        telemetry_path: telemetry_path.to_string(),
+        debug_runtime: cli.debug_runtime,
+        stream: cli.enable_stream,
+        history: cli.history_retention > 0,
+        openmetrics: cli.otlp_endpoint.is_some(),
+        config: config.clone(),
+        reloader: reloader.clone(),
+        advertised: advertised.clone(),
+    });
+
+    app.with(middleware::TraceMiddleware::new(
+        trusted_proxies.clone(),
+        access_log,
+    ));
+    if !allow_cidrs.is_empty() {
+        app.with(middleware::AllowCidrMiddleware(allow_cidrs));
+    }
+    // 两者都可能被 /-/reload 替换，所以即使当前为空也要挂上
+    app.with(headers);
+    app.with(auth);
+    if cli.web_tenants_file.is_some() {
+        app.with(tenant::ScopeMiddleware::new(telemetry_path));
+    }
+    if !compression.is_empty() {
+        // Outgoing compression middleware
+        app.with(middleware::CompressionMiddleware::new(
+            compression,
+            compression_threshold,
+        ));
+    }
+    app.at("/").get(home::handle_home);
+    let mut metrics_route = app.at(telemetry_path);
+    let scrape_limit =
+        middleware::ScrapeLimitMiddleware::new(max_requests, rate_limit, trusted_proxies);
+    metrics_route.with(scrape_limit.clone());
+    if request_timeout > Duration::ZERO {
+        metrics_route.with(middleware::DeadlineMiddleware::new(request_timeout));
+    }
+    metrics_route.get(handle_metrics);
+    // 和 /metrics 一样会运行 nvidia-smi，共用并发和速率限制
+    app.at("/api/v1/gpus")
+        .with(scrape_limit.clone())
+        .get(api::handle_gpus);
+    app.at("/health/gpus")
+        .with(scrape_limit.clone())
+        .get(health::handle_health);
+    app.at("/dashboard.json")
+        .with(scrape_limit.clone())
+        .get(dashboard::handle_dashboard);
+    app.at("/topology")
+        .with(scrape_limit.clone())
+        .get(topology::handle_topology);
+    app.at("/api/v1/targets").get(discovery::handle_targets);
+    app.at("/api/v1/metadata").get(metadata::handle_metadata);
+    app.at("/healthz").get(handle_healthz);
+    app.at("/readyz").get(handle_readyz);
+    app.at("/version").get(version::handle_version);
+    app.at("/config").get(config::handle_config);
+    if cli.enable_lifecycle {
+        let local_only = cli.lifecycle_localhost_only;
+        let mut reload_route = app.at("/-/reload");
+        if local_only {
+            reload_route.with(middleware::LocalOnlyMiddleware);
+        }
+        reload_route.post(reload::handle_reload);
+        let mut quit_route = app.at("/-/quit");
+        if local_only {
+            quit_route.with(middleware::LocalOnlyMiddleware);
+        }
+        quit_route.post(shutdown::handle_quit);
+    }
+    if cli.enable_admin_api {
+        app.at("/api/v1/admin/power-limit")
+            .get(admin::handle_get_power_limit)
+            .post(admin::handle_set_power_limit);
+    }
+    if cli.debug_runtime {
+        app.at("/debug/runtime").get(runtime::handle_debug_runtime);
+    }
+    if cli.enable_stream {
+        // 打开时和 /metrics 一样计入并发和速率限制；连接一直保持，超时约束的是其中每一次采集
+        let mut stream_route = app.at("/stream");
+        stream_route.with(scrape_limit);
+        if request_timeout > Duration::ZERO {
+            stream_route.with(middleware::DeadlineMiddleware::new(request_timeout));
+        }
+        stream_route.get(stream::handle_stream);
+    }
+    if cli.history_retention > 0 {
+        app.at("/history").get(history::handle_history);
+    }
+
+    let connection_options = listen::ConnectionOptions {
+        idle_timeout,
+        max_connections: (max_connections > 0).then(|| Arc::new(Semaphore::new(max_connections))),
+    };
+    let listener = listen::listeners(
+        endpoints,
+        config.read().unwrap().tls.as_ref(),
+        connection_options,
+    )?;
+    // pid 文件通常在 /run 下，要在降权之前写
+    if cli.daemonize {
+        daemon::daemonize(cli.pid_file.as_deref())?;
+    } else if let Some(path) = &cli.pid_file {
+        daemon::write_pid_file(path)?;
+    }
+    privileges::drop_to(cli.user.as_deref(), cli.group.as_deref())?;
+    // 在开始计数之前恢复，之后以降权后的用户写回
+    let mut persisted = Vec::new();
+    if let Some(path) = &cli.state_file {
+        if cli.collect_events {
+            persisted.extend(events::counters());
+        }
+        if settings.read().unwrap().energy.is_some() {
+            persisted.extend(energy::counters());
+        }
+        state::restore(path, &persisted)?;
+    }
+    if cli.sandbox {
+        // sandbox 放开的是文件所在的目录
+        let libvirt_dir = Some(format!(
+            "{}/domain.xml",
+            cli.collect_passthrough_libvirt_dir
+        ))
+        .filter(|_| cli.collect_passthrough && !cli.collect_passthrough_libvirt_dir.is_empty());
+        let files = [
+            &cli.config,
+            &cli.relabel_file,
+            &cli.alert_rules_file,
+            &cli.web_config_file,
+            &cli.basic_auth_file,
+            &cli.bearer_token_file,
+            &cli.web_tenants_file,
+            &cli.collect_processes_user_hash_key_file,
+            &cli.remote_write_bearer_token_file,
+            &cli.remote_write_password_file,
+            &cli.influxdb_token_file,
+            &cli.otlp_bearer_token_file,
+            &cli.mqtt_password_file,
+            &cli.nats_password_file,
+            &cli.nats_token_file,
+            &cli.elasticsearch_password_file,
+            &cli.elasticsearch_api_key_file,
+            &cli.consul_token_file,
+            &cli.energy_carbon_intensity_token_file,
+            &cli.ssh_hosts_file,
+            &cli.ssh_identity_file,
+            &cli.redfish_password_file,
+            &cli.redfish_ca_file,
+            &cli.hub_token_file,
+            &cli.push_hub_token_file,
+            &cli.push_hub_ca_file,
+            &libvirt_dir,
+        ]
+        .iter()
+        .filter_map(|file| file.as_deref())
+        .chain(cli.kubernetes_metadata_files.iter().map(String::as_str))
+        .collect::<Vec<_>>();
+        // 沙箱放开的是可写文件所在的目录
+        let capture = cli.capture_dir.as_ref().map(|dir| format!("{}/capture", dir));
+        let writable = cli
+            .log_file
+            .iter()
+            .chain(&capture)
+            .chain(&cli.state_file)
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        sandbox::apply(&files, &writable)?;
+    }
 
-fn process_nvidia_smi() -> Result<String> {
-    let output = Command::new("nvidia-smi")
-        .arg("--query-gpu=name,index,fan.speed,temperature.gpu,clocks.gr,clocks.sm,clocks.mem,power.draw,utilization.gpu,utilization.memory,memory.total,memory.free,memory.used")
-        .arg("--format=csv,noheader,nounits")
-        .output()
-        .with_context(|| "Failed to execute command")?;
-    let stdout = output.stdout.as_slice();
-    debug!("stdout: {}", String::from_utf8_lossy(stdout));
-    let mut rdr = csv::ReaderBuilder::new()
-        .has_headers(false)
-        .from_reader(stdout);
-    let mut buffer = String::new();
-    for result in rdr.records() {
-        let record = result?;
-        debug!("{:?}", record);
-        let name = record.get(0).unwrap();
-        let index = record.get(1).unwrap().trim();
-        for (idx, i) in (2..record.len()).enumerate() {
-            let value = record.get(i).unwrap();
-            buffer += &*format!(
-                "{}{{gpu=\"{}\", name=\"{}\"}} {}\n",
-                *METRIC_LIST.get(idx).unwrap(),
-                index,
-                name,
-                value
+    // 以上都在运行时启动线程之前完成，沙箱才能覆盖所有线程
+    async_std::task::block_on(async {
+        async_std::task::spawn(reload::on_sighup(reloader));
+        push::start(&settings, push_interval, &push_targets);
+        if cli.collect_events {
+            events::start();
+        }
+        if cli.collect_kernel_xid {
+            xid::start(&settings);
+        }
+        if cli.collect_topology {
+            topology::start(&settings);
+        }
+        if cli.collect_platform {
+            platform::start(&settings);
+        }
+        if settings.read().unwrap().energy.is_some() {
+            energy::start(&settings);
+        }
+        if let Some(path) = &cli.state_file {
+            state::start(path, persisted.clone());
+        }
+        if cli.history_retention > 0 {
+            history::start(
+                &settings,
+                Duration::from_secs(cli.history_interval),
+                Duration::from_secs(cli.history_retention),
             );
         }
+        if let Some(listener) = hub_listener {
+            async_std::task::spawn(hub::serve(listener, cli.hub_token_file.clone()));
+        }
+        if let Some(consul) = &consul {
+            let consul = consul.clone();
+            async_std::task::spawn(async move { consul.register().await });
+        }
+        if let Some(mdns) = &mdns {
+            let mdns = mdns.clone();
+            async_std::task::spawn(async move { mdns.run().await });
+        }
+        // 监听 socket 在此之前已经绑定好，连接会排队等待
+        if let Some(notifier) = &notifier {
+            notifier.notify("READY=1");
+            async_std::task::spawn(notifier.clone().watchdog());
+        }
+        let serve = async {
+            app.listen(listener).await?;
+            Ok(())
+        };
+        // 收到信号时 serve 被丢弃，监听端口随之关闭，已建立的连接继续处理
+        serve.race(shutdown::signal()).await?;
+        if let Some(notifier) = &notifier {
+            notifier.notify("STOPPING=1");
+        }
+        shutdown::drain(shutdown_timeout).await;
+        if let Some(path) = &cli.state_file {
+            if let Err(e) = state::save(path, &persisted) {
+                warn!("Failed to save the counters, {:#}", e);
+            }
+        }
+        if let Some(consul) = &consul {
+            if let Err(e) = consul.deregister().await {
+                warn!("Failed to deregister from Consul, {:#}", e);
+            }
+        }
+        if let Some(mdns) = &mdns {
+            if let Err(e) = mdns.goodbye().await {
+                warn!("Failed to say goodbye over mDNS, {:#}", e);
+            }
+        }
+        push::stop(&push_targets).await;
+        if let Some(path) = &cli.pid_file {
+            daemon::remove_pid_file(path);
+        }
+        logging::shutdown();
+        Ok(())
+    })
+}
+
+async fn handle_metrics(req: Request<State>) -> tide::Result {
+    let started = Instant::now();
+    let settings = match scrape_settings(&req) {
+        Ok(settings) => settings,
+        Err(e) => return Ok(bad_request(e)),
+    };
+    let nvidia_buffer = match collector::process_nvidia_smi(&settings).await {
+        Ok(nvidia_buffer) => nvidia_buffer,
+        Err(e) => {
+            error!("Failed to process nvidia-smi, {:#}", e);
+            String::new()
+        }
+    };
+    openmetrics::observe_scrape(started.elapsed().as_secs_f64());
+
+    let registry = registry_exposition(&settings);
+
+    // ETag 只取决于 GPU 数据，自身指标（时间戳等）每次都会变，不计入
+    let mut hasher = DefaultHasher::new();
+    nvidia_buffer.hash(&mut hasher);
+    let etag = format!("W/\"{:016x}\"", hasher.finish());
+    let not_modified = !nvidia_buffer.is_empty()
+        && req.header(IF_NONE_MATCH).is_some_and(|values| {
+            values
+                .iter()
+                .flat_map(|value| value.as_str().split(','))
+                .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        });
+    let openmetrics = req.state().openmetrics
+        && req.header(ACCEPT).is_some_and(|values| {
+            values
+                .iter()
+                .any(|value| openmetrics::accepted(value.as_str()))
+        });
+    let response = if not_modified {
+        Response::builder(StatusCode::NotModified)
+    } else if openmetrics {
+        // 转换要看到完整的文本，只能拼起来
+        let mut text = String::from_utf8_lossy(&registry).into_owned();
+        text.push_str(&nvidia_buffer);
+        Response::builder(StatusCode::Ok)
+            .content_type(openmetrics::CONTENT_TYPE)
+            .body(openmetrics::convert(&text))
+    } else {
+        // 两段直接写出，不再拼成一份完整副本
+        let len = registry.len() + nvidia_buffer.len();
+        let body = Cursor::new(registry).chain(Cursor::new(nvidia_buffer.into_bytes()));
+        Response::builder(StatusCode::Ok)
+            .content_type(mime::PLAIN)
+            .body(Body::from_reader(body, Some(len)))
+    };
+    let mut response = response
+        .header(ETAG, etag)
+        .header(CACHE_CONTROL, "no-cache")
+        .build();
+    if req.state().openmetrics {
+        response.insert_header(VARY, "Accept");
     }
+    Ok(response)
+}
+
+/// The settings for one scrape, narrowed by `?collect[]=`, `?target=`, `?gpu=` and `?uuid=`, or
+/// why the parameters are invalid.
+fn scrape_settings(req: &Request<State>) -> Result<config::Settings, String> {
+    let mut settings = req.state().settings.read().unwrap().clone();
+    // 租户只看得到分给它的 GPU 和指标
+    if let Some(tenant) = req.ext::<Arc<tenant::Tenant>>() {
+        settings.gpu_filter.tenant = Some(tenant.gpus.clone());
+        settings.metric_filter.tenant = tenant.metrics.clone();
+    }
+    // ?collect[]=a&collect[]=b 只运行列出的（且已启用的）采集器
+    let collect = req
+        .url()
+        .query_pairs()
+        .filter(|(key, _)| key == "collect[]")
+        .map(|(_, value)| value.into_owned())
+        .collect::<Vec<_>>();
+    if !collect.is_empty() {
+        if let Some(name) = collect
+            .iter()
+            .find(|name| !settings.collectors.iter().any(|c| c.name == name.as_str()))
+        {
+            return Err(format!("Unknown or disabled collector {:?}", name));
+        }
+        settings
+            .collectors
+            .retain(|c| collect.iter().any(|name| name == c.name));
+    }
+    // ?target=host 只采集这一台 SSH 主机
+    if let Some((_, target)) = req.url().query_pairs().find(|(key, _)| key == "target") {
+        let ssh = match &mut settings.ssh {
+            Some(ssh) => ssh,
+            None => return Err("?target= needs --ssh.host or --ssh.any-target".to_string()),
+        };
+        ssh.targets = vec![ssh.probe_target(&target)?];
+    }
+    // ?gpu=0,1 / ?uuid=GPU-... 只采集指定的设备
+    for (key, value) in req.url().query_pairs() {
+        let valid = match key.as_ref() {
+            "gpu" => |id: &str| id.parse::<u32>().is_ok(),
+            "uuid" => |id: &str| id.starts_with("GPU-") || id.starts_with("MIG-"),
+            _ => continue,
+        };
+        for id in value.split(',').map(str::trim) {
+            if !valid(id) {
+                return Err(format!("Invalid {} {:?}", key, id));
+            }
+            settings.devices.push(id.to_string());
+        }
+    }
+    Ok(settings)
+}
+
+fn bad_request(message: String) -> Response {
+    Response::builder(StatusCode::BadRequest)
+        .content_type(mime::PLAIN)
+        .body(message)
+        .build()
+}
 
-    Ok(buffer)
+/// The self-metrics followed by the GPU metrics, as served at the telemetry path.
+fn exposition(settings: &config::Settings, nvidia_buffer: &str) -> Vec<u8> {
+    let mut buffer = registry_exposition(settings);
+    buffer.extend_from_slice(nvidia_buffer.as_bytes());
+    buffer
 }
 
-async fn handle_metrics(_req: Request<()>) -> tide::Result {
+/// The exporter's own metrics and the others in the default registry, in the text format.
+fn registry_exposition(settings: &config::Settings) -> Vec<u8> {
+    // 采集之后再 gather，保证失败计数器包含本次结果
     let mut buffer = Vec::new();
     let encoder = prometheus::TextEncoder::new();
-    let metric_families = prometheus::gather();
-    encoder.encode(&metric_families, &mut buffer).unwrap();
+    encoder.encode(&registry_families(settings), &mut buffer).unwrap();
+    buffer
+}
 
-    match process_nvidia_smi() {
-        Ok(nvidia_buffer) => {
-            let mut buf: Vec<u8> = nvidia_buffer.as_bytes().iter().cloned().collect();
-            buffer.append(&mut buf);
-        }
-        Err(e) => error!("Failed to process nvidia-smi, {}", e),
+/// The registry's metric families a scrape with `settings` may see.
+fn registry_families(settings: &config::Settings) -> Vec<prometheus::proto::MetricFamily> {
+    let mut metric_families = prometheus::gather();
+    if settings.disable_exporter_metrics {
+        metric_families.retain(|mf| mf.get_name().starts_with("nvidia_"));
+    }
+    metric_families.retain(|mf| settings.metric_filter.allows(mf.get_name()));
+    // 自身记录的 GPU 序列（历史、事件等）不按租户过滤，整个去掉
+    if settings.gpu_filter.tenant.is_some() {
+        metric_families.retain(|mf| {
+            !mf.get_metric().iter().any(|m| {
+                m.get_label()
+                    .iter()
+                    .any(|l| matches!(l.get_name(), "gpu" | "uuid" | "pci_bus_id"))
+            })
+        });
     }
+    metric_families
+}
 
-    let response = Response::builder(StatusCode::Ok)
+async fn handle_healthz(_req: Request<State>) -> tide::Result {
+    // 只说明进程还在响应，不触发采集
+    Ok(Response::builder(StatusCode::Ok)
         .content_type(mime::PLAIN)
-        .body(Body::from(buffer))
-        .build();
-    Ok(response)
+        .body("OK")
+        .build())
 }
 
-async fn handle_home(_req: Request<()>) -> tide::Result {
-    let body = "<html>
-        <head><title>Nvidia SMI exporter</title></head>
-        <body>
-        <h1>Nvidia SMI exporter</h1>
-        <p><a href='/metrics'>Metrics</a></p>
-        </body>
-        </html>";
-
-    let body = Body::from(body);
-    let res = Response::builder(StatusCode::Ok)
+async fn handle_readyz(req: Request<State>) -> tide::Result {
+    let settings = req.state().settings.read().unwrap().clone();
+    let (status, body) = match collector::check_ready(&settings).await {
+        Ok(()) => (StatusCode::Ok, "OK".to_string()),
+        Err(e) => {
+            error!("Readiness check failed, {}", e);
+            (StatusCode::ServiceUnavailable, e)
+        }
+    };
+    Ok(Response::builder(status)
+        .content_type(mime::PLAIN)
         .body(body)
-        .content_type(mime::HTML)
-        .build();
-    Ok(res)
+        .build())
 }
@@ -0,0 +1,539 @@
+use anyhow::{bail, Context, Result};
+use async_std::net::UdpSocket;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::FromRawFd;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::cli::Cli;
+use crate::discovery::Advertised;
+use crate::push;
+
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const PORT: u16 = 5353;
+const SERVICE: &str = "_prometheus-http._tcp.local";
+/// Lists the service types on the network, for browsers that enumerate them.
+const SERVICES: &str = "_services._dns-sd._udp.local";
+/// RFC 6762 推荐的 TTL：主机名相关记录 120 秒，其余 75 分钟
+const HOST_TTL: u32 = 120;
+const TTL: u32 = 4500;
+/// Legacy unicast responses must not be cached longer than this.
+const LEGACY_TTL: u32 = 10;
+
+const A: u16 = 1;
+const PTR: u16 = 12;
+const TXT: u16 = 16;
+const AAAA: u16 = 28;
+const SRV: u16 = 33;
+const ANY: u16 = 255;
+const IN: u16 = 1;
+/// In a record's class, the record replaces what caches have for its name and type.
+const CACHE_FLUSH: u16 = 0x8000;
+/// In a question's class, the querier wants a unicast response.
+const UNICAST_RESPONSE: u16 = 0x8000;
+
+/// The exporter as a `_prometheus-http._tcp` DNS-SD service, announced and answered for over
+/// multicast DNS.
+pub struct Mdns {
+    socket: UdpSocket,
+    /// `--mdns.instance`
+    pub instance: String,
+    /// The SRV target, `<host>.local` unless advertised under another domain.
+    host: String,
+    /// Fixed when an IP address is advertised; None for those of the interfaces.
+    addresses: Option<Vec<IpAddr>>,
+    port: u16,
+    txt: Vec<String>,
+}
+
+/// A question, with the name in lower case.
+struct Question {
+    name: Vec<String>,
+    qtype: u16,
+    unicast: bool,
+}
+
+impl Mdns {
+    /// Binds the mDNS port; done before the sandbox is applied.
+    pub fn new(cli: &Cli, advertised: &Advertised) -> Result<Self> {
+        let instance = cli
+            .mdns_instance
+            .clone()
+            .unwrap_or_else(|| advertised.address());
+        if instance.is_empty() || instance.len() > 63 {
+            bail!(
+                "mDNS instance name {:?} must be 1 to 63 bytes, see --mdns.instance",
+                instance
+            );
+        }
+        let (host, addresses) = match advertised.host.parse::<IpAddr>() {
+            Ok(ip) => (format!("{}.local", push::hostname()), Some(vec![ip])),
+            Err(_) if !advertised.host.contains('.') => {
+                (format!("{}.local", advertised.host), None)
+            }
+            Err(_) if advertised.host.ends_with(".local") => (advertised.host.clone(), None),
+            // 其他域名的主机由普通 DNS 解析
+            Err(_) => (advertised.host.clone(), Some(Vec::new())),
+        };
+        let mut txt = vec![
+            format!("path={}", advertised.metrics_path),
+            format!("scheme={}", advertised.scheme),
+        ];
+        txt.extend(
+            advertised
+                .labels
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value)),
+        );
+        if let Some(entry) = txt.iter().find(|entry| entry.len() > 255) {
+            bail!("mDNS TXT entry {:?} is over 255 bytes", entry);
+        }
+        let socket = bind().context("Failed to bind the mDNS port 5353")?;
+        info!(
+            "Announcing {:?} as {} on {}:{} over mDNS",
+            instance, SERVICE, host, advertised.port
+        );
+        Ok(Mdns {
+            socket: UdpSocket::from(socket),
+            instance,
+            host,
+            addresses,
+            port: advertised.port,
+            txt,
+        })
+    }
+
+    /// Announces the service twice, then answers queries for it until dropped.
+    pub async fn run(&self) {
+        for _ in 0..2 {
+            if let Err(e) = self.send(&self.response(0, &[], TTL), multicast()).await {
+                warn!("Failed to announce over mDNS, {:#}", e);
+            }
+            async_std::task::sleep(Duration::from_secs(1)).await;
+        }
+        let mut packet = [0u8; 9000];
+        loop {
+            let (len, source) = match self.socket.recv_from(&mut packet).await {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("Failed to receive over mDNS, {}", e);
+                    async_std::task::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            if let Err(e) = self.answer(&packet[..len], source).await {
+                debug!("Failed to answer {} over mDNS, {:#}", source, e);
+            }
+        }
+    }
+
+    /// Tells the network the service is going away.
+    pub async fn goodbye(&self) -> Result<()> {
+        self.send(&self.response(0, &[], 0), multicast()).await
+    }
+
+    async fn answer(&self, packet: &[u8], source: SocketAddr) -> Result<()> {
+        let (id, questions) = match parse(packet) {
+            Some(query) => query,
+            None => return Ok(()),
+        };
+        if !questions.iter().any(|q| self.owns(q)) {
+            return Ok(());
+        }
+        debug!("Answering {} over mDNS", source);
+        // 源端口不是 5353 的是普通 DNS 客户端（如 dig），按单播 DNS 回复
+        if source.port() != PORT {
+            let questions = questions
+                .into_iter()
+                .filter(|q| self.owns(q))
+                .collect::<Vec<_>>();
+            return self
+                .send(&self.response(id, &questions, LEGACY_TTL), source)
+                .await;
+        }
+        let destination = match questions.iter().all(|q| q.unicast) {
+            true => source,
+            false => multicast(),
+        };
+        self.send(&self.response(0, &[], TTL), destination).await
+    }
+
+    /// Whether a question is about one of this service's names.
+    fn owns(&self, question: &Question) -> bool {
+        let is = |name: &[String]| eq(&question.name, name);
+        match question.qtype {
+            PTR => is(&labels(SERVICE)) || is(&labels(SERVICES)),
+            SRV | TXT => is(&self.instance_name()),
+            A | AAAA => self.addresses.is_none() && is(&labels(&self.host)),
+            ANY => {
+                is(&labels(SERVICE))
+                    || is(&labels(SERVICES))
+                    || is(&self.instance_name())
+                    || (self.addresses.is_none() && is(&labels(&self.host)))
+            }
+            _ => false,
+        }
+    }
+
+    fn instance_name(&self) -> Vec<String> {
+        let mut name = vec![self.instance.clone()];
+        name.extend(labels(SERVICE));
+        name
+    }
+
+    /// All the records of the service, with their TTLs capped at `ttl` (0 for goodbyes), in reply
+    /// to `questions` with `id` for legacy unicast.
+    fn response(&self, id: u16, questions: &[Question], ttl: u32) -> Vec<u8> {
+        let instance = self.instance_name();
+        let host = labels(&self.host);
+        let mut records = Vec::new();
+        let mut count = 0u16;
+        let mut record =
+            |name: &[String], rtype: u16, flush: bool, record_ttl: u32, data: &[u8]| {
+                put_name(&mut records, name);
+                records.extend_from_slice(&rtype.to_be_bytes());
+                let class = if flush && id == 0 {
+                    IN | CACHE_FLUSH
+                } else {
+                    IN
+                };
+                records.extend_from_slice(&class.to_be_bytes());
+                records.extend_from_slice(&record_ttl.min(ttl).to_be_bytes());
+                records.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                records.extend_from_slice(data);
+                count += 1;
+            };
+        let mut data = Vec::new();
+        put_name(&mut data, &labels(SERVICE));
+        record(&labels(SERVICES), PTR, false, TTL, &data);
+        let mut data = Vec::new();
+        put_name(&mut data, &instance);
+        record(&labels(SERVICE), PTR, false, TTL, &data);
+        let mut data = vec![0, 0, 0, 0]; // priority, weight
+        data.extend_from_slice(&self.port.to_be_bytes());
+        put_name(&mut data, &host);
+        record(&instance, SRV, true, HOST_TTL, &data);
+        let mut data = Vec::new();
+        for entry in &self.txt {
+            data.push(entry.len() as u8);
+            data.extend_from_slice(entry.as_bytes());
+        }
+        record(&instance, TXT, true, TTL, &data);
+        let addresses = match &self.addresses {
+            Some(addresses) => addresses.clone(),
+            None => interface_addresses(),
+        };
+        for address in addresses {
+            match address {
+                IpAddr::V4(ip) => record(&host, A, true, HOST_TTL, &ip.octets()),
+                IpAddr::V6(ip) => record(&host, AAAA, true, HOST_TTL, &ip.octets()),
+            }
+        }
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&id.to_be_bytes());
+        packet.extend_from_slice(&0x8400u16.to_be_bytes()); // QR, AA
+        packet.extend_from_slice(&(questions.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&count.to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0, 0]);
+        for question in questions {
+            put_name(&mut packet, &question.name);
+            packet.extend_from_slice(&question.qtype.to_be_bytes());
+            packet.extend_from_slice(&IN.to_be_bytes());
+        }
+        packet.extend_from_slice(&records);
+        packet
+    }
+
+    async fn send(&self, packet: &[u8], destination: SocketAddr) -> Result<()> {
+        self.socket
+            .send_to(packet, destination)
+            .await
+            .with_context(|| format!("Failed to send to {}", destination))?;
+        Ok(())
+    }
+}
+
+fn multicast() -> SocketAddr {
+    SocketAddr::from((GROUP, PORT))
+}
+
+/// A UDP socket on port 5353 joined to the mDNS group, sharing the port with any other
+/// responder such as Avahi.
+fn bind() -> Result<std::net::UdpSocket> {
+    // SAFETY: 新建的 fd 立即交给 UdpSocket 管理，其余调用只读写本地变量
+    let socket = unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let socket = std::net::UdpSocket::from_raw_fd(fd);
+        let one: libc::c_int = 1;
+        for option in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+            let ret = libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                &one as *const _ as *const libc::c_void,
+                std::mem::size_of_val(&one) as libc::socklen_t,
+            );
+            if ret != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+        let mut addr: libc::sockaddr_in = std::mem::zeroed();
+        addr.sin_family = libc::AF_INET as libc::sa_family_t;
+        addr.sin_port = PORT.to_be();
+        let ret = libc::bind(
+            fd,
+            &addr as *const _ as *const libc::sockaddr,
+            std::mem::size_of_val(&addr) as libc::socklen_t,
+        );
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        socket
+    };
+    socket.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_ttl_v4(255)?;
+    Ok(socket)
+}
+
+/// The addresses of the interfaces that are up, except loopback and IPv6 link-local ones.
+fn interface_addresses() -> Vec<IpAddr> {
+    let mut addresses = Vec::new();
+    let mut list: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: getifaddrs 成功后链表一直有效，直到 freeifaddrs
+    unsafe {
+        if libc::getifaddrs(&mut list) != 0 {
+            warn!(
+                "Failed to list the interface addresses, {}",
+                std::io::Error::last_os_error()
+            );
+            return addresses;
+        }
+        let mut entry = list;
+        while let Some(ifaddr) = entry.as_ref() {
+            entry = ifaddr.ifa_next;
+            let flags = ifaddr.ifa_flags as libc::c_int;
+            if ifaddr.ifa_addr.is_null()
+                || flags & libc::IFF_UP == 0
+                || flags & libc::IFF_LOOPBACK != 0
+            {
+                continue;
+            }
+            match (*ifaddr.ifa_addr).sa_family as libc::c_int {
+                libc::AF_INET => {
+                    let addr = &*(ifaddr.ifa_addr as *const libc::sockaddr_in);
+                    addresses.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                        addr.sin_addr.s_addr,
+                    ))));
+                }
+                libc::AF_INET6 => {
+                    let addr = &*(ifaddr.ifa_addr as *const libc::sockaddr_in6);
+                    let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                    if ip.segments()[0] & 0xffc0 != 0xfe80 {
+                        addresses.push(IpAddr::V6(ip));
+                    }
+                }
+                _ => {}
+            }
+        }
+        libc::freeifaddrs(list);
+    }
+    addresses.sort();
+    addresses.dedup();
+    addresses
+}
+
+/// The ID and questions of a query, or None for responses and malformed packets.
+fn parse(packet: &[u8]) -> Option<(u16, Vec<Question>)> {
+    let header = packet.get(..12)?;
+    let id = u16::from_be_bytes([header[0], header[1]]);
+    if header[2] & 0x80 != 0 {
+        return None;
+    }
+    let count = u16::from_be_bytes([header[4], header[5]]);
+    let mut pos = 12;
+    let mut questions = Vec::new();
+    for _ in 0..count {
+        let name = read_name(packet, &mut pos)?;
+        let fields = packet.get(pos..pos + 4)?;
+        pos += 4;
+        let qtype = u16::from_be_bytes([fields[0], fields[1]]);
+        let class = u16::from_be_bytes([fields[2], fields[3]]);
+        questions.push(Question {
+            name,
+            qtype,
+            unicast: class & UNICAST_RESPONSE != 0,
+        });
+    }
+    Some((id, questions))
+}
+
+/// A possibly compressed name at `pos`, which is moved past it.
+fn read_name(packet: &[u8], pos: &mut usize) -> Option<Vec<String>> {
+    let mut name = Vec::new();
+    let mut at = *pos;
+    let mut jumped = false;
+    // 压缩指针最多跟 16 次，防止循环
+    for _ in 0..16 {
+        loop {
+            let len = *packet.get(at)? as usize;
+            if len == 0 {
+                if !jumped {
+                    *pos = at + 1;
+                }
+                return Some(name);
+            }
+            if len & 0xc0 == 0xc0 {
+                let offset = (len & 0x3f) << 8 | *packet.get(at + 1)? as usize;
+                if !jumped {
+                    *pos = at + 2;
+                    jumped = true;
+                }
+                at = offset;
+                break;
+            }
+            let label = packet.get(at + 1..at + 1 + len)?;
+            name.push(String::from_utf8_lossy(label).to_lowercase());
+            at += 1 + len;
+        }
+    }
+    None
+}
+
+fn put_name(buf: &mut Vec<u8>, name: &[String]) {
+    for label in name {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+}
+
+fn labels(name: &str) -> Vec<String> {
+    name.split('.').map(String::from).collect()
+}
+
+/// Whether two names are the same, ignoring ASCII case.
+fn eq(a: &[String], b: &[String]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVICE_NAME: &[u8] = b"\x10_prometheus-http\x04_tcp\x05local\x00";
+    const INSTANCE_NAME: &[u8] = b"\x04gpu1\x10_prometheus-http\x04_tcp\x05local\x00";
+    const HOST_NAME: &[u8] = b"\x04gpu1\x05local\x00";
+
+    async fn mdns() -> Mdns {
+        Mdns {
+            socket: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            instance: "gpu1".to_string(),
+            host: "gpu1.local".to_string(),
+            addresses: Some(vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7))]),
+            port: 9101,
+            txt: vec!["path=/metrics".to_string()],
+        }
+    }
+
+    /// The records of the service with `class` for the unique ones and `ttl` for all of them.
+    fn records(class: &[u8], ttl: &[u8], host_ttl: &[u8]) -> Vec<u8> {
+        [
+            b"\x09_services\x07_dns-sd\x04_udp\x05local\x00\x00\x0c\x00\x01",
+            ttl,
+            b"\x00\x1d",
+            SERVICE_NAME,
+            SERVICE_NAME,
+            b"\x00\x0c\x00\x01",
+            ttl,
+            b"\x00\x22",
+            INSTANCE_NAME,
+            INSTANCE_NAME,
+            b"\x00\x21",
+            class,
+            host_ttl,
+            b"\x00\x12\x00\x00\x00\x00\x23\x8d",
+            HOST_NAME,
+            INSTANCE_NAME,
+            b"\x00\x10",
+            class,
+            ttl,
+            b"\x00\x0e\x0dpath=/metrics",
+            HOST_NAME,
+            b"\x00\x01",
+            class,
+            host_ttl,
+            b"\x00\x04\xc0\x00\x02\x07",
+        ]
+        .concat()
+    }
+
+    #[async_std::test]
+    async fn announces_records() {
+        let expected = [
+            &b"\x00\x00\x84\x00\x00\x00\x00\x05\x00\x00\x00\x00"[..],
+            &records(b"\x80\x01", b"\x00\x00\x11\x94", b"\x00\x00\x00\x78"),
+        ]
+        .concat();
+        assert_eq!(mdns().await.response(0, &[], TTL), expected);
+        // goodbye 的 TTL 为 0
+        let goodbye = [
+            &b"\x00\x00\x84\x00\x00\x00\x00\x05\x00\x00\x00\x00"[..],
+            &records(b"\x80\x01", b"\x00\x00\x00\x00", b"\x00\x00\x00\x00"),
+        ]
+        .concat();
+        assert_eq!(mdns().await.response(0, &[], 0), goodbye);
+    }
+
+    #[async_std::test]
+    async fn answers_legacy_unicast_queries() {
+        let mdns = mdns().await;
+        // 第二个问题的名字压缩指向第一个问题里的服务名
+        let query = [
+            &b"\x12\x34\x01\x00\x00\x02\x00\x00\x00\x00\x00\x00"[..],
+            b"\x04GPU1",
+            SERVICE_NAME,
+            b"\x00\x21\x00\x01",
+            b"\x06_other\xc0\x11\x00\x21\x00\x01",
+        ]
+        .concat();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        mdns.answer(&query, client.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut response = [0u8; 1024];
+        let (len, _) =
+            async_std::future::timeout(Duration::from_secs(5), client.recv_from(&mut response))
+                .await
+                .expect("no response")
+                .unwrap();
+        // 只回答自己的问题，不带缓存刷新位，TTL 不超过 10 秒
+        let expected = [
+            &b"\x12\x34\x84\x00\x00\x01\x00\x05\x00\x00\x00\x00"[..],
+            INSTANCE_NAME,
+            b"\x00\x21\x00\x01",
+            &records(b"\x00\x01", b"\x00\x00\x00\x0a", b"\x00\x00\x00\x0a"),
+        ]
+        .concat();
+        assert_eq!(&response[..len], &expected[..]);
+    }
+
+    #[test]
+    fn parses_queries() {
+        let query = b"\x00\x07\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+            \x10_Prometheus-HTTP\x04_tcp\x05local\x00\x00\x0c\x80\x01";
+        let (id, questions) = parse(query).unwrap();
+        assert_eq!(id, 7);
+        assert_eq!(questions[0].name, labels(SERVICE));
+        assert_eq!((questions[0].qtype, questions[0].unicast), (PTR, true));
+        // 响应、截断的包和循环的压缩指针
+        assert!(parse(b"\x00\x07\x84\x00\x00\x00\x00\x00\x00\x00\x00\x00").is_none());
+        assert!(parse(&query[..30]).is_none());
+        assert!(
+            parse(b"\x00\x07\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\xc0\x0c\x00\x0c\x00\x01")
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,423 @@
+use prometheus::proto::MetricType;
+use serde::Serialize;
+use tide::{Body, Request, Response, StatusCode};
+
+use crate::collector::{self, COLLECTORS};
+use crate::State;
+
+/// Units of the collectors' metrics, in nvidia-smi's `nounits` output.
+const UNITS: &[(&str, &str)] = &[
+    ("fan", "percent"),
+    ("temperature", "celsius"),
+    ("clocks", "megahertz"),
+    ("power", "watts"),
+    ("utilization", "percent"),
+    ("memory", "mebibytes"),
+];
+
+/// A metric besides the collectors' ones.
+struct Known {
+    name: &'static str,
+    kind: &'static str,
+    unit: Option<&'static str>,
+    help: &'static str,
+    /// The option exporting it, none if it is always exported.
+    option: Option<&'static str>,
+}
+
+const METRICS: &[Known] = &[
+    Known {
+        name: collector::PROCESS_METRIC,
+        kind: "gauge",
+        unit: Some("mebibytes"),
+        help: "GPU memory used by a compute process.",
+        option: Some("--collect.processes"),
+    },
+    Known {
+        name: collector::MIG_METRIC,
+        kind: "gauge",
+        unit: None,
+        help: "A MIG device with its profile.",
+        option: Some("--collect.mig-devices"),
+    },
+    Known {
+        name: collector::PASSTHROUGH_METRIC,
+        kind: "gauge",
+        unit: None,
+        help: "An NVIDIA GPU bound to vfio-pci for passthrough to a VM.",
+        option: Some("--collect.passthrough"),
+    },
+    Known {
+        name: collector::ALLOCATED_METRIC,
+        kind: "gauge",
+        unit: None,
+        help: "Whether the kubelet allocated the GPU, or any of its MIG devices.",
+        option: Some("--kubernetes.pod-labels"),
+    },
+    Known {
+        name: collector::JETSON_TEMPERATURE_METRIC,
+        kind: "gauge",
+        unit: Some("celsius"),
+        help: "Temperature of each sensor of a Jetson module.",
+        option: Some("--collect.jetson"),
+    },
+    Known {
+        name: collector::JETSON_POWER_METRIC,
+        kind: "gauge",
+        unit: Some("watts"),
+        help: "Power of each rail of a Jetson module.",
+        option: Some("--collect.jetson"),
+    },
+    Known {
+        name: "nvidia_scrape_success",
+        kind: "gauge",
+        unit: None,
+        help: "Whether the GPUs of the last scrape or push were collected; 0 while the last readings are served with the circuit breaker open.",
+        option: None,
+    },
+    Known {
+        name: "nvidia_events_total",
+        kind: "counter",
+        unit: None,
+        help: "Number of NVML events by type (ecc_single_bit, ecc_double_bit, xid, clock), with --collect.events.",
+        option: Some("--collect.events"),
+    },
+    Known {
+        name: "nvidia_xid_errors_total",
+        kind: "counter",
+        unit: None,
+        help: "Number of XID critical errors by XID, with --collect.events.",
+        option: Some("--collect.events"),
+    },
+    Known {
+        name: "nvidia_recent_event_info",
+        kind: "gauge",
+        unit: None,
+        help: "The last 20 NVML events, with their data (the XID of xid events) and Unix time.",
+        option: Some("--collect.events"),
+    },
+    Known {
+        name: "nvidia_kernel_xid_total",
+        kind: "counter",
+        unit: None,
+        help: "Number of NVRM Xid messages in the kernel log since boot by GPU index (empty if nvidia-smi does not list the GPU), PCI address and XID, with --collect.kernel-xid.",
+        option: Some("--collect.kernel-xid"),
+    },
+    Known {
+        name: "nvidia_topology_link_info",
+        kind: "gauge",
+        unit: None,
+        help: "How the GPU reaches each other GPU and NIC, as in nvidia-smi topo -m, with --collect.topology.",
+        option: Some("--collect.topology"),
+    },
+    Known {
+        name: "nvidia_topology_nvlink_peers",
+        kind: "gauge",
+        unit: None,
+        help: "Number of other GPUs the GPU reaches over NVLink, with --collect.topology.",
+        option: Some("--collect.topology"),
+    },
+    Known {
+        name: "nvidia_topology_numa_node",
+        kind: "gauge",
+        unit: None,
+        help: "NUMA node closest to the GPU, with --collect.topology.",
+        option: Some("--collect.topology"),
+    },
+    Known {
+        name: "nvidia_gpu_platform_info",
+        kind: "gauge",
+        unit: None,
+        help: "The chassis, slot and tray of the GPU on DGX/HGX and OEM systems, from the platform section of nvidia-smi -q, with --collect.platform.",
+        option: Some("--collect.platform"),
+    },
+    Known {
+        name: "nvidia_energy_cost_total",
+        kind: "counter",
+        unit: None,
+        help: "Cost of the energy the GPU drew at --energy.price-per-kwh, since the exporter started or, with --state-file, first ran.",
+        option: Some("--energy.price-per-kwh"),
+    },
+    Known {
+        name: "nvidia_co2e_grams_total",
+        kind: "counter",
+        unit: Some("grams"),
+        help: "Grams of CO2-equivalent emitted for the energy the GPU drew at the carbon intensity, since the exporter started or, with --state-file, first ran.",
+        option: Some("--energy.carbon-intensity"),
+    },
+    Known {
+        name: "nvidia_carbon_intensity_grams_per_kwh",
+        kind: "gauge",
+        unit: None,
+        help: "Grams of CO2-equivalent per kWh the emissions are counted at.",
+        option: Some("--energy.carbon-intensity"),
+    },
+    Known {
+        name: "nvidia_temperature_gpu_zscore",
+        kind: "gauge",
+        unit: None,
+        help: "Standard deviations of the last GPU temperature from its mean over --history.retention.",
+        option: Some("--history.retention"),
+    },
+    Known {
+        name: "nvidia_power_draw_zscore",
+        kind: "gauge",
+        unit: None,
+        help: "Standard deviations of the last power draw from its mean over --history.retention.",
+        option: Some("--history.retention"),
+    },
+    Known {
+        name: "nvidia_utilization_gpu_collapsed",
+        kind: "gauge",
+        unit: None,
+        help: "Whether the GPU utilization dropped to 5% or less after averaging 50% or more over the previous 5 minutes.",
+        option: Some("--history.retention"),
+    },
+    Known {
+        name: "nvidia_smi_exporter_collect_failures_total",
+        kind: "counter",
+        unit: None,
+        help: "Number of failed nvidia-smi collections by reason (exec, timeout, parse).",
+        option: None,
+    },
+    Known {
+        name: "nvidia_smi_exporter_last_collect_success_timestamp_seconds",
+        kind: "gauge",
+        unit: Some("seconds"),
+        help: "Unix timestamp of the last successful nvidia-smi collection.",
+        option: None,
+    },
+    Known {
+        name: "nvidia_smi_exporter_scrape_duration_seconds",
+        kind: "histogram",
+        unit: Some("seconds"),
+        help: "Time taken to collect the metrics of a scrape of the telemetry path.",
+        option: None,
+    },
+    Known {
+        name: "nvidia_smi_exporter_degraded",
+        kind: "gauge",
+        unit: None,
+        help: "Whether the optional collections (processes, MIG devices) are skipped because collections exceeded --collect.latency-budget.",
+        option: None,
+    },
+    Known {
+        name: "nvidia_smi_exporter_circuit_breaker_open",
+        kind: "gauge",
+        unit: None,
+        help: "Whether nvidia-smi is not run after --collect.breaker-failures failed collections in a row.",
+        option: None,
+    },
+    Known {
+        name: "nvidia_smi_exporter_environment_info",
+        kind: "gauge",
+        unit: None,
+        help: "The container the exporter runs in, if any, and whether it is in a user namespace (unprivileged).",
+        option: None,
+    },
+    Known {
+        name: "nvidia_smi_exporter_platform_info",
+        kind: "gauge",
+        unit: None,
+        help: "The platform the exporter runs on (linux, wsl2), and whether its driver reports only part of the metrics.",
+        option: None,
+    },
+    Known {
+        name: "nvidia_smi_exporter_device_access",
+        kind: "gauge",
+        unit: None,
+        help: "NVIDIA device nodes by whether the exporter can open them (ok, denied, missing).",
+        option: None,
+    },
+    Known {
+        name: "nvidia_smi_exporter_scrapes_rejected_total",
+        kind: "counter",
+        unit: None,
+        help: "Number of scrapes rejected before collection by reason (concurrency, rate_limit).",
+        option: Some("--web.max-requests"),
+    },
+    Known {
+        name: "nvidia_smi_exporter_scrape_deadline_exceeded_total",
+        kind: "counter",
+        unit: None,
+        help: "Number of scrapes aborted by --web.request-timeout.",
+        option: Some("--web.request-timeout"),
+    },
+    Known {
+        name: "nvidia_smi_exporter_container_runtime_info",
+        kind: "gauge",
+        unit: None,
+        help: "Container runtimes whose API socket was found, by runtime and socket.",
+        option: Some("--collect.processes"),
+    },
+    Known {
+        name: "nvidia_smi_exporter_ssh_up",
+        kind: "gauge",
+        unit: None,
+        help: "Whether nvidia-smi could be run on each SSH host in the last collection.",
+        option: Some("--ssh.host"),
+    },
+    Known {
+        name: "nvidia_smi_exporter_federate_up",
+        kind: "gauge",
+        unit: None,
+        help: "Whether each --federate.target answered the last scrape.",
+        option: Some("--federate.target"),
+    },
+    Known {
+        name: "nvidia_smi_exporter_hub_connections",
+        kind: "gauge",
+        unit: None,
+        help: "Agents connected to the hub.",
+        option: Some("--hub.listen"),
+    },
+    Known {
+        name: "nvidia_smi_exporter_hub_last_report_timestamp_seconds",
+        kind: "gauge",
+        unit: Some("seconds"),
+        help: "Unix timestamp of the last report of each agent the hub has not forgotten.",
+        option: Some("--hub.listen"),
+    },
+    Known {
+        name: "nvidia_smi_exporter_redfish_up",
+        kind: "gauge",
+        unit: None,
+        help: "Whether the BMC answered the last Redfish collection.",
+        option: Some("--redfish.url"),
+    },
+    Known {
+        name: "nvidia_smi_exporter_amd_smi_up",
+        kind: "gauge",
+        unit: None,
+        help: "Whether amd-smi or rocm-smi answered the last collection of AMD GPUs.",
+        option: Some("--collect.amd"),
+    },
+    Known {
+        name: "nvidia_smi_exporter_xpu_smi_up",
+        kind: "gauge",
+        unit: None,
+        help: "Whether xpu-smi answered the last collection of Intel GPUs.",
+        option: Some("--collect.intel"),
+    },
+    Known {
+        name: "nvidia_smi_exporter_tegrastats_up",
+        kind: "gauge",
+        unit: None,
+        help: "Whether tegrastats answered the last collection of the Jetson GPU.",
+        option: Some("--collect.jetson"),
+    },
+    Known {
+        name: "nvidia_smi_exporter_nvml_events_up",
+        kind: "gauge",
+        unit: None,
+        help: "Whether NVML events are being listened to, with --collect.events.",
+        option: Some("--collect.events"),
+    },
+    Known {
+        name: "nvidia_smi_exporter_kernel_log_up",
+        kind: "gauge",
+        unit: None,
+        help: "Whether the kernel log is being read for Xid messages, with --collect.kernel-xid.",
+        option: Some("--collect.kernel-xid"),
+    },
+    Known {
+        name: "nvidia_smi_exporter_carbon_intensity_up",
+        kind: "gauge",
+        unit: None,
+        help: "Whether --energy.carbon-intensity-url answered the last fetch.",
+        option: Some("--energy.carbon-intensity-url"),
+    },
+    Known {
+        name: "nvidia_smi_exporter_push_failures_total",
+        kind: "counter",
+        unit: None,
+        help: "Number of failed pushes by target (remote_write, pushgateway, influxdb, graphite, statsd, otlp, zabbix, mqtt, nats, fluentd, kafka, cloudwatch, cloud_monitoring, azure_monitor, elasticsearch, hub, agentx, alerts).",
+        option: Some("--push.interval"),
+    },
+];
+
+#[derive(Serialize)]
+struct Metadata {
+    metrics: Vec<Metric>,
+}
+
+#[derive(Serialize)]
+struct Metric {
+    name: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    unit: Option<&'static str>,
+    help: String,
+    /// The collector of a GPU metric, `query-field` for a `--query-field`.
+    collector: Option<&'static str>,
+    /// The option the metric needs, none if it is always exported.
+    option: Option<String>,
+}
+
+/// `GET /api/v1/metadata`: every metric this version can export, with its type, unit, help and
+/// the collector or option it needs, before relabeling. Runs no nvidia-smi.
+pub async fn handle_metadata(req: Request<State>) -> tide::Result {
+    let mut metrics = Vec::new();
+    for c in COLLECTORS {
+        let unit = UNITS
+            .iter()
+            .find(|(name, _)| *name == c.name)
+            .map(|(_, unit)| *unit);
+        for (field, metric) in c.fields {
+            metrics.push(Metric {
+                name: metric.to_string(),
+                kind: "gauge",
+                unit,
+                help: format!("nvidia-smi --query-gpu={}", field),
+                collector: Some(c.name),
+                option: Some(format!("--collector.{}", c.name)),
+            });
+        }
+    }
+    for field in &req.state().settings.read().unwrap().query_fields {
+        metrics.push(Metric {
+            name: field.metric.clone(),
+            kind: "gauge",
+            unit: None,
+            help: field
+                .help
+                .clone()
+                .unwrap_or_else(|| format!("nvidia-smi --query-gpu={}", field.field)),
+            collector: Some("query-field"),
+            option: Some("--query-field".to_string()),
+        });
+    }
+    for known in METRICS {
+        metrics.push(Metric {
+            name: known.name.to_string(),
+            kind: known.kind,
+            unit: known.unit,
+            help: known.help.to_string(),
+            collector: None,
+            option: known.option.map(str::to_string),
+        });
+    }
+    // 其余注册过的指标，如 process_*
+    for family in prometheus::gather() {
+        if metrics.iter().any(|m| m.name == family.get_name()) {
+            continue;
+        }
+        metrics.push(Metric {
+            name: family.get_name().to_string(),
+            kind: match family.get_field_type() {
+                MetricType::COUNTER => "counter",
+                MetricType::GAUGE => "gauge",
+                MetricType::HISTOGRAM => "histogram",
+                MetricType::SUMMARY => "summary",
+                MetricType::UNTYPED => "untyped",
+            },
+            unit: None,
+            help: family.get_help().to_string(),
+            collector: None,
+            option: None,
+        });
+    }
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&Metadata { metrics })?)
+        .build())
+}
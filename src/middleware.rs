@@ -0,0 +1,654 @@
+use anyhow::bail;
+use async_std::future::timeout;
+use ipnet::IpNet;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_int_counter, register_int_counter_vec, Encoder, IntCounter, IntCounterVec,
+};
+use ruzstd::encoding::CompressionLevel;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tide::http::headers::{ACCEPT_ENCODING, CONTENT_ENCODING, USER_AGENT, VARY};
+use tide::{http::mime, Middleware, Next, Request, Response, StatusCode};
+use tide_compress::CompressMiddleware;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+use crate::runtime::RequestGuard;
+
+const X_FORWARDED_FOR: &str = "X-Forwarded-For";
+const X_REQUEST_ID: &str = "X-Request-Id";
+
+lazy_static! {
+    static ref SCRAPES_REJECTED: IntCounterVec = register_int_counter_vec!(
+        "nvidia_smi_exporter_scrapes_rejected_total",
+        "Number of scrapes rejected before collection by reason (concurrency, rate_limit).",
+        &["reason"]
+    )
+    .unwrap();
+    static ref SCRAPE_DEADLINE_EXCEEDED: IntCounter = register_int_counter!(
+        "nvidia_smi_exporter_scrape_deadline_exceeded_total",
+        "Number of scrapes aborted by --web.request-timeout."
+    )
+    .unwrap();
+}
+
+/// `--web.access-log.format`: literal text with `{field}` placeholders.
+#[derive(Clone, Debug)]
+pub struct AccessLogFormat {
+    template: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug)]
+enum Part {
+    Text(String),
+    Field(&'static str),
+}
+
+const ACCESS_LOG_FIELDS: &[&str] = &[
+    "client",
+    "method",
+    "path",
+    "status",
+    "duration",
+    "request_id",
+    "user_agent",
+];
+
+impl FromStr for AccessLogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => bail!("unclosed {{ in {:?}", s),
+            };
+            let name = &rest[start + 1..end];
+            match ACCESS_LOG_FIELDS.iter().find(|&&field| field == name) {
+                Some(field) => parts.push(Part::Field(field)),
+                None => bail!(
+                    "unknown field {{{}}}, expected one of {}",
+                    name,
+                    ACCESS_LOG_FIELDS.join(", ")
+                ),
+            }
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        Ok(AccessLogFormat {
+            template: s.to_string(),
+            parts,
+        })
+    }
+}
+
+impl std::fmt::Display for AccessLogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.template)
+    }
+}
+
+impl AccessLogFormat {
+    fn render(&self, value: impl Fn(&str) -> String) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Field(field) => value(field),
+            })
+            .collect()
+    }
+}
+
+/// Wraps every request in an `http` span carrying the client address and a request ID, and with
+/// `--web.access-log` logs each response to the `access` target.
+pub struct TraceMiddleware {
+    trusted_proxies: Vec<IpNet>,
+    access_log: Option<AccessLogFormat>,
+    id_prefix: u64,
+    next_id: AtomicU64,
+}
+
+impl TraceMiddleware {
+    /// `X-Forwarded-For` is only believed when the peer is in `trusted_proxies`.
+    pub fn new(trusted_proxies: Vec<IpNet>, access_log: Option<AccessLogFormat>) -> Self {
+        TraceMiddleware {
+            trusted_proxies,
+            access_log,
+            id_prefix: RandomState::new().build_hasher().finish(),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Keeps an incoming `X-Request-Id` if it is safe to log, otherwise makes a new one.
+    fn request_id<State>(&self, req: &Request<State>) -> String {
+        if let Some(id) = req.header(X_REQUEST_ID) {
+            let id = id.as_str();
+            let valid = id.len() <= 128
+                && id
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b));
+            if !id.is_empty() && valid {
+                return id.to_string();
+            }
+        }
+        let n = self.next_id.fetch_add(1, Ordering::Relaxed);
+        format!("{:016x}-{:x}", self.id_prefix, n)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for TraceMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let _in_flight = RequestGuard::new();
+        let started = Instant::now();
+        let request_id = self.request_id(&req);
+        // Unix socket 上没有对端地址
+        let client = client_ip(&req, &self.trusted_proxies)
+            .map_or_else(|| "unix".to_string(), |ip| ip.to_string());
+        let method = req.method();
+        let path = req.url().path().to_string();
+        let user_agent = req.header(USER_AGENT).map(|ua| ua.as_str().to_string());
+        let span = info_span!("http", %method, %path, %client, %request_id);
+        let mut res = next.run(req).instrument(span.clone()).await;
+        span.in_scope(|| {
+            let status = u16::from(res.status());
+            let elapsed = started.elapsed();
+            match res.error() {
+                Some(e) => error!(status, ?elapsed, "Request failed, {}", e),
+                None => debug!(status, ?elapsed, "Response sent"),
+            }
+            if let Some(format) = &self.access_log {
+                let line = format.render(|field| match field {
+                    "client" => client.clone(),
+                    "method" => method.to_string(),
+                    "path" => path.clone(),
+                    "status" => status.to_string(),
+                    "duration" => format!("{:.3}", elapsed.as_secs_f64() * 1000.0),
+                    "request_id" => request_id.clone(),
+                    "user_agent" => user_agent.clone().unwrap_or_else(|| "-".to_string()),
+                    _ => unreachable!(),
+                });
+                info!(target: "access", "{}", line);
+            }
+        });
+        res.insert_header(X_REQUEST_ID, request_id);
+        Ok(res)
+    }
+}
+
+/// Rejects clients outside the `--web.allow-cidr` networks with 403.
+pub struct AllowCidrMiddleware(pub Vec<IpNet>);
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AllowCidrMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        // Unix socket 上没有对端地址，视为本机访问
+        if let Some(ip) = peer_ip(&req) {
+            if !self.0.iter().any(|net| net.contains(&ip)) {
+                warn!("Rejected request from {} outside --web.allow-cidr", ip);
+                return Ok(Response::new(StatusCode::Forbidden));
+            }
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+/// Rejects clients other than loopback addresses and Unix sockets with 403.
+pub struct LocalOnlyMiddleware;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for LocalOnlyMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let local = peer_ip(&req).is_none_or(|ip| ip.is_loopback());
+        if !local {
+            warn!(
+                "Rejected {} from {:?}, only local clients are allowed",
+                req.url().path(),
+                req.peer_addr()
+            );
+            return Ok(Response::new(StatusCode::Forbidden));
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+/// The peer, or behind `trusted_proxies` the right-most `X-Forwarded-For` entry that is not one.
+fn client_ip<State>(req: &Request<State>, trusted_proxies: &[IpNet]) -> Option<IpAddr> {
+    let mut client = peer_ip(req)?;
+    let forwarded = req
+        .header(X_FORWARDED_FOR)
+        .into_iter()
+        .flat_map(|values| values.iter())
+        .flat_map(|value| value.as_str().split(','))
+        .collect::<Vec<_>>();
+    for hop in forwarded.iter().rev() {
+        if !trusted_proxies.iter().any(|net| net.contains(&client)) {
+            break;
+        }
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) => client = unmap(ip),
+            Err(_) => break,
+        }
+    }
+    Some(client)
+}
+
+/// The TCP peer address with IPv4-mapped IPv6 addresses turned back into IPv4; `None` on Unix
+/// sockets.
+fn peer_ip<State>(req: &Request<State>) -> Option<IpAddr> {
+    req.peer_addr()
+        .and_then(|addr| addr.parse::<SocketAddr>().ok())
+        .map(|addr| unmap(addr.ip()))
+}
+
+fn unmap(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+        ip => ip,
+    }
+}
+
+/// tide-compress negotiated with only the `--web.compression` algorithms (`br`, `gzip`,
+/// `deflate`), and zstd, which tide-compress lacks, done here.
+pub struct CompressionMiddleware {
+    inner: CompressMiddleware,
+    algorithms: Vec<String>,
+    threshold: usize,
+}
+
+impl CompressionMiddleware {
+    pub fn new(algorithms: Vec<String>, threshold: usize) -> Self {
+        CompressionMiddleware {
+            inner: CompressMiddleware::with_threshold(threshold),
+            algorithms,
+            threshold,
+        }
+    }
+
+    /// Drops unwanted codings from Accept-Encoding and expands `*` to the allowed ones.
+    fn accept_encoding(&self, header: &str) -> String {
+        let mut accepted = Vec::new();
+        for proposal in header.split(',').map(str::trim) {
+            let (coding, params) = match proposal.find(';') {
+                Some(i) => proposal.split_at(i),
+                None => (proposal, ""),
+            };
+            let coding = coding.trim().to_ascii_lowercase();
+            if coding == "*" {
+                accepted.extend(self.algorithms.iter().map(|a| format!("{}{}", a, params)));
+            } else if coding == "identity" || self.algorithms.contains(&coding) {
+                accepted.push(proposal.to_string());
+            }
+        }
+        accepted.join(", ")
+    }
+
+    /// Whether zstd is allowed and the client ranks it no lower than any other allowed coding.
+    fn prefers_zstd(&self, header: &str) -> bool {
+        if !self.algorithms.iter().any(|a| a == "zstd") {
+            return false;
+        }
+        let mut zstd = 0.0;
+        let mut others: f64 = 0.0;
+        for proposal in self.accept_encoding(header).split(',').map(str::trim) {
+            let (coding, params) = match proposal.find(';') {
+                Some(i) => proposal.split_at(i),
+                None => (proposal, ""),
+            };
+            // q 缺省为 1，写错的按 0 处理
+            let quality = match params.trim_start_matches(';').trim().strip_prefix("q=") {
+                Some(q) => q.trim().parse().unwrap_or(0.0),
+                None => 1.0,
+            };
+            match coding.trim().to_ascii_lowercase().as_str() {
+                "zstd" => zstd = quality,
+                "identity" => {}
+                _ => others = others.max(quality),
+            }
+        }
+        zstd > 0.0 && zstd >= others
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for CompressionMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let zstd = req
+            .header(ACCEPT_ENCODING)
+            .is_some_and(|header| self.prefers_zstd(header.as_str()));
+        if zstd {
+            req.remove_header(ACCEPT_ENCODING);
+            let mut res = next.run(req).await;
+            // 长度未知的流式响应（/stream）不压缩
+            let compress = res.header(CONTENT_ENCODING).is_none()
+                && res.len().is_some_and(|len| len >= self.threshold);
+            if compress {
+                let content_type = res.content_type();
+                let body = res.take_body().into_bytes().await?;
+                res.set_body(ruzstd::encoding::compress_to_vec(
+                    body.as_slice(),
+                    CompressionLevel::Fastest,
+                ));
+                if let Some(content_type) = content_type {
+                    res.set_content_type(content_type);
+                }
+                res.insert_header(CONTENT_ENCODING, "zstd");
+            }
+            res.append_header(VARY, "Accept-Encoding");
+            return Ok(res);
+        }
+        if let Some(header) = req.header(ACCEPT_ENCODING) {
+            let accepted = self.accept_encoding(header.as_str());
+            if accepted.is_empty() {
+                req.remove_header(ACCEPT_ENCODING);
+            } else {
+                req.insert_header(ACCEPT_ENCODING, accepted);
+            }
+        }
+        self.inner.handle(req, next).await
+    }
+}
+
+/// Delegates to a middleware that `/-/reload` can replace while the server is running.
+pub struct Swappable<M>(Arc<RwLock<Arc<M>>>);
+
+impl<M> Swappable<M> {
+    pub fn new(inner: M) -> Self {
+        Swappable(Arc::new(RwLock::new(Arc::new(inner))))
+    }
+
+    pub fn swap(&self, inner: M) {
+        *self.0.write().unwrap() = Arc::new(inner);
+    }
+}
+
+impl<M> Clone for Swappable<M> {
+    fn clone(&self) -> Self {
+        Swappable(self.0.clone())
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State, M> Middleware<State> for Swappable<M>
+where
+    State: Clone + Send + Sync + 'static,
+    M: Middleware<State>,
+{
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        // 先取出当前实例再释放锁，处理过程中的 reload 不影响本次请求
+        let inner = self.0.read().unwrap().clone();
+        inner.handle(req, next).await
+    }
+}
+
+/// Adds the web config's `http_server_config.headers` to every response.
+pub struct HeadersMiddleware(pub BTreeMap<String, String>);
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for HeadersMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let mut res = next.run(req).await;
+        for (name, value) in &self.0 {
+            res.insert_header(name.as_str(), value.as_str());
+        }
+        Ok(res)
+    }
+}
+
+/// Clients `ScrapeLimitMiddleware` keeps a rate limit bucket for at most.
+const MAX_RATE_LIMITED_CLIENTS: usize = 1024;
+
+/// Caps concurrent scrapes and, optionally, scrapes per minute from each client IP. Clones share
+/// the limits, so routes that all run nvidia-smi count together.
+#[derive(Clone)]
+pub struct ScrapeLimitMiddleware {
+    max_in_flight: usize,
+    in_flight: Arc<AtomicUsize>,
+    per_minute: u32,
+    trusted_proxies: Arc<Vec<IpNet>>,
+    clients: Arc<Mutex<HashMap<IpAddr, (f64, Instant)>>>,
+}
+
+impl ScrapeLimitMiddleware {
+    /// Zero disables the respective limit. Clients are told apart as in `TraceMiddleware`, so
+    /// behind `trusted_proxies` each gets its own rate limit.
+    pub fn new(max_in_flight: usize, per_minute: u32, trusted_proxies: Vec<IpNet>) -> Self {
+        for reason in &["concurrency", "rate_limit"] {
+            SCRAPES_REJECTED.with_label_values(&[reason]);
+        }
+        ScrapeLimitMiddleware {
+            max_in_flight,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            per_minute,
+            trusted_proxies: Arc::new(trusted_proxies),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Token bucket per client holding up to `per_minute` scrapes, refilled continuously.
+    fn allow(&self, ip: IpAddr) -> bool {
+        let capacity = f64::from(self.per_minute);
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_RATE_LIMITED_CLIENTS && !clients.contains_key(&ip) {
+            // 已经攒满的桶和新建的没有区别，可以丢掉
+            clients.retain(|_, (tokens, at)| {
+                *tokens + now.duration_since(*at).as_secs_f64() * capacity / 60.0 < capacity
+            });
+            // 仍然满了（大量不同来源，例如 IPv6）就丢掉最久没来的
+            while clients.len() >= MAX_RATE_LIMITED_CLIENTS {
+                let oldest = clients
+                    .iter()
+                    .min_by_key(|(_, (_, at))| *at)
+                    .map(|(ip, _)| *ip)
+                    .unwrap();
+                clients.remove(&oldest);
+            }
+        }
+        let (tokens, at) = clients.entry(ip).or_insert((capacity, now));
+        *tokens = (*tokens + now.duration_since(*at).as_secs_f64() * capacity / 60.0).min(capacity);
+        *at = now;
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ScrapeLimitMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if self.per_minute > 0 {
+            // Unix socket 上没有对端地址，不限速；双栈监听时 IPv4 客户端与映射地址共用一个桶
+            if let Some(ip) = client_ip(&req, &self.trusted_proxies) {
+                if !self.allow(ip) {
+                    warn!("Rate limited scrape from {}", ip);
+                    SCRAPES_REJECTED.with_label_values(&["rate_limit"]).inc();
+                    crate::openmetrics::exemplar(&*SCRAPES_REJECTED, &["rate_limit"]);
+                    return Ok(Response::new(StatusCode::TooManyRequests));
+                }
+            }
+        }
+
+        let in_flight = InFlight::enter(&self.in_flight);
+        if self.max_in_flight > 0 && in_flight.0 > self.max_in_flight {
+            warn!("Rejected scrape, {} already in flight", self.max_in_flight);
+            SCRAPES_REJECTED.with_label_values(&["concurrency"]).inc();
+            crate::openmetrics::exemplar(&*SCRAPES_REJECTED, &["concurrency"]);
+            return Ok(Response::new(StatusCode::ServiceUnavailable));
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+/// Holds a slot of `ScrapeLimitMiddleware::in_flight`, with the count including itself.
+struct InFlight<'a>(usize, &'a AtomicUsize);
+
+impl<'a> InFlight<'a> {
+    fn enter(counter: &'a AtomicUsize) -> Self {
+        InFlight(counter.fetch_add(1, Ordering::SeqCst) + 1, counter)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.1.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Aborts a scrape that runs past the deadline and answers 503 with the exporter's own metrics.
+pub struct DeadlineMiddleware(Duration);
+
+impl DeadlineMiddleware {
+    pub fn new(deadline: Duration) -> Self {
+        lazy_static::initialize(&SCRAPE_DEADLINE_EXCEEDED);
+        DeadlineMiddleware(deadline)
+    }
+}
+
+/// Counts a scrape, or a `/stream` collection, aborted after `deadline`.
+pub fn deadline_exceeded(deadline: Duration) {
+    warn!("Scrape aborted after {:?}", deadline);
+    SCRAPE_DEADLINE_EXCEEDED.inc();
+    crate::openmetrics::exemplar(&*SCRAPE_DEADLINE_EXCEEDED, &[]);
+}
+
+#[tide::utils::async_trait]
+impl Middleware<crate::State> for DeadlineMiddleware {
+    async fn handle(
+        &self,
+        req: Request<crate::State>,
+        next: Next<'_, crate::State>,
+    ) -> tide::Result {
+        // 无效的参数由 handler 回 400，这时没有可用的设置
+        let settings = crate::scrape_settings(&req).ok();
+        // 超时后丢弃 handler，其中的 nvidia-smi 随 kill_on_drop 被杀掉
+        match timeout(self.0, next.run(req)).await {
+            Ok(res) => Ok(res),
+            Err(_) => {
+                deadline_exceeded(self.0);
+                // 与正常抓取一样按 --metric-exclude、--disable-exporter-metrics 和租户过滤
+                let mut metric_families = settings
+                    .as_ref()
+                    .map(crate::registry_families)
+                    .unwrap_or_default();
+                metric_families.retain(|mf| mf.get_name().starts_with("nvidia_smi_exporter_"));
+                let mut buffer = Vec::new();
+                prometheus::TextEncoder::new().encode(&metric_families, &mut buffer)?;
+                Ok(Response::builder(StatusCode::ServiceUnavailable)
+                    .content_type(mime::PLAIN)
+                    .body(buffer)
+                    .build())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zstd_negotiation() {
+        let all = CompressionMiddleware::new(
+            ["zstd", "br", "gzip", "deflate"].map(String::from).to_vec(),
+            0,
+        );
+        assert!(all.prefers_zstd("zstd"));
+        assert!(all.prefers_zstd("gzip, br, zstd"));
+        assert!(all.prefers_zstd("*"));
+        assert!(!all.prefers_zstd("gzip, zstd;q=0.5"));
+        assert!(!all.prefers_zstd("zstd;q=0"));
+        assert!(!all.prefers_zstd("gzip, deflate"));
+        let gzip = CompressionMiddleware::new(vec!["gzip".to_string()], 0);
+        assert!(!gzip.prefers_zstd("zstd"));
+        assert_eq!(
+            gzip.accept_encoding("zstd, br;q=0.9, gzip;q=0.5"),
+            "gzip;q=0.5"
+        );
+    }
+
+    #[test]
+    fn unmaps_ipv4_mapped_addresses() {
+        let mapped: IpAddr = "::ffff:192.0.2.7".parse().unwrap();
+        assert_eq!(unmap(mapped), "192.0.2.7".parse::<IpAddr>().unwrap());
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(unmap(v6), v6);
+    }
+
+    #[test]
+    fn rate_limit_shares_the_bucket_of_mapped_clients() {
+        let limit = ScrapeLimitMiddleware::new(0, 2, Vec::new());
+        let v4: IpAddr = "192.0.2.7".parse().unwrap();
+        let mapped: IpAddr = "::ffff:192.0.2.7".parse().unwrap();
+        assert!(limit.allow(unmap(v4)));
+        assert!(limit.allow(unmap(mapped)));
+        assert!(!limit.allow(unmap(mapped)));
+        assert!(limit.allow("192.0.2.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn rate_limit_forgets_the_oldest_clients() {
+        let limit = ScrapeLimitMiddleware::new(0, 1, Vec::new());
+        let base = u128::from("2001:db8::".parse::<std::net::Ipv6Addr>().unwrap());
+        let client = |n: u128| IpAddr::V6((base + n).into());
+        assert!(limit.allow(client(0)));
+        assert!(!limit.allow(client(0)));
+        // 每个来源都用掉了令牌，没有可以直接丢掉的满桶
+        for n in 1..2 * MAX_RATE_LIMITED_CLIENTS as u128 {
+            assert!(limit.allow(client(n)));
+            assert!(limit.clients.lock().unwrap().len() <= MAX_RATE_LIMITED_CLIENTS);
+        }
+        assert!(limit.allow(client(0)));
+    }
+
+    #[test]
+    fn renders_access_log_format() {
+        let format: AccessLogFormat = "{client} - \"{method} {path}\" {status} {duration}ms"
+            .parse()
+            .unwrap();
+        let line = format.render(|field| match field {
+            "client" => "192.0.2.7".to_string(),
+            "method" => "GET".to_string(),
+            "path" => "/metrics".to_string(),
+            "status" => "200".to_string(),
+            "duration" => "12".to_string(),
+            field => panic!("{} is not in the format", field),
+        });
+        assert_eq!(line, "192.0.2.7 - \"GET /metrics\" 200 12ms");
+        assert_eq!(
+            format.to_string(),
+            "{client} - \"{method} {path}\" {status} {duration}ms"
+        );
+        let empty: AccessLogFormat = "".parse().unwrap();
+        assert_eq!(empty.render(|_| unreachable!()), "");
+        let fields: AccessLogFormat = "{request_id}{user_agent}".parse().unwrap();
+        assert_eq!(
+            fields.render(|field| format!("<{}>", field)),
+            "<request_id><user_agent>"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_access_log_format() {
+        for invalid in ["{path", "{host}", "{}", "{ path }", "{Path}"] {
+            assert!(invalid.parse::<AccessLogFormat>().is_err(), "{:?}", invalid);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use clap::ValueEnum;
+use std::f64::consts::PI;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cli::Cli;
+use crate::gpu::Gpu;
+
+/// How long the noise on the utilization holds, about a scrape interval.
+const NOISE_PERIOD_SECONDS: u64 = 15;
+
+/// `--backend`: where the GPUs come from.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Backend {
+    NvidiaSmi,
+    /// Synthetic GPUs, for dashboards and alert rules without hardware.
+    Mock,
+}
+
+impl Backend {
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::NvidiaSmi => "nvidia-smi",
+            Backend::Mock => "mock",
+        }
+    }
+}
+
+/// `--backend mock`: synthetic A100s whose load drifts over a quarter of an hour, each GPU out of
+/// phase with the others.
+#[derive(Clone, Debug)]
+pub struct Mock {
+    /// `--backend.mock.gpus`
+    gpus: usize,
+}
+
+impl Mock {
+    pub fn from_cli(cli: &Cli) -> Option<Self> {
+        (cli.backend == Backend::Mock).then_some(Mock {
+            gpus: cli.backend_mock_gpus,
+        })
+    }
+
+    /// The GPUs, with a value for each of `fields` the mock knows.
+    pub fn gpus(&self, fields: &[(&str, &str)]) -> Vec<Gpu> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        (0..self.gpus)
+            .map(|index| {
+                let load = load(now, index);
+                // 显存占用变化比利用率慢
+                let memory = 0.5 + 0.4 * (2.0 * PI * now as f64 / 3600.0 + index as f64).sin();
+                Gpu {
+                    index: index.to_string(),
+                    uuid: format!("GPU-6d6f636b-0000-0000-0000-{:012}", index),
+                    name: "NVIDIA A100-SXM4-80GB".to_string(),
+                    driver_version: "mock".to_string(),
+                    values: fields
+                        .iter()
+                        .filter_map(|(field, metric)| {
+                            Some((metric.to_string(), value(field, load, memory)?))
+                        })
+                        .collect(),
+                    host: None,
+                }
+            })
+            .collect()
+    }
+
+    /// `nvidia-smi topo -m` of the GPUs: an NVSwitch system whose first half of GPUs is on
+    /// NUMA node 0 and the other half on node 1.
+    pub fn topology(&self) -> String {
+        let mut text = String::from("\t");
+        for index in 0..self.gpus {
+            text += &format!("GPU{}\t", index);
+        }
+        text += "CPU Affinity\tNUMA Affinity\tGPU NUMA ID\n";
+        for index in 0..self.gpus {
+            let numa = index * 2 / self.gpus.max(1);
+            text += &format!("GPU{}\t", index);
+            for peer in 0..self.gpus {
+                text += if peer == index { " X \t" } else { "NV12\t" };
+            }
+            text += &format!("{}-{}\t{}\t\tN/A\n", numa * 64, numa * 64 + 63, numa);
+        }
+        text += "\nLegend:\n\n  X    = Self\n";
+        text += "  SYS  = Connection traversing PCIe as well as the SMP interconnect between NUMA nodes (e.g., QPI/UPI)\n";
+        text += "  NV#  = Connection traversing a bonded set of # NVLinks\n";
+        text
+    }
+
+    /// The head and platform section of `nvidia-smi -q` of each GPU, as on a rack whose trays
+    /// hold four GPUs each.
+    pub fn query(&self) -> String {
+        let mut text = String::from("\n==============NVSMI LOG==============\n\n");
+        text += "Driver Version                            : mock\n\n";
+        text += &format!(
+            "Attached GPUs                             : {}\n",
+            self.gpus
+        );
+        for index in 0..self.gpus {
+            text += &format!("GPU 00000000:{:02X}:00.0\n", index + 1);
+            text += "    Product Name                          : NVIDIA A100-SXM4-80GB\n";
+            text += &format!(
+                "    GPU UUID                              : GPU-6d6f636b-0000-0000-0000-{:012}\n",
+                index
+            );
+            text += "    Platform Info\n";
+            text += "        Chassis Serial Number             : 1820000000001\n";
+            text += &format!(
+                "        Slot Number                       : {}\n",
+                index / 4 + 1
+            );
+            text += &format!(
+                "        Tray Index                        : {}\n",
+                index / 4
+            );
+            text += "        Host ID                           : 1\n";
+            text += "        Peer Type                         : Switch Connected\n";
+            text += &format!(
+                "        Module Id                         : {}\n",
+                index % 4 + 1
+            );
+        }
+        text
+    }
+}
+
+/// Between 0 and 1: a slow wave plus noise that changes every `NOISE_PERIOD_SECONDS`.
+fn load(now: u64, index: usize) -> f64 {
+    let wave = (2.0 * PI * now as f64 / 900.0 + index as f64 * 1.3).sin();
+    let noise = noise(now / NOISE_PERIOD_SECONDS, index as u64) * 2.0 - 1.0;
+    (0.5 + 0.35 * wave + 0.15 * noise).clamp(0.0, 1.0)
+}
+
+/// splitmix64 of the time bucket and GPU, as a fraction.
+fn noise(bucket: u64, index: u64) -> f64 {
+    let mut z = bucket
+        .wrapping_mul(0x9e3779b97f4a7c15)
+        .wrapping_add(index.wrapping_mul(0xbf58476d1ce4e5b9));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// A plausible reading of `field` on an A100 at `load`, with `memory` of its memory in use; none
+/// for other fields, which are left out like nvidia-smi's `[N/A]`.
+fn value(field: &str, load: f64, memory: f64) -> Option<f64> {
+    let total = 81920.0;
+    let used = (1024.0 + (total - 4096.0) * memory).round();
+    let value = match field {
+        "fan.speed" => (30.0 + 50.0 * load).round(),
+        "temperature.gpu" => (32.0 + 45.0 * load).round(),
+        "temperature.memory" => (38.0 + 40.0 * load).round(),
+        "clocks.gr" | "clocks.sm" => (210.0 + 1200.0 * load).round(),
+        "clocks.mem" | "clocks.max.mem" => 1593.0,
+        "clocks.max.gr" | "clocks.max.sm" => 1410.0,
+        "power.draw" => ((60.0 + 340.0 * load) * 100.0).round() / 100.0,
+        "power.limit" | "enforced.power.limit" | "power.default_limit" => 400.0,
+        "power.min_limit" => 100.0,
+        "power.max_limit" => 400.0,
+        "utilization.gpu" => (100.0 * load).round(),
+        "utilization.memory" => (70.0 * load).round(),
+        "memory.total" => total,
+        "memory.used" => used,
+        "memory.free" => total - used,
+        "pcie.link.gen.current" | "pcie.link.gen.max" => 4.0,
+        "pcie.link.width.current" | "pcie.link.width.max" => 16.0,
+        field if field.starts_with("ecc.errors.") => 0.0,
+        _ => return None,
+    };
+    Some(value)
+}
@@ -0,0 +1,251 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_rustls::TlsConnector;
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::TcpStream;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use surf::Url;
+
+use crate::cli::Cli;
+use crate::push::{Batch, Target};
+use crate::tls;
+
+const KEEP_ALIVE_SECONDS: u16 = 60;
+// MQTT 3.1.1 control packet types, shifted into the fixed header
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const DISCONNECT: u8 = 0xe0;
+
+/// An MQTT broker (`--push.mqtt.url`), sent a JSON message per GPU on every push. Connects for
+/// each push, so a restarted broker needs no reconnection logic.
+pub struct Mqtt {
+    host: String,
+    port: u16,
+    tls: Option<TlsConnector>,
+    /// With `{job}`, `{instance}` and `{gpu}` placeholders.
+    topic: String,
+    qos: u8,
+    retain: bool,
+    client_id: String,
+    username: Option<String>,
+    password_file: Option<String>,
+    instance: String,
+    /// `job` and `instance`.
+    labels: Vec<(String, String)>,
+}
+
+impl Mqtt {
+    pub fn new(address: &str, cli: &Cli, labels: Vec<(String, String)>) -> Result<Self> {
+        let url =
+            Url::parse(address).with_context(|| format!("Invalid --push.mqtt.url {}", address))?;
+        let (tls, default_port) = match url.scheme() {
+            "mqtt" | "tcp" => (None, 1883),
+            "mqtts" | "ssl" => {
+                let config = tls::client_config(
+                    cli.mqtt_ca_file.as_deref(),
+                    cli.mqtt_cert_file.as_deref(),
+                    cli.mqtt_key_file.as_deref(),
+                )?;
+                (Some(TlsConnector::from(Arc::new(config))), 8883)
+            }
+            scheme => bail!("Unsupported --push.mqtt.url scheme {:?}", scheme),
+        };
+        if cli.mqtt_topic.contains(&['+', '#'][..]) {
+            bail!("--push.mqtt.topic must not contain wildcards");
+        }
+        let instance = labels
+            .iter()
+            .find(|(name, _)| name == "instance")
+            .map_or("", |(_, value)| value.as_str());
+        Ok(Mqtt {
+            host: url
+                .host_str()
+                .ok_or_else(|| anyhow!("Invalid --push.mqtt.url {}", address))?
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port: url.port().unwrap_or(default_port),
+            tls,
+            topic: cli.mqtt_topic.clone(),
+            qos: cli.mqtt_qos,
+            retain: cli.mqtt_retain,
+            client_id: cli
+                .mqtt_client_id
+                .clone()
+                .unwrap_or_else(|| format!("nvidia-smi-exporter-{}", instance)),
+            username: cli.mqtt_username.clone(),
+            password_file: cli.mqtt_password_file.clone(),
+            instance: instance.to_string(),
+            labels,
+        })
+    }
+
+    /// A topic and `{"gpu", "instance", "name", "timestamp", "metrics": {...}}` for each GPU,
+    /// with `"host"` over SSH.
+    fn messages(&self, batch: &Batch) -> Result<Vec<(String, Vec<u8>)>> {
+        let timestamp = batch.timestamp.duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        Ok(batch
+            .gpus()
+            .into_iter()
+            .map(|gpu| {
+                let mut topic = self.topic.replace("{gpu}", gpu.index);
+                topic = topic.replace("{host}", gpu.host.unwrap_or_default());
+                for (label, value) in &self.labels {
+                    topic = topic.replace(&format!("{{{}}}", label), value);
+                }
+                let mut payload = json!({
+                    "gpu": gpu.index,
+                    "instance": self.instance,
+                    "name": gpu.name,
+                    "timestamp": timestamp,
+                    "metrics": gpu.metrics,
+                });
+                if let Some(host) = gpu.host {
+                    payload["host"] = host.into();
+                }
+                (topic, payload.to_string().into_bytes())
+            })
+            .collect())
+    }
+
+    async fn publish<S>(&self, mut stream: S, messages: &[(String, Vec<u8>)]) -> Result<()>
+    where
+        S: ReadExt + WriteExt + Unpin,
+    {
+        // CONNECT: protocol name, level 4, flags, keep alive, client id, user name, password
+        let mut packet = Vec::new();
+        put_string(&mut packet, b"MQTT");
+        packet.push(4);
+        let mut flags = 0x02;
+        let password = match (&self.username, &self.password_file) {
+            (Some(_), Some(file)) => Some(
+                std::fs::read_to_string(file)
+                    .with_context(|| format!("Failed to read {}", file))?
+                    .trim()
+                    .to_string(),
+            ),
+            _ => None,
+        };
+        if self.username.is_some() {
+            flags |= 0x80;
+        }
+        if password.is_some() {
+            flags |= 0x40;
+        }
+        packet.push(flags);
+        packet.extend_from_slice(&KEEP_ALIVE_SECONDS.to_be_bytes());
+        put_string(&mut packet, self.client_id.as_bytes());
+        if let Some(username) = &self.username {
+            put_string(&mut packet, username.as_bytes());
+        }
+        if let Some(password) = &password {
+            put_string(&mut packet, password.as_bytes());
+        }
+        write_packet(&mut stream, CONNECT, &packet).await?;
+        let (packet_type, body) = read_packet(&mut stream).await?;
+        if packet_type != CONNACK || body.len() != 2 {
+            bail!("Expected CONNACK from the broker");
+        }
+        match body[1] {
+            0 => {}
+            1 => bail!("Broker refused the connection: unacceptable protocol version"),
+            2 => bail!("Broker refused the connection: client id rejected"),
+            3 => bail!("Broker refused the connection: server unavailable"),
+            4 => bail!("Broker refused the connection: bad user name or password"),
+            5 => bail!("Broker refused the connection: not authorized"),
+            code => bail!("Broker refused the connection with code {}", code),
+        }
+
+        for (i, (topic, payload)) in messages.iter().enumerate() {
+            let packet_id = i as u16 + 1;
+            let mut packet = Vec::new();
+            put_string(&mut packet, topic.as_bytes());
+            if self.qos > 0 {
+                packet.extend_from_slice(&packet_id.to_be_bytes());
+            }
+            packet.extend_from_slice(payload);
+            let header = PUBLISH | self.qos << 1 | self.retain as u8;
+            write_packet(&mut stream, header, &packet).await?;
+            if self.qos > 0 {
+                // QoS 1 的 PUBACK 按发送顺序返回
+                let (packet_type, body) = read_packet(&mut stream).await?;
+                if packet_type != PUBACK || body != packet_id.to_be_bytes() {
+                    bail!("Expected PUBACK for {} from the broker", topic);
+                }
+            }
+        }
+        write_packet(&mut stream, DISCONNECT, &[]).await?;
+        Ok(())
+    }
+}
+
+#[tide::utils::async_trait]
+impl Target for Mqtt {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    async fn push(&self, batch: &Batch) -> Result<()> {
+        let messages = self.messages(batch)?;
+        let stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .with_context(|| format!("Failed to connect to {}:{}", self.host, self.port))?;
+        match &self.tls {
+            Some(connector) => {
+                let name = webpki::DNSNameRef::try_from_ascii_str(&self.host)
+                    .map_err(|_| anyhow!("{} is not a valid TLS server name", self.host))?;
+                let stream = connector
+                    .connect(name, stream)
+                    .await
+                    .with_context(|| format!("TLS handshake with {} failed", self.host))?;
+                self.publish(stream, &messages).await
+            }
+            None => self.publish(stream, &messages).await,
+        }
+    }
+}
+
+/// A UTF-8 string or binary data, prefixed with its length.
+fn put_string(buf: &mut Vec<u8>, s: &[u8]) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s);
+}
+
+async fn write_packet<S: WriteExt + Unpin>(stream: &mut S, header: u8, body: &[u8]) -> Result<()> {
+    let mut packet = vec![header];
+    // 剩余长度：每字节 7 位，最高位表示后面还有
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        packet.push(if len > 0 { byte | 0x80 } else { byte });
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    stream.write_all(&packet).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// The packet type (without flags) and the body.
+async fn read_packet<S: ReadExt + Unpin>(stream: &mut S) -> Result<(u8, Vec<u8>)> {
+    let mut byte = [0u8; 1];
+    stream.read_exact(&mut byte).await?;
+    let packet_type = byte[0] & 0xf0;
+    let mut len = 0usize;
+    for shift in 0..4 {
+        stream.read_exact(&mut byte).await?;
+        len |= ((byte[0] & 0x7f) as usize) << (7 * shift);
+        if byte[0] & 0x80 == 0 {
+            let mut body = vec![0u8; len];
+            stream.read_exact(&mut body).await?;
+            return Ok((packet_type, body));
+        }
+    }
+    bail!("Invalid MQTT packet length")
+}
@@ -0,0 +1,245 @@
+use clap::Args;
+
+use crate::collector::{Collector, COLLECTORS};
+use crate::gpu::Gpu;
+
+pub const OK: i32 = 0;
+pub const WARNING: i32 = 1;
+pub const CRITICAL: i32 = 2;
+pub const UNKNOWN: i32 = 3;
+
+/// `check` thresholds; a GPU above one is reported with the Nagios/Icinga plugin exit status.
+#[derive(Args, Clone, Debug, Default)]
+pub struct Thresholds {
+    /// Warn when a GPU is hotter than this many degrees C
+    #[arg(id = "warn-temp", long = "warn-temp")]
+    pub warn_temp: Option<f64>,
+    /// Critical when a GPU is hotter than this many degrees C
+    #[arg(id = "crit-temp", long = "crit-temp")]
+    pub crit_temp: Option<f64>,
+    /// Warn when more than this percentage of a GPU's memory is used
+    #[arg(id = "warn-mem", long = "warn-mem")]
+    pub warn_mem: Option<f64>,
+    /// Critical when more than this percentage of a GPU's memory is used
+    #[arg(id = "crit-mem", long = "crit-mem")]
+    pub crit_mem: Option<f64>,
+    /// Warn when a GPU draws more than this many watts
+    #[arg(id = "warn-power", long = "warn-power")]
+    pub warn_power: Option<f64>,
+    /// Critical when a GPU draws more than this many watts
+    #[arg(id = "crit-power", long = "crit-power")]
+    pub crit_power: Option<f64>,
+}
+
+/// One checked quantity: its perfdata label and unit, the collector providing it, and how to
+/// read it from a GPU.
+struct Check {
+    label: &'static str,
+    unit: &'static str,
+    collector: &'static str,
+    value: fn(&Gpu) -> Option<f64>,
+    warn: Option<f64>,
+    crit: Option<f64>,
+}
+
+impl Thresholds {
+    pub fn is_empty(&self) -> bool {
+        self.checks().is_empty()
+    }
+
+    /// The collectors the thresholds need, which `check` runs even when they are disabled.
+    pub fn collectors(&self) -> impl Iterator<Item = &'static Collector> {
+        self.checks()
+            .into_iter()
+            .filter_map(|check| COLLECTORS.iter().find(|c| c.name == check.collector))
+    }
+
+    fn checks(&self) -> Vec<Check> {
+        let checks = vec![
+            Check {
+                label: "temperature",
+                unit: "",
+                collector: "temperature",
+                value: |gpu| gpu.value("nvidia_temperature_gpu"),
+                warn: self.warn_temp,
+                crit: self.crit_temp,
+            },
+            Check {
+                label: "memory",
+                unit: "%",
+                collector: "memory",
+                value: |gpu| {
+                    let (used, total) = (
+                        gpu.value("nvidia_memory_used")?,
+                        gpu.value("nvidia_memory_total")?,
+                    );
+                    (total > 0.0).then(|| used / total * 100.0)
+                },
+                warn: self.warn_mem,
+                crit: self.crit_mem,
+            },
+            Check {
+                label: "power",
+                unit: "",
+                collector: "power",
+                value: |gpu| gpu.value("nvidia_power_draw"),
+                warn: self.warn_power,
+                crit: self.crit_power,
+            },
+        ];
+        checks
+            .into_iter()
+            .filter(|check| check.warn.is_some() || check.crit.is_some())
+            .collect()
+    }
+
+    /// The exit status and the plugin output line, `STATUS - text | perfdata`.
+    pub fn evaluate(&self, gpus: &[Gpu]) -> (i32, String) {
+        let mut status = OK;
+        let mut problems = Vec::new();
+        let mut perfdata = Vec::new();
+        for gpu in gpus {
+            for check in self.checks() {
+                let value = match (check.value)(gpu) {
+                    Some(value) => value,
+                    None => {
+                        status = worst(status, UNKNOWN);
+                        problems.push(format!("GPU {} {} not available", gpu.index, check.label));
+                        continue;
+                    }
+                };
+                let threshold = |t: Option<f64>| t.map_or_else(String::new, |t| t.to_string());
+                perfdata.push(format!(
+                    "'gpu{}_{}'={:.1}{};{};{}",
+                    gpu.index,
+                    check.label,
+                    value,
+                    check.unit,
+                    threshold(check.warn),
+                    threshold(check.crit)
+                ));
+                let exceeded = [(check.crit, CRITICAL), (check.warn, WARNING)]
+                    .iter()
+                    .find_map(|&(t, code)| t.filter(|t| value > *t).map(|t| (t, code)));
+                if let Some((t, code)) = exceeded {
+                    status = worst(status, code);
+                    problems.push(format!(
+                        "GPU {} {} {:.1}{} > {}",
+                        gpu.index, check.label, value, check.unit, t
+                    ));
+                }
+            }
+        }
+        let text = if problems.is_empty() {
+            format!("{} GPU(s) within thresholds", gpus.len())
+        } else {
+            problems.join(", ")
+        };
+        (
+            status,
+            format!("{} - {} | {}", name(status), text, perfdata.join(" ")),
+        )
+    }
+}
+
+/// The more severe status, ranking UNKNOWN between WARNING and CRITICAL.
+fn worst(a: i32, b: i32) -> i32 {
+    let severity = |status| match status {
+        OK => 0,
+        WARNING => 1,
+        UNKNOWN => 2,
+        _ => 3,
+    };
+    if severity(b) > severity(a) {
+        b
+    } else {
+        a
+    }
+}
+
+pub fn name(status: i32) -> &'static str {
+    match status {
+        OK => "OK",
+        WARNING => "WARNING",
+        CRITICAL => "CRITICAL",
+        _ => "UNKNOWN",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Command, FromArgMatches};
+
+    fn thresholds(args: &[&str]) -> Result<Thresholds, clap::Error> {
+        let matches = Thresholds::augment_args(Command::new("check"))
+            .try_get_matches_from(std::iter::once("check").chain(args.iter().copied()))?;
+        Thresholds::from_arg_matches(&matches)
+    }
+
+    fn gpu(index: &str, values: &[(&str, f64)]) -> Gpu {
+        Gpu {
+            index: index.to_string(),
+            uuid: format!("GPU-{}", index),
+            name: "NVIDIA A100-SXM4-80GB".to_string(),
+            driver_version: "550.54.15".to_string(),
+            values: values.iter().map(|(m, v)| (m.to_string(), *v)).collect(),
+            host: None,
+        }
+    }
+
+    #[test]
+    fn parses_thresholds() {
+        let t = thresholds(&["--warn-temp", "80", "--crit-mem=95.5"]).unwrap();
+        assert_eq!((t.warn_temp, t.crit_mem), (Some(80.0), Some(95.5)));
+        assert!(!t.is_empty());
+        let names = t.collectors().map(|c| c.name).collect::<Vec<_>>();
+        assert_eq!(names, ["temperature", "memory"]);
+        assert!(thresholds(&[]).unwrap().is_empty());
+        assert!(thresholds(&["--warn-temp", "hot"]).is_err());
+        assert!(thresholds(&["--warn-temp"]).is_err());
+    }
+
+    #[test]
+    fn within_thresholds() {
+        let t = thresholds(&["--warn-temp", "80", "--crit-temp", "90"]).unwrap();
+        let gpus = [gpu("0", &[("nvidia_temperature_gpu", 80.0)])];
+        assert_eq!(
+            t.evaluate(&gpus),
+            (
+                OK,
+                "OK - 1 GPU(s) within thresholds | 'gpu0_temperature'=80.0;80;90".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn reports_the_worst_status() {
+        let t =
+            thresholds(&["--warn-temp", "80", "--crit-temp", "90", "--warn-mem", "50"]).unwrap();
+        let gpus = [
+            gpu(
+                "0",
+                &[
+                    ("nvidia_temperature_gpu", 85.0),
+                    ("nvidia_memory_used", 30.0),
+                    ("nvidia_memory_total", 40.0),
+                ],
+            ),
+            gpu("1", &[("nvidia_temperature_gpu", 91.0)]),
+        ];
+        let (status, output) = t.evaluate(&gpus);
+        assert_eq!(status, CRITICAL);
+        assert_eq!(
+            output,
+            "CRITICAL - GPU 0 temperature 85.0 > 80, GPU 0 memory 75.0% > 50, \
+             GPU 1 temperature 91.0 > 90, GPU 1 memory not available \
+             | 'gpu0_temperature'=85.0;80;90 'gpu0_memory'=75.0%;50; 'gpu1_temperature'=91.0;80;90"
+        );
+        // 读数缺失是 UNKNOWN，比 WARNING 严重
+        let (status, _) = t.evaluate(&[gpu("1", &[])]);
+        assert_eq!(status, UNKNOWN);
+        assert_eq!(worst(WARNING, UNKNOWN), UNKNOWN);
+        assert_eq!(worst(CRITICAL, UNKNOWN), CRITICAL);
+    }
+}
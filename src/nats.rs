@@ -0,0 +1,275 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_rustls::TlsConnector;
+use async_std::io::{BufReader, ReadExt, WriteExt};
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use surf::Url;
+
+use crate::cli::Cli;
+use crate::push::{Batch, Target};
+use crate::tls;
+use crate::version;
+
+/// The server's `INFO`, as far as it matters here.
+#[derive(Deserialize)]
+struct Info {
+    #[serde(default)]
+    tls_required: bool,
+    #[serde(default)]
+    headers: bool,
+    max_payload: Option<usize>,
+}
+
+/// A NATS server (`--push.nats.url`), sent a JSON message per GPU on every push. With
+/// `--push.nats.jetstream` every message waits for the stream's acknowledgement. Connects for
+/// each push, like `Mqtt`.
+pub struct Nats {
+    host: String,
+    port: u16,
+    tls: Option<TlsConnector>,
+    /// With `{job}`, `{instance}` and `{gpu}` placeholders.
+    subject: String,
+    jetstream: bool,
+    username: Option<String>,
+    password_file: Option<String>,
+    token_file: Option<String>,
+    instance: String,
+    /// `job` and `instance`.
+    labels: Vec<(String, String)>,
+}
+
+impl Nats {
+    pub fn new(address: &str, cli: &Cli, labels: Vec<(String, String)>) -> Result<Self> {
+        let url =
+            Url::parse(address).with_context(|| format!("Invalid --push.nats.url {}", address))?;
+        let tls = match url.scheme() {
+            "nats" => None,
+            "tls" => {
+                let config = tls::client_config(
+                    cli.nats_ca_file.as_deref(),
+                    cli.nats_cert_file.as_deref(),
+                    cli.nats_key_file.as_deref(),
+                )?;
+                Some(TlsConnector::from(Arc::new(config)))
+            }
+            scheme => bail!("Unsupported --push.nats.url scheme {:?}", scheme),
+        };
+        if cli.nats_subject.contains(&['*', '>', ' '][..]) {
+            bail!("--push.nats.subject must not contain wildcards or spaces");
+        }
+        let instance = labels
+            .iter()
+            .find(|(name, _)| name == "instance")
+            .map_or("", |(_, value)| value.as_str());
+        Ok(Nats {
+            host: url
+                .host_str()
+                .ok_or_else(|| anyhow!("Invalid --push.nats.url {}", address))?
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port: url.port().unwrap_or(4222),
+            tls,
+            subject: cli.nats_subject.clone(),
+            jetstream: cli.nats_jetstream,
+            username: cli.nats_username.clone(),
+            password_file: cli.nats_password_file.clone(),
+            token_file: cli.nats_token_file.clone(),
+            instance: instance.to_string(),
+            labels,
+        })
+    }
+
+    /// A subject and `{"gpu", "instance", "name", "timestamp", "metrics": {...}}` for each GPU.
+    fn messages(&self, batch: &Batch) -> Result<Vec<(String, Vec<u8>)>> {
+        let timestamp = batch.timestamp.duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        // 主机名里的点会把一个 token 拆成几个
+        let token = |value: &str| value.replace(&['.', '*', '>', ' '][..], "_");
+        Ok(batch
+            .gpus()
+            .into_iter()
+            .map(|gpu| {
+                let mut subject = self.subject.replace("{gpu}", &token(gpu.index));
+                subject = subject.replace("{host}", &token(gpu.host.unwrap_or_default()));
+                for (label, value) in &self.labels {
+                    subject = subject.replace(&format!("{{{}}}", label), &token(value));
+                }
+                let mut payload = json!({
+                    "gpu": gpu.index,
+                    "instance": self.instance,
+                    "name": gpu.name,
+                    "timestamp": timestamp,
+                    "metrics": gpu.metrics,
+                });
+                if let Some(host) = gpu.host {
+                    payload["host"] = host.into();
+                }
+                (subject, payload.to_string().into_bytes())
+            })
+            .collect())
+    }
+
+    fn connect_options(&self, info: &Info) -> Result<String> {
+        let read = |file: &String| {
+            std::fs::read_to_string(file)
+                .map(|s| s.trim().to_string())
+                .with_context(|| format!("Failed to read {}", file))
+        };
+        let mut options = json!({
+            "verbose": false,
+            "pedantic": false,
+            "tls_required": self.tls.is_some(),
+            "name": "nvidia-smi-exporter",
+            "lang": "rust",
+            "version": version::VERSION,
+            "protocol": 1,
+            "headers": info.headers,
+            // 没有匹配的流时服务器立即回 503，而不是等到超时
+            "no_responders": info.headers,
+        });
+        if let (Some(username), Some(file)) = (&self.username, &self.password_file) {
+            options["user"] = json!(username);
+            options["pass"] = json!(read(file)?);
+        }
+        if let Some(file) = &self.token_file {
+            options["auth_token"] = json!(read(file)?);
+        }
+        Ok(options.to_string())
+    }
+
+    async fn publish<S>(&self, stream: S, info: &Info, messages: &[(String, Vec<u8>)]) -> Result<()>
+    where
+        S: ReadExt + WriteExt + Unpin,
+    {
+        let mut stream = BufReader::new(stream);
+        let inbox = format!("_INBOX.{}", inbox_token());
+        let mut commands = format!("CONNECT {}\r\n", self.connect_options(info)?).into_bytes();
+        if self.jetstream {
+            commands.extend_from_slice(format!("SUB {}.* 1\r\n", inbox).as_bytes());
+        }
+        for (i, (subject, payload)) in messages.iter().enumerate() {
+            if let Some(max_payload) = info.max_payload {
+                if payload.len() > max_payload {
+                    bail!("Message for {} exceeds the server's max_payload", subject);
+                }
+            }
+            let reply = match self.jetstream {
+                true => format!(" {}.{}", inbox, i),
+                false => String::new(),
+            };
+            commands.extend_from_slice(
+                format!("PUB {}{} {}\r\n", subject, reply, payload.len()).as_bytes(),
+            );
+            commands.extend_from_slice(payload);
+            commands.extend_from_slice(b"\r\n");
+        }
+        // PONG 说明之前的命令都已处理，认证失败等错误会在它之前返回
+        commands.extend_from_slice(b"PING\r\n");
+        stream.get_mut().write_all(&commands).await?;
+        stream.get_mut().flush().await?;
+
+        let mut pong = false;
+        let mut acks = 0;
+        while !pong || (self.jetstream && acks < messages.len()) {
+            let line = read_line(&mut stream).await?;
+            let mut words = line.split_whitespace();
+            match words.next().unwrap_or_default() {
+                "PONG" => pong = true,
+                "PING" => {
+                    stream.get_mut().write_all(b"PONG\r\n").await?;
+                    stream.get_mut().flush().await?;
+                }
+                "-ERR" => bail!("Server error: {}", line[4..].trim().trim_matches('\'')),
+                // MSG <subject> <sid> <size> 或 HMSG <subject> <sid> <header size> <size>
+                command @ ("MSG" | "HMSG") => {
+                    let subject = words.next().unwrap_or_default().to_string();
+                    let sizes = words
+                        .map(|word| word.parse::<usize>().ok())
+                        .collect::<Vec<_>>();
+                    let (header_size, size) = match (command, &sizes[..]) {
+                        ("MSG", [_, Some(size)]) => (0, *size),
+                        ("HMSG", [_, Some(header_size), Some(size)]) => (*header_size, *size),
+                        _ => bail!("Invalid {} from the server", command),
+                    };
+                    let mut body = vec![0u8; size + 2];
+                    stream.read_exact(&mut body).await?;
+                    let i = subject.rsplit('.').next().unwrap_or_default();
+                    let published = i
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|i| messages.get(i))
+                        .map_or("", |(subject, _)| subject.as_str());
+                    let headers = String::from_utf8_lossy(&body[..header_size]);
+                    if headers.starts_with("NATS/1.0 503") {
+                        bail!("No JetStream stream captures {}", published);
+                    }
+                    let ack: Value = serde_json::from_slice(&body[header_size..size])
+                        .with_context(|| format!("Invalid acknowledgement for {}", published))?;
+                    if let Some(error) = ack.get("error") {
+                        bail!("JetStream rejected {}: {}", published, error["description"]);
+                    }
+                    acks += 1;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[tide::utils::async_trait]
+impl Target for Nats {
+    fn name(&self) -> &'static str {
+        "nats"
+    }
+
+    async fn push(&self, batch: &Batch) -> Result<()> {
+        let messages = self.messages(batch)?;
+        if messages.is_empty() {
+            return Ok(());
+        }
+        let stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .with_context(|| format!("Failed to connect to {}:{}", self.host, self.port))?;
+        // INFO 总是明文发送，之后才升级为 TLS
+        let line = read_line(&mut BufReader::new(&stream)).await?;
+        let info = line
+            .strip_prefix("INFO ")
+            .ok_or_else(|| anyhow!("Expected INFO from the server, got {:?}", line))?;
+        let info: Info = serde_json::from_str(info).context("Invalid INFO from the server")?;
+        match &self.tls {
+            Some(connector) => {
+                let name = webpki::DNSNameRef::try_from_ascii_str(&self.host)
+                    .map_err(|_| anyhow!("{} is not a valid TLS server name", self.host))?;
+                let stream = connector
+                    .connect(name, stream)
+                    .await
+                    .with_context(|| format!("TLS handshake with {} failed", self.host))?;
+                self.publish(stream, &info, &messages).await
+            }
+            None if info.tls_required => bail!("The server requires TLS, use a tls:// URL"),
+            None => self.publish(stream, &info, &messages).await,
+        }
+    }
+}
+
+/// A protocol line without the trailing CRLF.
+async fn read_line<S: ReadExt + Unpin>(stream: &mut BufReader<S>) -> Result<String> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        bail!("Connection closed by the server");
+    }
+    Ok(line.trim_end().to_string())
+}
+
+/// A unique token for the reply subjects of one connection.
+fn inbox_token() -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    format!("{:x}{:x}", std::process::id(), nanos)
+}
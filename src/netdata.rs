@@ -0,0 +1,143 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+use tracing::error;
+
+use crate::collector::{self, COLLECTORS};
+use crate::config::Settings;
+use crate::push;
+
+/// Chart type of every chart, as in `nvidia_smi.temperature_gpu`.
+const CHART_TYPE: &str = "nvidia_smi";
+const PRIORITY: usize = 70000;
+/// Values are sent in thousandths, as `SET` only takes integers.
+const DIVISOR: f64 = 1000.0;
+
+/// Units of the collectors' metrics on the charts.
+const UNITS: &[(&str, &str)] = &[
+    ("fan", "percentage"),
+    ("temperature", "Celsius"),
+    ("clocks", "MHz"),
+    ("power", "Watts"),
+    ("utilization", "percentage"),
+    ("memory", "MiB"),
+];
+
+/// Runs as a Netdata external plugin: collects every `update_every` seconds and writes a chart
+/// per metric with a dimension per GPU to stdout, until stdout is closed. Prints `DISABLE`, which
+/// tells Netdata not to restart the plugin, when there are no GPUs to begin with.
+pub async fn run(settings: &Settings, update_every: u64) -> i32 {
+    if let Err(e) = collector::check_ready(settings).await {
+        error!("No GPUs: {}", e);
+        println!("DISABLE");
+        return 0;
+    }
+    let interval = Duration::from_secs(update_every);
+    // 已经定义过的图表及其维度，出现新的 GPU 时重新定义
+    let mut charts: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    loop {
+        let started = Instant::now();
+        match collector::process_nvidia_smi(settings).await {
+            Ok(nvidia_buffer) => {
+                let exposition =
+                    String::from_utf8_lossy(&crate::exposition(settings, &nvidia_buffer))
+                        .into_owned();
+                let out = render(settings, update_every, &exposition, &mut charts);
+                let mut stdout = io::stdout().lock();
+                // Netdata 停止插件时关闭管道
+                if stdout
+                    .write_all(out.as_bytes())
+                    .and_then(|()| stdout.flush())
+                    .is_err()
+                {
+                    return 0;
+                }
+            }
+            // 这一轮不输出，Netdata 图表上留空
+            Err(e) => error!("Failed to process nvidia-smi, {:#}", e),
+        }
+        async_std::task::sleep(interval.saturating_sub(started.elapsed())).await;
+    }
+}
+
+/// The `CHART` and `DIMENSION` lines of charts that are new or have new GPUs, and a
+/// `BEGIN`/`SET`/`END` block per chart.
+fn render(
+    settings: &Settings,
+    update_every: u64,
+    exposition: &str,
+    charts: &mut BTreeMap<String, Vec<String>>,
+) -> String {
+    let mut values: BTreeMap<&str, Vec<(&str, f64)>> = BTreeMap::new();
+    let samples = push::parse(exposition);
+    for sample in &samples {
+        let gpu = sample.labels.iter().find(|(name, _)| name == "gpu");
+        if let (true, Some((_, gpu))) = (sample.name.starts_with("nvidia_"), gpu) {
+            if sample.value.is_finite() {
+                values
+                    .entry(&sample.name)
+                    .or_default()
+                    .push((gpu, sample.value));
+            }
+        }
+    }
+
+    let fields = collector::fields(settings);
+    let mut out = String::new();
+    for (priority, (metric, gpus)) in values.iter().enumerate() {
+        let id = metric.trim_start_matches("nvidia_");
+        let dimensions = gpus
+            .iter()
+            .map(|(gpu, _)| gpu.to_string())
+            .collect::<Vec<_>>();
+        let defined = charts.get(*metric);
+        if defined.is_none_or(|defined| dimensions.iter().any(|d| !defined.contains(d))) {
+            let field = fields.iter().find(|(_, m)| m == metric).map(|(f, _)| *f);
+            let family = COLLECTORS
+                .iter()
+                .find(|c| c.fields.iter().any(|(_, m)| m == metric))
+                .map_or("other", |c| c.name);
+            let units = UNITS
+                .iter()
+                .find(|(collector, _)| *collector == family)
+                .map_or("value", |(_, units)| *units);
+            let title = help(exposition, metric)
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("nvidia-smi {}", field.unwrap_or(metric)));
+            out += &format!(
+                "CHART {}.{} '' '{}' '{}' '{}' '{}.{}' line {} {} '' nvidia_smi_exporter\n",
+                CHART_TYPE,
+                id,
+                title.replace('\'', ""),
+                units,
+                family,
+                CHART_TYPE,
+                id,
+                PRIORITY + priority,
+                update_every
+            );
+            for gpu in &dimensions {
+                out += &format!(
+                    "DIMENSION gpu{} 'GPU {}' absolute 1 {}\n",
+                    gpu, gpu, DIVISOR
+                );
+            }
+            charts.insert(metric.to_string(), dimensions);
+        }
+        out += &format!("BEGIN {}.{}\n", CHART_TYPE, id);
+        for (gpu, value) in gpus {
+            out += &format!("SET gpu{} = {}\n", gpu, (value * DIVISOR).round() as i64);
+        }
+        out += "END\n";
+    }
+    out
+}
+
+/// The `# HELP` text of `metric`, for `--query-field`s that have one.
+pub fn help<'a>(exposition: &'a str, metric: &str) -> Option<&'a str> {
+    exposition.lines().find_map(|line| {
+        line.strip_prefix("# HELP ")?
+            .strip_prefix(metric)?
+            .strip_prefix(' ')
+    })
+}
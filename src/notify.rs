@@ -0,0 +1,77 @@
+use anyhow::{Context, Result};
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::collector;
+
+/// systemd's `$NOTIFY_SOCKET` (`Type=notify`), and the ping interval if `WatchdogSec=` is set.
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    watchdog: Option<Duration>,
+}
+
+/// Takes the notification socket and watchdog settings from the environment, so nvidia-smi does
+/// not inherit them. Must run before other threads start.
+pub fn from_env() -> Result<Option<Arc<Notifier>>> {
+    let path = std::env::var("NOTIFY_SOCKET").ok();
+    let watchdog_usec = std::env::var("WATCHDOG_USEC").ok();
+    let watchdog_pid = std::env::var("WATCHDOG_PID").ok();
+    std::env::remove_var("NOTIFY_SOCKET");
+    std::env::remove_var("WATCHDOG_USEC");
+    std::env::remove_var("WATCHDOG_PID");
+
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    // @ 开头的是抽象命名空间的 socket
+    let addr = match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => SocketAddr::from_abstract_name(name),
+        _ => SocketAddr::from_pathname(&path),
+    }
+    .with_context(|| format!("Invalid NOTIFY_SOCKET {}", path))?;
+    let watchdog = match (watchdog_usec, watchdog_pid) {
+        (Some(_), Some(pid)) if pid != std::process::id().to_string() => None,
+        (Some(usec), _) => Some(Duration::from_micros(
+            usec.parse().with_context(|| "Invalid WATCHDOG_USEC")?,
+        )),
+        (None, _) => None,
+    };
+    Ok(Some(Arc::new(Notifier {
+        socket: UnixDatagram::unbound().with_context(|| "Failed to create notify socket")?,
+        addr,
+        watchdog,
+    })))
+}
+
+impl Notifier {
+    /// Sends a state such as `READY=1`; failures are only logged.
+    pub fn notify(&self, state: &str) {
+        match self.socket.send_to_addr(state.as_bytes(), &self.addr) {
+            Ok(_) => debug!("Notified systemd {}", state),
+            Err(e) => warn!("Failed to notify systemd {}, {}", state, e),
+        }
+    }
+
+    /// Pings the watchdog at half its interval while the last collection, if any, succeeded, so
+    /// systemd restarts an exporter that is wedged or whose collections keep failing.
+    pub async fn watchdog(self: Arc<Self>) {
+        let interval = match self.watchdog {
+            Some(interval) => interval / 2,
+            None => return,
+        };
+        loop {
+            async_std::task::sleep(interval).await;
+            match collector::last_collection().map(|last| last.result) {
+                Some(Err(e)) => warn!("Skipping watchdog ping, last collection failed: {}", e),
+                _ => self.notify("WATCHDOG=1"),
+            }
+        }
+    }
+}
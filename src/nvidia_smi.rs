@@ -0,0 +1,52 @@
+use async_std::process::{Command, Output};
+use std::fmt::Debug;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+
+use crate::collector;
+use crate::environment;
+use crate::ssh::{Ssh, Target};
+
+pub type OutputFuture<'a> = Pin<Box<dyn Future<Output = io::Result<Output>> + Send + 'a>>;
+
+/// How the collections run nvidia-smi, so that tests can answer with canned outputs, malformed
+/// ones included, instead of a driver.
+pub trait NvidiaSmi: Debug + Send + Sync {
+    /// `nvidia-smi <args>` to completion, on `remote` over SSH if given.
+    fn output<'a>(
+        &'a self,
+        args: &'a [String],
+        remote: Option<(&'a Ssh, &'a Target)>,
+    ) -> OutputFuture<'a>;
+
+    /// Whether the outputs come from the GPUs of this machine, whose device nodes then explain
+    /// failures.
+    fn local_devices(&self) -> bool {
+        true
+    }
+}
+
+/// Runs the real nvidia-smi, or ssh.
+#[derive(Debug)]
+pub struct Exec;
+
+impl NvidiaSmi for Exec {
+    fn output<'a>(
+        &'a self,
+        args: &'a [String],
+        remote: Option<(&'a Ssh, &'a Target)>,
+    ) -> OutputFuture<'a> {
+        Box::pin(async move {
+            let mut command = match remote {
+                Some((ssh, target)) => ssh.command(target, "nvidia-smi", args),
+                None => {
+                    let mut command = Command::new(environment::nvidia_smi());
+                    command.args(args);
+                    command
+                }
+            };
+            collector::output(&mut command).await
+        })
+    }
+}
@@ -0,0 +1,160 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::io::Write;
+
+use crate::collector;
+use crate::config::Settings;
+use crate::gpu::Gpu;
+use crate::influx;
+use crate::nagios::{self, Thresholds};
+use crate::push;
+
+/// `nvidia-smi -L` failed or found no GPUs, or none is left to export; Nagios CRITICAL.
+const EXIT_NO_GPUS: i32 = nagios::CRITICAL;
+/// The `--query-gpu` collection failed; Nagios UNKNOWN.
+const EXIT_COLLECT_FAILED: i32 = nagios::UNKNOWN;
+/// `print --output` could not write the file.
+const EXIT_WRITE_FAILED: i32 = 4;
+
+/// `print --format`.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum OutputFormat {
+    Prometheus,
+    Influx,
+}
+
+/// Ways to collect once instead of serving.
+pub enum Mode {
+    /// `--dry-run`: the exported GPUs on stderr and the metrics on stdout.
+    DryRun,
+    /// `print`: the metrics on stdout.
+    Print(OutputFormat),
+    /// `print --output`: the metrics in a file, replaced atomically.
+    Textfile(String, OutputFormat),
+    /// `check`: the exported GPUs on stdout.
+    Check,
+    /// `check --warn-...`: a Nagios plugin status line on stdout.
+    Nagios(Thresholds),
+    /// `replay`: the metrics of a capture on stdout, without listing the GPUs first.
+    Replay,
+}
+
+/// Checks the GPUs are visible, collects once with the configured collectors and filters, and
+/// prints what `mode` asks for. Returns the process exit code.
+pub async fn run(mode: Mode, settings: &Settings) -> i32 {
+    let mut settings = settings.clone();
+    if let Mode::Nagios(thresholds) = &mode {
+        for collector in thresholds.collectors() {
+            if !settings.collectors.iter().any(|c| c.name == collector.name) {
+                settings.collectors.push(collector);
+            }
+        }
+    }
+    let settings = &settings;
+    let listed = !matches!(mode, Mode::Replay);
+    let (gpus, nvidia_buffer) = match collect(settings, listed).await {
+        Ok(collected) => collected,
+        Err((code, message)) => {
+            match mode {
+                Mode::Nagios(_) => println!("{} - {}", nagios::name(code), message),
+                _ => eprintln!("{}", message),
+            }
+            return code;
+        }
+    };
+    let metrics = |settings: &Settings, format| render(settings, &nvidia_buffer, format);
+    // 指标输出到 stdout，摘要输出到 stderr，便于重定向
+    match mode {
+        Mode::DryRun => {
+            eprint!("{}", summary(&gpus));
+            print!("{}", metrics(settings, OutputFormat::Prometheus));
+        }
+        Mode::Print(format) => print!("{}", metrics(settings, format)),
+        Mode::Replay => print!("{}", metrics(settings, OutputFormat::Prometheus)),
+        Mode::Check => print!("{}", summary(&gpus)),
+        Mode::Nagios(thresholds) => {
+            let (status, line) = thresholds.evaluate(&gpus);
+            println!("{}", line);
+            return status;
+        }
+        Mode::Textfile(path, format) => {
+            let settings = textfile_settings(settings);
+            if let Err(e) = write_atomically(&path, metrics(&settings, format).as_bytes()) {
+                eprintln!("{:#}", e);
+                return EXIT_WRITE_FAILED;
+            }
+        }
+    }
+    0
+}
+
+/// The metrics of a collection in `format`.
+pub fn render(settings: &Settings, nvidia_buffer: &str, format: OutputFormat) -> String {
+    let exposition =
+        String::from_utf8_lossy(&crate::exposition(settings, nvidia_buffer)).into_owned();
+    match format {
+        OutputFormat::Prometheus => exposition,
+        // 不带时间戳，由 Telegraf 之类的读取方打上
+        OutputFormat::Influx => influx::line_protocol(&push::parse(&exposition), &[], None),
+    }
+}
+
+/// The settings of `print --output`.
+pub fn textfile_settings(settings: &Settings) -> Settings {
+    // node_exporter 自己也导出 process_* 指标，写进 textfile 会冲突
+    Settings {
+        disable_exporter_metrics: true,
+        ..settings.clone()
+    }
+}
+
+/// Writes a temporary file next to `path` and renames it over `path`, so readers such as the
+/// textfile collector never see a partial file. The temporary name does not end in `.prom`.
+pub fn write_atomically(path: &str, content: &[u8]) -> Result<()> {
+    let tmp = format!("{}.{}.tmp", path, std::process::id());
+    let result = std::fs::File::create(&tmp)
+        .and_then(|mut file| {
+            file.write_all(content)?;
+            file.sync_all()
+        })
+        .and_then(|()| std::fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result.with_context(|| format!("Failed to write {}", path))
+}
+
+/// The exported GPUs and their metrics, after checking nvidia-smi lists GPUs if `listed`, or
+/// the exit code and the problem.
+async fn collect(settings: &Settings, listed: bool) -> Result<(Vec<Gpu>, String), (i32, String)> {
+    if listed {
+        if let Err(e) = collector::check_ready(settings).await {
+            return Err((EXIT_NO_GPUS, format!("No GPUs: {}", e)));
+        }
+    }
+    let nvidia_buffer = match collector::process_nvidia_smi(settings).await {
+        Ok(nvidia_buffer) => nvidia_buffer,
+        Err(e) => {
+            return Err((EXIT_COLLECT_FAILED, format!("Collection failed: {:#}", e)));
+        }
+    };
+    let gpus = match collector::last_collection().map(|c| c.result) {
+        Some(Ok(gpus)) => gpus,
+        _ => Vec::new(),
+    };
+    if gpus.is_empty() {
+        return Err((
+            EXIT_NO_GPUS,
+            "No GPUs left after --gpu-include/--gpu-exclude".to_string(),
+        ));
+    }
+    Ok((gpus, nvidia_buffer))
+}
+
+fn summary(gpus: &[Gpu]) -> String {
+    let mut summary = format!("Exporting {} GPU(s):\n", gpus.len());
+    for gpu in gpus {
+        summary.push_str(&format!("  {} {} {}\n", gpu.index, gpu.name, gpu.uuid));
+    }
+    summary
+}
@@ -0,0 +1,357 @@
+use lazy_static::lazy_static;
+use prometheus::core::Collector;
+use prometheus::{register_histogram, Histogram};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What Prometheus asks for when it can take exemplars.
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+const SCRAPE_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// A sample by its name and label pairs sorted by name.
+type Key = (String, Vec<(String, String)>);
+
+struct Exemplar {
+    trace_id: String,
+    value: f64,
+    timestamp: f64,
+}
+
+lazy_static! {
+    /// The last exemplar of each counter and histogram bucket sample, with `--otlp.endpoint`.
+    static ref EXEMPLARS: Mutex<HashMap<Key, Exemplar>> = Mutex::new(HashMap::new());
+    pub static ref SCRAPE_DURATION: Histogram = register_histogram!(
+        "nvidia_smi_exporter_scrape_duration_seconds",
+        "Time taken to collect the metrics of a scrape of the telemetry path.",
+        SCRAPE_BUCKETS.to_vec()
+    )
+    .unwrap();
+}
+
+/// Records `seconds` in `nvidia_smi_exporter_scrape_duration_seconds`, with the current trace as
+/// the exemplar of its bucket.
+pub fn observe_scrape(seconds: f64) {
+    SCRAPE_DURATION.observe(seconds);
+    let le = SCRAPE_BUCKETS
+        .iter()
+        .copied()
+        .find(|le| seconds <= *le)
+        .unwrap_or(f64::INFINITY);
+    record(
+        "nvidia_smi_exporter_scrape_duration_seconds_bucket",
+        vec![("le".to_string(), format_le(le))],
+        seconds,
+    );
+}
+
+/// Makes the current trace the exemplar of the counter `counter{label_values}` that was just
+/// incremented.
+pub fn exemplar(counter: &impl Collector, label_values: &[&str]) {
+    let desc = match counter.desc().first() {
+        Some(desc) => *desc,
+        None => return,
+    };
+    let labels = desc
+        .variable_labels
+        .iter()
+        .zip(label_values)
+        .map(|(name, value)| (name.clone(), value.to_string()))
+        .collect();
+    record(&desc.fq_name, labels, 1.0);
+}
+
+fn record(name: &str, mut labels: Vec<(String, String)>, value: f64) {
+    let trace_id = match trace_id() {
+        Some(trace_id) => trace_id,
+        None => return,
+    };
+    labels.sort();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    EXEMPLARS.lock().unwrap().insert(
+        (name.to_string(), labels),
+        Exemplar {
+            trace_id,
+            value,
+            timestamp,
+        },
+    );
+}
+
+/// The trace of the current span, if it is exported.
+#[cfg(feature = "otlp")]
+fn trace_id() -> Option<String> {
+    use opentelemetry::trace::TraceContextExt;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    let context = tracing::Span::current().context();
+    let span = context.span();
+    let span_context = span.span_context();
+    span_context
+        .is_valid()
+        .then(|| span_context.trace_id().to_string())
+}
+
+#[cfg(not(feature = "otlp"))]
+fn trace_id() -> Option<String> {
+    None
+}
+
+/// Whether the `Accept` header takes OpenMetrics.
+pub fn accepted(accept: &str) -> bool {
+    accept.split(',').any(|media| {
+        let mut params = media.split(';').map(str::trim);
+        params.next() == Some("application/openmetrics-text")
+            && !params.any(|param| param.replace(' ', "") == "q=0")
+    })
+}
+
+/// Rewrites the text format as OpenMetrics 1.0 with the recorded exemplars: counter families
+/// lose their `_total` in `# HELP` and `# TYPE`, `untyped` becomes `unknown`, label sets lose
+/// their spaces, timestamps turn into seconds, other comments are dropped and `# EOF` ends it.
+pub fn convert(text: &str) -> String {
+    let counters = text
+        .lines()
+        .filter_map(|line| line.strip_prefix("# TYPE "))
+        .filter_map(|rest| rest.strip_suffix(" counter"))
+        .filter(|name| name.ends_with("_total"))
+        .collect::<HashSet<_>>();
+    let exemplars = EXEMPLARS.lock().unwrap();
+    let mut out = String::with_capacity(text.len() + 6);
+    for line in text.lines() {
+        if let Some(rest) = line.strip_prefix("# HELP ") {
+            let (name, help) = rest.split_once(' ').unwrap_or((rest, ""));
+            // 文本格式里 HELP 不转义双引号，OpenMetrics 要转义
+            let _ = writeln!(
+                out,
+                "# HELP {} {}",
+                family(name, &counters),
+                help.replace('"', "\\\"")
+            );
+        } else if let Some(rest) = line.strip_prefix("# TYPE ") {
+            let (name, kind) = rest.split_once(' ').unwrap_or((rest, "unknown"));
+            let kind = match kind {
+                "counter" if !counters.contains(name) => "unknown",
+                "untyped" => "unknown",
+                kind => kind,
+            };
+            let _ = writeln!(out, "# TYPE {} {}", family(name, &counters), kind);
+        } else if !line.is_empty() && !line.starts_with('#') {
+            sample(&mut out, line, &exemplars);
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+fn family<'a>(name: &'a str, counters: &HashSet<&str>) -> &'a str {
+    match counters.contains(name) {
+        true => name.trim_end_matches("_total"),
+        false => name,
+    }
+}
+
+/// One sample line, with the exemplar recorded for it if any.
+fn sample(out: &mut String, line: &str, exemplars: &HashMap<Key, Exemplar>) {
+    let name_end = line.find(['{', ' ']).unwrap_or(line.len());
+    let name = &line[..name_end];
+    let mut rest = &line[name_end..];
+    out.push_str(name);
+    let mut labels = Vec::new();
+    if rest.starts_with('{') {
+        let (end, parsed) = label_set(rest);
+        labels = parsed;
+        out.push('{');
+        for (i, (label, value)) in labels.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "{}=\"{}\"", label, value);
+        }
+        out.push('}');
+        rest = &rest[end..];
+    }
+    let mut fields = rest.split_whitespace();
+    let value = fields.next().unwrap_or("NaN");
+    let _ = write!(out, " {}", value);
+    // 文本格式的时间戳是毫秒，OpenMetrics 是秒
+    if let Some(ms) = fields.next().and_then(|ms| ms.parse::<i64>().ok()) {
+        let _ = write!(out, " {}", ms as f64 / 1000.0);
+    }
+    if exemplars.is_empty() {
+        out.push('\n');
+        return;
+    }
+    // 值在转义状态下比较即可；le 统一格式
+    let mut key_labels = labels
+        .into_iter()
+        .map(|(label, value)| match label.as_str() {
+            "le" => {
+                let le = value.parse().map_or(value, format_le);
+                (label, le)
+            }
+            _ => (label, unescape(&value)),
+        })
+        .collect::<Vec<_>>();
+    key_labels.sort();
+    if let Some(exemplar) = exemplars.get(&(name.to_string(), key_labels)) {
+        let _ = write!(
+            out,
+            " # {{trace_id=\"{}\"}} {} {}",
+            exemplar.trace_id, exemplar.value, exemplar.timestamp
+        );
+    }
+    out.push('\n');
+}
+
+/// The label pairs of `{name="value", …}`, values still escaped, and where the set ends.
+fn label_set(s: &str) -> (usize, Vec<(String, String)>) {
+    let mut labels = Vec::new();
+    let mut name = String::new();
+    let mut value = String::new();
+    let mut quoted = false;
+    let mut chars = s.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' if quoted => {
+                value.push(c);
+                if let Some((_, escaped)) = chars.next() {
+                    value.push(escaped);
+                }
+            }
+            '"' if quoted => {
+                labels.push((std::mem::take(&mut name), std::mem::take(&mut value)));
+                quoted = false;
+            }
+            c if quoted => value.push(c),
+            '"' => quoted = true,
+            '}' => return (i + 1, labels),
+            '=' | ',' => {}
+            c if c.is_whitespace() => {}
+            c => name.push(c),
+        }
+    }
+    (s.len(), labels)
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match (c, c == '\\') {
+            (_, true) => match chars.next() {
+                Some('n') => out.push('\n'),
+                Some(c) => out.push(c),
+                None => out.push('\\'),
+            },
+            (c, false) => out.push(c),
+        }
+    }
+    out
+}
+
+fn format_le(le: f64) -> String {
+    match le {
+        le if le == f64::INFINITY => "+Inf".to_string(),
+        le => le.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_openmetrics() {
+        assert!(accepted(
+            "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5"
+        ));
+        assert!(accepted("text/plain, application/openmetrics-text; q=0.9"));
+        assert!(!accepted("application/openmetrics-text; q=0"));
+        assert!(!accepted("text/plain;version=0.0.4"));
+    }
+
+    #[test]
+    fn converts_the_text_format() {
+        let text = "\
+# HELP test_events_total Number of \"events\".
+# TYPE test_events_total counter
+test_events_total{gpu=\"0\", event=\"xid\"} 3
+# HELP test_temperature Temperature.
+# TYPE test_temperature untyped
+test_temperature 42 1700000000123
+# HELP test_up Up.
+# TYPE test_up gauge
+test_up 1
+";
+        assert_eq!(
+            convert(text),
+            "\
+# HELP test_events Number of \\\"events\\\".
+# TYPE test_events counter
+test_events_total{gpu=\"0\",event=\"xid\"} 3
+# HELP test_temperature Temperature.
+# TYPE test_temperature unknown
+test_temperature 42 1700000000.123
+# HELP test_up Up.
+# TYPE test_up gauge
+test_up 1
+# EOF
+"
+        );
+    }
+
+    #[test]
+    fn attaches_exemplars() {
+        {
+            let mut exemplars = EXEMPLARS.lock().unwrap();
+            for (name, labels, value) in [
+                ("test_scrape_seconds_bucket", vec![("le", "0.5")], 0.3),
+                (
+                    "test_rejected_total",
+                    vec![("path", "/a\"b"), ("reason", "rate_limit")],
+                    1.0,
+                ),
+            ] {
+                let labels = labels
+                    .into_iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect();
+                exemplars.insert(
+                    (name.to_string(), labels),
+                    Exemplar {
+                        trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".to_string(),
+                        value,
+                        timestamp: 1700000000.5,
+                    },
+                );
+            }
+        }
+        let text = "\
+# TYPE test_scrape_seconds histogram
+test_scrape_seconds_bucket{le=\"0.25\"} 0
+test_scrape_seconds_bucket{le=\"0.5\"} 1
+test_scrape_seconds_bucket{le=\"+Inf\"} 1
+# TYPE test_rejected_total counter
+test_rejected_total{reason=\"rate_limit\",path=\"/a\\\"b\"} 1
+test_rejected_total{reason=\"concurrency\",path=\"/a\\\"b\"} 0
+";
+        assert_eq!(
+            convert(text),
+            "\
+# TYPE test_scrape_seconds histogram
+test_scrape_seconds_bucket{le=\"0.25\"} 0
+test_scrape_seconds_bucket{le=\"0.5\"} 1 # {trace_id=\"4bf92f3577b34da6a3ce929d0e0e4736\"} 0.3 1700000000.5
+test_scrape_seconds_bucket{le=\"+Inf\"} 1
+# TYPE test_rejected counter
+test_rejected_total{reason=\"rate_limit\",path=\"/a\\\"b\"} 1 # {trace_id=\"4bf92f3577b34da6a3ce929d0e0e4736\"} 1 1700000000.5
+test_rejected_total{reason=\"concurrency\",path=\"/a\\\"b\"} 0
+# EOF
+"
+        );
+    }
+}
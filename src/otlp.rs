@@ -0,0 +1,188 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use surf::http::headers::AUTHORIZATION;
+use surf::Url;
+
+use crate::cli::Cli;
+use crate::push::{Batch, Sample, Target};
+use crate::remotewrite::{bytes_field, varint};
+use crate::version;
+
+/// `AGGREGATION_TEMPORALITY_CUMULATIVE`
+const CUMULATIVE: u64 = 2;
+
+/// An OTLP/HTTP metrics receiver (`--push.otlp.url`), such as the OpenTelemetry Collector's
+/// `otlp` receiver.
+pub struct Otlp {
+    client: surf::Client,
+    url: Url,
+    token_file: Option<String>,
+    /// `service.name` (`--push.job`), `service.instance.id` (`--push.instance`) and
+    /// `--push.otlp.resource-attribute`.
+    resource: Vec<(String, String)>,
+    /// Start of the counters, which count from the exporter's start.
+    start: SystemTime,
+}
+
+impl Otlp {
+    pub fn new(url: &str, cli: &Cli, labels: Vec<(String, String)>) -> Result<Self> {
+        let url = Url::parse(url).with_context(|| format!("Invalid --push.otlp.url {}", url))?;
+        let mut resource = cli.otlp_resource_attributes.clone();
+        let defaults = labels.into_iter().map(|(name, value)| match name.as_str() {
+            "job" => ("service.name".to_string(), value),
+            "instance" => ("service.instance.id".to_string(), value),
+            _ => (name, value),
+        });
+        let defaults = defaults.chain(Some((
+            "service.version".to_string(),
+            version::VERSION.to_string(),
+        )));
+        for (key, value) in defaults {
+            if !resource.iter().any(|(k, _)| *k == key) {
+                resource.push((key, value));
+            }
+        }
+        Ok(Otlp {
+            client: surf::Client::new(),
+            url,
+            token_file: cli.otlp_bearer_token_file.clone(),
+            resource,
+            start: SystemTime::now(),
+        })
+    }
+}
+
+/// A `KEY=VALUE` resource attribute; unlike label names, keys may contain dots.
+pub fn parse_attribute(s: &str) -> Result<(String, String)> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(anyhow!("expected KEY=VALUE")),
+    }
+}
+
+#[tide::utils::async_trait]
+impl Target for Otlp {
+    fn name(&self) -> &'static str {
+        "otlp"
+    }
+
+    async fn push(&self, batch: &Batch) -> Result<()> {
+        let start = self.start.duration_since(UNIX_EPOCH)?.as_nanos() as u64;
+        let timestamp = batch.timestamp.duration_since(UNIX_EPOCH)?.as_nanos() as u64;
+        let body = encode(batch, &self.resource, start, timestamp);
+        let mut request = surf::post(self.url.clone())
+            .body(body)
+            .header("Content-Type", "application/x-protobuf")
+            .header(
+                "User-Agent",
+                format!("nvidia-smi-exporter/{}", version::VERSION),
+            )
+            .build();
+        // 每次推送都重新读取，令牌轮换后不必重启
+        if let Some(file) = &self.token_file {
+            let token = std::fs::read_to_string(file)
+                .with_context(|| format!("Failed to read {}", file))?;
+            request.insert_header(AUTHORIZATION, format!("Bearer {}", token.trim()));
+        }
+        let mut response = self
+            .client
+            .send(request)
+            .await
+            .map_err(|e| e.into_inner())?;
+        if !response.status().is_success() {
+            let text = response.body_string().await.unwrap_or_default();
+            bail!("{} {}", response.status(), text.trim());
+        }
+        Ok(())
+    }
+}
+
+/// Encodes an `ExportMetricsServiceRequest` protobuf with one metric per metric name. Metrics the
+/// exposition types as counters become cumulative monotonic sums, everything else gauges.
+fn encode(batch: &Batch, resource: &[(String, String)], start: u64, timestamp: u64) -> Vec<u8> {
+    let mut help = HashMap::new();
+    let mut counters = Vec::new();
+    for line in batch.exposition.lines() {
+        if let Some(rest) = line.strip_prefix("# HELP ") {
+            if let Some((name, text)) = rest.split_once(' ') {
+                help.insert(name, text);
+            }
+        } else if let Some(rest) = line.strip_prefix("# TYPE ") {
+            if let Some((name, "counter")) = rest.split_once(' ') {
+                counters.push(name);
+            }
+        }
+    }
+
+    let mut metrics: Vec<(&str, Vec<&Sample>)> = Vec::new();
+    for sample in &batch.samples {
+        match metrics.iter_mut().find(|(name, _)| *name == sample.name) {
+            Some((_, samples)) => samples.push(sample),
+            None => metrics.push((&sample.name, vec![sample])),
+        }
+    }
+
+    let mut scope_metrics = Vec::new();
+    let mut scope = Vec::new();
+    bytes_field(&mut scope, 1, env!("CARGO_PKG_NAME").as_bytes());
+    bytes_field(&mut scope, 2, version::VERSION.as_bytes());
+    bytes_field(&mut scope_metrics, 1, &scope);
+    for (name, samples) in metrics {
+        let counter = counters.contains(&name);
+        let mut data = Vec::new();
+        for sample in samples {
+            // NumberDataPoint { start_time_unix_nano = 2; time_unix_nano = 3; as_double = 4;
+            // attributes = 7; }
+            let mut point = Vec::new();
+            if counter {
+                point.push(2 << 3 | 1);
+                point.extend_from_slice(&start.to_le_bytes());
+            }
+            point.push(3 << 3 | 1);
+            point.extend_from_slice(&timestamp.to_le_bytes());
+            point.push(4 << 3 | 1);
+            point.extend_from_slice(&sample.value.to_le_bytes());
+            for (key, value) in &sample.labels {
+                bytes_field(&mut point, 7, &attribute(key, value));
+            }
+            bytes_field(&mut data, 1, &point);
+        }
+        let mut metric = Vec::new();
+        bytes_field(&mut metric, 1, name.as_bytes());
+        if let Some(help) = help.get(name) {
+            bytes_field(&mut metric, 2, help.as_bytes());
+        }
+        if counter {
+            // Sum { aggregation_temporality = 2; is_monotonic = 3; }
+            data.push(2 << 3);
+            varint(&mut data, CUMULATIVE);
+            data.extend_from_slice(&[3 << 3, 1]);
+            bytes_field(&mut metric, 7, &data);
+        } else {
+            bytes_field(&mut metric, 5, &data);
+        }
+        bytes_field(&mut scope_metrics, 2, &metric);
+    }
+
+    let mut attributes = Vec::new();
+    for (key, value) in resource {
+        bytes_field(&mut attributes, 1, &attribute(key, value));
+    }
+    let mut resource_metrics = Vec::new();
+    bytes_field(&mut resource_metrics, 1, &attributes);
+    bytes_field(&mut resource_metrics, 2, &scope_metrics);
+    let mut request = Vec::new();
+    bytes_field(&mut request, 1, &resource_metrics);
+    request
+}
+
+/// A `KeyValue` with a string value.
+fn attribute(key: &str, value: &str) -> Vec<u8> {
+    let mut any_value = Vec::new();
+    bytes_field(&mut any_value, 1, value.as_bytes());
+    let mut key_value = Vec::new();
+    bytes_field(&mut key_value, 1, key.as_bytes());
+    bytes_field(&mut key_value, 2, &any_value);
+    key_value
+}
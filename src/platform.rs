@@ -0,0 +1,153 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_std::future::timeout;
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::config::Settings;
+
+/// How often the platform is read again; it only changes when trays are moved.
+const PLATFORM_INTERVAL: Duration = Duration::from_secs(600);
+/// The fields of the `Platform Info` section of `nvidia-smi -q`, as labels.
+const FIELDS: &[&str] = &[
+    "chassis_serial_number",
+    "slot_number",
+    "tray_index",
+    "host_id",
+    "peer_type",
+    "module_id",
+];
+
+lazy_static! {
+    static ref PLATFORM_INFO: IntGaugeVec = register_int_gauge_vec!(
+        "nvidia_gpu_platform_info",
+        "The chassis, slot and tray of the GPU on DGX/HGX and OEM systems, from the platform section of nvidia-smi -q, with --collect.platform.",
+        &[&["gpu", "uuid"], FIELDS].concat()
+    )
+    .unwrap();
+}
+
+/// The `Platform Info` of a GPU in `nvidia-smi -q`.
+#[derive(Debug)]
+pub struct Platform {
+    /// The index of the GPU, its position in the output.
+    pub gpu: String,
+    pub uuid: String,
+    /// The fields by snake case name, without `N/A` ones.
+    pub fields: HashMap<String, String>,
+}
+
+/// The GPUs of the output of `nvidia-smi -q` that have a platform section with values; the
+/// others, e.g. PCIe cards, or all of them with drivers before it existed, are left out.
+pub fn parse(text: &str) -> Vec<Platform> {
+    let mut platforms: Vec<Platform> = Vec::new();
+    let mut gpus = 0;
+    // Platform Info 的缩进，其下更深的行属于它
+    let mut section = None;
+    for line in text.lines() {
+        let indent = line.len() - line.trim_start().len();
+        let line = line.trim();
+        if indent == 0 && line.starts_with("GPU ") {
+            platforms.push(Platform {
+                gpu: gpus.to_string(),
+                uuid: String::new(),
+                fields: HashMap::new(),
+            });
+            gpus += 1;
+            section = None;
+            continue;
+        }
+        let platform = match platforms.last_mut() {
+            Some(platform) => platform,
+            None => continue,
+        };
+        if section.is_some_and(|section| indent <= section) {
+            section = None;
+        }
+        if line == "Platform Info" {
+            section = Some(indent);
+            continue;
+        }
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => continue,
+        };
+        if section.is_some() {
+            if !value.is_empty() && value != "N/A" {
+                let key = key.to_ascii_lowercase().replace(' ', "_");
+                platform.fields.insert(key, value.to_string());
+            }
+        } else if key == "GPU UUID" {
+            platform.uuid = value.to_string();
+        }
+    }
+    platforms.retain(|platform| !platform.fields.is_empty());
+    platforms
+}
+
+/// The platform of the local GPUs, or of the mock ones.
+async fn platforms(settings: &Settings) -> Result<Vec<Platform>> {
+    let text = match &settings.mock {
+        Some(mock) => mock.query(),
+        None => {
+            let args = vec!["-q".to_string()];
+            let output = timeout(
+                settings.collect_timeout,
+                settings.nvidia_smi.output(&args, None),
+            )
+            .await
+            .map_err(|_| anyhow!("nvidia-smi timed out after {:?}", settings.collect_timeout))?
+            .with_context(|| "Failed to execute command")?;
+            if !output.status.success() {
+                bail!(
+                    "nvidia-smi exited with {}: {}",
+                    output.status,
+                    (String::from_utf8_lossy(&output.stdout)
+                        + String::from_utf8_lossy(&output.stderr))
+                    .trim()
+                );
+            }
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        }
+    };
+    Ok(parse(&text))
+}
+
+/// `--collect.platform`: exports the platform of the GPUs, read again every
+/// `PLATFORM_INTERVAL`.
+pub fn start(settings: &Arc<RwLock<Settings>>) {
+    let settings = settings.clone();
+    async_std::task::spawn(async move {
+        loop {
+            let current = settings.read().unwrap().clone();
+            match platforms(&current).await {
+                Ok(platforms) => {
+                    if platforms.is_empty() {
+                        debug!("nvidia-smi -q has no platform info for any GPU");
+                    }
+                    export(&platforms);
+                }
+                Err(e) => warn!("Failed to read the GPU platform, {:#}", e),
+            }
+            async_std::task::sleep(PLATFORM_INTERVAL).await;
+        }
+    });
+}
+
+fn export(platforms: &[Platform]) {
+    PLATFORM_INFO.reset();
+    for platform in platforms {
+        let mut labels = vec![platform.gpu.as_str(), platform.uuid.as_str()];
+        labels.extend(FIELDS.iter().map(|field| {
+            platform
+                .fields
+                .get(*field)
+                .map(String::as_str)
+                .unwrap_or_default()
+        }));
+        PLATFORM_INFO.with_label_values(&labels).set(1);
+    }
+}
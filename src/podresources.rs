@@ -0,0 +1,62 @@
+use anyhow::{anyhow, Context, Result};
+use async_std::future::timeout;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::grpc::{self, messages, string};
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+const METHOD: &str = "/v1.PodResourcesLister/List";
+
+/// The container a device is allocated to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Owner {
+    pub namespace: String,
+    pub pod: String,
+    pub container: String,
+}
+
+impl Owner {
+    pub fn labels(&self) -> [(String, String); 3] {
+        [
+            ("namespace".to_string(), self.namespace.clone()),
+            ("pod".to_string(), self.pod.clone()),
+            ("container".to_string(), self.container.clone()),
+        ]
+    }
+}
+
+/// The containers of the NVIDIA devices allocated by the kubelet at `socket`, by device id: a
+/// GPU or MIG UUID, or a GPU index, depending on the device plugin's configuration.
+pub async fn owners(socket: &str) -> Result<HashMap<String, Owner>> {
+    // 空的 ListPodResourcesRequest
+    let response = timeout(TIMEOUT, grpc::call(socket, METHOD, &[]))
+        .await
+        .map_err(|_| anyhow!("The kubelet did not answer within {:?}", TIMEOUT))?
+        .context("Failed to call the kubelet's pod-resources API")?;
+    let mut owners = HashMap::new();
+    // ListPodResourcesResponse { repeated PodResources pod_resources = 1 }
+    for pod in messages(&response, 1)? {
+        let namespace = string(pod, 2)?;
+        let name = string(pod, 1)?;
+        for container in messages(pod, 3)? {
+            let owner = Owner {
+                namespace: namespace.clone(),
+                pod: name.clone(),
+                container: string(container, 1)?,
+            };
+            for devices in messages(container, 2)? {
+                if !string(devices, 1)?.starts_with("nvidia.com/") {
+                    continue;
+                }
+                for id in messages(devices, 2)? {
+                    // 时间片共享的设备 ID 形如 GPU-<uuid>::<副本号>
+                    let id = String::from_utf8_lossy(id);
+                    let id = id.split("::").next().unwrap_or_default();
+                    owners.insert(id.to_string(), owner.clone());
+                }
+            }
+        }
+    }
+    Ok(owners)
+}
@@ -0,0 +1,85 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::ffi::CString;
+use std::io;
+use tracing::info;
+
+/// Switches to `--user`/`--group` once sockets are bound and key files are read.
+pub fn drop_to(user: Option<&str>, group: Option<&str>) -> Result<()> {
+    if user.is_none() && group.is_none() {
+        return Ok(());
+    }
+    let (uid, user_gid) = match user {
+        Some(user) => {
+            let (uid, gid) = lookup_user(user)?;
+            (Some(uid), gid)
+        }
+        None => (None, None),
+    };
+    // 没有指定 --group 时用该用户的主组
+    let gid = match (group, user_gid) {
+        (Some(group), _) => Some(lookup_group(group)?),
+        (None, Some(gid)) => Some(gid),
+        (None, None) if uid.is_some() => {
+            bail!(
+                "--user {} has no passwd entry, pass --group too",
+                user.unwrap()
+            )
+        }
+        (None, None) => None,
+    };
+
+    unsafe {
+        if let Some(gid) = gid {
+            if libc::setgroups(1, &gid) != 0 {
+                return Err(io::Error::last_os_error()).with_context(|| {
+                    "Failed to drop supplementary groups, --user/--group require starting as root"
+                });
+            }
+            if libc::setgid(gid) != 0 {
+                return Err(io::Error::last_os_error())
+                    .with_context(|| format!("Failed to set group id {}", gid));
+            }
+        }
+        if let Some(uid) = uid {
+            if libc::setuid(uid) != 0 {
+                return Err(io::Error::last_os_error())
+                    .with_context(|| format!("Failed to set user id {}", uid));
+            }
+            if uid != 0 && libc::setuid(0) == 0 {
+                bail!("Still able to regain root after switching to uid {}", uid);
+            }
+        }
+        info!(
+            "Dropped privileges to uid {} gid {}",
+            libc::getuid(),
+            libc::getgid()
+        );
+    }
+    Ok(())
+}
+
+/// A user name or numeric uid, with the primary group from the passwd entry if there is one.
+fn lookup_user(user: &str) -> Result<(libc::uid_t, Option<libc::gid_t>)> {
+    let entry = match user.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe { libc::getpwuid(uid) },
+        Err(_) => {
+            let name = CString::new(user).with_context(|| format!("Invalid --user {:?}", user))?;
+            unsafe { libc::getpwnam(name.as_ptr()) }
+        }
+    };
+    match (unsafe { entry.as_ref() }, user.parse()) {
+        (Some(entry), _) => Ok((entry.pw_uid, Some(entry.pw_gid))),
+        (None, Ok(uid)) => Ok((uid, None)),
+        (None, Err(_)) => Err(anyhow!("Unknown --user {}", user)),
+    }
+}
+
+fn lookup_group(group: &str) -> Result<libc::gid_t> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).with_context(|| format!("Invalid --group {:?}", group))?;
+    unsafe { libc::getgrnam(name.as_ptr()).as_ref() }
+        .map(|entry| entry.gr_gid)
+        .ok_or_else(|| anyhow!("Unknown --group {}", group))
+}
@@ -0,0 +1,165 @@
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+use ring::hmac;
+use std::fmt::Write;
+
+use crate::slurm;
+
+lazy_static! {
+    /// Unix time of the boot, which process start times in `/proc/<pid>/stat` count from.
+    static ref BOOT_TIME: Option<u64> = std::fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok();
+    static ref CLOCK_TICKS: u64 = match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        ticks if ticks > 0 => ticks as u64,
+        _ => 100,
+    };
+}
+
+/// What `/proc` tells about a GPU process, with `--collect.processes.metadata`.
+pub struct Metadata {
+    pub uid: libc::uid_t,
+    /// The short name of `/proc/<pid>/comm`, at most 15 characters.
+    pub command: String,
+    /// Unix time.
+    pub start_time: Option<u64>,
+    /// The arguments joined by spaces, cut at `--collect.processes.cmdline-length` characters.
+    pub cmdline: Option<String>,
+}
+
+pub fn metadata(pid: u32, cmdline_length: usize) -> Option<Metadata> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // pid (comm) state ppid ...，comm 里可能有空格和括号
+    let (head, tail) = stat.rsplit_once(')')?;
+    let (_, command) = head.split_once('(')?;
+    let start_time = tail
+        .split_whitespace()
+        .nth(19)
+        .and_then(|ticks| ticks.parse::<u64>().ok())
+        .zip(*BOOT_TIME)
+        .map(|(ticks, boot)| boot + ticks / *CLOCK_TICKS);
+    let cmdline = match cmdline_length {
+        0 => None,
+        length => std::fs::read(format!("/proc/{}/cmdline", pid))
+            .ok()
+            .map(|raw| {
+                String::from_utf8_lossy(&raw)
+                    .split('\0')
+                    .filter(|arg| !arg.is_empty())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+                    .chars()
+                    .take(length)
+                    .collect()
+            }),
+    };
+    Some(Metadata {
+        uid: slurm::owner(pid)?,
+        command: command.to_string(),
+        start_time,
+        cmdline,
+    })
+}
+
+/// The user name of `uid`, or the uid if it has no name.
+pub fn user(uid: libc::uid_t) -> String {
+    slurm::user_name(uid).unwrap_or_else(|| uid.to_string())
+}
+
+/// `--collect.processes.user-hash-key-file`.
+pub fn load_key(path: &str) -> Result<hmac::Key> {
+    let key = std::fs::read(path).with_context(|| format!("Failed to read {}", path))?;
+    let key = key.trim_ascii();
+    if key.is_empty() {
+        bail!("{} is empty", path);
+    }
+    Ok(hmac::Key::new(hmac::HMAC_SHA256, key))
+}
+
+/// The user as the first 16 hex digits of its HMAC-SHA256 with `key`: the same user always
+/// gets the same value, but without the key the names cannot be guessed back from it.
+pub fn hash_user(key: &hmac::Key, user: &str) -> String {
+    let tag = hmac::sign(key, user.as_bytes());
+    tag.as_ref()[..8]
+        .iter()
+        .fold(String::with_capacity(16), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn key_file(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "nvidia-smi-exporter-{}-{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn hashes_users() {
+        // RFC 4231 的第二个用例，取前 8 字节
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"Jefe");
+        assert_eq!(
+            hash_user(&key, "what do ya want for nothing?"),
+            "5bdcc146bf60754e"
+        );
+        let alice = hash_user(&key, "alice");
+        assert_eq!(alice.len(), 16);
+        assert_eq!(hash_user(&key, "alice"), alice);
+        assert_ne!(hash_user(&key, "bob"), alice);
+        let other = hmac::Key::new(hmac::HMAC_SHA256, b"other");
+        assert_ne!(hash_user(&other, "alice"), alice);
+    }
+
+    #[test]
+    fn loads_keys() {
+        let path = key_file("key", "Jefe\n");
+        let key = load_key(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            hash_user(&key, "what do ya want for nothing?"),
+            "5bdcc146bf60754e"
+        );
+        let path = key_file("empty-key", " \n");
+        assert!(load_key(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(load_key(&path).is_err());
+    }
+
+    #[test]
+    fn reads_process_metadata() {
+        let pid = std::process::id();
+        let metadata = metadata(pid, 10).unwrap();
+        assert_eq!(metadata.uid, unsafe { libc::getuid() });
+        let comm = std::fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap();
+        assert_eq!(metadata.command, comm.trim_end());
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let start_time = metadata.start_time.unwrap();
+        assert!(
+            start_time <= now && start_time + 3600 > now,
+            "{}",
+            start_time
+        );
+        let cmdline = metadata.cmdline.unwrap();
+        assert_eq!(cmdline.chars().count(), 10);
+        assert!(std::env::args().next().unwrap().starts_with(&cmdline));
+
+        assert!(super::metadata(pid, 0).unwrap().cmdline.is_none());
+        assert!(super::metadata(u32::MAX, 10).is_none());
+    }
+}
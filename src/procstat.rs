@@ -0,0 +1,126 @@
+use lazy_static::lazy_static;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{Counter, Gauge, Opts};
+use std::mem;
+
+lazy_static! {
+    static ref REGISTERED: () = prometheus::register(Box::new(ProcessCollector::new())).unwrap();
+}
+
+/// The `process_*` self-metrics, which the prometheus crate only collects on Linux, from
+/// `getrusage(2)`, `getrlimit(2)` and the `kern.proc.pid` sysctl. There is no cheap way to
+/// count the open descriptors, so `process_open_fds` is left out.
+struct ProcessCollector {
+    cpu: Counter,
+    resident: Gauge,
+    virtual_memory: Gauge,
+    start_time: Gauge,
+    max_fds: Gauge,
+    descs: Vec<Desc>,
+}
+
+impl ProcessCollector {
+    fn new() -> Self {
+        let cpu = Counter::with_opts(Opts::new(
+            "process_cpu_seconds_total",
+            "Total user and system CPU time spent in seconds.",
+        ))
+        .unwrap();
+        let gauge = |name: &str, help: &str| Gauge::with_opts(Opts::new(name, help)).unwrap();
+        let resident = gauge(
+            "process_resident_memory_bytes",
+            "Resident memory size in bytes.",
+        );
+        let virtual_memory = gauge(
+            "process_virtual_memory_bytes",
+            "Virtual memory size in bytes.",
+        );
+        let start_time = gauge(
+            "process_start_time_seconds",
+            "Start time of the process since unix epoch in seconds.",
+        );
+        let max_fds = gauge(
+            "process_max_fds",
+            "Maximum number of open file descriptors.",
+        );
+        let descs = [&resident, &virtual_memory, &start_time, &max_fds]
+            .iter()
+            .flat_map(|gauge| gauge.desc())
+            .chain(cpu.desc())
+            .cloned()
+            .collect();
+        ProcessCollector {
+            cpu,
+            resident,
+            virtual_memory,
+            start_time,
+            max_fds,
+            descs,
+        }
+    }
+}
+
+impl Collector for ProcessCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = Vec::new();
+        let mut usage: libc::rusage = unsafe { mem::zeroed() };
+        if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } == 0 {
+            let seconds = |tv: libc::timeval| tv.tv_sec as f64 + tv.tv_usec as f64 / 1e6;
+            let total = seconds(usage.ru_utime) + seconds(usage.ru_stime);
+            // 计数器只能增加，按差值补上
+            let delta = total - self.cpu.get();
+            if delta > 0.0 {
+                self.cpu.inc_by(delta);
+            }
+            families.extend(self.cpu.collect());
+        }
+        if let Some(process) = kinfo_proc() {
+            let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as f64;
+            self.resident.set(process.ki_rssize as f64 * page_size);
+            self.virtual_memory.set(process.ki_size as f64);
+            self.start_time
+                .set(process.ki_start.tv_sec as f64 + process.ki_start.tv_usec as f64 / 1e6);
+            families.extend(self.resident.collect());
+            families.extend(self.virtual_memory.collect());
+            families.extend(self.start_time.collect());
+        }
+        let mut limit: libc::rlimit = unsafe { mem::zeroed() };
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == 0 {
+            self.max_fds.set(limit.rlim_cur as f64);
+            families.extend(self.max_fds.collect());
+        }
+        families
+    }
+}
+
+pub fn register() {
+    lazy_static::initialize(&REGISTERED);
+}
+
+/// This process from the `kern.proc.pid.<pid>` sysctl.
+fn kinfo_proc() -> Option<libc::kinfo_proc> {
+    let mut mib = [
+        libc::CTL_KERN,
+        libc::KERN_PROC,
+        libc::KERN_PROC_PID,
+        std::process::id() as libc::c_int,
+    ];
+    let mut process: libc::kinfo_proc = unsafe { mem::zeroed() };
+    let mut size = mem::size_of::<libc::kinfo_proc>();
+    let result = unsafe {
+        libc::sysctl(
+            mib.as_mut_ptr(),
+            mib.len() as libc::c_uint,
+            &mut process as *mut _ as *mut libc::c_void,
+            &mut size,
+            std::ptr::null(),
+            0,
+        )
+    };
+    (result == 0 && size == mem::size_of::<libc::kinfo_proc>()).then_some(process)
+}
@@ -0,0 +1,363 @@
+use anyhow::{anyhow, bail, Result};
+use async_std::future::timeout;
+use async_std::net::{ToSocketAddrs, UdpSocket};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, error, warn};
+
+use crate::agentx::AgentX;
+use crate::alert::Alerter;
+use crate::azuremonitor::AzureMonitor;
+use crate::cli::Cli;
+use crate::cloudmonitoring::CloudMonitoring;
+use crate::cloudwatch::CloudWatch;
+use crate::collector;
+use crate::config::Settings;
+use crate::elasticsearch::Elasticsearch;
+use crate::fluentd::Fluentd;
+use crate::graphite::Graphite;
+use crate::hub::Hub;
+use crate::influx::InfluxDb;
+use crate::kafka::Kafka;
+use crate::mqtt::Mqtt;
+use crate::nats::Nats;
+use crate::otlp::Otlp;
+use crate::pushgateway::Pushgateway;
+use crate::remotewrite::RemoteWrite;
+use crate::statsd::Statsd;
+use crate::zabbix::Zabbix;
+
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest UDP datagram sent, to stay under common MTUs.
+const MAX_DATAGRAM: usize = 1400;
+
+lazy_static! {
+    static ref PUSH_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "nvidia_smi_exporter_push_failures_total",
+        "Number of failed pushes by target (remote_write, pushgateway, influxdb, graphite, statsd, otlp, zabbix, mqtt, nats, fluentd, kafka, cloudwatch, cloud_monitoring, azure_monitor, elasticsearch, hub, agentx, alerts).",
+        &["target"]
+    )
+    .unwrap();
+}
+
+/// One line of the exposition; `labels` does not include `__name__`.
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+}
+
+/// One collection, both as the exposition and parsed into samples.
+pub struct Batch {
+    pub exposition: String,
+    pub samples: Vec<Sample>,
+    pub timestamp: SystemTime,
+}
+
+/// The samples of one GPU, for targets that send a document per GPU.
+pub struct Gpu<'a> {
+    /// The `gpu` label.
+    pub index: &'a str,
+    pub name: &'a str,
+    /// The `host` label, with `--ssh.host`.
+    pub host: Option<&'a str>,
+    /// Finite values by metric name.
+    pub metrics: Map<String, Value>,
+}
+
+impl Batch {
+    /// The samples with a `gpu` label, by host and GPU; the exporter's own metrics are left out.
+    pub fn gpus(&self) -> Vec<Gpu<'_>> {
+        let mut gpus: BTreeMap<(Option<&str>, &str), Gpu> = BTreeMap::new();
+        for sample in &self.samples {
+            let label = |name: &str| {
+                sample
+                    .labels
+                    .iter()
+                    .find(|(n, _)| n == name)
+                    .map(|(_, value)| value.as_str())
+            };
+            if let Some(index) = label("gpu") {
+                let host = label("host");
+                let gpu = gpus.entry((host, index)).or_insert_with(|| Gpu {
+                    index,
+                    name: label("name").unwrap_or_default(),
+                    host,
+                    metrics: Map::new(),
+                });
+                if sample.value.is_finite() {
+                    gpu.metrics.insert(sample.name.clone(), sample.value.into());
+                }
+            }
+        }
+        gpus.into_values().collect()
+    }
+}
+
+/// Somewhere the metrics are sent every `--push.interval`.
+#[tide::utils::async_trait]
+pub trait Target: Send + Sync {
+    /// For logs and the `target` label of the failure counter.
+    fn name(&self) -> &'static str;
+
+    async fn push(&self, batch: &Batch) -> Result<()>;
+
+    /// Called once before the first push, once the runtime is up.
+    fn start(&self) {}
+
+    /// Overrides `--push.interval`.
+    fn interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Called once on shutdown.
+    async fn stop(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The push targets configured on the command line.
+pub fn targets(cli: &Cli) -> Result<Vec<Arc<dyn Target>>> {
+    let labels = vec![
+        ("job".to_string(), cli.push_job.clone()),
+        ("instance".to_string(), instance(cli)),
+    ];
+    let mut targets: Vec<Arc<dyn Target>> = Vec::new();
+    if let Some(url) = &cli.remote_write_url {
+        targets.push(Arc::new(RemoteWrite::new(url, cli, labels.clone())?));
+    }
+    if let Some(url) = &cli.pushgateway_url {
+        targets.push(Arc::new(Pushgateway::new(url, cli, labels.clone())?));
+    }
+    if let Some(url) = &cli.influxdb_url {
+        targets.push(Arc::new(InfluxDb::new(url, cli, labels.clone())?));
+    }
+    if let Some(address) = &cli.graphite_address {
+        targets.push(Arc::new(Graphite::new(address, cli, labels.clone())));
+    }
+    if let Some(address) = &cli.statsd_address {
+        targets.push(Arc::new(Statsd::new(address, cli, labels.clone())));
+    }
+    if let Some(url) = &cli.otlp_url {
+        targets.push(Arc::new(Otlp::new(url, cli, labels.clone())?));
+    }
+    if let Some(address) = &cli.zabbix_address {
+        targets.push(Arc::new(Zabbix::new(address, cli, labels.clone())));
+    }
+    if let Some(url) = &cli.mqtt_url {
+        targets.push(Arc::new(Mqtt::new(url, cli, labels.clone())?));
+    }
+    if let Some(url) = &cli.nats_url {
+        targets.push(Arc::new(Nats::new(url, cli, labels.clone())?));
+    }
+    if let Some(address) = &cli.fluentd_address {
+        targets.push(Arc::new(Fluentd::new(address, cli, labels.clone())));
+    }
+    if !cli.kafka_brokers.is_empty() {
+        targets.push(Arc::new(Kafka::new(
+            &cli.kafka_brokers,
+            cli,
+            labels.clone(),
+        )?));
+    }
+    if let Some(region) = &cli.cloudwatch_region {
+        targets.push(Arc::new(CloudWatch::new(region, cli, labels.clone())?));
+    }
+    if cli.cloud_monitoring {
+        targets.push(Arc::new(CloudMonitoring::new(cli)?));
+    }
+    if cli.azure_monitor {
+        targets.push(Arc::new(AzureMonitor::new(cli)));
+    }
+    if let Some(url) = &cli.elasticsearch_url {
+        targets.push(Arc::new(Elasticsearch::new(url, cli, labels.clone())?));
+    }
+    if let Some(url) = &cli.push_hub_url {
+        targets.push(Arc::new(Hub::new(url, cli, labels.clone())?));
+    }
+    if let Some(url) = &cli.alert_webhook_url {
+        targets.push(Arc::new(Alerter::new(url, cli, labels)?));
+    }
+    if let Some(master) = &cli.agentx_master {
+        targets.push(Arc::new(AgentX::new(master, cli)));
+    }
+    Ok(targets)
+}
+
+/// A `NAME=VALUE` label.
+pub fn parse_label(s: &str) -> Result<(String, String)> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected NAME=VALUE"))?;
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        bail!("invalid label name {:?}", name);
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// `--push.instance`, or the host name.
+pub fn instance(cli: &Cli) -> String {
+    cli.push_instance.clone().unwrap_or_else(hostname)
+}
+
+/// The host name, or `localhost` if it cannot be read.
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: gethostname 最多写入 buf.len() 字节
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return "localhost".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// Starts a push loop for each distinct interval, `interval` unless the target has its own.
+pub fn start(settings: &Arc<RwLock<Settings>>, interval: Duration, targets: &[Arc<dyn Target>]) {
+    let mut groups: Vec<(Duration, Vec<Arc<dyn Target>>)> = Vec::new();
+    for target in targets {
+        PUSH_FAILURES.with_label_values(&[target.name()]);
+        target.start();
+        let interval = target.interval().unwrap_or(interval);
+        match groups.iter_mut().find(|(other, _)| *other == interval) {
+            Some((_, group)) => group.push(target.clone()),
+            None => groups.push((interval, vec![target.clone()])),
+        }
+    }
+    for (interval, group) in groups {
+        async_std::task::spawn(run(settings.clone(), interval, group));
+    }
+}
+
+/// Collects every `interval` like a scrape would and sends the result to each target. A push
+/// that takes longer than `interval` is abandoned.
+async fn run(settings: Arc<RwLock<Settings>>, interval: Duration, targets: Vec<Arc<dyn Target>>) {
+    loop {
+        let started = Instant::now();
+        let settings = settings.read().unwrap().clone();
+        // 采集失败时仍然推送自身指标，失败计数能被看到
+        let nvidia_buffer = match collector::process_nvidia_smi(&settings).await {
+            Ok(nvidia_buffer) => nvidia_buffer,
+            Err(e) => {
+                error!("Failed to process nvidia-smi, {:#}", e);
+                String::new()
+            }
+        };
+        let exposition =
+            String::from_utf8_lossy(&crate::exposition(&settings, &nvidia_buffer)).into_owned();
+        let batch = Batch {
+            samples: parse(&exposition),
+            exposition,
+            timestamp: SystemTime::now(),
+        };
+        for target in &targets {
+            match timeout(interval, target.push(&batch)).await {
+                Ok(Ok(())) => debug!(
+                    "Pushed {} samples to {}",
+                    batch.samples.len(),
+                    target.name()
+                ),
+                Ok(Err(e)) => {
+                    warn!("Push to {} failed, {:#}", target.name(), e);
+                    PUSH_FAILURES.with_label_values(&[target.name()]).inc();
+                }
+                Err(_) => {
+                    warn!("Push to {} timed out after {:?}", target.name(), interval);
+                    PUSH_FAILURES.with_label_values(&[target.name()]).inc();
+                }
+            }
+        }
+        async_std::task::sleep(interval.saturating_sub(started.elapsed())).await;
+    }
+}
+
+/// Lets each target clean up, e.g. delete its metrics from the Pushgateway.
+pub async fn stop(targets: &[Arc<dyn Target>]) {
+    for target in targets {
+        match timeout(STOP_TIMEOUT, target.stop()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Failed to stop pushing to {}, {:#}", target.name(), e),
+            Err(_) => warn!("Stopping {} timed out", target.name()),
+        }
+    }
+}
+
+/// Sends newline-terminated `lines` to a UDP `address`, as few to a datagram as fit.
+pub async fn send_datagrams(address: &str, lines: &[String]) -> Result<()> {
+    let address = address
+        .to_socket_addrs()
+        .await
+        .ok()
+        .and_then(|mut addresses| addresses.next())
+        .ok_or_else(|| anyhow!("Failed to resolve {}", address))?;
+    let local = if address.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(address).await?;
+    let mut datagram = String::new();
+    for line in lines {
+        if !datagram.is_empty() && datagram.len() + line.len() > MAX_DATAGRAM {
+            socket.send(datagram.as_bytes()).await?;
+            datagram.clear();
+        }
+        datagram.push_str(line);
+    }
+    if !datagram.is_empty() {
+        socket.send(datagram.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Reads back the text exposition format as written by `exposition`, skipping comments.
+pub fn parse(text: &str) -> Vec<Sample> {
+    text.lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(parse_line)
+        .collect()
+}
+
+fn parse_line(line: &str) -> Option<Sample> {
+    let name_end = line.find(&['{', ' '][..])?;
+    let name = line[..name_end].to_string();
+    let mut rest = &line[name_end..];
+    let mut labels = Vec::new();
+    if let Some(mut pairs) = rest.strip_prefix('{') {
+        loop {
+            pairs = pairs.trim_start_matches(&[',', ' '][..]);
+            if let Some(after) = pairs.strip_prefix('}') {
+                rest = after;
+                break;
+            }
+            let (label, after) = pairs.split_once("=\"")?;
+            let mut value = String::new();
+            let mut chars = after.char_indices();
+            let end = loop {
+                match chars.next()? {
+                    (i, '"') => break i,
+                    (_, '\\') => match chars.next()?.1 {
+                        'n' => value.push('\n'),
+                        c => value.push(c),
+                    },
+                    (_, c) => value.push(c),
+                }
+            };
+            labels.push((label.trim().to_string(), value));
+            pairs = &after[end + 1..];
+        }
+    }
+    let value = rest.split_whitespace().next()?.parse().ok()?;
+    Some(Sample {
+        name,
+        labels,
+        value,
+    })
+}
@@ -0,0 +1,87 @@
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::URL_SAFE;
+use base64::Engine;
+use surf::http::Method;
+use surf::Url;
+
+use crate::cli::Cli;
+use crate::push::{Batch, Target};
+use crate::version;
+
+/// A Pushgateway group (`--push.pushgateway.url`), replaced by every push.
+pub struct Pushgateway {
+    client: surf::Client,
+    /// `<url>/metrics/job/<job>/instance/<instance>/...`
+    url: Url,
+    delete_on_shutdown: bool,
+}
+
+impl Pushgateway {
+    /// `labels` (`job`, `instance`) and `--push.pushgateway.grouping` make up the grouping key.
+    pub fn new(address: &str, cli: &Cli, labels: Vec<(String, String)>) -> Result<Self> {
+        let mut url = Url::parse(address)
+            .with_context(|| format!("Invalid --push.pushgateway.url {}", address))?;
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .map_err(|_| anyhow!("Invalid --push.pushgateway.url {}", address))?;
+            segments.pop_if_empty().push("metrics");
+            for (name, value) in labels.iter().chain(&cli.pushgateway_grouping) {
+                // 值里的 / 即使转义也不行，空值也无法放进路径，这两种用 base64
+                if value.is_empty() {
+                    segments.push(&format!("{}@base64", name)).push("=");
+                } else if value.contains('/') {
+                    segments
+                        .push(&format!("{}@base64", name))
+                        .push(&URL_SAFE.encode(value));
+                } else {
+                    segments.push(name).push(value);
+                }
+            }
+        }
+        Ok(Pushgateway {
+            client: surf::Client::new(),
+            url,
+            delete_on_shutdown: cli.pushgateway_delete_on_shutdown,
+        })
+    }
+
+    async fn send(&self, method: Method, body: &str) -> Result<()> {
+        let request = surf::RequestBuilder::new(method, self.url.clone())
+            .body(body.to_string())
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .header(
+                "User-Agent",
+                format!("nvidia-smi-exporter/{}", version::VERSION),
+            )
+            .build();
+        let mut response = self
+            .client
+            .send(request)
+            .await
+            .map_err(|e| e.into_inner())?;
+        if !response.status().is_success() {
+            let text = response.body_string().await.unwrap_or_default();
+            bail!("{} {}", response.status(), text.trim());
+        }
+        Ok(())
+    }
+}
+
+#[tide::utils::async_trait]
+impl Target for Pushgateway {
+    fn name(&self) -> &'static str {
+        "pushgateway"
+    }
+
+    async fn push(&self, batch: &Batch) -> Result<()> {
+        self.send(Method::Put, &batch.exposition).await
+    }
+
+    async fn stop(&self) -> Result<()> {
+        if self.delete_on_shutdown {
+            self.send(Method::Delete, "").await?;
+        }
+        Ok(())
+    }
+}
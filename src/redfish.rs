@@ -0,0 +1,258 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_rustls::TlsConnector;
+use async_std::future::timeout;
+use async_std::io::{Read, ReadExt, Write, WriteExt};
+use async_std::net::TcpStream;
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge, IntGauge};
+use serde_json::Value;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use surf::http::auth::BasicAuth;
+use surf::Url;
+use tracing::{debug, warn};
+
+use crate::cli::Cli;
+use crate::gpu::Gpu;
+use crate::tls;
+
+/// How long the list of GPUs is reused; walking the BMC's processors takes a request each.
+const DISCOVERY_TTL: Duration = Duration::from_secs(600);
+
+lazy_static! {
+    static ref GPUS: Mutex<Option<(Instant, Vec<BmcGpu>)>> = Mutex::new(None);
+    static ref UP: IntGauge = register_int_gauge!(
+        "nvidia_smi_exporter_redfish_up",
+        "Whether the BMC answered the last Redfish collection."
+    )
+    .unwrap();
+}
+
+/// The BMC at `--redfish.url`.
+#[derive(Clone)]
+pub struct Redfish {
+    host: String,
+    port: u16,
+    tls: Option<TlsConnector>,
+    username: Option<String>,
+    password_file: Option<String>,
+}
+
+impl fmt::Debug for Redfish {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Redfish({}:{})", self.host, self.port)
+    }
+}
+
+/// A GPU as the BMC lists it among the processors of a system.
+#[derive(Clone, Debug)]
+struct BmcGpu {
+    /// The processor's `Id`, e.g. `GPU_SXM_1`.
+    id: String,
+    /// `Model`, or `Name` without one.
+    name: String,
+    /// The `@odata.id` of its `EnvironmentMetrics`.
+    environment: String,
+}
+
+impl Redfish {
+    pub fn from_cli(cli: &Cli) -> Result<Option<Self>> {
+        let address = match &cli.redfish_url {
+            Some(address) => address,
+            None => return Ok(None),
+        };
+        let url =
+            Url::parse(address).with_context(|| format!("Invalid --redfish.url {}", address))?;
+        let (tls, default_port) = match url.scheme() {
+            "http" => (None, 80),
+            "https" => {
+                let config = tls::client_config(cli.redfish_ca_file.as_deref(), None, None)?;
+                (Some(TlsConnector::from(Arc::new(config))), 443)
+            }
+            scheme => bail!("Unsupported --redfish.url scheme {:?}", scheme),
+        };
+        Ok(Some(Redfish {
+            host: url
+                .host_str()
+                .ok_or_else(|| anyhow!("Invalid --redfish.url {}", address))?
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port: url.port().unwrap_or(default_port),
+            tls,
+            username: cli.redfish_username.clone(),
+            password_file: cli.redfish_password_file.clone(),
+        }))
+    }
+
+    /// `GET path` as JSON.
+    async fn get(&self, path: &str) -> Result<Value> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .with_context(|| format!("Failed to connect to {}:{}", self.host, self.port))?;
+        let response = match &self.tls {
+            Some(connector) => {
+                let name = webpki::DNSNameRef::try_from_ascii_str(&self.host)
+                    .map_err(|_| anyhow!("{} is not a valid TLS server name", self.host))?;
+                let stream = connector
+                    .connect(name, stream)
+                    .await
+                    .with_context(|| format!("TLS handshake with {} failed", self.host))?;
+                self.request(stream, path).await?
+            }
+            None => self.request(stream, path).await?,
+        };
+        let response = String::from_utf8_lossy(&response);
+        let (head, body) = response
+            .split_once("\r\n\r\n")
+            .context("Invalid response from the BMC")?;
+        let status = head.lines().next().unwrap_or_default();
+        if !status.contains(" 200 ") {
+            bail!("GET {} returned {}", path, status);
+        }
+        serde_json::from_str(body).with_context(|| format!("Invalid JSON from {}", path))
+    }
+
+    async fn request<S: Read + Write + Unpin>(&self, mut stream: S, path: &str) -> Result<Vec<u8>> {
+        // HTTP/1.0：响应读到连接关闭为止，不必处理分块编码
+        let mut request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\n",
+            path, self.host
+        );
+        if let Some(username) = &self.username {
+            // 每次都重新读取，密码轮换后不必重启
+            let password = match &self.password_file {
+                Some(file) => std::fs::read_to_string(file)
+                    .with_context(|| format!("Failed to read {}", file))?
+                    .trim()
+                    .to_string(),
+                None => String::new(),
+            };
+            let auth = BasicAuth::new(username, password);
+            request += &format!("Authorization: {}\r\n", auth.value());
+        }
+        request += "\r\n";
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok(response)
+    }
+
+    /// The processors of type GPU of every system, with where their readings are.
+    async fn discover(&self) -> Result<Vec<BmcGpu>> {
+        let mut gpus = Vec::new();
+        for system in members(&self.get("/redfish/v1/Systems").await?) {
+            let system = self.get(&system).await?;
+            let processors = match link(&system["Processors"]) {
+                Some(processors) => self.get(&processors).await?,
+                None => continue,
+            };
+            for processor in members(&processors) {
+                let processor = self.get(&processor).await?;
+                if processor["ProcessorType"].as_str() != Some("GPU") {
+                    continue;
+                }
+                // 较新的 BMC 在处理器下给出 EnvironmentMetrics，HGX 等放在 GPU 自己的机箱下
+                let environment = match link(&processor["EnvironmentMetrics"]) {
+                    Some(environment) => Some(environment),
+                    None => match link(&processor["Links"]["Chassis"][0]) {
+                        Some(chassis) => link(&self.get(&chassis).await?["EnvironmentMetrics"]),
+                        None => None,
+                    },
+                };
+                let environment = match environment {
+                    Some(environment) => environment,
+                    None => {
+                        debug!("No EnvironmentMetrics for {:?}", processor["@odata.id"]);
+                        continue;
+                    }
+                };
+                let name = processor["Model"]
+                    .as_str()
+                    .or_else(|| processor["Name"].as_str())
+                    .unwrap_or_default();
+                gpus.push(BmcGpu {
+                    id: processor["Id"].as_str().unwrap_or_default().to_string(),
+                    name: name.to_string(),
+                    environment,
+                });
+            }
+        }
+        Ok(gpus)
+    }
+
+    async fn gpus(&self) -> Result<Vec<BmcGpu>> {
+        if let Some((at, gpus)) = &*GPUS.lock().unwrap() {
+            if at.elapsed() < DISCOVERY_TTL {
+                return Ok(gpus.clone());
+            }
+        }
+        let gpus = self.discover().await?;
+        *GPUS.lock().unwrap() = Some((Instant::now(), gpus.clone()));
+        Ok(gpus)
+    }
+
+    /// The temperature and power draw of each GPU as the BMC reads them, out of band, as
+    /// `nvidia_temperature_gpu` and `nvidia_power_draw` of a `Gpu` whose index is the
+    /// processor's Redfish `Id`.
+    async fn readings(&self) -> Result<Vec<Gpu>> {
+        let mut readings = Vec::new();
+        for gpu in self.gpus().await? {
+            let environment = self.get(&gpu.environment).await?;
+            let values = [
+                ("nvidia_temperature_gpu", "TemperatureCelsius"),
+                ("nvidia_power_draw", "PowerWatts"),
+            ]
+            .iter()
+            .filter_map(|(metric, property)| {
+                let value = environment[property]["Reading"].as_f64()?;
+                Some((metric.to_string(), value))
+            })
+            .collect();
+            readings.push(Gpu {
+                index: gpu.id,
+                uuid: format!("redfish:{}", gpu.environment),
+                name: gpu.name,
+                driver_version: String::new(),
+                values,
+                host: None,
+            });
+        }
+        Ok(readings)
+    }
+}
+
+/// The readings of `--redfish.url`, within `collect_timeout`, or none if it is not given or
+/// does not answer; exported as `nvidia_smi_exporter_redfish_up`.
+pub async fn collect(redfish: Option<&Redfish>, collect_timeout: Duration) -> Vec<Gpu> {
+    let redfish = match redfish {
+        Some(redfish) => redfish,
+        None => return Vec::new(),
+    };
+    let result = timeout(collect_timeout, redfish.readings())
+        .await
+        .map_err(|_| anyhow!("Timed out after {:?}", collect_timeout))
+        .and_then(|result| result);
+    UP.set(result.is_ok() as i64);
+    result.unwrap_or_else(|e| {
+        warn!("Failed to read GPU sensors from the BMC, {:#}", e);
+        // 下次重新发现，GPU 可能换了
+        *GPUS.lock().unwrap() = None;
+        Vec::new()
+    })
+}
+
+fn members(collection: &Value) -> Vec<String> {
+    collection["Members"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(link)
+        .collect()
+}
+
+/// The `@odata.id` of a link.
+fn link(value: &Value) -> Option<String> {
+    value["@odata.id"].as_str().map(String::from)
+}
@@ -0,0 +1,273 @@
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// One rule of `--relabel.file`, a subset of Prometheus' `metric_relabel_configs`.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RelabelConfig {
+    #[serde(default)]
+    pub action: Action,
+    #[serde(default)]
+    pub source_labels: Vec<String>,
+    #[serde(default = "default_separator")]
+    pub separator: String,
+    #[serde(default = "default_regex")]
+    pub regex: String,
+    #[serde(default)]
+    pub target_label: Option<String>,
+    #[serde(default = "default_replacement")]
+    pub replacement: String,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Sets `target_label` to `replacement` when `regex` matches the joined `source_labels`.
+    #[default]
+    Replace,
+    /// Drops the series unless `regex` matches the joined `source_labels`.
+    Keep,
+    /// Drops the series when `regex` matches the joined `source_labels`.
+    Drop,
+    /// Removes the labels whose name matches `regex`.
+    LabelDrop,
+    /// Removes the labels whose name does not match `regex`.
+    LabelKeep,
+    /// Copies the labels whose name matches `regex` to the name given by `replacement`.
+    LabelMap,
+}
+
+fn default_separator() -> String {
+    ";".to_string()
+}
+
+fn default_regex() -> String {
+    "(.*)".to_string()
+}
+
+fn default_replacement() -> String {
+    "$1".to_string()
+}
+
+/// A `RelabelConfig` with its regex compiled, anchored at both ends like in Prometheus.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub config: RelabelConfig,
+    regex: Regex,
+}
+
+impl Rule {
+    fn new(config: RelabelConfig) -> Result<Self> {
+        let regex = Regex::new(&format!("^(?:{})$", config.regex))
+            .with_context(|| format!("Invalid regex {:?}", config.regex))?;
+        match (config.action, &config.target_label) {
+            (Action::Replace, None) => bail!("replace needs a target_label"),
+            (Action::Replace, Some(label)) if !valid_name(label) || label == NAME => {
+                bail!("Invalid target_label {:?}", label)
+            }
+            _ => {}
+        }
+        Ok(Rule { config, regex })
+    }
+}
+
+/// The pseudo label holding the metric name, readable by `source_labels` but never changed.
+const NAME: &str = "__name__";
+
+pub fn from_file(path: &str) -> Result<Vec<Rule>> {
+    let content =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+    let configs: Vec<RelabelConfig> = serde_yaml::from_str(&content)
+        .with_context(|| format!("Invalid relabel config {}", path))?;
+    configs
+        .into_iter()
+        .enumerate()
+        .map(|(i, config)| Rule::new(config).with_context(|| format!("{}: rule {}", path, i + 1)))
+        .collect()
+}
+
+/// Applies `rules` in order to the labels of a `metric` sample; `false` means the sample is
+/// dropped. Labels starting with `__` are removed afterwards.
+pub fn apply(rules: &[Rule], metric: &str, labels: &mut Vec<(String, String)>) -> bool {
+    for rule in rules {
+        let config = &rule.config;
+        let value = || {
+            config
+                .source_labels
+                .iter()
+                .map(|name| match name.as_str() {
+                    NAME => metric,
+                    name => get(labels, name).unwrap_or(""),
+                })
+                .collect::<Vec<_>>()
+                .join(&config.separator)
+        };
+        match config.action {
+            Action::Replace => {
+                let value = value();
+                if let Some(captures) = rule.regex.captures(&value) {
+                    let mut result = String::new();
+                    captures.expand(&config.replacement, &mut result);
+                    set(labels, config.target_label.as_deref().unwrap(), result);
+                }
+            }
+            Action::Keep if !rule.regex.is_match(&value()) => return false,
+            Action::Drop if rule.regex.is_match(&value()) => return false,
+            Action::Keep | Action::Drop => {}
+            Action::LabelDrop => labels.retain(|(name, _)| !rule.regex.is_match(name)),
+            Action::LabelKeep => labels.retain(|(name, _)| rule.regex.is_match(name)),
+            Action::LabelMap => {
+                let mapped = labels
+                    .iter()
+                    .filter_map(|(name, value)| {
+                        let captures = rule.regex.captures(name)?;
+                        let mut target = String::new();
+                        captures.expand(&config.replacement, &mut target);
+                        Some((target, value.clone()))
+                    })
+                    .filter(|(target, _)| valid_name(target))
+                    .collect::<Vec<_>>();
+                for (name, value) in mapped {
+                    set(labels, &name, value);
+                }
+            }
+        }
+    }
+    labels.retain(|(name, _)| !name.starts_with("__"));
+    true
+}
+
+fn get<'a>(labels: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    labels
+        .iter()
+        .find(|(label, _)| label == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// Sets a label, removing it when the value is empty as Prometheus does.
+fn set(labels: &mut Vec<(String, String)>, name: &str, value: String) {
+    labels.retain(|(label, _)| label != name);
+    if !value.is_empty() {
+        labels.push((name.to_string(), value));
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(yaml: &str) -> Result<Vec<Rule>> {
+        let configs: Vec<RelabelConfig> = serde_yaml::from_str(yaml)?;
+        configs.into_iter().map(Rule::new).collect()
+    }
+
+    fn labels(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn replaces() {
+        let rules = parse(
+            r#"
+- source_labels: [__name__, gpu]
+  regex: nvidia_(.*);(\d+)
+  target_label: slot
+  replacement: $1-$2
+- source_labels: [uuid]
+  target_label: uuid
+  replacement: ""
+- source_labels: [gpu]
+  regex: "1"
+  target_label: gpu
+  replacement: one
+"#,
+        )
+        .unwrap();
+        let mut gpu0 = labels(&[("gpu", "0"), ("uuid", "GPU-0")]);
+        assert!(apply(&rules, "nvidia_temperature_gpu", &mut gpu0));
+        assert_eq!(gpu0, labels(&[("gpu", "0"), ("slot", "temperature_gpu-0")]));
+        // regex 两端锚定，"1" 不匹配 "10"
+        let mut gpu10 = labels(&[("gpu", "10")]);
+        assert!(apply(&rules, "nvidia_temperature_gpu", &mut gpu10));
+        assert_eq!(
+            gpu10,
+            labels(&[("gpu", "10"), ("slot", "temperature_gpu-10")])
+        );
+    }
+
+    #[test]
+    fn keeps_and_drops() {
+        let rules = parse(
+            r#"
+- action: keep
+  source_labels: [__name__]
+  regex: nvidia_(temperature|power)_.*
+- action: drop
+  source_labels: [gpu, uuid]
+  regex: 1;.*
+"#,
+        )
+        .unwrap();
+        let gpu = |index: &str| labels(&[("gpu", index), ("uuid", "GPU-x")]);
+        assert!(apply(&rules, "nvidia_power_draw", &mut gpu("0")));
+        assert!(!apply(&rules, "nvidia_power_draw", &mut gpu("1")));
+        assert!(!apply(&rules, "nvidia_fan_speed", &mut gpu("0")));
+    }
+
+    #[test]
+    fn drops_keeps_and_maps_labels() {
+        let rules = parse(
+            r#"
+- action: labelmap
+  regex: __meta_(.+)
+- action: labelmap
+  regex: (name)
+  replacement: 0$1
+- action: labeldrop
+  regex: uuid|pci_.*
+"#,
+        )
+        .unwrap();
+        let mut series = labels(&[
+            ("gpu", "0"),
+            ("uuid", "GPU-0"),
+            ("pci_bus_id", "00000000:3B:00.0"),
+            ("__meta_rack", "r1"),
+            ("name", "A100"),
+        ]);
+        assert!(apply(&rules, "nvidia_power_draw", &mut series));
+        // 非法的目标名被忽略，__ 开头的标签最后去掉
+        assert_eq!(
+            series,
+            labels(&[("gpu", "0"), ("name", "A100"), ("rack", "r1")])
+        );
+
+        let keep = parse("- action: labelkeep\n  regex: gpu\n").unwrap();
+        let mut series = labels(&[("gpu", "0"), ("uuid", "GPU-0")]);
+        assert!(apply(&keep, "nvidia_power_draw", &mut series));
+        assert_eq!(series, labels(&[("gpu", "0")]));
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        for invalid in [
+            "- source_labels: [gpu]\n",
+            "- target_label: 0gpu\n",
+            "- target_label: __name__\n",
+            "- target_label: gpu\n  regex: (\n",
+            "- action: hashmod\n",
+            "- target_label: gpu\n  modulus: 2\n",
+        ] {
+            assert!(parse(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use anyhow::Result;
+use async_std::prelude::*;
+use signal_hook::consts::SIGHUP;
+use signal_hook_async_std::Signals;
+use std::ffi::OsString;
+use std::sync::{Arc, RwLock};
+use tide::{http::mime, Request, Response, StatusCode};
+use tracing::{error, info, warn};
+
+use crate::admin;
+use crate::auth::AuthMiddleware;
+use crate::cli::{self, Cli};
+use crate::config::{Config, Settings};
+use crate::middleware::{HeadersMiddleware, Swappable};
+use crate::tenant;
+use crate::webconfig::WebConfig;
+use crate::State;
+
+/// The files whose contents can be re-read without restarting.
+pub struct ConfigFiles {
+    pub web_config_file: Option<String>,
+    pub basic_auth_file: Option<String>,
+    pub bearer_token_file: Option<String>,
+    pub tenants_file: Option<String>,
+}
+
+impl ConfigFiles {
+    pub fn from_cli(cli: &Cli) -> Self {
+        ConfigFiles {
+            web_config_file: cli.web_config_file.clone(),
+            basic_auth_file: cli.basic_auth_file.clone(),
+            bearer_token_file: cli.bearer_token_file.clone(),
+            tenants_file: cli.web_tenants_file.clone(),
+        }
+    }
+
+    /// Reads the web config and builds the credentials from it and the auth files.
+    pub fn load(&self) -> Result<(WebConfig, AuthMiddleware)> {
+        let web_config = match &self.web_config_file {
+            Some(path) => WebConfig::from_file(path)?,
+            None => WebConfig::default(),
+        };
+        if web_config.http_server_config.http2 == Some(true) {
+            warn!("http_server_config.http2 is not supported, serving HTTP/1.1 only");
+        }
+        let mut auth = AuthMiddleware::default().with_users(web_config.basic_auth_users.clone())?;
+        if let Some(path) = &self.basic_auth_file {
+            auth = auth.with_users_file(path)?;
+        }
+        if let Some(path) = &self.bearer_token_file {
+            auth = auth.with_tokens_file(path)?;
+        }
+        if let Some(path) = &self.tenants_file {
+            auth = auth.with_tenants(tenant::load(path)?)?;
+        }
+        Ok((web_config, auth))
+    }
+}
+
+/// Re-reads the `--config` file's `Settings` and `ConfigFiles`, and swaps the running auth and
+/// header middlewares.
+pub struct Reloader {
+    /// The original command line, parsed again together with the `--config` file.
+    pub args: Vec<OsString>,
+    pub settings: Arc<RwLock<Settings>>,
+    pub files: ConfigFiles,
+    pub auth: Swappable<AuthMiddleware>,
+    pub headers: Swappable<HeadersMiddleware>,
+    pub config: Arc<RwLock<Config>>,
+}
+
+impl Reloader {
+    pub fn reload(&self) -> Result<()> {
+        // 全部读取成功后才替换，失败时保留原来的配置
+        let cli = cli::parse(&self.args)?;
+        let settings = Settings::from_cli(&cli)?;
+        let (web_config, auth) = self.files.load()?;
+        // 不能因为重新加载后没有了用户而开放管理接口
+        admin::check_auth(&cli, &auth)?;
+        let mut config = self.config.write().unwrap();
+        if web_config.tls_server_config.is_some() && web_config.tls_server_config != config.tls {
+            warn!("tls_server_config changed, restart to apply it");
+        }
+        self.auth.swap(auth);
+        self.headers.swap(HeadersMiddleware(
+            web_config.http_server_config.headers.clone(),
+        ));
+        config.headers = web_config.http_server_config.headers;
+        config.basic_auth_users = Config::redact(&web_config.basic_auth_users);
+        config.collect_timeout_seconds = settings.collect_timeout.as_secs();
+        config.collect_latency_budget_seconds =
+            settings.latency_budget.map_or(0, |budget| budget.as_secs());
+        config.collect_breaker_failures = settings.breaker.as_ref().map_or(0, |b| b.failures);
+        config.collect_breaker_cooldown_seconds =
+            settings.breaker.as_ref().map(|b| b.cooldown.as_secs());
+        config.disable_exporter_metrics = settings.disable_exporter_metrics;
+        config.collectors = settings.collector_names();
+        config.gpu_filter = settings.gpu_filter.clone();
+        config.metric_filter = settings.metric_filter.clone();
+        config.query_fields = settings.query_fields.clone();
+        config.relabel_configs = settings.relabel_configs();
+        config.health = settings.health.clone();
+        config.energy_price_per_kwh = cli.energy_price_per_kwh;
+        config.energy_currency = cli.energy_currency.clone();
+        config.energy_carbon_intensity = cli.energy_carbon_intensity;
+        config.energy_carbon_intensity_url = cli.energy_carbon_intensity_url.clone();
+        config.energy_interval_seconds = cli.energy_interval;
+        *self.settings.write().unwrap() = settings;
+        Ok(())
+    }
+}
+
+/// Reloads on every SIGHUP, regardless of `--web.enable-lifecycle`.
+pub async fn on_sighup(reloader: Arc<Reloader>) {
+    let mut signals = match Signals::new([SIGHUP]) {
+        Ok(signals) => signals,
+        Err(e) => return error!("Failed to register SIGHUP handler, {}", e),
+    };
+    while signals.next().await.is_some() {
+        match reloader.reload() {
+            Ok(()) => info!("Configuration reloaded on SIGHUP"),
+            Err(e) => error!("Failed to reload configuration on SIGHUP, {:#}", e),
+        }
+    }
+}
+
+/// `POST /-/reload`, enabled by `--web.enable-lifecycle`.
+pub async fn handle_reload(req: Request<State>) -> tide::Result {
+    let (status, body) = match req.state().reloader.reload() {
+        Ok(()) => {
+            info!("Configuration reloaded");
+            (StatusCode::Ok, "OK".to_string())
+        }
+        Err(e) => {
+            error!("Failed to reload configuration, {:#}", e);
+            (StatusCode::InternalServerError, format!("{:#}", e))
+        }
+    };
+    Ok(Response::builder(status)
+        .content_type(mime::PLAIN)
+        .body(body)
+        .build())
+}
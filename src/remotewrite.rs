@@ -0,0 +1,228 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::time::{Duration, UNIX_EPOCH};
+use surf::http::auth::BasicAuth;
+use surf::http::headers::AUTHORIZATION;
+use surf::{StatusCode, Url};
+use tracing::debug;
+
+use crate::cli::Cli;
+use crate::push::{Batch, Sample, Target};
+use crate::version;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+enum Auth {
+    None,
+    Bearer {
+        token_file: String,
+    },
+    Basic {
+        username: String,
+        password_file: String,
+    },
+}
+
+/// A Prometheus remote-write 1.0 receiver (`--push.remote-write.url`), such as Prometheus with
+/// `--web.enable-remote-write-receiver`, Mimir or Thanos Receive.
+pub struct RemoteWrite {
+    client: surf::Client,
+    url: Url,
+    auth: Auth,
+    /// `job` and `instance`, added to samples that do not have them.
+    labels: Vec<(String, String)>,
+    max_retries: u32,
+}
+
+impl RemoteWrite {
+    pub fn new(url: &str, cli: &Cli, labels: Vec<(String, String)>) -> Result<Self> {
+        let url =
+            Url::parse(url).with_context(|| format!("Invalid --push.remote-write.url {}", url))?;
+        let auth = match (
+            &cli.remote_write_bearer_token_file,
+            &cli.remote_write_username,
+            &cli.remote_write_password_file,
+        ) {
+            (Some(token_file), _, _) => Auth::Bearer {
+                token_file: token_file.clone(),
+            },
+            (None, Some(username), Some(password_file)) => Auth::Basic {
+                username: username.clone(),
+                password_file: password_file.clone(),
+            },
+            _ => Auth::None,
+        };
+        Ok(RemoteWrite {
+            client: surf::Client::new(),
+            url,
+            auth,
+            labels,
+            max_retries: cli.remote_write_max_retries,
+        })
+    }
+
+    /// Sends once; the error says whether retrying may help (connection errors, 429 and 5xx).
+    async fn send(&self, body: &[u8]) -> Result<(), (bool, anyhow::Error)> {
+        let mut request = surf::post(self.url.clone())
+            .body(body.to_vec())
+            .header("Content-Encoding", "snappy")
+            .header("Content-Type", "application/x-protobuf")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .header(
+                "User-Agent",
+                format!("nvidia-smi-exporter/{}", version::VERSION),
+            )
+            .build();
+        // 每次推送都重新读取，令牌轮换后不必重启
+        let read = |file: &String| {
+            std::fs::read_to_string(file)
+                .map(|s| s.trim().to_string())
+                .with_context(|| format!("Failed to read {}", file))
+                .map_err(|e| (false, e))
+        };
+        match &self.auth {
+            Auth::None => {}
+            Auth::Bearer { token_file } => {
+                request.insert_header(AUTHORIZATION, format!("Bearer {}", read(token_file)?));
+            }
+            Auth::Basic {
+                username,
+                password_file,
+            } => {
+                let password = read(password_file)?;
+                request.insert_header(AUTHORIZATION, BasicAuth::new(username, password).value());
+            }
+        }
+        let mut response = self
+            .client
+            .send(request)
+            .await
+            .map_err(|e| (true, e.into_inner()))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let text = response.body_string().await.unwrap_or_default();
+        let retry = status == StatusCode::TooManyRequests || status.is_server_error();
+        Err((retry, anyhow!("{} {}", status, text.trim())))
+    }
+}
+
+#[tide::utils::async_trait]
+impl Target for RemoteWrite {
+    fn name(&self) -> &'static str {
+        "remote_write"
+    }
+
+    async fn push(&self, batch: &Batch) -> Result<()> {
+        let timestamp = batch.timestamp.duration_since(UNIX_EPOCH)?.as_millis() as i64;
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&encode(&batch.samples, &self.labels, timestamp))
+            .with_context(|| "Failed to compress remote-write request")?;
+        let mut backoff = INITIAL_BACKOFF;
+        let mut retries = 0;
+        loop {
+            match self.send(&body).await {
+                Ok(()) => return Ok(()),
+                Err((true, e)) if retries < self.max_retries => {
+                    debug!("Remote write failed, {:#}, retrying in {:?}", e, backoff);
+                    async_std::task::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    retries += 1;
+                }
+                Err((_, e)) if retries > 0 => bail!("{:#} (after {} retries)", e, retries),
+                Err((_, e)) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Encodes a `prometheus.WriteRequest` protobuf, one series per sample with labels sorted by name.
+fn encode(samples: &[Sample], extra_labels: &[(String, String)], timestamp: i64) -> Vec<u8> {
+    let mut request = Vec::new();
+    for sample in samples {
+        let mut labels = vec![("__name__", sample.name.as_str())];
+        labels.extend(sample.labels.iter().map(|(n, v)| (n.as_str(), v.as_str())));
+        for (name, value) in extra_labels {
+            if !labels.iter().any(|(n, _)| n == name) {
+                labels.push((name, value));
+            }
+        }
+        labels.sort_unstable();
+
+        let mut series = Vec::new();
+        for (name, value) in labels {
+            let mut label = Vec::new();
+            bytes_field(&mut label, 1, name.as_bytes());
+            bytes_field(&mut label, 2, value.as_bytes());
+            bytes_field(&mut series, 1, &label);
+        }
+        // Sample { double value = 1; int64 timestamp = 2; }
+        let mut point = vec![1 << 3 | 1];
+        point.extend_from_slice(&sample.value.to_le_bytes());
+        point.push(2 << 3);
+        varint(&mut point, timestamp as u64);
+        bytes_field(&mut series, 2, &point);
+        bytes_field(&mut request, 1, &series);
+    }
+    request
+}
+
+pub fn bytes_field(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    varint(buf, field << 3 | 2);
+    varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+pub fn varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_write_request() {
+        let samples = [Sample {
+            name: "up".to_string(),
+            labels: vec![("gpu".to_string(), "0".to_string())],
+            value: 1.0,
+        }];
+        let extra = [
+            ("job".to_string(), "x".to_string()),
+            ("gpu".to_string(), "9".to_string()),
+        ];
+        // WriteRequest { timeseries: [TimeSeries {
+        //   labels: [__name__="up", gpu="0", job="x"], samples: [{value: 1.0, timestamp: 1000}] }] }
+        let expected: &[u8] = b"\x0a\x32\
+            \x0a\x0e\x0a\x08__name__\x12\x02up\
+            \x0a\x08\x0a\x03gpu\x12\x010\
+            \x0a\x08\x0a\x03job\x12\x01x\
+            \x12\x0c\x09\x00\x00\x00\x00\x00\x00\xf0\x3f\x10\xe8\x07";
+        assert_eq!(encode(&samples, &extra, 1000), expected);
+        assert!(encode(&[], &extra, 1000).is_empty());
+    }
+
+    #[test]
+    fn encodes_varints() {
+        for (n, expected) in [
+            (0, &[0x00][..]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (300, &[0xac, 0x02]),
+            (
+                u64::MAX,
+                &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01],
+            ),
+        ] {
+            let mut buf = Vec::new();
+            varint(&mut buf, n);
+            assert_eq!(buf, expected, "{}", n);
+        }
+    }
+}
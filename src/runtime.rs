@@ -0,0 +1,106 @@
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+use tide::convert::json;
+use tide::{Body, Request, Response, StatusCode};
+
+lazy_static! {
+    static ref COLLECTIONS: Mutex<HashMap<u64, Instant>> = Mutex::new(HashMap::new());
+    static ref CHILDREN: Mutex<HashSet<u32>> = Mutex::new(HashSet::new());
+}
+
+static NEXT_COLLECTION: AtomicU64 = AtomicU64::new(0);
+static COLLECTIONS_STARTED: AtomicU64 = AtomicU64::new(0);
+static REQUESTS_IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Marks an nvidia-smi collection as in flight until dropped.
+pub struct CollectionGuard(u64);
+
+impl CollectionGuard {
+    pub fn new() -> Self {
+        let id = NEXT_COLLECTION.fetch_add(1, Ordering::Relaxed);
+        COLLECTIONS_STARTED.fetch_add(1, Ordering::Relaxed);
+        COLLECTIONS.lock().unwrap().insert(id, Instant::now());
+        CollectionGuard(id)
+    }
+}
+
+impl Drop for CollectionGuard {
+    fn drop(&mut self) {
+        COLLECTIONS.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Counts an HTTP request as in flight until dropped.
+pub struct RequestGuard;
+
+impl RequestGuard {
+    pub fn new() -> Self {
+        REQUESTS_IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+        RequestGuard
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        REQUESTS_IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Tracks a running nvidia-smi child by pid until dropped.
+pub struct ChildGuard(u32);
+
+impl ChildGuard {
+    pub fn new(pid: u32) -> Self {
+        CHILDREN.lock().unwrap().insert(pid);
+        ChildGuard(pid)
+    }
+}
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        CHILDREN.lock().unwrap().remove(&self.0);
+    }
+}
+
+pub fn requests_in_flight() -> usize {
+    REQUESTS_IN_FLIGHT.load(Ordering::Relaxed)
+}
+
+/// Sends SIGKILL to every child still registered, returning how many there were.
+pub fn kill_children() -> usize {
+    let children = CHILDREN.lock().unwrap();
+    for pid in children.iter() {
+        unsafe {
+            libc::kill(*pid as libc::pid_t, libc::SIGKILL);
+        }
+    }
+    children.len()
+}
+
+pub async fn handle_debug_runtime<State>(_req: Request<State>) -> tide::Result {
+    let mut collections: Vec<_> = COLLECTIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, started)| (*id, started.elapsed()))
+        .collect();
+    collections.sort_by_key(|(id, _)| *id);
+    let collections: Vec<_> = collections
+        .into_iter()
+        .map(|(id, elapsed)| json!({ "id": id, "elapsed_seconds": elapsed.as_secs_f64() }))
+        .collect();
+
+    let body = json!({
+        // async-std 不暴露任务数和队列深度，只能给出自己记录的状态
+        "requests_in_flight": REQUESTS_IN_FLIGHT.load(Ordering::Relaxed),
+        "collections_started": COLLECTIONS_STARTED.load(Ordering::Relaxed),
+        "collections_in_flight": collections,
+        "children": CHILDREN.lock().unwrap().iter().collect::<Vec<_>>(),
+    });
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&body)?)
+        .build())
+}
@@ -0,0 +1,154 @@
+#[cfg(target_os = "linux")]
+use anyhow::Context;
+use anyhow::{bail, Result};
+#[cfg(target_os = "linux")]
+use landlock::{
+    path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus,
+    ABI,
+};
+#[cfg(target_os = "linux")]
+use seccompiler::{apply_filter, BpfProgram, SeccompAction, SeccompFilter};
+#[cfg(target_os = "linux")]
+use std::collections::BTreeMap;
+#[cfg(target_os = "linux")]
+use std::convert::TryInto;
+#[cfg(target_os = "linux")]
+use std::path::{Path, PathBuf};
+#[cfg(target_os = "linux")]
+use tracing::{info, warn};
+
+#[cfg(target_os = "linux")]
+/// Read-only, executable system locations nvidia-smi and its libraries live in.
+const SYSTEM_PATHS: &[&str] = &[
+    "/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/etc", "/opt", "/proc", "/sys",
+];
+
+#[cfg(target_os = "linux")]
+/// Syscalls nothing in the exporter or nvidia-smi needs; they fail with EPERM.
+const DENIED_SYSCALLS: &[i64] = &[
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_reboot,
+    libc::SYS_kexec_load,
+    libc::SYS_kexec_file_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_acct,
+    libc::SYS_settimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_adjtimex,
+    libc::SYS_setuid,
+    libc::SYS_setgid,
+    libc::SYS_setreuid,
+    libc::SYS_setregid,
+    libc::SYS_setresuid,
+    libc::SYS_setresgid,
+    libc::SYS_setgroups,
+];
+
+#[cfg(target_os = "linux")]
+/// Restricts the process to reading system paths and the directories of `files`, writing the
+/// directories of `writable` (log files, which are rotated, and captures), executing from
+/// system paths and `$PATH`, and opening `/dev` read-write, then installs the seccomp denylist.
+///
+/// Both only apply to the calling thread and what it starts later, so this must run before the
+/// async runtime spawns its threads.
+pub fn apply(files: &[&str], writable: &[&str]) -> Result<()> {
+    if thread_count()? > 1 {
+        bail!("--sandbox must be applied before other threads start (not possible with --otlp.endpoint)");
+    }
+    landlock(files, writable)?;
+    seccomp()?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn landlock(files: &[&str], writable: &[&str]) -> Result<()> {
+    let abi = ABI::V5;
+    let read_exec = AccessFs::from_read(abi);
+    let search_paths: Vec<PathBuf> = std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).collect())
+        .unwrap_or_default();
+    // 允许整个目录：文件被替换（如 ConfigMap 更新）后 inode 会变，reload 仍要能读到
+    let dirs: Vec<&Path> = files.iter().filter_map(|f| Path::new(f).parent()).collect();
+    let writable_dirs: Vec<&Path> = writable
+        .iter()
+        .filter_map(|f| Path::new(f).parent())
+        .collect();
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))?
+        .create()?
+        .add_rules(path_beneath_rules(SYSTEM_PATHS, read_exec))?
+        .add_rules(path_beneath_rules(&search_paths, read_exec))?
+        .add_rules(path_beneath_rules(&dirs, AccessFs::from_read(abi)))?
+        .add_rules(path_beneath_rules(&writable_dirs, AccessFs::from_all(abi)))?
+        // nvidia-smi 以读写方式打开 /dev/nvidia* 并 ioctl
+        .add_rules(path_beneath_rules(
+            &["/dev"],
+            AccessFs::from_read(abi) | AccessFs::WriteFile | AccessFs::IoctlDev,
+        ))?
+        .restrict_self()
+        .with_context(|| "Failed to enable Landlock")?;
+    match status.ruleset {
+        RulesetStatus::FullyEnforced => info!("Landlock filesystem rules enforced"),
+        RulesetStatus::PartiallyEnforced => {
+            warn!("Landlock filesystem rules only partially enforced by this kernel")
+        }
+        RulesetStatus::NotEnforced => {
+            warn!("Landlock is not available, filesystem access is not restricted")
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn seccomp() -> Result<()> {
+    let rules = DENIED_SYSCALLS
+        .iter()
+        .map(|syscall| (*syscall, Vec::new()))
+        .collect::<BTreeMap<_, _>>();
+    let filter = SeccompFilter::new(
+        rules,
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::EPERM as u32),
+        std::env::consts::ARCH
+            .try_into()
+            .with_context(|| "seccomp is not supported on this architecture")?,
+    )?;
+    let program: BpfProgram = filter.try_into()?;
+    apply_filter(&program).with_context(|| "Failed to install seccomp filter")?;
+    info!(
+        "seccomp filter installed, {} syscalls denied",
+        DENIED_SYSCALLS.len()
+    );
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub fn thread_count() -> Result<usize> {
+    Ok(std::fs::read_dir("/proc/self/task")
+        .with_context(|| "Failed to read /proc/self/task")?
+        .count())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn apply(_files: &[&str], _writable: &[&str]) -> Result<()> {
+    bail!("--sandbox needs Landlock and seccomp, which only Linux has");
+}
@@ -0,0 +1,154 @@
+use clap::{ArgAction, Command};
+use serde_json::{json, Map, Value};
+
+/// `config-schema`: a JSON Schema (draft 2020-12) for `--config` files, derived from the command
+/// line options so the two cannot drift apart.
+pub fn config_schema(command: &Command) -> Value {
+    let mut options = Vec::new();
+    for arg in command.get_arguments() {
+        let name = match arg.get_long() {
+            Some(long) if long != "config" => long,
+            _ => continue,
+        };
+        let help = arg.get_help().map(ToString::to_string);
+        let schema = match arg.get_action() {
+            ArgAction::SetTrue => json!({"type": "boolean"}),
+            ArgAction::Count => json!({"type": "integer", "minimum": 0}),
+            ArgAction::Help | ArgAction::Version => continue,
+            action => {
+                let default = arg.get_default_values().first().and_then(|v| v.to_str());
+                let values = arg
+                    .get_possible_values()
+                    .iter()
+                    .map(|v| v.get_name().to_string())
+                    .collect::<Vec<_>>();
+                let mut value = match default {
+                    _ if !values.is_empty() => json!({"enum": values}),
+                    Some(default) if default.parse::<u64>().is_ok() => {
+                        json!({"type": "integer", "minimum": 0})
+                    }
+                    _ => json!({"type": "string"}),
+                };
+                if let Some(default) = default {
+                    value["default"] = match default.parse::<u64>() {
+                        Ok(n) => json!(n),
+                        Err(_) => json!(default),
+                    };
+                }
+                if matches!(action, ArgAction::Append) {
+                    json!({"anyOf": [value, {"type": "array", "items": value}]})
+                } else {
+                    value
+                }
+            }
+        };
+        options.push((name, describe(schema, help)));
+    }
+    options.sort_by_key(|(name, _)| *name);
+
+    let mut schema = object(&options);
+    schema["$schema"] = json!("https://json-schema.org/draft/2020-12/schema");
+    schema["title"] = json!("nvidia-smi-exporter --config file");
+    schema
+}
+
+fn describe(mut schema: Value, help: Option<String>) -> Value {
+    if let Some(help) = help {
+        schema["description"] = json!(help);
+    }
+    schema
+}
+
+/// An object accepting every option by its full name and, for dotted names, nested under each of
+/// its prefixes: `collect.timeout` or `collect: {timeout: ...}`. A prefix that is an option itself,
+/// e.g. `mdns` of `mdns.name`, takes either form.
+fn object(options: &[(&str, Value)]) -> Value {
+    let mut properties = Map::new();
+    for (name, schema) in options {
+        properties.insert(name.to_string(), schema.clone());
+    }
+    let mut prefixes = options
+        .iter()
+        .flat_map(|(name, _)| name.match_indices('.').map(move |(i, _)| &name[..i]))
+        .collect::<Vec<_>>();
+    prefixes.sort_unstable();
+    prefixes.dedup();
+    for prefix in prefixes {
+        let nested = options
+            .iter()
+            .filter_map(|(name, schema)| {
+                name.strip_prefix(prefix)?
+                    .strip_prefix('.')
+                    .map(|rest| (rest, schema.clone()))
+            })
+            .collect::<Vec<_>>();
+        let nested = object(&nested);
+        let schema = match properties.remove(prefix) {
+            Some(option) => json!({"anyOf": [option, nested]}),
+            None => nested,
+        };
+        properties.insert(prefix.to_string(), schema);
+    }
+    json!({
+        "type": "object",
+        "properties": properties,
+        "additionalProperties": false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(config: &str) -> Vec<String> {
+        let schema = config_schema(&crate::cli::command());
+        let validator = jsonschema::draft202012::new(&schema).unwrap();
+        let config: Value = serde_yaml::from_str(config).unwrap();
+        validator
+            .iter_errors(&config)
+            .map(|e| format!("{} at {}", e, e.instance_path()))
+            .collect()
+    }
+
+    #[test]
+    fn accepts_sample_config() {
+        let config = r#"
+verbose: 1
+listen: ["0.0.0.0:9101", "unix:/run/nvidia-smi-exporter.sock"]
+web:
+  telemetry-path: /metrics
+  max-requests: 10
+  enable-lifecycle: true
+  access-log: true
+  access-log.format: "{client} {path}"
+collect.timeout: 5
+collect:
+  processes:
+    cmdline-length: 64
+  passthrough: true
+mdns: true
+mdns.instance: gpu-node-1
+collector.clocks: false
+disable-exporter-metrics: true
+"#;
+        assert_eq!(errors(config), Vec::<String>::new());
+        assert_eq!(
+            errors("web: {access-log: {format: '{path}'}}\nmdns: {instance: x}\n"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        for config in [
+            "listen-address: 0.0.0.0:9101\n",
+            "web: {telemetry-paths: /metrics}\n",
+            "mdns: yes please\n",
+            "collect: {processes: {unknown: 1}}\n",
+            "collect.timeout: -1\n",
+            "verbose: true\n",
+        ] {
+            assert!(!errors(config).is_empty(), "{:?}", config);
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use anyhow::Result;
+use async_std::channel::{self, Receiver, Sender};
+use async_std::prelude::*;
+use async_std::task;
+use lazy_static::lazy_static;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook_async_std::Signals;
+use std::time::{Duration, Instant};
+use tide::{http::mime, Request, Response, StatusCode};
+use tracing::{info, warn};
+
+use crate::runtime;
+
+lazy_static! {
+    static ref QUIT: (Sender<()>, Receiver<()>) = channel::bounded(1);
+}
+
+/// Resolves on the first SIGTERM or SIGINT, or a `/-/quit` request.
+pub async fn signal() -> Result<()> {
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    let signal = async {
+        if let Some(signal) = signals.next().await {
+            info!("Received signal {}, shutting down", signal);
+        }
+    };
+    let quit = async {
+        if QUIT.1.recv().await.is_ok() {
+            info!("Received /-/quit, shutting down");
+        }
+    };
+    signal.race(quit).await;
+    Ok(())
+}
+
+/// `POST /-/quit`, enabled by `--web.enable-lifecycle`; shuts down like SIGTERM.
+pub async fn handle_quit<State>(_req: Request<State>) -> tide::Result {
+    // 稍后再触发，先让本次响应写回客户端；重复请求直接忽略
+    task::spawn(async {
+        task::sleep(Duration::from_millis(100)).await;
+        let _ = QUIT.0.try_send(());
+    });
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(mime::PLAIN)
+        .body("Requesting termination... Goodbye!")
+        .build())
+}
+
+/// Waits up to `deadline` for in-flight requests, then kills any nvidia-smi left behind.
+pub async fn drain(deadline: Duration) {
+    let started = Instant::now();
+    while runtime::requests_in_flight() > 0 {
+        if started.elapsed() >= deadline {
+            warn!(
+                "{} requests still in flight after {:?}, abandoning them",
+                runtime::requests_in_flight(),
+                deadline
+            );
+            break;
+        }
+        task::sleep(Duration::from_millis(50)).await;
+    }
+    let killed = runtime::kill_children();
+    if killed > 0 {
+        warn!("Killed {} nvidia-smi processes still running", killed);
+    }
+}
@@ -0,0 +1,87 @@
+use std::ffi::CStr;
+
+/// The Slurm job a process belongs to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Job {
+    pub id: String,
+    /// The uid if it has no name, or empty if the user cannot be found.
+    pub user: String,
+}
+
+impl Job {
+    pub fn labels(&self) -> [(String, String); 2] {
+        [
+            ("slurm_job_id".to_string(), self.id.clone()),
+            ("slurm_user".to_string(), self.user.clone()),
+        ]
+    }
+}
+
+/// The job `pid` runs in, from the cgroup slurmd puts it in, e.g.
+/// `/slurm/uid_1000/job_4242/step_0/task_0` (cgroup v1) or
+/// `/system.slice/slurmstepd.scope/job_4242/step_0/user/task_0` (v2), or else from its
+/// `SLURM_JOB_ID` and `SLURM_JOB_USER`.
+pub fn job(pid: u32) -> Option<Job> {
+    let cgroup = std::fs::read_to_string(format!("/proc/{}/cgroup", pid)).unwrap_or_default();
+    let component = |prefix: &str| {
+        cgroup
+            .lines()
+            .filter_map(|line| line.splitn(3, ':').nth(2))
+            .flat_map(|path| path.split('/'))
+            .find_map(|c| c.strip_prefix(prefix).filter(|id| is_number(id)))
+            .map(str::to_string)
+    };
+    // 读别人的 environ 需要同一用户或 root，所以 cgroup 优先
+    let environ = || {
+        let environ = std::fs::read(format!("/proc/{}/environ", pid)).ok()?;
+        let var = |name: &str| {
+            environ
+                .split(|&b| b == 0)
+                .find_map(|entry| entry.strip_prefix(format!("{}=", name).as_bytes()))
+                .map(|value| String::from_utf8_lossy(value).into_owned())
+        };
+        Some((var("SLURM_JOB_ID")?, var("SLURM_JOB_USER")))
+    };
+    let (id, user) = match component("job_") {
+        Some(id) => (id, None),
+        None => environ().filter(|(id, _)| is_number(id))?,
+    };
+    let uid = || {
+        component("uid_")
+            .and_then(|uid| uid.parse().ok())
+            .or_else(|| owner(pid))
+    };
+    let user = user
+        .or_else(|| uid().map(|uid| user_name(uid).unwrap_or_else(|| uid.to_string())))
+        .unwrap_or_default();
+    Some(Job { id, user })
+}
+
+fn is_number(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_digit())
+}
+
+/// The real uid of `pid`.
+pub fn owner(pid: u32) -> Option<libc::uid_t> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Uid:"))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+pub fn user_name(uid: libc::uid_t) -> Option<String> {
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = std::ptr::null_mut();
+    // SAFETY: getpwuid_r 只写入 entry 和 buf，结果指向 entry 或为空；可在多个线程里调用
+    let rc = unsafe { libc::getpwuid_r(uid, &mut entry, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if rc != 0 || result.is_null() {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(entry.pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
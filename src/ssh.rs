@@ -0,0 +1,233 @@
+use anyhow::{bail, Context, Result};
+use async_std::process::Command;
+use std::str::FromStr;
+
+use crate::cli::Cli;
+
+/// A host to run nvidia-smi on, `[user@]host[:port]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Target {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl Target {
+    /// The `host` label: the host, and the port if given.
+    pub fn label(&self) -> String {
+        match self.port {
+            Some(port) if self.host.contains(':') => format!("[{}]:{}", self.host, port),
+            Some(port) => format!("{}:{}", self.host, port),
+            None => self.host.clone(),
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (user, rest) = match s.rsplit_once('@') {
+            Some((user, rest)) => (Some(user.to_string()), rest),
+            None => (None, s),
+        };
+        // [::1]:22；不带方括号的 IPv6 地址没有端口
+        let (host, port) = if let Some(rest) = rest.strip_prefix('[') {
+            match rest.split_once(']') {
+                Some((host, "")) => (host, None),
+                Some((host, port)) => match port.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None => bail!("expected [HOST]:PORT"),
+                },
+                None => bail!("missing ]"),
+            }
+        } else {
+            match rest.split_once(':') {
+                Some((host, port)) if !port.contains(':') => (host, Some(port)),
+                _ => (rest, None),
+            }
+        };
+        if host.is_empty() {
+            bail!("empty host");
+        }
+        let port = match port {
+            Some(port) => Some(
+                port.parse()
+                    .with_context(|| format!("invalid port {:?}", port))?,
+            ),
+            None => None,
+        };
+        Ok(Target {
+            user,
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+/// How to reach the `--ssh.host`s.
+#[derive(Clone, Debug)]
+pub struct Ssh {
+    pub targets: Vec<Target>,
+    /// Whether `?target=` may name hosts not in `targets`.
+    pub any_target: bool,
+    user: Option<String>,
+    identity_file: Option<String>,
+    options: Vec<String>,
+    connect_timeout: u64,
+}
+
+impl Ssh {
+    /// The `--ssh.host`s and those of `--ssh.hosts-file`, or none for local collection and no
+    /// `?target=`.
+    pub fn from_cli(cli: &Cli) -> Result<Option<Ssh>> {
+        let mut targets = cli.ssh_hosts.clone();
+        if let Some(path) = &cli.ssh_hosts_file {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path))?;
+            for (i, line) in text.lines().enumerate() {
+                let line = line.split('#').next().unwrap_or_default().trim();
+                if line.is_empty() {
+                    continue;
+                }
+                targets.push(
+                    line.parse()
+                        .with_context(|| format!("{}:{}", path, i + 1))?,
+                );
+            }
+        }
+        if targets.is_empty() && !cli.ssh_any_target {
+            return Ok(None);
+        }
+        for (i, target) in targets.iter().enumerate() {
+            if targets[..i].iter().any(|t| t.label() == target.label()) {
+                bail!("SSH host {} is given more than once", target.label());
+            }
+        }
+        Ok(Some(Ssh {
+            targets,
+            any_target: cli.ssh_any_target,
+            user: cli.ssh_user.clone(),
+            identity_file: cli.ssh_identity_file.clone(),
+            options: cli.ssh_options.clone(),
+            connect_timeout: cli.ssh_connect_timeout,
+        }))
+    }
+
+    /// The `?target=` to probe, which must be one of `targets`, and not name a user, unless
+    /// `any_target`.
+    pub fn probe_target(&self, target: &str) -> Result<Target, String> {
+        let target = target
+            .parse::<Target>()
+            .map_err(|e| format!("Invalid target {:?}: {:#}", target, e))?;
+        if target.user.is_some() && !self.any_target {
+            return Err(format!(
+                "A user in target {:?} needs --ssh.any-target",
+                target.label()
+            ));
+        }
+        if let Some(known) = self.targets.iter().find(|t| t.label() == target.label()) {
+            // 按标签匹配，总是用配置里的用户名，抓取方不能换成别的用户登录
+            return Ok(known.clone());
+        }
+        match self.any_target {
+            true => Ok(target),
+            false => Err(format!(
+                "Unknown target {:?}, see --ssh.any-target",
+                target.label()
+            )),
+        }
+    }
+
+    /// `ssh <target> <program> <args>`, never prompting for anything.
+    pub fn command(&self, target: &Target, program: &str, args: &[String]) -> Command {
+        let mut command = Command::new("ssh");
+        command
+            .arg("-o")
+            .arg("BatchMode=yes")
+            .arg("-o")
+            .arg(format!("ConnectTimeout={}", self.connect_timeout));
+        for option in &self.options {
+            command.arg("-o").arg(option);
+        }
+        if let Some(path) = &self.identity_file {
+            command.arg("-i").arg(path);
+        }
+        if let Some(port) = target.port {
+            command.arg("-p").arg(port.to_string());
+        }
+        if let Some(user) = target.user.as_ref().or(self.user.as_ref()) {
+            command.arg("-l").arg(user);
+        }
+        // 远端由 shell 解析命令行，参数要加引号
+        let remote = std::iter::once(program)
+            .chain(args.iter().map(String::as_str))
+            .map(quote)
+            .collect::<Vec<_>>()
+            .join(" ");
+        command.arg("--").arg(&target.host).arg(remote);
+        command
+    }
+}
+
+fn quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_.,=:/@%+".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ssh(any_target: bool) -> Ssh {
+        Ssh {
+            targets: vec![
+                "monitor@gpu1.example.com".parse().unwrap(),
+                "gpu2.example.com:2222".parse().unwrap(),
+            ],
+            any_target,
+            user: None,
+            identity_file: None,
+            options: Vec::new(),
+            connect_timeout: 5,
+        }
+    }
+
+    #[test]
+    fn probes_configured_targets_as_configured() {
+        let ssh = ssh(false);
+        assert_eq!(
+            ssh.probe_target("gpu1.example.com"),
+            Ok("monitor@gpu1.example.com".parse().unwrap())
+        );
+        assert_eq!(
+            ssh.probe_target("gpu2.example.com:2222"),
+            Ok("gpu2.example.com:2222".parse().unwrap())
+        );
+        // 不能通过 ?target=root@host 换用户
+        assert!(ssh.probe_target("root@gpu1.example.com").is_err());
+        assert!(ssh.probe_target("gpu2.example.com").is_err());
+        assert!(ssh.probe_target("gpu3.example.com").is_err());
+    }
+
+    #[test]
+    fn probes_any_target_with_any_target() {
+        let ssh = ssh(true);
+        assert_eq!(
+            ssh.probe_target("root@gpu1.example.com"),
+            Ok("monitor@gpu1.example.com".parse().unwrap())
+        );
+        assert_eq!(
+            ssh.probe_target("root@gpu3.example.com"),
+            Ok(Target {
+                user: Some("root".to_string()),
+                host: "gpu3.example.com".to_string(),
+                port: None,
+            })
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use anyhow::{Context, Result};
+use prometheus::core::Collector;
+use prometheus::{CounterVec, IntCounterVec};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::oneshot;
+
+/// How often `--state-file` is written besides at shutdown, bounding what a crash loses.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A counter the exporter derives itself, kept in `--state-file` across restarts.
+#[derive(Clone, Copy)]
+pub enum Counter {
+    Int(&'static IntCounterVec),
+    Float(&'static CounterVec),
+}
+
+impl Counter {
+    fn samples(self) -> (String, Vec<Sample>) {
+        let families = match self {
+            Counter::Int(counter) => counter.collect(),
+            Counter::Float(counter) => counter.collect(),
+        };
+        let family = &families[0];
+        let mut samples = family
+            .get_metric()
+            .iter()
+            .map(|metric| Sample {
+                labels: metric
+                    .get_label()
+                    .iter()
+                    .map(|label| (label.get_name().to_string(), label.get_value().to_string()))
+                    .collect(),
+                value: metric.get_counter().get_value(),
+            })
+            .collect::<Vec<_>>();
+        samples.sort_by(|a, b| a.labels.cmp(&b.labels));
+        (family.get_name().to_string(), samples)
+    }
+
+    fn add(self, sample: &Sample) -> Result<()> {
+        let labels = sample
+            .labels
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect::<HashMap<_, _>>();
+        match self {
+            Counter::Int(counter) => counter
+                .get_metric_with(&labels)?
+                .inc_by(sample.value as u64),
+            Counter::Float(counter) => counter.get_metric_with(&labels)?.inc_by(sample.value),
+        }
+        Ok(())
+    }
+}
+
+/// The content of `--state-file`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct State {
+    /// Samples by metric name.
+    counters: BTreeMap<String, Vec<Sample>>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct Sample {
+    labels: BTreeMap<String, String>,
+    value: f64,
+}
+
+/// Adds the values saved in `path` to `counters`, before anything counts; a missing file is a
+/// first start. Saved counters of collectors no longer enabled are left out, and dropped from
+/// the file at the next save.
+pub fn restore(path: &str, counters: &[Counter]) -> Result<()> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path)),
+    };
+    let state: State =
+        serde_json::from_str(&content).with_context(|| format!("Invalid state file {}", path))?;
+    let mut restored = 0;
+    for counter in counters {
+        let (name, _) = counter.samples();
+        for sample in state.counters.get(&name).into_iter().flatten() {
+            // 标签变了的（升级后）不恢复，其余照常
+            match counter.add(sample) {
+                Ok(()) => restored += 1,
+                Err(e) => warn!("Not restoring {} {:?}, {}", name, sample.labels, e),
+            }
+        }
+    }
+    info!("Restored {} counter values from {}", restored, path);
+    Ok(())
+}
+
+/// Writes the current values of `counters` to `path`.
+pub fn save(path: &str, counters: &[Counter]) -> Result<()> {
+    let state = State {
+        counters: counters.iter().map(|counter| counter.samples()).collect(),
+    };
+    oneshot::write_atomically(path, &serde_json::to_vec_pretty(&state)?)
+}
+
+/// `--state-file`: saves `counters` every `SAVE_INTERVAL`.
+pub fn start(path: &str, counters: Vec<Counter>) {
+    let path = path.to_string();
+    async_std::task::spawn(async move {
+        loop {
+            async_std::task::sleep(SAVE_INTERVAL).await;
+            if let Err(e) = save(&path, &counters) {
+                warn!("Failed to save the counters, {:#}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lazy_static::lazy_static;
+    use prometheus::{opts, Opts};
+
+    lazy_static! {
+        static ref EVENTS: IntCounterVec =
+            IntCounterVec::new(opts!("test_events_total", "Events."), &["gpu", "event"]).unwrap();
+        static ref COST: CounterVec =
+            CounterVec::new(Opts::new("test_cost_total", "Cost."), &["gpu"]).unwrap();
+    }
+
+    #[test]
+    fn saves_and_restores_counters() {
+        let path = std::env::temp_dir().join(format!(
+            "nvidia-smi-exporter-state-{}.json",
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap();
+        let counters = [Counter::Int(&EVENTS), Counter::Float(&COST)];
+        assert!(restore(path, &counters).is_ok());
+
+        EVENTS.with_label_values(&["0", "xid"]).inc_by(3);
+        COST.with_label_values(&["0"]).inc_by(0.25);
+        save(path, &counters).unwrap();
+        let state: State = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(
+            state.counters["test_events_total"],
+            [Sample {
+                labels: [("event", "xid"), ("gpu", "0")]
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+                value: 3.0,
+            }]
+        );
+
+        // 重启后从保存的值继续计数，计数器不回退
+        EVENTS.reset();
+        COST.reset();
+        restore(path, &counters).unwrap();
+        EVENTS.with_label_values(&["0", "xid"]).inc();
+        assert_eq!(EVENTS.with_label_values(&["0", "xid"]).get(), 4);
+        assert_eq!(COST.with_label_values(&["0"]).get(), 0.25);
+
+        std::fs::write(
+            path,
+            r#"{"counters":{"test_events_total":[{"labels":{"gpu":"1"},"value":1}]}}"#,
+        )
+        .unwrap();
+        EVENTS.reset();
+        restore(path, &counters).unwrap();
+        assert!(EVENTS.collect()[0].get_metric().is_empty());
+
+        std::fs::write(path, "{").unwrap();
+        assert!(restore(path, &counters).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+use anyhow::Result;
+
+use crate::cli::Cli;
+use crate::graphite::sanitize;
+use crate::push::{self, Batch, Sample, Target};
+
+/// A StatsD or DogStatsD server (`--push.statsd.address`), e.g. the Datadog agent, sent gauges
+/// over UDP.
+pub struct Statsd {
+    address: String,
+    prefix: String,
+    dogstatsd: bool,
+    /// `job` and `instance`.
+    labels: Vec<(String, String)>,
+}
+
+impl Statsd {
+    pub fn new(address: &str, cli: &Cli, labels: Vec<(String, String)>) -> Self {
+        Statsd {
+            address: address.to_string(),
+            prefix: cli.statsd_prefix.trim_end_matches('.').to_string(),
+            dogstatsd: cli.statsd_dogstatsd,
+            labels,
+        }
+    }
+
+    /// `<prefix>.<instance>.<metric>.<label>.<value>...:<value>|g`, or with
+    /// `--push.statsd.dogstatsd` `<prefix>.<metric>:<value>|g|#<label>:<value>,...` including
+    /// `job` and `instance`.
+    fn lines(&self, sample: &Sample) -> Vec<String> {
+        let mut name = self.prefix.clone();
+        let mut push = |component: &str| {
+            if !name.is_empty() {
+                name.push('.');
+            }
+            name.push_str(component);
+        };
+        if self.dogstatsd {
+            push(&sanitize(&sample.name));
+            let mut tags = sample.labels.iter().chain(&self.labels).collect::<Vec<_>>();
+            tags.sort_by(|a, b| a.0.cmp(&b.0));
+            tags.dedup_by(|a, b| a.0 == b.0);
+            let tags = tags
+                .into_iter()
+                .filter(|(_, value)| !value.is_empty())
+                .map(|(name, value)| format!("{}:{}", tag(name), tag(value)))
+                .collect::<Vec<_>>()
+                .join(",");
+            return vec![format!("{}:{}|g|#{}\n", name, sample.value, tags)];
+        }
+
+        let instance = self.labels.iter().find(|(name, _)| name == "instance");
+        push(&sanitize(instance.map_or("", |(_, value)| value.as_str())));
+        push(&sanitize(&sample.name));
+        let mut labels = sample.labels.iter().collect::<Vec<_>>();
+        labels.sort();
+        for (label, value) in labels {
+            push(&sanitize(label));
+            push(&sanitize(value));
+        }
+        // StatsD 把带符号的 gauge 当作增量，负值要先归零
+        if sample.value < 0.0 {
+            vec![
+                format!("{}:0|g\n", name),
+                format!("{}:{}|g\n", name, sample.value),
+            ]
+        } else {
+            vec![format!("{}:{}|g\n", name, sample.value)]
+        }
+    }
+}
+
+/// DogStatsD tags end at `,`, `|` and the end of the line.
+fn tag(s: &str) -> String {
+    s.replace(&[',', '|', '#', '\n'][..], "_")
+}
+
+#[tide::utils::async_trait]
+impl Target for Statsd {
+    fn name(&self) -> &'static str {
+        "statsd"
+    }
+
+    async fn push(&self, batch: &Batch) -> Result<()> {
+        let lines = batch
+            .samples
+            .iter()
+            .filter(|sample| sample.value.is_finite())
+            .flat_map(|sample| self.lines(sample))
+            .collect::<Vec<_>>();
+        push::send_datagrams(&self.address, &lines).await
+    }
+}
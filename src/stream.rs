@@ -0,0 +1,126 @@
+use async_std::future::timeout;
+use lazy_static::lazy_static;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tide::http::headers::CACHE_CONTROL;
+use tide::sse::Sender;
+use tide::{Request, StatusCode};
+use tracing::error;
+
+use crate::api;
+use crate::collector;
+use crate::config::Settings;
+use crate::middleware;
+use crate::tenant::Tenant;
+use crate::State;
+
+/// How often `/stream` sends the GPUs.
+const INTERVAL: Duration = Duration::from_secs(1);
+/// How long a sample nobody streams any more is kept.
+const SAMPLE_TTL: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    /// The last event sent for each query string, so that clients streaming the same GPUs share
+    /// one nvidia-smi run per second.
+    static ref LATEST: Mutex<HashMap<String, Arc<Slot>>> = Mutex::new(HashMap::new());
+}
+
+/// The SSE event name and its JSON data.
+type Event = (&'static str, String);
+/// The last event of a query string and when it was collected.
+type Slot = async_lock::Mutex<Option<(Instant, Arc<Event>)>>;
+
+/// `GET /stream`: Server-Sent Events with the GPUs every second, as `gpus` events shaped like
+/// `/api/v1/gpus` plus a `timestamp`, or `error` events when the collection failed. Takes the
+/// same `?collect[]=`, `?gpu=`, `?uuid=` and `?target=` parameters. Each collection is held to
+/// `--web.request-timeout`, with an `error` event when it runs past it.
+pub async fn handle_stream(req: Request<State>) -> tide::Result {
+    if let Err(e) = crate::scrape_settings(&req) {
+        return Ok(crate::bad_request(e));
+    }
+    let mut res = tide::sse::upgrade(req, stream);
+    // no-transform 让压缩中间件跳过，否则事件会积在压缩缓冲区里
+    res.insert_header(CACHE_CONTROL, "no-cache, no-transform");
+    Ok(res)
+}
+
+async fn stream(req: Request<State>, sender: Sender) -> tide::Result<()> {
+    // 租户之间不能共用结果
+    let tenant = req.ext::<Arc<Tenant>>().map(|tenant| tenant.name.as_str());
+    let key = format!(
+        "{}?{}",
+        tenant.unwrap_or_default(),
+        req.url().query().unwrap_or_default()
+    );
+    loop {
+        let started = Instant::now();
+        // 每次重新取，/-/reload 之后立即生效
+        let settings = crate::scrape_settings(&req)
+            .map_err(|e| tide::Error::from_str(StatusCode::BadRequest, e))?;
+        let deadline =
+            Duration::from_secs(req.state().config.read().unwrap().request_timeout_seconds);
+        let event = sample(&key, &settings, deadline).await;
+        // 客户端断开后发送失败，结束这条流
+        if sender.send(event.0, &event.1, None).await.is_err() {
+            return Ok(());
+        }
+        async_std::task::sleep(INTERVAL.saturating_sub(started.elapsed())).await;
+    }
+}
+
+async fn sample(key: &str, settings: &Settings, deadline: Duration) -> Arc<Event> {
+    let slot = {
+        let mut latest = LATEST.lock().unwrap();
+        // 没有流在用、又已过期的样本丢掉
+        latest.retain(|_, slot| {
+            Arc::strong_count(slot) > 1
+                || slot.try_lock().is_some_and(|latest| {
+                    latest
+                        .as_ref()
+                        .is_some_and(|(at, _)| at.elapsed() < SAMPLE_TTL)
+                })
+        });
+        latest.entry(key.to_string()).or_default().clone()
+    };
+    // 只锁这个查询：同时到达的客户端等这一次的结果而不是各自运行 nvidia-smi，其它查询不受影响
+    let mut latest = slot.lock().await;
+    if let Some((at, event)) = &*latest {
+        if at.elapsed() < INTERVAL {
+            return event.clone();
+        }
+    }
+    let fields = collector::fields(settings);
+    let collected = match deadline {
+        Duration::ZERO => Ok(collector::collect_gpus(settings, &fields).await),
+        deadline => timeout(deadline, collector::collect_gpus(settings, &fields)).await,
+    };
+    let event = match collected {
+        Ok(Ok(gpus)) => {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            let gpus = gpus
+                .iter()
+                .map(|gpu| api::to_json(gpu, &fields))
+                .collect::<Vec<_>>();
+            ("gpus", json!({ "timestamp": timestamp, "gpus": gpus }))
+        }
+        Ok(Err(e)) => {
+            error!("Failed to process nvidia-smi, {:#}", e);
+            ("error", json!({ "error": format!("{:#}", e) }))
+        }
+        Err(_) => {
+            middleware::deadline_exceeded(deadline);
+            (
+                "error",
+                json!({ "error": format!("Collection aborted after {:?}", deadline) }),
+            )
+        }
+    };
+    let event = Arc::new((event.0, event.1.to_string()));
+    *latest = Some((Instant::now(), event.clone()));
+    event
+}
@@ -0,0 +1,140 @@
+use anyhow::{bail, Context, Result};
+use regex::RegexSet;
+use serde::Deserialize;
+use serde_yaml::Value;
+use std::sync::Arc;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+use tracing::debug;
+
+use crate::auth;
+use crate::filter::GpuMatcher;
+
+/// What tenants may request besides the telemetry path; the other endpoints show every GPU or
+/// change them.
+const TENANT_PATHS: &[&str] = &[
+    "/api/v1/gpus",
+    "/api/v1/metadata",
+    "/stream",
+    "/dashboard.json",
+    "/healthz",
+    "/readyz",
+];
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TenantsFile {
+    tenants: Vec<TenantConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TenantConfig {
+    name: String,
+    #[serde(default)]
+    users: Vec<String>,
+    bearer_token_file: Option<String>,
+    gpus: Vec<Value>,
+    #[serde(default)]
+    metrics: Vec<String>,
+}
+
+/// A tenant of `--web.tenants-file`: the credentials it scrapes with and what they show.
+#[derive(Debug)]
+pub struct Tenant {
+    pub name: String,
+    /// Basic auth users, of `--web.basic-auth-file` or `--web.config.file`.
+    pub users: Vec<String>,
+    pub tokens: Vec<String>,
+    pub gpus: Vec<GpuMatcher>,
+    /// Anchored at both ends; every metric when None.
+    pub metrics: Option<RegexSet>,
+}
+
+pub fn load(path: &str) -> Result<Vec<Arc<Tenant>>> {
+    let content =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+    let file: TenantsFile =
+        serde_yaml::from_str(&content).with_context(|| format!("Invalid tenants file {}", path))?;
+    let mut tenants = Vec::new();
+    for config in file.tenants {
+        let tenant =
+            Tenant::new(config).with_context(|| format!("Invalid tenants file {}", path))?;
+        tenants.push(Arc::new(tenant));
+    }
+    Ok(tenants)
+}
+
+impl Tenant {
+    fn new(config: TenantConfig) -> Result<Self> {
+        let name = config.name;
+        let tokens = match &config.bearer_token_file {
+            Some(path) => {
+                let tokens = auth::read_lines(path)?
+                    .into_iter()
+                    .map(|(_, token)| token)
+                    .collect::<Vec<_>>();
+                if tokens.is_empty() {
+                    bail!("No tokens found in {}", path);
+                }
+                tokens
+            }
+            None => Vec::new(),
+        };
+        if config.users.is_empty() && tokens.is_empty() {
+            bail!("Tenant {} has neither users nor bearer_token_file", name);
+        }
+        if config.gpus.is_empty() {
+            bail!("Tenant {} has no gpus", name);
+        }
+        let gpus = config
+            .gpus
+            .iter()
+            .map(|gpu| match gpu {
+                Value::Number(index) => index.to_string().parse(),
+                Value::String(gpu) => gpu.parse(),
+                _ => bail!("Invalid GPU {:?} of tenant {}", gpu, name),
+            })
+            .collect::<Result<Vec<GpuMatcher>>>()?;
+        let metrics = match config.metrics.is_empty() {
+            true => None,
+            false => Some(
+                RegexSet::new(config.metrics.iter().map(|p| format!("^(?:{})$", p)))
+                    .with_context(|| format!("Invalid metrics of tenant {}", name))?,
+            ),
+        };
+        Ok(Tenant {
+            name,
+            users: config.users,
+            tokens,
+            gpus,
+            metrics,
+        })
+    }
+}
+
+/// Answers `403` to tenants outside the telemetry path and `TENANT_PATHS`.
+pub struct ScopeMiddleware {
+    telemetry_path: String,
+}
+
+impl ScopeMiddleware {
+    pub fn new(telemetry_path: &str) -> Self {
+        ScopeMiddleware {
+            telemetry_path: telemetry_path.to_string(),
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ScopeMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if let Some(tenant) = req.ext::<Arc<Tenant>>() {
+            let path = req.url().path();
+            if path != self.telemetry_path && !TENANT_PATHS.contains(&path) {
+                debug!("Rejected request of tenant {} for {}", tenant.name, path);
+                return Ok(Response::new(StatusCode::Forbidden));
+            }
+        }
+        Ok(next.run(req).await)
+    }
+}
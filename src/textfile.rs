@@ -0,0 +1,66 @@
+use async_std::prelude::*;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::collector;
+use crate::config::Settings;
+use crate::oneshot::{self, OutputFormat};
+use crate::shutdown;
+
+/// `print --output --interval`: writes the metrics to `path` every `interval` until SIGTERM or
+/// SIGINT, for nodes that only allow node_exporter's port. With `remove_stale_after`, removes
+/// the file once no collection was written for that long, and on shutdown. Returns the process
+/// exit code.
+pub async fn run(
+    settings: &Settings,
+    path: &str,
+    format: OutputFormat,
+    interval: Duration,
+    remove_stale_after: Option<Duration>,
+) -> i32 {
+    let settings = oneshot::textfile_settings(settings);
+    let writing = async {
+        // 上次运行留下的文件也算旧的
+        let mut written_at = Instant::now();
+        loop {
+            let started = Instant::now();
+            match collector::process_nvidia_smi(&settings).await {
+                Ok(nvidia_buffer) => {
+                    let metrics = oneshot::render(&settings, &nvidia_buffer, format);
+                    match oneshot::write_atomically(path, metrics.as_bytes()) {
+                        Ok(()) => written_at = Instant::now(),
+                        Err(e) => error!("{:#}", e),
+                    }
+                }
+                // 保留上次的文件，由 node_textfile_mtime_seconds 发现
+                Err(e) => error!("Failed to process nvidia-smi, {:#}", e),
+            }
+            if let Some(stale_after) = remove_stale_after {
+                if written_at.elapsed() >= stale_after && remove(path) {
+                    warn!("Removed {}, not written for {:?}", path, stale_after);
+                }
+            }
+            async_std::task::sleep(interval.saturating_sub(started.elapsed())).await;
+        }
+    };
+    if let Err(e) = writing.race(shutdown::signal()).await {
+        error!("Failed to register signal handlers, {:#}", e);
+        return 1;
+    }
+    if remove_stale_after.is_some() && remove(path) {
+        info!("Removed {}", path);
+    }
+    0
+}
+
+/// Whether `path` was there and is removed now.
+fn remove(path: &str) -> bool {
+    match std::fs::remove_file(path) {
+        Ok(()) => true,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+        Err(e) => {
+            error!("Failed to remove {}, {}", path, e);
+            false
+        }
+    }
+}
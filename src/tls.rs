@@ -0,0 +1,268 @@
+use anyhow::{anyhow, bail, Context, Result};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, Certificate, ClientConfig,
+    ClientHello, NoClientAuth, PrivateKey, ProtocolVersion, ResolvesServerCert, RootCertStore,
+    ServerConfig, SupportedCipherSuite, ALL_CIPHERSUITES,
+};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{error, info};
+
+/// How often the certificate files are checked for changes during handshakes.
+const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// `tls_server_config` of an exporter-toolkit web config, also built from the `--tls-*` flags.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsServerConfig {
+    pub cert_file: String,
+    pub key_file: String,
+    #[serde(default)]
+    pub client_auth_type: ClientAuthType,
+    pub client_ca_file: Option<String>,
+    #[serde(default)]
+    pub min_version: Option<TlsVersion>,
+    #[serde(default)]
+    pub max_version: Option<TlsVersion>,
+    /// TLS 1.2 suites by their IANA name; TLS 1.3 suites are only restricted if some are listed.
+    #[serde(default)]
+    pub cipher_suites: Vec<String>,
+    #[serde(default = "default_true")]
+    pub prefer_server_cipher_suites: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Versions as named in exporter-toolkit; rustls has nothing below TLS 1.2.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Deserialize, Serialize)]
+pub enum TlsVersion {
+    TLS10,
+    TLS11,
+    TLS12,
+    TLS13,
+}
+
+impl std::str::FromStr for TlsVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "TLS10" => Ok(TlsVersion::TLS10),
+            "TLS11" => Ok(TlsVersion::TLS11),
+            "TLS12" => Ok(TlsVersion::TLS12),
+            "TLS13" => Ok(TlsVersion::TLS13),
+            _ => bail!("Unknown TLS version {:?}, expected TLS12 or TLS13", s),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub enum ClientAuthType {
+    #[default]
+    NoClientCert,
+    VerifyClientCertIfGiven,
+    RequireAndVerifyClientCert,
+}
+
+impl TlsServerConfig {
+    /// Builds the rustls config; with a client CA, client certificates are verified against it.
+    pub fn build(&self) -> Result<ServerConfig> {
+        let verifier = match (&self.client_auth_type, &self.client_ca_file) {
+            (ClientAuthType::NoClientCert, _) => NoClientAuth::new(),
+            (_, None) => bail!(
+                "client_auth_type {:?} requires client_ca_file",
+                self.client_auth_type
+            ),
+            (ClientAuthType::VerifyClientCertIfGiven, Some(ca)) => {
+                AllowAnyAnonymousOrAuthenticatedClient::new(load_roots(ca)?)
+            }
+            (ClientAuthType::RequireAndVerifyClientCert, Some(ca)) => {
+                AllowAnyAuthenticatedClient::new(load_roots(ca)?)
+            }
+        };
+        let mut config = ServerConfig::new(verifier);
+        config.cert_resolver = Arc::new(CertReloader::new(&self.cert_file, &self.key_file)?);
+        config.versions = self.versions()?;
+        config.ciphersuites = self.cipher_suites()?;
+        config.ignore_client_order = self.prefer_server_cipher_suites;
+        Ok(config)
+    }
+
+    fn versions(&self) -> Result<Vec<ProtocolVersion>> {
+        let min = self.min_version.unwrap_or(TlsVersion::TLS12);
+        let max = self.max_version.unwrap_or(TlsVersion::TLS13);
+        let mut versions = Vec::new();
+        if min <= TlsVersion::TLS13 && max >= TlsVersion::TLS13 {
+            versions.push(ProtocolVersion::TLSv1_3);
+        }
+        if min <= TlsVersion::TLS12 && max >= TlsVersion::TLS12 {
+            versions.push(ProtocolVersion::TLSv1_2);
+        }
+        if versions.is_empty() {
+            bail!(
+                "No TLS version between min_version {:?} and max_version {:?} is supported (TLS12, TLS13)",
+                min,
+                max
+            );
+        }
+        Ok(versions)
+    }
+
+    fn cipher_suites(&self) -> Result<Vec<&'static SupportedCipherSuite>> {
+        if self.cipher_suites.is_empty() {
+            return Ok(ALL_CIPHERSUITES.to_vec());
+        }
+        let mut suites = Vec::new();
+        for name in &self.cipher_suites {
+            // Go 的名字里 CHACHA20_POLY1305 不带 _SHA256 后缀
+            let suite = ALL_CIPHERSUITES
+                .iter()
+                .find(|suite| {
+                    let known = format!("{:?}", suite.suite);
+                    known == *name || known == format!("{}_SHA256", name)
+                })
+                .ok_or_else(|| anyhow!("Unsupported cipher suite {}", name))?;
+            suites.push(*suite);
+        }
+        if !suites
+            .iter()
+            .any(|suite| suite.usable_for_version(ProtocolVersion::TLSv1_3))
+        {
+            suites.extend(
+                ALL_CIPHERSUITES
+                    .iter()
+                    .filter(|suite| suite.usable_for_version(ProtocolVersion::TLSv1_3)),
+            );
+        }
+        Ok(suites)
+    }
+}
+
+/// Serves the certificate and key from disk, re-reading them when the files change so renewed
+/// certificates are picked up without a restart.
+struct CertReloader {
+    cert_file: String,
+    key_file: String,
+    state: Mutex<CertState>,
+}
+
+struct CertState {
+    key: CertifiedKey,
+    modified: (Option<SystemTime>, Option<SystemTime>),
+    checked_at: Instant,
+}
+
+impl CertReloader {
+    fn new(cert_file: &str, key_file: &str) -> Result<Self> {
+        let modified = modified(cert_file, key_file);
+        let key = load_certified_key(cert_file, key_file)?;
+        Ok(CertReloader {
+            cert_file: cert_file.to_string(),
+            key_file: key_file.to_string(),
+            state: Mutex::new(CertState {
+                key,
+                modified,
+                checked_at: Instant::now(),
+            }),
+        })
+    }
+}
+
+impl ResolvesServerCert for CertReloader {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        let mut state = self.state.lock().unwrap();
+        if state.checked_at.elapsed() >= CERT_CHECK_INTERVAL {
+            state.checked_at = Instant::now();
+            let modified = modified(&self.cert_file, &self.key_file);
+            if modified != state.modified {
+                // 证书和私钥可能不是同时写入的，读取失败时继续用旧的，下次再试
+                match load_certified_key(&self.cert_file, &self.key_file) {
+                    Ok(key) => {
+                        info!("Reloaded TLS certificate {}", self.cert_file);
+                        state.key = key;
+                        state.modified = modified;
+                    }
+                    Err(e) => error!("Failed to reload TLS certificate, {:#}", e),
+                }
+            }
+        }
+        Some(state.key.clone())
+    }
+}
+
+/// A client config trusting `ca_file`, or the Mozilla roots without it, and presenting
+/// `cert_file`/`key_file` if given.
+pub fn client_config(
+    ca_file: Option<&str>,
+    cert_file: Option<&str>,
+    key_file: Option<&str>,
+) -> Result<ClientConfig> {
+    let mut config = ClientConfig::new();
+    match ca_file {
+        Some(path) => config.root_store = load_roots(path)?,
+        None => config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+    }
+    if let (Some(cert_file), Some(key_file)) = (cert_file, key_file) {
+        config
+            .set_single_client_cert(load_certs(cert_file)?, load_key(key_file)?)
+            .with_context(|| {
+                format!("Invalid certificate/key pair {} / {}", cert_file, key_file)
+            })?;
+    }
+    Ok(config)
+}
+
+fn modified(cert_file: &str, key_file: &str) -> (Option<SystemTime>, Option<SystemTime>) {
+    let mtime = |path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    (mtime(cert_file), mtime(key_file))
+}
+
+fn load_certified_key(cert_file: &str, key_file: &str) -> Result<CertifiedKey> {
+    let signing_key = any_supported_type(&load_key(key_file)?)
+        .map_err(|_| anyhow!("Unsupported private key in {}", key_file))?;
+    let key = CertifiedKey::new(load_certs(cert_file)?, Arc::new(signing_key));
+    key.cross_check_end_entity_cert(None)
+        .with_context(|| format!("Invalid certificate/key pair {} / {}", cert_file, key_file))?;
+    Ok(key)
+}
+
+fn load_roots(path: &str) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for ca in load_certs(path)? {
+        roots
+            .add(&ca)
+            .map_err(|e| anyhow!("Invalid CA certificate in {}: {:?}", path, e))?;
+    }
+    Ok(roots)
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    let certs = certs(&mut BufReader::new(file))
+        .map_err(|_| anyhow!("Failed to parse PEM certificates in {}", path))?;
+    if certs.is_empty() {
+        bail!("No certificates found in {}", path);
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKey> {
+    // 先按 PKCS#8 读，读不到再按 RSA 读
+    let open = || File::open(path).with_context(|| format!("Failed to open {}", path));
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(open()?)).unwrap_or_default();
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut BufReader::new(open()?)).unwrap_or_default();
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("No PKCS#8 or RSA private key found in {}", path))
+}
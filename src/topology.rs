@@ -0,0 +1,363 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_std::future::timeout;
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use regex::Regex;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tide::{Body, Request, Response, StatusCode};
+use tracing::{error, warn};
+
+use crate::config::Settings;
+use crate::State;
+
+/// How long a topology is reused; it only changes with hardware.
+const TOPOLOGY_TTL: Duration = Duration::from_secs(600);
+
+lazy_static! {
+    static ref ANSI: Regex = Regex::new(r"\x1b\[[0-9;]*m").unwrap();
+    static ref CACHE: Mutex<Option<(Instant, Arc<Topology>)>> = Mutex::new(None);
+    static ref LINK_INFO: IntGaugeVec = register_int_gauge_vec!(
+        "nvidia_topology_link_info",
+        "How the GPU reaches each other GPU and NIC, as in nvidia-smi topo -m, with --collect.topology.",
+        &["gpu", "peer", "link"]
+    )
+    .unwrap();
+    static ref NVLINK_PEERS: IntGaugeVec = register_int_gauge_vec!(
+        "nvidia_topology_nvlink_peers",
+        "Number of other GPUs the GPU reaches over NVLink, with --collect.topology.",
+        &["gpu"]
+    )
+    .unwrap();
+    static ref NUMA_NODE: IntGaugeVec = register_int_gauge_vec!(
+        "nvidia_topology_numa_node",
+        "NUMA node closest to the GPU, with --collect.topology.",
+        &["gpu"]
+    )
+    .unwrap();
+}
+
+/// `nvidia-smi topo -m`: the devices in the order of the matrix, and the legend of its links.
+#[derive(Debug, Serialize)]
+pub struct Topology {
+    pub devices: Vec<Device>,
+    pub legend: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Device {
+    /// The matrix's name of it, `GPU0` or `NIC0`.
+    pub name: String,
+    /// The index of a GPU.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gpu: Option<String>,
+    /// The device of a NIC, e.g. `mlx5_0`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// The link to each other device, e.g. `NV12` or `SYS`.
+    pub links: BTreeMap<String, String>,
+    /// The other columns, e.g. `cpu_affinity`, null for `N/A`.
+    #[serde(flatten)]
+    pub affinity: BTreeMap<String, Option<String>>,
+}
+
+/// Parses the output of `nvidia-smi topo -m`, whose columns are separated by tabs.
+pub fn parse(text: &str) -> Result<Topology> {
+    let text = ANSI.replace_all(text, "");
+    let mut lines = text.lines();
+    let header = lines
+        .find(|line| line.starts_with('\t'))
+        .ok_or_else(|| anyhow!("No topology matrix"))?;
+    let columns = header
+        .split('\t')
+        .skip(1)
+        .map(str::trim)
+        .filter(|column| !column.is_empty())
+        .collect::<Vec<_>>();
+    let rows = lines
+        .by_ref()
+        .take_while(|line| !line.trim().is_empty())
+        .collect::<Vec<_>>();
+
+    // 矩阵的列是同时作为行出现的设备，其余是亲和性
+    let names = rows
+        .iter()
+        .filter_map(|row| row.split('\t').next())
+        .map(str::trim)
+        .collect::<Vec<_>>();
+    let device_columns = columns.iter().take_while(|c| names.contains(c)).count();
+    if device_columns == 0 {
+        bail!("No devices in the topology matrix");
+    }
+    let mut devices = Vec::new();
+    for row in rows {
+        let mut cells = row.split('\t').map(str::trim);
+        let name = cells.next().unwrap_or_default().to_string();
+        let links = columns[..device_columns]
+            .iter()
+            .zip(cells.by_ref())
+            .filter(|(column, _)| **column != name)
+            .map(|(column, link)| (column.to_string(), link.to_string()))
+            .collect();
+        // 列之间有时多一个制表符，按非空单元格对应
+        let affinity = columns[device_columns..]
+            .iter()
+            .zip(cells.filter(|cell| !cell.is_empty()))
+            .map(|(column, value)| {
+                let key = column.to_ascii_lowercase().replace(' ', "_");
+                (key, Some(value.to_string()).filter(|v| v != "N/A"))
+            })
+            .collect();
+        devices.push(Device {
+            gpu: name.strip_prefix("GPU").map(str::to_string),
+            device: None,
+            name,
+            links,
+            affinity,
+        });
+    }
+
+    // "  X    = Self" 和 "  NIC0: mlx5_0"
+    let mut legend = BTreeMap::new();
+    for line in lines {
+        if let Some((key, meaning)) = line.split_once('=') {
+            let key = key.trim();
+            if !key.is_empty() && !key.contains(' ') {
+                legend.insert(key.to_string(), meaning.trim().to_string());
+            }
+        } else if let Some((nic, name)) = line.trim().split_once(':') {
+            if let Some(device) = devices.iter_mut().find(|d| d.name == nic) {
+                device.device = Some(name.trim().to_string());
+            }
+        }
+    }
+    Ok(Topology { devices, legend })
+}
+
+/// The topology of the local GPUs, or of the mock ones, reused for `TOPOLOGY_TTL`.
+async fn topology(settings: &Settings) -> Result<Arc<Topology>> {
+    if let Some((at, topology)) = &*CACHE.lock().unwrap() {
+        if at.elapsed() < TOPOLOGY_TTL {
+            return Ok(topology.clone());
+        }
+    }
+    let text = match &settings.mock {
+        Some(mock) => mock.topology(),
+        None => {
+            let args = vec!["topo".to_string(), "-m".to_string()];
+            let output = timeout(
+                settings.collect_timeout,
+                settings.nvidia_smi.output(&args, None),
+            )
+            .await
+            .map_err(|_| anyhow!("nvidia-smi timed out after {:?}", settings.collect_timeout))?
+            .with_context(|| "Failed to execute command")?;
+            if !output.status.success() {
+                bail!(
+                    "nvidia-smi exited with {}: {}",
+                    output.status,
+                    (String::from_utf8_lossy(&output.stdout)
+                        + String::from_utf8_lossy(&output.stderr))
+                    .trim()
+                );
+            }
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        }
+    };
+    let topology = Arc::new(parse(&text)?);
+    *CACHE.lock().unwrap() = Some((Instant::now(), topology.clone()));
+    Ok(topology)
+}
+
+/// `GET /topology`: the `nvidia-smi topo -m` matrix as JSON, with the link from each device to
+/// every other one, the CPU and NUMA affinities of the GPUs and the legend.
+pub async fn handle_topology(req: Request<State>) -> tide::Result {
+    let settings = req.state().settings.read().unwrap().clone();
+    if settings.ssh.is_some() {
+        return Ok(crate::bad_request(
+            "The topology is only available for the local GPUs".to_string(),
+        ));
+    }
+    match topology(&settings).await {
+        Ok(topology) => Ok(Response::builder(StatusCode::Ok)
+            .body(Body::from_json(&*topology)?)
+            .build()),
+        Err(e) => {
+            error!("Failed to read the GPU topology, {:#}", e);
+            Ok(Response::builder(StatusCode::ServiceUnavailable)
+                .body(Body::from_json(&json!({ "error": format!("{:#}", e) }))?)
+                .build())
+        }
+    }
+}
+
+/// `--collect.topology`: exports the topology of the GPUs, read again every `TOPOLOGY_TTL`.
+pub fn start(settings: &Arc<RwLock<Settings>>) {
+    let settings = settings.clone();
+    async_std::task::spawn(async move {
+        loop {
+            let current = settings.read().unwrap().clone();
+            match topology(&current).await {
+                Ok(topology) => export(&topology),
+                Err(e) => warn!("Failed to read the GPU topology, {:#}", e),
+            }
+            async_std::task::sleep(TOPOLOGY_TTL).await;
+        }
+    });
+}
+
+fn export(topology: &Topology) {
+    LINK_INFO.reset();
+    NVLINK_PEERS.reset();
+    NUMA_NODE.reset();
+    for device in &topology.devices {
+        let gpu = match &device.gpu {
+            Some(gpu) => gpu,
+            None => continue,
+        };
+        let mut nvlink_peers = 0;
+        for (peer, link) in &device.links {
+            let other = topology.devices.iter().find(|d| d.name == *peer);
+            let peer = match other {
+                Some(Device { gpu: Some(gpu), .. }) => gpu,
+                Some(Device {
+                    device: Some(device),
+                    ..
+                }) => device,
+                _ => peer,
+            };
+            if other.is_some_and(|d| d.gpu.is_some()) && link.starts_with("NV") {
+                nvlink_peers += 1;
+            }
+            LINK_INFO.with_label_values(&[gpu, peer, link]).set(1);
+        }
+        NVLINK_PEERS.with_label_values(&[gpu]).set(nvlink_peers);
+        let numa = device
+            .affinity
+            .get("numa_affinity")
+            .and_then(|numa| numa.as_deref()?.parse().ok());
+        if let Some(numa) = numa {
+            NUMA_NODE.with_label_values(&[gpu]).set(numa);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `nvidia-smi topo -m` of a DGX A100 slice with driver 535: NVLink between the GPUs, two
+    /// NICs, and the header underlined with ANSI codes.
+    const DGX: &str = "\x1b[4m\tGPU0\tGPU1\tGPU2\tGPU3\tNIC0\tNIC1\tCPU Affinity\tNUMA Affinity\tGPU NUMA ID\x1b[0m
+GPU0\t X \tNV12\tNV12\tNV12\tPXB\tSYS\t48-63,176-191\t3\t\tN/A
+GPU1\tNV12\t X \tNV12\tNV12\tPXB\tSYS\t48-63,176-191\t3\t\tN/A
+GPU2\tNV12\tNV12\t X \tNV12\tSYS\tPXB\t16-31,144-159\t1\t\tN/A
+GPU3\tNV12\tNV12\tNV12\t X \tSYS\tPXB\t16-31,144-159\t1\t\tN/A
+NIC0\tPXB\tPXB\tSYS\tSYS\t X \tSYS\t\t\t\t
+NIC1\tSYS\tSYS\tPXB\tPXB\tSYS\t X \t\t\t\t
+
+Legend:
+
+  X    = Self
+  SYS  = Connection traversing PCIe as well as the SMP interconnect between NUMA nodes (e.g., QPI/UPI)
+  NODE = Connection traversing PCIe as well as the interconnect between PCIe Host Bridges within a NUMA node
+  PHB  = Connection traversing PCIe as well as a PCIe Host Bridge (typically the CPU)
+  PXB  = Connection traversing multiple PCIe bridges (without traversing the PCIe Host Bridge)
+  PIX  = Connection traversing at most a single PCIe bridge
+  NV#  = Connection traversing a bonded set of # NVLinks
+
+NIC Legend:
+
+  NIC0: mlx5_0
+  NIC1: mlx5_1
+
+";
+
+    /// A PCIe workstation with driver 470: no NVLink or NICs, and no GPU NUMA ID column.
+    const WORKSTATION: &str = "\tGPU0\tGPU1\tGPU2\tCPU Affinity\tNUMA Affinity
+GPU0\t X \tPIX\tSYS\t0-11,24-35\t0
+GPU1\tPIX\t X \tSYS\t0-11,24-35\t0
+GPU2\tSYS\tSYS\t X \t12-23,36-47\tN/A
+
+Legend:
+
+  X    = Self
+  SYS  = Connection traversing PCIe as well as the SMP interconnect between NUMA nodes (e.g., QPI/UPI)
+  PIX  = Connection traversing at most a single PCIe bridge
+";
+
+    #[test]
+    fn parses_nvlink_matrix() {
+        let topology = parse(DGX).unwrap();
+        let names = topology
+            .devices
+            .iter()
+            .map(|d| d.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["GPU0", "GPU1", "GPU2", "GPU3", "NIC0", "NIC1"]);
+
+        let gpu = &topology.devices[2];
+        assert_eq!(gpu.gpu.as_deref(), Some("2"));
+        assert_eq!(gpu.device, None);
+        assert_eq!(gpu.links.len(), 5);
+        assert!(!gpu.links.contains_key("GPU2"));
+        assert_eq!(gpu.links["GPU0"], "NV12");
+        assert_eq!(gpu.links["NIC0"], "SYS");
+        assert_eq!(gpu.links["NIC1"], "PXB");
+        assert_eq!(
+            gpu.affinity["cpu_affinity"].as_deref(),
+            Some("16-31,144-159")
+        );
+        assert_eq!(gpu.affinity["numa_affinity"].as_deref(), Some("1"));
+        assert_eq!(gpu.affinity["gpu_numa_id"], None);
+
+        let nic = &topology.devices[4];
+        assert_eq!(nic.gpu, None);
+        assert_eq!(nic.device.as_deref(), Some("mlx5_0"));
+        assert_eq!(nic.links["GPU1"], "PXB");
+        assert_eq!(nic.links["NIC1"], "SYS");
+        assert!(nic.affinity.is_empty());
+
+        assert_eq!(topology.legend["X"], "Self");
+        assert_eq!(
+            topology.legend["NV#"],
+            "Connection traversing a bonded set of # NVLinks"
+        );
+        assert_eq!(topology.legend.len(), 7);
+
+        export(&topology);
+        assert_eq!(NVLINK_PEERS.with_label_values(&["0"]).get(), 3);
+        assert_eq!(NUMA_NODE.with_label_values(&["3"]).get(), 1);
+        assert_eq!(
+            LINK_INFO.with_label_values(&["0", "mlx5_1", "SYS"]).get(),
+            1
+        );
+        assert_eq!(LINK_INFO.with_label_values(&["0", "1", "NV12"]).get(), 1);
+    }
+
+    #[test]
+    fn parses_pcie_matrix() {
+        let topology = parse(WORKSTATION).unwrap();
+        assert_eq!(topology.devices.len(), 3);
+        let gpu = &topology.devices[0];
+        assert_eq!(gpu.links["GPU1"], "PIX");
+        assert_eq!(gpu.links["GPU2"], "SYS");
+        assert_eq!(gpu.affinity["cpu_affinity"].as_deref(), Some("0-11,24-35"));
+        assert_eq!(gpu.affinity["numa_affinity"].as_deref(), Some("0"));
+        assert_eq!(topology.devices[2].affinity["numa_affinity"], None);
+        assert_eq!(
+            topology.legend["PIX"],
+            "Connection traversing at most a single PCIe bridge"
+        );
+    }
+
+    #[test]
+    fn rejects_output_without_matrix() {
+        assert!(parse("").is_err());
+        assert!(parse("NVML: Unable to determine the topology\n").is_err());
+        assert!(parse("\tCPU Affinity\tNUMA Affinity\n\n").is_err());
+    }
+}
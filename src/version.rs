@@ -0,0 +1,55 @@
+use lazy_static::lazy_static;
+use tide::convert::json;
+use tide::{Body, Request, Response, StatusCode};
+
+use crate::collector;
+use crate::State;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_COMMIT: &str = env!("GIT_COMMIT");
+pub const BUILD_DATE: &str = env!("BUILD_DATE");
+pub const TARGET: &str = env!("BUILD_TARGET");
+/// The cargo features this binary was built with.
+pub const FEATURES: &[&str] = &[
+    #[cfg(feature = "otlp")]
+    "otlp",
+    #[cfg(feature = "hyper")]
+    "hyper",
+];
+
+lazy_static! {
+    /// `--version` output, enough to tell which binary a bug report is about.
+    pub static ref LONG_VERSION: String = format!(
+        "{}\ncommit: {}\nbuilt: {}\ntarget: {}\nfeatures: {}",
+        VERSION,
+        GIT_COMMIT,
+        BUILD_DATE,
+        TARGET,
+        if FEATURES.is_empty() {
+            "none".to_string()
+        } else {
+            FEATURES.join(",")
+        }
+    );
+}
+
+/// Build and driver details for fleet inventory tooling.
+pub async fn handle_version(req: Request<State>) -> tide::Result {
+    // 驱动版本来自最近一次成功采集，还没有采集过时为 null
+    let driver_version = collector::last_collection()
+        .and_then(|last| last.result.ok())
+        .and_then(|gpus| gpus.into_iter().next())
+        .map(|gpu| gpu.driver_version);
+    let body = json!({
+        "version": VERSION,
+        "git_commit": GIT_COMMIT,
+        "build_date": BUILD_DATE,
+        "target": TARGET,
+        "features": FEATURES,
+        "driver_version": driver_version,
+        "backend": req.state().config.read().unwrap().backend,
+    });
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&body)?)
+        .build())
+}
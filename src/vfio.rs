@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::path::Path;
+use tracing::debug;
+
+const DRIVER: &str = "/sys/bus/pci/drivers/vfio-pci";
+const NVIDIA: &str = "0x10de";
+
+/// An NVIDIA GPU bound to vfio-pci, which nvidia-smi cannot see.
+#[derive(Clone, Debug)]
+pub struct Device {
+    /// As sysfs writes it, e.g. `0000:3b:00.0`.
+    pub bus_id: String,
+    /// The PCI device id, e.g. `0x20b0`.
+    pub device_id: String,
+    /// The libvirt domain the device is assigned to, if any.
+    pub domain: Option<String>,
+}
+
+/// The NVIDIA display controllers bound to vfio-pci, with the libvirt domains that have them as
+/// a `<hostdev>` in the status XML under `libvirt_dir`.
+pub fn devices(libvirt_dir: Option<&str>) -> Vec<Device> {
+    let entries = match std::fs::read_dir(DRIVER) {
+        Ok(entries) => entries,
+        // 没加载 vfio-pci 模块
+        Err(_) => return Vec::new(),
+    };
+    let domains = libvirt_dir.map(hostdevs).unwrap_or_default();
+    let mut devices = entries
+        .filter_map(|entry| {
+            let bus_id = entry.ok()?.file_name().into_string().ok()?;
+            let path = Path::new(DRIVER).join(&bus_id);
+            let read = |name: &str| {
+                std::fs::read_to_string(path.join(name))
+                    .ok()
+                    .map(|s| s.trim().to_string())
+            };
+            // 只要 VGA/3D 控制器（0x03xxxx），同一张卡的 HDMI 音频功能也会绑到 vfio-pci
+            if read("vendor")? != NVIDIA || !read("class")?.starts_with("0x03") {
+                return None;
+            }
+            Some(Device {
+                domain: domains.get(&bus_id).cloned(),
+                device_id: read("device")?,
+                bus_id,
+            })
+        })
+        .collect::<Vec<_>>();
+    devices.sort_by(|a, b| a.bus_id.cmp(&b.bus_id));
+    devices
+}
+
+/// The running domain of each PCI host device, from libvirt's `<name>.xml` status files.
+fn hostdevs(dir: &str) -> HashMap<String, String> {
+    let mut domains = HashMap::new();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("Failed to read {}, {}", dir, e);
+            return domains;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension() != Some("xml".as_ref()) {
+            continue;
+        }
+        let (domain, xml) = match (path.file_stem(), std::fs::read_to_string(&path)) {
+            (Some(domain), Ok(xml)) => (domain.to_string_lossy().into_owned(), xml),
+            _ => continue,
+        };
+        // <hostdev mode='subsystem' type='pci'><source><address domain='0x0000' bus='0x3b' slot='0x00' function='0x0'/></source>…
+        for hostdev in xml.split("<hostdev").skip(1) {
+            let hostdev = hostdev.split("</hostdev>").next().unwrap_or_default();
+            let source = match hostdev.split_once("<source>") {
+                Some((_, source)) => source.split("</source>").next().unwrap_or_default(),
+                None => continue,
+            };
+            let address = match source.split_once("<address") {
+                Some((_, address)) => address.split('>').next().unwrap_or_default(),
+                None => continue,
+            };
+            if let Some(bus_id) = bus_id(address) {
+                domains.insert(bus_id, domain.clone());
+            }
+        }
+    }
+    domains
+}
+
+/// `0000:3b:00.0` from the attributes of an `<address>` element.
+fn bus_id(address: &str) -> Option<String> {
+    let attribute = |name: &str| {
+        let value = ["'", "\""].iter().find_map(|quote| {
+            let start = address.find(&format!(" {}={}", name, quote))? + name.len() + 3;
+            let end = address[start..].find(quote)?;
+            Some(&address[start..start + end])
+        })?;
+        u32::from_str_radix(value.trim_start_matches("0x"), 16).ok()
+    };
+    Some(format!(
+        "{:04x}:{:02x}:{:02x}.{:x}",
+        attribute("domain")?,
+        attribute("bus")?,
+        attribute("slot")?,
+        attribute("function")?
+    ))
+}
@@ -0,0 +1,34 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+use crate::tls::TlsServerConfig;
+
+/// The `--web.config.file` format shared with Prometheus' exporter-toolkit.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebConfig {
+    pub tls_server_config: Option<TlsServerConfig>,
+    #[serde(default)]
+    pub http_server_config: HttpServerConfig,
+    #[serde(default)]
+    pub basic_auth_users: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpServerConfig {
+    /// tide only speaks HTTP/1.1, so this is accepted but has no effect.
+    #[serde(default)]
+    pub http2: Option<bool>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl WebConfig {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let content =
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+        serde_yaml::from_str(&content).with_context(|| format!("Invalid web config {}", path))
+    }
+}
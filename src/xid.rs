@@ -0,0 +1,317 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_std::future::timeout;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, register_int_gauge, IntCounterVec, IntGauge};
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::config::Settings;
+
+/// The kernel log, from boot, as one record per read.
+const KMSG: &str = "/dev/kmsg";
+/// How long to wait before reading the kernel log again after it failed.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    /// `NVRM: Xid (PCI:0000:3b:00): 79, pid=…`, also `(PCI:0000:07:00 GPU-I:01)` on MIG.
+    static ref XID: Regex =
+        Regex::new(r"NVRM: Xid \(PCI:([0-9A-Fa-f]+:[0-9A-Fa-f]+:[0-9A-Fa-f]+)[^)]*\): (\d+)")
+            .unwrap();
+    static ref KERNEL_XID: IntCounterVec = register_int_counter_vec!(
+        "nvidia_kernel_xid_total",
+        "Number of NVRM Xid messages in the kernel log since boot by GPU index (empty if nvidia-smi does not list the GPU), PCI address and XID, with --collect.kernel-xid.",
+        &["gpu", "pci_bus_id", "xid"]
+    )
+    .unwrap();
+    static ref UP: IntGauge = register_int_gauge!(
+        "nvidia_smi_exporter_kernel_log_up",
+        "Whether the kernel log is being read for Xid messages, with --collect.kernel-xid."
+    )
+    .unwrap();
+}
+
+/// Where the reading got to, so that reading again after a failure counts no message twice.
+#[derive(Default)]
+struct Position {
+    /// Sequence number of the last `/dev/kmsg` record read.
+    kmsg: Option<u64>,
+    /// Whether journalctl was followed before, and only new messages are wanted.
+    journal: bool,
+}
+
+/// `--collect.kernel-xid`: counts the Xid messages of the NVIDIA driver in the kernel log, from
+/// `/dev/kmsg` or else `journalctl -k`, on a thread of its own.
+pub fn start(settings: &Arc<RwLock<Settings>>) {
+    lazy_static::initialize(&UP);
+    let settings = settings.clone();
+    let spawned = std::thread::Builder::new()
+        .name("kernel-xid".to_string())
+        .spawn(move || {
+            let mut position = Position::default();
+            let mut gpus = HashMap::new();
+            loop {
+                if let Err(e) = follow(&settings, &mut position, &mut gpus) {
+                    warn!("Not reading Xid messages from the kernel log, {:#}", e);
+                }
+                UP.set(0);
+                std::thread::sleep(RETRY_INTERVAL);
+            }
+        });
+    if let Err(e) = spawned {
+        warn!("Failed to start reading the kernel log, {}", e);
+    }
+}
+
+fn follow(
+    settings: &Arc<RwLock<Settings>>,
+    position: &mut Position,
+    gpus: &mut HashMap<String, String>,
+) -> Result<()> {
+    // 没有 root 或 CAP_SYSLOG（kernel.dmesg_restrict=1）时读不了 /dev/kmsg
+    let kmsg = match File::open(KMSG) {
+        Ok(kmsg) => kmsg,
+        Err(e) => {
+            debug!("Failed to open {}, {}, reading journalctl -k", KMSG, e);
+            return follow_journal(settings, position, gpus)
+                .with_context(|| format!("Failed to open {} ({})", KMSG, e));
+        }
+    };
+    info!("Reading Xid messages from {}", KMSG);
+    UP.set(1);
+    read_kmsg(kmsg, settings, position, gpus)
+}
+
+fn read_kmsg(
+    mut kmsg: File,
+    settings: &Arc<RwLock<Settings>>,
+    position: &mut Position,
+    gpus: &mut HashMap<String, String>,
+) -> Result<()> {
+    let mut buffer = vec![0; 8192];
+    loop {
+        let len = match kmsg.read(&mut buffer) {
+            Ok(0) => bail!("{} ended", KMSG),
+            Ok(len) => len,
+            // 还没读到的记录被新记录覆盖了
+            Err(e) if e.raw_os_error() == Some(libc::EPIPE) => continue,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", KMSG)),
+        };
+        let record = String::from_utf8_lossy(&buffer[..len]);
+        if let Some(message) = new_message(position, &record) {
+            count(message, gpus, || indexes(settings));
+        }
+    }
+}
+
+/// The message of a `/dev/kmsg` record, `6,339,5140900,-;NVRM: Xid ...`, unless it was read
+/// before.
+fn new_message<'a>(position: &mut Position, record: &'a str) -> Option<&'a str> {
+    let (header, message) = record.split_once(';')?;
+    let seq = header.split(',').nth(1).and_then(|seq| seq.parse().ok());
+    if let (Some(seq), Some(last)) = (seq, position.kmsg) {
+        if seq <= last {
+            return None;
+        }
+    }
+    position.kmsg = seq.or(position.kmsg);
+    Some(message)
+}
+
+fn follow_journal(
+    settings: &Arc<RwLock<Settings>>,
+    position: &mut Position,
+    gpus: &mut HashMap<String, String>,
+) -> Result<()> {
+    // 第一次从本次启动开始读，与 /dev/kmsg 一致；重新读时只要新消息
+    let lines = match position.journal {
+        true => "--lines=0",
+        false => "--lines=all",
+    };
+    let mut child = Command::new("journalctl")
+        .args(["-k", "-f", "-o", "cat", "--no-pager", lines])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| "Failed to execute journalctl")?;
+    info!("Reading Xid messages from journalctl -k");
+    UP.set(1);
+    position.journal = true;
+    let stdout = child.stdout.take().unwrap();
+    for line in BufReader::new(stdout).lines() {
+        match line {
+            Ok(line) => count(&line, gpus, || indexes(settings)),
+            Err(e) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(e).with_context(|| "Failed to read journalctl");
+            }
+        }
+    }
+    let output = child.wait_with_output()?;
+    bail!(
+        "journalctl exited with {}: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+    )
+}
+
+/// The PCI address and the XID of an Xid message.
+fn parse(message: &str) -> Option<(String, String)> {
+    let captures = XID.captures(message)?;
+    Some((bus_id(&captures[1])?, captures[2].to_string()))
+}
+
+/// Counts `message` if it is an Xid message, with the index of its GPU from `gpus`, looked up
+/// again with `indexes` for a GPU not in it.
+fn count(
+    message: &str,
+    gpus: &mut HashMap<String, String>,
+    indexes: impl FnOnce() -> Result<HashMap<String, String>>,
+) {
+    let (bus_id, xid) = match parse(message) {
+        Some(xid) => xid,
+        None => return,
+    };
+    if !gpus.contains_key(&bus_id) {
+        match indexes() {
+            Ok(indexes) => gpus.extend(indexes),
+            Err(e) => debug!("Failed to look up the GPU indexes, {:#}", e),
+        }
+    }
+    // 掉卡（Xid 79）后 nvidia-smi 里已经没有这块 GPU，gpu 留空，只有 PCI 地址
+    let gpu = gpus.get(&bus_id).map(String::as_str).unwrap_or_default();
+    debug!("Xid {} on GPU {} ({}) in the kernel log", xid, gpu, bus_id);
+    KERNEL_XID.with_label_values(&[gpu, &bus_id, &xid]).inc();
+}
+
+/// The GPU indexes by PCI bus, from nvidia-smi.
+fn indexes(settings: &Arc<RwLock<Settings>>) -> Result<HashMap<String, String>> {
+    let settings = settings.read().unwrap().clone();
+    let args = vec![
+        "--query-gpu=index,pci.bus_id".to_string(),
+        "--format=csv,noheader".to_string(),
+    ];
+    let output = async_std::task::block_on(timeout(
+        settings.collect_timeout,
+        settings.nvidia_smi.output(&args, None),
+    ))
+    .map_err(|_| anyhow!("nvidia-smi timed out after {:?}", settings.collect_timeout))?
+    .with_context(|| "Failed to execute command")?;
+    if !output.status.success() {
+        bail!(
+            "nvidia-smi exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(parse_indexes(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// `0, 00000000:3B:00.0` lines as indexes by PCI bus.
+fn parse_indexes(text: &str) -> HashMap<String, String> {
+    text.lines()
+        .filter_map(|line| {
+            let (index, bus_id) = line.split_once(',')?;
+            Some((self::bus_id(bus_id.trim())?, index.trim().to_string()))
+        })
+        .collect()
+}
+
+/// `0000:3b:00` of the kernel log and `00000000:3B:00.0` of nvidia-smi as the same
+/// `0000:3b:00`.
+fn bus_id(s: &str) -> Option<String> {
+    let mut parts = s.split(':');
+    let domain = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let bus = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let device = parts.next()?.split('.').next()?;
+    let device = u32::from_str_radix(device, 16).ok()?;
+    Some(format!("{:04x}:{:02x}:{:02x}", domain, bus, device))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_xid_messages() {
+        assert_eq!(
+            parse("NVRM: Xid (PCI:0000:3b:00): 79, pid='<unknown>', name=<unknown>, GPU has fallen off the bus."),
+            Some(("0000:3b:00".to_string(), "79".to_string()))
+        );
+        assert_eq!(
+            parse("NVRM: Xid (PCI:0000:07:00 GPU-I:01): 43, pid=2714, name=python3, Ch 00000008"),
+            Some(("0000:07:00".to_string(), "43".to_string()))
+        );
+        assert_eq!(parse("NVRM: GPU at PCI:0000:3b:00: GPU-5f0b8a5e"), None);
+        assert_eq!(parse("NVRM: Xid (PCI:zz:3b:00): 79"), None);
+    }
+
+    #[test]
+    fn matches_bus_ids_of_nvidia_smi() {
+        assert_eq!(bus_id("00000000:3B:00.0").as_deref(), Some("0000:3b:00"));
+        assert_eq!(bus_id("0000:3b:00").as_deref(), Some("0000:3b:00"));
+        assert_eq!(bus_id("0000:3b"), None);
+        let indexes = parse_indexes("0, 00000000:07:00.0\n1, 00000000:3B:00.0\n");
+        assert_eq!(indexes["0000:3b:00"], "1");
+        assert_eq!(indexes["0000:07:00"], "0");
+    }
+
+    #[test]
+    fn skips_kmsg_records_read_before() {
+        let mut position = Position::default();
+        assert_eq!(
+            new_message(
+                &mut position,
+                "3,1041,5140900,-;NVRM: Xid (PCI:0000:3b:00): 79"
+            ),
+            Some("NVRM: Xid (PCI:0000:3b:00): 79")
+        );
+        assert_eq!(position.kmsg, Some(1041));
+        // 重新打开 /dev/kmsg 后从头读到的旧记录
+        assert_eq!(new_message(&mut position, "3,1040,5140800,-;earlier"), None);
+        assert_eq!(new_message(&mut position, "3,1041,5140900,-;again"), None);
+        assert_eq!(
+            new_message(&mut position, "6,1042,5141000,-;later"),
+            Some("later")
+        );
+        assert_eq!(new_message(&mut position, "no header"), None);
+        assert_eq!(position.kmsg, Some(1042));
+    }
+
+    #[test]
+    fn counts_by_index_and_pci_address() {
+        let mut gpus = HashMap::new();
+        let mut lookups = 0;
+        let mut count = |message: &str| {
+            count(message, &mut gpus, || {
+                lookups += 1;
+                Ok(parse_indexes("0, 00000000:AF:00.0\n"))
+            })
+        };
+        count("NVRM: Xid (PCI:0000:af:00): 13, Graphics Exception");
+        count("NVRM: Xid (PCI:0000:af:00): 13, Graphics Exception");
+        count("NVRM: Xid (PCI:0000:d8:00): 79, GPU has fallen off the bus.");
+        count("NVRM: loading NVIDIA UNIX x86_64 Kernel Module");
+        assert_eq!(lookups, 2);
+        assert_eq!(
+            KERNEL_XID
+                .with_label_values(&["0", "0000:af:00", "13"])
+                .get(),
+            2
+        );
+        assert_eq!(
+            KERNEL_XID
+                .with_label_values(&["", "0000:d8:00", "79"])
+                .get(),
+            1
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use anyhow::{bail, Context, Result};
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::TcpStream;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tracing::debug;
+
+use crate::cli::Cli;
+use crate::push::{Batch, Sample, Target};
+
+/// `ZBXD` and the protocol version.
+const HEADER: &[u8] = b"ZBXD\x01";
+/// Longest response read back; the server only reports counts.
+const MAX_RESPONSE: u64 = 1 << 20;
+/// Trapper key of the low-level discovery rule.
+const DISCOVERY_KEY: &str = "nvidia.gpu.discovery";
+
+/// A Zabbix server or proxy (`--push.zabbix.address`), sent item values with the sender
+/// (trapper) protocol.
+pub struct Zabbix {
+    address: String,
+    host: String,
+    discovery_interval: Duration,
+    /// The discovery last sent, and when.
+    discovered: Mutex<Option<(Value, Instant)>>,
+}
+
+impl Zabbix {
+    pub fn new(address: &str, cli: &Cli, labels: Vec<(String, String)>) -> Self {
+        let instance = labels
+            .into_iter()
+            .find(|(name, _)| name == "instance")
+            .map(|(_, value)| value)
+            .unwrap_or_default();
+        Zabbix {
+            address: address.to_string(),
+            host: cli.zabbix_host.clone().unwrap_or(instance),
+            discovery_interval: Duration::from_secs(cli.zabbix_discovery_interval),
+            discovered: Mutex::new(None),
+        }
+    }
+
+    /// The GPUs for the discovery rule, `{#GPU}` and `{#GPU.NAME}` from the labels.
+    fn discovery(samples: &[Sample]) -> Value {
+        let mut gpus = BTreeMap::new();
+        for sample in samples {
+            if let Some(gpu) = label(sample, "gpu") {
+                gpus.entry(gpu)
+                    .or_insert_with(|| label(sample, "name").unwrap_or_default());
+            }
+        }
+        Value::Array(
+            gpus.into_iter()
+                .map(|(gpu, name)| json!({"{#GPU}": gpu, "{#GPU.NAME}": name}))
+                .collect(),
+        )
+    }
+
+    /// Whether the GPUs changed or `--push.zabbix.discovery-interval` passed since the last
+    /// discovery sent.
+    fn discovery_due(&self, discovery: &Value) -> bool {
+        match &*self.discovered.lock().unwrap() {
+            Some((last, at)) => last != discovery || at.elapsed() >= self.discovery_interval,
+            None => true,
+        }
+    }
+}
+
+fn label<'a>(sample: &'a Sample, name: &str) -> Option<&'a str> {
+    sample
+        .labels
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// `<metric>[<value>,...]` with the values of the labels other than `name`, ordered by label
+/// name, e.g. `nvidia_temperature_gpu[0]`.
+fn key(sample: &Sample) -> String {
+    let mut labels = sample
+        .labels
+        .iter()
+        .filter(|(name, _)| name != "name")
+        .collect::<Vec<_>>();
+    if labels.is_empty() {
+        return sample.name.clone();
+    }
+    labels.sort();
+    let params = labels
+        .into_iter()
+        .map(|(_, value)| param(value))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("{}[{}]", sample.name, params)
+}
+
+/// Item key parameters with `,` or `]`, or starting with `"` or a space, need quotes.
+fn param(value: &str) -> String {
+    if value.contains(&[',', ']'][..]) || value.starts_with(&['"', ' '][..]) {
+        format!("\"{}\"", value.replace('"', "\\\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[tide::utils::async_trait]
+impl Target for Zabbix {
+    fn name(&self) -> &'static str {
+        "zabbix"
+    }
+
+    async fn push(&self, batch: &Batch) -> Result<()> {
+        let clock = batch.timestamp.duration_since(UNIX_EPOCH)?;
+        let item = |key: String, value: String| {
+            json!({
+                "host": self.host,
+                "key": key,
+                "value": value,
+                "clock": clock.as_secs(),
+                "ns": clock.subsec_nanos(),
+            })
+        };
+        // 发现规则放在最前面，新 GPU 的监控项要等服务端处理完才会创建
+        let mut data = Vec::new();
+        let discovery = Self::discovery(&batch.samples);
+        let discovery_due = self.discovery_due(&discovery);
+        if discovery_due {
+            data.push(item(DISCOVERY_KEY.to_string(), discovery.to_string()));
+        }
+        data.extend(
+            batch
+                .samples
+                .iter()
+                .filter(|sample| sample.value.is_finite())
+                .map(|sample| item(key(sample), sample.value.to_string())),
+        );
+        let request = json!({
+            "request": "sender data",
+            "data": data,
+            "clock": clock.as_secs(),
+            "ns": clock.subsec_nanos(),
+        })
+        .to_string();
+
+        let mut stream = TcpStream::connect(&self.address)
+            .await
+            .with_context(|| format!("Failed to connect to {}", self.address))?;
+        stream.write_all(HEADER).await?;
+        stream
+            .write_all(&(request.len() as u64).to_le_bytes())
+            .await?;
+        stream.write_all(request.as_bytes()).await?;
+        stream.flush().await?;
+
+        let mut header = [0u8; 13];
+        stream.read_exact(&mut header).await?;
+        if &header[..5] != HEADER {
+            bail!("Unexpected response from {}", self.address);
+        }
+        let len = u64::from_le_bytes(header[5..].try_into().unwrap());
+        if len > MAX_RESPONSE {
+            bail!("Response from {} too long ({} bytes)", self.address, len);
+        }
+        let mut body = vec![0u8; len as usize];
+        stream.read_exact(&mut body).await?;
+        let response: Value = serde_json::from_slice(&body)
+            .with_context(|| format!("Invalid response from {}", self.address))?;
+        // 模板里没有的监控项算作 failed，这是正常情况
+        let info = response["info"].as_str().unwrap_or_default();
+        if response["response"] != "success" {
+            bail!("{} {}", response["response"], info);
+        }
+        debug!("Zabbix {}", info);
+        if discovery_due {
+            *self.discovered.lock().unwrap() = Some((discovery, Instant::now()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+    use std::time::SystemTime;
+
+    fn sample(name: &str, labels: &[(&str, &str)], value: f64) -> Sample {
+        Sample {
+            name: name.to_string(),
+            labels: labels
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+            value,
+        }
+    }
+
+    #[test]
+    fn item_keys() {
+        let temperature = sample(
+            "nvidia_temperature_gpu",
+            &[("uuid", "GPU-0"), ("name", "A100"), ("gpu", "0")],
+            45.0,
+        );
+        assert_eq!(key(&temperature), "nvidia_temperature_gpu[0,GPU-0]");
+        assert_eq!(key(&sample("nvidia_gpus", &[], 8.0)), "nvidia_gpus");
+        assert_eq!(param("a,b"), "\"a,b\"");
+        assert_eq!(param("\"q\""), "\"\\\"q\\\"\"");
+        assert_eq!(param(" x"), "\" x\"");
+        assert_eq!(param("x y"), "x y");
+    }
+
+    #[async_std::test]
+    async fn sends_trapper_frame() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let zabbix = Zabbix {
+            address: listener.local_addr().unwrap().to_string(),
+            host: "node1".to_string(),
+            discovery_interval: Duration::from_secs(3600),
+            discovered: Mutex::new(None),
+        };
+        let server = async_std::task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0u8; 13 + 302];
+            stream.read_exact(&mut request).await.unwrap();
+            let response = br#"{"response":"success","info":"processed: 2; failed: 0; total: 2"}"#;
+            stream.write_all(HEADER).await.unwrap();
+            stream
+                .write_all(&(response.len() as u64).to_le_bytes())
+                .await
+                .unwrap();
+            stream.write_all(response).await.unwrap();
+            request
+        });
+        let batch = Batch {
+            exposition: String::new(),
+            samples: vec![
+                sample(
+                    "nvidia_temperature_gpu",
+                    &[("gpu", "0"), ("name", "A100")],
+                    45.0,
+                ),
+                // 非有限值不发送
+                sample("nvidia_power_draw", &[("gpu", "0")], f64::NAN),
+            ],
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_500),
+        };
+        zabbix.push(&batch).await.unwrap();
+
+        let mut expected = b"ZBXD\x01\x2e\x01\x00\x00\x00\x00\x00\x00".to_vec();
+        expected.extend_from_slice(
+            br#"{"clock":1700000000,"data":[{"clock":1700000000,"host":"node1","key":"nvidia.gpu.discovery","ns":500000000,"value":"[{\"{#GPU.NAME}\":\"A100\",\"{#GPU}\":\"0\"}]"},{"clock":1700000000,"host":"node1","key":"nvidia_temperature_gpu[0]","ns":500000000,"value":"45"}],"ns":500000000,"request":"sender data"}"#,
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&server.await),
+            String::from_utf8_lossy(&expected)
+        );
+        // 发现结果没变，下次不再发送
+        assert!(!zabbix.discovery_due(&Zabbix::discovery(&batch.samples)));
+    }
+}
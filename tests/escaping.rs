@@ -0,0 +1,145 @@
+//! Whatever the GPU and process names, the rendered samples must parse as the text format and
+//! give back the same labels and values.
+
+use nvidia_smi_exporter::{default_collectors, exposition, render_prometheus, GpuSnapshot};
+use proptest::prelude::*;
+
+/// One sample line: the metric, its labels unescaped, and the value.
+type Sample = (String, Vec<(String, String)>, f64);
+
+/// Parses `metric{name="value", …} value` as Prometheus does, unescaping `\\`, `\"` and `\n`.
+fn parse(line: &str) -> Result<Sample, String> {
+    let name_end = line
+        .find(['{', ' '])
+        .ok_or_else(|| "no value".to_string())?;
+    let metric = &line[..name_end];
+    if metric.is_empty()
+        || metric.starts_with(|c: char| c.is_ascii_digit())
+        || !metric
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+    {
+        return Err(format!("invalid metric name {:?}", metric));
+    }
+    let mut labels = Vec::new();
+    let mut chars = line[name_end..].chars().peekable();
+    if chars.peek() == Some(&'{') {
+        chars.next();
+        loop {
+            let mut name = String::new();
+            for c in chars.by_ref() {
+                if c == '=' {
+                    break;
+                }
+                name.push(c);
+            }
+            let name = name.trim_start().to_string();
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(format!("invalid label name {:?}", name));
+            }
+            if chars.next() != Some('"') {
+                return Err(format!("unquoted value of {}", name));
+            }
+            let mut value = String::new();
+            loop {
+                match chars.next() {
+                    Some('\\') => match chars.next() {
+                        Some('\\') => value.push('\\'),
+                        Some('"') => value.push('"'),
+                        Some('n') => value.push('\n'),
+                        c => return Err(format!("invalid escape {:?} in {}", c, name)),
+                    },
+                    Some('"') => break,
+                    Some('\n') => return Err(format!("newline in {}", name)),
+                    Some(c) => value.push(c),
+                    None => return Err(format!("unterminated value of {}", name)),
+                }
+            }
+            labels.push((name, value));
+            match chars.next() {
+                Some(',') => continue,
+                Some('}') => break,
+                c => {
+                    return Err(format!(
+                        "expected , or }} after {}, got {:?}",
+                        labels.len(),
+                        c
+                    ))
+                }
+            }
+        }
+    }
+    if chars.next() != Some(' ') {
+        return Err("no space before the value".to_string());
+    }
+    let value = chars.collect::<String>();
+    let value = match value.as_str() {
+        "+Inf" => f64::INFINITY,
+        "-Inf" => f64::NEG_INFINITY,
+        "NaN" => f64::NAN,
+        _ => value
+            .parse()
+            .map_err(|_| format!("invalid value {:?}", value))?,
+    };
+    Ok((metric.to_string(), labels, value))
+}
+
+/// Arbitrary names, and short ones made of the characters the text format gives a meaning to.
+fn name() -> impl Strategy<Value = String> {
+    prop_oneof![any::<String>(), "[\\\\\"\n\r{}=, a-zA-Z0-9]{0,16}"]
+}
+
+fn same_value(a: f64, b: f64) -> bool {
+    a == b || (a.is_nan() && b.is_nan())
+}
+
+proptest! {
+    #[test]
+    fn gpu_names_round_trip(names in prop::collection::vec(name(), 1..4), value in any::<f64>()) {
+        let gpus = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| GpuSnapshot {
+                index: i.to_string(),
+                uuid: format!("GPU-{}", i),
+                name: name.clone(),
+                driver_version: "550.90.07".to_string(),
+                values: vec![("nvidia_power_draw".to_string(), value)],
+                host: None,
+            })
+            .collect::<Vec<_>>();
+        let text = render_prometheus(&gpus, &default_collectors());
+        let lines = text.lines().collect::<Vec<_>>();
+        prop_assert_eq!(lines.len(), gpus.len());
+        for (line, gpu) in lines.iter().zip(&gpus) {
+            let (metric, labels, parsed) = parse(line).map_err(TestCaseError::fail)?;
+            prop_assert_eq!(metric, "nvidia_power_draw");
+            prop_assert_eq!(
+                labels,
+                vec![
+                    ("gpu".to_string(), gpu.index.clone()),
+                    ("name".to_string(), gpu.name.clone()),
+                ]
+            );
+            prop_assert!(same_value(parsed, value), "{} read back as {}", value, parsed);
+        }
+    }
+
+    #[test]
+    fn process_names_round_trip(name in name(), pid in any::<u32>(), value in any::<f64>()) {
+        let labels = vec![
+            ("gpu".to_string(), "0".to_string()),
+            ("pid".to_string(), pid.to_string()),
+            ("process_name".to_string(), name),
+        ];
+        let mut buffer = String::new();
+        exposition::sample(&mut buffer, "nvidia_process_used_memory", &labels, value);
+        prop_assert!(buffer.ends_with('\n'));
+        prop_assert_eq!(buffer.matches('\n').count(), 1);
+        let (metric, parsed_labels, parsed) =
+            parse(buffer.trim_end_matches('\n')).map_err(TestCaseError::fail)?;
+        prop_assert_eq!(metric, "nvidia_process_used_memory");
+        prop_assert_eq!(parsed_labels, labels);
+        prop_assert!(same_value(parsed, value), "{} read back as {}", value, parsed);
+    }
+}
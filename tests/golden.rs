@@ -0,0 +1,331 @@
+//! Runs `nvidia-smi-exporter print` against captured `nvidia-smi --query-gpu` outputs of
+//! different GPU generations and drivers, in `tests/golden/<case>.csv`, and compares the GPU
+//! samples with `tests/golden/<case>.prom`. `UPDATE_GOLDEN=1 cargo test --test golden` rewrites
+//! the `.prom` files after an intended change of the output. The same fake nvidia-smi also
+//! drives `--capture-dir` with `replay`, `print --output --interval`, and a scrape past
+//! `--web.request-timeout`.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Answers `-L` and `--query-gpu` from the files next to it, like nvidia-smi on the captured node.
+const FAKE_NVIDIA_SMI: &str = r#"#!/bin/sh
+dir=$(dirname "$0")
+case "$1" in
+-L) cat "$dir/list.txt" ;;
+--query-gpu=*) cat "$dir/query-gpu.csv" ;;
+*) exit 1 ;;
+esac
+"#;
+
+#[test]
+fn golden() {
+    let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+    let mut cases = fs::read_dir(&golden)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some(OsStr::new("csv")))
+        .collect::<Vec<_>>();
+    cases.sort();
+    assert!(!cases.is_empty(), "No cases in {}", golden.display());
+    let mut failed = Vec::new();
+    for csv in &cases {
+        let name = csv.file_stem().unwrap().to_string_lossy();
+        let actual = samples(&name, csv);
+        let expected = csv.with_extension("prom");
+        if update {
+            fs::write(&expected, &actual).unwrap();
+            continue;
+        }
+        match fs::read_to_string(&expected) {
+            Ok(expected) if expected == actual => {}
+            Ok(expected) => failed.push(format!("{}:\n{}", name, diff(&expected, &actual))),
+            Err(e) => failed.push(format!("{}: {}: {}", name, expected.display(), e)),
+        }
+    }
+    assert!(
+        failed.is_empty(),
+        "Output differs from the golden files (UPDATE_GOLDEN=1 to accept):\n{}",
+        failed.join("\n")
+    );
+}
+
+/// The GPU samples `print` exports for the captured `csv`, without the exporter's own metrics,
+/// which depend on the machine running the tests.
+fn samples(name: &str, csv: &Path) -> String {
+    let dir = fake_nvidia_smi(name, csv);
+    let output = Command::new(env!("CARGO_BIN_EXE_nvidia-smi-exporter"))
+        .args(["--disable-exporter-metrics", "print"])
+        .env_clear()
+        .env("PATH", format!("{}:/usr/bin:/bin", dir.display()))
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(
+        output.status.success(),
+        "{}: print exited with {}: {}",
+        name,
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    gpu_samples(&output.stdout)
+}
+
+fn gpu_samples(stdout: &[u8]) -> String {
+    String::from_utf8_lossy(stdout)
+        .lines()
+        .filter(|line| !line.starts_with('#') && !line.starts_with("nvidia_smi_exporter_"))
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+/// An output nvidia-smi cut short is saved by `--capture-dir`, `replay` hits the same error with
+/// the capture, and exports the same samples as the exporter once the capture is complete.
+#[test]
+fn capture_and_replay() {
+    let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let csv = fs::read_to_string(golden.join("t4-535.csv")).unwrap();
+    let truncated = std::env::temp_dir().join(format!(
+        "nvidia-smi-exporter-capture-{}.csv",
+        std::process::id()
+    ));
+    fs::write(&truncated, csv.replace(", 5936\n", "\n")).unwrap();
+    let dir = fake_nvidia_smi("capture", &truncated);
+    fs::remove_file(&truncated).unwrap();
+    let captures = dir.join("captures");
+    fs::create_dir(&captures).unwrap();
+    let exporter = |args: &[&OsStr]| {
+        Command::new(env!("CARGO_BIN_EXE_nvidia-smi-exporter"))
+            .arg("--disable-exporter-metrics")
+            .args(args)
+            .env_clear()
+            .env("PATH", format!("{}:/usr/bin:/bin", dir.display()))
+            .output()
+            .unwrap()
+    };
+
+    let output = exporter(&[
+        "--capture-dir".as_ref(),
+        captures.as_ref(),
+        "print".as_ref(),
+    ]);
+    assert!(!output.status.success());
+    let saved = fs::read_dir(&captures)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    assert_eq!(saved.len(), 1, "{:?}", saved);
+    let capture = fs::read_to_string(&saved[0]).unwrap();
+    let mut lines = capture.splitn(3, '\n');
+    assert!(lines
+        .next()
+        .unwrap()
+        .starts_with("# nvidia-smi-exporter capture"));
+    let command = lines.next().unwrap();
+    assert!(command.starts_with("nvidia-smi --query-gpu=name,index,uuid,"));
+    assert_eq!(lines.next().unwrap(), csv.replace(", 5936\n", "\n"));
+
+    let output = exporter(&["replay".as_ref(), saved[0].as_ref()]);
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Expected 15 fields, got 14"), "{}", stderr);
+
+    // 补全后的捕获与原来的输出导出相同的样本
+    fs::write(
+        &saved[0],
+        format!("# nvidia-smi-exporter capture\n{}\n{}", command, csv),
+    )
+    .unwrap();
+    let output = exporter(&["replay".as_ref(), saved[0].as_ref()]);
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        gpu_samples(&output.stdout),
+        fs::read_to_string(golden.join("t4-535.prom")).unwrap()
+    );
+}
+
+/// `print --output --interval` keeps the file current, removes it once nvidia-smi fails for
+/// longer than `--remove-stale-after`, writes it again after, and removes it on SIGTERM.
+#[test]
+fn textfile_daemon() {
+    let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let dir = fake_nvidia_smi("textfile", &golden.join("t4-535.csv"));
+    let output = dir.join("nvidia.prom");
+    let mut child = Killed(
+        Command::new(env!("CARGO_BIN_EXE_nvidia-smi-exporter"))
+            .args(["--disable-exporter-metrics", "print", "--output"])
+            .arg(&output)
+            .args(["--interval", "1", "--remove-stale-after", "2"])
+            .env_clear()
+            .env("PATH", format!("{}:/usr/bin:/bin", dir.display()))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+    let wait_for = |what: &str, done: &dyn Fn() -> bool| {
+        let started = Instant::now();
+        while !done() {
+            assert!(started.elapsed() < Duration::from_secs(10), "{}", what);
+            sleep(Duration::from_millis(50));
+        }
+    };
+    let expected = fs::read_to_string(golden.join("t4-535.prom")).unwrap();
+    let written = || fs::read(&output).is_ok_and(|content| gpu_samples(&content) == expected);
+
+    wait_for("Not written", &written);
+    fs::write(dir.join("query-gpu.csv"), "garbage\n").unwrap();
+    wait_for("Not removed when stale", &|| !output.exists());
+    fs::copy(golden.join("t4-535.csv"), dir.join("query-gpu.csv")).unwrap();
+    wait_for("Not written again", &written);
+
+    unsafe { libc::kill(child.0.id() as libc::pid_t, libc::SIGTERM) };
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.0.try_wait().unwrap() {
+            break status;
+        }
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "Still running after SIGTERM"
+        );
+        sleep(Duration::from_millis(50));
+    };
+    assert!(status.success(), "Exited with {}", status);
+    assert!(!output.exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// A scrape past `--web.request-timeout` gets 503 with the exporter's own metrics, filtered like
+/// a scrape.
+#[test]
+fn request_timeout() {
+    let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let dir = fake_nvidia_smi("deadline", &golden.join("t4-535.csv"));
+    fs::write(
+        dir.join("nvidia-smi"),
+        FAKE_NVIDIA_SMI.replace("--query-gpu=*) cat", "--query-gpu=*) sleep 10; cat"),
+    )
+    .unwrap();
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let _child = Killed(
+        Command::new(env!("CARGO_BIN_EXE_nvidia-smi-exporter"))
+            .arg("--listen")
+            .arg(format!("127.0.0.1:{}", port))
+            .args([
+                "--web.request-timeout",
+                "1",
+                "--metric-exclude",
+                "nvidia_smi_exporter_scrapes_rejected_total",
+            ])
+            .env_clear()
+            .env("PATH", format!("{}:/usr/bin:/bin", dir.display()))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+    let started = Instant::now();
+    let mut stream = loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => break stream,
+            Err(e) => assert!(started.elapsed() < Duration::from_secs(10), "{}", e),
+        }
+        sleep(Duration::from_millis(50));
+    };
+    stream
+        .set_read_timeout(Some(Duration::from_secs(30)))
+        .unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+    assert!(
+        response.contains("\nnvidia_smi_exporter_scrape_deadline_exceeded_total 1\n"),
+        "{}",
+        response
+    );
+    assert!(
+        !response.contains("nvidia_smi_exporter_scrapes_rejected_total"),
+        "{}",
+        response
+    );
+}
+
+/// A child process killed when dropped, also when a test fails.
+struct Killed(Child);
+
+impl Drop for Killed {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// A directory with an `nvidia-smi` replaying `csv`, and listing its GPUs for `-L`.
+fn fake_nvidia_smi(name: &str, csv: &Path) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "nvidia-smi-exporter-golden-{}-{}",
+        std::process::id(),
+        name
+    ));
+    fs::create_dir_all(&dir).unwrap();
+    let output = fs::read_to_string(csv).unwrap();
+    let list = output
+        .lines()
+        .map(|line| {
+            let columns = line.split(", ").collect::<Vec<_>>();
+            format!(
+                "GPU {}: {} (UUID: {})\n",
+                columns[1], columns[0], columns[2]
+            )
+        })
+        .collect::<String>();
+    fs::write(dir.join("list.txt"), list).unwrap();
+    fs::write(dir.join("query-gpu.csv"), output).unwrap();
+    let script = dir.join("nvidia-smi");
+    fs::write(&script, FAKE_NVIDIA_SMI).unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    dir
+}
+
+/// The lines only in `expected` (`-`) or only in `actual` (`+`).
+fn diff(expected: &str, actual: &str) -> String {
+    let mut lines = Vec::new();
+    for line in expected
+        .lines()
+        .filter(|l| !actual.lines().any(|a| a == *l))
+    {
+        lines.push(format!("- {}", line));
+    }
+    for line in actual
+        .lines()
+        .filter(|l| !expected.lines().any(|e| e == *l))
+    {
+        lines.push(format!("+ {}", line));
+    }
+    if lines.is_empty() {
+        lines.push("  (same lines in another order)".to_string());
+    }
+    lines.join("\n")
+}